@@ -1,18 +1,199 @@
+use std::os::linux::net::SocketAddrExt;
+
+use anyhow::bail;
 use log::warn;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use tokio::net::UnixStream;
+use tonic::body::BoxBody;
+use tonic::client::GrpcService;
+use tonic::codegen::{Body, Bytes, StdError};
 use tonic::transport::{Channel, Endpoint, Uri};
+use tonic::Code;
 use tower::service_fn;
 use url::ParseError as urlParseError;
 
 use crate::v1::agent_client::AgentClient;
+use crate::v1::{ApiVersionRequest, ApiVersionResponse, ServiceMapEdge};
 
 #[path = "agent.v1.rs"]
 #[rustfmt::skip]
 #[allow(clippy::all)]
 pub mod v1;
 
+/// Encoded descriptors of the agent API, served through gRPC reflection.
+pub const FILE_DESCRIPTOR_SET: &[u8] = include_bytes!("agent_descriptor.bin");
+
+/// Semantic version of the agent API. The minor version is bumped when RPCs or fields
+/// are added, the major one when existing ones change incompatibly.
+pub const API_VERSION: &str = "1.19.0";
+
+/// Optional features an agent advertises through ApiVersion. Agents predating
+/// ApiVersion support none of them.
+pub mod features {
+    /// Reporting requests with ReportRequests and reading them with GetRecentRequests.
+    pub const REQUEST_EVENTS: &str = "request_events";
+    /// Streaming dependency changes with WatchDependencies.
+    pub const DEPENDENCY_STREAMING: &str = "dependency_streaming";
+    /// Exporting service maps with ExportGraph.
+    pub const GRAPH_EXPORT: &str = "graph_export";
+    /// Reading retained service maps with GetServiceMapAt and GetServiceMapRange.
+    pub const SNAPSHOTS: &str = "snapshots";
+    /// Comparing retained service maps with DiffServiceMap.
+    pub const SNAPSHOT_DIFF: &str = "snapshot_diff";
+    /// Pausing and resuming programs with PauseProgram and ResumeProgram.
+    pub const PROGRAM_PAUSE: &str = "program_pause";
+    /// Checking load requests without loading anything with ValidateProgram.
+    pub const PROGRAM_VALIDATION: &str = "program_validation";
+    /// Reading the records of program management operations with GetAuditLog.
+    pub const AUDIT_LOG: &str = "audit_log";
+    /// Trace context read from the headers of reported requests, and GetRecentRequests
+    /// filtering by trace id.
+    pub const TRACE_CONTEXT: &str = "trace_context";
+    /// Headers and query parameters of reported requests kept as allowed by the
+    /// metadata of the program named in ReportRequests.
+    pub const CAPTURE_ALLOWLIST: &str = "capture_allowlist";
+    /// Redacted statements of reported database operations, slow ones always kept
+    /// and filtered with GetRecentRequests.
+    pub const SLOW_QUERY_LOG: &str = "slow_query_log";
+    /// Logs of the kernel verifier kept for loads it rejected, read with
+    /// GetLoadDiagnostics.
+    pub const LOAD_DIAGNOSTICS: &str = "load_diagnostics";
+    /// The kernel programs and maps of managed programs, and their run statistics,
+    /// described by InspectPrograms.
+    pub const BPF_INTROSPECTION: &str = "bpf_introspection";
+    /// Snapshot ids and checksums in service map snapshots, and sequence numbers in
+    /// dependency events.
+    pub const SNAPSHOT_CHECKSUMS: &str = "snapshot_checksums";
+    /// Resuming WatchDependencies after the last change received, with resume_after.
+    pub const DEPENDENCY_RESUME: &str = "dependency_resume";
+    /// Streaming the changes of service maps with WatchServiceMap.
+    pub const SERVICE_MAP_STREAMING: &str = "service_map_streaming";
+    /// Pushing service maps to, and taking commands from, a server serving hub.
+    pub const HUB_PUSH: &str = "hub_push";
+    /// Pushing service map updates in batches, coalescing those of a program queued
+    /// together.
+    pub const PUSH_BATCHES: &str = "push_batches";
+    /// Pushing to the replica of a sharded server owning the node, see
+    /// [`shard_of`](crate::shard_of).
+    pub const PUSH_SHARDING: &str = "push_sharding";
+    /// Tracing a pod closely for a while with TracePod.
+    pub const POD_TRACING: &str = "pod_tracing";
+
+    /// Every feature of this version of the API.
+    pub const ALL: &[&str] = &[
+        REQUEST_EVENTS,
+        DEPENDENCY_STREAMING,
+        GRAPH_EXPORT,
+        SNAPSHOTS,
+        SNAPSHOT_DIFF,
+        PROGRAM_PAUSE,
+        PROGRAM_VALIDATION,
+        AUDIT_LOG,
+        TRACE_CONTEXT,
+        CAPTURE_ALLOWLIST,
+        SLOW_QUERY_LOG,
+        LOAD_DIAGNOSTICS,
+        BPF_INTROSPECTION,
+        SNAPSHOT_CHECKSUMS,
+        DEPENDENCY_RESUME,
+        SERVICE_MAP_STREAMING,
+        HUB_PUSH,
+        PUSH_BATCHES,
+        PUSH_SHARDING,
+        POD_TRACING,
+    ];
+}
+
+/// Returns whether an agent serving `api_version` can be used by a client built
+/// against [`API_VERSION`], i.e. whether both have the same major version.
+pub fn is_compatible(api_version: &str) -> bool {
+    let major = |version: &str| version.split('.').next()?.parse::<u64>().ok();
+    major(api_version).is_some() && major(api_version) == major(API_VERSION)
+}
+
+/// Asks the agent for its API version. Agents predating ApiVersion return `None`.
+pub async fn api_version<T>(
+    client: &mut AgentClient<T>,
+) -> anyhow::Result<Option<ApiVersionResponse>>
+where
+    T: GrpcService<BoxBody>,
+    T::Error: Into<StdError>,
+    T::ResponseBody: Body<Data = Bytes> + Send + 'static,
+    <T::ResponseBody as Body>::Error: Into<StdError> + Send,
+{
+    match client.api_version(ApiVersionRequest {}).await {
+        Ok(response) => Ok(Some(response.into_inner())),
+        Err(status) if status.code() == Code::Unimplemented => Ok(None),
+        Err(status) => Err(status.into()),
+    }
+}
+
+/// Fails with an explanation when the agent doesn't support `feature`, rather than
+/// letting the command fail on an unimplemented or misunderstood RPC.
+pub async fn require_feature<T>(client: &mut AgentClient<T>, feature: &str) -> anyhow::Result<()>
+where
+    T: GrpcService<BoxBody>,
+    T::Error: Into<StdError>,
+    T::ResponseBody: Body<Data = Bytes> + Send + 'static,
+    <T::ResponseBody as Body>::Error: Into<StdError> + Send,
+{
+    let Some(version) = api_version(client).await? else {
+        bail!(
+            "The agent predates API versioning and doesn't support {}, upgrade it to use this command",
+            feature
+        );
+    };
+    if !is_compatible(&version.api_version) {
+        bail!(
+            "The agent serves API {}, which is incompatible with this client's {}",
+            version.api_version,
+            API_VERSION
+        );
+    }
+    if !version.features.iter().any(|f| f == feature) {
+        bail!(
+            "The agent (API {}) doesn't support {}, upgrade it to use this command",
+            version.api_version,
+            feature
+        );
+    }
+    Ok(())
+}
+
+/// Returns the shard, out of `shards`, owning the node named `node_name`, with jump
+/// consistent hashing: adding a shard only moves the nodes the new one takes over.
+/// Agents push to the replica of the server at this index, and the replicas use it to
+/// tell the nodes they own.
+pub fn shard_of(node_name: &str, shards: u32) -> u32 {
+    // FNV-1a rather than the std hasher, whose output may change between releases.
+    let mut key = fnv1a(FNV_OFFSET_BASIS, node_name.as_bytes());
+    let (mut shard, mut next) = (0i64, 0i64);
+    while next < shards as i64 {
+        shard = next;
+        key = key.wrapping_mul(2_862_933_555_777_941_757).wrapping_add(1);
+        next = ((shard + 1) as f64 * ((1u64 << 31) as f64 / ((key >> 33) + 1) as f64)) as i64;
+    }
+    shard as u32
+}
+
+/// Returns the checksum of the snapshot of a service map made of `edges`, in their
+/// order: the 64-bit FNV-1a hash of their protobuf encodings, one after the other.
+/// Copies of a snapshot rebuilt from updates are checked against it.
+pub fn snapshot_checksum<'a>(edges: impl IntoIterator<Item = &'a ServiceMapEdge>) -> u64 {
+    edges.into_iter().fold(FNV_OFFSET_BASIS, |hash, edge| {
+        fnv1a(hash, &prost::Message::encode_to_vec(edge))
+    })
+}
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
 pub fn select_channel(path: String) -> Option<Channel> {
     let address = Endpoint::try_from(format!("unix:/{path}"));
     if let Err(e) = address {
@@ -20,11 +201,24 @@ pub fn select_channel(path: String) -> Option<Channel> {
         return None;
     };
     let address = address.unwrap();
-    let channel = address
-        .connect_with_connector_lazy(service_fn(move |_: Uri| UnixStream::connect(path.clone())));
+    let channel =
+        address.connect_with_connector_lazy(service_fn(move |_: Uri| connect_unix(path.clone())));
     Some(channel)
 }
 
+/// Connects to a unix socket, in the abstract namespace when `path` starts with `@`.
+async fn connect_unix(path: String) -> std::io::Result<UnixStream> {
+    match path.strip_prefix('@') {
+        Some(name) => {
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            let stream = std::os::unix::net::UnixStream::connect_addr(&addr)?;
+            stream.set_nonblocking(true)?;
+            UnixStream::from_std(stream)
+        }
+        None => UnixStream::connect(path).await,
+    }
+}
+
 pub async fn new_agent_client(sock_patch: String) -> anyhow::Result<AgentClient<Channel>> {
     let channel = select_channel(sock_patch).unwrap();
     Ok(AgentClient::new(channel))
@@ -117,6 +311,10 @@ pub enum ProgramState {
     Running,
     Stopped,
     Failed,
+    /// Running, but an eBPF program it reads the maps of was unloaded or detached.
+    Degraded,
+    /// Neither polled nor exported until resumed, with its maps and state kept.
+    Paused,
 }
 
 impl TryFrom<u32> for ProgramType {
@@ -152,6 +350,8 @@ impl TryFrom<u32> for ProgramState {
             2 => Ok(ProgramState::Running),
             3 => Ok(ProgramState::Stopped),
             4 => Ok(ProgramState::Failed),
+            5 => Ok(ProgramState::Degraded),
+            6 => Ok(ProgramState::Paused),
             _ => Err(ParseError::InvalidProgramState {
                 program_state: value,
             }),
@@ -169,6 +369,107 @@ impl TryFrom<ProgramState> for u32 {
             ProgramState::Running => Ok(2),
             ProgramState::Stopped => Ok(3),
             ProgramState::Failed => Ok(4),
+            ProgramState::Degraded => Ok(5),
+            ProgramState::Paused => Ok(6),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_compatible() {
+        assert!(is_compatible(API_VERSION));
+        assert!(is_compatible("1.0.0"));
+        assert!(is_compatible("1.99.3"));
+        assert!(!is_compatible("2.0.0"));
+        assert!(!is_compatible("0.9.0"));
+        assert!(!is_compatible(""));
+        assert!(!is_compatible("v1.2.0"));
+    }
+
+    #[test]
+    fn test_features_are_advertised_once() {
+        let mut all = features::ALL.to_vec();
+        all.sort_unstable();
+        all.dedup();
+        assert_eq!(all.len(), features::ALL.len());
+        assert!(all.iter().all(|feature| !feature.is_empty()));
+    }
+
+    fn nodes() -> impl Iterator<Item = String> {
+        (0..10_000).map(|i| format!("node-{}", i))
+    }
+
+    #[test]
+    fn test_shard_of_is_stable() {
+        // Agents and replicas of different releases must agree on the owner of a node.
+        assert_eq!(shard_of("node-1", 1), 0);
+        assert_eq!(shard_of("node-1", 8), 7);
+        assert_eq!(shard_of("node-2", 8), 5);
+        assert_eq!(shard_of("worker-a", 8), 3);
+        assert_eq!(shard_of("ip-10-0-1-17.ec2.internal", 32), 7);
+        assert_eq!(shard_of("", 0), 0);
+        for node in nodes().take(100) {
+            assert_eq!(shard_of(&node, 5), shard_of(&node, 5));
+        }
+    }
+
+    #[test]
+    fn test_shard_of_spreads_nodes() {
+        let mut counts = [0; 8];
+        for node in nodes() {
+            let shard = shard_of(&node, 8);
+            assert!(shard < 8);
+            counts[shard as usize] += 1;
+        }
+        // 1250 each when perfectly spread.
+        for count in counts {
+            assert!((1000..1500).contains(&count), "{:?}", counts);
+        }
+    }
+
+    #[test]
+    fn test_shard_of_moves_few_nodes() {
+        for shards in 1..10 {
+            let mut moved = 0u32;
+            for node in nodes() {
+                let (before, after) = (shard_of(&node, shards), shard_of(&node, shards + 1));
+                if before != after {
+                    // Only to the new shard, never between the existing ones.
+                    assert_eq!(after, shards);
+                    moved += 1;
+                }
+            }
+            // The new shard takes its share, 1 / (shards + 1) of the nodes.
+            let expected = 10_000 / (shards + 1);
+            assert!(
+                moved.abs_diff(expected) < expected / 5,
+                "{} nodes moved to shard {}",
+                moved,
+                shards
+            );
+        }
+    }
+
+    #[test]
+    fn test_snapshot_checksum() {
+        let edge = |server_port| ServiceMapEdge {
+            client_workload: "default/web".to_string(),
+            server_workload: "default/db".to_string(),
+            server_port,
+            protocol: "TCP".to_string(),
+            bytes_sent: 100,
+            ..Default::default()
+        };
+        let edges = [edge(5432), edge(6379)];
+        // Agents and servers of different releases must agree on it.
+        assert_eq!(snapshot_checksum(&[]), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(snapshot_checksum(&edges), 0xc859_3810_bf0f_d031);
+        // The order of the edges matters.
+        let reversed = [edge(6379), edge(5432)];
+        assert_ne!(snapshot_checksum(&reversed), snapshot_checksum(&edges));
+    }
+}
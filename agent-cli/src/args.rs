@@ -1,7 +1,20 @@
+use crate::audit::AuditCommand;
+use crate::bpf::BpfCommand;
+use crate::dependencies::WatchDependenciesCommand;
+use crate::diagnostics::DiagnosticsCommand;
+use crate::diff::DiffCommand;
+use crate::dump::DumpMapsCommand;
 use crate::get::GetCommand;
+use crate::graph::ExportGraphCommand;
 use crate::list::ListCommand;
 use crate::load::LoadCommand;
+use crate::pause::{PauseCommand, ResumeCommand};
+use crate::requests::RequestsCommand;
+use crate::snapshots::SnapshotsCommand;
+use crate::trace_pod::TracePodCommand;
 use crate::unload::UnloadCommand;
+use crate::validate::ValidateCommand;
+use crate::version::VersionCommand;
 use agent_api::new_agent_client;
 use clap::{Parser, Subcommand};
 
@@ -12,6 +25,16 @@ use clap::{Parser, Subcommand};
 #[command(name = "conductor")]
 #[command(disable_version_flag = true)]
 pub(crate) struct AgentCli {
+    /// Optional: Location of the agent unix socket. Prefix it with @ for a socket in
+    /// the abstract namespace.
+    #[clap(
+        long,
+        global = true,
+        verbatim_doc_comment,
+        default_value = "/run/eva/agent.sock"
+    )]
+    pub(crate) socket: String,
+
     #[command(subcommand)]
     pub(crate) command: SubCommands,
 }
@@ -27,6 +50,17 @@ pub(crate) enum SubCommands {
     /// Requires the name of the program to be unloaded.
     Unload(UnloadCommand),
 
+    /// Pauses a running program.
+    /// It is neither polled nor exported until resumed, but its maps stay attached and its state is kept.
+    Pause(PauseCommand),
+
+    /// Resumes a paused program.
+    Resume(ResumeCommand),
+
+    /// Checks whether a program could be loaded, without loading or attaching anything.
+    /// Exits with an error when a check fails.
+    Validate(ValidateCommand),
+
     /// Lists the programs in the system.
     /// Programs can be filtered by type (builtin or wasm) and metadata.
     List(ListCommand),
@@ -34,17 +68,74 @@ pub(crate) enum SubCommands {
     /// Retrieves detailed information about a specific program.
     /// Requires the name of the program to be retrieved.
     Get(GetCommand),
+
+    /// Dumps the pinned eBPF maps of a program.
+    /// Entries can be written as JSON or as a binary capture file.
+    DumpMaps(DumpMapsCommand),
+
+    /// Shows recently sampled requests seen on this node.
+    /// Requests can be filtered by workload, status code and latency.
+    Requests(RequestsCommand),
+
+    /// Focuses tracing on a pod of this node for a few minutes, then goes back to normal.
+    /// Only its process is traced, HTTP is parsed both ways, and its requests are all sampled.
+    TracePod(TracePodCommand),
+
+    /// Watches for workload dependencies appearing or disappearing on this node.
+    /// Runs until interrupted.
+    Dependencies(WatchDependenciesCommand),
+
+    /// Exports the service map as a graph.
+    /// Supports DOT, GraphML and D3 JSON for visualization tools.
+    Graph(ExportGraphCommand),
+
+    /// Shows retained snapshots of a program's service map.
+    /// A single snapshot or those taken within a time range can be shown.
+    Snapshots(SnapshotsCommand),
+
+    /// Compares two snapshots of a program's service map.
+    /// Lists added and removed edges, and edges whose throughput changed significantly.
+    Diff(DiffCommand),
+
+    /// Shows the program management operations recorded by the agent.
+    /// Each operation is listed with who asked for it, when, and how it went.
+    Audit(AuditCommand),
+
+    /// Shows why the kernel verifier rejected the last load of a program.
+    /// Prints the log of the verifier, and exits with an error when there is one.
+    Diagnostics(DiagnosticsCommand),
+
+    /// Shows the kernel programs and maps behind managed programs, like bpftool.
+    /// Lists their ids, attach points, pinned maps and, on request, run statistics.
+    #[command(subcommand)]
+    Bpf(BpfCommand),
+
+    /// Shows the API version of the agent and the features it supports.
+    Version(VersionCommand),
 }
 
 impl AgentCli {
     pub(crate) async fn execute(&self) -> anyhow::Result<()> {
-        let socket_path = "/run/eva/agent.sock";
-        let agent_client = new_agent_client(socket_path.to_string()).await?;
+        let agent_client = new_agent_client(self.socket.clone()).await?;
         match &self.command {
             SubCommands::Load(l) => l.execute(agent_client).await,
             SubCommands::Unload(u) => u.execute(agent_client).await,
+            SubCommands::Pause(p) => p.execute(agent_client).await,
+            SubCommands::Resume(r) => r.execute(agent_client).await,
+            SubCommands::Validate(v) => v.execute(agent_client).await,
             SubCommands::List(l) => l.execute(agent_client).await,
             SubCommands::Get(g) => g.execute(agent_client).await,
+            SubCommands::DumpMaps(d) => d.execute(agent_client).await,
+            SubCommands::Requests(r) => r.execute(agent_client).await,
+            SubCommands::TracePod(t) => t.execute(agent_client).await,
+            SubCommands::Dependencies(d) => d.execute(agent_client).await,
+            SubCommands::Graph(g) => g.execute(agent_client).await,
+            SubCommands::Snapshots(s) => s.execute(agent_client).await,
+            SubCommands::Diff(d) => d.execute(agent_client).await,
+            SubCommands::Audit(a) => a.execute(agent_client).await,
+            SubCommands::Diagnostics(d) => d.execute(agent_client).await,
+            SubCommands::Bpf(b) => b.execute(agent_client).await,
+            SubCommands::Version(v) => v.execute(agent_client).await,
             // SubCommands::Image(i) => i.execute(agent_client).await,
         }
     }
@@ -0,0 +1,63 @@
+use clap::Parser;
+use comfy_table::Table;
+use tonic::transport::Channel;
+
+use agent_api::features;
+use agent_api::v1::agent_client::AgentClient;
+use agent_api::v1::GetAuditLogRequest;
+
+use crate::utils::{format_time, parse_time};
+use crate::version::require_feature;
+
+#[derive(Parser, Debug)]
+pub(crate) struct AuditCommand {
+    /// Optional: Only show the operations on this program.
+    #[clap(short, long)]
+    pub(crate) program: Option<String>,
+
+    /// Optional: Only show the operations since this time.
+    /// Format: seconds since the Unix epoch, or a duration ago such as 30m or 2h.
+    /// Example: --since 1d
+    #[clap(long, verbatim_doc_comment, value_parser = parse_time)]
+    pub(crate) since: Option<u64>,
+
+    /// Optional: Maximum number of operations to show, newest first.
+    #[clap(short, long, default_value = "100")]
+    pub(crate) limit: u32,
+}
+
+impl AuditCommand {
+    pub(crate) async fn execute(&self, agent_client: AgentClient<Channel>) -> anyhow::Result<()> {
+        let mut client = agent_client;
+        require_feature(&mut client, features::AUDIT_LOG).await?;
+        let request = GetAuditLogRequest {
+            program: self.program.clone().unwrap_or_default(),
+            since_ns: self.since.unwrap_or_default(),
+            limit: self.limit,
+        };
+        let response = client.get_audit_log(request).await?.into_inner();
+
+        let mut table = Table::new();
+        table.load_preset(comfy_table::presets::NOTHING);
+        table.set_header(vec![
+            "Time",
+            "Operation",
+            "Program",
+            "Caller",
+            "Outcome",
+            "Error",
+        ]);
+        for record in response.records {
+            table.add_row(vec![
+                format_time(record.timestamp_ns),
+                record.operation,
+                record.program,
+                record.caller,
+                if record.success { "ok" } else { "failed" }.to_string(),
+                record.error,
+            ]);
+        }
+        println!("{table}\n");
+        Ok(())
+    }
+}
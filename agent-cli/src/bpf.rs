@@ -0,0 +1,130 @@
+use clap::{Args, Subcommand};
+use comfy_table::Table;
+use tonic::transport::Channel;
+
+use agent_api::features;
+use agent_api::v1::agent_client::AgentClient;
+use agent_api::v1::{InspectProgramsRequest, ProgramInspection};
+
+use crate::version::require_feature;
+
+#[derive(Subcommand, Debug)]
+pub(crate) enum BpfCommand {
+    /// List the kernel programs of managed programs, with where they are attached.
+    List(BpfListArgs),
+    /// List the maps of managed programs.
+    Maps(BpfArgs),
+    /// List where the maps of managed programs are pinned.
+    Pins(BpfArgs),
+}
+
+#[derive(Args, Debug)]
+pub(crate) struct BpfArgs {
+    /// Optional: Only show this program, all of them by default.
+    pub(crate) name: Option<String>,
+}
+
+#[derive(Args, Debug)]
+pub(crate) struct BpfListArgs {
+    /// Optional: Only show this program, all of them by default.
+    pub(crate) name: Option<String>,
+
+    /// Optional: Count the runs of the kernel programs over this many seconds,
+    /// at most 60. Statistics are enabled in the kernel meanwhile.
+    /// Example: --stats 5
+    #[clap(long, verbatim_doc_comment, value_parser = clap::value_parser!(u32).range(1..=60))]
+    pub(crate) stats: Option<u32>,
+}
+
+impl BpfCommand {
+    pub(crate) async fn execute(&self, agent_client: AgentClient<Channel>) -> anyhow::Result<()> {
+        let (name, stats) = match self {
+            BpfCommand::List(args) => (&args.name, args.stats),
+            BpfCommand::Maps(args) | BpfCommand::Pins(args) => (&args.name, None),
+        };
+        let mut client = agent_client;
+        require_feature(&mut client, features::BPF_INTROSPECTION).await?;
+        let request = InspectProgramsRequest {
+            name: name.clone().unwrap_or_default(),
+            stats_window_ms: stats.unwrap_or_default() * 1000,
+        };
+        let response = client.inspect_programs(request).await?.into_inner();
+
+        let table = match self {
+            BpfCommand::List(_) => programs_table(&response.programs, stats.is_some()),
+            BpfCommand::Maps(_) => maps_table(&response.programs),
+            BpfCommand::Pins(_) => pins_table(&response.programs),
+        };
+        println!("{table}\n");
+        Ok(())
+    }
+}
+
+fn programs_table(inspections: &[ProgramInspection], stats: bool) -> Table {
+    let mut table = Table::new();
+    table.load_preset(comfy_table::presets::NOTHING);
+    let mut header = vec!["Program", "ID", "Name", "Type", "Attach Point", "Maps"];
+    if stats {
+        header.extend(["Runs", "Run Time (ns)", "Avg (ns)"]);
+    }
+    table.set_header(header);
+    for inspection in inspections {
+        for program in &inspection.programs {
+            let map_ids: Vec<_> = program.map_ids.iter().map(|id| id.to_string()).collect();
+            let mut row = vec![
+                inspection.name.clone(),
+                program.id.to_string(),
+                program.name.clone(),
+                program.program_type.clone(),
+                program.attach_point.clone(),
+                map_ids.join(","),
+            ];
+            if stats {
+                row.extend([
+                    program.run_cnt.to_string(),
+                    program.run_time_ns.to_string(),
+                    program
+                        .run_time_ns
+                        .checked_div(program.run_cnt)
+                        .unwrap_or_default()
+                        .to_string(),
+                ]);
+            }
+            table.add_row(row);
+        }
+    }
+    table
+}
+
+fn maps_table(inspections: &[ProgramInspection]) -> Table {
+    let mut table = Table::new();
+    table.load_preset(comfy_table::presets::NOTHING);
+    table.set_header(vec!["Program", "ID", "Name", "Owner ID"]);
+    for inspection in inspections {
+        for map in &inspection.maps {
+            table.add_row(vec![
+                inspection.name.clone(),
+                map.id.to_string(),
+                map.name.clone(),
+                map.owner_id.to_string(),
+            ]);
+        }
+    }
+    table
+}
+
+fn pins_table(inspections: &[ProgramInspection]) -> Table {
+    let mut table = Table::new();
+    table.load_preset(comfy_table::presets::NOTHING);
+    table.set_header(vec!["Program", "Map", "Pin Path"]);
+    for inspection in inspections {
+        for map in &inspection.maps {
+            table.add_row(vec![
+                inspection.name.clone(),
+                map.name.clone(),
+                map.pin_path.clone(),
+            ]);
+        }
+    }
+    table
+}
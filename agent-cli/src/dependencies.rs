@@ -0,0 +1,105 @@
+use std::time::Duration;
+
+use clap::Parser;
+use tonic::transport::Channel;
+use tonic::Code;
+
+use agent_api::features;
+use agent_api::v1::agent_client::AgentClient;
+use agent_api::v1::{DependencyChange, DependencyEvent, WatchDependenciesRequest};
+
+use crate::version::require_feature;
+
+/// Time waited before watching again once the agent became unreachable.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Parser, Debug)]
+pub(crate) struct WatchDependenciesCommand {
+    /// Optional: Print the recently retained changes before watching for new ones.
+    #[clap(long, verbatim_doc_comment)]
+    pub(crate) replay: bool,
+
+    /// Optional: Print the changes after the one with this sequence, as printed in the
+    /// first column, before watching for new ones. Resumes an earlier watch.
+    /// Example: --resume-after 42
+    #[clap(long, verbatim_doc_comment, conflicts_with = "replay")]
+    pub(crate) resume_after: Option<u64>,
+
+    /// Optional: Keep watching when the agent becomes unreachable, resuming after the
+    /// last change printed once it is back.
+    #[clap(long, verbatim_doc_comment)]
+    pub(crate) reconnect: bool,
+}
+
+impl WatchDependenciesCommand {
+    pub(crate) async fn execute(&self, agent_client: AgentClient<Channel>) -> anyhow::Result<()> {
+        let mut client = agent_client;
+        require_feature(&mut client, features::DEPENDENCY_STREAMING).await?;
+        if self.resume_after.is_some() || self.reconnect {
+            require_feature(&mut client, features::DEPENDENCY_RESUME).await?;
+        }
+        let mut request = WatchDependenciesRequest {
+            replay: self.replay,
+            resume_after: self.resume_after.unwrap_or_default(),
+        };
+
+        loop {
+            let status = match watch(&mut client, &request).await {
+                Ok(()) => return Ok(()),
+                Err((last, status)) => {
+                    request.resume_after = last.unwrap_or(request.resume_after);
+                    status
+                }
+            };
+            if !self.reconnect || status.code() != Code::Unavailable {
+                return Err(status.into());
+            }
+            eprintln!(
+                "Lost the agent ({}), resuming after change {}",
+                status.message(),
+                request.resume_after
+            );
+            tokio::time::sleep(RECONNECT_DELAY).await;
+        }
+    }
+}
+
+/// Prints the changes streamed for `request` until the stream ends. Fails with the
+/// sequence of the last change printed, if any.
+async fn watch(
+    client: &mut AgentClient<Channel>,
+    request: &WatchDependenciesRequest,
+) -> Result<(), (Option<u64>, tonic::Status)> {
+    let mut stream = client
+        .watch_dependencies(request.clone())
+        .await
+        .map_err(|status| (None, status))?
+        .into_inner();
+    let mut last = None;
+    loop {
+        match stream.message().await {
+            Ok(Some(event)) => {
+                print_event(&event);
+                last = Some(event.sequence);
+            }
+            Ok(None) => return Ok(()),
+            Err(status) => return Err((last, status)),
+        }
+    }
+}
+
+fn print_event(event: &DependencyEvent) {
+    let change = match DependencyChange::try_from(event.change) {
+        Ok(DependencyChange::Added) => "ADDED",
+        Ok(DependencyChange::Removed) => "REMOVED",
+        Err(_) => "UNKNOWN",
+    };
+    println!(
+        "{:<8} {:<20} {:<8} {} -> {}",
+        event.sequence,
+        event.timestamp_ns / 1_000_000_000,
+        change,
+        event.client_workload,
+        event.server_workload
+    );
+}
@@ -0,0 +1,41 @@
+use anyhow::bail;
+use clap::Parser;
+use tonic::transport::Channel;
+
+use agent_api::features;
+use agent_api::v1::agent_client::AgentClient;
+use agent_api::v1::GetLoadDiagnosticsRequest;
+
+use crate::utils::format_time;
+use crate::version::require_feature;
+
+#[derive(Parser, Debug)]
+pub(crate) struct DiagnosticsCommand {
+    /// Required: The name of the program whose last load to diagnose.
+    pub(crate) name: String,
+}
+
+impl DiagnosticsCommand {
+    pub(crate) async fn execute(&self, agent_client: AgentClient<Channel>) -> anyhow::Result<()> {
+        let mut client = agent_client;
+        require_feature(&mut client, features::LOAD_DIAGNOSTICS).await?;
+        let request = GetLoadDiagnosticsRequest {
+            name: self.name.clone(),
+        };
+        let response = client.get_load_diagnostics(request).await?.into_inner();
+        let Some(diagnostics) = response.diagnostics else {
+            println!(
+                "The last load of {} was not rejected by the verifier",
+                self.name
+            );
+            return Ok(());
+        };
+
+        println!("Time:          {}", format_time(diagnostics.timestamp_ns));
+        println!("eBPF program:  {}", diagnostics.ebpf_program);
+        println!("Error:         {}", diagnostics.error);
+        println!("Verifier log:\n{}", diagnostics.verifier_log);
+        // Fail the command, so that scripts can gate on it.
+        bail!("Program {} was rejected by the verifier", self.name)
+    }
+}
@@ -0,0 +1,90 @@
+use clap::Parser;
+use comfy_table::Table;
+use tonic::transport::Channel;
+
+use agent_api::features;
+use agent_api::v1::agent_client::AgentClient;
+use agent_api::v1::{DiffServiceMapRequest, EdgeChange, ServiceMapEdge};
+
+use crate::utils::{format_time, parse_time};
+use crate::version::require_feature;
+
+#[derive(Parser, Debug)]
+pub(crate) struct DiffCommand {
+    /// Required: The name of the program whose service map is compared.
+    pub(crate) name: String,
+
+    /// Required: Compare from the snapshot taken at or before this time.
+    /// Format: seconds since the Unix epoch, or a duration ago such as 30m or 2h.
+    /// Example: --from 1h
+    #[clap(long, verbatim_doc_comment, value_parser = parse_time)]
+    pub(crate) from: u64,
+
+    /// Optional: Compare to the snapshot taken at or before this time, now by default.
+    #[clap(long, verbatim_doc_comment, value_parser = parse_time)]
+    pub(crate) to: Option<u64>,
+
+    /// Optional: Smallest relative throughput change shown, e.g. 0.5 for 50%.
+    #[clap(long, verbatim_doc_comment, default_value_t = 0.5)]
+    pub(crate) min_change: f64,
+}
+
+impl DiffCommand {
+    pub(crate) async fn execute(&self, agent_client: AgentClient<Channel>) -> anyhow::Result<()> {
+        let mut client = agent_client;
+        require_feature(&mut client, features::SNAPSHOT_DIFF).await?;
+        let request = DiffServiceMapRequest {
+            name: self.name.clone(),
+            from_ns: self.from,
+            to_ns: self.to.unwrap_or_default(),
+            min_change: self.min_change,
+        };
+        let response = client.diff_service_map(request).await?.into_inner();
+
+        println!(
+            "Comparing {} with {}\n",
+            format_time(response.from_timestamp_ns),
+            format_time(response.to_timestamp_ns)
+        );
+        let mut table = Table::new();
+        table.load_preset(comfy_table::presets::NOTHING);
+        table.set_header(vec![
+            "Change",
+            "Client",
+            "Server",
+            "Port",
+            "Protocol",
+            "Throughput Before",
+            "Throughput After",
+        ]);
+        for diff in response.edges {
+            let change = match EdgeChange::try_from(diff.change) {
+                Ok(EdgeChange::Added) => "Added".to_string(),
+                Ok(EdgeChange::Removed) => "Removed".to_string(),
+                Ok(EdgeChange::Throughput) if diff.throughput_change.is_finite() => {
+                    format!("Throughput {:+.0}%", diff.throughput_change * 100.0)
+                }
+                Ok(EdgeChange::Throughput) => "Throughput, was idle".to_string(),
+                Err(_) => "Unknown".to_string(),
+            };
+            let Some(edge) = diff.after.as_ref().or(diff.before.as_ref()) else {
+                continue;
+            };
+            table.add_row(vec![
+                change,
+                edge.client_workload.clone(),
+                edge.server_workload.clone(),
+                edge.server_port.to_string(),
+                edge.protocol.clone(),
+                throughput(diff.before.as_ref()),
+                throughput(diff.after.as_ref()),
+            ]);
+        }
+        println!("{table}\n");
+        Ok(())
+    }
+}
+
+fn throughput(edge: Option<&ServiceMapEdge>) -> String {
+    edge.map_or("-".to_string(), |e| format!("{:.0} B/s", e.throughput_bps))
+}
@@ -0,0 +1,92 @@
+use std::fs;
+use std::path::PathBuf;
+
+use clap::{Parser, ValueEnum};
+use prost::Message;
+use serde_json::{json, Value};
+use tonic::transport::Channel;
+
+use agent_api::v1::agent_client::AgentClient;
+use agent_api::v1::{DumpMapsRequest, DumpMapsResponse};
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum DumpFormat {
+    /// Human readable JSON, with keys and values hex encoded.
+    Json,
+    /// The raw protobuf-encoded response, for replaying or diffing offline.
+    Capture,
+}
+
+#[derive(Parser, Debug)]
+pub(crate) struct DumpMapsCommand {
+    /// Required: The name of the program whose maps are dumped.
+    pub(crate) name: String,
+
+    /// Optional: Only dump the named map. Can be repeated. All maps are dumped by default.
+    #[clap(short, long = "map")]
+    pub(crate) maps: Vec<String>,
+
+    /// Optional: Output format.
+    #[clap(short, long, value_enum, default_value_t = DumpFormat::Json)]
+    pub(crate) format: DumpFormat,
+
+    /// Optional: File to write the dump to. Required for the capture format,
+    /// JSON is printed to stdout otherwise.
+    #[clap(short, long)]
+    pub(crate) output: Option<PathBuf>,
+}
+
+impl DumpMapsCommand {
+    pub(crate) async fn execute(&self, agent_client: AgentClient<Channel>) -> anyhow::Result<()> {
+        let mut client = agent_client;
+        let request = DumpMapsRequest {
+            name: self.name.clone(),
+            maps: self.maps.clone(),
+        };
+        let response = client.dump_maps(request).await?.into_inner();
+
+        match (self.format, &self.output) {
+            (DumpFormat::Json, None) => {
+                println!("{}", serde_json::to_string_pretty(&to_json(&response))?)
+            }
+            (DumpFormat::Json, Some(path)) => {
+                fs::write(path, serde_json::to_vec_pretty(&to_json(&response))?)?
+            }
+            (DumpFormat::Capture, Some(path)) => fs::write(path, response.encode_to_vec())?,
+            (DumpFormat::Capture, None) => {
+                return Err(anyhow::anyhow!(
+                    "--output is required for the capture format"
+                ))
+            }
+        }
+        Ok(())
+    }
+}
+
+fn to_json(response: &DumpMapsResponse) -> Value {
+    let maps: Vec<Value> = response
+        .maps
+        .iter()
+        .map(|map| {
+            let entries: Vec<Value> = map
+                .entries
+                .iter()
+                .map(|entry| {
+                    json!({
+                        "key": hex::encode(&entry.key),
+                        "value": hex::encode(&entry.value),
+                        "decoded": entry.decoded,
+                    })
+                })
+                .collect();
+            json!({
+                "name": map.name,
+                "prog_id": map.prog_id,
+                "key_type": map.key_type,
+                "value_type": map.value_type,
+                "entries": entries,
+            })
+        })
+        .collect();
+    json!({ "maps": maps })
+}
@@ -0,0 +1,65 @@
+use std::fs;
+use std::path::PathBuf;
+
+use clap::{Parser, ValueEnum};
+use tonic::transport::Channel;
+
+use agent_api::features;
+use agent_api::v1::agent_client::AgentClient;
+use agent_api::v1::{ExportGraphRequest, GraphFormat};
+
+use crate::version::require_feature;
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ExportFormat {
+    /// Graphviz DOT.
+    Dot,
+    /// GraphML, e.g. for Gephi or yEd.
+    Graphml,
+    /// A {nodes, links} JSON document for d3-force.
+    D3,
+}
+
+impl From<ExportFormat> for GraphFormat {
+    fn from(format: ExportFormat) -> Self {
+        match format {
+            ExportFormat::Dot => GraphFormat::Dot,
+            ExportFormat::Graphml => GraphFormat::Graphml,
+            ExportFormat::D3 => GraphFormat::D3Json,
+        }
+    }
+}
+
+#[derive(Parser, Debug)]
+pub(crate) struct ExportGraphCommand {
+    /// Optional: The name of the program whose service map is exported.
+    /// The maps of all loaded programs are merged by default.
+    #[clap(verbatim_doc_comment)]
+    pub(crate) name: Option<String>,
+
+    /// Optional: Output format.
+    #[clap(short, long, value_enum, default_value_t = ExportFormat::Dot)]
+    pub(crate) format: ExportFormat,
+
+    /// Optional: File to write the graph to. It is printed to stdout by default.
+    #[clap(short, long)]
+    pub(crate) output: Option<PathBuf>,
+}
+
+impl ExportGraphCommand {
+    pub(crate) async fn execute(&self, agent_client: AgentClient<Channel>) -> anyhow::Result<()> {
+        let mut client = agent_client;
+        require_feature(&mut client, features::GRAPH_EXPORT).await?;
+        let request = ExportGraphRequest {
+            name: self.name.clone().unwrap_or_default(),
+            format: GraphFormat::from(self.format) as i32,
+        };
+        let response = client.export_graph(request).await?.into_inner();
+
+        match &self.output {
+            Some(path) => fs::write(path, response.document)?,
+            None => print!("{}", response.document),
+        }
+        Ok(())
+    }
+}
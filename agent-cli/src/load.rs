@@ -2,10 +2,11 @@ use clap::{Args, Subcommand};
 use tonic::transport::Channel;
 
 use agent_api::v1::agent_client::AgentClient;
-use agent_api::v1::LoadRequest;
+use agent_api::v1::bytecode_location::Location;
+use agent_api::v1::{BytecodeLocation, LoadRequest};
 
 use crate::table::ProgTable;
-use crate::utils::parse_key_val;
+use crate::utils::{parse_key_val, status_error};
 
 #[derive(Subcommand, Debug)]
 pub(crate) enum LoadCommand {
@@ -30,6 +31,11 @@ pub(crate) struct LoadBuiltinArgs {
     #[clap(short, long)]
     pub(crate) name: String,
 
+    /// Optional: Local eBPF object of the program, run through the kernel verifier
+    /// before loading. A rejection fails the load, with the log of the verifier.
+    #[clap(short, long, verbatim_doc_comment)]
+    pub(crate) file: Option<String>,
+
     /// Optional: Specify Key/Value metadata to be attached to a program when it
     /// is loaded by agent.
     /// Format: <KEY>=<VALUE>
@@ -37,6 +43,10 @@ pub(crate) struct LoadBuiltinArgs {
     /// This can later be used to `list` a certain subset of programs which contain
     /// the specified metadata.
     /// Example: --metadata owner=acme
+    ///
+    /// The schedule and run_after_rollout keys only run the program within daily
+    /// windows, in UTC, and for some minutes after each deployment rollout.
+    /// Example: --metadata "schedule=02:00-03:00 14:00-14:30,run_after_rollout=10"
     #[clap(short, long, verbatim_doc_comment, value_parser=parse_key_val, value_delimiter = ',')]
     pub(crate) metadata: Option<Vec<(String, String)>>,
 
@@ -64,6 +74,10 @@ pub(crate) struct LoadWasmArgs {
     /// This can later be used to list a certain subset of programs which contain
     /// the specified metadata.
     /// Example: --metadata owner=acme
+    ///
+    /// The schedule and run_after_rollout keys only run the program within daily
+    /// windows, in UTC, and for some minutes after each deployment rollout.
+    /// Example: --metadata "schedule=02:00-03:00 14:00-14:30,run_after_rollout=10"
     #[clap(short, long, verbatim_doc_comment, value_parser=parse_key_val, value_delimiter = ',')]
     pub(crate) metadata: Option<Vec<(String, String)>>,
 
@@ -100,7 +114,9 @@ pub(crate) async fn execute_load_builtin(
     args: &LoadBuiltinArgs,
 ) -> anyhow::Result<()> {
     let request = tonic::Request::new(LoadRequest {
-        bytecode: None,
+        bytecode: args.file.clone().map(|file| BytecodeLocation {
+            location: Some(Location::File(file)),
+        }),
         name: args.name.clone(),
         program_type: 0,
         metadata: args
@@ -119,7 +135,11 @@ pub(crate) async fn execute_load_builtin(
             .collect(),
     });
 
-    let response = client.load(request).await?.into_inner();
+    let response = client
+        .load(request)
+        .await
+        .map_err(status_error)?
+        .into_inner();
     ProgTable::new_program(&response.info)?.print();
 
     Ok(())
@@ -2,12 +2,25 @@ use crate::args::AgentCli;
 use clap::Parser;
 
 mod args;
+mod audit;
+mod bpf;
+mod dependencies;
+mod diagnostics;
+mod diff;
+mod dump;
 mod get;
+mod graph;
 mod list;
 mod load;
+mod pause;
+mod requests;
+mod snapshots;
 mod table;
+mod trace_pod;
 mod unload;
 mod utils;
+mod validate;
+mod version;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
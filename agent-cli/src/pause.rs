@@ -0,0 +1,44 @@
+use clap::Parser;
+use tonic::transport::Channel;
+
+use agent_api::features;
+use agent_api::v1::agent_client::AgentClient;
+use agent_api::v1::{PauseProgramRequest, ResumeProgramRequest};
+
+use crate::version::require_feature;
+
+#[derive(Parser, Debug)]
+pub(crate) struct PauseCommand {
+    /// Required: The name of the program to pause.
+    pub(crate) name: String,
+}
+
+impl PauseCommand {
+    pub(crate) async fn execute(&self, agent_client: AgentClient<Channel>) -> anyhow::Result<()> {
+        let mut client = agent_client;
+        require_feature(&mut client, features::PROGRAM_PAUSE).await?;
+        let request = PauseProgramRequest {
+            name: self.name.clone(),
+        };
+        let _response = client.pause_program(request).await?.into_inner();
+        Ok(())
+    }
+}
+
+#[derive(Parser, Debug)]
+pub(crate) struct ResumeCommand {
+    /// Required: The name of the program to resume.
+    pub(crate) name: String,
+}
+
+impl ResumeCommand {
+    pub(crate) async fn execute(&self, agent_client: AgentClient<Channel>) -> anyhow::Result<()> {
+        let mut client = agent_client;
+        require_feature(&mut client, features::PROGRAM_PAUSE).await?;
+        let request = ResumeProgramRequest {
+            name: self.name.clone(),
+        };
+        let _response = client.resume_program(request).await?.into_inner();
+        Ok(())
+    }
+}
@@ -0,0 +1,124 @@
+use std::time::Duration;
+
+use clap::Parser;
+use comfy_table::Table;
+use tonic::transport::Channel;
+
+use agent_api::features;
+use agent_api::v1::agent_client::AgentClient;
+use agent_api::v1::GetRecentRequestsRequest;
+
+use crate::version::require_feature;
+
+#[derive(Parser, Debug)]
+pub(crate) struct RequestsCommand {
+    /// Optional: Only show requests from or to this workload.
+    /// Format: <NAMESPACE>/<NAME>
+    /// Example: --workload default/frontend
+    #[clap(short, long, verbatim_doc_comment)]
+    pub(crate) workload: Option<String>,
+
+    /// Optional: Only show requests with a status code of at least this value.
+    /// Example: --min-status 500
+    #[clap(long, verbatim_doc_comment, default_value_t = 0)]
+    pub(crate) min_status: u32,
+
+    /// Optional: Only show requests slower than the node's current latency p99.
+    #[clap(long, verbatim_doc_comment)]
+    pub(crate) slow: bool,
+
+    /// Optional: Only show the requests of this distributed trace.
+    /// Example: --trace-id 4bf92f3577b34da6a3ce929d0e0e4736
+    #[clap(long, verbatim_doc_comment)]
+    pub(crate) trace_id: Option<String>,
+
+    /// Optional: Only show the database operations slower than the slow query
+    /// threshold of the agent, with their redacted statement.
+    #[clap(long, verbatim_doc_comment)]
+    pub(crate) slow_queries: bool,
+
+    /// Optional: Show the headers kept on each request, as allowed by the
+    /// capture_headers metadata of the program that reported it.
+    #[clap(long, verbatim_doc_comment)]
+    pub(crate) headers: bool,
+
+    /// Optional: Maximum number of requests to show.
+    #[clap(short, long, verbatim_doc_comment, default_value_t = 100)]
+    pub(crate) limit: u32,
+}
+
+impl RequestsCommand {
+    pub(crate) async fn execute(&self, agent_client: AgentClient<Channel>) -> anyhow::Result<()> {
+        let mut client = agent_client;
+        require_feature(&mut client, features::REQUEST_EVENTS).await?;
+        if self.trace_id.is_some() {
+            require_feature(&mut client, features::TRACE_CONTEXT).await?;
+        }
+        if self.headers {
+            require_feature(&mut client, features::CAPTURE_ALLOWLIST).await?;
+        }
+        if self.slow_queries {
+            require_feature(&mut client, features::SLOW_QUERY_LOG).await?;
+        }
+        let request = GetRecentRequestsRequest {
+            workload: self.workload.clone().unwrap_or_default(),
+            min_status: self.min_status,
+            slower_than_p99: self.slow,
+            limit: self.limit,
+            trace_id: self.trace_id.clone().unwrap_or_default(),
+            slow_queries_only: self.slow_queries,
+        };
+        let response = client.get_recent_requests(request).await?.into_inner();
+
+        let mut table = Table::new();
+        table.load_preset(comfy_table::presets::NOTHING);
+        let mut header = vec![
+            "Source",
+            "Destination",
+            "Protocol",
+            "Method",
+            "Path",
+            "Status",
+            "Latency",
+            "Trace",
+        ];
+        if self.slow_queries {
+            header.push("Statement");
+        }
+        if self.headers {
+            header.push("Headers");
+        }
+        table.set_header(header);
+        for r in response.requests {
+            let mut row = vec![
+                r.src_workload,
+                r.dst_workload,
+                r.protocol,
+                r.method,
+                r.path,
+                r.status.to_string(),
+                format!("{:?}", Duration::from_nanos(r.latency_ns)),
+                r.trace_id,
+            ];
+            if self.slow_queries {
+                row.push(r.statement);
+            }
+            if self.headers {
+                let mut headers: Vec<_> = r
+                    .headers
+                    .iter()
+                    .map(|(name, value)| format!("{}: {}", name, value))
+                    .collect();
+                headers.sort();
+                row.push(headers.join("\n"));
+            }
+            table.add_row(row);
+        }
+        println!("{table}\n");
+        println!(
+            "p99 latency: {:?}",
+            Duration::from_nanos(response.p99_latency_ns)
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,156 @@
+use clap::Parser;
+use comfy_table::Table;
+use tonic::transport::Channel;
+
+use agent_api::features;
+use agent_api::v1::agent_client::AgentClient;
+use agent_api::v1::{
+    GetServiceMapAtRequest, GetServiceMapRangeRequest, ServiceMapSnapshot, WatchServiceMapRequest,
+};
+
+use crate::utils::{format_time, parse_time};
+use crate::version::require_feature;
+
+#[derive(Parser, Debug)]
+pub(crate) struct SnapshotsCommand {
+    /// Required: The name of the program whose service map history is queried.
+    pub(crate) name: String,
+
+    /// Optional: Show the snapshot taken at or before this time, now by default.
+    /// Format: seconds since the Unix epoch, or a duration ago such as 30m or 2h.
+    /// Example: --at 1h
+    #[clap(long, verbatim_doc_comment, value_parser = parse_time, conflicts_with = "from")]
+    pub(crate) at: Option<u64>,
+
+    /// Optional: List the snapshots taken since this time instead.
+    /// Example: --from 1h
+    #[clap(long, verbatim_doc_comment, value_parser = parse_time)]
+    pub(crate) from: Option<u64>,
+
+    /// Optional: End of the listed range, now by default.
+    #[clap(long, verbatim_doc_comment, value_parser = parse_time, requires = "from")]
+    pub(crate) to: Option<u64>,
+
+    /// Optional: Print the service map as it is now, then a line for each new snapshot
+    /// with the edges added, changed and removed since the previous one.
+    #[clap(long, verbatim_doc_comment, conflicts_with_all = ["at", "from"])]
+    pub(crate) watch: bool,
+}
+
+impl SnapshotsCommand {
+    pub(crate) async fn execute(&self, agent_client: AgentClient<Channel>) -> anyhow::Result<()> {
+        let mut client = agent_client;
+        require_feature(&mut client, features::SNAPSHOTS).await?;
+        if self.watch {
+            require_feature(&mut client, features::SERVICE_MAP_STREAMING).await?;
+            let request = WatchServiceMapRequest {
+                name: self.name.clone(),
+                resync_interval: 0,
+            };
+            let mut stream = client.watch_service_map(request).await?.into_inner();
+            while let Some(update) = stream.message().await? {
+                if update.full {
+                    print_edges(&ServiceMapSnapshot {
+                        timestamp_ns: update.timestamp_ns,
+                        edges: update.edges,
+                        snapshot_id: update.snapshot_id,
+                        checksum: update.checksum,
+                    });
+                    continue;
+                }
+                println!(
+                    "Snapshot {} taken at {}: {} edges added or changed, {} removed",
+                    update.snapshot_id,
+                    format_time(update.timestamp_ns),
+                    update.edges.len(),
+                    update.removed.len()
+                );
+            }
+            return Ok(());
+        }
+        if let Some(start_ns) = self.from {
+            let request = GetServiceMapRangeRequest {
+                name: self.name.clone(),
+                start_ns,
+                end_ns: self.to.unwrap_or_default(),
+            };
+            let response = client.get_service_map_range(request).await?.into_inner();
+            let mut table = Table::new();
+            table.load_preset(comfy_table::presets::NOTHING);
+            table.set_header(vec!["Id", "Timestamp", "Edges", "Bytes Sent", "Active"]);
+            for snapshot in response.snapshots {
+                table.add_row(vec![
+                    snapshot.snapshot_id.to_string(),
+                    format_time(snapshot.timestamp_ns),
+                    snapshot.edges.len().to_string(),
+                    snapshot
+                        .edges
+                        .iter()
+                        .map(|e| e.bytes_sent)
+                        .sum::<u64>()
+                        .to_string(),
+                    snapshot
+                        .edges
+                        .iter()
+                        .map(|e| e.active_connections)
+                        .sum::<u64>()
+                        .to_string(),
+                ]);
+            }
+            println!("{table}\n");
+            return Ok(());
+        }
+
+        let request = GetServiceMapAtRequest {
+            name: self.name.clone(),
+            timestamp_ns: self.at.unwrap_or(u64::MAX),
+        };
+        let snapshot = client
+            .get_service_map_at(request)
+            .await?
+            .into_inner()
+            .snapshot
+            .ok_or(anyhow::anyhow!(
+                "No snapshot of {} retained for that time",
+                self.name
+            ))?;
+        print_edges(&snapshot);
+        Ok(())
+    }
+}
+
+fn print_edges(snapshot: &ServiceMapSnapshot) {
+    println!(
+        "Snapshot {} taken at {}, checksum {:016x}\n",
+        snapshot.snapshot_id,
+        format_time(snapshot.timestamp_ns),
+        snapshot.checksum
+    );
+    let mut table = Table::new();
+    table.load_preset(comfy_table::presets::NOTHING);
+    table.set_header(vec![
+        "Client",
+        "Server",
+        "Port",
+        "Protocol",
+        "Bytes Sent",
+        "Bytes Received",
+        "Active",
+        "Resets",
+        "Throughput",
+    ]);
+    for edge in snapshot.edges.iter() {
+        table.add_row(vec![
+            edge.client_workload.clone(),
+            edge.server_workload.clone(),
+            edge.server_port.to_string(),
+            edge.protocol.clone(),
+            edge.bytes_sent.to_string(),
+            edge.bytes_received.to_string(),
+            edge.active_connections.to_string(),
+            edge.resets.to_string(),
+            format!("{:.0} B/s", edge.throughput_bps),
+        ]);
+    }
+    println!("{table}\n");
+}
@@ -76,6 +76,12 @@ impl ProgTable {
             ProgramState::Stopped => {
                 table.add_row(vec!["State:", "Stopped"]);
             }
+            ProgramState::Degraded => {
+                table.add_row(vec!["State:", "Degraded"]);
+            }
+            ProgramState::Paused => {
+                table.add_row(vec!["State:", "Paused"]);
+            }
         };
 
         if info.ebpf_maps.is_empty() {
@@ -108,6 +114,14 @@ impl ProgTable {
             }
         }
 
+        if let Some(diagnostics) = &info.load_diagnostics {
+            let data = &format!(
+                "{}: {} (see `diagnostics {}`)",
+                diagnostics.ebpf_program, diagnostics.error, info.name
+            );
+            table.add_row(vec!["Rejected:", data]);
+        }
+
         Ok(ProgTable(table))
     }
 
@@ -142,6 +156,8 @@ impl ProgTable {
             ProgramState::Running => "Running",
             ProgramState::Failed => "Failed",
             ProgramState::Stopped => "Stopped",
+            ProgramState::Degraded => "Degraded",
+            ProgramState::Paused => "Paused",
         };
 
         self.add_row_list(
@@ -0,0 +1,69 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use clap::Parser;
+use tonic::transport::Channel;
+
+use agent_api::features;
+use agent_api::v1::agent_client::AgentClient;
+use agent_api::v1::TracePodRequest;
+
+use crate::version::require_feature;
+
+#[derive(Parser, Debug)]
+pub(crate) struct TracePodCommand {
+    /// Required unless --stop: The pod to trace, which must run on this node.
+    /// Format: <NAMESPACE>/<NAME> or <NAME>
+    /// Example: default/frontend-6d8f7c9b5-x2k4q
+    #[clap(verbatim_doc_comment, required_unless_present = "stop")]
+    pub(crate) pod: Option<String>,
+
+    /// Optional: Namespace of the pod, when not given with its name.
+    #[clap(short, long, verbatim_doc_comment, default_value = "default")]
+    pub(crate) namespace: String,
+
+    /// Optional: Minutes after which tracing goes back to normal.
+    #[clap(short, long, verbatim_doc_comment, default_value_t = 5)]
+    pub(crate) minutes: u32,
+
+    /// Optional: Stop the running trace now rather than when it expires.
+    #[clap(long, verbatim_doc_comment, conflicts_with = "pod")]
+    pub(crate) stop: bool,
+}
+
+impl TracePodCommand {
+    pub(crate) async fn execute(&self, agent_client: AgentClient<Channel>) -> anyhow::Result<()> {
+        let mut client = agent_client;
+        require_feature(&mut client, features::POD_TRACING).await?;
+        let (namespace, pod) = match self.pod.as_deref().map(|pod| pod.split_once('/')) {
+            Some(Some((namespace, name))) => (namespace.to_string(), name.to_string()),
+            Some(None) => (self.namespace.clone(), self.pod.clone().unwrap_or_default()),
+            None => Default::default(),
+        };
+        let request = TracePodRequest {
+            namespace,
+            pod,
+            duration_secs: self.minutes.max(1).saturating_mul(60),
+        };
+        let response = client.trace_pod(request).await?.into_inner();
+        if self.stop {
+            println!("Stopped tracing");
+            return Ok(());
+        }
+
+        let expires_at = UNIX_EPOCH + Duration::from_nanos(response.expires_at_ns);
+        let remaining = expires_at
+            .duration_since(SystemTime::now())
+            .unwrap_or_default();
+        println!(
+            "Tracing the pod of {} (lowest process {}) for the next {}s",
+            response.workload,
+            response.pid,
+            remaining.as_secs()
+        );
+        println!(
+            "Its requests are all sampled: see them with `requests --workload {}`",
+            response.workload
+        );
+        Ok(())
+    }
+}
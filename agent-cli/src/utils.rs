@@ -1,5 +1,66 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use prost::Message;
+use tonic::Status;
+
+use agent_api::v1::ErrorDetail;
+
 /// Parse a single key-value pair
 pub(crate) fn parse_key_val(s: &str) -> Result<(String, String), std::io::Error> {
     let pos = s.find('=').ok_or(std::io::ErrorKind::InvalidInput)?;
     Ok((s[..pos].to_string(), s[pos + 1..].to_string()))
 }
+
+/// Parse a point in time, either as seconds since the Unix epoch or as a duration
+/// ago such as `90s`, `30m`, `2h` or `1d`, into nanoseconds since the Unix epoch.
+pub(crate) fn parse_time(s: &str) -> Result<u64, String> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|e| e.to_string())?;
+    let unit = match s.chars().last() {
+        Some('s') => 1,
+        Some('m') => 60,
+        Some('h') => 60 * 60,
+        Some('d') => 24 * 60 * 60,
+        _ => {
+            let secs = s.parse::<u64>().map_err(|e| e.to_string())?;
+            return Ok(Duration::from_secs(secs).as_nanos() as u64);
+        }
+    };
+    let ago = s[..s.len() - 1].parse::<u64>().map_err(|e| e.to_string())?;
+    Ok(now
+        .saturating_sub(Duration::from_secs(ago.saturating_mul(unit)))
+        .as_nanos() as u64)
+}
+
+/// Format nanoseconds since the Unix epoch as seconds, with how long ago that was.
+pub(crate) fn format_time(timestamp_ns: u64) -> String {
+    let at = UNIX_EPOCH + Duration::from_nanos(timestamp_ns);
+    match at.elapsed() {
+        Ok(ago) => format!("{} ({}s ago)", timestamp_ns / 1_000_000_000, ago.as_secs()),
+        Err(_) => (timestamp_ns / 1_000_000_000).to_string(),
+    }
+}
+
+/// Convert a status into an error, listing the metadata keys it details as rejected,
+/// or the log of the verifier that rejected an eBPF program.
+pub(crate) fn status_error(status: Status) -> anyhow::Error {
+    if let Ok(detail) = ErrorDetail::decode(status.details()) {
+        if !detail.verifier_log.is_empty() {
+            return anyhow::anyhow!(
+                "{}\nVerifier log:\n{}",
+                status.message(),
+                detail.verifier_log
+            );
+        }
+        if !detail.metadata_errors.is_empty() {
+            let errors: Vec<String> = detail
+                .metadata_errors
+                .iter()
+                .map(|e| format!("  {}: {}", e.key, e.message))
+                .collect();
+            return anyhow::anyhow!("Invalid metadata:\n{}", errors.join("\n"));
+        }
+    }
+    status.into()
+}
@@ -0,0 +1,102 @@
+use anyhow::bail;
+use clap::Parser;
+use comfy_table::Table;
+use tonic::transport::Channel;
+
+use agent_api::v1::agent_client::AgentClient;
+use agent_api::v1::bytecode_location::Location;
+use agent_api::v1::{BytecodeImage, BytecodeLocation, ValidateProgramRequest, ValidationStatus};
+use agent_api::{features, ImagePullPolicy};
+
+use crate::utils::parse_key_val;
+use crate::version::require_feature;
+
+#[derive(Parser, Debug)]
+pub(crate) struct ValidateCommand {
+    /// Required: The name of the program to validate.
+    #[clap(short, long)]
+    pub(crate) name: String,
+
+    /// Optional: Validate a wasm program rather than a builtin one.
+    #[clap(long)]
+    pub(crate) wasm: bool,
+
+    /// Optional: Local eBPF object or wasm module to verify.
+    #[clap(short, long, conflicts_with = "image_url")]
+    pub(crate) file: Option<String>,
+
+    /// Optional: Container Image URL of the bytecode to verify.
+    #[clap(short, long)]
+    pub(crate) image_url: Option<String>,
+
+    /// Optional: Key/Value metadata the program would be loaded with.
+    /// Format: <KEY>=<VALUE>
+    /// Example: --metadata interval=5
+    #[clap(short, long, verbatim_doc_comment, value_parser=parse_key_val, value_delimiter = ',')]
+    pub(crate) metadata: Option<Vec<(String, String)>>,
+
+    /// Optional: eBPF maps that the program would use.
+    /// Format: <MAP_NAME>=<PROG_NAME>
+    /// Example: --ebpf-maps my_map=my_prog
+    #[clap(short, long, verbatim_doc_comment, value_parser=parse_key_val, value_delimiter = ',')]
+    pub(crate) ebpf_maps: Option<Vec<(String, String)>>,
+}
+
+impl ValidateCommand {
+    pub(crate) async fn execute(&self, agent_client: AgentClient<Channel>) -> anyhow::Result<()> {
+        let mut client = agent_client;
+        require_feature(&mut client, features::PROGRAM_VALIDATION).await?;
+        let location = match (&self.file, &self.image_url) {
+            (Some(file), _) => Some(Location::File(file.clone())),
+            (None, Some(url)) => Some(Location::Image(BytecodeImage {
+                url: url.clone(),
+                image_pull_policy: ImagePullPolicy::IfNotPresent.into(),
+                username: None,
+                password: None,
+            })),
+            (None, None) => None,
+        };
+        let request = ValidateProgramRequest {
+            bytecode: location.map(|location| BytecodeLocation {
+                location: Some(location),
+            }),
+            name: self.name.clone(),
+            program_type: u32::from(self.wasm),
+            ebpf_maps: self
+                .ebpf_maps
+                .clone()
+                .unwrap_or_default()
+                .into_iter()
+                .collect(),
+            metadata: self
+                .metadata
+                .clone()
+                .unwrap_or_default()
+                .into_iter()
+                .collect(),
+        };
+        let response = client.validate_program(request).await?.into_inner();
+
+        let mut table = Table::new();
+        table.load_preset(comfy_table::presets::NOTHING);
+        table.set_header(vec!["Check", "Status", "Message"]);
+        for check in &response.checks {
+            let status = match check.status() {
+                ValidationStatus::Passed => "Passed",
+                ValidationStatus::Failed => "Failed",
+                ValidationStatus::Skipped => "Skipped",
+            };
+            table.add_row(vec![check.name.as_str(), status, check.message.as_str()]);
+        }
+        println!("{table}");
+        for error in &response.metadata_errors {
+            println!("  {}: {}", error.key, error.message);
+        }
+
+        // Fail the command, so that scripts can gate on it.
+        if !response.valid {
+            bail!("Program {} is not valid", self.name);
+        }
+        Ok(())
+    }
+}
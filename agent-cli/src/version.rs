@@ -0,0 +1,31 @@
+use clap::Parser;
+use tonic::transport::Channel;
+
+use agent_api::v1::agent_client::AgentClient;
+use agent_api::{api_version, is_compatible, API_VERSION};
+
+pub(crate) use agent_api::require_feature;
+
+#[derive(Parser, Debug)]
+pub(crate) struct VersionCommand {}
+
+impl VersionCommand {
+    pub(crate) async fn execute(&self, agent_client: AgentClient<Channel>) -> anyhow::Result<()> {
+        let mut client = agent_client;
+        println!("Client API version: {}", API_VERSION);
+        match api_version(&mut client).await? {
+            Some(version) => {
+                println!(
+                    "Agent API version:  {} (agent {})",
+                    version.api_version, version.agent_version
+                );
+                if !is_compatible(&version.api_version) {
+                    println!("The agent API is incompatible with this client.");
+                }
+                println!("Agent features:     {}", version.features.join(", "));
+            }
+            None => println!("Agent API version:  unknown, the agent predates API versioning"),
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,51 @@
+//! Cost of a service map poll, which aggregates every connection of the conn-tracer
+//! map into edges. Run with `cargo bench -p agent --features bench`.
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+
+use agent::bench::{PollFixture, Traffic};
+
+fn poll(c: &mut Criterion) {
+    let mut group = c.benchmark_group("poll");
+    // Large maps take long enough per poll that fewer samples are still precise.
+    group.sample_size(20);
+    for connections in [1_000, 10_000, 50_000] {
+        let traffic = Traffic {
+            connections,
+            workloads: 200,
+            closed: 0.0,
+        };
+        group.throughput(Throughput::Elements(u64::from(connections)));
+
+        // Steady state: every connection stays open, so each poll sees the same map.
+        let fixture = PollFixture::new(traffic);
+        fixture.poll().unwrap();
+        group.bench_with_input(
+            BenchmarkId::new("active", connections),
+            &fixture,
+            |b, fixture| b.iter(|| fixture.poll().unwrap()),
+        );
+
+        // Closed connections are moved out of the map by the poll seeing them, so
+        // every iteration starts from a fresh map.
+        let traffic = Traffic {
+            closed: 0.1,
+            ..traffic
+        };
+        group.bench_with_input(
+            BenchmarkId::new("churn", connections),
+            &traffic,
+            |b, traffic| {
+                b.iter_batched(
+                    || PollFixture::new(*traffic),
+                    |fixture| fixture.poll().unwrap(),
+                    BatchSize::LargeInput,
+                )
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, poll);
+criterion_main!(benches);
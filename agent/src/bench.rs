@@ -0,0 +1,98 @@
+//! Fixtures for the benchmarks in `benches/`, which only see the public API of the
+//! crate. Built with the `bench` feature.
+
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+
+use conn_tracer_common::{
+    ConnectionKey, ConnectionStats, CONNECTION_ROLE_CLIENT, PROTOCOL_HTTP, PROTOCOL_REDIS,
+};
+
+use crate::common::maps::MemoryMap;
+use crate::managers::cache::CacheManager;
+use crate::progs::service_map::program::ServiceMap;
+use crate::progs::types::Program;
+
+/// Shape of the synthetic connections a [`PollFixture`] is filled with.
+#[derive(Debug, Clone, Copy)]
+pub struct Traffic {
+    /// Connections in the map.
+    pub connections: u32,
+    /// Workloads the connections are spread over, each with an IP of its own.
+    pub workloads: u32,
+    /// Fraction of the connections that are closed, and moved out of the map by the
+    /// first poll.
+    pub closed: f64,
+}
+
+/// A service map polling an in-memory connections map filled with synthetic traffic,
+/// the workload of every address known to its cache.
+pub struct PollFixture {
+    service_map: ServiceMap,
+}
+
+impl PollFixture {
+    pub fn new(traffic: Traffic) -> Self {
+        let cache_mgr = CacheManager::empty();
+        for w in 0..traffic.workloads {
+            cache_mgr.insert_workload(
+                &workload_ip(w).to_string(),
+                &format!("workload-{}", w),
+                "bench",
+                "Deployment",
+            );
+        }
+
+        let mut conns = MemoryMap::default();
+        let closed_every = match traffic.closed {
+            closed if closed > 0.0 => (1.0 / closed).round() as u32,
+            _ => 0,
+        };
+        for id in 0..traffic.connections {
+            // Clients talk to the next few workloads, so that edges have several
+            // connections each.
+            let client = id % traffic.workloads;
+            let server = (client + 1 + id / traffic.workloads % 4) % traffic.workloads;
+            let key = ConnectionKey {
+                id,
+                pid: 1000 + client,
+                src_addr: workload_ip(client).into(),
+                src_port: 30000 + id % 30000,
+                dest_addr: workload_ip(server).into(),
+                dest_port: 8080,
+                role: CONNECTION_ROLE_CLIENT,
+            };
+            let closed = closed_every != 0 && id % closed_every == 0;
+            let stats = ConnectionStats {
+                bytes_sent: u64::from(id) * 1024,
+                bytes_received: u64::from(id) * 4096,
+                is_active: u64::from(!closed),
+                protocol: u64::from(if id % 3 == 0 {
+                    PROTOCOL_REDIS
+                } else {
+                    PROTOCOL_HTTP
+                }),
+                duration_ns: u64::from(id) * 1_000_000,
+                ..Default::default()
+            };
+            conns.insert(key, stats);
+        }
+
+        Self {
+            service_map: ServiceMap::with_connections(Box::new(conns), cache_mgr, HashMap::new()),
+        }
+    }
+
+    /// Aggregates the connections into edges and updates their metrics.
+    pub fn poll(&self) -> anyhow::Result<()> {
+        self.service_map.poll()
+    }
+
+    pub fn edges(&self) -> usize {
+        self.service_map.graph_edges().len()
+    }
+}
+
+fn workload_ip(workload: u32) -> Ipv4Addr {
+    Ipv4Addr::from(0x0a00_0000 + workload + 1)
+}
@@ -1,38 +1,224 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
 use std::fmt::Debug;
+use std::sync::Arc;
 
+use log::warn;
 use prometheus_client::collector::Collector as PrometheusCollector;
 use prometheus_client::encoding::DescriptorEncoder;
+use prometheus_client::registry::Registry;
 
+use crate::common::native_histogram::NativeHistogramSeries;
+use crate::managers::cache::CacheManager;
 use crate::managers::registry::RegistryManager;
+use crate::managers::scheduler::PollScheduler;
+use crate::progs::types::Program;
+use crate::server::push::PushStats;
 use agent_api::ProgramState;
 
-#[derive(Debug)]
+/// Builds the registry metrics are encoded from, on every scrape or push. Each running
+/// program gets its own sub-registry, so that the prefix and static labels set in its
+/// metadata apply to all of its metrics.
+#[derive(Debug, Clone)]
 pub(crate) struct Collector {
     registry_manager: RegistryManager,
+    scheduler: PollScheduler,
+    cache_manager: CacheManager,
+    push_stats: Option<PushStats>,
 }
 
 impl Collector {
-    pub(crate) fn new(registry_manager: RegistryManager) -> Self {
-        Self { registry_manager }
+    pub(crate) fn new(
+        registry_manager: RegistryManager,
+        scheduler: PollScheduler,
+        cache_manager: CacheManager,
+    ) -> Self {
+        Self {
+            registry_manager,
+            scheduler,
+            cache_manager,
+            push_stats: None,
+        }
     }
-}
 
-impl PrometheusCollector for Collector {
-    fn encode(&self, mut encoder: DescriptorEncoder) -> Result<(), std::fmt::Error> {
-        let progs = self.registry_manager.builtin.list();
+    /// Also exposes the metrics of the push to the server, when the agent pushes.
+    pub(crate) fn with_push_stats(mut self, push_stats: Option<PushStats>) -> Self {
+        self.push_stats = push_stats;
+        self
+    }
 
-        // 筛选出状态为 Running 的 progs
-        let running_progs: Vec<_> = progs
-            .iter()
-            .filter(|prog| prog.get_state() == ProgramState::Running)
-            .collect();
+    pub(crate) fn running_progs(&self) -> Vec<Arc<dyn Program>> {
+        self.registry_manager
+            .builtin
+            .list()
+            .into_iter()
+            .filter(|prog| {
+                matches!(
+                    prog.get_state(),
+                    ProgramState::Running | ProgramState::Degraded
+                )
+            })
+            .collect()
+    }
 
-        for prog in running_progs {
-            if let Err(e) = prog.collect(&mut encoder) {
-                eprintln!("Failed to collect metrics: {:?}", e);
+    pub(crate) fn registry(&self) -> Registry {
+        let mut registry = Registry::default();
+        registry.register_collector(Box::new(SchedulerCollector(self.scheduler.clone())));
+        registry.register_collector(Box::new(CacheCollector(self.cache_manager.clone())));
+        if let Some(push_stats) = &self.push_stats {
+            registry.register_collector(Box::new(PushCollector(push_stats.clone())));
+        }
+
+        for prog in self.running_progs() {
+            let scope = MetricScope::from_metadata(&prog.get_metadata());
+            let mut sub_registry = &mut registry;
+            if let Some(prefix) = scope.prefix {
+                sub_registry = sub_registry.sub_registry_with_prefix(prefix);
+            }
+            if !scope.labels.is_empty() {
+                sub_registry = sub_registry.sub_registry_with_labels(
+                    scope
+                        .labels
+                        .into_iter()
+                        .map(|(name, value)| (Cow::Owned(name), Cow::Owned(value))),
+                );
             }
+            sub_registry.register_collector(Box::new(ProgramCollector(prog)));
         }
+        registry
+    }
 
+    /// Gathers the native histograms of the running programs, scoped like the rest of
+    /// their metrics.
+    pub(crate) fn native_histograms(&self) -> Vec<NativeHistogramSeries> {
+        self.running_progs()
+            .iter()
+            .flat_map(|prog| {
+                let scope = MetricScope::from_metadata(&prog.get_metadata());
+                prog.native_histograms().into_iter().map(move |mut series| {
+                    if let Some(prefix) = &scope.prefix {
+                        series.name = format!("{}_{}", prefix, series.name);
+                    }
+                    series.labels.extend(scope.labels.iter().cloned());
+                    series
+                })
+            })
+            .collect()
+    }
+}
+
+/// Prefix and static labels applied to every metric of a program, so that metrics of
+/// several clusters can be aggregated without relabeling. Set in the program metadata
+/// as `metric_prefix` and `metric_labels`, the latter as `name=value` pairs separated
+/// by commas, e.g. `cluster=prod-eu,team=payments`.
+#[derive(Debug, Default)]
+struct MetricScope {
+    prefix: Option<String>,
+    labels: Vec<(String, String)>,
+}
+
+impl MetricScope {
+    fn from_metadata(metadata: &HashMap<String, String>) -> Self {
+        let prefix = metadata
+            .get("metric_prefix")
+            .map(|prefix| prefix.trim().trim_end_matches('_').to_string())
+            .filter(|prefix| !prefix.is_empty());
+        let labels = metadata
+            .get("metric_labels")
+            .map(|labels| {
+                labels
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|label| !label.is_empty())
+                    .filter_map(|label| match label.split_once('=') {
+                        Some((name, value)) if !name.trim().is_empty() => {
+                            Some((name.trim().to_string(), value.trim().to_string()))
+                        }
+                        _ => {
+                            warn!("Ignoring malformed metric label {:?}", label);
+                            None
+                        }
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        Self { prefix, labels }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct ProgramCollector(pub(crate) Arc<dyn Program>);
+
+impl PrometheusCollector for ProgramCollector {
+    fn encode(&self, mut encoder: DescriptorEncoder) -> Result<(), std::fmt::Error> {
+        if let Err(e) = self.0.collect(&mut encoder) {
+            eprintln!("Failed to collect metrics: {:?}", e);
+        }
         Ok(())
     }
 }
+
+#[derive(Debug)]
+struct SchedulerCollector(PollScheduler);
+
+impl PrometheusCollector for SchedulerCollector {
+    fn encode(&self, mut encoder: DescriptorEncoder) -> Result<(), std::fmt::Error> {
+        self.0.collect(&mut encoder)
+    }
+}
+
+#[derive(Debug)]
+struct CacheCollector(CacheManager);
+
+impl PrometheusCollector for CacheCollector {
+    fn encode(&self, mut encoder: DescriptorEncoder) -> Result<(), std::fmt::Error> {
+        self.0.collect(&mut encoder)
+    }
+}
+
+#[derive(Debug)]
+struct PushCollector(PushStats);
+
+impl PrometheusCollector for PushCollector {
+    fn encode(&self, mut encoder: DescriptorEncoder) -> Result<(), std::fmt::Error> {
+        self.0.collect(&mut encoder)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scope(metadata: &[(&str, &str)]) -> MetricScope {
+        MetricScope::from_metadata(
+            &metadata
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_metric_scope_from_metadata() {
+        let scope = scope(&[
+            ("metric_prefix", " eva_ "),
+            ("metric_labels", "cluster=prod-eu, team = payments,,bad, =x"),
+        ]);
+        assert_eq!(scope.prefix.as_deref(), Some("eva"));
+        assert_eq!(
+            scope.labels,
+            vec![
+                ("cluster".to_string(), "prod-eu".to_string()),
+                ("team".to_string(), "payments".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_metric_scope_without_metadata() {
+        let empty = scope(&[("metric_prefix", "_")]);
+        assert_eq!(empty.prefix, None);
+        assert!(empty.labels.is_empty());
+        assert!(scope(&[]).prefix.is_none());
+    }
+}
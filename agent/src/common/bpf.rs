@@ -0,0 +1,265 @@
+use std::ffi::CString;
+use std::io;
+use std::mem;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+const BPF_MAP_GET_NEXT_KEY: libc::c_long = 4;
+pub(crate) const BPF_OBJ_GET: libc::c_long = 7;
+pub(crate) const BPF_PROG_GET_FD_BY_ID: libc::c_long = 13;
+pub(crate) const BPF_OBJ_GET_INFO_BY_FD: libc::c_long = 15;
+pub(crate) const BPF_LINK_GET_FD_BY_ID: libc::c_long = 30;
+pub(crate) const BPF_LINK_GET_NEXT_ID: libc::c_long = 31;
+pub(crate) const BPF_ENABLE_STATS: libc::c_long = 32;
+/// `BPF_STATS_RUN_TIME`, the only statistics `BPF_ENABLE_STATS` enables.
+const BPF_STATS_RUN_TIME: u32 = 0;
+
+/// `union bpf_attr` as used by the `*_GET_NEXT_ID` and `*_GET_FD_BY_ID` commands.
+#[repr(C)]
+#[derive(Default)]
+pub(crate) struct GetIdAttr {
+    pub(crate) id: u32,
+    pub(crate) next_id: u32,
+    pub(crate) open_flags: u32,
+}
+
+/// `union bpf_attr` as used by `BPF_OBJ_GET_INFO_BY_FD`.
+#[repr(C)]
+pub(crate) struct GetInfoAttr {
+    pub(crate) bpf_fd: u32,
+    pub(crate) info_len: u32,
+    pub(crate) info: u64,
+}
+
+/// `union bpf_attr` as used by `BPF_OBJ_GET`.
+#[repr(C)]
+#[derive(Default)]
+struct ObjGetAttr {
+    pathname: u64,
+    bpf_fd: u32,
+    file_flags: u32,
+}
+
+/// The leading fields of `struct bpf_prog_info`, up to the run statistics.
+#[repr(C)]
+#[derive(Default)]
+struct ProgInfo {
+    prog_type: u32,
+    id: u32,
+    tag: [u8; 8],
+    jited_prog_len: u32,
+    xlated_prog_len: u32,
+    jited_prog_insns: u64,
+    xlated_prog_insns: u64,
+    load_time: u64,
+    created_by_uid: u32,
+    nr_map_ids: u32,
+    map_ids: u64,
+    name: [u8; 16],
+    ifindex: u32,
+    gpl_compatible: u32,
+    netns_dev: u64,
+    netns_ino: u64,
+    nr_jited_ksyms: u32,
+    nr_jited_func_lens: u32,
+    jited_ksyms: u64,
+    jited_func_lens: u64,
+    btf_id: u32,
+    func_info_rec_size: u32,
+    func_info: u64,
+    nr_func_info: u32,
+    nr_line_info: u32,
+    line_info: u64,
+    jited_line_info: u64,
+    nr_jited_line_info: u32,
+    line_info_rec_size: u32,
+    jited_line_info_rec_size: u32,
+    nr_prog_tags: u32,
+    prog_tags: u64,
+    run_time_ns: u64,
+    run_cnt: u64,
+}
+
+/// `union bpf_attr` as used by `BPF_MAP_GET_NEXT_KEY`.
+#[repr(C)]
+#[derive(Default)]
+struct NextKeyAttr {
+    map_fd: u32,
+    _pad: u32,
+    key: u64,
+    next_key: u64,
+}
+
+/// The leading fields of `struct bpf_map_info`, which are all that is needed.
+#[repr(C)]
+#[derive(Default)]
+struct MapInfo {
+    map_type: u32,
+    id: u32,
+    key_size: u32,
+    value_size: u32,
+    max_entries: u32,
+}
+
+/// `BPF_MAP_TYPE_HASH`, `BPF_MAP_TYPE_PERCPU_HASH`, `BPF_MAP_TYPE_LRU_HASH` and
+/// `BPF_MAP_TYPE_LRU_PERCPU_HASH`: the map types that fill up, unlike arrays.
+const HASH_MAP_TYPES: [u32; 4] = [1, 5, 9, 10];
+
+/// How full a hash map is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct MapUsage {
+    pub(crate) entries: u32,
+    pub(crate) max_entries: u32,
+}
+
+/// What the kernel reports of a loaded eBPF program.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct ProgramStats {
+    pub(crate) id: u32,
+    pub(crate) name: String,
+    /// `enum bpf_prog_type`.
+    pub(crate) program_type: u32,
+    pub(crate) map_ids: Vec<u32>,
+    /// Runs and time spent running, counted only while statistics are enabled.
+    pub(crate) run_cnt: u64,
+    pub(crate) run_time_ns: u64,
+}
+
+pub(crate) fn bpf<T>(cmd: libc::c_long, attr: &mut T) -> io::Result<libc::c_long> {
+    let ret = unsafe { libc::syscall(libc::SYS_bpf, cmd, attr as *mut T, mem::size_of::<T>()) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(ret)
+}
+
+/// Fills `info` with what the kernel reports of the object behind `fd`.
+fn obj_info<T>(fd: &OwnedFd, info: &mut T) -> io::Result<()> {
+    let mut attr = GetInfoAttr {
+        bpf_fd: fd.as_raw_fd() as u32,
+        info_len: mem::size_of::<T>() as u32,
+        info: info as *mut T as u64,
+    };
+    bpf(BPF_OBJ_GET_INFO_BY_FD, &mut attr)?;
+    Ok(())
+}
+
+/// Returns what the kernel reports of the program with the given id.
+pub(crate) fn program_stats(id: u32) -> io::Result<ProgramStats> {
+    let mut attr = GetIdAttr {
+        id,
+        ..Default::default()
+    };
+    let fd = unsafe { OwnedFd::from_raw_fd(bpf(BPF_PROG_GET_FD_BY_ID, &mut attr)? as i32) };
+
+    // The number of maps is only known from a first call.
+    let mut info = ProgInfo::default();
+    obj_info(&fd, &mut info)?;
+    let mut map_ids = vec![0u32; info.nr_map_ids as usize];
+    info = ProgInfo {
+        nr_map_ids: map_ids.len() as u32,
+        map_ids: map_ids.as_mut_ptr() as u64,
+        ..Default::default()
+    };
+    obj_info(&fd, &mut info)?;
+    map_ids.truncate(info.nr_map_ids as usize);
+
+    let name_len = info
+        .name
+        .iter()
+        .position(|&b| b == 0)
+        .unwrap_or(info.name.len());
+    Ok(ProgramStats {
+        id: info.id,
+        name: String::from_utf8_lossy(&info.name[..name_len]).into_owned(),
+        program_type: info.prog_type,
+        map_ids,
+        run_cnt: info.run_cnt,
+        run_time_ns: info.run_time_ns,
+    })
+}
+
+/// Returns the id of the map pinned at `path`.
+pub(crate) fn pinned_map_id(path: &Path) -> io::Result<u32> {
+    let pathname = CString::new(path.as_os_str().as_bytes())?;
+    let mut attr = ObjGetAttr {
+        pathname: pathname.as_ptr() as u64,
+        ..Default::default()
+    };
+    let fd = unsafe { OwnedFd::from_raw_fd(bpf(BPF_OBJ_GET, &mut attr)? as i32) };
+    let mut info = MapInfo::default();
+    obj_info(&fd, &mut info)?;
+    Ok(info.id)
+}
+
+/// Counts the entries of the hash map pinned at `path`, or returns `None` for the maps
+/// of other types. Entries are walked one key at a time, so this is only meant to be
+/// called every now and then.
+pub(crate) fn pinned_map_usage(path: &Path) -> io::Result<Option<MapUsage>> {
+    let pathname = CString::new(path.as_os_str().as_bytes())?;
+    let mut attr = ObjGetAttr {
+        pathname: pathname.as_ptr() as u64,
+        ..Default::default()
+    };
+    let fd = unsafe { OwnedFd::from_raw_fd(bpf(BPF_OBJ_GET, &mut attr)? as i32) };
+    let mut info = MapInfo::default();
+    obj_info(&fd, &mut info)?;
+    if !HASH_MAP_TYPES.contains(&info.map_type) {
+        return Ok(None);
+    }
+
+    let mut key = vec![0u8; info.key_size as usize];
+    let mut next_key = vec![0u8; info.key_size as usize];
+    let mut attr = NextKeyAttr {
+        map_fd: fd.as_raw_fd() as u32,
+        // A null key asks for the first one.
+        key: 0,
+        next_key: next_key.as_mut_ptr() as u64,
+        ..Default::default()
+    };
+    let mut entries = 0;
+    // Walks restart from the first key when the current one is deleted meanwhile.
+    while entries < info.max_entries {
+        match bpf(BPF_MAP_GET_NEXT_KEY, &mut attr) {
+            Ok(_) => entries += 1,
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => break,
+            Err(e) => return Err(e),
+        }
+        key.copy_from_slice(&next_key);
+        attr.key = key.as_ptr() as u64;
+    }
+    Ok(Some(MapUsage {
+        entries,
+        max_entries: info.max_entries,
+    }))
+}
+
+/// Enables the run statistics of every eBPF program, until the returned fd is closed.
+pub(crate) fn enable_stats() -> io::Result<OwnedFd> {
+    let mut attr = BPF_STATS_RUN_TIME;
+    let fd = bpf(BPF_ENABLE_STATS, &mut attr)?;
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as i32) })
+}
+
+/// Names an `enum bpf_prog_type` the way bpftool does.
+pub(crate) fn program_type_name(program_type: u32) -> String {
+    let name = match program_type {
+        1 => "socket_filter",
+        2 => "kprobe",
+        3 => "sched_cls",
+        4 => "sched_act",
+        5 => "tracepoint",
+        6 => "xdp",
+        7 => "perf_event",
+        8 => "cgroup_skb",
+        9 => "cgroup_sock",
+        13 => "sock_ops",
+        14 => "sk_skb",
+        17 => "raw_tracepoint",
+        26 => "tracing",
+        29 => "lsm",
+        _ => return program_type.to_string(),
+    };
+    name.to_string()
+}
@@ -3,6 +3,23 @@ pub mod directories {
     pub const RTDIR_MODE: u32 = 0o6770;
     pub const RTDIR: &str = "/run/eva";
     pub const RTPATH_AGENT_SOCKET: &str = "/run/eva/agent.sock";
+    /// Socket tracer settings put back once the running pod trace ends.
+    pub const RTPATH_POD_TRACE: &str = "/run/eva/pod-trace";
+    /// Engine API sockets looked for in standalone mode, Docker's first.
+    pub const CONTAINER_RUNTIME_SOCKETS: &[&str] =
+        &["/var/run/docker.sock", "/run/podman/podman.sock"];
 }
 
 pub const DEFAULT_INTERVAL: u64 = 15;
+pub const DEFAULT_EDGE_TTL: u64 = 300;
+pub const DEFAULT_DEPENDENCY_ABSENT_INTERVALS: u32 = 10;
+pub const DEFAULT_UNKNOWN_IP_RETRIES: u32 = 3;
+pub const DEFAULT_SNAPSHOT_WINDOW: u64 = 3600;
+pub const DEFAULT_SNAPSHOT_RETENTION: u64 = 86400;
+pub const DEFAULT_SNAPSHOT_COMPACTION: u64 = 300;
+pub const DEFAULT_HTTP_MAX_ROUTES: usize = 100;
+pub const DEFAULT_QUIC_IDLE_TIMEOUT: u64 = 60;
+pub const DEFAULT_CONTAINER_SYNC_INTERVAL: u64 = 10;
+pub const DEFAULT_HEALTH_CHECK_INTERVAL: u64 = 30;
+pub const DEFAULT_WINDOW_CHECK_INTERVAL: u64 = 15;
+pub const DEFAULT_FINDINGS_INTERVAL: u64 = 60;
@@ -0,0 +1,119 @@
+use prost::Message;
+use thiserror::Error;
+use tonic::{Code, Status};
+
+use agent_api::v1::{ErrorDetail, ErrorReason};
+use agent_api::ProgramState;
+
+use crate::progs::schema::MetadataErrors;
+
+/// Why a program could not be managed. Returned through the API as a status with the
+/// matching code, detailed by an [`ErrorDetail`] so that clients can tell the reasons
+/// apart without parsing messages.
+#[derive(Debug, Error)]
+pub(crate) enum AgentError {
+    #[error("Program {0} not found")]
+    ProgramNotFound(String),
+    #[error("Invalid program type {0}")]
+    InvalidProgramType(u32),
+    #[error(transparent)]
+    InvalidMetadata(#[from] MetadataErrors),
+    #[error("Program {program} is {state:?}, expected {expected}")]
+    InvalidState {
+        program: String,
+        state: ProgramState,
+        expected: &'static str,
+    },
+    #[error("Required eBPF program {0} not loaded")]
+    EbpfProgramNotLoaded(String),
+    #[error("Map {0} not found")]
+    MapNotFound(String),
+    #[error("Unsupported by the kernel: {0}")]
+    KernelUnsupported(String),
+    #[error("bpfman is unavailable: {0}")]
+    BpfmanUnavailable(String),
+    #[error("eBPF program {program} was rejected by the verifier: {error}")]
+    VerifierRejected {
+        program: String,
+        error: String,
+        verifier_log: String,
+    },
+    #[error(transparent)]
+    Internal(anyhow::Error),
+}
+
+impl AgentError {
+    fn code(&self) -> Code {
+        match self {
+            AgentError::ProgramNotFound(_) => Code::NotFound,
+            AgentError::InvalidProgramType(_)
+            | AgentError::InvalidMetadata(_)
+            | AgentError::VerifierRejected { .. } => Code::InvalidArgument,
+            AgentError::InvalidState { .. }
+            | AgentError::EbpfProgramNotLoaded(_)
+            | AgentError::MapNotFound(_) => Code::FailedPrecondition,
+            AgentError::KernelUnsupported(_) => Code::Unimplemented,
+            AgentError::BpfmanUnavailable(_) => Code::Unavailable,
+            AgentError::Internal(_) => Code::Internal,
+        }
+    }
+
+    fn detail(&self) -> ErrorDetail {
+        let (reason, subject) = match self {
+            AgentError::ProgramNotFound(name) => (ErrorReason::ProgramNotFound, name.clone()),
+            AgentError::InvalidProgramType(_) => (ErrorReason::InvalidProgramType, String::new()),
+            AgentError::InvalidMetadata(_) => (ErrorReason::InvalidMetadata, String::new()),
+            AgentError::InvalidState { program, .. } => {
+                (ErrorReason::InvalidState, program.clone())
+            }
+            AgentError::EbpfProgramNotLoaded(name) => {
+                (ErrorReason::EbpfProgramNotLoaded, name.clone())
+            }
+            AgentError::MapNotFound(name) => (ErrorReason::MapNotFound, name.clone()),
+            AgentError::KernelUnsupported(_) => (ErrorReason::KernelUnsupported, String::new()),
+            AgentError::BpfmanUnavailable(_) => (ErrorReason::BpfmanUnavailable, String::new()),
+            AgentError::VerifierRejected { program, .. } => {
+                (ErrorReason::VerifierRejected, program.clone())
+            }
+            AgentError::Internal(_) => (ErrorReason::Internal, String::new()),
+        };
+        let metadata_errors = match self {
+            AgentError::InvalidMetadata(errors) => errors.0.clone(),
+            _ => Vec::new(),
+        };
+        let verifier_log = match self {
+            AgentError::VerifierRejected { verifier_log, .. } => verifier_log.clone(),
+            _ => String::new(),
+        };
+        ErrorDetail {
+            reason: reason.into(),
+            subject,
+            metadata_errors,
+            verifier_log,
+        }
+    }
+}
+
+/// Keeps the reason of errors raised as an [`AgentError`] and passed on as
+/// `anyhow::Error`, e.g. by [`Program::init`](crate::progs::types::Program::init).
+impl From<anyhow::Error> for AgentError {
+    fn from(e: anyhow::Error) -> Self {
+        match e.downcast::<AgentError>() {
+            Ok(e) => e,
+            Err(e) => match e.downcast::<MetadataErrors>() {
+                Ok(errors) => AgentError::InvalidMetadata(errors),
+                Err(e) => AgentError::Internal(e),
+            },
+        }
+    }
+}
+
+impl From<AgentError> for Status {
+    fn from(e: AgentError) -> Self {
+        Status::with_details(
+            e.code(),
+            format!("{:#}", e),
+            e.detail().encode_to_vec().into(),
+        )
+    }
+}
@@ -0,0 +1,612 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::sync::Arc;
+
+use serde_json::json;
+
+use agent_api::v1::{
+    EdgeChange, EdgeDiff, GraphFormat, ServiceMapEdge, ServiceMapSnapshot, ServiceMapUpdate,
+};
+
+use crate::managers::cache::Workload;
+
+/// Relative throughput change reported by a diff when the request doesn't set one.
+const DEFAULT_MIN_CHANGE: f64 = 0.5;
+/// Edges sending less than this many bytes per second in both snapshots are too quiet
+/// for their throughput changes to be reported.
+const MIN_DIFF_THROUGHPUT: f64 = 1024.0;
+/// Updates between the full ones of a service map watch when the request doesn't set
+/// how many.
+const DEFAULT_RESYNC_INTERVAL: u32 = 20;
+
+/// A directed edge of the service map, from the client workload to the server one.
+#[derive(Debug, Clone)]
+pub(crate) struct GraphEdge {
+    pub(crate) client: Arc<Workload>,
+    pub(crate) server: Arc<Workload>,
+    pub(crate) server_port: u32,
+    pub(crate) protocol: &'static str,
+    pub(crate) bytes_sent: u64,
+    pub(crate) bytes_received: u64,
+    pub(crate) active_conns: u64,
+    pub(crate) resets: u64,
+    pub(crate) connect_timeouts: u64,
+}
+
+impl From<&GraphEdge> for ServiceMapEdge {
+    fn from(edge: &GraphEdge) -> Self {
+        Self {
+            client_workload: node_id(&edge.client),
+            server_workload: node_id(&edge.server),
+            server_port: edge.server_port,
+            protocol: edge.protocol.to_string(),
+            bytes_sent: edge.bytes_sent,
+            bytes_received: edge.bytes_received,
+            active_connections: edge.active_conns,
+            resets: edge.resets,
+            connect_timeouts: edge.connect_timeouts,
+            throughput_bps: 0.0,
+        }
+    }
+}
+
+type EdgeKey = (String, String, u32, &'static str);
+
+/// The workloads and edges of one or more service maps. Edges observed more than
+/// once, from both of their ends or by several programs, are summed.
+#[derive(Debug, Default)]
+pub(crate) struct Graph {
+    nodes: BTreeMap<String, Arc<Workload>>,
+    edges: BTreeMap<EdgeKey, GraphEdge>,
+}
+
+impl Graph {
+    pub(crate) fn extend(&mut self, edges: Vec<GraphEdge>) {
+        for edge in edges {
+            let client = node_id(&edge.client);
+            let server = node_id(&edge.server);
+            self.nodes
+                .entry(client.clone())
+                .or_insert_with(|| edge.client.clone());
+            self.nodes
+                .entry(server.clone())
+                .or_insert_with(|| edge.server.clone());
+
+            let key = (client, server, edge.server_port, edge.protocol);
+            match self.edges.get_mut(&key) {
+                Some(existing) => {
+                    existing.bytes_sent += edge.bytes_sent;
+                    existing.bytes_received += edge.bytes_received;
+                    existing.active_conns += edge.active_conns;
+                    existing.resets += edge.resets;
+                    existing.connect_timeouts += edge.connect_timeouts;
+                }
+                None => {
+                    self.edges.insert(key, edge);
+                }
+            }
+        }
+    }
+
+    /// The workloads of the graph, by node id.
+    pub(crate) fn nodes(&self) -> impl Iterator<Item = (&String, &Arc<Workload>)> {
+        self.nodes.iter()
+    }
+
+    /// The edges of the graph, sorted by client, server, server port and protocol.
+    pub(crate) fn edges(&self) -> impl Iterator<Item = &GraphEdge> {
+        self.edges.values()
+    }
+
+    pub(crate) fn render(&self, format: GraphFormat) -> String {
+        match format {
+            GraphFormat::Dot => self.to_dot(),
+            GraphFormat::Graphml => self.to_graphml(),
+            GraphFormat::D3Json => self.to_d3_json(),
+        }
+    }
+
+    fn to_dot(&self) -> String {
+        let mut out = String::from("digraph service_map {\n");
+        for (id, workload) in self.nodes.iter() {
+            let _ = writeln!(
+                out,
+                "  \"{}\" [label=\"{}\", namespace=\"{}\", kind=\"{}\"];",
+                dot_escape(id),
+                dot_escape(&workload.name),
+                dot_escape(&workload.namespace),
+                dot_escape(&workload.kind)
+            );
+        }
+        for ((client, server, port, protocol), edge) in self.edges.iter() {
+            let _ = writeln!(
+                out,
+                "  \"{}\" -> \"{}\" [label=\"{}:{}\", weight={}, bytes_sent={}, bytes_received={}, active_connections={}, resets={}, connect_timeouts={}];",
+                dot_escape(client),
+                dot_escape(server),
+                protocol,
+                port,
+                edge.bytes_sent,
+                edge.bytes_sent,
+                edge.bytes_received,
+                edge.active_conns,
+                edge.resets,
+                edge.connect_timeouts
+            );
+        }
+        out.push_str("}\n");
+        out
+    }
+
+    fn to_graphml(&self) -> String {
+        let mut out = String::from(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+             <graphml xmlns=\"http://graphml.graphdrawing.org/xmlns\">\n",
+        );
+        for (key, target, attr_type) in [
+            ("name", "node", "string"),
+            ("namespace", "node", "string"),
+            ("kind", "node", "string"),
+            ("port", "edge", "int"),
+            ("protocol", "edge", "string"),
+            ("weight", "edge", "long"),
+            ("bytes_sent", "edge", "long"),
+            ("bytes_received", "edge", "long"),
+            ("active_connections", "edge", "long"),
+            ("resets", "edge", "long"),
+            ("connect_timeouts", "edge", "long"),
+        ] {
+            let _ = writeln!(
+                out,
+                "  <key id=\"{}\" for=\"{}\" attr.name=\"{}\" attr.type=\"{}\"/>",
+                key, target, key, attr_type
+            );
+        }
+
+        out.push_str("  <graph id=\"service_map\" edgedefault=\"directed\">\n");
+        for (id, workload) in self.nodes.iter() {
+            let _ = writeln!(out, "    <node id=\"{}\">", xml_escape(id));
+            for (key, value) in [
+                ("name", &workload.name),
+                ("namespace", &workload.namespace),
+                ("kind", &workload.kind),
+            ] {
+                let _ = writeln!(
+                    out,
+                    "      <data key=\"{}\">{}</data>",
+                    key,
+                    xml_escape(value)
+                );
+            }
+            out.push_str("    </node>\n");
+        }
+        for ((client, server, port, protocol), edge) in self.edges.iter() {
+            let _ = writeln!(
+                out,
+                "    <edge source=\"{}\" target=\"{}\">",
+                xml_escape(client),
+                xml_escape(server)
+            );
+            for (key, value) in [
+                ("port", port.to_string()),
+                ("protocol", protocol.to_string()),
+                ("weight", edge.bytes_sent.to_string()),
+                ("bytes_sent", edge.bytes_sent.to_string()),
+                ("bytes_received", edge.bytes_received.to_string()),
+                ("active_connections", edge.active_conns.to_string()),
+                ("resets", edge.resets.to_string()),
+                ("connect_timeouts", edge.connect_timeouts.to_string()),
+            ] {
+                let _ = writeln!(out, "      <data key=\"{}\">{}</data>", key, value);
+            }
+            out.push_str("    </edge>\n");
+        }
+        out.push_str("  </graph>\n</graphml>\n");
+        out
+    }
+
+    /// Renders the `{nodes, links}` shape expected by d3-force, with each link's
+    /// `value` set to its weight.
+    fn to_d3_json(&self) -> String {
+        let nodes: Vec<_> = self
+            .nodes
+            .iter()
+            .map(|(id, workload)| {
+                json!({
+                    "id": id,
+                    "name": workload.name.as_str(),
+                    "namespace": workload.namespace.as_str(),
+                    "kind": workload.kind.as_str(),
+                })
+            })
+            .collect();
+        let links: Vec<_> = self
+            .edges
+            .iter()
+            .map(|((client, server, port, protocol), edge)| {
+                json!({
+                    "source": client,
+                    "target": server,
+                    "port": port,
+                    "protocol": protocol,
+                    "value": edge.bytes_sent,
+                    "bytes_sent": edge.bytes_sent,
+                    "bytes_received": edge.bytes_received,
+                    "active_connections": edge.active_conns,
+                    "resets": edge.resets,
+                    "connect_timeouts": edge.connect_timeouts,
+                })
+            })
+            .collect();
+        json!({ "nodes": nodes, "links": links }).to_string()
+    }
+}
+
+/// Compares two snapshots, returning the edges that appeared or disappeared, and those
+/// whose throughput changed by more than `min_change` relative to `before`.
+pub(crate) fn diff_snapshots(
+    before: &ServiceMapSnapshot,
+    after: &ServiceMapSnapshot,
+    min_change: f64,
+) -> Vec<EdgeDiff> {
+    let min_change = if min_change > 0.0 {
+        min_change
+    } else {
+        DEFAULT_MIN_CHANGE
+    };
+    let mut before_edges = by_key(before);
+    let after_edges = by_key(after);
+
+    let mut diffs = Vec::new();
+    for (key, after_edge) in after_edges {
+        let Some(before_edge) = before_edges.remove(&key) else {
+            diffs.push(EdgeDiff {
+                change: EdgeChange::Added as i32,
+                before: None,
+                after: Some(after_edge),
+                throughput_change: 0.0,
+            });
+            continue;
+        };
+
+        let (was, is) = (before_edge.throughput_bps, after_edge.throughput_bps);
+        if was.max(is) < MIN_DIFF_THROUGHPUT {
+            continue;
+        }
+        let throughput_change = if was > 0.0 {
+            (is - was) / was
+        } else {
+            f64::INFINITY
+        };
+        if throughput_change.abs() >= min_change {
+            diffs.push(EdgeDiff {
+                change: EdgeChange::Throughput as i32,
+                before: Some(before_edge),
+                after: Some(after_edge),
+                throughput_change,
+            });
+        }
+    }
+    diffs.extend(before_edges.into_values().map(|before_edge| EdgeDiff {
+        change: EdgeChange::Removed as i32,
+        before: Some(before_edge),
+        after: None,
+        throughput_change: 0.0,
+    }));
+    diffs
+}
+
+/// Turns the successive snapshots of a service map into the updates of a watcher. The
+/// first update is a full one, as is every `resync_interval`th, and the others only
+/// carry what changed since the previous update.
+#[derive(Debug)]
+pub(crate) struct ServiceMapWatch {
+    resync_interval: u32,
+    /// Last snapshot sent, and the updates sent since the last full one.
+    last: Option<ServiceMapSnapshot>,
+    since_full: u32,
+}
+
+impl ServiceMapWatch {
+    pub(crate) fn new(resync_interval: u32) -> Self {
+        Self {
+            resync_interval: if resync_interval > 0 {
+                resync_interval
+            } else {
+                DEFAULT_RESYNC_INTERVAL
+            },
+            last: None,
+            since_full: 0,
+        }
+    }
+
+    /// Makes the next update a full one.
+    pub(crate) fn resync(&mut self) {
+        self.last = None;
+    }
+
+    /// Returns the update bringing the watcher to `snapshot`, or `None` when it was
+    /// already sent.
+    pub(crate) fn update(&mut self, snapshot: ServiceMapSnapshot) -> Option<ServiceMapUpdate> {
+        let last_id = self.last.as_ref().map(|last| last.snapshot_id);
+        if last_id == Some(snapshot.snapshot_id) {
+            return None;
+        }
+        let mut update = ServiceMapUpdate {
+            snapshot_id: snapshot.snapshot_id,
+            timestamp_ns: snapshot.timestamp_ns,
+            checksum: snapshot.checksum,
+            ..Default::default()
+        };
+        match self.last.take() {
+            // Snapshots are numbered anew when the program is loaded again.
+            Some(last)
+                if self.since_full + 1 < self.resync_interval
+                    && last.snapshot_id < snapshot.snapshot_id =>
+            {
+                let mut before_edges = by_key(&last);
+                for (key, edge) in by_key(&snapshot) {
+                    if before_edges.remove(&key).as_ref() != Some(&edge) {
+                        update.edges.push(edge);
+                    }
+                }
+                update.removed = before_edges
+                    .into_values()
+                    .map(|edge| ServiceMapEdge {
+                        client_workload: edge.client_workload,
+                        server_workload: edge.server_workload,
+                        server_port: edge.server_port,
+                        protocol: edge.protocol,
+                        ..Default::default()
+                    })
+                    .collect();
+                self.since_full += 1;
+            }
+            _ => {
+                update.full = true;
+                update.edges = snapshot.edges.clone();
+                self.since_full = 0;
+            }
+        }
+        self.last = Some(snapshot);
+        Some(update)
+    }
+}
+
+/// Folds `next` into `update`, the update before it, so that sending the result
+/// brings a watcher to the same copy as sending both.
+pub(crate) fn coalesce_updates(update: &mut ServiceMapUpdate, next: ServiceMapUpdate) {
+    if next.full {
+        *update = next;
+        return;
+    }
+    let changed: BTreeSet<_> = next.edges.iter().map(snapshot_key).collect();
+    let removed: BTreeSet<_> = next.removed.iter().map(snapshot_key).collect();
+    update.edges.retain(|e| {
+        let key = snapshot_key(e);
+        !changed.contains(&key) && !removed.contains(&key)
+    });
+    update.edges.extend(next.edges);
+    // A full update has nothing to remove.
+    if !update.full {
+        update
+            .removed
+            .retain(|e| !changed.contains(&snapshot_key(e)));
+        update.removed.extend(next.removed);
+    }
+    update.snapshot_id = next.snapshot_id;
+    update.timestamp_ns = next.timestamp_ns;
+    update.checksum = next.checksum;
+}
+
+type SnapshotKey = (String, String, u32, String);
+
+fn snapshot_key(e: &ServiceMapEdge) -> SnapshotKey {
+    (
+        e.client_workload.clone(),
+        e.server_workload.clone(),
+        e.server_port,
+        e.protocol.clone(),
+    )
+}
+
+fn by_key(snapshot: &ServiceMapSnapshot) -> BTreeMap<SnapshotKey, ServiceMapEdge> {
+    snapshot
+        .edges
+        .iter()
+        .map(|e| (snapshot_key(e), e.clone()))
+        .collect()
+}
+
+pub(crate) fn node_id(workload: &Workload) -> String {
+    format!("{}/{}", workload.namespace, workload.name)
+}
+
+fn dot_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::managers::symbol::SymbolTable;
+
+    fn workload(symbols: &SymbolTable, name: &str) -> Arc<Workload> {
+        Arc::new(Workload {
+            name: symbols.intern(name),
+            namespace: symbols.intern("default"),
+            kind: symbols.intern("Deployment"),
+            labels: Vec::new(),
+        })
+    }
+
+    fn edge(symbols: &SymbolTable, client: &str, server: &str, bytes_sent: u64) -> GraphEdge {
+        GraphEdge {
+            client: workload(symbols, client),
+            server: workload(symbols, server),
+            server_port: 8080,
+            protocol: "http",
+            bytes_sent,
+            bytes_received: 10,
+            active_conns: 1,
+            resets: 0,
+            connect_timeouts: 0,
+        }
+    }
+
+    fn graph(symbols: &SymbolTable) -> Graph {
+        let mut graph = Graph::default();
+        graph.extend(vec![
+            edge(symbols, "web", "api", 100),
+            edge(symbols, "web", "api", 50),
+        ]);
+        graph.extend(vec![edge(symbols, "api", "db\"1", 7)]);
+        graph
+    }
+
+    #[test]
+    fn test_extend_sums_edges_observed_twice() {
+        let symbols = SymbolTable::default();
+        let graph = graph(&symbols);
+        let nodes: Vec<_> = graph.nodes().map(|(id, _)| id.as_str()).collect();
+        assert_eq!(nodes, vec!["default/api", "default/db\"1", "default/web"]);
+        let edges: Vec<_> = graph
+            .edges()
+            .map(|e| {
+                (
+                    node_id(&e.client),
+                    e.bytes_sent,
+                    e.bytes_received,
+                    e.active_conns,
+                )
+            })
+            .collect();
+        assert_eq!(
+            edges,
+            vec![
+                ("default/api".to_string(), 7, 10, 1),
+                ("default/web".to_string(), 150, 20, 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_render_dot() {
+        let symbols = SymbolTable::default();
+        let dot = graph(&symbols).render(GraphFormat::Dot);
+        assert!(dot.starts_with("digraph service_map {\n"));
+        assert!(dot.contains(
+            "  \"default/db\\\"1\" [label=\"db\\\"1\", namespace=\"default\", kind=\"Deployment\"];\n"
+        ));
+        assert!(dot.contains(
+            "  \"default/web\" -> \"default/api\" [label=\"http:8080\", weight=150, bytes_sent=150, bytes_received=20, active_connections=2, resets=0, connect_timeouts=0];\n"
+        ));
+        assert!(dot.ends_with("}\n"));
+    }
+
+    #[test]
+    fn test_render_graphml() {
+        let symbols = SymbolTable::default();
+        let graphml = graph(&symbols).render(GraphFormat::Graphml);
+        assert!(graphml.contains("<node id=\"default/db&quot;1\">"));
+        assert!(graphml.contains("<data key=\"name\">db&quot;1</data>"));
+        assert!(graphml.contains("<edge source=\"default/web\" target=\"default/api\">"));
+        assert!(graphml.contains("<data key=\"weight\">150</data>"));
+        assert_eq!(graphml.matches("<edge ").count(), 2);
+        assert!(graphml.ends_with("</graphml>\n"));
+    }
+
+    #[test]
+    fn test_render_d3_json() {
+        let symbols = SymbolTable::default();
+        let d3: serde_json::Value =
+            serde_json::from_str(&graph(&symbols).render(GraphFormat::D3Json)).unwrap();
+        assert_eq!(d3["nodes"].as_array().unwrap().len(), 3);
+        assert_eq!(
+            d3["nodes"][2],
+            json!({
+                "id": "default/web",
+                "name": "web",
+                "namespace": "default",
+                "kind": "Deployment",
+            })
+        );
+        let link = &d3["links"][1];
+        assert_eq!(link["source"], "default/web");
+        assert_eq!(link["target"], "default/api");
+        assert_eq!(link["value"], 150);
+        assert_eq!(link["bytes_received"], 20);
+    }
+
+    fn snapshot_edge(server: &str, throughput_bps: f64) -> ServiceMapEdge {
+        ServiceMapEdge {
+            client_workload: "default/web".to_string(),
+            server_workload: format!("default/{}", server),
+            server_port: 8080,
+            protocol: "http".to_string(),
+            throughput_bps,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_diff_snapshots() {
+        let before = ServiceMapSnapshot {
+            edges: vec![
+                snapshot_edge("api", 10_000.0),
+                snapshot_edge("cache", 10_000.0),
+                snapshot_edge("db", 100.0),
+                snapshot_edge("queue", 2_000.0),
+            ],
+            ..Default::default()
+        };
+        let after = ServiceMapSnapshot {
+            edges: vec![
+                snapshot_edge("api", 12_000.0),
+                snapshot_edge("cache", 30_000.0),
+                // Too quiet in both snapshots to be reported.
+                snapshot_edge("db", 900.0),
+                snapshot_edge("search", 1.0),
+            ],
+            ..Default::default()
+        };
+        let changes = |diffs: Vec<EdgeDiff>| -> Vec<(EdgeChange, String, f64)> {
+            diffs
+                .into_iter()
+                .map(|diff| {
+                    let edge = diff.after.as_ref().or(diff.before.as_ref()).unwrap();
+                    (
+                        diff.change(),
+                        edge.server_workload.clone(),
+                        diff.throughput_change,
+                    )
+                })
+                .collect()
+        };
+        assert_eq!(
+            changes(diff_snapshots(&before, &after, 0.0)),
+            vec![
+                (EdgeChange::Throughput, "default/cache".to_string(), 2.0),
+                (EdgeChange::Added, "default/search".to_string(), 0.0),
+                (EdgeChange::Removed, "default/queue".to_string(), 0.0),
+            ]
+        );
+        assert_eq!(
+            changes(diff_snapshots(&before, &after, 0.1)),
+            vec![
+                (EdgeChange::Throughput, "default/api".to_string(), 0.2),
+                (EdgeChange::Throughput, "default/cache".to_string(), 2.0),
+                (EdgeChange::Added, "default/search".to_string(), 0.0),
+                (EdgeChange::Removed, "default/queue".to_string(), 0.0),
+            ]
+        );
+    }
+}
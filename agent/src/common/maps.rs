@@ -0,0 +1,143 @@
+#[cfg(any(test, feature = "bench"))]
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Debug;
+use std::io;
+
+use anyhow::Error;
+use aya::maps::{HashMap as AyaHashMap, MapData};
+use aya::Pod;
+
+#[cfg(any(test, feature = "bench"))]
+use crate::common::utils::pod_bytes;
+
+/// The operations programs perform on the BPF hash maps they read, so that they can be
+/// tested against in-memory fixtures instead of pinned kernel maps.
+pub(crate) trait MapAccess<K, V>: Debug + Send + Sync {
+    /// Returns all entries of the map. Entries removed by the kernel while iterating
+    /// may make the iteration fail.
+    fn entries(&self) -> Result<Vec<(K, V)>, Error>;
+
+    fn get(&self, key: &K) -> Option<V>;
+
+    fn insert(&mut self, key: &K, value: &V) -> Result<(), Error>;
+
+    /// Removes a key from the map. Removing a key the map no longer holds, such as one
+    /// the kernel evicted from an LRU map since it was read, succeeds.
+    fn remove(&mut self, key: &K) -> Result<(), Error>;
+}
+
+impl<K, V> MapAccess<K, V> for AyaHashMap<MapData, K, V>
+where
+    K: Pod + Debug + Send + Sync,
+    V: Pod + Debug + Send + Sync,
+{
+    fn entries(&self) -> Result<Vec<(K, V)>, Error> {
+        self.iter().map(|item| item.map_err(Error::from)).collect()
+    }
+
+    fn get(&self, key: &K) -> Option<V> {
+        AyaHashMap::get(self, key, 0).ok()
+    }
+
+    fn insert(&mut self, key: &K, value: &V) -> Result<(), Error> {
+        Ok(AyaHashMap::insert(self, key, value, 0)?)
+    }
+
+    fn remove(&mut self, key: &K) -> Result<(), Error> {
+        match AyaHashMap::remove(self, key).map_err(Error::from) {
+            Err(e) if is_not_found(&e) => Ok(()),
+            result => result,
+        }
+    }
+}
+
+/// Whether a map operation failed for a key the map doesn't hold.
+fn is_not_found(e: &Error) -> bool {
+    e.chain()
+        .filter_map(|e| e.downcast_ref::<io::Error>())
+        .any(|e| e.raw_os_error() == Some(libc::ENOENT))
+}
+
+/// An in-memory [`MapAccess`], keeping the insertion order of its entries.
+#[cfg(any(test, feature = "bench"))]
+#[derive(Debug)]
+pub(crate) struct MemoryMap<K, V> {
+    /// Entries by insertion order.
+    entries: BTreeMap<u64, (K, V)>,
+    /// Insertion order of each key, by its bytes.
+    index: HashMap<Vec<u8>, u64>,
+    next: u64,
+}
+
+#[cfg(any(test, feature = "bench"))]
+impl<K, V> Default for MemoryMap<K, V> {
+    fn default() -> Self {
+        Self {
+            entries: BTreeMap::new(),
+            index: HashMap::new(),
+            next: 0,
+        }
+    }
+}
+
+#[cfg(any(test, feature = "bench"))]
+impl<K: Pod, V: Pod> MemoryMap<K, V> {
+    pub(crate) fn insert(&mut self, key: K, value: V) {
+        if let Some(order) = self.index.insert(pod_bytes(&key), self.next) {
+            self.entries.remove(&order);
+        }
+        self.entries.insert(self.next, (key, value));
+        self.next += 1;
+    }
+}
+
+#[cfg(any(test, feature = "bench"))]
+impl<K, V> MapAccess<K, V> for MemoryMap<K, V>
+where
+    K: Pod + Debug + Send + Sync,
+    V: Pod + Debug + Send + Sync,
+{
+    fn entries(&self) -> Result<Vec<(K, V)>, Error> {
+        Ok(self.entries.values().copied().collect())
+    }
+
+    fn get(&self, key: &K) -> Option<V> {
+        let order = self.index.get(&pod_bytes(key))?;
+        self.entries.get(order).map(|(_, v)| *v)
+    }
+
+    fn insert(&mut self, key: &K, value: &V) -> Result<(), Error> {
+        MemoryMap::insert(self, *key, *value);
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &K) -> Result<(), Error> {
+        if let Some(order) = self.index.remove(&pod_bytes(key)) {
+            self.entries.remove(&order);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_removing_missing_keys_succeeds() {
+        let mut map = MemoryMap::<u32, u64>::default();
+        map.insert(1, 10);
+        assert!(MapAccess::remove(&mut map, &1).is_ok());
+        assert!(MapAccess::remove(&mut map, &1).is_ok());
+        assert!(map.entries().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_is_not_found() {
+        let missing = Error::from(io::Error::from_raw_os_error(libc::ENOENT));
+        assert!(is_not_found(&missing.context("bpf_map_delete_elem failed")));
+        let denied = Error::from(io::Error::from_raw_os_error(libc::EPERM));
+        assert!(!is_not_found(&denied));
+        assert!(!is_not_found(&Error::msg("Key not found")));
+    }
+}
@@ -1,3 +1,8 @@
+pub(crate) mod bpf;
 pub(crate) mod constants;
+pub(crate) mod errors;
+pub(crate) mod graph;
+pub(crate) mod maps;
+pub(crate) mod native_histogram;
 pub(crate) mod types;
 pub(crate) mod utils;
@@ -0,0 +1,280 @@
+use std::collections::BTreeMap;
+
+use clap::ValueEnum;
+
+/// Resolution histograms start at: each bucket is 2^(1/8), about 9%, wider than the
+/// previous one.
+const INITIAL_SCHEMA: i32 = 3;
+const MIN_SCHEMA: i32 = -4;
+/// Resolution is halved whenever an observation would need more buckets than this.
+const MAX_BUCKETS: usize = 160;
+/// Observations at or below this, in the unit of the histogram, go to the zero bucket.
+const ZERO_THRESHOLD: f64 = 1e-9;
+
+/// Which kind of histogram an exporter sends for latency data.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum HistogramMode {
+    /// Classic histograms with fixed buckets, one series per bucket.
+    #[default]
+    Classic,
+    /// Native histograms with sparse exponential buckets, one series per histogram.
+    Native,
+    /// Both, e.g. while migrating dashboards.
+    Both,
+}
+
+impl HistogramMode {
+    pub(crate) fn classic(&self) -> bool {
+        *self != HistogramMode::Native
+    }
+
+    pub(crate) fn native(&self) -> bool {
+        *self != HistogramMode::Classic
+    }
+}
+
+/// A sparse histogram with exponential buckets, as defined by Prometheus native
+/// histograms. Bucket `i` holds observations in `(base^(i-1), base^i]` where
+/// `base = 2^(2^-schema)`. Only positive observations are expected.
+#[derive(Debug, Clone)]
+pub(crate) struct NativeHistogram {
+    schema: i32,
+    count: u64,
+    sum: f64,
+    zero_count: u64,
+    buckets: BTreeMap<i32, u64>,
+}
+
+impl Default for NativeHistogram {
+    fn default() -> Self {
+        Self {
+            schema: INITIAL_SCHEMA,
+            count: 0,
+            sum: 0.0,
+            zero_count: 0,
+            buckets: BTreeMap::new(),
+        }
+    }
+}
+
+/// A run of `length` consecutive buckets, starting `offset` buckets after the end of
+/// the previous span, or at bucket `offset` for the first one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct BucketSpan {
+    pub(crate) offset: i32,
+    pub(crate) length: u32,
+}
+
+impl NativeHistogram {
+    pub(crate) fn observe(&mut self, value: f64) {
+        self.count += 1;
+        self.sum += value;
+        if value <= ZERO_THRESHOLD {
+            self.zero_count += 1;
+            return;
+        }
+
+        *self
+            .buckets
+            .entry(bucket_index(value, self.schema))
+            .or_default() += 1;
+        while self.buckets.len() > MAX_BUCKETS && self.schema > MIN_SCHEMA {
+            self.downscale();
+        }
+    }
+
+    /// Halves the resolution by merging every pair of neighbouring buckets.
+    fn downscale(&mut self) {
+        let mut merged = BTreeMap::new();
+        for (index, count) in std::mem::take(&mut self.buckets) {
+            *merged.entry((index + 1).div_euclid(2)).or_default() += count;
+        }
+        self.buckets = merged;
+        self.schema -= 1;
+    }
+
+    pub(crate) fn schema(&self) -> i32 {
+        self.schema
+    }
+
+    pub(crate) fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub(crate) fn sum(&self) -> f64 {
+        self.sum
+    }
+
+    pub(crate) fn zero_threshold(&self) -> f64 {
+        ZERO_THRESHOLD
+    }
+
+    pub(crate) fn zero_count(&self) -> u64 {
+        self.zero_count
+    }
+
+    /// Returns the populated buckets as spans, and their counts, each as the delta to
+    /// the previous bucket's count.
+    pub(crate) fn positive_buckets(&self) -> (Vec<BucketSpan>, Vec<i64>) {
+        let mut spans: Vec<BucketSpan> = Vec::new();
+        let mut deltas = Vec::with_capacity(self.buckets.len());
+        let mut next_index = None;
+        let mut previous_count = 0i64;
+        for (&index, &count) in self.buckets.iter() {
+            match next_index {
+                Some(next) if next == index => {
+                    if let Some(span) = spans.last_mut() {
+                        span.length += 1;
+                    }
+                }
+                Some(next) => spans.push(BucketSpan {
+                    offset: index - next,
+                    length: 1,
+                }),
+                None => spans.push(BucketSpan {
+                    offset: index,
+                    length: 1,
+                }),
+            }
+            next_index = Some(index + 1);
+            deltas.push(count as i64 - previous_count);
+            previous_count = count as i64;
+        }
+        (spans, deltas)
+    }
+}
+
+fn bucket_index(value: f64, schema: i32) -> i32 {
+    (value.log2() * 2f64.powi(schema)).ceil() as i32
+}
+
+/// A native histogram along with the metric name and labels it is exported under.
+#[derive(Debug, Clone)]
+pub(crate) struct NativeHistogramSeries {
+    pub(crate) name: String,
+    pub(crate) help: String,
+    pub(crate) labels: Vec<(String, String)>,
+    pub(crate) histogram: NativeHistogram,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket_index() {
+        // At schema 0 each bucket is twice as wide as the previous one, bucket `i`
+        // holding (2^(i-1), 2^i].
+        assert_eq!(bucket_index(1.0, 0), 0);
+        assert_eq!(bucket_index(1.5, 0), 1);
+        assert_eq!(bucket_index(2.0, 0), 1);
+        assert_eq!(bucket_index(2.5, 0), 2);
+        assert_eq!(bucket_index(0.25, 0), -2);
+        assert_eq!(bucket_index(0.3, 0), -1);
+        // At schema 3 there are 8 buckets per power of two.
+        assert_eq!(bucket_index(1.0, 3), 0);
+        assert_eq!(bucket_index(2.0, 3), 8);
+        assert_eq!(bucket_index(1.05, 3), 1);
+        assert_eq!(bucket_index(0.5, 3), -8);
+        // At negative schemas a bucket spans several powers of two.
+        assert_eq!(bucket_index(4.0, -1), 1);
+        assert_eq!(bucket_index(5.0, -1), 2);
+        assert_eq!(bucket_index(16.0, -1), 2);
+    }
+
+    #[test]
+    fn test_observe() {
+        let mut histogram = NativeHistogram::default();
+        histogram.observe(0.0);
+        histogram.observe(1.0);
+        histogram.observe(1.0);
+        histogram.observe(2.0);
+        histogram.observe(8.0);
+        assert_eq!(histogram.schema(), INITIAL_SCHEMA);
+        assert_eq!(histogram.count(), 5);
+        assert_eq!(histogram.sum(), 12.0);
+        assert_eq!(histogram.zero_count(), 1);
+
+        let (spans, deltas) = histogram.positive_buckets();
+        // Buckets 0, 8 and 24 at schema 3.
+        assert_eq!(
+            spans,
+            vec![
+                BucketSpan {
+                    offset: 0,
+                    length: 1,
+                },
+                BucketSpan {
+                    offset: 7,
+                    length: 1,
+                },
+                BucketSpan {
+                    offset: 15,
+                    length: 1,
+                },
+            ]
+        );
+        assert_eq!(deltas, vec![2, -1, 0]);
+    }
+
+    #[test]
+    fn test_downscale_merges_bucket_pairs() {
+        let mut histogram = NativeHistogram::default();
+        let values = [0.3, 0.5, 1.0, 1.5, 2.0, 3.0, 4.0, 100.0];
+        for value in values {
+            histogram.observe(value);
+        }
+        histogram.downscale();
+        assert_eq!(histogram.schema(), INITIAL_SCHEMA - 1);
+        assert_eq!(histogram.count(), values.len() as u64);
+
+        // Every observation lands in the bucket it would have at the lower schema.
+        let mut expected = BTreeMap::new();
+        for value in values {
+            *expected
+                .entry(bucket_index(value, INITIAL_SCHEMA - 1))
+                .or_insert(0) += 1;
+        }
+        assert_eq!(histogram.buckets, expected);
+    }
+
+    #[test]
+    fn test_observe_downscales_past_max_buckets() {
+        let mut histogram = NativeHistogram::default();
+        // Each value lands in its own bucket at the initial schema.
+        let values: Vec<f64> = (0..=MAX_BUCKETS as i32)
+            .map(|i| 2f64.powf(i as f64 / 8.0) * 1.01)
+            .collect();
+        for value in &values {
+            histogram.observe(*value);
+        }
+        assert_eq!(histogram.schema(), INITIAL_SCHEMA - 1);
+        assert!(histogram.buckets.len() <= MAX_BUCKETS);
+        assert_eq!(histogram.buckets.values().sum::<u64>(), values.len() as u64);
+        for value in &values {
+            let index = bucket_index(*value, histogram.schema());
+            assert!(histogram.buckets.contains_key(&index));
+        }
+    }
+
+    #[test]
+    fn test_downscale_stops_at_min_schema() {
+        let mut histogram = NativeHistogram::default();
+        for exponent in -1000..1000 {
+            histogram.observe(2f64.powi(exponent));
+        }
+        assert_eq!(histogram.count(), 2000);
+        assert!(histogram.schema() >= MIN_SCHEMA);
+        assert!(histogram.buckets.len() <= MAX_BUCKETS);
+
+        // Past the lowest resolution, buckets are kept rather than merged.
+        let mut histogram = NativeHistogram {
+            schema: MIN_SCHEMA,
+            buckets: (0..=MAX_BUCKETS as i32).map(|i| (i, 1)).collect(),
+            ..Default::default()
+        };
+        histogram.observe(1.0);
+        assert_eq!(histogram.schema(), MIN_SCHEMA);
+        assert_eq!(histogram.buckets.len(), MAX_BUCKETS + 1);
+    }
+}
@@ -1,6 +1,8 @@
 use std::hash::Hasher;
 use std::result::Result;
+use std::{mem, slice};
 
+use aya::Pod;
 use bytes::Bytes;
 use fnv::FnvHasher;
 use http_body_util::Empty;
@@ -46,3 +48,18 @@ pub fn fnv_hash(s: &str) -> u32 {
     hasher.write(s.as_bytes());
     hasher.finish() as u32
 }
+
+/// Returns the in-memory representation of a map key or value, as the kernel stores it.
+pub fn pod_bytes<T: Pod>(value: &T) -> Vec<u8> {
+    unsafe { slice::from_raw_parts(value as *const T as *const u8, mem::size_of::<T>()) }.to_vec()
+}
+
+/// Days from 1970-01-01 to a date of the proleptic Gregorian calendar.
+pub fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
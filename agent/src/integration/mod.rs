@@ -0,0 +1,106 @@
+//! End-to-end tests of the kernel-dependent parts of the agent: traffic is generated
+//! between two network namespaces linked by a veth pair, traced by the eBPF programs
+//! and turned into service map edges and metrics.
+//!
+//! They need root, a kernel able to load the tracers and the `ip` tool, so they are
+//! only built with the `integration-tests` feature. Build the conn-tracer eBPF object
+//! first, or point `CONN_TRACER_OBJECT` at one, then run:
+//!
+//! ```text
+//! sudo -E cargo test -p agent --features integration-tests integration
+//! ```
+
+mod netns;
+mod tracer;
+mod traffic;
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use prometheus_client::encoding::text::encode;
+use prometheus_client::registry::Registry;
+
+use crate::collector::ProgramCollector;
+use crate::common::graph::GraphEdge;
+use crate::managers::cache::CacheManager;
+use crate::progs::service_map::program::ServiceMap;
+use crate::progs::types::Program;
+
+use self::netns::TestNetwork;
+use self::tracer::ConnTracer;
+
+const TCP_PORT: u16 = 7000;
+const HTTP_PORT: u16 = 8080;
+const UDP_PORT: u16 = 5353;
+
+fn require_root() {
+    assert!(
+        nix::unistd::Uid::effective().is_root(),
+        "integration tests need root to create namespaces and load eBPF programs"
+    );
+}
+
+fn edges_on_port(service_map: &ServiceMap, port: u16) -> Vec<GraphEdge> {
+    service_map
+        .graph_edges()
+        .into_iter()
+        .filter(|edge| edge.server_port == u32::from(port))
+        .collect()
+}
+
+#[test]
+fn test_traffic_between_namespaces() {
+    require_root();
+    let net = TestNetwork::new("bpfc-it", [10, 231, 0]).unwrap();
+    let tracer = ConnTracer::load().unwrap();
+
+    let cache_mgr = CacheManager::empty();
+    let client_ip = net.client_ip.to_string();
+    let server_ip = net.server_ip.to_string();
+    cache_mgr.insert_workload(&client_ip, "frontend", "it", "Deployment");
+    cache_mgr.insert_workload(&server_ip, "backend", "it", "Deployment");
+    let service_map = Arc::new(ServiceMap::with_connections(
+        Box::new(tracer.connections().unwrap()),
+        cache_mgr,
+        HashMap::new(),
+    ));
+
+    traffic::tcp_echo(&net, TCP_PORT, 64 * 1024).unwrap();
+    traffic::http_get(&net, HTTP_PORT).unwrap();
+    traffic::udp_exchange(&net, UDP_PORT, 10).unwrap();
+    // Let the closing handshakes reach the state tracepoint.
+    thread::sleep(Duration::from_millis(200));
+    service_map.poll().unwrap();
+
+    let tcp = edges_on_port(&service_map, TCP_PORT);
+    assert_eq!(tcp.len(), 1, "edges: {:?}", service_map.graph_edges());
+    assert_eq!(tcp[0].client.name.as_str(), "frontend");
+    assert_eq!(tcp[0].server.name.as_str(), "backend");
+    assert!(tcp[0].bytes_sent > 0);
+    // Closed connections are kept with their totals, but no longer active.
+    assert_eq!(tcp[0].active_conns, 0);
+
+    let http = edges_on_port(&service_map, HTTP_PORT);
+    assert_eq!(http.len(), 1);
+    assert_eq!(http[0].protocol, "http");
+
+    // Only TCP is traced.
+    assert!(edges_on_port(&service_map, UDP_PORT).is_empty());
+
+    let mut registry = Registry::default();
+    registry.register_collector(Box::new(ProgramCollector(service_map.clone())));
+    let mut metrics = String::new();
+    encode(&mut metrics, &registry).unwrap();
+    let observed = metrics
+        .lines()
+        .filter(|line| line.starts_with("connection_observed_bytes{"))
+        .collect::<Vec<_>>();
+    assert!(observed
+        .iter()
+        .any(|line| line.contains(&format!("server_port=\"{}\"", TCP_PORT))));
+    assert!(observed
+        .iter()
+        .any(|line| line.contains("protocol=\"http\"")));
+}
@@ -0,0 +1,112 @@
+use std::fs::File;
+use std::net::Ipv4Addr;
+use std::os::fd::AsRawFd;
+use std::process::Command;
+use std::thread;
+
+use anyhow::{bail, Context};
+
+/// Where `ip netns` keeps the namespaces it creates.
+const NETNS_DIR: &str = "/var/run/netns";
+
+/// Runs `ip` with the given arguments.
+fn ip(args: &[&str]) -> anyhow::Result<()> {
+    let output = Command::new("ip")
+        .args(args)
+        .output()
+        .context("Failed to run ip")?;
+    if !output.status.success() {
+        bail!(
+            "ip {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+/// A network namespace, deleted with everything in it when dropped.
+#[derive(Debug)]
+pub(crate) struct NetNs {
+    name: String,
+}
+
+impl NetNs {
+    pub(crate) fn new(name: &str) -> anyhow::Result<Self> {
+        // Left over by an aborted run.
+        let _ = ip(&["netns", "delete", name]);
+        ip(&["netns", "add", name])?;
+        let netns = Self {
+            name: name.to_string(),
+        };
+        netns.exec(&["link", "set", "lo", "up"])?;
+        Ok(netns)
+    }
+
+    /// Runs `ip` inside the namespace.
+    pub(crate) fn exec(&self, args: &[&str]) -> anyhow::Result<()> {
+        let mut netns_args = vec!["-n", self.name.as_str()];
+        netns_args.extend_from_slice(args);
+        ip(&netns_args)
+    }
+
+    /// Runs `f` on a thread that joined the namespace, so that the sockets it opens
+    /// live there. The namespace of the other threads is left untouched.
+    pub(crate) fn spawn<F, T>(&self, f: F) -> thread::JoinHandle<anyhow::Result<T>>
+    where
+        F: FnOnce() -> anyhow::Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let path = format!("{}/{}", NETNS_DIR, self.name);
+        thread::spawn(move || {
+            let netns = File::open(&path).with_context(|| format!("Failed to open {}", path))?;
+            if unsafe { libc::setns(netns.as_raw_fd(), libc::CLONE_NEWNET) } != 0 {
+                return Err(std::io::Error::last_os_error()).context("Failed to join namespace");
+            }
+            f()
+        })
+    }
+}
+
+impl Drop for NetNs {
+    fn drop(&mut self) {
+        let _ = ip(&["netns", "delete", &self.name]);
+    }
+}
+
+/// Two namespaces linked by a veth pair, one end in each, on a /24 of their own.
+#[derive(Debug)]
+pub(crate) struct TestNetwork {
+    pub(crate) client: NetNs,
+    pub(crate) server: NetNs,
+    pub(crate) client_ip: Ipv4Addr,
+    pub(crate) server_ip: Ipv4Addr,
+}
+
+impl TestNetwork {
+    pub(crate) fn new(prefix: &str, subnet: [u8; 3]) -> anyhow::Result<Self> {
+        let client = NetNs::new(&format!("{}-client", prefix))?;
+        let server = NetNs::new(&format!("{}-server", prefix))?;
+        let [a, b, c] = subnet;
+        let client_ip = Ipv4Addr::new(a, b, c, 1);
+        let server_ip = Ipv4Addr::new(a, b, c, 2);
+
+        // Created in the client namespace so that nothing is left on the host if the
+        // move fails, as deleting a namespace deletes its interfaces.
+        client.exec(&[
+            "link", "add", "veth0", "type", "veth", "peer", "name", "veth1",
+        ])?;
+        client.exec(&["link", "set", "veth1", "netns", &server.name])?;
+        client.exec(&["addr", "add", &format!("{}/24", client_ip), "dev", "veth0"])?;
+        server.exec(&["addr", "add", &format!("{}/24", server_ip), "dev", "veth1"])?;
+        client.exec(&["link", "set", "veth0", "up"])?;
+        server.exec(&["link", "set", "veth1", "up"])?;
+
+        Ok(Self {
+            client,
+            server,
+            client_ip,
+            server_ip,
+        })
+    }
+}
@@ -0,0 +1,89 @@
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::Context;
+use aya::maps::{HashMap as AyaHashMap, MapData};
+use aya::programs::{KProbe, TracePoint};
+use aya::{Bpf, BpfLoader};
+
+use conn_tracer_common::{ConnectionKey, ConnectionStats};
+
+/// Overrides the location of the conn-tracer eBPF object.
+const OBJECT_ENV: &str = "CONN_TRACER_OBJECT";
+
+/// The conn-tracer programs, loaded and attached by the test itself rather than by
+/// bpfman. Its pinned maps live in a directory of their own, removed when dropped, so
+/// that a tracer running on the host is left alone.
+pub(crate) struct ConnTracer {
+    bpf: Bpf,
+    pin_dir: PathBuf,
+}
+
+impl ConnTracer {
+    pub(crate) fn load() -> anyhow::Result<Self> {
+        let object = std::env::var(OBJECT_ENV)
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| {
+                PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+                    .join("../ebpf/conn-tracer/target/bpfel-unknown-none/release/conn-tracer")
+            });
+        let pin_dir = PathBuf::from(format!(
+            "/sys/fs/bpf/bpfconductor-it-{}",
+            std::process::id()
+        ));
+        fs::create_dir_all(&pin_dir)?;
+        let mut tracer = Self {
+            bpf: BpfLoader::new()
+                .map_pin_path(&pin_dir)
+                .load_file(&object)
+                .with_context(|| format!("Failed to load {:?}, set {}", object, OBJECT_ENV))?,
+            pin_dir,
+        };
+
+        for (name, function) in [
+            ("sock_conn_tracer", "tcp_data_queue"),
+            ("sock_send_tracer", "tcp_sendmsg"),
+            ("udp_send_tracer", "udp_sendmsg"),
+            ("udp_receive_tracer", "skb_consume_udp"),
+            ("original_dst_lookup", "nf_getsockopt"),
+            ("original_dst_lookup_ret", "nf_getsockopt"),
+        ] {
+            let program: &mut KProbe = tracer.program(name)?.try_into()?;
+            program.load()?;
+            program.attach(function, 0)?;
+        }
+        for (name, category, tracepoint) in [
+            ("sock_state_tracer", "sock", "inet_sock_set_state"),
+            ("sock_receive_reset_tracer", "tcp", "tcp_receive_reset"),
+            ("sock_send_reset_tracer", "tcp", "tcp_send_reset"),
+        ] {
+            let program: &mut TracePoint = tracer.program(name)?.try_into()?;
+            program.load()?;
+            program.attach(category, tracepoint)?;
+        }
+        Ok(tracer)
+    }
+
+    fn program(&mut self, name: &str) -> anyhow::Result<&mut aya::programs::Program> {
+        self.bpf
+            .program_mut(name)
+            .ok_or(anyhow::anyhow!("No program named {}", name))
+    }
+
+    /// Opens the connections map, as the service map program does from bpfman's pins.
+    pub(crate) fn connections(
+        &self,
+    ) -> anyhow::Result<AyaHashMap<MapData, ConnectionKey, ConnectionStats>> {
+        let map_data = MapData::from_pin(self.pin_dir.join("CONNECTIONS"))
+            .map_err(|_| anyhow::anyhow!("No maps named CONNECTIONS"))?;
+        aya::maps::Map::LruHashMap(map_data)
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("Failed to convert map"))
+    }
+}
+
+impl Drop for ConnTracer {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.pin_dir);
+    }
+}
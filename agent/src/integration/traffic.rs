@@ -0,0 +1,147 @@
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::mpsc;
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+
+use crate::integration::netns::TestNetwork;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Accepts one TCP connection in the server namespace and hands it to `handle`, while
+/// `connect` runs against it in the client namespace.
+fn tcp_exchange<S, C>(net: &TestNetwork, port: u16, handle: S, connect: C) -> anyhow::Result<()>
+where
+    S: FnOnce(TcpStream) -> anyhow::Result<()> + Send + 'static,
+    C: FnOnce(TcpStream) -> anyhow::Result<()> + Send + 'static,
+{
+    let (ready_tx, ready_rx) = mpsc::channel();
+    let server = net.server.spawn(move || {
+        let listener = TcpListener::bind(("0.0.0.0", port))?;
+        let _ = ready_tx.send(());
+        let (stream, _) = listener.accept()?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        handle(stream)
+    });
+    if ready_rx.recv_timeout(TIMEOUT).is_err() {
+        return server
+            .join()
+            .map_err(|_| anyhow::anyhow!("Server panicked"))?
+            .context("Server failed to listen");
+    }
+
+    let addr = SocketAddr::from((net.server_ip, port));
+    let client = net.client.spawn(move || {
+        let stream = TcpStream::connect_timeout(&addr, TIMEOUT)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        connect(stream)
+    });
+    client
+        .join()
+        .map_err(|_| anyhow::anyhow!("Client panicked"))??;
+    server
+        .join()
+        .map_err(|_| anyhow::anyhow!("Server panicked"))?
+}
+
+/// Sends `size` bytes to a server echoing them back.
+pub(crate) fn tcp_echo(net: &TestNetwork, port: u16, size: usize) -> anyhow::Result<()> {
+    tcp_exchange(
+        net,
+        port,
+        move |mut stream| {
+            let mut buf = vec![0; size];
+            stream.read_exact(&mut buf)?;
+            stream.write_all(&buf)?;
+            Ok(())
+        },
+        move |mut stream| {
+            let sent: Vec<u8> = (0..size).map(|i| i as u8).collect();
+            stream.write_all(&sent)?;
+            let mut received = vec![0; size];
+            stream.read_exact(&mut received)?;
+            if received != sent {
+                bail!("Echo mismatch");
+            }
+            Ok(())
+        },
+    )
+}
+
+/// Sends an HTTP/1.1 request to a server answering it.
+pub(crate) fn http_get(net: &TestNetwork, port: u16) -> anyhow::Result<()> {
+    tcp_exchange(
+        net,
+        port,
+        |mut stream| {
+            read_until_blank_line(&mut stream)?;
+            stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")?;
+            Ok(())
+        },
+        |mut stream| {
+            stream.write_all(b"GET /healthz HTTP/1.1\r\nHost: backend\r\n\r\n")?;
+            let mut response = Vec::new();
+            stream.read_to_end(&mut response)?;
+            if !response.starts_with(b"HTTP/1.1 200") {
+                bail!(
+                    "Unexpected response: {:?}",
+                    String::from_utf8_lossy(&response)
+                );
+            }
+            Ok(())
+        },
+    )
+}
+
+fn read_until_blank_line(stream: &mut TcpStream) -> anyhow::Result<()> {
+    let mut request = Vec::new();
+    let mut byte = [0; 1];
+    while !request.ends_with(b"\r\n\r\n") {
+        if stream.read(&mut byte)? == 0 {
+            bail!("Connection closed before the end of the request");
+        }
+        request.push(byte[0]);
+    }
+    Ok(())
+}
+
+/// Sends `count` datagrams to a server answering each of them.
+pub(crate) fn udp_exchange(net: &TestNetwork, port: u16, count: usize) -> anyhow::Result<()> {
+    let (ready_tx, ready_rx) = mpsc::channel();
+    let server = net.server.spawn(move || {
+        let socket = UdpSocket::bind(("0.0.0.0", port))?;
+        socket.set_read_timeout(Some(TIMEOUT))?;
+        let _ = ready_tx.send(());
+        let mut buf = [0; 64];
+        for _ in 0..count {
+            let (len, peer) = socket.recv_from(&mut buf)?;
+            socket.send_to(&buf[..len], peer)?;
+        }
+        Ok(())
+    });
+    if ready_rx.recv_timeout(TIMEOUT).is_err() {
+        return server
+            .join()
+            .map_err(|_| anyhow::anyhow!("Server panicked"))?
+            .context("Server failed to bind");
+    }
+
+    let addr = SocketAddr::from((net.server_ip, port));
+    let client = net.client.spawn(move || {
+        let socket = UdpSocket::bind(("0.0.0.0", 0))?;
+        socket.set_read_timeout(Some(TIMEOUT))?;
+        let mut buf = [0; 64];
+        for i in 0..count {
+            socket.send_to(format!("ping {}", i).as_bytes(), addr)?;
+            socket.recv_from(&mut buf)?;
+        }
+        Ok(())
+    });
+    client
+        .join()
+        .map_err(|_| anyhow::anyhow!("Client panicked"))??;
+    server
+        .join()
+        .map_err(|_| anyhow::anyhow!("Server panicked"))?
+}
@@ -0,0 +1,244 @@
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+
+use clap::Parser;
+
+use crate::common::native_histogram::HistogramMode;
+use crate::managers::alias::AliasRule;
+use crate::server::push::PushCompression;
+use crate::server::remote_write::parse_label;
+use crate::server::rpc::parse_mode;
+
+#[cfg(feature = "bench")]
+#[doc(hidden)]
+pub mod bench;
+mod collector;
+mod common;
+#[cfg(all(test, feature = "integration-tests"))]
+mod integration;
+mod managers;
+mod progs;
+mod server;
+mod utils;
+
+pub use crate::server::serve;
+pub use crate::server::systemd::{listen_fds, ListenFds};
+pub use crate::utils::init_env;
+
+#[derive(Parser, Debug)]
+#[command(
+    long_about = "An agent managing user space programs, including eBPF and non-eBPF, with a metrics server."
+)]
+#[command(name = "agent")]
+pub struct Args {
+    /// Optional: socket address to listen on for the metrics server.
+    #[clap(long, verbatim_doc_comment, default_value = "0.0.0.0:8080")]
+    pub(crate) metrics_addr: String,
+    /// Optional: Path under which to expose metrics.
+    #[clap(long, verbatim_doc_comment, default_value = "/metrics")]
+    pub(crate) metrics_path: String,
+    /// Optional: How latency histograms are exposed to scrapes: classic, native
+    /// or both. Native histograms are only sent to scrapers asking for the
+    /// protobuf format; others keep getting the classic ones.
+    #[clap(long, verbatim_doc_comment, value_enum, default_value = "classic")]
+    pub(crate) metrics_histograms: HistogramMode,
+    /// Optional: Location of the agent unix socket. Prefix it with @ to listen
+    /// in the abstract namespace instead, which needs no file on the host.
+    /// Sockets passed by systemd with FileDescriptorName=agent replace it.
+    /// Example: --agent-socket-path @eva/agent.sock
+    #[clap(long, verbatim_doc_comment, default_value = "/run/eva/agent.sock")]
+    pub(crate) agent_socket_path: PathBuf,
+    /// Optional: File mode of the agent unix socket, in octal. Defaults to 0660.
+    #[clap(long, verbatim_doc_comment, value_parser = parse_mode)]
+    pub(crate) agent_socket_mode: Option<u32>,
+    /// Optional: User allowed to connect to an agent socket in the abstract
+    /// namespace, which has no file mode. Only root is by default. Can be repeated.
+    /// Example: --agent-socket-allow-uid 1000
+    #[clap(long, verbatim_doc_comment)]
+    pub(crate) agent_socket_allow_uid: Vec<u32>,
+    /// Optional: Group whose processes may connect to an agent socket in the
+    /// abstract namespace, by their primary group. Can be repeated.
+    /// Example: --agent-socket-allow-gid 1000
+    #[clap(long, verbatim_doc_comment)]
+    pub(crate) agent_socket_allow_gid: Vec<u32>,
+    /// Optional: TCP address to also serve the agent API on. Over TCP, only the
+    /// methods reading the agent state are served, unless --agent-token-file is
+    /// set. Traffic isn't encrypted, so prefer a loopback address, reached with
+    /// kubectl port-forward.
+    /// Example: --agent-addr 127.0.0.1:9080
+    #[clap(long, verbatim_doc_comment)]
+    pub(crate) agent_addr: Option<SocketAddr>,
+    /// Optional: File holding the token clients must send as a bearer token to
+    /// call the agent API over TCP. Every method is then served, to them only. The
+    /// file is read on every call, so that rotated tokens are picked up.
+    /// Example: --agent-token-file /etc/eva/agent-token
+    #[clap(long, verbatim_doc_comment)]
+    pub(crate) agent_token_file: Option<PathBuf>,
+    /// Optional: Run outside Kubernetes, e.g. on bare-metal hosts. Connections are
+    /// attributed to the local processes, named after their systemd unit or
+    /// container, instead of pods.
+    #[clap(long, verbatim_doc_comment)]
+    pub(crate) standalone: bool,
+    /// Optional: Docker or Podman API socket used in standalone mode to name
+    /// containers and their addresses. Defaults to /var/run/docker.sock, then
+    /// /run/podman/podman.sock, when present.
+    /// Example: --container-socket /run/user/1000/podman/podman.sock
+    #[clap(long, verbatim_doc_comment)]
+    pub(crate) container_socket: Option<PathBuf>,
+    /// Optional: Pod label copied onto the workloads of the service map, and from
+    /// there onto their metrics and events, e.g. to tell canary traffic from
+    /// stable traffic. Can be repeated.
+    /// Example: --workload-label app.kubernetes.io/version
+    #[clap(long, verbatim_doc_comment)]
+    pub(crate) workload_label: Vec<String>,
+    /// Optional: Renames the workloads whose name matches a regex before they
+    /// make edges, e.g. to merge the jobs of a CronJob into one workload. The
+    /// regex matches the whole name, and the alias may refer to its groups.
+    /// The namespace is optional. The first matching rule wins. Can be repeated.
+    /// Example: --workload-alias 'batch/report-\d+=report'
+    #[clap(long, verbatim_doc_comment, value_parser = AliasRule::parse)]
+    pub(crate) workload_alias: Vec<AliasRule>,
+    /// Optional: File of workload alias rules, one per line, applied after those
+    /// of --workload-alias. Lines starting with # are skipped.
+    #[clap(long, verbatim_doc_comment)]
+    pub(crate) workload_aliases_file: Option<PathBuf>,
+    /// Optional: Location of the bpfman unix socket.
+    #[clap(
+        long,
+        verbatim_doc_comment,
+        default_value = "/run/bpfman-sock/bpfman.sock"
+    )]
+    pub(crate) bpfman_socket_path: String,
+    /// Optional: Maximum number of program polls running at the same time.
+    #[clap(long, verbatim_doc_comment, default_value = "4")]
+    pub(crate) poll_workers: usize,
+    /// Optional: Fraction of reported requests kept for GetRecentRequests.
+    /// Errors, slow queries and requests slower than the p99 are always kept.
+    #[clap(long, verbatim_doc_comment, default_value = "0.01")]
+    pub(crate) request_sample_rate: f64,
+    /// Optional: Maximum number of sampled requests kept in memory.
+    #[clap(long, verbatim_doc_comment, default_value = "4096")]
+    pub(crate) request_buffer_size: usize,
+    /// Optional: Directory the socket tracer pinned its maps in, where TracePod
+    /// focuses it on a pod: /sys/fs/bpf, or the bpfman map directory of its
+    /// owner program when it is loaded by bpfman.
+    #[clap(long, verbatim_doc_comment, default_value = "/sys/fs/bpf")]
+    pub(crate) socket_tracer_maps: PathBuf,
+    /// Optional: Baggage entry kept from the headers of reported requests, next
+    /// to their trace context. Can be repeated.
+    /// Example: --baggage-key tenant
+    #[clap(long, verbatim_doc_comment)]
+    pub(crate) baggage_key: Vec<String>,
+    /// Optional: Milliseconds past which a reported database operation is a slow
+    /// query. Slow queries are always kept, their statement redacted.
+    #[clap(long, verbatim_doc_comment, default_value = "500")]
+    pub(crate) slow_query_threshold: u64,
+    /// Optional: Keep the key of the Redis commands reported, which is redacted
+    /// along with their other arguments by default.
+    #[clap(long, verbatim_doc_comment)]
+    pub(crate) slow_query_keep_redis_keys: bool,
+    /// Optional: File every slow query is appended to, one JSON object per line.
+    /// Example: --slow-query-log-path /var/log/bpfconductor/slow-queries.log
+    #[clap(long, verbatim_doc_comment)]
+    pub(crate) slow_query_log_path: Option<PathBuf>,
+    /// Optional: File every program management operation is appended to, one
+    /// JSON object per line.
+    /// Example: --audit-log-path /var/log/bpfconductor/audit.log
+    #[clap(long, verbatim_doc_comment)]
+    pub(crate) audit_log_path: Option<PathBuf>,
+    /// Optional: Maximum number of audit records kept in memory for GetAuditLog.
+    #[clap(long, verbatim_doc_comment, default_value = "1024")]
+    pub(crate) audit_log_size: usize,
+    /// Optional: Prometheus remote write endpoint to push metrics to, for
+    /// environments that don't scrape the agent.
+    /// Example: --remote-write-url https://prometheus.example.com/api/v1/write
+    #[clap(long, verbatim_doc_comment)]
+    pub(crate) remote_write_url: Option<String>,
+    /// Optional: Seconds between two remote write pushes.
+    #[clap(long, verbatim_doc_comment, default_value = "30")]
+    pub(crate) remote_write_interval: u64,
+    /// Optional: Maximum number of series sent in one remote write request.
+    #[clap(long, verbatim_doc_comment, default_value = "2000")]
+    pub(crate) remote_write_batch_size: usize,
+    /// Optional: Label added to every pushed series. Can be repeated.
+    /// Example: --remote-write-label node=worker-1
+    #[clap(long, verbatim_doc_comment, value_parser = parse_label)]
+    pub(crate) remote_write_label: Vec<(String, String)>,
+    /// Optional: File holding the bearer token sent with every push.
+    #[clap(long, verbatim_doc_comment)]
+    pub(crate) remote_write_bearer_token_file: Option<PathBuf>,
+    /// Optional: CA bundle to verify an https endpoint with. The system roots are
+    /// used by default.
+    #[clap(long, verbatim_doc_comment)]
+    pub(crate) remote_write_ca_file: Option<PathBuf>,
+    /// Optional: Client certificate and key for mutual TLS.
+    #[clap(long, verbatim_doc_comment, requires = "remote_write_key_file")]
+    pub(crate) remote_write_cert_file: Option<PathBuf>,
+    #[clap(long, verbatim_doc_comment, requires = "remote_write_cert_file")]
+    pub(crate) remote_write_key_file: Option<PathBuf>,
+    /// Optional: How latency histograms are pushed: classic, native or both.
+    #[clap(long, verbatim_doc_comment, value_enum, default_value = "classic")]
+    pub(crate) remote_write_histograms: HistogramMode,
+    /// Optional: sFlow collector to export sampled connections to, over UDP.
+    /// Example: --sflow-collector 10.0.0.5:6343
+    #[clap(long, verbatim_doc_comment)]
+    pub(crate) sflow_collector: Option<SocketAddr>,
+    /// Optional: Seconds between two sFlow exports.
+    #[clap(long, verbatim_doc_comment, default_value = "10")]
+    pub(crate) sflow_interval: u64,
+    /// Optional: One in this many connections carrying traffic in an interval is
+    /// exported to the sFlow collector.
+    #[clap(long, verbatim_doc_comment, default_value = "1")]
+    pub(crate) sflow_sampling_rate: u32,
+    /// Optional: Address the agent reports itself by in sFlow datagrams. The address
+    /// they are sent from by default.
+    #[clap(long, verbatim_doc_comment)]
+    pub(crate) sflow_agent_address: Option<Ipv4Addr>,
+    /// Optional: Server serving hub to connect to and push service maps to, for
+    /// environments where it can't dial the agent. Updates made while it is
+    /// unreachable are buffered and sent once it is back. Repeat it for each
+    /// replica of a sharded server, in the same order on every node: the agent
+    /// pushes to the replica owning its node name, or the next one reachable.
+    /// Example: --push-server-url http://hub.example.com:9090
+    #[clap(long, verbatim_doc_comment)]
+    pub(crate) push_server_url: Vec<String>,
+    /// Optional: Seconds between two looks for new service map snapshots to push.
+    #[clap(long, verbatim_doc_comment, default_value = "10")]
+    pub(crate) push_interval: u64,
+    /// Optional: Maximum number of updates buffered while the push server is
+    /// unreachable. The oldest are dropped past it.
+    #[clap(long, verbatim_doc_comment, default_value = "1000")]
+    pub(crate) push_buffer_size: usize,
+    /// Optional: Maximum number of service map updates pushed in one message.
+    #[clap(long, verbatim_doc_comment, default_value = "100")]
+    pub(crate) push_batch_size: usize,
+    /// Optional: Push every update made, rather than folding the updates of a
+    /// program made while the previous one waits to be sent into it.
+    #[clap(long, verbatim_doc_comment)]
+    pub(crate) push_no_coalesce: bool,
+    /// Optional: Compression of the messages pushed: none or zstd.
+    #[clap(long, verbatim_doc_comment, value_enum, default_value = "zstd")]
+    pub(crate) push_compression: PushCompression,
+    /// Optional: File holding the token sent to the push server as a bearer
+    /// token, which tells it the cluster of the agent. Read every time the agent
+    /// connects, so that rotated tokens are picked up.
+    /// Example: --push-token-file /etc/eva/push-token
+    #[clap(long, verbatim_doc_comment)]
+    pub(crate) push_token_file: Option<PathBuf>,
+    /// Optional: TCP address to serve a web UI drawing the live service maps on.
+    /// Like the agent API, it is not authenticated, so it is not served by default.
+    /// Example: --ui-addr 127.0.0.1:9081
+    #[clap(long, verbatim_doc_comment)]
+    pub(crate) ui_addr: Option<SocketAddr>,
+    /// Optional: Record significant findings on this node as Kubernetes events:
+    /// edge error spikes, OOM kills and eBPF maps filling up. Needs the
+    /// permission to create events.
+    #[clap(long, verbatim_doc_comment)]
+    pub(crate) finding_events: bool,
+    /// Optional: Name of the node the agent runs on, which it registers with at
+    /// the push server and records findings on. The hostname of a pod isn't the
+    /// name of its node, so a DaemonSet sets it from spec.nodeName through the
+    /// downward API. The hostname by default, for hosts outside Kubernetes.
+    #[clap(long, verbatim_doc_comment, env = "NODE_NAME")]
+    pub(crate) node_name: Option<String>,
+}
@@ -0,0 +1,133 @@
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use ahash::AHashMap;
+use anyhow::Context;
+use parking_lot::RwLock;
+use regex::Regex;
+
+use crate::managers::cache::Workload;
+use crate::managers::symbol::{Symbol, SymbolTable};
+
+/// Names remembered at most, so that churning names, e.g. of the jobs of a CronJob,
+/// don't grow the memo without bound.
+const MAX_MEMOIZED_NAMES: usize = 65536;
+
+/// A rule renaming the workloads whose name matches `pattern`, in `namespace` or in
+/// every namespace.
+#[derive(Debug, Clone)]
+pub(crate) struct AliasRule {
+    namespace: Option<String>,
+    pattern: Regex,
+    alias: String,
+}
+
+impl AliasRule {
+    /// Parses a rule written `[<namespace>/]<name regex>=<alias>`. The regex matches
+    /// the whole name, and the alias may refer to its groups, e.g. `$1`.
+    pub(crate) fn parse(s: &str) -> Result<Self, String> {
+        let (selector, alias) = s
+            .rsplit_once('=')
+            .filter(|(selector, alias)| !selector.is_empty() && !alias.is_empty())
+            .ok_or(format!(
+                "expected [<namespace>/]<regex>=<alias>, got {:?}",
+                s
+            ))?;
+        let (namespace, pattern) = match selector.split_once('/') {
+            Some((namespace, pattern)) if is_namespace(namespace) => {
+                (Some(namespace.to_string()), pattern)
+            }
+            _ => (None, selector),
+        };
+        let pattern = Regex::new(&format!("^(?:{})$", pattern))
+            .map_err(|e| format!("invalid regex {:?}: {}", pattern, e))?;
+        Ok(Self {
+            namespace,
+            pattern,
+            alias: alias.to_string(),
+        })
+    }
+
+    fn rename(&self, namespace: &str, name: &str) -> Option<String> {
+        if self.namespace.as_deref().is_some_and(|ns| ns != namespace) {
+            return None;
+        }
+        self.pattern
+            .is_match(name)
+            .then(|| self.pattern.replace(name, self.alias.as_str()).into_owned())
+    }
+}
+
+/// Renames workloads before they make edges, e.g. to merge the jobs spawned by a
+/// CronJob into one logical workload or to match the names used elsewhere in the
+/// business. The first matching rule wins; workloads matching none keep their name.
+#[derive(Debug, Default)]
+pub(crate) struct WorkloadAliases {
+    rules: Vec<AliasRule>,
+    /// Alias of each (namespace, name) seen, if any.
+    memo: RwLock<AHashMap<(Symbol, Symbol), Option<Symbol>>>,
+}
+
+impl WorkloadAliases {
+    /// Builds the aliases from the given rules, followed by those of `path`, one per
+    /// line. Empty lines and lines starting with `#` are skipped.
+    pub(crate) fn new(mut rules: Vec<AliasRule>, path: Option<&Path>) -> anyhow::Result<Self> {
+        if let Some(path) = path {
+            let file = fs::read_to_string(path)
+                .with_context(|| format!("Failed to read workload aliases {}", path.display()))?;
+            for (i, line) in file.lines().enumerate() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                rules.push(
+                    AliasRule::parse(line)
+                        .map_err(|e| anyhow::anyhow!("{}:{}: {}", path.display(), i + 1, e))?,
+                );
+            }
+        }
+        Ok(Self {
+            rules,
+            memo: RwLock::new(AHashMap::new()),
+        })
+    }
+
+    /// Returns the workload under its alias, or as it is when no rule matches it.
+    pub(crate) fn apply(&self, workload: Arc<Workload>, symbols: &SymbolTable) -> Arc<Workload> {
+        if self.rules.is_empty() {
+            return workload;
+        }
+        let id = (workload.namespace.clone(), workload.name.clone());
+        let alias = self.memo.read().get(&id).cloned();
+        let alias = match alias {
+            Some(alias) => alias,
+            None => {
+                let alias = self
+                    .rules
+                    .iter()
+                    .find_map(|rule| rule.rename(&workload.namespace, &workload.name))
+                    .map(|alias| symbols.intern(&alias));
+                let mut memo = self.memo.write();
+                if memo.len() >= MAX_MEMOIZED_NAMES {
+                    memo.clear();
+                }
+                memo.insert(id, alias.clone());
+                alias
+            }
+        };
+        match alias {
+            Some(name) if name != workload.name => Arc::new(Workload {
+                name,
+                ..(*workload).clone()
+            }),
+            _ => workload,
+        }
+    }
+}
+
+fn is_namespace(s: &str) -> bool {
+    !s.is_empty()
+        && s.chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+}
@@ -0,0 +1,116 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use log::warn;
+use parking_lot::Mutex;
+use serde_json::json;
+
+use agent_api::v1::{AuditRecord, GetAuditLogRequest};
+
+const DEFAULT_LIMIT: usize = 100;
+
+#[derive(Debug)]
+struct Inner {
+    records: VecDeque<AuditRecord>,
+    capacity: usize,
+    file: Option<File>,
+}
+
+/// Records the program management operations, with who asked for them, when, and how
+/// they went. The latest records are kept in memory for GetAuditLog, and every record
+/// is also appended to a file, one JSON object per line, when one is configured.
+#[derive(Debug, Clone)]
+pub(crate) struct AuditLog {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl AuditLog {
+    pub(crate) fn new(capacity: usize, path: Option<&Path>) -> anyhow::Result<Self> {
+        let file = match path {
+            Some(path) => Some(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .mode(0o600)
+                    .open(path)
+                    .with_context(|| format!("Failed to open audit log {}", path.display()))?,
+            ),
+            None => None,
+        };
+        Ok(Self {
+            inner: Arc::new(Mutex::new(Inner {
+                records: VecDeque::with_capacity(capacity),
+                capacity: capacity.max(1),
+                file,
+            })),
+        })
+    }
+
+    /// Records an operation on `program` asked for by `caller`, with the error it
+    /// failed with, if any.
+    pub(crate) fn record(
+        &self,
+        caller: &str,
+        operation: &str,
+        program: &str,
+        metadata: HashMap<String, String>,
+        error: Option<String>,
+    ) {
+        let timestamp_ns = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64);
+        let record = AuditRecord {
+            timestamp_ns,
+            operation: operation.to_string(),
+            program: program.to_string(),
+            caller: caller.to_string(),
+            success: error.is_none(),
+            error: error.unwrap_or_default(),
+            metadata,
+        };
+
+        let mut inner = self.inner.lock();
+        if let Some(file) = inner.file.as_mut() {
+            let line = json!({
+                "timestamp_ns": record.timestamp_ns,
+                "operation": record.operation,
+                "program": record.program,
+                "caller": record.caller,
+                "success": record.success,
+                "error": record.error,
+                "metadata": record.metadata,
+            });
+            if let Err(e) = writeln!(file, "{}", line) {
+                warn!("Failed to append to the audit log: {}", e);
+            }
+        }
+        if inner.records.len() == inner.capacity {
+            inner.records.pop_front();
+        }
+        inner.records.push_back(record);
+    }
+
+    /// Returns the retained records matching the filter, newest first.
+    pub(crate) fn recent(&self, filter: &GetAuditLogRequest) -> Vec<AuditRecord> {
+        let limit = match filter.limit {
+            0 => DEFAULT_LIMIT,
+            n => n as usize,
+        };
+        self.inner
+            .lock()
+            .records
+            .iter()
+            .rev()
+            .filter(|r| filter.program.is_empty() || r.program == filter.program)
+            .filter(|r| r.timestamp_ns >= filter.since_ns)
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+}
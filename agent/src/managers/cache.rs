@@ -1,4 +1,8 @@
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
 use ahash::AHashMap;
 use futures::{StreamExt, TryStreamExt};
@@ -13,16 +17,29 @@ use kube::{
     runtime::{predicates, reflector, watcher, WatchStreamExt},
     Client, ResourceExt,
 };
-use log::{debug, info};
+use log::{debug, info, warn};
 use parking_lot::RwLock;
+use prometheus_client::encoding::DescriptorEncoder;
+
+use crate::common::constants::DEFAULT_CONTAINER_SYNC_INTERVAL;
+use crate::managers::alias::WorkloadAliases;
+use crate::managers::cache_health::CacheHealth;
+use crate::managers::container::ContainerResolver;
+use crate::managers::identity::IdentityTable;
+use crate::managers::process::{hostname, ProcessResolver};
+use crate::managers::symbol::{Symbol, SymbolTable};
 
 type Cache<K, V> = Arc<RwLock<AHashMap<K, Arc<V>>>>;
 
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct Workload {
-    pub name: String,
-    pub namespace: String,
-    pub kind: String,
+    pub name: Symbol,
+    pub namespace: Symbol,
+    pub kind: Symbol,
+    /// Values of the pod labels configured with `--workload-label`, in the configured
+    /// order. Labels missing from the pod are left out, and workloads other than pods
+    /// have none.
+    pub labels: Vec<(Symbol, Symbol)>,
 }
 
 #[derive(Clone, Debug)]
@@ -38,6 +55,20 @@ pub(crate) struct CacheManager {
     pub cronjobs: Store<CronJob>,
     pub pod_descriptors: Cache<ObjectRef<Pod>, Workload>,
     pub ip_to_workload: Cache<String, Workload>,
+    /// Moves on whenever the IP index changes, for resolutions made from it to be
+    /// dropped.
+    pub generation: Arc<AtomicU64>,
+    pub symbols: SymbolTable,
+    /// Identities of the workloads in the IP index, pushed down to tracers.
+    pub identities: IdentityTable,
+    /// Keys of the pod labels copied onto their workloads.
+    pub workload_labels: Arc<[String]>,
+    /// Set when running outside Kubernetes, where connections are attributed to local
+    /// processes instead of pods.
+    pub processes: Option<ProcessResolver>,
+    pub health: Arc<CacheHealth>,
+    /// Renames workloads before they make edges, see `--workload-alias`.
+    pub aliases: Arc<WorkloadAliases>,
 }
 
 macro_rules! spawn_watcher {
@@ -51,7 +82,10 @@ macro_rules! spawn_watcher {
 }
 
 impl CacheManager {
-    pub(crate) async fn new() -> anyhow::Result<CacheManager> {
+    pub(crate) async fn new(
+        workload_labels: Vec<String>,
+        aliases: WorkloadAliases,
+    ) -> anyhow::Result<CacheManager> {
         info!("Initializing cache manager");
         let (pod_reader, pod_writer) = reflector::store::<Pod>();
         let (node_reader, node_writer) = reflector::store::<Node>();
@@ -75,6 +109,13 @@ impl CacheManager {
             cronjobs: cronjobs_reader,
             pod_descriptors: Arc::new(RwLock::new(AHashMap::new())),
             ip_to_workload: Arc::new(RwLock::new(AHashMap::new())),
+            generation: Arc::new(AtomicU64::new(0)),
+            symbols: SymbolTable::default(),
+            identities: IdentityTable::default(),
+            workload_labels: workload_labels.into(),
+            processes: None,
+            health: Arc::new(CacheHealth::default()),
+            aliases: Arc::new(aliases),
         };
 
         spawn_watcher!(cache_mgr, Pod, pod_writer, watching_pods);
@@ -90,6 +131,159 @@ impl CacheManager {
         Ok(cache_mgr)
     }
 
+    /// Creates a cache manager for hosts outside Kubernetes. Nothing is watched, so its
+    /// stores stay empty and connections are attributed to processes instead. The IP
+    /// index holds the containers of the local Docker or Podman runtime, if any.
+    pub(crate) async fn standalone(
+        container_socket: Option<PathBuf>,
+        aliases: WorkloadAliases,
+    ) -> CacheManager {
+        info!("Initializing cache manager in standalone mode");
+        let symbols = SymbolTable::default();
+        let ip_to_workload = Arc::new(RwLock::new(AHashMap::new()));
+        let generation = Arc::new(AtomicU64::new(0));
+        let health = Arc::new(CacheHealth::default());
+        let containers = ContainerResolver::new(
+            container_socket,
+            hostname(),
+            ip_to_workload.clone(),
+            generation.clone(),
+            symbols.clone(),
+            health.clone(),
+        );
+        if let Some(containers) = containers.clone() {
+            if let Err(e) = containers.sync().await {
+                warn!("Failed to list containers: {:?}", e);
+            }
+            tokio::spawn(containers.run(Duration::from_secs(DEFAULT_CONTAINER_SYNC_INTERVAL)));
+        }
+
+        Self {
+            pods: reflector::store::<Pod>().0,
+            nodes: reflector::store::<Node>().0,
+            services: reflector::store::<Service>().0,
+            replicasets: reflector::store::<ReplicaSet>().0,
+            deployments: reflector::store::<Deployment>().0,
+            statefulsets: reflector::store::<StatefulSet>().0,
+            daemonsets: reflector::store::<DaemonSet>().0,
+            jobs: reflector::store::<Job>().0,
+            cronjobs: reflector::store::<CronJob>().0,
+            pod_descriptors: Arc::new(RwLock::new(AHashMap::new())),
+            ip_to_workload,
+            generation,
+            workload_labels: Arc::new([]),
+            processes: Some(ProcessResolver::new(symbols.clone(), containers)),
+            symbols,
+            identities: IdentityTable::default(),
+            health,
+            aliases: Arc::new(aliases),
+        }
+    }
+
+    /// Creates a cache manager that watches nothing, for tests and benchmarks to fill its
+    /// IP index.
+    #[cfg(any(test, feature = "bench"))]
+    pub(crate) fn empty() -> CacheManager {
+        Self {
+            pods: reflector::store::<Pod>().0,
+            nodes: reflector::store::<Node>().0,
+            services: reflector::store::<Service>().0,
+            replicasets: reflector::store::<ReplicaSet>().0,
+            deployments: reflector::store::<Deployment>().0,
+            statefulsets: reflector::store::<StatefulSet>().0,
+            daemonsets: reflector::store::<DaemonSet>().0,
+            jobs: reflector::store::<Job>().0,
+            cronjobs: reflector::store::<CronJob>().0,
+            pod_descriptors: Arc::new(RwLock::new(AHashMap::new())),
+            ip_to_workload: Arc::new(RwLock::new(AHashMap::new())),
+            generation: Arc::new(AtomicU64::new(0)),
+            symbols: SymbolTable::default(),
+            identities: IdentityTable::default(),
+            workload_labels: Arc::new([]),
+            processes: None,
+            health: Arc::new(CacheHealth::default()),
+            aliases: Arc::new(WorkloadAliases::default()),
+        }
+    }
+
+    /// Indexes a workload under an IP, as the pod and container watchers do.
+    #[cfg(any(test, feature = "bench"))]
+    pub(crate) fn insert_workload(&self, ip: &str, name: &str, namespace: &str, kind: &str) {
+        let workload = Workload {
+            name: self.symbols.intern(name),
+            namespace: self.symbols.intern(namespace),
+            kind: self.symbols.intern(kind),
+            labels: Vec::new(),
+        };
+        self.ip_to_workload
+            .write()
+            .insert(ip.to_string(), Arc::new(workload));
+        self.invalidate();
+    }
+
+    /// Generation of the IP index, see [`generation`](Self::generation).
+    pub(crate) fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    /// Moves the generation on, once the IP index has changed.
+    fn invalidate(&self) {
+        self.generation.fetch_add(1, Ordering::Release);
+    }
+
+    /// Returns the workload an IP belongs to, accounting the lookup in the cache health.
+    pub(crate) fn resolve_ip(&self, ip: &str) -> Option<Arc<Workload>> {
+        let workload = self.ip_to_workload.read().get(ip).cloned();
+        self.health.observe_lookup(workload.is_some());
+        workload
+    }
+
+    pub(crate) fn collect(&self, encoder: &mut DescriptorEncoder) -> Result<(), std::fmt::Error> {
+        let tracked_ips = self.ip_to_workload.read().len();
+        self.health.collect(encoder, tracked_ips)
+    }
+
+    /// Returns the workload of the pod a process runs in, found from the pod UID in its
+    /// cgroup path.
+    pub(crate) fn resolve_pid(&self, pid: u32) -> Option<Arc<Workload>> {
+        let cgroups = fs::read_to_string(format!("/proc/{}/cgroup", pid)).ok()?;
+        let uid = cgroups.lines().find_map(pod_uid)?;
+        let pod = self
+            .pods
+            .state()
+            .into_iter()
+            .find(|pod| pod.metadata.uid.as_deref() == Some(uid.as_str()))?;
+        self.pod_descriptors
+            .read()
+            .get(&ObjectRef::from_obj(&*pod))
+            .cloned()
+    }
+
+    /// Returns the workload of a pod and the processes of this node running in it,
+    /// found from the pod UID in their cgroup path.
+    pub(crate) fn pod_processes(
+        &self,
+        namespace: &str,
+        name: &str,
+    ) -> Option<(Arc<Workload>, Vec<u32>)> {
+        let pod = self.pods.get(&ObjectRef::new(name).within(namespace))?;
+        let uid = pod.metadata.uid.as_deref()?;
+        let workload = self
+            .pod_descriptors
+            .read()
+            .get(&ObjectRef::from_obj(&*pod))
+            .cloned()?;
+        let pids = fs::read_dir("/proc")
+            .ok()?
+            .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse::<u32>().ok())
+            .filter(|pid| {
+                fs::read_to_string(format!("/proc/{}/cgroup", pid))
+                    .is_ok_and(|cgroups| cgroups.lines().find_map(pod_uid).as_deref() == Some(uid))
+            })
+            .collect();
+        Some((workload, pids))
+    }
+
     async fn get_controller_of_owner(
         &self,
         owner_ref: OwnerReference,
@@ -218,18 +412,33 @@ impl CacheManager {
         }
 
         let entry = Arc::new(Workload {
-            name,
-            namespace,
-            kind,
+            name: self.symbols.intern(&name),
+            namespace: self.symbols.intern(&namespace),
+            kind: self.symbols.intern(&kind),
+            labels: self.pod_labels(pod),
         });
         let mut pod_descriptors = self.pod_descriptors.write();
         pod_descriptors.insert(ObjectRef::from_obj(pod), entry.clone());
         entry
     }
 
+    /// Returns the configured labels the pod carries, so that pods of the same
+    /// controller with different versions, e.g. canary and stable, are told apart.
+    fn pod_labels(&self, pod: &Pod) -> Vec<(Symbol, Symbol)> {
+        let labels = pod.labels();
+        self.workload_labels
+            .iter()
+            .filter_map(|key| {
+                let value = labels.get(key)?;
+                Some((self.symbols.intern(key), self.symbols.intern(value)))
+            })
+            .collect()
+    }
+
     async fn watching_pods(&self, writer: Writer<Pod>) -> anyhow::Result<()> {
         let client = Client::try_default().await?;
         let api: Api<Pod> = Api::all(client);
+        let health = self.health.clone();
         let stream = watcher(api, watcher::Config::default().any_semantic())
             .default_backoff()
             .modify(|pod| {
@@ -238,6 +447,7 @@ impl CacheManager {
                 pod.annotations_mut().clear();
             })
             .reflect(writer)
+            .inspect(move |event| health.observe_watch("pods", event.is_ok()))
             .applied_objects()
             .predicate_filter(predicates::resource_version);
         futures::pin_mut!(stream);
@@ -245,12 +455,13 @@ impl CacheManager {
         while let Some(pod) = stream.try_next().await? {
             let entry = self.resolve_pod_descriptor(&pod).await;
             let mut ips = self.ip_to_workload.write();
+            let mut changed = false;
             if let Some(status) = pod.status.as_ref() {
                 if let Some(pod_ips) = status.pod_ips.as_ref() {
                     for ip in pod_ips {
                         match ip.ip.clone() {
                             Some(ip) => {
-                                ips.insert(ip, entry.clone());
+                                changed |= ips.insert(ip, entry.clone()).as_ref() != Some(&entry);
                             }
                             None => {
                                 debug!("IP is None, skipping");
@@ -260,6 +471,9 @@ impl CacheManager {
                     }
                 }
             }
+            if changed {
+                self.invalidate();
+            }
         }
 
         Ok(())
@@ -268,6 +482,7 @@ impl CacheManager {
     async fn watching_nodes(&self, writer: Writer<Node>) -> anyhow::Result<()> {
         let client = Client::try_default().await?;
         let api: Api<Node> = Api::all(client);
+        let health = self.health.clone();
 
         let stream = watcher(api, watcher::Config::default().any_semantic())
             .default_backoff()
@@ -277,26 +492,31 @@ impl CacheManager {
                 node.metadata.annotations = None;
             })
             .reflect(writer)
+            .inspect(move |event| health.observe_watch("nodes", event.is_ok()))
             .applied_objects()
             .predicate_filter(predicates::resource_version);
         futures::pin_mut!(stream);
 
         while let Some(node) = stream.try_next().await? {
             let mut ips = self.ip_to_workload.write();
+            let mut changed = false;
             if let Some(status) = node.status.as_ref() {
                 if let Some(addresses) = status.addresses.as_ref() {
                     for addr in addresses {
-                        ips.insert(
-                            addr.address.clone(),
-                            Arc::new(Workload {
-                                name: node.name_any(),
-                                namespace: "node".to_string(),
-                                kind: "Node".to_string(),
-                            }),
-                        );
+                        let workload = Arc::new(Workload {
+                            name: self.symbols.intern(&node.name_any()),
+                            namespace: self.symbols.intern("node"),
+                            kind: self.symbols.intern("Node"),
+                            labels: Vec::new(),
+                        });
+                        changed |=
+                            ips.insert(addr.address.clone(), workload.clone()) != Some(workload);
                     }
                 }
             }
+            if changed {
+                self.invalidate();
+            }
         }
 
         Ok(())
@@ -305,6 +525,7 @@ impl CacheManager {
     async fn watching_services(&self, writer: Writer<Service>) -> anyhow::Result<()> {
         let client = Client::try_default().await?;
         let api: Api<Service> = Api::all(client);
+        let health = self.health.clone();
 
         let stream = watcher(api, watcher::Config::default().any_semantic())
             .default_backoff()
@@ -313,12 +534,14 @@ impl CacheManager {
                 service.metadata.annotations = None;
             })
             .reflect(writer)
+            .inspect(move |event| health.observe_watch("services", event.is_ok()))
             .applied_objects()
             .predicate_filter(predicates::resource_version);
         futures::pin_mut!(stream);
 
         while let Some(service) = stream.try_next().await? {
             let mut ips = self.ip_to_workload.write();
+            let mut changed = false;
             if let Some(spec) = service.spec.as_ref() {
                 if let Some(cluster_ips) = spec.cluster_ips.as_ref() {
                     for ip_str in cluster_ips {
@@ -327,14 +550,15 @@ impl CacheManager {
                                 if ip == "None" {
                                     continue;
                                 }
-                                ips.insert(
-                                    ip,
-                                    Arc::new(Workload {
-                                        name: service.name_any(),
-                                        namespace: service.namespace().unwrap_or_default(),
-                                        kind: "Service".to_string(),
-                                    }),
-                                );
+                                let workload = Arc::new(Workload {
+                                    name: self.symbols.intern(&service.name_any()),
+                                    namespace: self
+                                        .symbols
+                                        .intern(&service.namespace().unwrap_or_default()),
+                                    kind: self.symbols.intern("Service"),
+                                    labels: Vec::new(),
+                                });
+                                changed |= ips.insert(ip, workload.clone()) != Some(workload);
                             }
                             Err(e) => {
                                 debug!("Failed to parse IP: {:?}, skipping", e);
@@ -344,6 +568,9 @@ impl CacheManager {
                     }
                 }
             }
+            if changed {
+                self.invalidate();
+            }
         }
 
         Ok(())
@@ -352,6 +579,7 @@ impl CacheManager {
     async fn watching_replicasets(&self, writer: Writer<ReplicaSet>) -> anyhow::Result<()> {
         let client = Client::try_default().await?;
         let api: Api<ReplicaSet> = Api::all(client);
+        let health = self.health.clone();
 
         let stream = watcher(api, watcher::Config::default().any_semantic())
             .default_backoff()
@@ -361,6 +589,7 @@ impl CacheManager {
                 replicaset.metadata.annotations = None;
             })
             .reflect(writer)
+            .inspect(move |event| health.observe_watch("replicasets", event.is_ok()))
             .applied_objects()
             .predicate_filter(predicates::resource_version);
         futures::pin_mut!(stream);
@@ -373,6 +602,7 @@ impl CacheManager {
     async fn watching_deployments(&self, writer: Writer<Deployment>) -> anyhow::Result<()> {
         let client = Client::try_default().await?;
         let api: Api<Deployment> = Api::all(client);
+        let health = self.health.clone();
 
         let stream = watcher(api, watcher::Config::default().any_semantic())
             .default_backoff()
@@ -382,6 +612,7 @@ impl CacheManager {
                 deployment.metadata.annotations = None;
             })
             .reflect(writer)
+            .inspect(move |event| health.observe_watch("deployments", event.is_ok()))
             .applied_objects()
             .predicate_filter(predicates::resource_version);
         futures::pin_mut!(stream);
@@ -392,6 +623,7 @@ impl CacheManager {
     async fn watching_daemonsets(&self, writer: Writer<DaemonSet>) -> anyhow::Result<()> {
         let client = Client::try_default().await?;
         let api: Api<DaemonSet> = Api::all(client);
+        let health = self.health.clone();
 
         let stream = watcher(api, watcher::Config::default().any_semantic())
             .default_backoff()
@@ -401,6 +633,7 @@ impl CacheManager {
                 daemonset.metadata.annotations = None;
             })
             .reflect(writer)
+            .inspect(move |event| health.observe_watch("daemonsets", event.is_ok()))
             .applied_objects()
             .predicate_filter(predicates::resource_version);
         futures::pin_mut!(stream);
@@ -412,6 +645,7 @@ impl CacheManager {
     async fn watching_statefulsets(&self, writer: Writer<StatefulSet>) -> anyhow::Result<()> {
         let client = Client::try_default().await?;
         let api: Api<StatefulSet> = Api::all(client);
+        let health = self.health.clone();
 
         let stream = watcher(api, watcher::Config::default().any_semantic())
             .default_backoff()
@@ -421,6 +655,7 @@ impl CacheManager {
                 statefulset.metadata.annotations = None;
             })
             .reflect(writer)
+            .inspect(move |event| health.observe_watch("statefulsets", event.is_ok()))
             .applied_objects()
             .predicate_filter(predicates::resource_version);
         futures::pin_mut!(stream);
@@ -431,6 +666,7 @@ impl CacheManager {
     async fn watching_jobs(&self, writer: Writer<Job>) -> anyhow::Result<()> {
         let client = Client::try_default().await?;
         let api: Api<Job> = Api::all(client);
+        let health = self.health.clone();
 
         let stream = watcher(api, watcher::Config::default().any_semantic())
             .default_backoff()
@@ -440,6 +676,7 @@ impl CacheManager {
                 job.metadata.annotations = None;
             })
             .reflect(writer)
+            .inspect(move |event| health.observe_watch("jobs", event.is_ok()))
             .applied_objects()
             .predicate_filter(predicates::resource_version);
         futures::pin_mut!(stream);
@@ -450,6 +687,7 @@ impl CacheManager {
     async fn watching_cronjobs(&self, writer: Writer<CronJob>) -> anyhow::Result<()> {
         let client = Client::try_default().await?;
         let api: Api<CronJob> = Api::all(client);
+        let health = self.health.clone();
 
         let stream = watcher(api, watcher::Config::default().any_semantic())
             .default_backoff()
@@ -459,6 +697,7 @@ impl CacheManager {
                 cronjob.metadata.annotations = None;
             })
             .reflect(writer)
+            .inspect(move |event| health.observe_watch("cronjobs", event.is_ok()))
             .applied_objects()
             .predicate_filter(predicates::resource_version);
         futures::pin_mut!(stream);
@@ -486,7 +725,44 @@ impl CacheManager {
         let cronjobs = self.cronjobs.clone();
         cronjobs.wait_until_ready().await?;
 
+        self.health.synced();
         info!("Cache sync complete");
         Ok(())
     }
 }
+
+/// Extracts the pod UID from a cgroup line, for both the cgroupfs driver
+/// (`/kubepods/burstable/pod<uid>/...`) and the systemd one
+/// (`/kubepods.slice/kubepods-burstable-pod<uid_with_underscores>.slice/...`).
+fn pod_uid(line: &str) -> Option<String> {
+    line.split('/').find_map(|component| {
+        let (_, uid) = component.rsplit_once("pod")?;
+        let uid = uid.trim_end_matches(".slice").replace('_', "-");
+        (uid.len() == 36).then_some(uid)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UID: &str = "5f0c4b1e-7d2a-4c3b-9e8f-1a2b3c4d5e6f";
+
+    #[test]
+    fn test_pod_uid() {
+        assert_eq!(
+            pod_uid(&format!("0::/kubepods/burstable/pod{}/0123abcd", UID)).as_deref(),
+            Some(UID)
+        );
+        assert_eq!(
+            pod_uid(&format!(
+                "0::/kubepods.slice/kubepods-burstable.slice/kubepods-burstable-pod{}.slice/cri-containerd-0123abcd.scope",
+                UID.replace('-', "_")
+            ))
+            .as_deref(),
+            Some(UID)
+        );
+        assert_eq!(pod_uid("0::/system.slice/nginx.service"), None);
+        assert_eq!(pod_uid("0::/kubepods/burstable/podabc"), None);
+    }
+}
@@ -0,0 +1,147 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use ahash::AHashMap;
+use parking_lot::RwLock;
+use prometheus_client::encoding::{DescriptorEncoder, EncodeLabelSet, EncodeMetric};
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::registry::Unit;
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct ResourceLabels {
+    resource: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct LookupLabels {
+    result: &'static str,
+}
+
+/// How fresh and how complete the workload cache is, so that edges missing from the
+/// service map can be traced to the cache. Each informer records when it last
+/// received an event, and every IP lookup whether it found a workload.
+#[derive(Debug, Default)]
+pub(crate) struct CacheHealth {
+    /// Unix time, in seconds, each informer last received an event, by resource.
+    last_events: RwLock<AHashMap<&'static str, u64>>,
+    watch_errors: Family<ResourceLabels, Counter>,
+    /// Unix time, in seconds, the cache was last synced with its source.
+    last_sync: AtomicU64,
+    lookups: Family<LookupLabels, Counter>,
+    unresolved_ips: Gauge,
+}
+
+impl CacheHealth {
+    /// Records an event received by the informer of `resource`, or the error its watch
+    /// failed with.
+    pub(crate) fn observe_watch(&self, resource: &'static str, ok: bool) {
+        if ok {
+            self.last_events.write().insert(resource, unix_now());
+            self.last_sync.store(unix_now(), Ordering::Relaxed);
+        } else {
+            self.watch_errors
+                .get_or_create(&ResourceLabels {
+                    resource: resource.to_string(),
+                })
+                .inc();
+        }
+    }
+
+    /// Records a complete sync of the cache with its source: the initial list of the
+    /// informers, or a listing of the local containers.
+    pub(crate) fn synced(&self) {
+        self.last_sync.store(unix_now(), Ordering::Relaxed);
+    }
+
+    /// Records an IP lookup, and whether it found a workload.
+    pub(crate) fn observe_lookup(&self, found: bool) {
+        let result = if found { "hit" } else { "miss" };
+        self.lookups.get_or_create(&LookupLabels { result }).inc();
+    }
+
+    /// Total IP lookups that found no workload.
+    pub(crate) fn misses(&self) -> u64 {
+        self.lookups
+            .get_or_create(&LookupLabels { result: "miss" })
+            .get()
+    }
+
+    /// Sets the number of IPs a poll found no workload for.
+    pub(crate) fn set_unresolved(&self, count: u64) {
+        self.unresolved_ips.set(count as i64);
+    }
+
+    pub(crate) fn collect(
+        &self,
+        encoder: &mut DescriptorEncoder,
+        tracked_ips: usize,
+    ) -> Result<(), std::fmt::Error> {
+        let tracked: Gauge = Gauge::default();
+        tracked.set(tracked_ips as i64);
+        let metric_encoder = encoder.encode_descriptor(
+            "workload_cache_tracked_ips",
+            "IPs the workload cache resolves to a workload",
+            None,
+            tracked.metric_type(),
+        )?;
+        tracked.encode(metric_encoder)?;
+
+        let now = unix_now();
+        let lag = Family::<ResourceLabels, Gauge>::default();
+        for (resource, last_event) in self.last_events.read().iter() {
+            lag.get_or_create(&ResourceLabels {
+                resource: resource.to_string(),
+            })
+            .set(now.saturating_sub(*last_event) as i64);
+        }
+        let metric_encoder = encoder.encode_descriptor(
+            "workload_cache_informer_lag",
+            "time since the informer of a resource last received an event",
+            Some(&Unit::Seconds),
+            lag.metric_type(),
+        )?;
+        lag.encode(metric_encoder)?;
+
+        let metric_encoder = encoder.encode_descriptor(
+            "workload_cache_watch_errors",
+            "watches of a resource that failed",
+            None,
+            self.watch_errors.metric_type(),
+        )?;
+        self.watch_errors.encode(metric_encoder)?;
+
+        let last_sync: Gauge = Gauge::default();
+        last_sync.set(self.last_sync.load(Ordering::Relaxed) as i64);
+        let metric_encoder = encoder.encode_descriptor(
+            "workload_cache_last_sync_timestamp",
+            "Unix time the workload cache was last synced with its source",
+            Some(&Unit::Seconds),
+            last_sync.metric_type(),
+        )?;
+        last_sync.encode(metric_encoder)?;
+
+        let metric_encoder = encoder.encode_descriptor(
+            "workload_cache_ip_lookups",
+            "IP lookups in the workload cache, by whether a workload was found",
+            None,
+            self.lookups.metric_type(),
+        )?;
+        self.lookups.encode(metric_encoder)?;
+
+        let metric_encoder = encoder.encode_descriptor(
+            "workload_cache_unresolved_ips",
+            "IP lookups that found no workload in the last poll of the service map",
+            None,
+            self.unresolved_ips.metric_type(),
+        )?;
+        self.unresolved_ips.encode(metric_encoder)
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
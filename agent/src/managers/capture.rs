@@ -0,0 +1,162 @@
+use std::collections::HashMap;
+
+use agent_api::v1::RequestSample;
+
+/// The headers and query parameters kept on the requests reported for a program, set
+/// by its `capture_headers` and `capture_query_params` metadata. Nothing is kept by
+/// default, since either may carry anything from cookies to credentials.
+#[derive(Debug, Default)]
+pub(crate) struct CapturePolicy {
+    /// Header names, lower case.
+    headers: Vec<String>,
+    query_params: Vec<String>,
+}
+
+impl CapturePolicy {
+    pub(crate) fn from_metadata(metadata: &HashMap<String, String>) -> Self {
+        let list = |key: &str| -> Vec<String> {
+            metadata
+                .get(key)
+                .map(|values| {
+                    values
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|value| !value.is_empty())
+                        .map(String::from)
+                        .collect()
+                })
+                .unwrap_or_default()
+        };
+        Self {
+            headers: list("capture_headers")
+                .into_iter()
+                .map(|name| name.to_ascii_lowercase())
+                .collect(),
+            query_params: list("capture_query_params"),
+        }
+    }
+
+    /// Sets the `headers` of a request, its original ones, to those in the allowlist,
+    /// and drops the query parameters of its path missing from theirs, as well as its
+    /// fragment. Kept header names are lower cased.
+    pub(crate) fn apply(&self, request: &mut RequestSample, headers: HashMap<String, String>) {
+        request.headers = headers
+            .into_iter()
+            .map(|(name, value)| (name.to_ascii_lowercase(), value))
+            .filter(|(name, _)| self.headers.contains(name))
+            .collect();
+
+        let path = request.path.split('#').next().unwrap_or_default();
+        let Some((path, query)) = path.split_once('?') else {
+            request.path.truncate(path.len());
+            return;
+        };
+        let query: Vec<_> = query
+            .split('&')
+            .filter(|param| {
+                let name = param.split_once('=').map_or(*param, |(name, _)| name);
+                self.query_params.iter().any(|allowed| allowed == name)
+            })
+            .collect();
+        request.path = if query.is_empty() {
+            path.to_string()
+        } else {
+            format!("{}?{}", path, query.join("&"))
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::managers::trace_context;
+
+    fn policy(headers: &str, query_params: &str) -> CapturePolicy {
+        CapturePolicy::from_metadata(&HashMap::from([
+            ("capture_headers".to_string(), headers.to_string()),
+            ("capture_query_params".to_string(), query_params.to_string()),
+        ]))
+    }
+
+    fn request(path: &str, headers: &[(&str, &str)]) -> (RequestSample, HashMap<String, String>) {
+        let request = RequestSample {
+            path: path.to_string(),
+            ..Default::default()
+        };
+        let headers = headers
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        (request, headers)
+    }
+
+    #[test]
+    fn test_from_metadata() {
+        let policy = policy(" X-Request-ID ,,User-Agent", "page, q ");
+        assert_eq!(policy.headers, ["x-request-id", "user-agent"]);
+        assert_eq!(policy.query_params, ["page", "q"]);
+        let policy = CapturePolicy::from_metadata(&HashMap::new());
+        assert!(policy.headers.is_empty() && policy.query_params.is_empty());
+    }
+
+    #[test]
+    fn test_headers() {
+        let (mut sample, headers) = request(
+            "/",
+            &[
+                ("X-Request-Id", "abc"),
+                ("Cookie", "session=s3cret"),
+                ("authorization", "Bearer s3cret"),
+            ],
+        );
+        policy("x-request-id", "").apply(&mut sample, headers.clone());
+        assert_eq!(
+            sample.headers,
+            HashMap::from([("x-request-id".to_string(), "abc".to_string())])
+        );
+        CapturePolicy::default().apply(&mut sample, headers);
+        assert!(sample.headers.is_empty());
+    }
+
+    #[test]
+    fn test_query_params() {
+        let capture = policy("", "page,q");
+        for (path, expected) in [
+            (
+                "/search?q=shoes&token=s3cret&page=2",
+                "/search?q=shoes&page=2",
+            ),
+            ("/search?token=s3cret", "/search"),
+            ("/search?q=shoes#reviews", "/search?q=shoes"),
+            ("/search#token=s3cret", "/search"),
+            ("/search?page", "/search?page"),
+            ("/search?qq=1&page2=1", "/search"),
+            ("/", "/"),
+        ] {
+            let (mut sample, headers) = request(path, &[]);
+            capture.apply(&mut sample, headers);
+            assert_eq!(sample.path, expected, "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_trace_context_of_dropped_headers() {
+        // The trace context is parsed from headers the policy doesn't keep.
+        let (mut sample, headers) = request(
+            "/checkout?card=4111",
+            &[
+                (
+                    "traceparent",
+                    "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+                ),
+                ("cookie", "session=s3cret"),
+            ],
+        );
+        sample.headers = headers;
+        trace_context::extract(&mut sample, &[], &CapturePolicy::default());
+        assert_eq!(sample.trace_id, "0af7651916cd43dd8448eb211c80319c");
+        assert!(sample.trace_sampled);
+        assert!(sample.headers.is_empty());
+        assert_eq!(sample.path, "/checkout");
+    }
+}
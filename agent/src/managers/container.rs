@@ -0,0 +1,288 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use ahash::AHashMap;
+use anyhow::{bail, Context};
+use http_body_util::{BodyExt, Empty};
+use hyper::body::Bytes;
+use hyper::Request;
+use hyper_util::rt::TokioIo;
+use log::{debug, info, warn};
+use parking_lot::RwLock;
+use serde_json::Value;
+use tokio::net::UnixStream;
+use tokio::time::MissedTickBehavior;
+
+use crate::common::constants::directories::CONTAINER_RUNTIME_SOCKETS;
+use crate::managers::cache::Workload;
+use crate::managers::cache_health::CacheHealth;
+use crate::managers::symbol::SymbolTable;
+
+/// Labels set by Docker Compose, and by podman-compose, on the containers they create.
+const COMPOSE_PROJECT_LABEL: &str = "com.docker.compose.project";
+const COMPOSE_SERVICE_LABEL: &str = "com.docker.compose.service";
+
+/// Names the containers of a Docker or Podman host, which both serve the Docker Engine
+/// API, so that single-node container hosts get the same attribution as pods. A
+/// container is named after its Compose service within its Compose project, and
+/// otherwise after its container name within the host namespace. The containers are
+/// listed periodically: their addresses fill the cache manager's IP index, and their
+/// ids name the local processes found in their cgroups.
+#[derive(Debug, Clone)]
+pub(crate) struct ContainerResolver {
+    socket: PathBuf,
+    namespace: String,
+    containers: Arc<RwLock<AHashMap<String, Arc<Workload>>>>,
+    ip_to_workload: Arc<RwLock<AHashMap<String, Arc<Workload>>>>,
+    /// The cache manager's generation, moved on when a listing changes the IP index.
+    generation: Arc<AtomicU64>,
+    symbols: SymbolTable,
+    health: Arc<CacheHealth>,
+}
+
+impl ContainerResolver {
+    /// Connects to the given Engine API socket, or to the first default one present.
+    /// Returns `None` when there is no container runtime to ask.
+    pub(crate) fn new(
+        socket: Option<PathBuf>,
+        namespace: String,
+        ip_to_workload: Arc<RwLock<AHashMap<String, Arc<Workload>>>>,
+        generation: Arc<AtomicU64>,
+        symbols: SymbolTable,
+        health: Arc<CacheHealth>,
+    ) -> Option<Self> {
+        let socket = socket.or_else(|| {
+            CONTAINER_RUNTIME_SOCKETS
+                .iter()
+                .map(PathBuf::from)
+                .find(|path| path.exists())
+        })?;
+        info!(
+            "Resolving containers with the runtime at {}",
+            socket.display()
+        );
+        Some(Self {
+            socket,
+            namespace,
+            containers: Arc::new(RwLock::new(AHashMap::new())),
+            ip_to_workload,
+            generation,
+            symbols,
+            health,
+        })
+    }
+
+    /// Returns the workload of a container, given its full id.
+    pub(crate) fn resolve_id(&self, id: &str) -> Option<Arc<Workload>> {
+        self.containers.read().get(id).cloned()
+    }
+
+    /// Lists the running containers again, replacing the previous listing.
+    pub(crate) async fn sync(&self) -> anyhow::Result<()> {
+        let listing = get_json(&self.socket, "/containers/json").await?;
+        let Some(listing) = listing.as_array() else {
+            bail!("Unexpected container listing: {}", listing);
+        };
+
+        let mut containers = AHashMap::new();
+        let mut ips = AHashMap::new();
+        for container in listing {
+            let Some(id) = container["Id"].as_str() else {
+                continue;
+            };
+            let workload = Arc::new(self.describe(container));
+            let networks = container["NetworkSettings"]["Networks"].as_object();
+            for network in networks.into_iter().flat_map(|networks| networks.values()) {
+                if let Some(ip) = network["IPAddress"].as_str().filter(|ip| !ip.is_empty()) {
+                    ips.insert(ip.to_string(), workload.clone());
+                }
+            }
+            containers.insert(id.to_string(), workload);
+        }
+        debug!(
+            "Listed {} containers with {} addresses",
+            containers.len(),
+            ips.len()
+        );
+        *self.containers.write() = containers;
+        // Outside Kubernetes nothing else fills the index.
+        let mut ip_to_workload = self.ip_to_workload.write();
+        if *ip_to_workload != ips {
+            *ip_to_workload = ips;
+            self.generation.fetch_add(1, Ordering::Release);
+        }
+        self.health.synced();
+        Ok(())
+    }
+
+    fn describe(&self, container: &Value) -> Workload {
+        let labels = &container["Labels"];
+        let name = labels[COMPOSE_SERVICE_LABEL]
+            .as_str()
+            .or_else(|| {
+                container["Names"][0]
+                    .as_str()
+                    .map(|name| name.trim_start_matches('/'))
+            })
+            .or_else(|| container["Id"].as_str())
+            .unwrap_or_default();
+        let namespace = labels[COMPOSE_PROJECT_LABEL]
+            .as_str()
+            .unwrap_or(&self.namespace);
+        Workload {
+            name: self.symbols.intern(name),
+            namespace: self.symbols.intern(namespace),
+            kind: self.symbols.intern("Container"),
+            labels: Vec::new(),
+        }
+    }
+
+    /// Keeps the listing current until the agent stops. A failed listing keeps the
+    /// previous one, as a restarting runtime doesn't stop its containers.
+    pub(crate) async fn run(self, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        loop {
+            ticker.tick().await;
+            if let Err(e) = self.sync().await {
+                warn!("Failed to list containers: {:?}", e);
+            }
+        }
+    }
+}
+
+async fn get_json(socket: &Path, path: &str) -> anyhow::Result<Value> {
+    let stream = UnixStream::connect(socket)
+        .await
+        .with_context(|| format!("Failed to connect to {}", socket.display()))?;
+    let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+    tokio::task::spawn(async move {
+        if let Err(e) = conn.await {
+            debug!("Container runtime connection failed: {:?}", e);
+        }
+    });
+
+    let request = Request::get(path)
+        // The Engine API ignores it, but HTTP/1.1 requires it.
+        .header(hyper::header::HOST, "localhost")
+        .body(Empty::<Bytes>::new())?;
+    let response = sender.send_request(request).await?;
+    let status = response.status();
+    let body = response.into_body().collect().await?.to_bytes();
+    if !status.is_success() {
+        bail!(
+            "{} returned {}: {}",
+            path,
+            status,
+            String::from_utf8_lossy(&body)
+        );
+    }
+    Ok(serde_json::from_slice(&body)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::UnixListener;
+
+    use super::*;
+
+    fn resolver(socket: PathBuf) -> ContainerResolver {
+        ContainerResolver::new(
+            Some(socket),
+            "host-1".to_string(),
+            Arc::new(RwLock::new(AHashMap::new())),
+            Arc::new(AtomicU64::new(0)),
+            SymbolTable::default(),
+            Arc::new(CacheHealth::default()),
+        )
+        .unwrap()
+    }
+
+    /// Answers one request on `listener` with `listing`.
+    async fn serve(listener: UnixListener, listing: Value) {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        while !request.ends_with(b"\r\n\r\n") {
+            let mut buf = [0; 1024];
+            let n = stream.read(&mut buf).await.unwrap();
+            request.extend_from_slice(&buf[..n]);
+        }
+        assert!(request.starts_with(b"GET /containers/json HTTP/1.1\r\n"));
+        let body = listing.to_string();
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        stream.write_all(response.as_bytes()).await.unwrap();
+    }
+
+    fn workload(workload: Option<Arc<Workload>>) -> Option<(String, String)> {
+        workload.map(|w| (w.namespace.to_string(), w.name.to_string()))
+    }
+
+    #[test]
+    fn test_describe_compose_and_plain_containers() {
+        let resolver = resolver(PathBuf::from("/nonexistent/docker.sock"));
+        let compose = resolver.describe(&json!({
+            "Id": "3f2a1b9c0d11",
+            "Names": ["/shop-api-1"],
+            "Labels": {
+                COMPOSE_PROJECT_LABEL: "shop",
+                COMPOSE_SERVICE_LABEL: "api",
+            },
+        }));
+        assert_eq!(
+            (compose.namespace.as_str(), compose.name.as_str()),
+            ("shop", "api")
+        );
+        let plain = resolver.describe(&json!({"Id": "3f2a1b9c0d11", "Names": ["/redis"]}));
+        assert_eq!(
+            (plain.namespace.as_str(), plain.name.as_str()),
+            ("host-1", "redis")
+        );
+        let unnamed = resolver.describe(&json!({"Id": "3f2a1b9c0d11"}));
+        assert_eq!(unnamed.name.as_str(), "3f2a1b9c0d11");
+        assert_eq!(unnamed.kind.as_str(), "Container");
+    }
+
+    #[tokio::test]
+    async fn test_sync_fills_ids_and_addresses() {
+        let socket = std::env::temp_dir().join(format!("agent-docker-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&socket);
+        let listener = UnixListener::bind(&socket).unwrap();
+        let server = tokio::spawn(serve(
+            listener,
+            json!([
+                {
+                    "Id": "aaaa",
+                    "Names": ["/redis"],
+                    "NetworkSettings": {"Networks": {
+                        "bridge": {"IPAddress": "172.17.0.2"},
+                        "none": {"IPAddress": ""},
+                    }},
+                },
+                {"Names": ["/no-id"]},
+            ]),
+        ));
+
+        let resolver = resolver(socket.clone());
+        resolver.sync().await.unwrap();
+        server.await.unwrap();
+        std::fs::remove_file(&socket).unwrap();
+
+        assert_eq!(
+            workload(resolver.resolve_id("aaaa")),
+            Some(("host-1".to_string(), "redis".to_string()))
+        );
+        assert_eq!(workload(resolver.resolve_id("bbbb")), None);
+        let ips = resolver.ip_to_workload.read();
+        assert_eq!(ips.len(), 1);
+        assert_eq!(ips["172.17.0.2"].name.as_str(), "redis");
+        assert_eq!(resolver.generation.load(Ordering::Acquire), 1);
+    }
+}
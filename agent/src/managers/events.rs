@@ -0,0 +1,341 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use anyhow::bail;
+use parking_lot::Mutex;
+use tokio::sync::broadcast;
+
+use agent_api::v1::{DependencyEvent, GetRecentRequestsRequest, RequestSample};
+
+use crate::managers::slow_query::SlowQueryLog;
+
+/// Number of recent latencies, sampled or not, the p99 is estimated from.
+const LATENCY_WINDOW: usize = 2048;
+/// How many reported requests pass between two recomputations of the p99.
+const P99_REFRESH: u64 = 256;
+const DEFAULT_LIMIT: usize = 100;
+/// Dependency changes kept for watchers asking for a replay.
+const DEPENDENCY_HISTORY: usize = 256;
+
+#[derive(Debug)]
+struct Inner {
+    samples: VecDeque<RequestSample>,
+    capacity: usize,
+    sample_rate: f64,
+    /// Workload every request from or to is kept, while a pod of it is traced.
+    focus: Option<String>,
+    latencies: VecDeque<u64>,
+    p99_latency_ns: u64,
+    since_refresh: u64,
+}
+
+impl Inner {
+    /// Errors, requests slower than the current p99 and those of the focused workload
+    /// are always kept; everything else is kept at the configured rate.
+    fn should_sample(&self, request: &RequestSample) -> bool {
+        request.status >= 500
+            || self.focus.as_ref().is_some_and(|workload| {
+                request.src_workload == *workload || request.dst_workload == *workload
+            })
+            || (self.p99_latency_ns > 0 && request.latency_ns > self.p99_latency_ns)
+            || rand::random::<f64>() < self.sample_rate
+    }
+
+    fn observe_latency(&mut self, latency_ns: u64) {
+        if self.latencies.len() == LATENCY_WINDOW {
+            self.latencies.pop_front();
+        }
+        self.latencies.push_back(latency_ns);
+
+        self.since_refresh += 1;
+        if self.since_refresh >= P99_REFRESH || self.p99_latency_ns == 0 {
+            self.since_refresh = 0;
+            let mut sorted: Vec<u64> = self.latencies.iter().copied().collect();
+            sorted.sort_unstable();
+            let idx = (sorted.len() * 99 / 100).min(sorted.len() - 1);
+            self.p99_latency_ns = sorted[idx];
+        }
+    }
+}
+
+/// Keeps a sampled subset of the requests reported on this node in a fixed-size ring
+/// buffer, so that recent errors and slow requests can be looked at without a tracing
+/// backend. Dependency changes published by programs are fanned out to watchers.
+#[derive(Debug, Clone)]
+pub(crate) struct EventsManager {
+    inner: Arc<Mutex<Inner>>,
+    /// Baggage entries kept from the headers of reported requests.
+    baggage_keys: Arc<[String]>,
+    slow_queries: Arc<SlowQueryLog>,
+    dependencies: broadcast::Sender<DependencyEvent>,
+    dependency_history: Arc<Mutex<VecDeque<DependencyEvent>>>,
+}
+
+impl EventsManager {
+    pub(crate) fn new(
+        capacity: usize,
+        sample_rate: f64,
+        baggage_keys: Vec<String>,
+        slow_queries: SlowQueryLog,
+    ) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Inner {
+                samples: VecDeque::with_capacity(capacity),
+                capacity: capacity.max(1),
+                sample_rate: sample_rate.clamp(0.0, 1.0),
+                focus: None,
+                latencies: VecDeque::with_capacity(LATENCY_WINDOW),
+                p99_latency_ns: 0,
+                since_refresh: 0,
+            })),
+            baggage_keys: baggage_keys.into(),
+            slow_queries: Arc::new(slow_queries),
+            dependencies: broadcast::channel(DEPENDENCY_HISTORY).0,
+            dependency_history: Arc::new(Mutex::new(VecDeque::with_capacity(DEPENDENCY_HISTORY))),
+        }
+    }
+
+    /// Baggage entries kept from the headers of reported requests.
+    pub(crate) fn baggage_keys(&self) -> &[String] {
+        &self.baggage_keys
+    }
+
+    /// Records a batch of reported requests, parsed already, and returns how many of
+    /// them were sampled.
+    pub(crate) fn record(&self, requests: Vec<RequestSample>) -> u32 {
+        let mut inner = self.inner.lock();
+        let mut sampled = 0;
+        for mut request in requests {
+            let slow_query = self.slow_queries.check(&mut request);
+            // Decide against the p99 from before this request is part of it.
+            let keep = slow_query || inner.should_sample(&request);
+            inner.observe_latency(request.latency_ns);
+            if !keep {
+                continue;
+            }
+            if inner.samples.len() == inner.capacity {
+                inner.samples.pop_front();
+            }
+            inner.samples.push_back(request);
+            sampled += 1;
+        }
+        sampled
+    }
+
+    /// Keeps every request from or to `workload`, named `<NAMESPACE>/<NAME>`, until
+    /// the focus is cleared with `None`.
+    pub(crate) fn focus(&self, workload: Option<String>) {
+        self.inner.lock().focus = workload;
+    }
+
+    /// Returns the retained requests matching the filter, newest first, along with the
+    /// current latency p99.
+    pub(crate) fn recent(&self, filter: &GetRecentRequestsRequest) -> (Vec<RequestSample>, u64) {
+        let inner = self.inner.lock();
+        let limit = match filter.limit {
+            0 => DEFAULT_LIMIT,
+            n => n as usize,
+        };
+        let requests = inner
+            .samples
+            .iter()
+            .rev()
+            .filter(|r| {
+                filter.workload.is_empty()
+                    || r.src_workload == filter.workload
+                    || r.dst_workload == filter.workload
+            })
+            .filter(|r| r.status >= filter.min_status)
+            .filter(|r| filter.trace_id.is_empty() || r.trace_id == filter.trace_id)
+            .filter(|r| !filter.slow_queries_only || r.slow_query)
+            .filter(|r| !filter.slower_than_p99 || r.latency_ns > inner.p99_latency_ns)
+            .take(limit)
+            .cloned()
+            .collect();
+        (requests, inner.p99_latency_ns)
+    }
+
+    /// Numbers a dependency change after the last one, and sends it to the watchers.
+    pub(crate) fn publish_dependency(&self, mut event: DependencyEvent) {
+        let mut history = self.dependency_history.lock();
+        // The history is never emptied, so the last change keeps the count.
+        event.sequence = history.back().map_or(0, |last| last.sequence) + 1;
+        if history.len() == DEPENDENCY_HISTORY {
+            history.pop_front();
+        }
+        history.push_back(event.clone());
+        // Having no watcher is not an error.
+        let _ = self.dependencies.send(event);
+    }
+
+    /// Subscribes to dependency changes, returning the retained ones first when
+    /// `replay` is set, or those after the `resume_after` sequence when it is not 0.
+    /// Fails when resuming would miss changes no longer retained, or numbered before
+    /// the agent restarted.
+    pub(crate) fn watch_dependencies(
+        &self,
+        replay: bool,
+        resume_after: u64,
+    ) -> anyhow::Result<(Vec<DependencyEvent>, broadcast::Receiver<DependencyEvent>)> {
+        // Changes are sent under the history lock, so none can land in both the
+        // replay and the receiver.
+        let history = self.dependency_history.lock();
+        let rx = self.dependencies.subscribe();
+        if resume_after > 0 {
+            let last = history.back().map_or(0, |event| event.sequence);
+            let oldest = history.front().map_or(1, |event| event.sequence);
+            if resume_after > last {
+                bail!(
+                    "Change {} was never published, the last is {}",
+                    resume_after,
+                    last
+                );
+            }
+            if resume_after + 1 < oldest {
+                bail!(
+                    "Changes after {} are no longer retained, the oldest is {}",
+                    resume_after,
+                    oldest
+                );
+            }
+            let replayed = history
+                .iter()
+                .filter(|event| event.sequence > resume_after)
+                .cloned()
+                .collect();
+            return Ok((replayed, rx));
+        }
+        let replayed = if replay {
+            history.iter().cloned().collect()
+        } else {
+            vec![]
+        };
+        Ok((replayed, rx))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn manager(capacity: usize, sample_rate: f64) -> EventsManager {
+        let slow_queries = SlowQueryLog::new(Duration::from_secs(1), false, None).unwrap();
+        EventsManager::new(capacity, sample_rate, vec![], slow_queries)
+    }
+
+    fn request(src: &str, dst: &str, status: u32, latency_ns: u64) -> RequestSample {
+        RequestSample {
+            src_workload: src.to_string(),
+            dst_workload: dst.to_string(),
+            protocol: "http".to_string(),
+            status,
+            latency_ns,
+            ..Default::default()
+        }
+    }
+
+    fn kept(requests: &[RequestSample]) -> Vec<(u32, u64)> {
+        requests.iter().map(|r| (r.status, r.latency_ns)).collect()
+    }
+
+    #[test]
+    fn test_record_keeps_errors_and_slow_requests() {
+        let events = manager(16, 0.0);
+        let sampled = events.record(vec![
+            request("default/web", "default/api", 200, 100),
+            request("default/web", "default/api", 200, 1000),
+            request("default/web", "default/db", 503, 50),
+            request("default/web", "default/api", 200, 50),
+        ]);
+        assert_eq!(sampled, 2);
+
+        let (requests, p99) = events.recent(&GetRecentRequestsRequest::default());
+        assert_eq!(kept(&requests), vec![(503, 50), (200, 1000)]);
+        assert_eq!(p99, 100);
+
+        let errors = GetRecentRequestsRequest {
+            min_status: 500,
+            ..Default::default()
+        };
+        assert_eq!(kept(&events.recent(&errors).0), vec![(503, 50)]);
+        let slow = GetRecentRequestsRequest {
+            slower_than_p99: true,
+            ..Default::default()
+        };
+        assert_eq!(kept(&events.recent(&slow).0), vec![(200, 1000)]);
+        let db = GetRecentRequestsRequest {
+            workload: "default/db".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(kept(&events.recent(&db).0), vec![(503, 50)]);
+    }
+
+    #[test]
+    fn test_record_drops_oldest_samples() {
+        let events = manager(2, 1.0);
+        let sampled = events.record(
+            (1..=3)
+                .map(|i| request("default/web", "default/api", 200, i))
+                .collect(),
+        );
+        assert_eq!(sampled, 3);
+        let (requests, _) = events.recent(&GetRecentRequestsRequest::default());
+        assert_eq!(kept(&requests), vec![(200, 3), (200, 2)]);
+
+        let one = GetRecentRequestsRequest {
+            limit: 1,
+            ..Default::default()
+        };
+        assert_eq!(kept(&events.recent(&one).0), vec![(200, 3)]);
+    }
+
+    #[test]
+    fn test_focus_keeps_every_request_of_workload() {
+        let events = manager(16, 0.0);
+        events.focus(Some("default/api".to_string()));
+        let sampled = events.record(vec![
+            request("default/web", "default/api", 200, 10),
+            request("default/api", "default/db", 200, 10),
+            request("default/web", "default/cache", 200, 10),
+        ]);
+        assert_eq!(sampled, 2);
+
+        events.focus(None);
+        assert_eq!(
+            events.record(vec![request("default/web", "default/api", 200, 10)]),
+            0
+        );
+    }
+
+    fn dependency(client: &str) -> DependencyEvent {
+        DependencyEvent {
+            client_workload: client.to_string(),
+            server_workload: "default/api".to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_watch_dependencies_replays_and_resumes() {
+        let events = manager(16, 0.0);
+        events.publish_dependency(dependency("default/web"));
+        events.publish_dependency(dependency("default/batch"));
+
+        let sequences = |replayed: Vec<DependencyEvent>| -> Vec<u64> {
+            replayed.iter().map(|event| event.sequence).collect()
+        };
+        let (replayed, _) = events.watch_dependencies(true, 0).unwrap();
+        assert_eq!(sequences(replayed), vec![1, 2]);
+        let (replayed, _) = events.watch_dependencies(false, 0).unwrap();
+        assert!(replayed.is_empty());
+        let (replayed, _) = events.watch_dependencies(false, 1).unwrap();
+        assert_eq!(sequences(replayed), vec![2]);
+        assert!(events.watch_dependencies(false, 3).is_err());
+
+        let (_, mut rx) = events.watch_dependencies(false, 2).unwrap();
+        events.publish_dependency(dependency("default/cron"));
+        assert_eq!(rx.try_recv().unwrap().sequence, 3);
+    }
+}
@@ -0,0 +1,520 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use bpfman_lib::directories::RTDIR_FS_MAPS;
+use k8s_openapi::api::core::v1::ContainerStatus;
+use kube::runtime::reflector::ObjectRef;
+use log::{debug, warn};
+use tokio::sync::broadcast;
+use tokio::time::{self, Instant, MissedTickBehavior};
+
+use agent_api::ProgramState;
+
+use crate::common::bpf::pinned_map_usage;
+use crate::common::graph::node_id;
+use crate::common::types::ListFilter;
+use crate::managers::cache::Workload;
+use crate::managers::prog::ProgManager;
+use crate::progs::service_map::events::{publish_node_warning, publish_warning};
+use crate::progs::types::{ShutdownSignal, SnapshotQuery};
+
+/// Errors an edge must see between two checks to spike.
+const MIN_ERROR_SPIKE: u64 = 10;
+/// How many times its usual rate the errors of an edge must reach to spike.
+const ERROR_SPIKE_FACTOR: f64 = 3.0;
+/// Weight of the newest rate in the usual error rate of an edge.
+const ALPHA: f64 = 0.3;
+/// Fill ratio past which a hash map is under pressure, and below which it is no
+/// longer, so that a map hovering around the limit isn't reported over and over.
+const MAP_PRESSURE_HIGH: f64 = 0.9;
+const MAP_PRESSURE_LOW: f64 = 0.8;
+/// Shortest time between two events of the same finding.
+const COOLDOWN: Duration = Duration::from_secs(600);
+const OOM_KILLED: &str = "OOMKilled";
+
+/// The errors of an edge: its resets and connection timeouts.
+#[derive(Debug)]
+struct EdgeErrors {
+    total: u64,
+    /// Usual number of errors between two checks.
+    rate: f64,
+}
+
+impl EdgeErrors {
+    /// Errors made before the edge was seen have no rate.
+    fn new(total: u64) -> Self {
+        Self { total, rate: 0.0 }
+    }
+
+    /// Updates the errors with their new `total`, and returns the errors since the last
+    /// check along with their usual number when they spiked.
+    fn update(&mut self, total: u64) -> Option<(u64, f64)> {
+        // Totals start over when the edge expires and comes back.
+        let new = total.saturating_sub(self.total);
+        self.total = total;
+        let spike = new >= MIN_ERROR_SPIKE && new as f64 >= ERROR_SPIKE_FACTOR * self.rate.max(1.0);
+        let usual = self.rate;
+        self.rate = ALPHA * new as f64 + (1.0 - ALPHA) * self.rate;
+        spike.then_some((new, usual))
+    }
+}
+
+/// The pinned maps under pressure.
+#[derive(Debug, Default)]
+struct MapPressure {
+    pressured: HashSet<PathBuf>,
+}
+
+impl MapPressure {
+    /// Notes how full the map pinned at `pin` is, and returns whether it just came
+    /// under pressure.
+    fn update(&mut self, pin: &Path, fill: f64) -> bool {
+        if fill < MAP_PRESSURE_LOW {
+            self.pressured.remove(pin);
+            return false;
+        }
+        fill >= MAP_PRESSURE_HIGH && self.pressured.insert(pin.to_path_buf())
+    }
+}
+
+/// When each finding was last recorded.
+#[derive(Debug, Default)]
+struct Cooldowns {
+    published: HashMap<String, Instant>,
+}
+
+impl Cooldowns {
+    /// Returns whether the finding `key` may be recorded `now`, noting it was if so.
+    fn publish(&mut self, key: &str, now: Instant) -> bool {
+        self.published
+            .retain(|_, at| now.saturating_duration_since(*at) < COOLDOWN);
+        if self.published.contains_key(key) {
+            return false;
+        }
+        self.published.insert(key.to_string(), now);
+        true
+    }
+}
+
+/// What a finding is recorded on.
+#[derive(Debug)]
+enum Subject {
+    Workload(Arc<Workload>),
+    Node,
+}
+
+#[derive(Debug)]
+struct Finding {
+    /// Identifies the finding for its cooldown.
+    key: String,
+    subject: Subject,
+    reason: &'static str,
+    message: String,
+}
+
+/// Looks for significant changes on this node and records them as Kubernetes events,
+/// so that they show in `kubectl describe` and the event pipelines of the cluster:
+/// edges whose errors spike, containers and processes killed for lack of memory, and
+/// eBPF hash maps filling up. Each finding is recorded at most once per cooldown.
+#[derive(Debug)]
+pub(crate) struct FindingsMonitor {
+    prog_manager: ProgManager,
+    node: String,
+    interval: Duration,
+    /// Errors of each edge, by program and edge.
+    edges: HashMap<(String, String), EdgeErrors>,
+    /// Restarts of each container of the pods of this node, by pod UID and container.
+    restarts: HashMap<(String, String), i32>,
+    /// Value of the `oom_kill` counter of the kernel at the last check.
+    oom_kills: Option<u64>,
+    map_pressure: MapPressure,
+    cooldowns: Cooldowns,
+}
+
+impl FindingsMonitor {
+    pub(crate) fn new(prog_manager: ProgManager, node: String, interval: Duration) -> Self {
+        Self {
+            prog_manager,
+            node,
+            interval,
+            edges: HashMap::new(),
+            restarts: HashMap::new(),
+            oom_kills: None,
+            map_pressure: MapPressure::default(),
+            cooldowns: Cooldowns::default(),
+        }
+    }
+
+    pub(crate) async fn run(mut self, mut shutdown_rx: broadcast::Receiver<ShutdownSignal>) {
+        let mut interval = time::interval(self.interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => self.check().await,
+                signal = shutdown_rx.recv() => match signal {
+                    Ok(ShutdownSignal::All) | Err(_) => break,
+                    Ok(_) => {}
+                },
+            }
+        }
+    }
+
+    async fn check(&mut self) {
+        let mut findings = self.error_spikes();
+        findings.extend(self.oom_kills());
+        findings.extend(self.map_pressure());
+
+        for finding in findings {
+            if !self.cooldowns.publish(&finding.key, Instant::now()) {
+                debug!("Skipping {} finding, in cooldown", finding.key);
+                continue;
+            }
+            warn!("{}", finding.message);
+            let generate_name = format!("{}-", finding.reason.to_ascii_lowercase());
+            match finding.subject {
+                Subject::Workload(workload) => {
+                    publish_warning(workload, generate_name, finding.reason, finding.message).await
+                }
+                Subject::Node => {
+                    publish_node_warning(&self.node, generate_name, finding.reason, finding.message)
+                        .await
+                }
+            }
+        }
+    }
+
+    /// Finds the edges of the running service maps whose errors since the last check
+    /// reached several times their usual rate.
+    fn error_spikes(&mut self) -> Vec<Finding> {
+        let mut findings = Vec::new();
+        let mut seen = HashSet::new();
+        let programs = self
+            .prog_manager
+            .registry_manager
+            .list_programs(ListFilter::new(None, HashMap::new()));
+        for program in programs {
+            if !matches!(
+                program.get_state(),
+                ProgramState::Running | ProgramState::Degraded
+            ) {
+                continue;
+            }
+            let name = program.get_name();
+            let Some(snapshot) = program
+                .service_map_snapshots(SnapshotQuery::At(u64::MAX))
+                .pop()
+            else {
+                continue;
+            };
+            for edge in snapshot.edges {
+                let edge_name = format!(
+                    "{} -> {}:{}/{}",
+                    edge.client_workload, edge.server_workload, edge.server_port, edge.protocol
+                );
+                let key = (name.clone(), edge_name);
+                let total = edge.resets + edge.connect_timeouts;
+                seen.insert(key.clone());
+                let Some(errors) = self.edges.get_mut(&key) else {
+                    self.edges.insert(key, EdgeErrors::new(total));
+                    continue;
+                };
+                let Some((new, usual)) = errors.update(total) else {
+                    continue;
+                };
+                let subject = self
+                    .workload(&edge.server_workload)
+                    .map_or(Subject::Node, Subject::Workload);
+                findings.push(Finding {
+                    key: format!("EdgeErrorSpike/{}/{}", key.0, key.1),
+                    subject,
+                    reason: "EdgeErrorSpike",
+                    message: format!(
+                        "Errors on edge {} of {} spiked to {} resets and timeouts in {:?}, usually about {:.0}",
+                        key.1,
+                        key.0,
+                        new,
+                        self.interval,
+                        usual
+                    ),
+                });
+            }
+        }
+        self.edges.retain(|key, _| seen.contains(key));
+        findings
+    }
+
+    /// Finds the containers of this node restarted after being killed for lack of
+    /// memory, and the kernel OOM kills of other processes.
+    fn oom_kills(&mut self) -> Vec<Finding> {
+        let mut findings = Vec::new();
+        let mut seen = HashSet::new();
+        let cache_manager = &self.prog_manager.cache_manager;
+        for pod in cache_manager.pods.state() {
+            if pod.spec.as_ref().and_then(|spec| spec.node_name.as_deref())
+                != Some(self.node.as_str())
+            {
+                continue;
+            }
+            let (Some(uid), Some(statuses)) = (
+                pod.metadata.uid.clone(),
+                pod.status
+                    .as_ref()
+                    .and_then(|status| status.container_statuses.as_ref()),
+            ) else {
+                continue;
+            };
+            for status in statuses {
+                let key = (uid.clone(), status.name.clone());
+                seen.insert(key.clone());
+                let previous = self.restarts.insert(key, status.restart_count);
+                if !oom_killed_since(status, previous) {
+                    continue;
+                }
+                let pod_name = pod.metadata.name.clone().unwrap_or_default();
+                let namespace = pod.metadata.namespace.clone().unwrap_or_default();
+                let subject = cache_manager
+                    .pod_descriptors
+                    .read()
+                    .get(&ObjectRef::from_obj(&*pod))
+                    .cloned()
+                    .map_or(Subject::Node, Subject::Workload);
+                findings.push(Finding {
+                    key: format!("ContainerOOMKilled/{}/{}", uid, status.name),
+                    subject,
+                    reason: "ContainerOOMKilled",
+                    message: format!(
+                        "Container {} of pod {}/{} was killed for lack of memory, {} restarts",
+                        status.name, namespace, pod_name, status.restart_count
+                    ),
+                });
+            }
+        }
+        self.restarts.retain(|key, _| seen.contains(key));
+
+        // Containers killed without exiting, e.g. one of their processes, and the
+        // processes of the node itself are only counted by the kernel.
+        let oom_kills = kernel_oom_kills();
+        if let Some(kills) = other_oom_kills(self.oom_kills, oom_kills, findings.len()) {
+            findings.push(Finding {
+                key: "OOMKill".to_string(),
+                subject: Subject::Node,
+                reason: "OOMKill",
+                message: format!(
+                    "The kernel killed {} processes of node {} for lack of memory",
+                    kills, self.node
+                ),
+            });
+        }
+        if oom_kills.is_some() {
+            self.oom_kills = oom_kills;
+        }
+        findings
+    }
+
+    /// Finds the hash maps of the running programs filling up.
+    fn map_pressure(&mut self) -> Vec<Finding> {
+        let mut findings = Vec::new();
+        let mut seen = HashSet::new();
+        let programs = self
+            .prog_manager
+            .registry_manager
+            .list_programs(ListFilter::new(None, HashMap::new()));
+        for program in programs {
+            if !matches!(
+                program.get_state(),
+                ProgramState::Running | ProgramState::Degraded | ProgramState::Paused
+            ) {
+                continue;
+            }
+            let Ok(info) = program.get_program_info() else {
+                continue;
+            };
+            for (map_name, prog_id) in &info.ebpf_maps {
+                let pin = Path::new(RTDIR_FS_MAPS).join(format!("{}/{}", prog_id, map_name));
+                let usage = match pinned_map_usage(&pin) {
+                    Ok(Some(usage)) if usage.max_entries > 0 => usage,
+                    Ok(_) => continue,
+                    Err(e) => {
+                        debug!("Failed to count the entries of {}: {:?}", pin.display(), e);
+                        continue;
+                    }
+                };
+                let fill = f64::from(usage.entries) / f64::from(usage.max_entries);
+                let pressured = self.map_pressure.update(&pin, fill);
+                seen.insert(pin);
+                if !pressured {
+                    continue;
+                }
+                findings.push(Finding {
+                    key: format!("MapCapacityPressure/{}/{}", info.name, map_name),
+                    subject: Subject::Node,
+                    reason: "MapCapacityPressure",
+                    message: format!(
+                        "Map {} of program {} is {:.0}% full, {} of {} entries",
+                        map_name,
+                        info.name,
+                        fill * 100.0,
+                        usage.entries,
+                        usage.max_entries
+                    ),
+                });
+            }
+        }
+        self.map_pressure.pressured.retain(|pin| seen.contains(pin));
+        findings
+    }
+
+    /// Returns the workload named `<NAMESPACE>/<NAME>`, if it runs pods.
+    fn workload(&self, name: &str) -> Option<Arc<Workload>> {
+        self.prog_manager
+            .cache_manager
+            .pod_descriptors
+            .read()
+            .values()
+            .find(|workload| node_id(workload) == name)
+            .cloned()
+    }
+}
+
+/// Whether the container of `status`, restarted `previous` times at the last check,
+/// was killed for lack of memory since. Containers seen for the first time may have
+/// restarted long ago.
+fn oom_killed_since(status: &ContainerStatus, previous: Option<i32>) -> bool {
+    let oom_killed = status
+        .last_state
+        .as_ref()
+        .and_then(|state| state.terminated.as_ref())
+        .is_some_and(|terminated| terminated.reason.as_deref() == Some(OOM_KILLED));
+    let restarted = previous.is_some_and(|previous| status.restart_count > previous);
+    oom_killed && restarted
+}
+
+/// Returns how many processes the kernel killed for lack of memory between two reads
+/// of its counter, besides the `containers` reported already.
+fn other_oom_kills(previous: Option<u64>, current: Option<u64>, containers: usize) -> Option<u64> {
+    let kills = current?.saturating_sub(previous?);
+    kills
+        .checked_sub(containers as u64)
+        .filter(|others| *others > 0)
+}
+
+/// Reads how many processes the kernel killed for lack of memory since boot.
+fn kernel_oom_kills() -> Option<u64> {
+    fs::read_to_string("/proc/vmstat")
+        .ok()?
+        .lines()
+        .find_map(|line| line.strip_prefix("oom_kill "))
+        .and_then(|count| count.trim().parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use k8s_openapi::api::core::v1::{ContainerState, ContainerStateTerminated};
+
+    use super::*;
+
+    #[test]
+    fn test_edge_error_spike() {
+        let mut errors = EdgeErrors::new(100);
+        // Errors below the minimum never spike, whatever the usual rate.
+        assert_eq!(errors.update(109), None);
+        let mut errors = EdgeErrors::new(0);
+        assert_eq!(errors.update(10), Some((10, 0.0)));
+        // 3 errors a check on average after a while.
+        for total in (13..=100).step_by(3) {
+            errors.update(total);
+        }
+        let usual = errors.rate;
+        assert!((usual - 3.0).abs() < 0.1, "{}", usual);
+        // 3 times the usual rate spikes, just under doesn't.
+        assert_eq!(errors.update(100 + 10), Some((10, usual)));
+        let mut errors = EdgeErrors {
+            total: 0,
+            rate: 4.0,
+        };
+        assert_eq!(errors.update(11), None);
+        let mut errors = EdgeErrors {
+            total: 0,
+            rate: 4.0,
+        };
+        assert_eq!(errors.update(12), Some((12, 4.0)));
+    }
+
+    #[test]
+    fn test_edge_errors_start_over() {
+        let mut errors = EdgeErrors::new(500);
+        // The edge expired and came back with fewer errors.
+        assert_eq!(errors.update(20), None);
+        assert_eq!(errors.total, 20);
+        assert_eq!(errors.update(40), Some((20, 0.0)));
+    }
+
+    fn container(restarts: i32, reason: Option<&str>) -> ContainerStatus {
+        ContainerStatus {
+            name: "app".to_string(),
+            restart_count: restarts,
+            last_state: Some(ContainerState {
+                terminated: Some(ContainerStateTerminated {
+                    reason: reason.map(str::to_string),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_container_oom_killed() {
+        assert!(oom_killed_since(&container(3, Some(OOM_KILLED)), Some(2)));
+        // Killed before the agent saw the container, or already reported.
+        assert!(!oom_killed_since(&container(3, Some(OOM_KILLED)), None));
+        assert!(!oom_killed_since(&container(3, Some(OOM_KILLED)), Some(3)));
+        assert!(!oom_killed_since(&container(3, Some("Error")), Some(2)));
+        assert!(!oom_killed_since(&container(3, None), Some(2)));
+    }
+
+    #[test]
+    fn test_kernel_oom_kills() {
+        assert_eq!(other_oom_kills(Some(5), Some(8), 0), Some(3));
+        // Those of containers reported already aren't counted twice.
+        assert_eq!(other_oom_kills(Some(5), Some(8), 1), Some(2));
+        assert_eq!(other_oom_kills(Some(5), Some(8), 3), None);
+        assert_eq!(other_oom_kills(Some(5), Some(5), 0), None);
+        // The first read, or a failed one, is no baseline.
+        assert_eq!(other_oom_kills(None, Some(8), 0), None);
+        assert_eq!(other_oom_kills(Some(5), None, 0), None);
+    }
+
+    #[test]
+    fn test_map_pressure_hysteresis() {
+        let mut pressure = MapPressure::default();
+        let pin = Path::new("/run/eva/fs/maps/1/conn_info");
+        assert!(!pressure.update(pin, 0.85));
+        assert!(pressure.update(pin, 0.9));
+        // Reported once while it stays full, including around the high mark.
+        assert!(!pressure.update(pin, 0.95));
+        assert!(!pressure.update(pin, 0.85));
+        assert!(!pressure.update(pin, 0.9));
+        // Reported again only after going under the low mark.
+        assert!(!pressure.update(pin, 0.5));
+        assert!(pressure.update(pin, 0.99));
+        assert!(pressure.update(Path::new("/run/eva/fs/maps/2/conn_info"), 1.0));
+    }
+
+    #[test]
+    fn test_cooldown() {
+        let mut cooldowns = Cooldowns::default();
+        let start = Instant::now();
+        assert!(cooldowns.publish("OOMKill", start));
+        // The same finding twice in a check, or in the next ones, is recorded once.
+        assert!(!cooldowns.publish("OOMKill", start));
+        assert!(!cooldowns.publish("OOMKill", start + COOLDOWN / 2));
+        assert!(cooldowns.publish("MapCapacityPressure/conn-tracer/conn_info", start));
+        assert!(cooldowns.publish("OOMKill", start + COOLDOWN));
+        assert!(!cooldowns.publish("OOMKill", start + COOLDOWN * 3 / 2));
+    }
+}
@@ -0,0 +1,163 @@
+use std::collections::HashSet;
+use std::io;
+use std::mem;
+use std::time::Duration;
+
+use log::{info, warn};
+use tokio::sync::broadcast;
+use tokio::time;
+
+use agent_api::ProgramState;
+
+use crate::common::bpf::{
+    bpf, GetIdAttr, GetInfoAttr, BPF_LINK_GET_FD_BY_ID, BPF_LINK_GET_NEXT_ID,
+    BPF_OBJ_GET_INFO_BY_FD, BPF_PROG_GET_FD_BY_ID,
+};
+use crate::common::types::ListFilter;
+use crate::managers::registry::RegistryManager;
+use crate::progs::types::ShutdownSignal;
+
+/// Verifies that the eBPF programs owning the maps of the running user programs are
+/// still loaded and attached. bpfman owns those programs, so they can't be re-attached
+/// from here: user programs whose eBPF programs were unloaded or detached, e.g. by
+/// other tooling, are marked degraded until they are back. Programs attached without
+/// a bpf link are only known to be detached once a link was seen for them.
+#[derive(Debug)]
+pub(crate) struct HealthChecker {
+    registry_manager: RegistryManager,
+    /// eBPF programs seen attached through a bpf link.
+    linked: HashSet<u32>,
+}
+
+impl HealthChecker {
+    pub(crate) fn new(registry_manager: RegistryManager) -> Self {
+        Self {
+            registry_manager,
+            linked: HashSet::new(),
+        }
+    }
+
+    pub(crate) async fn run(
+        mut self,
+        interval: Duration,
+        mut shutdown_rx: broadcast::Receiver<ShutdownSignal>,
+    ) {
+        let mut interval = time::interval(interval);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => self.check(),
+                signal = shutdown_rx.recv() => match signal {
+                    Ok(ShutdownSignal::All) | Err(_) => break,
+                    Ok(_) => {}
+                },
+            }
+        }
+    }
+
+    fn check(&mut self) {
+        let linked = match linked_programs() {
+            Ok(linked) => linked,
+            Err(e) => {
+                warn!("Failed to list bpf links: {:?}", e);
+                return;
+            }
+        };
+        self.linked.extend(&linked);
+
+        let programs = self
+            .registry_manager
+            .list_programs(ListFilter::new(None, Default::default()));
+        for program in programs {
+            let state = program.get_state();
+            if state != ProgramState::Running && state != ProgramState::Degraded {
+                continue;
+            }
+            let Ok(info) = program.get_program_info() else {
+                continue;
+            };
+
+            let mut problems = Vec::new();
+            for prog_id in info.ebpf_maps.values().collect::<HashSet<_>>() {
+                match is_loaded(*prog_id) {
+                    Ok(false) => problems.push(format!("eBPF program {} was unloaded", prog_id)),
+                    Ok(true) if self.linked.contains(prog_id) && !linked.contains(prog_id) => {
+                        problems.push(format!("eBPF program {} was detached", prog_id))
+                    }
+                    Ok(true) => {}
+                    Err(e) => warn!("Failed to look up eBPF program {}: {:?}", prog_id, e),
+                }
+            }
+
+            match (state, problems.is_empty()) {
+                (ProgramState::Running, false) => {
+                    warn!("Program {} degraded: {}", info.name, problems.join(", "));
+                    program.set_state(ProgramState::Degraded);
+                }
+                (ProgramState::Degraded, true) => {
+                    info!("Program {} recovered", info.name);
+                    program.set_state(ProgramState::Running);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// The leading fields of `struct bpf_link_info`, which are all that is needed.
+#[repr(C)]
+#[derive(Default)]
+struct LinkInfo {
+    link_type: u32,
+    id: u32,
+    prog_id: u32,
+}
+
+fn is_loaded(prog_id: u32) -> io::Result<bool> {
+    let mut attr = GetIdAttr {
+        id: prog_id,
+        ..Default::default()
+    };
+    match bpf(BPF_PROG_GET_FD_BY_ID, &mut attr) {
+        Ok(fd) => {
+            unsafe { libc::close(fd as i32) };
+            Ok(true)
+        }
+        Err(e) if e.raw_os_error() == Some(libc::ENOENT) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Returns the ids of the programs attached through a bpf link.
+fn linked_programs() -> io::Result<HashSet<u32>> {
+    let mut programs = HashSet::new();
+    let mut attr = GetIdAttr::default();
+    loop {
+        match bpf(BPF_LINK_GET_NEXT_ID, &mut attr) {
+            Ok(_) => {}
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => return Ok(programs),
+            Err(e) => return Err(e),
+        }
+        attr.id = attr.next_id;
+
+        let mut by_id = GetIdAttr {
+            id: attr.next_id,
+            ..Default::default()
+        };
+        let fd = match bpf(BPF_LINK_GET_FD_BY_ID, &mut by_id) {
+            Ok(fd) => fd as i32,
+            // Released meanwhile.
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => continue,
+            Err(e) => return Err(e),
+        };
+        let mut link = LinkInfo::default();
+        let mut info = GetInfoAttr {
+            bpf_fd: fd as u32,
+            info_len: mem::size_of::<LinkInfo>() as u32,
+            info: &mut link as *mut LinkInfo as u64,
+        };
+        let res = bpf(BPF_OBJ_GET_INFO_BY_FD, &mut info);
+        unsafe { libc::close(fd) };
+        res?;
+        programs.insert(link.prog_id);
+    }
+}
@@ -0,0 +1,95 @@
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+
+use ahash::AHashMap;
+use anyhow::Error;
+use parking_lot::RwLock;
+
+use crate::common::maps::MapAccess;
+use crate::managers::cache::{CacheManager, Workload};
+
+/// Numeric identities of workloads, from 1, which tracers are given for the IPs of each
+/// so that the kernel tells workloads apart, and the agent resolves the ends of a
+/// connection without looking its addresses up. A workload keeps its identity once
+/// given one, for the kernel may still have it in its maps.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct IdentityTable {
+    inner: Arc<RwLock<Identities>>,
+}
+
+#[derive(Debug, Default)]
+struct Identities {
+    ids: AHashMap<Arc<Workload>, u32>,
+    /// Workload of each identity, at its index minus one.
+    workloads: Vec<Arc<Workload>>,
+}
+
+impl IdentityTable {
+    /// Returns the identity of a workload, giving it one if it has none yet.
+    pub(crate) fn identity(&self, workload: &Arc<Workload>) -> u32 {
+        if let Some(&id) = self.inner.read().ids.get(workload) {
+            return id;
+        }
+        let mut inner = self.inner.write();
+        if let Some(&id) = inner.ids.get(workload) {
+            return id;
+        }
+        let next = inner.workloads.len() as u32 + 1;
+        inner.ids.insert(workload.clone(), next);
+        inner.workloads.push(workload.clone());
+        next
+    }
+
+    pub(crate) fn workload(&self, identity: u32) -> Option<Arc<Workload>> {
+        let index = identity.checked_sub(1)?;
+        self.inner.read().workloads.get(index as usize).cloned()
+    }
+}
+
+/// The map of a tracer the identity of each IP is pushed down to, as `IDENTITIES`.
+#[derive(Debug)]
+pub(crate) struct IdentityMap {
+    map: Box<dyn MapAccess<u32, u32>>,
+    /// Identity pushed down for each IP, in host byte order.
+    pushed: AHashMap<u32, u32>,
+}
+
+impl IdentityMap {
+    pub(crate) fn new(map: Box<dyn MapAccess<u32, u32>>) -> Self {
+        Self {
+            map,
+            pushed: AHashMap::new(),
+        }
+    }
+
+    /// Pushes down the identity of each IP the cache manager knows, and removes those
+    /// of the IPs it forgot. Only changes are written to the map.
+    pub(crate) fn push(&mut self, cache_mgr: &CacheManager) -> Result<(), Error> {
+        let mut ips: Vec<(u32, Arc<Workload>)> = cache_mgr
+            .ip_to_workload
+            .read()
+            .iter()
+            .filter_map(|(ip, workload)| {
+                Some((ip.parse::<Ipv4Addr>().ok()?.into(), workload.clone()))
+            })
+            .collect();
+        // In order, for the identities given to be the same from one run to the next.
+        ips.sort_by_key(|(ip, _)| *ip);
+
+        let mut pushed = AHashMap::with_capacity(ips.len());
+        for (ip, workload) in ips {
+            let identity = cache_mgr.identities.identity(&workload);
+            if self.pushed.get(&ip) != Some(&identity) {
+                self.map.insert(&ip, &identity)?;
+            }
+            pushed.insert(ip, identity);
+        }
+        for ip in self.pushed.keys() {
+            if !pushed.contains_key(ip) {
+                self.map.remove(ip)?;
+            }
+        }
+        self.pushed = pushed;
+        Ok(())
+    }
+}
@@ -1,8 +1,187 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use aya::programs::{Program, ProgramError};
+use aya::BpfLoader;
+use parking_lot::Mutex;
+
+use agent_api::v1::bytecode_location::Location;
+use agent_api::v1::{BytecodeLocation, LoadDiagnostics};
+use agent_api::ProgramType;
+
+use crate::common::errors::AgentError;
+
+const BPF_FS: &str = "/sys/fs/bpf";
+/// Magic number and version every binary WASM module starts with.
+const WASM_HEADER: &[u8; 8] = b"\0asm\x01\0\0\0";
+
+/// Numbers the throwaway loads, so that concurrent ones pin their maps apart.
+static THROWAWAY_LOADS: AtomicU32 = AtomicU32::new(0);
+
+/// Outcome of a bytecode check that did not fail.
+#[derive(Debug)]
+pub(crate) enum Verification {
+    /// The bytecode was checked, as described.
+    Passed(String),
+    /// The bytecode could not be checked, for the given reason.
+    Skipped(String),
+}
+
 #[derive(Clone, Debug)]
-pub(crate) struct ImageManager {}
+pub(crate) struct ImageManager {
+    /// Why the verifier rejected the last load of each program, by program name.
+    diagnostics: Arc<Mutex<HashMap<String, LoadDiagnostics>>>,
+}
 
 impl ImageManager {
     pub(crate) fn new() -> Self {
-        Self {}
+        Self {
+            diagnostics: Arc::default(),
+        }
+    }
+
+    /// Keeps the verifier log of a rejected load of `program`, until the bytecode of
+    /// one of its next loads is verified. Other errors are not the verifier's.
+    pub(crate) fn record_rejection(&self, program: &str, error: &AgentError) {
+        let AgentError::VerifierRejected {
+            program: ebpf_program,
+            error,
+            verifier_log,
+        } = error
+        else {
+            return;
+        };
+        let timestamp_ns = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64);
+        self.diagnostics.lock().insert(
+            program.to_string(),
+            LoadDiagnostics {
+                timestamp_ns,
+                ebpf_program: ebpf_program.clone(),
+                error: error.clone(),
+                verifier_log: verifier_log.clone(),
+            },
+        );
+    }
+
+    pub(crate) fn clear_diagnostics(&self, program: &str) {
+        self.diagnostics.lock().remove(program);
+    }
+
+    /// Returns why the verifier rejected the last load of `program`, if it did.
+    pub(crate) fn diagnostics(&self, program: &str) -> Option<LoadDiagnostics> {
+        self.diagnostics.lock().get(program).cloned()
+    }
+
+    /// Checks that the bytecode of a program could be loaded, without attaching
+    /// anything. Each program of an eBPF object is loaded into the kernel, so that it
+    /// goes through the verifier, and unloaded right away. WASM modules are checked to
+    /// be binary modules. Blocks while the programs are verified.
+    pub(crate) fn verify(
+        &self,
+        bytecode: &BytecodeLocation,
+        program_type: ProgramType,
+    ) -> anyhow::Result<Verification> {
+        let path = match &bytecode.location {
+            Some(Location::File(path)) => Path::new(path),
+            Some(Location::Image(image)) => {
+                return Ok(Verification::Skipped(format!(
+                    "Pulling image {} is not supported",
+                    image.url
+                )))
+            }
+            None => return Ok(Verification::Skipped("No bytecode given".to_string())),
+        };
+        match program_type {
+            ProgramType::Wasm => verify_wasm(path),
+            ProgramType::Builtin => verify_ebpf(path),
+        }
+    }
+}
+
+fn verify_wasm(path: &Path) -> anyhow::Result<Verification> {
+    let module = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    if !module.starts_with(WASM_HEADER) {
+        return Err(anyhow::anyhow!(
+            "{} is not a binary WASM module",
+            path.display()
+        ));
+    }
+    Ok(Verification::Passed(format!(
+        "{} is a binary WASM module",
+        path.display()
+    )))
+}
+
+fn verify_ebpf(path: &Path) -> anyhow::Result<Verification> {
+    let bpf_fs = Path::new(BPF_FS);
+    if !bpf_fs.exists() {
+        return Err(AgentError::KernelUnsupported(format!("{} does not exist", BPF_FS)).into());
+    }
+    // Pinned maps are created in a directory of their own, so that the maps of the
+    // programs already loaded are left alone.
+    let pin_dir = bpf_fs.join(format!(
+        "bpfconductor-validate-{}-{}",
+        std::process::id(),
+        THROWAWAY_LOADS.fetch_add(1, Ordering::Relaxed)
+    ));
+    fs::create_dir_all(&pin_dir)?;
+    let result = load_programs(path, &pin_dir);
+    let _ = fs::remove_dir_all(&pin_dir);
+    result
+}
+
+/// Loads every program of the object at `path`, unloading them when returning. A
+/// program the verifier rejects fails with its log, as [`AgentError::VerifierRejected`].
+fn load_programs(path: &Path, pin_dir: &Path) -> anyhow::Result<Verification> {
+    let mut bpf = BpfLoader::new()
+        .map_pin_path(pin_dir)
+        .load_file(path)
+        .with_context(|| format!("Failed to load {}", path.display()))?;
+    let (mut verified, mut skipped) = (Vec::new(), Vec::new());
+    for (name, program) in bpf.programs_mut() {
+        let loaded = match program {
+            Program::KProbe(p) => p.load(),
+            Program::UProbe(p) => p.load(),
+            Program::TracePoint(p) => p.load(),
+            Program::Xdp(p) => p.load(),
+            Program::SchedClassifier(p) => p.load(),
+            Program::SocketFilter(p) => p.load(),
+            // Others need their attach target or the kernel BTF to be loaded.
+            _ => {
+                skipped.push(name.to_string());
+                continue;
+            }
+        };
+        match loaded {
+            Ok(()) => {}
+            Err(ProgramError::LoadError {
+                io_error,
+                verifier_log,
+            }) => {
+                return Err(AgentError::VerifierRejected {
+                    program: name.to_string(),
+                    error: io_error.to_string(),
+                    verifier_log: verifier_log.to_string(),
+                }
+                .into())
+            }
+            Err(e) => return Err(e).with_context(|| format!("Program {} was rejected", name)),
+        }
+        verified.push(name.to_string());
+    }
+    if verified.is_empty() && skipped.is_empty() {
+        return Err(anyhow::anyhow!("{} has no programs", path.display()));
+    }
+    let mut message = format!("Verified programs: {}", verified.join(", "));
+    if !skipped.is_empty() {
+        message.push_str(&format!("; not verified: {}", skipped.join(", ")));
     }
+    Ok(Verification::Passed(message))
 }
@@ -0,0 +1,132 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+
+use log::debug;
+
+use agent_api::v1::{KernelMap, KernelProgram, ProgramInfo, ProgramInspection};
+use bpfman_api::v1::attach_info::Info;
+use bpfman_api::v1::list_response::ListResult;
+use bpfman_api::v1::AttachInfo;
+
+use crate::common::bpf::{pinned_map_id, program_stats, program_type_name, ProgramStats};
+
+/// Describes the eBPF programs bpfman loaded for a managed program, i.e. those owning
+/// its maps and those sharing them, with the maps pinned for them. `loaded` lists the
+/// programs bpfman loaded.
+pub(crate) fn inspect(info: &ProgramInfo, loaded: &[ListResult]) -> ProgramInspection {
+    let mut programs = Vec::new();
+    let mut pin_dirs = BTreeMap::new();
+    for result in loaded {
+        let (Some(bpfman), Some(kernel)) = (&result.info, &result.kernel_info) else {
+            continue;
+        };
+        let owner_id = bpfman.map_owner_id.unwrap_or(kernel.id);
+        if !info
+            .ebpf_maps
+            .values()
+            .any(|&id| id == kernel.id || id == owner_id)
+        {
+            continue;
+        }
+        if !bpfman.map_pin_path.is_empty() {
+            pin_dirs.insert(owner_id, bpfman.map_pin_path.clone());
+        }
+
+        // Unloaded since bpfman listed it.
+        let stats = program_stats(kernel.id).unwrap_or_else(|e| {
+            debug!("Failed to look up eBPF program {}: {}", kernel.id, e);
+            ProgramStats {
+                id: kernel.id,
+                name: kernel.name.clone(),
+                ..Default::default()
+            }
+        });
+        programs.push(KernelProgram {
+            id: stats.id,
+            name: stats.name,
+            program_type: program_type_name(stats.program_type),
+            attach_point: attach_point(bpfman.attach.as_ref()),
+            map_ids: stats.map_ids,
+            run_cnt: stats.run_cnt,
+            run_time_ns: stats.run_time_ns,
+        });
+    }
+    programs.sort_by_key(|program| program.id);
+
+    let mut maps = Vec::new();
+    for (owner_id, dir) in pin_dirs {
+        let Ok(entries) = fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            maps.push(KernelMap {
+                id: pinned_map_id(&path).unwrap_or_default(),
+                name: entry.file_name().to_string_lossy().into_owned(),
+                owner_id,
+                pin_path: path.to_string_lossy().into_owned(),
+            });
+        }
+    }
+    maps.sort_by(|a, b| (a.owner_id, &a.name).cmp(&(b.owner_id, &b.name)));
+
+    ProgramInspection {
+        name: info.name.clone(),
+        programs,
+        maps,
+    }
+}
+
+/// Turns the run statistics of `inspections` into the runs since `before` was taken.
+pub(crate) fn runs_since(inspections: &mut [ProgramInspection], before: &[ProgramInspection]) {
+    let before: HashMap<u32, (u64, u64)> = before
+        .iter()
+        .flat_map(|inspection| &inspection.programs)
+        .map(|program| (program.id, (program.run_cnt, program.run_time_ns)))
+        .collect();
+    for program in inspections
+        .iter_mut()
+        .flat_map(|inspection| &mut inspection.programs)
+    {
+        let (run_cnt, run_time_ns) = before.get(&program.id).copied().unwrap_or_default();
+        program.run_cnt = program.run_cnt.saturating_sub(run_cnt);
+        program.run_time_ns = program.run_time_ns.saturating_sub(run_time_ns);
+    }
+}
+
+/// Describes where bpfman attached a program, the way bpftool lists perf events and
+/// links, e.g. `kretprobe:__sys_connect` or `tc:eth0/ingress`.
+fn attach_point(attach: Option<&AttachInfo>) -> String {
+    let Some(info) = attach.and_then(|attach| attach.info.as_ref()) else {
+        return String::new();
+    };
+    match info {
+        Info::KprobeAttachInfo(kprobe) => {
+            let kind = if kprobe.retprobe {
+                "kretprobe"
+            } else {
+                "kprobe"
+            };
+            match kprobe.offset {
+                0 => format!("{}:{}", kind, kprobe.fn_name),
+                offset => format!("{}:{}+{:#x}", kind, kprobe.fn_name, offset),
+            }
+        }
+        Info::UprobeAttachInfo(uprobe) => {
+            let kind = if uprobe.retprobe {
+                "uretprobe"
+            } else {
+                "uprobe"
+            };
+            match &uprobe.fn_name {
+                Some(fn_name) => format!("{}:{}:{}", kind, uprobe.target, fn_name),
+                None => format!("{}:{}+{:#x}", kind, uprobe.target, uprobe.offset),
+            }
+        }
+        Info::TracepointAttachInfo(tracepoint) => format!("tracepoint:{}", tracepoint.tracepoint),
+        Info::XdpAttachInfo(xdp) => format!("xdp:{}", xdp.iface),
+        Info::TcAttachInfo(tc) => format!("tc:{}/{}", tc.iface, tc.direction),
+        Info::FentryAttachInfo(fentry) => format!("fentry:{}", fentry.fn_name),
+        Info::FexitAttachInfo(fexit) => format!("fexit:{}", fexit.fn_name),
+    }
+}
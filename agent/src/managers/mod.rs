@@ -1,4 +1,21 @@
+pub(crate) mod alias;
+pub(crate) mod audit;
 pub(crate) mod cache;
+pub(crate) mod cache_health;
+pub(crate) mod capture;
+pub(crate) mod container;
+pub(crate) mod events;
+pub(crate) mod findings;
+pub(crate) mod health;
+pub(crate) mod identity;
 pub(crate) mod image;
+pub(crate) mod inspect;
+pub(crate) mod pod_trace;
+pub(crate) mod process;
 pub(crate) mod prog;
 pub(crate) mod registry;
+pub(crate) mod scheduler;
+pub(crate) mod slow_query;
+pub(crate) mod symbol;
+pub(crate) mod trace_context;
+pub(crate) mod windows;
@@ -0,0 +1,430 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, bail, Context};
+use aya::maps::{HashMap, Map, MapData, PerCpuArray, PerCpuValues};
+use aya::util::nr_cpus;
+use aya::Pod;
+use log::{info, warn};
+use parking_lot::Mutex;
+use tokio::task::JoinHandle;
+use tokio::time::{self, Instant};
+
+use agent_api::v1::{TracePodRequest, TracePodResponse};
+use socket_tracer_common::{
+    ControlValueIndex, EndpointRole, TrafficProtocol, MAX_TARGET_TGIDS, TARGET_TGID_SET,
+};
+
+use crate::common::graph::node_id;
+use crate::managers::cache::{CacheManager, Workload};
+use crate::managers::events::EventsManager;
+
+const DEFAULT_DURATION: Duration = Duration::from_secs(300);
+/// The longest a pod is traced for, so that a forgotten trace can't keep the tracer
+/// focused for good.
+const MAX_DURATION: Duration = Duration::from_secs(3600);
+/// How often the processes of a traced pod are looked for again, to trace the workers
+/// it forks and its restarted containers too.
+const TARGETS_REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+/// Command of the process holding the namespaces of a pod, which serves no traffic.
+const PAUSE_COMM: &str = "pause";
+const CONTROL_VALUES_MAP: &str = "ctrl_values";
+const CONTROL_MAP: &str = "ctrl_map";
+const TARGET_TGIDS_MAP: &str = "target_tgids";
+
+/// The socket tracer settings a trace changes, saved to be put back once it ends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct TracerSettings {
+    /// Process whose sockets are traced, those of the `target_tgids` map when
+    /// `TARGET_TGID_SET`, or all of them when otherwise not positive.
+    target_tgid: i64,
+    /// Roles HTTP is parsed for, as a mask of `EndpointRole`s.
+    http_roles: u64,
+}
+
+impl TracerSettings {
+    /// Reads the settings saved to `path` by [`TracerSettings::save`], if any.
+    fn load(path: &Path) -> anyhow::Result<Option<Self>> {
+        let saved = match fs::read_to_string(path) {
+            Ok(saved) => saved,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        };
+        let settings = saved
+            .split_once(' ')
+            .and_then(|(target_tgid, http_roles)| {
+                Some(Self {
+                    target_tgid: target_tgid.parse().ok()?,
+                    http_roles: http_roles.trim_end().parse().ok()?,
+                })
+            })
+            .ok_or_else(|| anyhow!("Invalid socket tracer settings in {}", path.display()))?;
+        Ok(Some(settings))
+    }
+
+    /// Saves the settings to `path` as `<TARGET_TGID> <HTTP_ROLES>`.
+    fn save(&self, path: &Path) -> anyhow::Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, format!("{} {}\n", self.target_tgid, self.http_roles))
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+}
+
+/// Returns the processes to trace among the `pids` of a pod, given their command: all
+/// of them but the pause process, those forked by others included, up to the number
+/// the tracer holds.
+fn traced_tgids(
+    pids: impl IntoIterator<Item = u32>,
+    comm: impl Fn(u32) -> Option<String>,
+) -> BTreeSet<u32> {
+    pids.into_iter()
+        .filter(|pid| comm(*pid).is_some_and(|comm| comm.trim_end() != PAUSE_COMM))
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .take(MAX_TARGET_TGIDS as usize)
+        .collect()
+}
+
+#[derive(Debug)]
+struct Trace {
+    id: u64,
+    workload: String,
+    saved: TracerSettings,
+    expiry: JoinHandle<()>,
+}
+
+/// Focuses the socket tracer and request sampling on one pod for a while, through the
+/// control maps the tracer pinned in `maps_dir`: only the sockets of the pod's
+/// processes are traced, those of its sidecars included, HTTP is parsed both as client
+/// and server, and every request from or to the pod's workload is sampled. Everything
+/// is put back once the trace expires, or is stopped or replaced by another one, since
+/// the tracer traces a single set of processes at most.
+///
+/// The settings put back are saved to `state_path`, so that a trace the agent stopped
+/// during is ended by [`PodTracer::restore`] once it starts again.
+#[derive(Debug, Clone)]
+pub(crate) struct PodTracer {
+    maps_dir: PathBuf,
+    state_path: PathBuf,
+    cache_manager: CacheManager,
+    events_manager: EventsManager,
+    current: Arc<Mutex<Option<Trace>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl PodTracer {
+    pub(crate) fn new(
+        maps_dir: PathBuf,
+        state_path: PathBuf,
+        cache_manager: CacheManager,
+        events_manager: EventsManager,
+    ) -> Self {
+        Self {
+            maps_dir,
+            state_path,
+            cache_manager,
+            events_manager,
+            current: Arc::new(Mutex::new(None)),
+            next_id: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Ends the running trace, if any, then traces the requested pod. A request
+    /// without a pod only ends the running trace.
+    pub(crate) fn start(&self, request: &TracePodRequest) -> anyhow::Result<TracePodResponse> {
+        let mut current = self.current.lock();
+        self.end(&mut current);
+        if request.pod.is_empty() {
+            return Ok(TracePodResponse::default());
+        }
+
+        let (workload, tgids) = self.pod_tgids(&request.namespace, &request.pod)?;
+        let duration = match request.duration_secs {
+            0 => DEFAULT_DURATION,
+            secs => Duration::from_secs(secs.into()).min(MAX_DURATION),
+        };
+
+        // Settings left by a trace that couldn't be ended are the ones to put back.
+        let saved = match TracerSettings::load(&self.state_path)? {
+            Some(saved) => saved,
+            None => self.settings()?,
+        };
+        saved.save(&self.state_path)?;
+        let focused = TracerSettings {
+            target_tgid: TARGET_TGID_SET,
+            http_roles: EndpointRole::Client as u64 | EndpointRole::Server as u64,
+        };
+        if let Err(e) = self.set_targets(&tgids).and_then(|()| self.apply(focused)) {
+            // Any map may have been written already.
+            self.restore_settings(saved);
+            return Err(e);
+        }
+        let workload = node_id(&workload);
+        self.events_manager.focus(Some(workload.clone()));
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let tracer = self.clone();
+        let (namespace, pod) = (request.namespace.clone(), request.pod.clone());
+        let expiry = tokio::spawn(async move {
+            let deadline = Instant::now() + duration;
+            let mut refresh = time::interval_at(
+                Instant::now() + TARGETS_REFRESH_INTERVAL,
+                TARGETS_REFRESH_INTERVAL,
+            );
+            loop {
+                tokio::select! {
+                    _ = time::sleep_until(deadline) => break,
+                    _ = refresh.tick() => tracer.refresh(id, &namespace, &pod),
+                }
+            }
+            tracer.expire(id);
+        });
+        info!(
+            "Tracing pod {}/{} of {}, processes {:?}, for {:?}",
+            request.namespace, request.pod, workload, tgids, duration
+        );
+        let pid = tgids.first().copied().unwrap_or_default();
+        *current = Some(Trace {
+            id,
+            workload: workload.clone(),
+            saved,
+            expiry,
+        });
+
+        let expires_at = SystemTime::now() + duration;
+        Ok(TracePodResponse {
+            workload,
+            pid,
+            expires_at_ns: expires_at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos() as u64,
+        })
+    }
+
+    /// Puts back the settings saved by a trace the agent didn't end, having stopped
+    /// during it. Called once the agent starts.
+    pub(crate) fn restore(&self) {
+        match TracerSettings::load(&self.state_path) {
+            Ok(Some(saved)) => {
+                info!("Ending the pod trace running when the agent stopped");
+                self.restore_settings(saved);
+            }
+            Ok(None) => {}
+            Err(e) => warn!("Failed to restore the socket tracer settings: {:#}", e),
+        }
+    }
+
+    /// Ends the running trace, if any. Called as the agent stops.
+    pub(crate) fn stop(&self) {
+        self.end(&mut self.current.lock());
+    }
+
+    fn end(&self, current: &mut Option<Trace>) {
+        match current.take() {
+            Some(trace) => {
+                trace.expiry.abort();
+                self.revert(trace);
+            }
+            // A trace may have been left running by an agent stopped during it.
+            None => self.restore(),
+        }
+    }
+
+    /// Traces the processes the pod of trace `id` runs now, unless the trace was
+    /// already ended or replaced.
+    fn refresh(&self, id: u64, namespace: &str, pod: &str) {
+        let current = self.current.lock();
+        if current.as_ref().map(|trace| trace.id) != Some(id) {
+            return;
+        }
+        // The pod may be gone, or restarting its containers; its last processes stay
+        // traced until the trace ends.
+        let Ok((_, tgids)) = self.pod_tgids(namespace, pod) else {
+            return;
+        };
+        if let Err(e) = self.set_targets(&tgids) {
+            warn!(
+                "Failed to update the processes traced of pod {}/{}: {:#}",
+                namespace, pod, e
+            );
+        }
+    }
+
+    /// Ends the trace `id`, unless it was already ended or replaced.
+    fn expire(&self, id: u64) {
+        let mut current = self.current.lock();
+        if current.as_ref().is_some_and(|trace| trace.id == id) {
+            if let Some(trace) = current.take() {
+                self.revert(trace);
+            }
+        }
+    }
+
+    fn revert(&self, trace: Trace) {
+        self.events_manager.focus(None);
+        if self.restore_settings(trace.saved) {
+            info!("Stopped tracing {}", trace.workload);
+        }
+    }
+
+    /// Puts back `saved`, forgetting them once they are, and whether they were.
+    fn restore_settings(&self, saved: TracerSettings) -> bool {
+        if let Err(e) = self.apply(saved) {
+            warn!("Failed to restore the socket tracer settings: {:#}", e);
+            return false;
+        }
+        if let Err(e) = self.set_targets(&BTreeSet::new()) {
+            warn!("Failed to clear the processes traced: {:#}", e);
+        }
+        match fs::remove_file(&self.state_path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                warn!("Failed to remove {}: {}", self.state_path.display(), e)
+            }
+            _ => {}
+        }
+        true
+    }
+
+    /// Returns the workload of a pod, and the processes to trace of it.
+    fn pod_tgids(
+        &self,
+        namespace: &str,
+        pod: &str,
+    ) -> anyhow::Result<(Arc<Workload>, BTreeSet<u32>)> {
+        let (workload, pids) = self
+            .cache_manager
+            .pod_processes(namespace, pod)
+            .ok_or_else(|| anyhow!("Pod {}/{} not found", namespace, pod))?;
+        let tgids = traced_tgids(pids, |pid| {
+            fs::read_to_string(format!("/proc/{}/comm", pid)).ok()
+        });
+        if tgids.is_empty() {
+            bail!("No process of pod {}/{} runs on this node", namespace, pod);
+        }
+        Ok((workload, tgids))
+    }
+
+    /// Reads the current settings from the first CPU, the tracer setting every CPU
+    /// to the same value.
+    fn settings(&self) -> anyhow::Result<TracerSettings> {
+        let values: PerCpuArray<_, i64> = self.control_map(CONTROL_VALUES_MAP)?;
+        let roles: PerCpuArray<_, u64> = self.control_map(CONTROL_MAP)?;
+        let target_tgid = values.get(&(ControlValueIndex::TargetTGIDIndex as u32), 0)?;
+        let http_roles = roles.get(&(TrafficProtocol::HTTP as u32), 0)?;
+        Ok(TracerSettings {
+            target_tgid: target_tgid.first().copied().unwrap_or_default(),
+            http_roles: http_roles.first().copied().unwrap_or_default(),
+        })
+    }
+
+    fn apply(&self, settings: TracerSettings) -> anyhow::Result<()> {
+        let cpus = nr_cpus()?;
+        let mut values: PerCpuArray<_, i64> = self.control_map(CONTROL_VALUES_MAP)?;
+        values.set(
+            ControlValueIndex::TargetTGIDIndex as u32,
+            PerCpuValues::try_from(vec![settings.target_tgid; cpus])?,
+            0,
+        )?;
+        let mut roles: PerCpuArray<_, u64> = self.control_map(CONTROL_MAP)?;
+        roles.set(
+            TrafficProtocol::HTTP as u32,
+            PerCpuValues::try_from(vec![settings.http_roles; cpus])?,
+            0,
+        )?;
+        Ok(())
+    }
+
+    /// Makes `tgids` the processes of the `target_tgids` map.
+    fn set_targets(&self, tgids: &BTreeSet<u32>) -> anyhow::Result<()> {
+        let mut targets: HashMap<_, u32, u8> =
+            Map::HashMap(self.pinned_map(TARGET_TGIDS_MAP)?).try_into()?;
+        let gone: Vec<u32> = targets
+            .keys()
+            .filter_map(Result::ok)
+            .filter(|tgid| !tgids.contains(tgid))
+            .collect();
+        for tgid in gone {
+            targets.remove(&tgid)?;
+        }
+        for tgid in tgids {
+            targets.insert(tgid, 1, 0)?;
+        }
+        Ok(())
+    }
+
+    fn control_map<V: Pod>(&self, name: &str) -> anyhow::Result<PerCpuArray<MapData, V>> {
+        Ok(Map::PerCpuArray(self.pinned_map(name)?).try_into()?)
+    }
+
+    fn pinned_map(&self, name: &str) -> anyhow::Result<MapData> {
+        let pin = self.maps_dir.join(name);
+        if !pin.exists() {
+            bail!(
+                "No socket tracer map at {}, is the socket tracer running?",
+                pin.display()
+            );
+        }
+        MapData::from_pin(&pin).with_context(|| format!("Failed to open {}", pin.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_traced_tgids() {
+        let comms = std::collections::HashMap::from([
+            (1, "pause"),
+            (7, "nginx"),
+            (8, "nginx"),
+            (9, "nginx"),
+            (12, "envoy"),
+        ]);
+        let comm = |pid| comms.get(&pid).map(|comm| format!("{}\n", comm));
+        // The workers forked by the master and the sidecar are traced too; processes
+        // gone since the pod was scanned aren't.
+        assert_eq!(
+            traced_tgids([12, 9, 1, 7, 8, 30], comm),
+            BTreeSet::from([7, 8, 9, 12])
+        );
+        assert!(traced_tgids([1], comm).is_empty());
+
+        let many = traced_tgids(1..=MAX_TARGET_TGIDS + 10, |_| Some("worker".to_string()));
+        assert_eq!(many.len(), MAX_TARGET_TGIDS as usize);
+        assert_eq!(many.first(), Some(&1));
+    }
+
+    #[test]
+    fn test_settings_saved() {
+        let path = std::env::temp_dir()
+            .join(format!("agent-pod-trace-{}", std::process::id()))
+            .join("pod-trace");
+        assert_eq!(TracerSettings::load(&path).unwrap(), None);
+
+        let settings = TracerSettings {
+            target_tgid: 0,
+            http_roles: EndpointRole::Server as u64,
+        };
+        settings.save(&path).unwrap();
+        assert_eq!(TracerSettings::load(&path).unwrap(), Some(settings));
+        let settings = TracerSettings {
+            target_tgid: TARGET_TGID_SET,
+            http_roles: u64::MAX,
+        };
+        settings.save(&path).unwrap();
+        assert_eq!(TracerSettings::load(&path).unwrap(), Some(settings));
+
+        fs::write(&path, "1234\n").unwrap();
+        assert!(TracerSettings::load(&path).is_err());
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,392 @@
+use std::fs;
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::sync::Arc;
+
+use ahash::AHashMap;
+use log::debug;
+use parking_lot::RwLock;
+
+use crate::managers::cache::Workload;
+use crate::managers::container::ContainerResolver;
+use crate::managers::symbol::SymbolTable;
+
+/// Namespace of the workloads standing for remote peers.
+pub(crate) const PEER_NAMESPACE: &str = "external";
+/// Length container ids are shortened to, as printed by container runtimes.
+const CONTAINER_ID_LEN: usize = 12;
+/// Cgroup prefixes of container scopes under systemd, and the runtime they stand for.
+const CONTAINER_SCOPES: &[(&str, &str)] = &[
+    ("docker-", "docker"),
+    ("cri-containerd-", "containerd"),
+    ("crio-", "crio"),
+    ("libpod-", "podman"),
+];
+/// Parent cgroups of containers when the runtime manages cgroups itself.
+const CONTAINER_PARENTS: &[(&str, &str)] = &[
+    ("docker", "docker"),
+    ("containerd", "containerd"),
+    ("libpod_parent", "podman"),
+];
+/// Executables running the program named by their first argument, which makes for a
+/// better workload name than the interpreter.
+const INTERPRETERS: &[&str] = &[
+    "python", "node", "java", "ruby", "perl", "php", "bash", "sh",
+];
+
+/// What a local process is attributed to, from the most to the least specific.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ProcessWorkload {
+    /// A container, named after its runtime and short id, e.g. `docker-3f2a1b9c0d11`,
+    /// unless the container runtime names it.
+    Container { runtime: &'static str, id: String },
+    /// A systemd service, e.g. `nginx.service`.
+    SystemdUnit(String),
+    /// Any other process, named after its executable, or after the script or archive
+    /// an interpreter runs, e.g. `app.py` for `python3 app.py`.
+    Executable(String),
+}
+
+impl ProcessWorkload {
+    fn name(&self) -> String {
+        match self {
+            ProcessWorkload::Container { runtime, id } => {
+                format!("{}-{}", runtime, &id[..CONTAINER_ID_LEN])
+            }
+            ProcessWorkload::SystemdUnit(name) | ProcessWorkload::Executable(name) => name.clone(),
+        }
+    }
+
+    fn kind(&self) -> &'static str {
+        match self {
+            ProcessWorkload::Container { .. } => "Container",
+            ProcessWorkload::SystemdUnit(_) => "SystemdUnit",
+            ProcessWorkload::Executable(_) => "Executable",
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Process {
+    /// The command line, compared to tell forks, which share their parent's, from
+    /// children that executed another program.
+    cmdline: Vec<u8>,
+    workload: Arc<Workload>,
+}
+
+/// Attributes connections to local processes when the agent runs outside Kubernetes,
+/// so that service map edges still join meaningful workloads. A process belongs to the
+/// container or systemd unit its cgroup is part of, and otherwise to its executable,
+/// within a namespace named after the host. Containers known to the container runtime
+/// take the name it gives them. Forked processes, such as the workers of a
+/// server, share the workload of their parent without being described again. Remote
+/// peers have no process the agent can see, so they are named after their address.
+#[derive(Debug, Clone)]
+pub(crate) struct ProcessResolver {
+    hostname: String,
+    processes: Arc<RwLock<AHashMap<u32, Process>>>,
+    containers: Option<ContainerResolver>,
+    symbols: SymbolTable,
+}
+
+impl ProcessResolver {
+    pub(crate) fn new(symbols: SymbolTable, containers: Option<ContainerResolver>) -> Self {
+        Self {
+            hostname: hostname(),
+            processes: Arc::new(RwLock::new(AHashMap::new())),
+            containers,
+            symbols,
+        }
+    }
+
+    pub(crate) fn resolve_pid(&self, pid: u32) -> Option<Arc<Workload>> {
+        if let Some(process) = self.processes.read().get(&pid) {
+            return Some(process.workload.clone());
+        }
+
+        let cmdline = match fs::read(format!("/proc/{}/cmdline", pid)) {
+            Ok(cmdline) => cmdline,
+            Err(e) => {
+                debug!("Failed to describe process {}: {:?}", pid, e);
+                return None;
+            }
+        };
+        let inherited = parent_pid(pid).and_then(|ppid| {
+            let processes = self.processes.read();
+            let parent = processes.get(&ppid)?;
+            (parent.cmdline == cmdline).then(|| parent.workload.clone())
+        });
+        let workload = match inherited {
+            Some(workload) => workload,
+            None => {
+                let described = describe_process(pid, &cmdline)?;
+                let named = match (&described, &self.containers) {
+                    (ProcessWorkload::Container { id, .. }, Some(containers)) => {
+                        containers.resolve_id(id)
+                    }
+                    _ => None,
+                };
+                named.unwrap_or_else(|| {
+                    Arc::new(Workload {
+                        name: self.symbols.intern(&described.name()),
+                        namespace: self.symbols.intern(&self.hostname),
+                        kind: self.symbols.intern(described.kind()),
+                        labels: Vec::new(),
+                    })
+                })
+            }
+        };
+        self.processes.write().insert(
+            pid,
+            Process {
+                cmdline,
+                workload: workload.clone(),
+            },
+        );
+        Some(workload)
+    }
+
+    pub(crate) fn resolve_peer(&self, ip: Ipv4Addr) -> Arc<Workload> {
+        Arc::new(Workload {
+            name: self.symbols.intern(&ip.to_string()),
+            namespace: self.symbols.intern(PEER_NAMESPACE),
+            kind: self.symbols.intern("Host"),
+            labels: Vec::new(),
+        })
+    }
+
+    /// Forgets the processes that exited, so that reused pids are described again.
+    pub(crate) fn purge(&self) {
+        self.processes
+            .write()
+            .retain(|pid, _| Path::new(&format!("/proc/{}", pid)).exists());
+    }
+}
+
+/// Name of the host, which standalone workloads are namespaced by.
+pub(crate) fn hostname() -> String {
+    fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|name| name.trim().to_string())
+        .ok()
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "localhost".to_string())
+}
+
+fn parent_pid(pid: u32) -> Option<u32> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // The command name is parenthesized and may contain spaces, so fields are
+    // counted from its end: state, then the parent pid.
+    let (_, fields) = stat.rsplit_once(')')?;
+    fields.split_whitespace().nth(1)?.parse().ok()
+}
+
+fn describe_process(pid: u32, cmdline: &[u8]) -> Option<ProcessWorkload> {
+    if let Ok(cgroups) = fs::read_to_string(format!("/proc/{}/cgroup", pid)) {
+        // Both `0::/system.slice/nginx.service` (v2) and `1:name=systemd:/...` (v1).
+        for path in cgroups
+            .lines()
+            .filter_map(|line| line.splitn(3, ':').nth(2))
+        {
+            if let Some(described) = describe_cgroup(path) {
+                return Some(described);
+            }
+        }
+    }
+
+    let args: Vec<String> = cmdline
+        .split(|b| *b == 0)
+        .filter(|arg| !arg.is_empty())
+        .map(|arg| String::from_utf8_lossy(arg).into_owned())
+        .collect();
+    let executable = fs::read_link(format!("/proc/{}/exe", pid))
+        .ok()
+        .and_then(|exe| Some(exe.file_name()?.to_string_lossy().into_owned()))
+        .or_else(|| args.first().map(|arg| basename(arg).to_string()))
+        .or_else(|| {
+            fs::read_to_string(format!("/proc/{}/comm", pid))
+                .ok()
+                .map(|comm| comm.trim().to_string())
+        })?;
+    Some(ProcessWorkload::Executable(
+        interpreted_program(&executable, &args).unwrap_or(executable),
+    ))
+}
+
+/// Looks for the innermost container or systemd service in a cgroup path.
+fn describe_cgroup(path: &str) -> Option<ProcessWorkload> {
+    let components: Vec<&str> = path.split('/').filter(|c| !c.is_empty()).collect();
+    for (i, component) in components.iter().enumerate().rev() {
+        if let Some(scope) = component.strip_suffix(".scope") {
+            let container = CONTAINER_SCOPES
+                .iter()
+                .find_map(|(prefix, runtime)| Some((scope.strip_prefix(prefix)?, *runtime)));
+            if let Some((id, runtime)) = container.filter(|(id, _)| is_container_id(id)) {
+                return Some(ProcessWorkload::Container {
+                    runtime,
+                    id: id.to_string(),
+                });
+            }
+        } else if component.ends_with(".service") {
+            return Some(ProcessWorkload::SystemdUnit(component.to_string()));
+        } else if is_container_id(component) && i > 0 {
+            let parent = components[i - 1];
+            if let Some((_, runtime)) = CONTAINER_PARENTS.iter().find(|(p, _)| *p == parent) {
+                return Some(ProcessWorkload::Container {
+                    runtime,
+                    id: component.to_string(),
+                });
+            }
+        }
+    }
+    None
+}
+
+fn is_container_id(s: &str) -> bool {
+    s.len() >= CONTAINER_ID_LEN && s.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Returns the script, module or archive run by an interpreter, e.g. `app.py` for
+/// `python3 app.py`, `http.server` for `python3 -m http.server` or `app.jar` for
+/// `java -jar app.jar`.
+fn interpreted_program(executable: &str, args: &[String]) -> Option<String> {
+    let interpreter = executable.trim_end_matches(|c: char| c.is_ascii_digit() || c == '.');
+    if !INTERPRETERS.contains(&interpreter) {
+        return None;
+    }
+    let mut args = args.iter().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-m" | "-jar" => return args.next().map(|arg| basename(arg).to_string()),
+            "-c" | "-e" => return None,
+            arg if arg.starts_with('-') => continue,
+            arg => return Some(basename(arg).to_string()),
+        }
+    }
+    None
+}
+
+fn basename(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_own_process() {
+        let resolver = ProcessResolver::new(SymbolTable::default(), None);
+        let workload = resolver.resolve_pid(std::process::id()).unwrap();
+        assert_eq!(workload.namespace.as_str(), hostname());
+        // Described once, then remembered until it exits.
+        let again = resolver.resolve_pid(std::process::id()).unwrap();
+        assert!(Arc::ptr_eq(&workload, &again));
+        resolver.purge();
+        assert_eq!(resolver.processes.read().len(), 1);
+        assert!(resolver.resolve_pid(u32::MAX).is_none());
+    }
+
+    #[test]
+    fn test_resolve_peer() {
+        let resolver = ProcessResolver::new(SymbolTable::default(), None);
+        let peer = resolver.resolve_peer(Ipv4Addr::new(10, 0, 1, 17));
+        assert_eq!(peer.name.as_str(), "10.0.1.17");
+        assert_eq!(peer.namespace.as_str(), PEER_NAMESPACE);
+        assert_eq!(peer.kind.as_str(), "Host");
+    }
+
+    #[test]
+    fn test_parent_pid() {
+        assert_eq!(
+            parent_pid(std::process::id()),
+            Some(std::os::unix::process::parent_id())
+        );
+        assert_eq!(parent_pid(u32::MAX), None);
+    }
+
+    const ID: &str = "3f2a1b9c0d11e2f3a4b5c6d7e8f9a0b1c2d3e4f5a6b7c8d9e0f1a2b3c4d5e6f7";
+
+    fn container(runtime: &'static str) -> Option<ProcessWorkload> {
+        Some(ProcessWorkload::Container {
+            runtime,
+            id: ID.to_string(),
+        })
+    }
+
+    #[test]
+    fn test_describe_cgroup() {
+        assert_eq!(
+            describe_cgroup(&format!("/system.slice/docker-{}.scope", ID)),
+            container("docker")
+        );
+        assert_eq!(
+            describe_cgroup(&format!("/machine.slice/libpod-{}.scope/container", ID)),
+            container("podman")
+        );
+        assert_eq!(
+            describe_cgroup(&format!("/docker/{}", ID)),
+            container("docker")
+        );
+        assert_eq!(
+            describe_cgroup("/system.slice/nginx.service"),
+            Some(ProcessWorkload::SystemdUnit("nginx.service".to_string()))
+        );
+        // The innermost one wins.
+        assert_eq!(
+            describe_cgroup(&format!(
+                "/system.slice/containerd.service/containerd/{}",
+                ID
+            )),
+            container("containerd")
+        );
+        assert_eq!(
+            describe_cgroup("/user.slice/user-1000.slice/session-2.scope"),
+            None
+        );
+        assert_eq!(describe_cgroup("/docker/abc"), None);
+        assert_eq!(describe_cgroup("/"), None);
+    }
+
+    #[test]
+    fn test_interpreted_program() {
+        let args = |args: &[&str]| -> Vec<String> { args.iter().map(|a| a.to_string()).collect() };
+        assert_eq!(
+            interpreted_program("python3.11", &args(&["python3", "-u", "/srv/app.py"])),
+            Some("app.py".to_string())
+        );
+        assert_eq!(
+            interpreted_program("python3", &args(&["python3", "-m", "http.server"])),
+            Some("http.server".to_string())
+        );
+        assert_eq!(
+            interpreted_program("java", &args(&["java", "-Xmx1g", "-jar", "/opt/app.jar"])),
+            Some("app.jar".to_string())
+        );
+        assert_eq!(
+            interpreted_program("bash", &args(&["bash", "-c", "sleep 1"])),
+            None
+        );
+        assert_eq!(interpreted_program("node", &args(&["node"])), None);
+        assert_eq!(
+            interpreted_program("nginx", &args(&["nginx", "-g", "daemon off;"])),
+            None
+        );
+    }
+
+    #[test]
+    fn test_process_workload_names() {
+        let docker = container("docker").unwrap();
+        assert_eq!(docker.name(), "docker-3f2a1b9c0d11");
+        assert_eq!(docker.kind(), "Container");
+        let unit = ProcessWorkload::SystemdUnit("nginx.service".to_string());
+        assert_eq!(
+            (unit.name().as_str(), unit.kind()),
+            ("nginx.service", "SystemdUnit")
+        );
+        let executable = ProcessWorkload::Executable("app.py".to_string());
+        assert_eq!(
+            (executable.name().as_str(), executable.kind()),
+            ("app.py", "Executable")
+        );
+    }
+}
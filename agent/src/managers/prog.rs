@@ -1,5 +1,6 @@
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Duration;
 
 use log::{debug, error, info};
 use parking_lot::Mutex;
@@ -9,17 +10,27 @@ use tokio::task::JoinHandle;
 use agent_api::ProgramState;
 use agent_api::ProgramType;
 
+use crate::common::constants::{DEFAULT_HEALTH_CHECK_INTERVAL, DEFAULT_WINDOW_CHECK_INTERVAL};
+use crate::common::errors::AgentError;
 use crate::common::types::ListFilter;
+use crate::managers::audit::AuditLog;
 use crate::managers::cache::CacheManager;
+use crate::managers::events::EventsManager;
+use crate::managers::health::HealthChecker;
 use crate::managers::image::ImageManager;
 use crate::managers::registry::RegistryManager;
+use crate::managers::scheduler::PollScheduler;
+use crate::managers::windows::WindowScheduler;
 use crate::progs::types::{Program, ShutdownSignal};
 
 #[derive(Debug, Clone)]
 pub(crate) struct ProgManager {
+    pub audit_log: AuditLog,
     pub cache_manager: CacheManager,
+    pub events_manager: EventsManager,
     pub image_manager: ImageManager,
     pub registry_manager: RegistryManager,
+    pub scheduler: PollScheduler,
     pub program_handles: Arc<Mutex<HashMap<String, JoinHandle<()>>>>,
     pub shutdown_tx: broadcast::Sender<ShutdownSignal>,
 }
@@ -27,16 +38,40 @@ pub(crate) struct ProgManager {
 impl ProgManager {
     pub(crate) async fn new(
         shutdown_tx: broadcast::Sender<ShutdownSignal>,
+        poll_workers: usize,
+        events_manager: EventsManager,
+        cache_manager: CacheManager,
+        audit_log: AuditLog,
     ) -> anyhow::Result<ProgManager> {
-        let cache_manager = CacheManager::new().await?;
-        cache_manager.wait_for_cache_sync().await?;
-        Ok(Self {
+        let scheduler = PollScheduler::new(poll_workers);
+        let s = scheduler.clone();
+        let shutdown_rx = shutdown_tx.subscribe();
+        tokio::spawn(async move {
+            s.run(shutdown_rx).await;
+        });
+
+        let registry_manager = RegistryManager::new();
+        let health_checker = HealthChecker::new(registry_manager.clone());
+        tokio::spawn(health_checker.run(
+            Duration::from_secs(DEFAULT_HEALTH_CHECK_INTERVAL),
+            shutdown_tx.subscribe(),
+        ));
+
+        let prog_manager = Self {
+            audit_log,
             cache_manager,
+            events_manager,
             image_manager: ImageManager::new(),
-            registry_manager: RegistryManager::new(),
+            registry_manager,
+            scheduler,
             program_handles: Arc::new(Mutex::new(HashMap::new())),
             shutdown_tx,
-        })
+        };
+        tokio::spawn(WindowScheduler::new(prog_manager.clone()).run(
+            Duration::from_secs(DEFAULT_WINDOW_CHECK_INTERVAL),
+            prog_manager.shutdown_tx.subscribe(),
+        ));
+        Ok(prog_manager)
     }
 
     pub(crate) async fn pre_load(
@@ -45,28 +80,36 @@ impl ProgManager {
         program_type: ProgramType,
         metadata: HashMap<String, String>,
         cache_manager: CacheManager,
+        events_manager: EventsManager,
         map_to_prog_id: HashMap<String, u32>,
-    ) -> Result<Arc<dyn Program>, anyhow::Error> {
+    ) -> Result<Arc<dyn Program>, AgentError> {
         let prog = match self.get(program_name.clone(), Some(program_type)).await {
             Some(p) => p,
             None => {
-                let err_msg = format!("Program {} not found.", program_name);
-                error!("{}", &err_msg);
-                return Err(anyhow::Error::msg(err_msg));
+                error!("Program {} not found.", program_name);
+                return Err(AgentError::ProgramNotFound(program_name));
             }
         };
         match prog.get_state() {
-            ProgramState::Uninitialized => match prog.init(metadata, cache_manager, map_to_prog_id)
-            {
-                Ok(()) => {
-                    prog.set_state(ProgramState::Initialized);
-                    info!("Program {} initialized successfully.", prog.get_name());
-                }
-                Err(e) => {
-                    error!("Failed to initialize program {}: {:?}", prog.get_name(), e);
-                    return Err(e);
+            ProgramState::Uninitialized => {
+                let metadata = match prog.metadata_schema().validate(&metadata) {
+                    Ok(metadata) => metadata,
+                    Err(e) => {
+                        error!("Rejected metadata of program {}: {}", prog.get_name(), e);
+                        return Err(e.into());
+                    }
+                };
+                match prog.init(metadata, cache_manager, events_manager, map_to_prog_id) {
+                    Ok(()) => {
+                        prog.set_state(ProgramState::Initialized);
+                        info!("Program {} initialized successfully.", prog.get_name());
+                    }
+                    Err(e) => {
+                        error!("Failed to initialize program {}: {:?}", prog.get_name(), e);
+                        return Err(e.into());
+                    }
                 }
-            },
+            }
             _ => {
                 debug!("Program {} is already initialized.", prog.get_name());
             }
@@ -88,7 +131,7 @@ impl ProgManager {
         self.registry_manager.list_programs(list_filter)
     }
 
-    pub(crate) async fn load(&self, prog: Arc<dyn Program>) -> Result<(), anyhow::Error> {
+    pub(crate) async fn load(&self, prog: Arc<dyn Program>) -> Result<(), AgentError> {
         match prog.get_state() {
             ProgramState::Initialized => {
                 let shutdown_rx = self.shutdown_tx.subscribe();
@@ -113,30 +156,77 @@ impl ProgManager {
 
                 let mut handlers = self.program_handles.lock();
                 handlers.insert(prog.get_name(), handle);
+                self.scheduler.register(prog.clone());
             }
-            _ => {
-                let err_msg = format!(
+            state => {
+                debug!(
                     "Program {} is in an invalid state to be loaded: {:?}",
                     prog.get_name(),
-                    prog.get_state()
+                    state
                 );
-                debug!("{}", &err_msg);
-                return Err(anyhow::Error::msg(err_msg));
+                return Err(AgentError::InvalidState {
+                    program: prog.get_name(),
+                    state,
+                    expected: "initialized",
+                });
             }
         }
 
         Ok(())
     }
 
-    pub(crate) async fn unload(&self, program_name: String) -> Result<(), anyhow::Error> {
+    /// Stops polling a running program, so that it no longer costs anything nor is
+    /// exported, without stopping it: its maps stay attached and its state is kept.
+    pub(crate) fn pause(&self, program_name: &str) -> Result<(), AgentError> {
+        let program = self
+            .registry_manager
+            .get_program(program_name, None)
+            .ok_or(AgentError::ProgramNotFound(program_name.to_string()))?;
+        match program.get_state() {
+            ProgramState::Running | ProgramState::Degraded => {}
+            state => {
+                return Err(AgentError::InvalidState {
+                    program: program_name.to_string(),
+                    state,
+                    expected: "running",
+                })
+            }
+        }
+
+        self.scheduler.unregister(program_name);
+        program.set_state(ProgramState::Paused);
+        info!("Program {} paused.", program_name);
+        Ok(())
+    }
+
+    /// Resumes polling a paused program where it left off.
+    pub(crate) fn resume(&self, program_name: &str) -> Result<(), AgentError> {
+        let program = self
+            .registry_manager
+            .get_program(program_name, None)
+            .ok_or(AgentError::ProgramNotFound(program_name.to_string()))?;
+        if program.get_state() != ProgramState::Paused {
+            return Err(AgentError::InvalidState {
+                program: program_name.to_string(),
+                state: program.get_state(),
+                expected: "paused",
+            });
+        }
+
+        // The health checker marks it degraded again if its eBPF programs went away.
+        program.set_state(ProgramState::Running);
+        self.scheduler.register(program);
+        info!("Program {} resumed.", program_name);
+        Ok(())
+    }
+
+    pub(crate) async fn unload(&self, program_name: String) -> Result<(), AgentError> {
         let program = self
             .registry_manager
             .get_program(program_name.as_str(), None)
-            .ok_or(anyhow::Error::msg(format!(
-                "Failed to get program {} for unloading.",
-                program_name
-            )))?;
+            .ok_or(AgentError::ProgramNotFound(program_name.clone()))?;
 
+        self.scheduler.unregister(&program_name);
         program.stop().await?;
 
         self.shutdown_tx
@@ -146,7 +236,7 @@ impl ProgManager {
                     "Failed to send shutdown signal for program {}: {:?}",
                     program_name, e
                 );
-                anyhow::Error::new(e)
+                AgentError::Internal(e.into())
             })?;
 
         let handle = {
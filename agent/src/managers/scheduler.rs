@@ -0,0 +1,298 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use ahash::AHashMap;
+use log::{debug, error};
+use parking_lot::Mutex;
+use prometheus_client::encoding::{DescriptorEncoder, EncodeLabelSet, EncodeMetric};
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::histogram::{exponential_buckets, Histogram};
+use prometheus_client::registry::Unit;
+use tokio::sync::{broadcast, Notify, Semaphore};
+use tokio::time::{self, Instant};
+
+use agent_api::ProgramState;
+
+use crate::common::constants::DEFAULT_INTERVAL;
+use crate::common::utils::fnv_hash;
+use crate::progs::types::{Program, ShutdownSignal};
+
+/// Programs falling due within this window of each other are polled in one wakeup.
+const BATCH_WINDOW: Duration = Duration::from_millis(200);
+/// How long the scheduler sleeps when nothing is registered.
+const IDLE_WAIT: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct PollLabels {
+    program: String,
+}
+
+#[derive(Debug)]
+struct Scheduled {
+    program: Arc<dyn Program>,
+    /// Tells this registration from later ones of a program with the same name.
+    generation: u64,
+    interval: Duration,
+    next_due: Instant,
+    in_flight: Arc<AtomicBool>,
+}
+
+/// Drives the periodic `poll` of every running program from a single loop. Each
+/// program keeps its own interval, but its first poll is offset by a phase derived
+/// from its name so that programs sharing an interval don't all wake at once. Due
+/// programs are batched and polled on a bounded pool of blocking workers.
+#[derive(Debug, Clone)]
+pub(crate) struct PollScheduler {
+    scheduled: Arc<Mutex<AHashMap<String, Scheduled>>>,
+    wakeup: Arc<Notify>,
+    workers: Arc<Semaphore>,
+    next_generation: Arc<AtomicU64>,
+    poll_durations: Family<PollLabels, Histogram, fn() -> Histogram>,
+}
+
+impl PollScheduler {
+    pub(crate) fn new(workers: usize) -> Self {
+        Self {
+            scheduled: Arc::new(Mutex::new(AHashMap::new())),
+            wakeup: Arc::new(Notify::new()),
+            workers: Arc::new(Semaphore::new(workers.max(1))),
+            next_generation: Arc::new(AtomicU64::new(0)),
+            poll_durations: Family::new_with_constructor(new_poll_histogram),
+        }
+    }
+
+    pub(crate) fn register(&self, program: Arc<dyn Program>) {
+        let name = program.get_name();
+        let interval = Duration::from_secs(
+            program
+                .get_metadata()
+                .get("interval")
+                .and_then(|i| i.parse::<u64>().ok())
+                .unwrap_or(DEFAULT_INTERVAL)
+                .max(1),
+        );
+        let phase = Duration::from_millis(u64::from(fnv_hash(&name)) % interval.as_millis() as u64);
+
+        debug!(
+            "Scheduling program {} every {:?} with phase {:?}",
+            name, interval, phase
+        );
+        self.scheduled.lock().insert(
+            name,
+            Scheduled {
+                program,
+                generation: self.next_generation.fetch_add(1, Ordering::Relaxed),
+                interval,
+                next_due: Instant::now() + phase,
+                in_flight: Arc::new(AtomicBool::new(false)),
+            },
+        );
+        self.wakeup.notify_one();
+    }
+
+    pub(crate) fn unregister(&self, name: &str) {
+        let mut scheduled = self.scheduled.lock();
+        if scheduled.remove(name).is_some() {
+            self.remove_poll_durations(name);
+        }
+    }
+
+    /// Unregisters the program `name` unless it was registered again since the
+    /// registration `generation`, e.g. reloaded while its last poll was running.
+    fn unregister_generation(&self, name: &str, generation: u64) {
+        let mut scheduled = self.scheduled.lock();
+        if scheduled
+            .get(name)
+            .is_some_and(|s| s.generation == generation)
+        {
+            scheduled.remove(name);
+            self.remove_poll_durations(name);
+        }
+    }
+
+    /// Records how long a poll of the registration `generation` of `name` took,
+    /// unless it was unregistered since, so that its metrics aren't created again.
+    fn observe_poll(&self, name: &str, generation: u64, duration: Duration) {
+        // Held so that the program can't be unregistered in between.
+        let scheduled = self.scheduled.lock();
+        if scheduled
+            .get(name)
+            .is_some_and(|s| s.generation == generation)
+        {
+            self.poll_durations
+                .get_or_create(&PollLabels {
+                    program: name.to_string(),
+                })
+                .observe(duration.as_secs_f64());
+        }
+    }
+
+    fn remove_poll_durations(&self, name: &str) {
+        self.poll_durations.remove(&PollLabels {
+            program: name.to_string(),
+        });
+    }
+
+    pub(crate) async fn run(&self, mut shutdown_rx: broadcast::Receiver<ShutdownSignal>) {
+        loop {
+            let next_due = self
+                .scheduled
+                .lock()
+                .values()
+                .map(|s| s.next_due)
+                .min()
+                .unwrap_or_else(|| Instant::now() + IDLE_WAIT);
+
+            tokio::select! {
+                _ = time::sleep_until(next_due) => {}
+                _ = self.wakeup.notified() => continue,
+                Ok(signal) = shutdown_rx.recv() => {
+                    if let ShutdownSignal::All = signal {
+                        debug!("Received shutdown signal, stopping poll scheduler");
+                        break;
+                    }
+                    continue;
+                }
+            }
+
+            for (program, generation, in_flight) in self.take_due(Instant::now()) {
+                self.dispatch(program, generation, in_flight);
+            }
+        }
+    }
+
+    pub(crate) fn collect(&self, encoder: &mut DescriptorEncoder) -> Result<(), std::fmt::Error> {
+        let metric_encoder = encoder.encode_descriptor(
+            "program_poll_duration",
+            "time spent in each poll of a program",
+            Some(&Unit::Seconds),
+            self.poll_durations.metric_type(),
+        )?;
+        self.poll_durations.encode(metric_encoder)
+    }
+
+    /// Returns the programs due before the end of the batch window and advances their
+    /// next deadline. A program whose previous poll is still running is skipped.
+    fn take_due(&self, now: Instant) -> Vec<(Arc<dyn Program>, u64, Arc<AtomicBool>)> {
+        let horizon = now + BATCH_WINDOW;
+        let mut due = Vec::new();
+        for scheduled in self.scheduled.lock().values_mut() {
+            if scheduled.next_due > horizon {
+                continue;
+            }
+            // Keep the phase when catching up after a stall instead of bursting.
+            while scheduled.next_due <= horizon {
+                scheduled.next_due += scheduled.interval;
+            }
+            if scheduled.in_flight.swap(true, Ordering::AcqRel) {
+                debug!(
+                    "Skipping poll of {}, previous poll still running",
+                    scheduled.program.get_name()
+                );
+                continue;
+            }
+            due.push((
+                scheduled.program.clone(),
+                scheduled.generation,
+                scheduled.in_flight.clone(),
+            ));
+        }
+        due
+    }
+
+    fn dispatch(&self, program: Arc<dyn Program>, generation: u64, in_flight: Arc<AtomicBool>) {
+        let workers = self.workers.clone();
+        let scheduler = self.clone();
+        tokio::spawn(async move {
+            let Ok(_permit) = workers.acquire_owned().await else {
+                in_flight.store(false, Ordering::Release);
+                return;
+            };
+
+            let name = program.get_name();
+            let p = program.clone();
+            let start = Instant::now();
+            let res = tokio::task::spawn_blocking(move || p.poll()).await;
+            in_flight.store(false, Ordering::Release);
+
+            let err = match res {
+                Ok(Ok(())) => {
+                    scheduler.observe_poll(&name, generation, start.elapsed());
+                    return;
+                }
+                Ok(Err(e)) => e,
+                Err(e) => anyhow::Error::new(e),
+            };
+            error!("Program {} failed to poll: {:?}", name, err);
+            program.set_state(ProgramState::Failed);
+            scheduler.unregister_generation(&name, generation);
+        });
+    }
+}
+
+fn new_poll_histogram() -> Histogram {
+    // 1ms up to roughly 16s
+    Histogram::new(exponential_buckets(0.001, 2.0, 15))
+}
+
+#[cfg(test)]
+mod tests {
+    use prometheus_client::encoding::text::encode;
+    use prometheus_client::registry::Registry;
+
+    use crate::progs::service_map::program::ServiceMap;
+
+    use super::*;
+
+    fn generation(scheduler: &PollScheduler, name: &str) -> Option<u64> {
+        scheduler.scheduled.lock().get(name).map(|s| s.generation)
+    }
+
+    fn encoded(scheduler: &PollScheduler) -> String {
+        let mut registry = <Registry>::default();
+        registry.register(
+            "program_poll_duration",
+            "time spent in each poll of a program",
+            scheduler.poll_durations.clone(),
+        );
+        let mut buffer = String::new();
+        encode(&mut buffer, &registry).unwrap();
+        buffer
+    }
+
+    #[test]
+    fn test_failed_poll_keeps_newer_registration() {
+        let scheduler = PollScheduler::new(1);
+        scheduler.register(Arc::new(ServiceMap::new()));
+        let old = generation(&scheduler, "service_map").unwrap();
+        // Reloaded while its last poll was running.
+        scheduler.register(Arc::new(ServiceMap::new()));
+        let new = generation(&scheduler, "service_map").unwrap();
+        assert_ne!(old, new);
+
+        scheduler.unregister_generation("service_map", old);
+        assert_eq!(generation(&scheduler, "service_map"), Some(new));
+        scheduler.unregister_generation("service_map", new);
+        assert_eq!(generation(&scheduler, "service_map"), None);
+    }
+
+    #[test]
+    fn test_poll_durations_not_recorded_after_unregister() {
+        let scheduler = PollScheduler::new(1);
+        scheduler.register(Arc::new(ServiceMap::new()));
+        let old = generation(&scheduler, "service_map").unwrap();
+        scheduler.observe_poll("service_map", old, Duration::from_millis(5));
+        assert!(encoded(&scheduler).contains("program=\"service_map\""));
+
+        scheduler.unregister("service_map");
+        assert!(!encoded(&scheduler).contains("program=\"service_map\""));
+        // A poll still running when the program was unloaded.
+        scheduler.observe_poll("service_map", old, Duration::from_millis(5));
+        assert!(!encoded(&scheduler).contains("program=\"service_map\""));
+
+        scheduler.register(Arc::new(ServiceMap::new()));
+        scheduler.observe_poll("service_map", old, Duration::from_millis(5));
+        assert!(!encoded(&scheduler).contains("program=\"service_map\""));
+    }
+}
@@ -0,0 +1,332 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::Context;
+use log::warn;
+use parking_lot::Mutex;
+use serde_json::json;
+
+use agent_api::v1::RequestSample;
+
+/// Protocols of the reported requests that are database operations.
+const DATABASE_PROTOCOLS: &[&str] = &["postgres", "mysql", "redis"];
+
+/// Flags the reported database operations slower than a threshold, so that they are
+/// always kept next to the sampled requests. They are also appended to a file, one JSON
+/// object per line, when one is configured.
+#[derive(Debug)]
+pub(crate) struct SlowQueryLog {
+    threshold_ns: u64,
+    /// Whether the keys of Redis commands are kept rather than redacted.
+    keep_redis_keys: bool,
+    file: Option<Mutex<File>>,
+}
+
+impl SlowQueryLog {
+    pub(crate) fn new(
+        threshold: Duration,
+        keep_redis_keys: bool,
+        path: Option<&Path>,
+    ) -> anyhow::Result<Self> {
+        let file = match path {
+            Some(path) => Some(Mutex::new(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .mode(0o600)
+                    .open(path)
+                    .with_context(|| format!("Failed to open slow query log {}", path.display()))?,
+            )),
+            None => None,
+        };
+        Ok(Self {
+            threshold_ns: threshold.as_nanos() as u64,
+            keep_redis_keys,
+            file,
+        })
+    }
+
+    /// Redacts the statement of a database operation, and flags it when it is slow.
+    /// Returns whether it is.
+    pub(crate) fn check(&self, request: &mut RequestSample) -> bool {
+        if !DATABASE_PROTOCOLS.contains(&request.protocol.as_str()) {
+            return false;
+        }
+        request.statement = match request.protocol.as_str() {
+            "redis" => redact_redis(&request.statement, self.keep_redis_keys),
+            protocol => redact_sql(&request.statement, protocol == "mysql"),
+        };
+        request.slow_query = request.latency_ns > self.threshold_ns;
+        if request.slow_query {
+            self.append(request);
+        }
+        request.slow_query
+    }
+
+    fn append(&self, request: &RequestSample) {
+        let Some(file) = self.file.as_ref() else {
+            return;
+        };
+        let line = json!({
+            "timestamp_ns": request.timestamp_ns,
+            "src_workload": request.src_workload,
+            "dst_workload": request.dst_workload,
+            "protocol": request.protocol,
+            "statement": request.statement,
+            "latency_ns": request.latency_ns,
+            "trace_id": request.trace_id,
+        });
+        if let Err(e) = writeln!(file.lock(), "{}", line) {
+            warn!("Failed to append to the slow query log: {}", e);
+        }
+    }
+}
+
+/// Replaces the values a SQL statement carries with `?`, since they may be anything
+/// from emails to credentials: its string and numeric literals, and its comments. In
+/// MySQL, double quotes delimit strings and backslashes escape quotes in them;
+/// elsewhere, double quotes delimit identifiers and backslashes only escape quotes in
+/// `E'...'` strings.
+fn redact_sql(statement: &str, mysql: bool) -> String {
+    let chars: Vec<char> = statement.chars().collect();
+    let mut redacted = String::with_capacity(statement.len());
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        let after_word = i > 0 && (chars[i - 1].is_alphanumeric() || chars[i - 1] == '_');
+        if c == '\'' || (c == '"' && mysql) {
+            let escape_string = !mysql
+                && i > 0
+                && chars[i - 1].eq_ignore_ascii_case(&'e')
+                && !(i > 1 && (chars[i - 2].is_alphanumeric() || chars[i - 2] == '_'));
+            i = quoted_end(&chars, i, c, mysql || escape_string);
+            redacted.push('?');
+        } else if c == '"' || (c == '`' && mysql) {
+            // Identifiers are kept, quotes inside them don't start strings.
+            let end = quoted_end(&chars, i, c, false);
+            redacted.extend(&chars[i..end]);
+            i = end;
+        } else if c == '-' && next == Some('-') || c == '#' && mysql {
+            while i < chars.len() && chars[i] != '\n' {
+                i += 1;
+            }
+            redacted.push_str(if c == '#' { "# ?" } else { "-- ?" });
+        } else if c == '/' && next == Some('*') {
+            i = chars[i + 2..]
+                .windows(2)
+                .position(|w| w == ['*', '/'])
+                .map_or(chars.len(), |end| i + 2 + end + 2);
+            redacted.push_str("/* ? */");
+        } else if c == '$' && !after_word {
+            match dollar_quoted_end(&chars, i) {
+                Some(end) => {
+                    i = end;
+                    redacted.push('?');
+                }
+                None => {
+                    redacted.push(c);
+                    i += 1;
+                }
+            }
+        } else if c.is_ascii_digit() && !after_word && !(i > 0 && chars[i - 1] == '$') {
+            let hex = c == '0' && matches!(next, Some('x' | 'X'));
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '.') {
+                // The sign of an exponent, as in 1.5e-3.
+                let exponent = !hex && chars[i].eq_ignore_ascii_case(&'e');
+                i += 1;
+                if exponent && matches!(chars.get(i), Some('+' | '-')) {
+                    i += 1;
+                }
+            }
+            redacted.push('?');
+        } else {
+            redacted.push(c);
+            i += 1;
+        }
+    }
+    redacted
+}
+
+/// Returns where the string starting with the quote at `start` ends, quotes being
+/// escaped by doubling them, or with a backslash when `backslash_escapes`.
+fn quoted_end(chars: &[char], start: usize, quote: char, backslash_escapes: bool) -> usize {
+    let mut i = start + 1;
+    while i < chars.len() {
+        match chars[i] {
+            '\\' if backslash_escapes => i += 2,
+            c if c == quote && chars.get(i + 1) == Some(&quote) => i += 2,
+            c if c == quote => return i + 1,
+            _ => i += 1,
+        }
+    }
+    chars.len()
+}
+
+/// Returns where the Postgres dollar-quoted string starting at `start`, e.g.
+/// `$tag$...$tag$`, ends, if it is one rather than a parameter like `$1`.
+fn dollar_quoted_end(chars: &[char], start: usize) -> Option<usize> {
+    let tag_len = chars[start + 1..]
+        .iter()
+        .position(|&c| c == '$')
+        .filter(|&len| {
+            chars[start + 1..start + 1 + len]
+                .iter()
+                .all(|&c| c.is_alphanumeric() || c == '_')
+                && !chars.get(start + 1).is_some_and(|c| c.is_ascii_digit())
+        })?;
+    let tag = &chars[start..start + tag_len + 2];
+    let body = start + tag.len();
+    (body..=chars.len().saturating_sub(tag.len()))
+        .find(|&i| chars[i..i + tag.len()] == *tag)
+        .map(|i| i + tag.len())
+        .or(Some(chars.len()))
+}
+
+/// Keeps the command of a Redis statement and redacts its arguments, but for its first
+/// one, usually the key, when `keep_keys`. Every argument of AUTH and HELLO is
+/// redacted, since they carry credentials.
+fn redact_redis(statement: &str, keep_keys: bool) -> String {
+    let mut words = statement.split_whitespace();
+    let Some(command) = words.next() else {
+        return String::new();
+    };
+    let credentials = ["auth", "hello"]
+        .iter()
+        .any(|c| command.eq_ignore_ascii_case(c));
+    let kept = usize::from(keep_keys && !credentials);
+    let mut redacted = command.to_string();
+    for (i, word) in words.enumerate() {
+        redacted.push(' ');
+        redacted.push_str(if i < kept { word } else { "?" });
+    }
+    redacted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact_sql_strings() {
+        assert_eq!(
+            redact_sql("SELECT * FROM users WHERE email = 'a@b.c'", false),
+            "SELECT * FROM users WHERE email = ?"
+        );
+        assert_eq!(
+            redact_sql("INSERT INTO t VALUES ('it''s', 'x')", false),
+            "INSERT INTO t VALUES (?, ?)"
+        );
+        // Backslashes escape quotes in MySQL and in Postgres E'' strings only.
+        assert_eq!(
+            redact_sql(r"INSERT INTO t VALUES ('it\'s', 'x')", true),
+            "INSERT INTO t VALUES (?, ?)"
+        );
+        assert_eq!(
+            redact_sql(r"INSERT INTO t VALUES (E'it\'s', 'x')", false),
+            "INSERT INTO t VALUES (E?, ?)"
+        );
+        assert_eq!(
+            redact_sql(r"INSERT INTO t VALUES ('C:\', 'secret')", false),
+            "INSERT INTO t VALUES (?, ?)"
+        );
+        // An unterminated string is redacted to the end.
+        assert_eq!(redact_sql("SELECT 'secret", false), "SELECT ?");
+    }
+
+    #[test]
+    fn test_redact_sql_double_quotes() {
+        assert_eq!(
+            redact_sql(r#"SELECT * FROM t WHERE name = "bob""#, true),
+            "SELECT * FROM t WHERE name = ?"
+        );
+        assert_eq!(
+            redact_sql(r#"SELECT "it's" FROM t WHERE id = 'x'"#, false),
+            r#"SELECT "it's" FROM t WHERE id = ?"#
+        );
+        assert_eq!(
+            redact_sql("SELECT `it's` FROM t WHERE id = 'x'", true),
+            "SELECT `it's` FROM t WHERE id = ?"
+        );
+    }
+
+    #[test]
+    fn test_redact_sql_dollar_quotes() {
+        assert_eq!(
+            redact_sql("SELECT $$it's$$, $1, $fn$a $$ b$fn$ FROM t", false),
+            "SELECT ?, $1, ? FROM t"
+        );
+        assert_eq!(redact_sql("SELECT $tag$secret", false), "SELECT ?");
+        assert_eq!(
+            redact_sql("SELECT price$ FROM t", false),
+            "SELECT price$ FROM t"
+        );
+    }
+
+    #[test]
+    fn test_redact_sql_numbers() {
+        assert_eq!(
+            redact_sql(
+                "SELECT * FROM t2 WHERE a = 42 AND b > 3.14 AND c < -7",
+                false
+            ),
+            "SELECT * FROM t2 WHERE a = ? AND b > ? AND c < -?"
+        );
+        assert_eq!(
+            redact_sql("SELECT 0x1F, X'1F', 1.5e-3, 2E+10, 0xE-1", true),
+            "SELECT ?, X?, ?, ?, ?-?"
+        );
+        assert_eq!(
+            redact_sql("SELECT col_1 FROM t WHERE id = $2", false),
+            "SELECT col_1 FROM t WHERE id = $2"
+        );
+    }
+
+    #[test]
+    fn test_redact_sql_comments() {
+        assert_eq!(
+            redact_sql("SELECT 1 -- it's user 42\nFROM t", false),
+            "SELECT ? -- ?\nFROM t"
+        );
+        assert_eq!(
+            redact_sql(
+                "SELECT /* traceparent='00-abc' */ a FROM t /* unterminated",
+                false
+            ),
+            "SELECT /* ? */ a FROM t /* ? */"
+        );
+        assert_eq!(
+            redact_sql("SELECT a # it's\nFROM t", true),
+            "SELECT a # ?\nFROM t"
+        );
+        assert_eq!(redact_sql("SELECT a - -1", false), "SELECT a - -?");
+    }
+
+    #[test]
+    fn test_redact_redis() {
+        assert_eq!(redact_redis("GET session:42", false), "GET ?");
+        assert_eq!(
+            redact_redis("SET session:42 token EX 60", false),
+            "SET ? ? ? ?"
+        );
+        assert_eq!(
+            redact_redis("SET session:42 token", true),
+            "SET session:42 ?"
+        );
+        assert_eq!(redact_redis("PING", true), "PING");
+        assert_eq!(redact_redis("", true), "");
+    }
+
+    #[test]
+    fn test_redact_redis_credentials() {
+        assert_eq!(redact_redis("AUTH s3cret", true), "AUTH ?");
+        assert_eq!(redact_redis("auth admin s3cret", true), "auth ? ?");
+        assert_eq!(
+            redact_redis("HELLO 3 AUTH admin s3cret SETNAME app", true),
+            "HELLO ? ? ? ? ? ?"
+        );
+    }
+}
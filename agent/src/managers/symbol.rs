@@ -0,0 +1,116 @@
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ops::Deref;
+use std::sync::Arc;
+
+use ahash::AHashSet;
+use parking_lot::RwLock;
+use prometheus_client::encoding::{
+    EncodeLabelKey, EncodeLabelValue, LabelKeyEncoder, LabelValueEncoder,
+};
+
+/// An interned string. A [`SymbolTable`] hands out exactly one allocation per distinct
+/// value, so symbols from the same table compare and hash by pointer.
+#[derive(Clone, Debug)]
+pub struct Symbol(Arc<str>);
+
+impl Symbol {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl PartialEq for Symbol {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for Symbol {}
+
+impl Hash for Symbol {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (Arc::as_ptr(&self.0) as *const u8 as usize).hash(state)
+    }
+}
+
+impl Deref for Symbol {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl EncodeLabelKey for Symbol {
+    fn encode(&self, encoder: &mut LabelKeyEncoder) -> Result<(), fmt::Error> {
+        EncodeLabelKey::encode(&self.as_str(), encoder)
+    }
+}
+
+impl EncodeLabelValue for Symbol {
+    fn encode(&self, encoder: &mut LabelValueEncoder) -> Result<(), fmt::Error> {
+        EncodeLabelValue::encode(&self.as_str(), encoder)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct SymbolTable {
+    strings: Arc<RwLock<AHashSet<Arc<str>>>>,
+}
+
+impl SymbolTable {
+    pub fn intern(&self, s: &str) -> Symbol {
+        if let Some(interned) = self.strings.read().get(s) {
+            return Symbol(interned.clone());
+        }
+
+        let mut strings = self.strings.write();
+        // Another writer may have interned the same value since the read lock was released.
+        if let Some(interned) = strings.get(s) {
+            return Symbol(interned.clone());
+        }
+        let interned: Arc<str> = Arc::from(s);
+        strings.insert(interned.clone());
+        Symbol(interned)
+    }
+
+    /// Forgets values that are no longer referenced outside the table.
+    pub fn purge(&self) {
+        self.strings.write().retain(|s| Arc::strong_count(s) > 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_intern_returns_one_symbol_per_value() {
+        let symbols = SymbolTable::default();
+        let frontend = symbols.intern("frontend");
+        assert_eq!(frontend, symbols.intern(&String::from("frontend")));
+        assert_ne!(frontend, symbols.intern("backend"));
+        assert_eq!(frontend.as_str(), "frontend");
+
+        // Equal values from different tables are different symbols.
+        assert_ne!(frontend, SymbolTable::default().intern("frontend"));
+    }
+
+    #[test]
+    fn test_purge_keeps_referenced_values() {
+        let symbols = SymbolTable::default();
+        let kept = symbols.intern("frontend");
+        drop(symbols.intern("backend"));
+
+        symbols.purge();
+        assert_eq!(symbols.strings.read().len(), 1);
+        assert_eq!(kept, symbols.intern("frontend"));
+    }
+}
@@ -0,0 +1,125 @@
+use std::collections::HashMap;
+
+use agent_api::v1::RequestSample;
+
+use crate::managers::capture::CapturePolicy;
+
+/// Longest tracestate kept, as propagators may drop longer ones.
+const MAX_TRACESTATE_LEN: usize = 512;
+
+/// Moves the W3C trace context of a reported request out of its headers, so that it
+/// joins the distributed trace it belongs to: the `traceparent` header into the trace
+/// id, parent span id and sampled flag, `tracestate` as it is, and the entries of
+/// `baggage` named in `baggage_keys`. Fields already set by the reporter are kept. Of
+/// the headers and the query, only what `capture` allows is kept once parsed.
+pub(crate) fn extract(
+    request: &mut RequestSample,
+    baggage_keys: &[String],
+    capture: &CapturePolicy,
+) {
+    let headers = std::mem::take(&mut request.headers);
+    let header = |name: &str| {
+        headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.trim())
+    };
+
+    if request.trace_id.is_empty() {
+        if let Some((trace_id, parent_span_id, sampled)) =
+            header("traceparent").and_then(traceparent)
+        {
+            request.trace_id = trace_id.to_string();
+            request.parent_span_id = parent_span_id.to_string();
+            request.trace_sampled = sampled;
+        }
+    }
+    if request.tracestate.is_empty() && !request.trace_id.is_empty() {
+        if let Some(tracestate) = header("tracestate").filter(|s| s.len() <= MAX_TRACESTATE_LEN) {
+            request.tracestate = tracestate.to_string();
+        }
+    }
+    if !baggage_keys.is_empty() {
+        if let Some(entries) = header("baggage").map(baggage) {
+            for (key, value) in entries {
+                if baggage_keys.contains(&key) {
+                    request.baggage.entry(key).or_insert(value);
+                }
+            }
+        }
+    }
+    capture.apply(request, headers);
+}
+
+/// Parses a `traceparent` header into its trace id, parent span id and sampled flag.
+/// Versions after 00 may append fields, which are ignored.
+fn traceparent(value: &str) -> Option<(&str, &str, bool)> {
+    let mut fields = value.split('-');
+    let version = fields.next()?;
+    let trace_id = fields.next()?;
+    let parent_span_id = fields.next()?;
+    let flags = fields.next()?;
+    if version.len() != 2 || !is_hex(version) || version == "ff" {
+        return None;
+    }
+    if version == "00" && fields.next().is_some() {
+        return None;
+    }
+    if trace_id.len() != 32 || !is_hex(trace_id) || trace_id.bytes().all(|b| b == b'0') {
+        return None;
+    }
+    if parent_span_id.len() != 16
+        || !is_hex(parent_span_id)
+        || parent_span_id.bytes().all(|b| b == b'0')
+    {
+        return None;
+    }
+    if flags.len() != 2 || !is_hex(flags) {
+        return None;
+    }
+    let flags = u8::from_str_radix(flags, 16).ok()?;
+    Some((trace_id, parent_span_id, flags & 1 == 1))
+}
+
+/// Lower case hexadecimal, as the trace context requires.
+fn is_hex(s: &str) -> bool {
+    s.bytes()
+        .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+}
+
+/// Parses the entries of a `baggage` header, leaving their properties out and
+/// percent-decoding their values.
+fn baggage(value: &str) -> HashMap<String, String> {
+    value
+        .split(',')
+        .filter_map(|member| {
+            let (entry, _properties) = member.split_once(';').unwrap_or((member, ""));
+            let (key, value) = entry.split_once('=')?;
+            let key = key.trim();
+            (!key.is_empty()).then(|| (key.to_string(), percent_decode(value.trim())))
+        })
+        .collect()
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = s
+            .get(i + 1..i + 3)
+            .filter(|hex| bytes[i] == b'%' && hex.bytes().all(|b| b.is_ascii_hexdigit()))
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
@@ -0,0 +1,324 @@
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::{info, warn};
+use tokio::sync::broadcast;
+use tokio::time::{self, Instant, MissedTickBehavior};
+
+use agent_api::ProgramState;
+
+use crate::common::errors::AgentError;
+use crate::common::types::ListFilter;
+use crate::managers::prog::ProgManager;
+use crate::progs::types::ShutdownSignal;
+
+/// Annotation a deployment bumps on each rollout.
+const REVISION_ANNOTATION: &str = "deployment.kubernetes.io/revision";
+/// Who the pauses and resumes of scheduled programs are audited as.
+const SCHEDULE_CALLER: &str = "schedule";
+
+/// A daily window, from `start` to `end` in minutes since midnight UTC. Windows ending
+/// before they start span midnight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct DailyWindow {
+    start: u32,
+    end: u32,
+}
+
+impl DailyWindow {
+    fn contains(&self, minute: u32) -> bool {
+        if self.start <= self.end {
+            (self.start..self.end).contains(&minute)
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+}
+
+impl FromStr for DailyWindow {
+    type Err = String;
+
+    /// Parses `HH:MM-HH:MM`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let minute = |time: &str| -> Option<u32> {
+            let (hours, minutes) = time.trim().split_once(':')?;
+            let (hours, minutes) = (hours.parse::<u32>().ok()?, minutes.parse::<u32>().ok()?);
+            (hours < 24 && minutes < 60).then_some(hours * 60 + minutes)
+        };
+        let window = s
+            .split_once('-')
+            .and_then(|(start, end)| Some((minute(start)?, minute(end)?)));
+        match window {
+            Some((start, end)) if start != end => Ok(Self { start, end }),
+            _ => Err(format!("expected HH:MM-HH:MM in UTC, got {:?}", s)),
+        }
+    }
+}
+
+/// When a program runs, set by its metadata: within the daily windows of `schedule`,
+/// separated by commas or spaces, e.g. `02:00-03:00 14:00-14:30`, and for `run_after_rollout` minutes after any
+/// deployment of the cluster rolls out. Programs with neither run all the time.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct TracingWindows {
+    daily: Vec<DailyWindow>,
+    after_rollout: Option<Duration>,
+}
+
+impl TracingWindows {
+    /// Returns the windows of a program, or `None` when it isn't scheduled. Metadata
+    /// was checked against the schema on load, so invalid windows are skipped.
+    pub(crate) fn from_metadata(metadata: &HashMap<String, String>) -> Option<Self> {
+        let daily: Vec<_> = metadata
+            .get("schedule")
+            .map(|schedule| {
+                schedule
+                    .split([',', ' '])
+                    .filter(|window| !window.is_empty())
+                    .filter_map(|window| window.parse().ok())
+                    .collect()
+            })
+            .unwrap_or_default();
+        let after_rollout = metadata
+            .get("run_after_rollout")
+            .and_then(|minutes| minutes.trim().parse::<u64>().ok())
+            .filter(|minutes| *minutes > 0)
+            .map(|minutes| Duration::from_secs(minutes * 60));
+        if daily.is_empty() && after_rollout.is_none() {
+            return None;
+        }
+        Some(Self {
+            daily,
+            after_rollout,
+        })
+    }
+
+    /// Whether the program should run at `minute` of the day, UTC, `since_rollout`
+    /// after the last rollout seen.
+    fn is_open(&self, minute: u32, since_rollout: Option<Duration>) -> bool {
+        self.daily.iter().any(|window| window.contains(minute))
+            || matches!(
+                (self.after_rollout, since_rollout),
+                (Some(after), Some(since)) if since < after
+            )
+    }
+}
+
+/// Checks a `schedule` metadata value: daily windows separated by commas or spaces.
+pub(crate) fn check_schedule(_key: &str, value: &str) -> Result<(), String> {
+    value
+        .split([',', ' '])
+        .filter(|window| !window.is_empty())
+        .try_for_each(|window| window.parse::<DailyWindow>().map(|_| ()))
+}
+
+/// Tells when deployments roll out, from their revision annotation.
+#[derive(Debug, Default)]
+struct RolloutWatch {
+    /// Last revision seen of each deployment, by `<NAMESPACE>/<NAME>`.
+    revisions: HashMap<String, String>,
+    /// Whether the deployments were looked at once, even if none had a revision.
+    initialized: bool,
+    last_rollout: Option<Instant>,
+}
+
+impl RolloutWatch {
+    /// Notes the current revision of each deployment, by `<NAMESPACE>/<NAME>`.
+    /// Deployments seen on the first call don't count, so that the rollouts before the
+    /// agent started don't open windows; those created later do.
+    fn observe(&mut self, revisions: impl IntoIterator<Item = (String, String)>) {
+        let first_check = !self.initialized;
+        self.initialized = true;
+        for (key, revision) in revisions {
+            let previous = self.revisions.insert(key, revision.clone());
+            if !first_check && previous != Some(revision) {
+                self.last_rollout = Some(Instant::now());
+            }
+        }
+    }
+}
+
+/// Pauses the scheduled programs when their tracing windows close, and resumes them
+/// when they open again, see [`TracingWindows`]. Only the programs it paused itself are
+/// resumed, so that programs paused by an operator stay paused; and it only acts as
+/// windows open or close, so that an operator can still resume a program outside them.
+#[derive(Debug)]
+pub(crate) struct WindowScheduler {
+    prog_manager: ProgManager,
+    rollouts: RolloutWatch,
+    /// Whether the windows of each scheduled program were open at the last check.
+    was_open: HashMap<String, bool>,
+    paused: HashSet<String>,
+}
+
+impl WindowScheduler {
+    pub(crate) fn new(prog_manager: ProgManager) -> Self {
+        Self {
+            prog_manager,
+            rollouts: RolloutWatch::default(),
+            was_open: HashMap::new(),
+            paused: HashSet::new(),
+        }
+    }
+
+    pub(crate) async fn run(
+        mut self,
+        interval: Duration,
+        mut shutdown_rx: broadcast::Receiver<ShutdownSignal>,
+    ) {
+        let mut interval = time::interval(interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => self.check(),
+                signal = shutdown_rx.recv() => match signal {
+                    Ok(ShutdownSignal::All) | Err(_) => break,
+                    Ok(_) => {}
+                },
+            }
+        }
+    }
+
+    fn check(&mut self) {
+        self.watch_rollouts();
+        let since_rollout = self.rollouts.last_rollout.map(|at| at.elapsed());
+        let minute = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| (d.as_secs() % 86400 / 60) as u32);
+
+        let programs = self
+            .prog_manager
+            .registry_manager
+            .list_programs(ListFilter::new(None, HashMap::new()));
+        let mut scheduled = HashSet::new();
+        for program in programs {
+            let Some(windows) = TracingWindows::from_metadata(&program.get_metadata()) else {
+                continue;
+            };
+            let state = program.get_state();
+            if !matches!(
+                state,
+                ProgramState::Running | ProgramState::Degraded | ProgramState::Paused
+            ) {
+                continue;
+            }
+            let name = program.get_name();
+            let open = windows.is_open(minute, since_rollout);
+            // Programs are loaded running, so a closed window pauses them right away.
+            let was_open = self.was_open.insert(name.clone(), open).unwrap_or(true);
+            scheduled.insert(name.clone());
+
+            if was_open && !open && matches!(state, ProgramState::Running | ProgramState::Degraded)
+            {
+                let result = self.prog_manager.pause(&name);
+                if result.is_ok() {
+                    self.paused.insert(name.clone());
+                    info!("Tracing window of program {} closed", name);
+                }
+                self.audit("pause", &name, &result);
+            } else if !was_open
+                && open
+                && state == ProgramState::Paused
+                && self.paused.remove(&name)
+            {
+                let result = self.prog_manager.resume(&name);
+                if result.is_ok() {
+                    info!("Tracing window of program {} opened", name);
+                }
+                self.audit("resume", &name, &result);
+            }
+        }
+        // Forget the programs unloaded, or loaded again without a schedule.
+        self.was_open.retain(|name, _| scheduled.contains(name));
+        self.paused.retain(|name| scheduled.contains(name));
+    }
+
+    /// Notes the time of the last deployment rollout, see [`RolloutWatch`].
+    fn watch_rollouts(&mut self) {
+        let deployments = self.prog_manager.cache_manager.deployments.state();
+        let revisions = deployments.iter().filter_map(|deployment| {
+            let revision = deployment
+                .metadata
+                .annotations
+                .as_ref()?
+                .get(REVISION_ANNOTATION)?;
+            let key = format!(
+                "{}/{}",
+                deployment.metadata.namespace.as_deref().unwrap_or_default(),
+                deployment.metadata.name.as_deref().unwrap_or_default()
+            );
+            Some((key, revision.clone()))
+        });
+        self.rollouts.observe(revisions);
+    }
+
+    fn audit<T>(&self, operation: &str, program: &str, result: &Result<T, AgentError>) {
+        if let Err(e) = result {
+            warn!(
+                "Failed to {} scheduled program {}: {}",
+                operation, program, e
+            );
+        }
+        self.prog_manager.audit_log.record(
+            SCHEDULE_CALLER,
+            operation,
+            program,
+            HashMap::new(),
+            result.as_ref().err().map(|e| e.to_string()),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_windows_span_midnight() {
+        let window: DailyWindow = "23:30-00:15".parse().unwrap();
+        assert!(window.contains(23 * 60 + 45));
+        assert!(window.contains(10));
+        assert!(!window.contains(15));
+        assert!(!window.contains(12 * 60));
+        assert!("02:00-02:00".parse::<DailyWindow>().is_err());
+        assert!("24:00-01:00".parse::<DailyWindow>().is_err());
+    }
+
+    #[test]
+    fn test_windows_open_after_rollouts() {
+        let metadata = HashMap::from([
+            ("schedule".to_string(), "02:00-03:00".to_string()),
+            ("run_after_rollout".to_string(), "10".to_string()),
+        ]);
+        let windows = TracingWindows::from_metadata(&metadata).unwrap();
+        assert!(windows.is_open(2 * 60 + 30, None));
+        assert!(!windows.is_open(3 * 60, None));
+        assert!(windows.is_open(3 * 60, Some(Duration::from_secs(60))));
+        assert!(!windows.is_open(3 * 60, Some(Duration::from_secs(600))));
+        assert_eq!(TracingWindows::from_metadata(&HashMap::new()), None);
+    }
+
+    fn revision(deployment: &str, revision: &str) -> (String, String) {
+        (format!("default/{}", deployment), revision.to_string())
+    }
+
+    #[test]
+    fn test_rollouts_after_first_check() {
+        let mut rollouts = RolloutWatch::default();
+        rollouts.observe([revision("api", "3")]);
+        assert!(rollouts.last_rollout.is_none());
+        rollouts.observe([revision("api", "3")]);
+        assert!(rollouts.last_rollout.is_none());
+        rollouts.observe([revision("api", "4")]);
+        assert!(rollouts.last_rollout.is_some());
+    }
+
+    #[test]
+    fn test_rollouts_without_deployments_at_start() {
+        let mut rollouts = RolloutWatch::default();
+        rollouts.observe([]);
+        // The first deployment annotated, once the agent runs, is a rollout.
+        rollouts.observe([revision("api", "1")]);
+        assert!(rollouts.last_rollout.is_some());
+    }
+}
@@ -1,2 +1,3 @@
+pub(crate) mod schema;
 pub(crate) mod service_map;
 pub(crate) mod types;
@@ -0,0 +1,207 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use thiserror::Error;
+
+use agent_api::v1::MetadataError;
+
+use crate::common::constants::DEFAULT_INTERVAL;
+use crate::managers::windows::check_schedule;
+
+/// Type of the values of a metadata key.
+#[derive(Debug, Clone, Copy)]
+pub enum MetadataType {
+    String,
+    /// `true` or `false`.
+    Bool,
+    /// A non-negative integer.
+    UInt,
+    /// A finite number.
+    Float,
+    /// One of the given values.
+    Enum(&'static [&'static str]),
+    /// Values separated by commas, each of the given type.
+    List(&'static MetadataType),
+    /// `name=value` pairs separated by commas.
+    Pairs,
+    /// Checked by the given function, from the key and the value.
+    Custom(fn(&str, &str) -> Result<(), String>),
+}
+
+impl MetadataType {
+    fn check(&self, key: &str, value: &str) -> Result<(), String> {
+        match self {
+            MetadataType::String => Ok(()),
+            MetadataType::Bool => match value {
+                "true" | "false" => Ok(()),
+                _ => Err(format!("expected true or false, got {:?}", value)),
+            },
+            MetadataType::UInt => value
+                .trim()
+                .parse::<u64>()
+                .map(|_| ())
+                .map_err(|_| format!("expected a non-negative integer, got {:?}", value)),
+            MetadataType::Float => match value.trim().parse::<f64>() {
+                Ok(v) if v.is_finite() => Ok(()),
+                _ => Err(format!("expected a number, got {:?}", value)),
+            },
+            MetadataType::Enum(values) if values.contains(&value) => Ok(()),
+            MetadataType::Enum(values) => Err(format!(
+                "expected one of {}, got {:?}",
+                values.join(", "),
+                value
+            )),
+            MetadataType::List(item) => value
+                .split(',')
+                .map(str::trim)
+                .filter(|v| !v.is_empty())
+                .try_for_each(|v| item.check(key, v)),
+            MetadataType::Pairs => value
+                .split(',')
+                .map(str::trim)
+                .filter(|pair| !pair.is_empty())
+                .try_for_each(|pair| match pair.split_once('=') {
+                    Some((name, _)) if !name.trim().is_empty() => Ok(()),
+                    _ => Err(format!("expected <name>=<value>, got {:?}", pair)),
+                }),
+            MetadataType::Custom(check) => check(key, value),
+        }
+    }
+}
+
+/// A metadata key a program reads.
+#[derive(Debug, Clone)]
+pub struct MetadataKey {
+    pub name: &'static str,
+    /// Whether `name` is a prefix, matching every key that starts with it.
+    pub prefix: bool,
+    pub value_type: MetadataType,
+    /// Value set when the key is missing.
+    pub default: Option<String>,
+    pub required: bool,
+}
+
+impl MetadataKey {
+    pub fn new(name: &'static str, value_type: MetadataType) -> Self {
+        Self {
+            name,
+            prefix: false,
+            value_type,
+            default: None,
+            required: false,
+        }
+    }
+
+    pub fn prefix(mut self) -> Self {
+        self.prefix = true;
+        self
+    }
+
+    pub fn default_value(mut self, value: impl ToString) -> Self {
+        self.default = Some(value.to_string());
+        self
+    }
+
+    fn matches(&self, key: &str) -> bool {
+        if self.prefix {
+            key.starts_with(self.name)
+        } else {
+            key == self.name
+        }
+    }
+}
+
+/// The metadata keys a program reads. Keys it does not declare are kept as they are,
+/// since metadata also labels programs for `list`.
+#[derive(Debug, Clone, Default)]
+pub struct MetadataSchema {
+    keys: Vec<MetadataKey>,
+}
+
+impl MetadataSchema {
+    /// The keys read by the agent for every program: the poll `interval`, in seconds,
+    /// the `metric_prefix` and `metric_labels` of its metrics, the `capture_headers`
+    /// and `capture_query_params` kept on the requests it reports, and the `schedule`
+    /// and `run_after_rollout` windows it runs in.
+    pub fn common() -> Self {
+        Self::default()
+            .key(MetadataKey::new("interval", MetadataType::UInt).default_value(DEFAULT_INTERVAL))
+            .key(MetadataKey::new("metric_prefix", MetadataType::String))
+            .key(MetadataKey::new("metric_labels", MetadataType::Pairs))
+            .key(MetadataKey::new(
+                "capture_headers",
+                MetadataType::List(&MetadataType::String),
+            ))
+            .key(MetadataKey::new(
+                "capture_query_params",
+                MetadataType::List(&MetadataType::String),
+            ))
+            .key(MetadataKey::new(
+                "schedule",
+                MetadataType::Custom(check_schedule),
+            ))
+            .key(MetadataKey::new("run_after_rollout", MetadataType::UInt))
+    }
+
+    pub fn key(mut self, key: MetadataKey) -> Self {
+        self.keys.push(key);
+        self
+    }
+
+    /// Checks `metadata` against the schema, and returns it with the defaults of the
+    /// missing keys set.
+    pub fn validate(
+        &self,
+        metadata: &HashMap<String, String>,
+    ) -> Result<HashMap<String, String>, MetadataErrors> {
+        let mut errors = Vec::new();
+        for (key, value) in metadata {
+            if let Some(declared) = self.keys.iter().find(|declared| declared.matches(key)) {
+                if let Err(message) = declared.value_type.check(key, value) {
+                    errors.push(MetadataError {
+                        key: key.clone(),
+                        message,
+                    });
+                }
+            }
+        }
+
+        let mut metadata = metadata.clone();
+        for declared in self.keys.iter().filter(|declared| !declared.prefix) {
+            if metadata.contains_key(declared.name) {
+                continue;
+            }
+            if let Some(default) = &declared.default {
+                metadata.insert(declared.name.to_string(), default.clone());
+            } else if declared.required {
+                errors.push(MetadataError {
+                    key: declared.name.to_string(),
+                    message: "required".to_string(),
+                });
+            }
+        }
+
+        if !errors.is_empty() {
+            errors.sort_by(|a, b| a.key.cmp(&b.key));
+            return Err(MetadataErrors(errors));
+        }
+        Ok(metadata)
+    }
+}
+
+/// The values rejected by a schema and the required keys missing, by key.
+#[derive(Debug, Clone, Error)]
+pub struct MetadataErrors(pub Vec<MetadataError>);
+
+impl fmt::Display for MetadataErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid metadata: ")?;
+        for (i, error) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, "; ")?;
+            }
+            write!(f, "{}: {}", error.key, error.message)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use ahash::AHashSet;
+use anyhow::Error;
+use conn_tracer_common::{AggregateKey, AggregateStats, CONNECTION_ROLE_CLIENT};
+use log::debug;
+
+use crate::common::maps::MapAccess;
+use crate::managers::cache::{CacheManager, Workload};
+use crate::progs::service_map::program::{unknown_workload, Connection, EdgeStats};
+
+/// Whether the tracer counts TCP connections per pair of workloads rather than one by
+/// one, as set by the `kernel_aggregation` metadata.
+pub(crate) fn kernel_aggregation(metadata: &HashMap<String, String>) -> bool {
+    metadata.get("kernel_aggregation").map(String::as_str) == Some("true")
+}
+
+/// The aggregation mode of the tracer, where it counts TCP connections per pair of
+/// identities of their ends, and their server port, instead of one by one. Identities
+/// stand for workloads, see [`IdentityTable`](crate::managers::identity::IdentityTable).
+/// On nodes with many connections, the map polled then only has an entry per edge.
+///
+/// Connections are only counted under identities pushed down by the time they are
+/// counted, and those to other addresses under the unknown workload. Their handshakes
+/// and flows aren't known either, nor loopback traffic. QUIC flows are still counted
+/// one by one.
+#[derive(Debug)]
+pub(crate) struct Aggregation {
+    aggregates_map: Box<dyn MapAccess<AggregateKey, AggregateStats>>,
+    /// Totals of each edge when last polled, and the aggregates they add up.
+    edges: HashMap<Connection, EdgeStats>,
+    keys: HashMap<Connection, Vec<AggregateKey>>,
+}
+
+impl Aggregation {
+    pub(crate) fn new(aggregates_map: Box<dyn MapAccess<AggregateKey, AggregateStats>>) -> Self {
+        Self {
+            aggregates_map,
+            edges: HashMap::new(),
+            keys: HashMap::new(),
+        }
+    }
+
+    /// Reads the aggregates into the totals of their edges. The server end of an edge
+    /// is left out when its client end is aggregated on this node too, so that the
+    /// connections between pods of the node make one edge rather than two.
+    pub(crate) fn poll(&mut self, cache_mgr: &CacheManager) -> Result<(), Error> {
+        let entries = self.aggregates_map.entries()?;
+        let clients: AHashSet<_> = entries
+            .iter()
+            .filter(|(key, _)| key.role == CONNECTION_ROLE_CLIENT)
+            .map(|(key, _)| (key.client, key.server, key.server_port, key.protocol))
+            .collect();
+
+        let mut edges: HashMap<Connection, EdgeStats> = HashMap::new();
+        let mut keys: HashMap<Connection, Vec<AggregateKey>> = HashMap::new();
+        for (key, stats) in entries {
+            if key.role != CONNECTION_ROLE_CLIENT
+                && clients.contains(&(key.client, key.server, key.server_port, key.protocol))
+            {
+                continue;
+            }
+            let conn = Connection {
+                client: self.workload(key.client, cache_mgr),
+                server: self.workload(key.server, cache_mgr),
+                role: key.role,
+                server_port: key.server_port,
+                protocol: key.protocol,
+                loopback: false,
+            };
+            edges
+                .entry(conn.clone())
+                .or_default()
+                .merge(&EdgeStats::from(&stats));
+            keys.entry(conn).or_default().push(key);
+        }
+        self.edges = edges;
+        self.keys = keys;
+        Ok(())
+    }
+
+    pub(crate) fn edges(&self) -> &HashMap<Connection, EdgeStats> {
+        &self.edges
+    }
+
+    /// Removes the aggregates of expired edges, whose unchanged totals would otherwise
+    /// make them again on the next poll.
+    pub(crate) fn expire(&mut self, conns: &[Connection]) {
+        for conn in conns {
+            self.edges.remove(conn);
+            for key in self.keys.remove(conn).unwrap_or_default() {
+                // The kernel may have evicted it already.
+                if let Err(e) = self.aggregates_map.remove(&key) {
+                    debug!("Failed to remove aggregate {:?}: {:?}", key, e);
+                }
+            }
+        }
+    }
+
+    fn workload(&self, identity: u32, cache_mgr: &CacheManager) -> Arc<Workload> {
+        let workload = cache_mgr
+            .identities
+            .workload(identity)
+            .unwrap_or_else(|| unknown_workload(cache_mgr));
+        cache_mgr.aliases.apply(workload, &cache_mgr.symbols)
+    }
+}
+
+impl From<&AggregateStats> for EdgeStats {
+    fn from(stats: &AggregateStats) -> Self {
+        Self {
+            bytes_sent: stats.bytes_sent,
+            bytes_received: stats.bytes_received,
+            resets: stats.resets,
+            connect_timeouts: stats.connect_timeouts,
+            active_conns: stats.opened.saturating_sub(stats.closed),
+            opened_conns: stats.opened,
+            requests: stats.requests,
+            requesting_conns: stats.requesting,
+        }
+    }
+}
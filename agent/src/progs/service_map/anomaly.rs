@@ -0,0 +1,321 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use log::warn;
+
+use crate::progs::service_map::events::publish_warning;
+use crate::progs::service_map::program::Connection;
+
+const DEFAULT_THRESHOLD: f64 = 3.0;
+/// Weight of the newest sample in the moving mean and variance.
+const ALPHA: f64 = 0.3;
+/// Polls observed before an edge can be flagged.
+const WARMUP: u32 = 10;
+/// Floor of the band relative to the mean, so that a perfectly steady edge isn't
+/// flagged for the smallest wobble.
+const MIN_RELATIVE_STD: f64 = 0.1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Deviation {
+    Spike,
+    Drop,
+}
+
+impl fmt::Display for Deviation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Deviation::Spike => f.write_str("spiked"),
+            Deviation::Drop => f.write_str("dropped"),
+        }
+    }
+}
+
+/// Settings of the optional per-edge detector, read from the `anomaly_detection` and
+/// `anomaly_threshold` metadata keys.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct AnomalyConfig {
+    threshold: f64,
+}
+
+impl AnomalyConfig {
+    pub(crate) fn from_metadata(metadata: &HashMap<String, String>) -> Option<Self> {
+        if metadata.get("anomaly_detection").map(String::as_str) != Some("true") {
+            return None;
+        }
+        let threshold = metadata
+            .get("anomaly_threshold")
+            .and_then(|t| t.parse::<f64>().ok())
+            .filter(|t| *t > 0.0)
+            .unwrap_or(DEFAULT_THRESHOLD);
+        Some(Self { threshold })
+    }
+}
+
+/// An exponentially weighted mean and variance of one signal. A sample further than
+/// `threshold` standard deviations from the mean is anomalous.
+#[derive(Debug, Default)]
+pub(crate) struct Band {
+    mean: f64,
+    var: f64,
+    samples: u32,
+    deviation: Option<Deviation>,
+}
+
+impl Band {
+    /// Feeds a sample and returns the deviation if the signal just left the band.
+    fn observe(&mut self, x: f64, threshold: f64) -> Option<Deviation> {
+        let deviation = if self.samples >= WARMUP {
+            let std = self
+                .var
+                .sqrt()
+                .max(self.mean.abs() * MIN_RELATIVE_STD)
+                .max(f64::EPSILON);
+            let z = (x - self.mean) / std;
+            if z > threshold {
+                Some(Deviation::Spike)
+            } else if z < -threshold {
+                Some(Deviation::Drop)
+            } else {
+                None
+            }
+        } else {
+            None
+        };
+
+        let diff = x - self.mean;
+        let incr = ALPHA * diff;
+        self.mean += incr;
+        self.var = (1.0 - ALPHA) * (self.var + diff * incr);
+        self.samples = self.samples.saturating_add(1);
+
+        let started = deviation.filter(|d| self.deviation != Some(*d));
+        self.deviation = deviation;
+        started
+    }
+
+    pub(crate) fn is_anomalous(&self) -> bool {
+        self.deviation.is_some()
+    }
+}
+
+/// The signals watched on one edge: bytes sent per poll and open connections.
+#[derive(Debug, Default)]
+pub(crate) struct EdgeDetector {
+    pub(crate) bytes: Band,
+    pub(crate) connections: Band,
+    primed: bool,
+}
+
+impl EdgeDetector {
+    /// Feeds the signals of one poll. The first poll of an edge carries everything the
+    /// edge sent before it was seen, so it only primes the detector.
+    pub(crate) fn observe(
+        &mut self,
+        conn: &Connection,
+        bytes: u64,
+        connections: u64,
+        config: &AnomalyConfig,
+    ) -> Vec<EdgeAnomaly> {
+        if !self.primed {
+            self.primed = true;
+            return vec![];
+        }
+
+        let mut anomalies = Vec::new();
+        for (signal, band, value) in [
+            ("bytes sent per poll", &mut self.bytes, bytes),
+            ("open connections", &mut self.connections, connections),
+        ] {
+            let expected = band.mean;
+            if let Some(deviation) = band.observe(value as f64, config.threshold) {
+                anomalies.push(EdgeAnomaly {
+                    conn: conn.clone(),
+                    signal,
+                    deviation,
+                    value,
+                    expected,
+                });
+            }
+        }
+        anomalies
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct EdgeAnomaly {
+    conn: Connection,
+    signal: &'static str,
+    deviation: Deviation,
+    value: u64,
+    expected: f64,
+}
+
+/// Logs an anomaly and records it as a Kubernetes event on the server workload.
+pub(crate) async fn publish_anomaly(anomaly: EdgeAnomaly) {
+    let client = &anomaly.conn.client;
+    let server = &anomaly.conn.server;
+    let message = format!(
+        "{} on edge {}/{} -> {}/{}:{} {} to {}, expected about {:.0}",
+        anomaly.signal,
+        client.namespace,
+        client.name,
+        server.namespace,
+        server.name,
+        anomaly.conn.server_port,
+        anomaly.deviation,
+        anomaly.value,
+        anomaly.expected
+    );
+    warn!("{}", message);
+
+    publish_warning(
+        server.clone(),
+        format!("edge-anomaly-{}-", server.name),
+        "EdgeThroughputAnomaly",
+        message,
+    )
+    .await;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::managers::cache::Workload;
+    use crate::managers::symbol::SymbolTable;
+
+    fn connection() -> Connection {
+        let symbols = SymbolTable::default();
+        let workload = |name: &str| {
+            Arc::new(Workload {
+                name: symbols.intern(name),
+                namespace: symbols.intern("default"),
+                kind: symbols.intern("Deployment"),
+                labels: Vec::new(),
+            })
+        };
+        Connection {
+            client: workload("frontend"),
+            server: workload("checkout"),
+            role: 0,
+            server_port: 8080,
+            protocol: 0,
+            loopback: false,
+        }
+    }
+
+    fn config(threshold: &str) -> AnomalyConfig {
+        AnomalyConfig::from_metadata(&HashMap::from([
+            ("anomaly_detection".to_string(), "true".to_string()),
+            ("anomaly_threshold".to_string(), threshold.to_string()),
+        ]))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_anomaly_config() {
+        assert!(AnomalyConfig::from_metadata(&HashMap::new()).is_none());
+        assert_eq!(config("4.5").threshold, 4.5);
+        assert_eq!(config("-1").threshold, DEFAULT_THRESHOLD);
+        assert_eq!(config("high").threshold, DEFAULT_THRESHOLD);
+    }
+
+    #[test]
+    fn test_band_silent_during_warmup() {
+        let mut band = Band::default();
+        for i in 0..WARMUP {
+            let x = if i % 2 == 0 { 1.0 } else { 1_000_000.0 };
+            assert_eq!(band.observe(x, DEFAULT_THRESHOLD), None);
+        }
+        assert!(!band.is_anomalous());
+        assert_eq!(band.samples, WARMUP);
+    }
+
+    /// Feeds the warm-up samples at `level`.
+    fn warmed_up(level: f64) -> Band {
+        let mut band = Band::default();
+        for _ in 0..WARMUP {
+            band.observe(level, DEFAULT_THRESHOLD);
+        }
+        band
+    }
+
+    #[test]
+    fn test_band_spike_and_drop() {
+        let mut band = warmed_up(100.0);
+        // Wobbles stay within the band.
+        assert_eq!(band.observe(110.0, DEFAULT_THRESHOLD), None);
+        assert_eq!(band.observe(90.0, DEFAULT_THRESHOLD), None);
+        assert_eq!(
+            band.observe(1000.0, DEFAULT_THRESHOLD),
+            Some(Deviation::Spike)
+        );
+        assert!(band.is_anomalous());
+
+        let mut band = warmed_up(1000.0);
+        assert_eq!(band.observe(10.0, DEFAULT_THRESHOLD), Some(Deviation::Drop));
+        assert!(band.is_anomalous());
+
+        // A higher threshold lets more through.
+        let mut band = warmed_up(100.0);
+        assert_eq!(band.observe(200.0, 2.0), Some(Deviation::Spike));
+        let mut band = warmed_up(100.0);
+        assert_eq!(band.observe(200.0, 10.0), None);
+    }
+
+    #[test]
+    fn test_band_decays_to_new_level() {
+        let mut band = warmed_up(100.0);
+        assert_eq!(
+            band.observe(1000.0, DEFAULT_THRESHOLD),
+            Some(Deviation::Spike)
+        );
+        // The spike widens the band, so that staying at the new level isn't flagged
+        // again while the weight of the old samples decays.
+        for _ in 0..20 {
+            assert_eq!(band.observe(1000.0, DEFAULT_THRESHOLD), None);
+        }
+        assert!(!band.is_anomalous());
+        assert!((band.mean - 1000.0).abs() < 1.0);
+
+        // Once the new level is the usual one, going back is a drop.
+        assert_eq!(
+            band.observe(100.0, DEFAULT_THRESHOLD),
+            Some(Deviation::Drop)
+        );
+    }
+
+    #[test]
+    fn test_band_reports_deviation_once() {
+        let mut band = warmed_up(0.0);
+        // Nothing to scale a floor from, so any traffic spikes.
+        assert_eq!(band.observe(1.0, DEFAULT_THRESHOLD), Some(Deviation::Spike));
+        // Still out of the band, but already reported.
+        assert_eq!(band.observe(5.0, DEFAULT_THRESHOLD), None);
+        assert!(band.is_anomalous());
+    }
+
+    #[test]
+    fn test_edge_detector_primed_by_first_poll() {
+        let conn = connection();
+        let config = config("3");
+        let mut detector = EdgeDetector::default();
+        // Everything sent before the edge was seen.
+        assert!(detector.observe(&conn, 1 << 40, 1, &config).is_empty());
+        assert_eq!(detector.bytes.samples, 0);
+
+        for _ in 0..WARMUP {
+            assert!(detector.observe(&conn, 1000, 5, &config).is_empty());
+        }
+        let anomalies = detector.observe(&conn, 100_000, 5, &config);
+        assert_eq!(anomalies.len(), 1);
+        assert_eq!(anomalies[0].signal, "bytes sent per poll");
+        assert_eq!(anomalies[0].deviation, Deviation::Spike);
+        assert_eq!(anomalies[0].value, 100_000);
+        // The mean starts at zero and is still warming up to the usual level.
+        assert!((anomalies[0].expected - 1000.0).abs() < 50.0);
+        assert!(detector.bytes.is_anomalous());
+        assert!(!detector.connections.is_anomalous());
+    }
+}
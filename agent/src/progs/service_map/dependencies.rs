@@ -0,0 +1,178 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use ahash::{AHashMap, AHashSet};
+
+use agent_api::v1::{DependencyChange, DependencyEvent};
+
+use crate::common::constants::DEFAULT_DEPENDENCY_ABSENT_INTERVALS;
+use crate::managers::cache::Workload;
+
+type Pair = (Arc<Workload>, Arc<Workload>);
+
+#[derive(Debug)]
+struct Dependency {
+    polls_seen: u32,
+    absent_polls: u32,
+}
+
+/// Tracks which (client, server) workload pairs talk to each other. A pair seen for the
+/// first time is reported as added, and a pair seen for at least `absent_intervals`
+/// polls is reported as removed once it goes unseen for as many polls in a row. The
+/// first `absent_intervals` polls only build the baseline, so a restart of the agent
+/// doesn't report every existing dependency.
+#[derive(Debug, Default)]
+pub(crate) struct DependencyTracker {
+    dependencies: AHashMap<Pair, Dependency>,
+    polls: u32,
+}
+
+impl DependencyTracker {
+    pub(crate) fn update(
+        &mut self,
+        seen: AHashSet<Pair>,
+        absent_intervals: u32,
+    ) -> Vec<DependencyEvent> {
+        self.polls = self.polls.saturating_add(1);
+        let warm = self.polls > absent_intervals;
+        let mut events = Vec::new();
+
+        for dependency in self.dependencies.values_mut() {
+            dependency.absent_polls += 1;
+        }
+        for pair in seen {
+            match self.dependencies.get_mut(&pair) {
+                Some(dependency) => {
+                    dependency.polls_seen = dependency.polls_seen.saturating_add(1);
+                    dependency.absent_polls = 0;
+                }
+                None => {
+                    if warm {
+                        events.push(new_event(DependencyChange::Added, &pair));
+                    }
+                    self.dependencies.insert(
+                        pair,
+                        Dependency {
+                            polls_seen: 1,
+                            absent_polls: 0,
+                        },
+                    );
+                }
+            }
+        }
+
+        self.dependencies.retain(|pair, dependency| {
+            if dependency.absent_polls < absent_intervals {
+                return true;
+            }
+            if dependency.polls_seen >= absent_intervals {
+                events.push(new_event(DependencyChange::Removed, pair));
+            }
+            false
+        });
+        events
+    }
+}
+
+pub(crate) fn absent_intervals(metadata: &HashMap<String, String>) -> u32 {
+    metadata
+        .get("dependency_absent_intervals")
+        .and_then(|n| n.parse::<u32>().ok())
+        .unwrap_or(DEFAULT_DEPENDENCY_ABSENT_INTERVALS)
+        .max(1)
+}
+
+fn new_event(change: DependencyChange, (client, server): &Pair) -> DependencyEvent {
+    let timestamp_ns = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos() as u64);
+    DependencyEvent {
+        timestamp_ns,
+        change: change as i32,
+        client_workload: format!("{}/{}", client.namespace, client.name),
+        server_workload: format!("{}/{}", server.namespace, server.name),
+        // Numbered when published.
+        sequence: 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::managers::symbol::SymbolTable;
+
+    fn workload(symbols: &SymbolTable, name: &str) -> Arc<Workload> {
+        Arc::new(Workload {
+            name: symbols.intern(name),
+            namespace: symbols.intern("default"),
+            kind: symbols.intern("Deployment"),
+            labels: Vec::new(),
+        })
+    }
+
+    fn seen(symbols: &SymbolTable, servers: &[&str]) -> AHashSet<Pair> {
+        servers
+            .iter()
+            .map(|server| (workload(symbols, "web"), workload(symbols, server)))
+            .collect()
+    }
+
+    fn changes(events: Vec<DependencyEvent>) -> Vec<(DependencyChange, String)> {
+        events
+            .into_iter()
+            .map(|event| (event.change(), event.server_workload))
+            .collect()
+    }
+
+    #[test]
+    fn test_baseline_is_not_reported() {
+        let symbols = SymbolTable::default();
+        let mut tracker = DependencyTracker::default();
+        assert!(tracker.update(seen(&symbols, &["api"]), 2).is_empty());
+        assert!(tracker.update(seen(&symbols, &["api"]), 2).is_empty());
+        assert_eq!(
+            changes(tracker.update(seen(&symbols, &["api", "db"]), 2)),
+            vec![(DependencyChange::Added, "default/db".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_dependencies_removed_after_absent_intervals() {
+        let symbols = SymbolTable::default();
+        let mut tracker = DependencyTracker::default();
+        for _ in 0..3 {
+            tracker.update(seen(&symbols, &["api", "db"]), 2);
+        }
+        assert!(tracker.update(seen(&symbols, &["db"]), 2).is_empty());
+        assert_eq!(
+            changes(tracker.update(seen(&symbols, &["db"]), 2)),
+            vec![(DependencyChange::Removed, "default/api".to_string())]
+        );
+
+        // A pair seen for fewer polls than the interval goes away silently.
+        assert_eq!(
+            changes(tracker.update(seen(&symbols, &["db", "cache"]), 2)),
+            vec![(DependencyChange::Added, "default/cache".to_string())]
+        );
+        tracker.update(seen(&symbols, &["db"]), 2);
+        assert!(tracker.update(seen(&symbols, &["db"]), 2).is_empty());
+        assert_eq!(
+            changes(tracker.update(seen(&symbols, &["db", "cache"]), 2)),
+            vec![(DependencyChange::Added, "default/cache".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_absent_intervals_from_metadata() {
+        let metadata = |value: &str| {
+            HashMap::from([("dependency_absent_intervals".to_string(), value.to_string())])
+        };
+        assert_eq!(absent_intervals(&metadata("5")), 5);
+        assert_eq!(absent_intervals(&metadata("0")), 1);
+        assert_eq!(
+            absent_intervals(&HashMap::new()),
+            DEFAULT_DEPENDENCY_ABSENT_INTERVALS
+        );
+    }
+}
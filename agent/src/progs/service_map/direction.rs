@@ -0,0 +1,123 @@
+use std::fs;
+use std::ops::RangeInclusive;
+
+use ahash::AHashSet;
+
+use conn_tracer_common::{
+    ConnectionKey, ConnectionStats, CONNECTION_ROLE_CLIENT, CONNECTION_ROLE_SERVER,
+    CONNECTION_ROLE_UNKNOWN,
+};
+
+/// The kernel default of `net.ipv4.ip_local_port_range`.
+const DEFAULT_EPHEMERAL_PORTS: RangeInclusive<u32> = 32768..=60999;
+/// State of listening sockets in `/proc/net/tcp`.
+const TCP_LISTEN: &str = "0A";
+
+/// Returns the role of the socket of a connection, inferred from its ports when the
+/// kernel did not tell. The local end is the server when its port is listening in the
+/// network namespace of the socket owner. Otherwise the end on an ephemeral port is
+/// the client, and failing that the end on the lower port is the server.
+pub(crate) fn role(key: &ConnectionKey) -> u32 {
+    if key.role != CONNECTION_ROLE_UNKNOWN {
+        return key.role;
+    }
+    if listening_ports(key.pid).contains(&key.src_port) {
+        return CONNECTION_ROLE_SERVER;
+    }
+    let ephemeral = ephemeral_ports();
+    match (
+        ephemeral.contains(&key.src_port),
+        ephemeral.contains(&key.dest_port),
+    ) {
+        (true, false) => CONNECTION_ROLE_CLIENT,
+        (false, true) => CONNECTION_ROLE_SERVER,
+        _ if key.src_port < key.dest_port => CONNECTION_ROLE_SERVER,
+        _ if key.src_port > key.dest_port => CONNECTION_ROLE_CLIENT,
+        _ => CONNECTION_ROLE_UNKNOWN,
+    }
+}
+
+/// Returns the key of a connection with its role inferred, see [`role`].
+pub(crate) fn with_role(mut key: ConnectionKey) -> ConnectionKey {
+    key.role = role(&key);
+    key
+}
+
+/// Server ends of connections whose client end is observed on this node too, e.g.
+/// between two pods of the node. Only the client end of such a connection is
+/// accounted, so that it makes one edge rather than two.
+///
+/// A server end stays mirrored until it closes, even after its client end closed, since
+/// the totals of the client end were already accounted.
+#[derive(Debug, Default)]
+pub(crate) struct MirroredEnds {
+    keys: AHashSet<ConnectionKey>,
+}
+
+impl MirroredEnds {
+    /// Returns the server ends mirrored in the entries of a poll.
+    pub(crate) fn find(
+        &self,
+        entries: &[(ConnectionKey, ConnectionStats)],
+    ) -> AHashSet<ConnectionKey> {
+        let clients: AHashSet<_> = entries
+            .iter()
+            .filter(|(key, _)| role(key) == CONNECTION_ROLE_CLIENT)
+            .map(|(key, _)| (key.src_addr, key.src_port, key.dest_addr, key.dest_port))
+            .collect();
+        entries
+            .iter()
+            .map(|(key, _)| *key)
+            .filter(|key| {
+                self.keys.contains(key)
+                    || (role(key) == CONNECTION_ROLE_SERVER
+                        && clients.contains(&(
+                            key.dest_addr,
+                            key.dest_port,
+                            key.src_addr,
+                            key.src_port,
+                        )))
+            })
+            .collect()
+    }
+
+    /// Keeps the server ends mirrored in the last poll.
+    pub(crate) fn update(&mut self, keys: AHashSet<ConnectionKey>) {
+        self.keys = keys;
+    }
+}
+
+/// Local ports listening in the network namespace of a process, read from its view of
+/// `/proc/net/tcp` and `/proc/net/tcp6`.
+fn listening_ports(pid: u32) -> AHashSet<u32> {
+    ["tcp", "tcp6"]
+        .iter()
+        .filter_map(|table| fs::read_to_string(format!("/proc/{}/net/{}", pid, table)).ok())
+        .flat_map(|table| {
+            table
+                .lines()
+                .skip(1)
+                .filter_map(|line| {
+                    let mut fields = line.split_whitespace();
+                    let local = fields.nth(1)?;
+                    let state = fields.nth(1)?;
+                    if state != TCP_LISTEN {
+                        return None;
+                    }
+                    let (_, port) = local.rsplit_once(':')?;
+                    u32::from_str_radix(port, 16).ok()
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+fn ephemeral_ports() -> RangeInclusive<u32> {
+    fs::read_to_string("/proc/sys/net/ipv4/ip_local_port_range")
+        .ok()
+        .and_then(|range| {
+            let mut bounds = range.split_whitespace().map(str::parse::<u32>);
+            Some(bounds.next()?.ok()?..=bounds.next()?.ok()?)
+        })
+        .unwrap_or(DEFAULT_EPHEMERAL_PORTS)
+}
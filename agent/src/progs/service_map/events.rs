@@ -0,0 +1,123 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use k8s_openapi::api::core::v1::{Event, EventSource, ObjectReference};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{ObjectMeta, Time};
+use k8s_openapi::chrono::Utc;
+use kube::api::{Api, PostParams};
+use kube::Client;
+use log::debug;
+
+use crate::managers::cache::Workload;
+
+/// Records a warning as a Kubernetes event on `workload`. Failures are only logged,
+/// since events are best effort and the agent may lack the permission to create them.
+/// The configured pod labels of the workload are set on the event, so that events of a
+/// canary can be told from those of the stable version.
+pub(crate) async fn publish_warning(
+    workload: Arc<Workload>,
+    generate_name: String,
+    reason: &str,
+    message: String,
+) {
+    let labels = (!workload.labels.is_empty()).then(|| {
+        workload
+            .labels
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    });
+    let involved_object = ObjectReference {
+        api_version: Some(api_version(workload.kind.as_str()).to_string()),
+        kind: Some(workload.kind.to_string()),
+        name: Some(workload.name.to_string()),
+        namespace: Some(workload.namespace.to_string()),
+        ..Default::default()
+    };
+    create_warning(
+        workload.namespace.as_str(),
+        involved_object,
+        labels,
+        generate_name,
+        reason,
+        message,
+    )
+    .await;
+}
+
+/// Records a warning as a Kubernetes event on `node`, for findings that aren't about
+/// a workload. Like those of the kubelet, node events go to the default namespace.
+pub(crate) async fn publish_node_warning(
+    node: &str,
+    generate_name: String,
+    reason: &str,
+    message: String,
+) {
+    let involved_object = ObjectReference {
+        api_version: Some("v1".to_string()),
+        kind: Some("Node".to_string()),
+        name: Some(node.to_string()),
+        ..Default::default()
+    };
+    create_warning(
+        "default",
+        involved_object,
+        None,
+        generate_name,
+        reason,
+        message,
+    )
+    .await;
+}
+
+async fn create_warning(
+    namespace: &str,
+    involved_object: ObjectReference,
+    labels: Option<BTreeMap<String, String>>,
+    generate_name: String,
+    reason: &str,
+    message: String,
+) {
+    let client = match Client::try_default().await {
+        Ok(client) => client,
+        Err(e) => {
+            debug!("Failed to create client for {} event: {:?}", reason, e);
+            return;
+        }
+    };
+
+    let now = Time(Utc::now());
+    let event = Event {
+        metadata: ObjectMeta {
+            generate_name: Some(generate_name),
+            namespace: Some(namespace.to_string()),
+            labels,
+            ..Default::default()
+        },
+        involved_object,
+        reason: Some(reason.to_string()),
+        message: Some(message),
+        type_: Some("Warning".to_string()),
+        source: Some(EventSource {
+            component: Some("service_map".to_string()),
+            ..Default::default()
+        }),
+        first_timestamp: Some(now.clone()),
+        last_timestamp: Some(now),
+        count: Some(1),
+        ..Default::default()
+    };
+
+    let events: Api<Event> = Api::namespaced(client, namespace);
+    if let Err(e) = events.create(&PostParams::default(), &event).await {
+        debug!("Failed to publish {} event: {:?}", reason, e);
+    }
+}
+
+fn api_version(kind: &str) -> &'static str {
+    match kind {
+        "Deployment" | "DaemonSet" | "StatefulSet" | "ReplicaSet" => "apps/v1",
+        "Job" | "CronJob" => "batch/v1",
+        _ => "v1",
+    }
+}
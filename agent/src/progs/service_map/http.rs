@@ -0,0 +1,325 @@
+use std::collections::HashMap;
+use std::sync::atomic::AtomicU64;
+
+use ahash::{AHashMap, AHashSet};
+use anyhow::Error;
+use conn_tracer_common::{
+    HttpResponseStats, HTTP_ENCODING_BR, HTTP_ENCODING_DEFLATE, HTTP_ENCODING_GZIP,
+    HTTP_ENCODING_IDENTITY, HTTP_ENCODING_ZSTD,
+};
+use prometheus_client::encoding::{DescriptorEncoder, EncodeLabelSet, EncodeMetric};
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
+
+use crate::common::constants::DEFAULT_HTTP_MAX_ROUTES;
+use crate::managers::symbol::{Symbol, SymbolTable};
+use crate::progs::service_map::labels::Labels;
+use crate::progs::service_map::program::Connection;
+
+/// Route of the requests to an edge past its `http_max_routes` routes.
+const OTHER_ROUTE: &str = "{other}";
+
+/// Returns the method and path of a captured request line, and whether the path is
+/// whole rather than cut short by the end of the capture.
+pub(crate) fn request_line(line: &[u8]) -> Option<(&str, &str, bool)> {
+    let end = line.iter().position(|&b| b == 0).unwrap_or(line.len());
+    let line = match std::str::from_utf8(&line[..end]) {
+        Ok(line) => line,
+        Err(e) => std::str::from_utf8(&line[..e.valid_up_to()]).ok()?,
+    };
+    let (method, rest) = line.split_once(' ')?;
+    if method.is_empty() || !method.bytes().all(|b| b.is_ascii_uppercase()) {
+        return None;
+    }
+    Some(match rest.split_once([' ', '\r', '\n']) {
+        Some((path, _)) => (method, path, true),
+        None => (method, rest, false),
+    })
+}
+
+/// Collapses request paths into routes, so that the IDs they carry don't make a series
+/// each. A path takes the first configured pattern it matches; otherwise its numeric,
+/// UUID and hash segments are replaced with `{id}`, `{uuid}` and `{hash}`.
+#[derive(Debug, Default)]
+pub(crate) struct RouteTemplates {
+    /// Segments of the patterns set by the `http_route_patterns` metadata.
+    patterns: Vec<Vec<String>>,
+}
+
+impl RouteTemplates {
+    pub(crate) fn from_metadata(metadata: &HashMap<String, String>) -> Self {
+        let patterns = metadata
+            .get("http_route_patterns")
+            .map(|patterns| {
+                patterns
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|pattern| !pattern.is_empty())
+                    .map(|pattern| segments(pattern).map(String::from).collect())
+                    .collect()
+            })
+            .unwrap_or_default();
+        Self { patterns }
+    }
+
+    /// Returns the route of a path, without its query. A path cut short by the capture
+    /// has its last, partial, segment replaced with `*`.
+    pub(crate) fn route(&self, path: &str, whole: bool) -> String {
+        let (path, whole) = match path.split_once(['?', '#']) {
+            Some((path, _)) => (path, true),
+            None => (path, whole),
+        };
+        // Proxies are sent absolute URLs.
+        let path = match path.split_once("://") {
+            Some((_, rest)) => rest.find('/').map_or("/", |start| &rest[start..]),
+            None => path,
+        };
+        if !path.starts_with('/') {
+            return path.to_string();
+        }
+        let mut segments: Vec<_> = segments(path).collect();
+        if !whole {
+            segments.pop();
+            segments.push("*");
+        }
+        if let Some(pattern) = self
+            .patterns
+            .iter()
+            .find(|pattern| matches(pattern, &segments))
+        {
+            return format!("/{}", pattern.join("/"));
+        }
+        let mut route: String = segments
+            .iter()
+            .flat_map(|segment| ["/", collapse(segment)])
+            .collect();
+        if route.is_empty() || (whole && path.ends_with('/')) {
+            route.push('/');
+        }
+        route
+    }
+}
+
+fn segments(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|segment| !segment.is_empty())
+}
+
+/// Whether a path matches a pattern, whose `{name}` segments match any segment and
+/// whose last `*` segment matches the rest of the path.
+fn matches(pattern: &[String], segments: &[&str]) -> bool {
+    let (pattern, rest) = match pattern.split_last() {
+        Some((last, pattern)) if last == "*" => (pattern, true),
+        _ => (pattern, false),
+    };
+    if segments.len() < pattern.len() || (!rest && segments.len() != pattern.len()) {
+        return false;
+    }
+    pattern.iter().zip(segments).all(|(pattern, segment)| {
+        (pattern.starts_with('{') && pattern.ends_with('}')) || pattern == segment
+    })
+}
+
+fn collapse(segment: &str) -> &str {
+    let hex = |s: &str| s.bytes().all(|b| b.is_ascii_hexdigit());
+    if segment.bytes().all(|b| b.is_ascii_digit()) {
+        "{id}"
+    } else if segment.len() == 36
+        && segment.split('-').map(str::len).eq([8, 4, 4, 4, 12])
+        && hex(&segment.replace('-', ""))
+    {
+        "{uuid}"
+    } else if segment.len() >= 16 && hex(segment) {
+        "{hash}"
+    } else {
+        segment
+    }
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct RouteLabels {
+    method: Symbol,
+    route: Symbol,
+    #[prometheus(flatten)]
+    edge: Labels,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct EncodingLabels {
+    encoding: Symbol,
+    #[prometheus(flatten)]
+    edge: Labels,
+}
+
+#[derive(Debug)]
+struct HttpEdge {
+    labels: Labels,
+    routes: AHashSet<RouteLabels>,
+    encodings: AHashSet<EncodingLabels>,
+}
+
+/// Requests received by HTTP servers, per edge, method and route. Each edge keeps at
+/// most `http_max_routes` routes, past which requests are counted under `{other}`.
+///
+/// The responses of the servers are counted per content encoding, along with the
+/// bytes of their bodies, so that a deploy turning compression off shows as a jump in
+/// bandwidth. Bodies without a Content-Length, e.g. chunked ones, aren't sized.
+#[derive(Debug, Default)]
+pub(crate) struct HttpMetrics {
+    templates: RouteTemplates,
+    max_routes: usize,
+    edges: AHashMap<Connection, HttpEdge>,
+    requests: Family<RouteLabels, Counter>,
+    responses: Family<EncodingLabels, Counter>,
+    body_bytes: Family<EncodingLabels, Counter>,
+    /// Share of the body bytes sent with a content encoding since the last poll. Edges
+    /// without sized responses during a poll keep their last share.
+    compressed_ratio: Family<Labels, Gauge<f64, AtomicU64>>,
+}
+
+impl HttpMetrics {
+    pub(crate) fn from_metadata(metadata: &HashMap<String, String>) -> Self {
+        Self {
+            templates: RouteTemplates::from_metadata(metadata),
+            max_routes: metadata
+                .get("http_max_routes")
+                .and_then(|n| n.parse().ok())
+                .unwrap_or(DEFAULT_HTTP_MAX_ROUTES),
+            ..Default::default()
+        }
+    }
+
+    /// Counts `count` more requests starting with `line` on an edge.
+    pub(crate) fn observe(
+        &mut self,
+        conn: &Connection,
+        line: &[u8],
+        count: u64,
+        symbols: &SymbolTable,
+    ) {
+        let Some((method, path, whole)) = request_line(line) else {
+            return;
+        };
+        let edge = edge(&mut self.edges, conn, symbols);
+        let mut labels = RouteLabels {
+            method: symbols.intern(method),
+            route: symbols.intern(&self.templates.route(path, whole)),
+            edge: edge.labels.clone(),
+        };
+        if !edge.routes.contains(&labels) && edge.routes.len() >= self.max_routes {
+            labels.route = symbols.intern(OTHER_ROUTE);
+        }
+        self.requests.get_or_create(&labels).inc_by(count);
+        edge.routes.insert(labels);
+    }
+
+    /// Counts the responses sent on edges since the last poll, given per content
+    /// encoding, and updates the compressed share of the edges that sent bodies.
+    pub(crate) fn observe_responses(
+        &mut self,
+        responses: &[(Connection, u32, HttpResponseStats)],
+        symbols: &SymbolTable,
+    ) {
+        let mut shares: AHashMap<&Connection, (u64, u64)> = AHashMap::new();
+        for (conn, encoding, stats) in responses {
+            let edge = edge(&mut self.edges, conn, symbols);
+            let labels = EncodingLabels {
+                encoding: symbols.intern(encoding_name(*encoding)),
+                edge: edge.labels.clone(),
+            };
+            edge.encodings.insert(labels.clone());
+            self.responses
+                .get_or_create(&labels)
+                .inc_by(stats.responses);
+            self.body_bytes
+                .get_or_create(&labels)
+                .inc_by(stats.body_bytes);
+            let (compressed, total) = shares.entry(conn).or_default();
+            if *encoding != HTTP_ENCODING_IDENTITY {
+                *compressed += stats.body_bytes;
+            }
+            *total += stats.body_bytes;
+        }
+        for (conn, (compressed, total)) in shares {
+            if total > 0 {
+                self.compressed_ratio
+                    .get_or_create(&self.edges[conn].labels)
+                    .set(compressed as f64 / total as f64);
+            }
+        }
+    }
+
+    /// Removes the series of expired edges.
+    pub(crate) fn expire(&mut self, conns: &[Connection]) {
+        for conn in conns {
+            if let Some(edge) = self.edges.remove(conn) {
+                for labels in edge.routes {
+                    self.requests.remove(&labels);
+                }
+                for labels in edge.encodings {
+                    self.responses.remove(&labels);
+                    self.body_bytes.remove(&labels);
+                }
+                self.compressed_ratio.remove(&edge.labels);
+            }
+        }
+    }
+
+    pub(crate) fn encode(&self, encoder: &mut DescriptorEncoder) -> Result<(), Error> {
+        if self.edges.is_empty() {
+            return Ok(());
+        }
+        let metric_encoder = encoder.encode_descriptor(
+            "http_requests",
+            "HTTP requests received by servers, by method and route",
+            None,
+            self.requests.metric_type(),
+        )?;
+        self.requests.encode(metric_encoder)?;
+        let metric_encoder = encoder.encode_descriptor(
+            "http_responses",
+            "HTTP responses sent by servers, by content encoding",
+            None,
+            self.responses.metric_type(),
+        )?;
+        self.responses.encode(metric_encoder)?;
+        let metric_encoder = encoder.encode_descriptor(
+            "http_response_body_bytes",
+            "bytes of the HTTP response bodies sent by servers, as given by their Content-Length",
+            None,
+            self.body_bytes.metric_type(),
+        )?;
+        self.body_bytes.encode(metric_encoder)?;
+        let metric_encoder = encoder.encode_descriptor(
+            "http_response_compressed_ratio",
+            "share of the HTTP response body bytes recently sent by servers with a content encoding",
+            None,
+            self.compressed_ratio.metric_type(),
+        )?;
+        self.compressed_ratio.encode(metric_encoder)?;
+        Ok(())
+    }
+}
+
+fn edge<'a>(
+    edges: &'a mut AHashMap<Connection, HttpEdge>,
+    conn: &Connection,
+    symbols: &SymbolTable,
+) -> &'a mut HttpEdge {
+    edges.entry(conn.clone()).or_insert_with(|| HttpEdge {
+        labels: Labels::new(conn, symbols),
+        routes: AHashSet::new(),
+        encodings: AHashSet::new(),
+    })
+}
+
+fn encoding_name(encoding: u32) -> &'static str {
+    match encoding {
+        HTTP_ENCODING_IDENTITY => "identity",
+        HTTP_ENCODING_GZIP => "gzip",
+        HTTP_ENCODING_DEFLATE => "deflate",
+        HTTP_ENCODING_BR => "br",
+        HTTP_ENCODING_ZSTD => "zstd",
+        _ => "other",
+    }
+}
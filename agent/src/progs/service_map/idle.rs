@@ -0,0 +1,82 @@
+use ahash::AHashSet;
+use anyhow::Error;
+use conn_tracer_common::IdleStats;
+use prometheus_client::encoding::{DescriptorEncoder, EncodeMetric};
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::histogram::{exponential_buckets, Histogram};
+use prometheus_client::registry::Unit;
+
+use crate::managers::symbol::SymbolTable;
+use crate::progs::service_map::labels::Labels;
+use crate::progs::service_map::program::Connection;
+
+/// How long client connections sit idle between their requests, per edge. Periods
+/// close to the keep-alive timeout of the servers, or to the idle timeout of a load
+/// balancer in between, tell requests racing the connection being closed under them,
+/// which clients see as intermittent resets and 502s.
+#[derive(Debug)]
+pub(crate) struct IdleMetrics {
+    edges: AHashSet<Connection>,
+    periods: Family<Labels, Histogram, fn() -> Histogram>,
+}
+
+impl Default for IdleMetrics {
+    fn default() -> Self {
+        Self {
+            edges: AHashSet::new(),
+            periods: Family::new_with_constructor(new_idle_histogram),
+        }
+    }
+}
+
+impl IdleMetrics {
+    /// Counts the idle periods of edges since the last poll, given per bucket of the
+    /// tracer. Its buckets are those of the histogram, so the periods of each are
+    /// observed at their mean, which keeps both the bucket counts and the sum exact.
+    pub(crate) fn observe(&mut self, periods: &[(Connection, IdleStats)], symbols: &SymbolTable) {
+        for (conn, stats) in periods {
+            if stats.periods == 0 {
+                continue;
+            }
+            self.edges.insert(conn.clone());
+            let histogram = self.periods.get_or_create(&Labels::new(conn, symbols));
+            let mean = stats.total_ns as f64 / stats.periods as f64 / 1e9;
+            for _ in 0..stats.periods {
+                histogram.observe(mean);
+            }
+        }
+    }
+
+    /// Removes the series of expired edges.
+    pub(crate) fn expire(&mut self, conns: &[Connection], symbols: &SymbolTable) {
+        for conn in conns {
+            if self.edges.remove(conn) {
+                self.periods.remove(&Labels::new(conn, symbols));
+            }
+        }
+    }
+
+    pub(crate) fn encode(&self, encoder: &mut DescriptorEncoder) -> Result<(), Error> {
+        if self.edges.is_empty() {
+            return Ok(());
+        }
+        let metric_encoder = encoder.encode_descriptor(
+            "connection_idle",
+            "time client connections sat idle between two of their requests",
+            Some(&Unit::Seconds),
+            self.periods.metric_type(),
+        )?;
+        self.periods.encode(metric_encoder)?;
+        Ok(())
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.edges.clear();
+        self.periods.clear();
+    }
+}
+
+fn new_idle_histogram() -> Histogram {
+    // 1ms up to roughly 17 minutes, the buckets of the tracer
+    Histogram::new(exponential_buckets(0.001, 2.0, 21))
+}
@@ -0,0 +1,158 @@
+use prometheus_client::encoding::EncodeLabelSet;
+
+use crate::common::utils::fnv_hash;
+use crate::managers::cache::Workload;
+use crate::managers::symbol::{Symbol, SymbolTable};
+use crate::progs::service_map::program::{protocol_name, Connection};
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub(crate) struct Labels {
+    conn_id: Symbol,
+    client_id: Symbol,
+    client_name: Symbol,
+    client_namespace: Symbol,
+    client_kind: Symbol,
+    server_id: Symbol,
+    server_name: Symbol,
+    server_namespace: Symbol,
+    server_kind: Symbol,
+    server_port: Symbol,
+    role: Symbol,
+    protocol: Symbol,
+    loopback: Symbol,
+    /// The configured pod labels of both workloads, named after their side.
+    #[prometheus(flatten)]
+    workload_labels: Vec<(Symbol, Symbol)>,
+}
+
+impl Labels {
+    /// Builds the label set of an edge. Workload labels reuse the symbols held by the
+    /// cache manager and derived values are interned into the same table.
+    pub(crate) fn new(conn: &Connection, symbols: &SymbolTable) -> Self {
+        let client = &conn.client;
+        let server = &conn.server;
+        Labels {
+            conn_id: symbols.intern(&format!(
+                "{:x}",
+                fnv_hash(&format!(
+                    "{}{}{}{}",
+                    client.name, client.namespace, server.name, server.namespace
+                ))
+            )),
+            client_id: symbols.intern(&format!(
+                "{:x}",
+                fnv_hash(&format!("{}{}", client.name, client.namespace))
+            )),
+            client_name: client.name.clone(),
+            client_namespace: client.namespace.clone(),
+            client_kind: client.kind.clone(),
+            server_id: symbols.intern(&format!(
+                "{:x}",
+                fnv_hash(&format!("{}{}", server.name, server.namespace))
+            )),
+            server_name: server.name.clone(),
+            server_namespace: server.namespace.clone(),
+            server_kind: server.kind.clone(),
+            server_port: symbols.intern(&conn.server_port.to_string()),
+            role: symbols.intern(&conn.role.to_string()),
+            protocol: symbols.intern(protocol_name(conn.protocol)),
+            loopback: symbols.intern(if conn.loopback { "true" } else { "false" }),
+            workload_labels: workload_labels("client", client, symbols)
+                .chain(workload_labels("server", server, symbols))
+                .collect(),
+        }
+    }
+
+    /// Returns the labels as name and value pairs, for exporters that don't go through
+    /// the prometheus-client encoder.
+    pub(crate) fn pairs(&self) -> Vec<(String, String)> {
+        [
+            ("conn_id", &self.conn_id),
+            ("client_id", &self.client_id),
+            ("client_name", &self.client_name),
+            ("client_namespace", &self.client_namespace),
+            ("client_kind", &self.client_kind),
+            ("server_id", &self.server_id),
+            ("server_name", &self.server_name),
+            ("server_namespace", &self.server_namespace),
+            ("server_kind", &self.server_kind),
+            ("server_port", &self.server_port),
+            ("role", &self.role),
+            ("protocol", &self.protocol),
+            ("loopback", &self.loopback),
+        ]
+        .into_iter()
+        .chain(
+            self.workload_labels
+                .iter()
+                .map(|(name, value)| (&**name, value)),
+        )
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect()
+    }
+}
+
+/// Names the pod labels of a workload like kube-state-metrics does, prefixed with the
+/// side of the edge: `app.kubernetes.io/version` on the client becomes
+/// `client_label_app_kubernetes_io_version`.
+fn workload_labels<'a>(
+    side: &'a str,
+    workload: &'a Workload,
+    symbols: &'a SymbolTable,
+) -> impl Iterator<Item = (Symbol, Symbol)> + 'a {
+    workload.labels.iter().map(move |(key, value)| {
+        let key: String = key
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        (
+            symbols.intern(&format!("{}_label_{}", side, key)),
+            value.clone(),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use conn_tracer_common::{CONNECTION_ROLE_CLIENT, PROTOCOL_HTTP};
+
+    use super::*;
+
+    fn workload(symbols: &SymbolTable, name: &str) -> Arc<Workload> {
+        Arc::new(Workload {
+            name: symbols.intern(name),
+            namespace: symbols.intern("default"),
+            kind: symbols.intern("Deployment"),
+            labels: vec![(
+                symbols.intern("app.kubernetes.io/version"),
+                symbols.intern("v1"),
+            )],
+        })
+    }
+
+    #[test]
+    fn test_labels_share_interned_values() {
+        let symbols = SymbolTable::default();
+        let conn = Connection {
+            client: workload(&symbols, "frontend"),
+            server: workload(&symbols, "backend"),
+            role: CONNECTION_ROLE_CLIENT,
+            server_port: 8080,
+            protocol: PROTOCOL_HTTP,
+            loopback: false,
+        };
+        let labels = Labels::new(&conn, &symbols);
+        // Symbols compare by pointer, so equal labels were built from the same values.
+        assert_eq!(labels, Labels::new(&conn, &symbols));
+        assert_eq!(labels.protocol, symbols.intern("http"));
+
+        let pairs = labels.pairs();
+        assert!(pairs.contains(&("server_port".to_string(), "8080".to_string())));
+        assert!(pairs.contains(&(
+            "server_label_app_kubernetes_io_version".to_string(),
+            "v1".to_string()
+        )));
+    }
+}
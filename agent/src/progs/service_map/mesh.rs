@@ -0,0 +1,104 @@
+use std::collections::HashMap;
+use std::fs;
+use std::net::Ipv4Addr;
+
+use ahash::{AHashMap, AHashSet};
+use log::warn;
+
+use conn_tracer_common::{ConnectionKey, ConnectionStats};
+
+/// Address Istio's inbound sidecar connects to the application from.
+const ISTIO_INBOUND_PASSTHROUGH: Ipv4Addr = Ipv4Addr::new(127, 0, 0, 6);
+/// Ports the Istio and Linkerd sidecars redirect traffic to or serve on.
+const DEFAULT_SIDECAR_PORTS: &[u32] = &[
+    15001, 15006, 15008, 15020, 15021, 15090, // Istio
+    4140, 4143, 4191, // Linkerd
+];
+const DEFAULT_SIDECAR_PROCESSES: &[&str] = &["envoy", "linkerd2-proxy"];
+
+/// Settings of the mesh-aware mode, read from the `mesh_mode`, `mesh_sidecar_ports` and
+/// `mesh_sidecar_processes` metadata keys. In a mesh, a call from one pod to another
+/// shows as the application connecting to its sidecar, that sidecar connecting to the
+/// remote one, and the remote sidecar connecting to the remote application. With
+/// `mesh_mode` set to `collapse`, only the sockets of the applications are kept, so that
+/// the call is one edge between the two services. `raw`, the default, keeps every hop.
+#[derive(Debug, Clone)]
+pub(crate) struct MeshConfig {
+    ports: AHashSet<u32>,
+    processes: Vec<String>,
+}
+
+impl MeshConfig {
+    pub(crate) fn from_metadata(metadata: &HashMap<String, String>) -> Option<Self> {
+        match metadata.get("mesh_mode").map(String::as_str) {
+            None | Some("raw") => return None,
+            Some("collapse") => {}
+            Some(other) => {
+                warn!("Unknown mesh_mode {:?}, keeping sidecar hops", other);
+                return None;
+            }
+        }
+        let ports = match metadata.get("mesh_sidecar_ports") {
+            Some(ports) => ports
+                .split(',')
+                .filter_map(|port| port.trim().parse().ok())
+                .collect(),
+            None => DEFAULT_SIDECAR_PORTS.iter().copied().collect(),
+        };
+        let processes = match metadata.get("mesh_sidecar_processes") {
+            Some(processes) => processes
+                .split(',')
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .map(String::from)
+                .collect(),
+            None => DEFAULT_SIDECAR_PROCESSES
+                .iter()
+                .map(|name| name.to_string())
+                .collect(),
+        };
+        Some(Self { ports, processes })
+    }
+
+    /// Returns the classifier of one poll, which reads the name of each process once.
+    pub(crate) fn hops(&self) -> SidecarHops<'_> {
+        SidecarHops {
+            config: self,
+            sidecars: AHashMap::new(),
+        }
+    }
+}
+
+pub(crate) struct SidecarHops<'a> {
+    config: &'a MeshConfig,
+    /// Whether each process seen so far is a sidecar.
+    sidecars: AHashMap<u32, bool>,
+}
+
+impl SidecarHops<'_> {
+    /// Whether a socket is a hop through a sidecar rather than a connection of the
+    /// application: it is owned by a sidecar, either of its ports is a sidecar port, or
+    /// it comes from the address the inbound sidecar connects to applications from.
+    /// The exception is the inbound sidecar accepting a call redirected within its own
+    /// pod, which is where the server side of the call is seen once its original
+    /// destination is known.
+    pub(crate) fn is_hop(&mut self, key: &ConnectionKey, stats: &ConnectionStats) -> bool {
+        if stats
+            .original_dest()
+            .is_some_and(|(addr, _)| addr == key.src_addr)
+        {
+            return false;
+        }
+        if self.config.ports.contains(&key.src_port) || self.config.ports.contains(&key.dest_port) {
+            return true;
+        }
+        if key.src_ip() == ISTIO_INBOUND_PASSTHROUGH || key.dest_ip() == ISTIO_INBOUND_PASSTHROUGH {
+            return true;
+        }
+        let processes = &self.config.processes;
+        *self.sidecars.entry(key.pid).or_insert_with(|| {
+            fs::read_to_string(format!("/proc/{}/comm", key.pid))
+                .is_ok_and(|comm| processes.iter().any(|name| name == comm.trim_end()))
+        })
+    }
+}
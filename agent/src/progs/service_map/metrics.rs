@@ -0,0 +1,515 @@
+use std::collections::HashMap;
+use std::sync::atomic::AtomicU64;
+use std::time::{Duration, Instant};
+
+use ahash::AHashMap;
+use anyhow::Error;
+use prometheus_client::encoding::{DescriptorEncoder, EncodeMetric};
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::metrics::histogram::{exponential_buckets, Histogram};
+use prometheus_client::registry::Unit;
+
+use crate::common::graph::GraphEdge;
+use crate::common::native_histogram::{NativeHistogram, NativeHistogramSeries};
+use crate::managers::symbol::SymbolTable;
+use crate::progs::service_map::anomaly::{AnomalyConfig, EdgeAnomaly, EdgeDetector};
+use crate::progs::service_map::labels::Labels;
+use crate::progs::service_map::program::{protocol_name, Connection, EdgeStats};
+
+const CONNECTION_DURATION_HELP: &str = "lifetime of closed connections observed";
+/// Name of the connection duration histogram as exposed, with its unit suffix.
+pub(crate) const CONNECTION_DURATION_NAME: &str = "connection_duration_seconds";
+
+#[derive(Debug)]
+struct Edge {
+    labels: Labels,
+    exported: EdgeStats,
+    last_seen: Instant,
+    detector: EdgeDetector,
+}
+
+/// Per-edge metric families kept across scrapes. `update` applies the totals of a
+/// poll as deltas, so a scrape only encodes what is already there. Edges with no open
+/// connections and no new traffic for longer than the TTL are tombstoned and their
+/// series removed. Open connections are exported as a gauge and opened ones as a
+/// counter, whose rate is the rate of new connections. Bytes are exported per
+/// direction, sent and received. TCP handshakes are timed on the client end, from the
+/// SYN sent to the SYN-ACK received, which tells network latency apart from the time
+/// servers take to process requests. Clients are counted a request each time they send
+/// after receiving, and the share of their recent requests made on connections already
+/// used tells pooling clients from those opening a connection per request. When anomaly
+/// detection is enabled, the bytes sent and the open connections of every edge are also
+/// checked against their recent band.
+#[derive(Debug)]
+pub(crate) struct EdgeMetrics {
+    edges: AHashMap<Connection, Edge>,
+    bytes_sent: Family<Labels, Gauge>,
+    bytes_received: Family<Labels, Gauge>,
+    resets: Family<Labels, Counter>,
+    connect_timeouts: Family<Labels, Counter>,
+    active_conns: Family<Labels, Gauge>,
+    opened_conns: Family<Labels, Counter>,
+    requests: Family<Labels, Counter>,
+    /// Share of the requests made since the last poll on connections already used.
+    /// Edges without requests during a poll keep their last share.
+    reuse_ratio: Family<Labels, Gauge<f64, AtomicU64>>,
+    durations: Family<Labels, Histogram, fn() -> Histogram>,
+    native_durations: AHashMap<Labels, NativeHistogram>,
+    handshakes: Family<Labels, Histogram, fn() -> Histogram>,
+    anomaly_config: Option<AnomalyConfig>,
+    throughput_anomalies: Family<Labels, Gauge>,
+    connection_anomalies: Family<Labels, Gauge>,
+}
+
+impl EdgeMetrics {
+    pub(crate) fn new() -> Self {
+        Self {
+            edges: AHashMap::new(),
+            bytes_sent: Family::default(),
+            bytes_received: Family::default(),
+            resets: Family::default(),
+            connect_timeouts: Family::default(),
+            active_conns: Family::default(),
+            opened_conns: Family::default(),
+            requests: Family::default(),
+            reuse_ratio: Family::default(),
+            durations: Family::new_with_constructor(new_duration_histogram),
+            native_durations: AHashMap::new(),
+            handshakes: Family::new_with_constructor(new_handshake_histogram),
+            anomaly_config: None,
+            throughput_anomalies: Family::default(),
+            connection_anomalies: Family::default(),
+        }
+    }
+
+    pub(crate) fn set_anomaly_config(&mut self, config: Option<AnomalyConfig>) {
+        self.anomaly_config = config;
+    }
+
+    /// Applies the totals of a poll and returns the anomalies that started with it.
+    pub(crate) fn update(
+        &mut self,
+        conns: &HashMap<Connection, EdgeStats>,
+        symbols: &SymbolTable,
+        now: Instant,
+    ) -> Vec<EdgeAnomaly> {
+        let mut anomalies = Vec::new();
+        for (conn, stats) in conns.iter() {
+            let edge = Self::edge(&mut self.edges, conn, symbols, now);
+            if stats.active_conns > 0 || stats.total_grew(&edge.exported) {
+                edge.last_seen = now;
+            }
+
+            if let Some(config) = self.anomaly_config.as_ref() {
+                let sent = stats.bytes_sent.saturating_sub(edge.exported.bytes_sent);
+                let started = edge
+                    .detector
+                    .observe(conn, sent, stats.active_conns, config);
+                anomalies.extend(started);
+                self.throughput_anomalies
+                    .get_or_create(&edge.labels)
+                    .set(i64::from(edge.detector.bytes.is_anomalous()));
+                self.connection_anomalies
+                    .get_or_create(&edge.labels)
+                    .set(i64::from(edge.detector.connections.is_anomalous()));
+            }
+
+            self.bytes_sent
+                .get_or_create(&edge.labels)
+                .set(stats.bytes_sent as i64);
+            self.bytes_received
+                .get_or_create(&edge.labels)
+                .set(stats.bytes_received as i64);
+            self.resets
+                .get_or_create(&edge.labels)
+                .inc_by(stats.resets.saturating_sub(edge.exported.resets));
+            self.connect_timeouts.get_or_create(&edge.labels).inc_by(
+                stats
+                    .connect_timeouts
+                    .saturating_sub(edge.exported.connect_timeouts),
+            );
+            self.active_conns
+                .get_or_create(&edge.labels)
+                .set(stats.active_conns as i64);
+            self.opened_conns.get_or_create(&edge.labels).inc_by(
+                stats
+                    .opened_conns
+                    .saturating_sub(edge.exported.opened_conns),
+            );
+
+            let requests = stats.requests.saturating_sub(edge.exported.requests);
+            if requests > 0 {
+                let conns = stats
+                    .requesting_conns
+                    .saturating_sub(edge.exported.requesting_conns);
+                self.reuse_ratio
+                    .get_or_create(&edge.labels)
+                    .set(1.0 - (conns as f64 / requests as f64).min(1.0));
+            }
+            self.requests.get_or_create(&edge.labels).inc_by(requests);
+
+            edge.exported.bytes_sent = stats.bytes_sent;
+            edge.exported.bytes_received = stats.bytes_received;
+            edge.exported.resets = edge.exported.resets.max(stats.resets);
+            edge.exported.connect_timeouts =
+                edge.exported.connect_timeouts.max(stats.connect_timeouts);
+            edge.exported.active_conns = stats.active_conns;
+            edge.exported.opened_conns = edge.exported.opened_conns.max(stats.opened_conns);
+            edge.exported.requests = edge.exported.requests.max(stats.requests);
+            edge.exported.requesting_conns =
+                edge.exported.requesting_conns.max(stats.requesting_conns);
+        }
+        anomalies
+    }
+
+    /// Returns every edge with the totals last applied to it.
+    pub(crate) fn graph_edges(&self) -> Vec<GraphEdge> {
+        self.edges
+            .iter()
+            .map(|(conn, edge)| GraphEdge {
+                client: conn.client.clone(),
+                server: conn.server.clone(),
+                server_port: conn.server_port,
+                protocol: protocol_name(conn.protocol),
+                bytes_sent: edge.exported.bytes_sent,
+                bytes_received: edge.exported.bytes_received,
+                active_conns: edge.exported.active_conns,
+                resets: edge.exported.resets,
+                connect_timeouts: edge.exported.connect_timeouts,
+            })
+            .collect()
+    }
+
+    pub(crate) fn observe_duration(
+        &mut self,
+        conn: &Connection,
+        symbols: &SymbolTable,
+        duration: Duration,
+        now: Instant,
+    ) {
+        let edge = Self::edge(&mut self.edges, conn, symbols, now);
+        edge.last_seen = now;
+        self.durations
+            .get_or_create(&edge.labels)
+            .observe(duration.as_secs_f64());
+        self.native_durations
+            .entry(edge.labels.clone())
+            .or_default()
+            .observe(duration.as_secs_f64());
+    }
+
+    pub(crate) fn observe_handshake(
+        &mut self,
+        conn: &Connection,
+        symbols: &SymbolTable,
+        handshake: Duration,
+        now: Instant,
+    ) {
+        let edge = Self::edge(&mut self.edges, conn, symbols, now);
+        edge.last_seen = now;
+        self.handshakes
+            .get_or_create(&edge.labels)
+            .observe(handshake.as_secs_f64());
+    }
+
+    /// Returns the connection durations as native histograms. They are only exported
+    /// by exporters configured for them, since the text format can't carry them.
+    pub(crate) fn native_histograms(&self) -> Vec<NativeHistogramSeries> {
+        self.native_durations
+            .iter()
+            .map(|(labels, histogram)| NativeHistogramSeries {
+                name: CONNECTION_DURATION_NAME.to_string(),
+                help: CONNECTION_DURATION_HELP.to_string(),
+                labels: labels.pairs(),
+                histogram: histogram.clone(),
+            })
+            .collect()
+    }
+
+    /// Removes edges idle for longer than `ttl` and returns them so that the caller
+    /// can forget their accumulated totals.
+    pub(crate) fn expire(&mut self, ttl: Duration, now: Instant) -> Vec<Connection> {
+        let expired: Vec<Connection> = self
+            .edges
+            .iter()
+            .filter(|(_, edge)| now.duration_since(edge.last_seen) > ttl)
+            .map(|(conn, _)| conn.clone())
+            .collect();
+
+        for conn in expired.iter() {
+            if let Some(edge) = self.edges.remove(conn) {
+                self.bytes_sent.remove(&edge.labels);
+                self.bytes_received.remove(&edge.labels);
+                self.resets.remove(&edge.labels);
+                self.connect_timeouts.remove(&edge.labels);
+                self.active_conns.remove(&edge.labels);
+                self.opened_conns.remove(&edge.labels);
+                self.requests.remove(&edge.labels);
+                self.reuse_ratio.remove(&edge.labels);
+                self.durations.remove(&edge.labels);
+                self.native_durations.remove(&edge.labels);
+                self.handshakes.remove(&edge.labels);
+                self.throughput_anomalies.remove(&edge.labels);
+                self.connection_anomalies.remove(&edge.labels);
+            }
+        }
+        expired
+    }
+
+    pub(crate) fn encode(&self, encoder: &mut DescriptorEncoder) -> Result<(), Error> {
+        let metric_encoder = encoder.encode_descriptor(
+            "connection_observed",
+            "total bytes_sent value of connections observed",
+            Some(&Unit::Bytes),
+            self.bytes_sent.metric_type(),
+        )?;
+        self.bytes_sent.encode(metric_encoder)?;
+
+        let metric_encoder = encoder.encode_descriptor(
+            "connection_received",
+            "total bytes_received value of connections observed",
+            Some(&Unit::Bytes),
+            self.bytes_received.metric_type(),
+        )?;
+        self.bytes_received.encode(metric_encoder)?;
+
+        let metric_encoder = encoder.encode_descriptor(
+            "connection_resets",
+            "total TCP resets sent or received on connections observed",
+            None,
+            self.resets.metric_type(),
+        )?;
+        self.resets.encode(metric_encoder)?;
+
+        let metric_encoder = encoder.encode_descriptor(
+            "connection_connect_timeouts",
+            "total connection attempts that never completed the TCP handshake",
+            None,
+            self.connect_timeouts.metric_type(),
+        )?;
+        self.connect_timeouts.encode(metric_encoder)?;
+
+        let metric_encoder = encoder.encode_descriptor(
+            "connection_active",
+            "connections currently open on an edge",
+            None,
+            self.active_conns.metric_type(),
+        )?;
+        self.active_conns.encode(metric_encoder)?;
+
+        let metric_encoder = encoder.encode_descriptor(
+            "connection_opened",
+            "total connections opened on an edge",
+            None,
+            self.opened_conns.metric_type(),
+        )?;
+        self.opened_conns.encode(metric_encoder)?;
+
+        let metric_encoder = encoder.encode_descriptor(
+            "connection_requests",
+            "total requests made by clients on an edge",
+            None,
+            self.requests.metric_type(),
+        )?;
+        self.requests.encode(metric_encoder)?;
+
+        let metric_encoder = encoder.encode_descriptor(
+            "connection_reuse_ratio",
+            "share of the recent requests on an edge made on connections already used",
+            None,
+            self.reuse_ratio.metric_type(),
+        )?;
+        self.reuse_ratio.encode(metric_encoder)?;
+
+        let metric_encoder = encoder.encode_descriptor(
+            "connection_duration",
+            CONNECTION_DURATION_HELP,
+            Some(&Unit::Seconds),
+            self.durations.metric_type(),
+        )?;
+        self.durations.encode(metric_encoder)?;
+
+        let metric_encoder = encoder.encode_descriptor(
+            "connection_handshake",
+            "time from the SYN sent by a client to its connection being established",
+            Some(&Unit::Seconds),
+            self.handshakes.metric_type(),
+        )?;
+        self.handshakes.encode(metric_encoder)?;
+
+        if self.anomaly_config.is_some() {
+            let metric_encoder = encoder.encode_descriptor(
+                "connection_throughput_anomaly",
+                "whether bytes sent on an edge are outside their recent band",
+                None,
+                self.throughput_anomalies.metric_type(),
+            )?;
+            self.throughput_anomalies.encode(metric_encoder)?;
+
+            let metric_encoder = encoder.encode_descriptor(
+                "connection_count_anomaly",
+                "whether open connections on an edge are outside their recent band",
+                None,
+                self.connection_anomalies.metric_type(),
+            )?;
+            self.connection_anomalies.encode(metric_encoder)?;
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.edges.clear();
+        self.bytes_sent.clear();
+        self.bytes_received.clear();
+        self.resets.clear();
+        self.connect_timeouts.clear();
+        self.active_conns.clear();
+        self.opened_conns.clear();
+        self.requests.clear();
+        self.reuse_ratio.clear();
+        self.durations.clear();
+        self.native_durations.clear();
+        self.handshakes.clear();
+        self.throughput_anomalies.clear();
+        self.connection_anomalies.clear();
+    }
+
+    fn edge<'a>(
+        edges: &'a mut AHashMap<Connection, Edge>,
+        conn: &Connection,
+        symbols: &SymbolTable,
+        now: Instant,
+    ) -> &'a mut Edge {
+        edges.entry(conn.clone()).or_insert_with(|| Edge {
+            labels: Labels::new(conn, symbols),
+            exported: EdgeStats::default(),
+            last_seen: now,
+            detector: EdgeDetector::default(),
+        })
+    }
+}
+
+fn new_duration_histogram() -> Histogram {
+    // 1ms up to roughly 70 minutes
+    Histogram::new(exponential_buckets(0.001, 4.0, 12))
+}
+
+fn new_handshake_histogram() -> Histogram {
+    // 100us up to roughly 3 seconds, past the first SYN retransmission
+    Histogram::new(exponential_buckets(0.0001, 2.0, 16))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use conn_tracer_common::{CONNECTION_ROLE_CLIENT, PROTOCOL_HTTP};
+
+    use super::*;
+    use crate::managers::cache::Workload;
+
+    const TTL: Duration = Duration::from_secs(300);
+
+    fn connection(symbols: &SymbolTable, server: &str) -> Connection {
+        let workload = |name: &str| {
+            Arc::new(Workload {
+                name: symbols.intern(name),
+                namespace: symbols.intern("default"),
+                kind: symbols.intern("Deployment"),
+                labels: Vec::new(),
+            })
+        };
+        Connection {
+            client: workload("frontend"),
+            server: workload(server),
+            role: CONNECTION_ROLE_CLIENT,
+            server_port: 8080,
+            protocol: PROTOCOL_HTTP,
+            loopback: false,
+        }
+    }
+
+    fn stats(bytes_sent: u64, active_conns: u64) -> EdgeStats {
+        EdgeStats {
+            bytes_sent,
+            active_conns,
+            opened_conns: 1,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_idle_edges_expire() {
+        let symbols = SymbolTable::default();
+        let (open, closed) = (
+            connection(&symbols, "backend"),
+            connection(&symbols, "database"),
+        );
+        let mut metrics = EdgeMetrics::new();
+        let start = Instant::now();
+        metrics.update(
+            &HashMap::from([(open.clone(), stats(10, 1)), (closed.clone(), stats(10, 0))]),
+            &symbols,
+            start,
+        );
+
+        // Past the TTL, the edge with an open connection is kept, and so is the one
+        // which still sees traffic.
+        let later = start + TTL + Duration::from_secs(1);
+        metrics.update(
+            &HashMap::from([(open.clone(), stats(10, 1)), (closed.clone(), stats(20, 0))]),
+            &symbols,
+            later,
+        );
+        assert!(metrics.expire(TTL, later).is_empty());
+
+        let idle = later + TTL + Duration::from_secs(1);
+        metrics.update(
+            &HashMap::from([(open.clone(), stats(10, 1)), (closed.clone(), stats(20, 0))]),
+            &symbols,
+            idle,
+        );
+        assert_eq!(metrics.expire(TTL, idle), [closed]);
+        let edges = metrics.graph_edges();
+        assert_eq!(edges.len(), 1);
+        assert_eq!(edges[0].server.name.as_str(), "backend");
+    }
+
+    #[test]
+    fn test_counters_are_applied_as_deltas() {
+        let symbols = SymbolTable::default();
+        let conn = connection(&symbols, "backend");
+        let mut metrics = EdgeMetrics::new();
+        let now = Instant::now();
+        let opened = |metrics: &EdgeMetrics| {
+            let labels = &metrics.edges[&conn].labels;
+            metrics.opened_conns.get_or_create(labels).get()
+        };
+
+        metrics.update(
+            &HashMap::from([(conn.clone(), stats(10, 1))]),
+            &symbols,
+            now,
+        );
+        metrics.update(
+            &HashMap::from([(conn.clone(), stats(10, 1))]),
+            &symbols,
+            now,
+        );
+        assert_eq!(opened(&metrics), 1);
+        let mut more = stats(30, 2);
+        more.opened_conns = 3;
+        metrics.update(&HashMap::from([(conn.clone(), more)]), &symbols, now);
+        assert_eq!(opened(&metrics), 3);
+        // Totals going down, as closed connections are forgotten, don't decrease it.
+        metrics.update(
+            &HashMap::from([(conn.clone(), stats(30, 0))]),
+            &symbols,
+            now,
+        );
+        assert_eq!(opened(&metrics), 3);
+    }
+}
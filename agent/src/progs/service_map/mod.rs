@@ -1 +1,19 @@
+pub(crate) mod aggregation;
+pub(crate) mod anomaly;
+pub(crate) mod dependencies;
+pub(crate) mod direction;
+pub(crate) mod events;
+pub(crate) mod http;
+pub(crate) mod idle;
+pub(crate) mod labels;
+pub(crate) mod mesh;
+pub(crate) mod metrics;
+pub(crate) mod past;
 pub(crate) mod program;
+pub(crate) mod quarantine;
+pub(crate) mod quic;
+pub(crate) mod resolution;
+pub(crate) mod slo;
+pub(crate) mod snapshots;
+pub(crate) mod split;
+pub(crate) mod tls;
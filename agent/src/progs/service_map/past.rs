@@ -0,0 +1,97 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::progs::service_map::program::{Connection, EdgeStats};
+
+/// Totals of the connections that closed, per edge, which their edge keeps adding to
+/// those of its open connections. They are kept until the edge expires or, with
+/// `past_conn_ttl` set, until no connection of the edge closed for that long. With
+/// `past_conn_half_life` set, their bytes also halve over each half-life, so that the
+/// bytes of an edge follow its recent traffic rather than all it ever carried. Counts
+/// are never decayed, since they are exported as counters.
+#[derive(Debug, Default)]
+pub(crate) struct PastConnections {
+    edges: HashMap<Connection, Retired>,
+}
+
+#[derive(Debug)]
+struct Retired {
+    stats: EdgeStats,
+    /// When a connection of the edge last closed.
+    last_closed: Instant,
+    /// When the bytes were last decayed.
+    decayed: Instant,
+}
+
+impl PastConnections {
+    /// Adds the totals of a connection that closed to those of its edge.
+    pub(crate) fn record(&mut self, conn: &Connection, stats: &EdgeStats, now: Instant) {
+        let retired = self.edges.entry(conn.clone()).or_insert_with(|| Retired {
+            stats: EdgeStats::default(),
+            last_closed: now,
+            decayed: now,
+        });
+        retired.stats.merge(stats);
+        retired.last_closed = now;
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&Connection, &EdgeStats)> {
+        self.edges
+            .iter()
+            .map(|(conn, retired)| (conn, &retired.stats))
+    }
+
+    pub(crate) fn remove(&mut self, conn: &Connection) {
+        self.edges.remove(conn);
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.edges.clear();
+    }
+
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.edges.len()
+    }
+
+    /// Forgets the totals of edges none of whose connections closed within `ttl`, and
+    /// decays the bytes of the others over `half_life`.
+    pub(crate) fn age(&mut self, ttl: Option<Duration>, half_life: Option<Duration>, now: Instant) {
+        if let Some(ttl) = ttl {
+            self.edges
+                .retain(|_, retired| now.duration_since(retired.last_closed) <= ttl);
+        }
+        let Some(half_life) = half_life else {
+            return;
+        };
+        for retired in self.edges.values_mut() {
+            let elapsed = now.duration_since(retired.decayed);
+            let factor = 0.5f64.powf(elapsed.as_secs_f64() / half_life.as_secs_f64());
+            retired.stats.bytes_sent = (retired.stats.bytes_sent as f64 * factor).round() as u64;
+            retired.stats.bytes_received =
+                (retired.stats.bytes_received as f64 * factor).round() as u64;
+            retired.decayed = now;
+        }
+    }
+}
+
+/// How long the totals of closed connections are kept after the last one of their
+/// edge closed, as set by the `past_conn_ttl` metadata. Unset or 0 keeps them until
+/// their edge expires.
+pub(crate) fn past_conn_ttl(metadata: &HashMap<String, String>) -> Option<Duration> {
+    seconds(metadata, "past_conn_ttl")
+}
+
+/// Half-life of the bytes of closed connections, as set by the `past_conn_half_life`
+/// metadata. Unset or 0 doesn't decay them.
+pub(crate) fn past_conn_half_life(metadata: &HashMap<String, String>) -> Option<Duration> {
+    seconds(metadata, "past_conn_half_life")
+}
+
+fn seconds(metadata: &HashMap<String, String>, key: &str) -> Option<Duration> {
+    metadata
+        .get(key)
+        .and_then(|s| s.parse::<u64>().ok())
+        .filter(|&s| s > 0)
+        .map(Duration::from_secs)
+}
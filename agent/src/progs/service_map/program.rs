@@ -1,41 +1,136 @@
+use std::any::type_name;
 use std::cmp::PartialEq;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Debug;
+use std::hash::Hash;
 use std::net::Ipv4Addr;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+use ahash::{AHashMap, AHashSet};
 use anyhow::Error;
 use async_trait::async_trait;
-use aya::maps::{HashMap as AyaHashMap, Map, MapData};
+use aya::maps::{Array, HashMap as AyaHashMap, Map, MapData};
+use aya::Pod;
 use bpfman_lib::directories::RTDIR_FS_MAPS;
-use log::debug;
+use log::{debug, warn};
 use parking_lot::RwLock;
-use prometheus_client::encoding::{DescriptorEncoder, EncodeLabelSet, EncodeMetric};
-use prometheus_client::metrics::family::Family;
-use prometheus_client::metrics::gauge::Gauge;
-use prometheus_client::registry::Unit;
+use prometheus_client::encoding::DescriptorEncoder;
 use tokio::sync::broadcast;
-use tokio::time;
 
-use agent_api::v1::{BytecodeLocation, ProgramInfo};
+use agent_api::v1::{BytecodeLocation, MapDump, MapEntry, ProgramInfo, ServiceMapSnapshot};
 use agent_api::{ProgramState, ProgramType};
+#[cfg(test)]
+use conn_tracer_common::{AggregateKey, AggregateStats};
 use conn_tracer_common::{
-    ConnectionKey, ConnectionStats, CONNECTION_ROLE_CLIENT, CONNECTION_ROLE_SERVER,
-    CONNECTION_ROLE_UNKNOWN,
+    ConnectionKey, ConnectionStats, HttpRequestKey, HttpResponseKey, HttpResponseStats, IdleKey,
+    IdleStats, QuicInitial, TlsHandshake, CONNECTION_ROLE_CLIENT, CONNECTION_ROLE_SERVER,
+    CONNECTION_ROLE_UNKNOWN, IDENTITY_UNKNOWN, PROTOCOL_GRPC, PROTOCOL_HTTP, PROTOCOL_QUIC,
+    PROTOCOL_REDIS, PROTOCOL_TLS, TLS_CLIENT_HELLO,
 };
 
-use crate::common::constants::DEFAULT_INTERVAL;
-use crate::common::utils::fnv_hash;
+use crate::common::constants::{
+    DEFAULT_DEPENDENCY_ABSENT_INTERVALS, DEFAULT_EDGE_TTL, DEFAULT_HTTP_MAX_ROUTES,
+    DEFAULT_QUIC_IDLE_TIMEOUT, DEFAULT_SNAPSHOT_COMPACTION, DEFAULT_SNAPSHOT_RETENTION,
+    DEFAULT_SNAPSHOT_WINDOW, DEFAULT_UNKNOWN_IP_RETRIES,
+};
+use crate::common::errors::AgentError;
+use crate::common::graph::GraphEdge;
+use crate::common::maps::MapAccess;
+use crate::common::native_histogram::NativeHistogramSeries;
+use crate::common::utils::pod_bytes;
 use crate::managers::cache::{CacheManager, Workload};
-use crate::progs::types::{Program, ShutdownSignal};
+use crate::managers::events::EventsManager;
+use crate::managers::identity::IdentityMap;
+use crate::managers::process::PEER_NAMESPACE;
+use crate::managers::symbol::Symbol;
+use crate::progs::schema::{MetadataKey, MetadataSchema, MetadataType};
+use crate::progs::service_map::aggregation::{kernel_aggregation, Aggregation};
+use crate::progs::service_map::anomaly::{publish_anomaly, AnomalyConfig};
+use crate::progs::service_map::dependencies::{absent_intervals, DependencyTracker};
+use crate::progs::service_map::direction::{with_role, MirroredEnds};
+use crate::progs::service_map::http::HttpMetrics;
+use crate::progs::service_map::idle::IdleMetrics;
+use crate::progs::service_map::mesh::MeshConfig;
+use crate::progs::service_map::metrics::EdgeMetrics;
+use crate::progs::service_map::past::{past_conn_half_life, past_conn_ttl, PastConnections};
+use crate::progs::service_map::quarantine::{unknown_ip_retries, Quarantine};
+use crate::progs::service_map::quic::{self, QuicFlows};
+use crate::progs::service_map::resolution::Resolutions;
+use crate::progs::service_map::slo::{publish_fast_burn, validate_slo, SloSet, SLO_PREFIX};
+use crate::progs::service_map::snapshots::{SnapshotConfig, SnapshotRing};
+use crate::progs::service_map::split::TrafficSplit;
+use crate::progs::service_map::tls::{TlsMetrics, TlsSession};
+use crate::progs::types::{Flow, Program, ShutdownSignal, SnapshotQuery};
+
+/// Closed connections kept for flow exporters, which may take them late or never.
+const MAX_CLOSED_FLOWS: usize = 4096;
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub(crate) struct Connection {
-    client: Arc<Workload>,
-    server: Arc<Workload>,
-    role: u32,
-    server_port: u32,
+    pub(crate) client: Arc<Workload>,
+    pub(crate) server: Arc<Workload>,
+    pub(crate) role: u32,
+    pub(crate) server_port: u32,
+    pub(crate) protocol: u32,
+    /// Set for traffic that never left the host or pod, e.g. to a sidecar proxy over
+    /// localhost. Such connections are self-edges of their workload.
+    pub(crate) loopback: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct EdgeStats {
+    pub(crate) bytes_sent: u64,
+    pub(crate) bytes_received: u64,
+    pub(crate) resets: u64,
+    pub(crate) connect_timeouts: u64,
+    pub(crate) active_conns: u64,
+    /// Connections seen on the edge, open or since closed. Doesn't decrease as they
+    /// close, since closed connections are kept in the past connections.
+    pub(crate) opened_conns: u64,
+    /// Requests made by the clients of the edge, and the connections they made them on.
+    pub(crate) requests: u64,
+    pub(crate) requesting_conns: u64,
+}
+
+impl EdgeStats {
+    pub(crate) fn merge(&mut self, other: &EdgeStats) {
+        self.bytes_sent += other.bytes_sent;
+        self.bytes_received += other.bytes_received;
+        self.resets += other.resets;
+        self.connect_timeouts += other.connect_timeouts;
+        self.active_conns += other.active_conns;
+        self.opened_conns += other.opened_conns;
+        self.requests += other.requests;
+        self.requesting_conns += other.requesting_conns;
+    }
+
+    /// Whether any total grew past `other`. Totals going down, as those of closed
+    /// connections decay or are forgotten, don't count.
+    pub(crate) fn total_grew(&self, other: &EdgeStats) -> bool {
+        self.bytes_sent > other.bytes_sent
+            || self.bytes_received > other.bytes_received
+            || self.resets > other.resets
+            || self.connect_timeouts > other.connect_timeouts
+            || self.opened_conns > other.opened_conns
+            || self.requests > other.requests
+    }
+}
+
+impl From<&ConnectionStats> for EdgeStats {
+    fn from(stats: &ConnectionStats) -> Self {
+        Self {
+            bytes_sent: stats.bytes_sent,
+            bytes_received: stats.bytes_received,
+            resets: stats.resets,
+            connect_timeouts: stats.connect_timeouts,
+            active_conns: u64::from(stats.is_active == 1),
+            opened_conns: 1,
+            requests: stats.requests,
+            requesting_conns: u64::from(stats.requests > 0),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -45,9 +140,53 @@ struct Inner {
     program_state: ProgramState,
     ebpf_maps: HashMap<String, u32>,
     metadata: HashMap<String, String>,
-    current_conns_map: Option<AyaHashMap<MapData, ConnectionKey, ConnectionStats>>,
-    past_conns_map: HashMap<Connection, u64>,
+    current_conns_map: Option<Box<dyn MapAccess<ConnectionKey, ConnectionStats>>>,
+    /// Missing with tracers that don't capture TLS handshakes.
+    tls_handshakes_map: Option<Box<dyn MapAccess<ConnectionKey, TlsHandshake>>>,
+    /// ClientHellos sent by clients, missing like the TLS handshakes map.
+    tls_client_hellos_map: Option<Box<dyn MapAccess<ConnectionKey, TlsHandshake>>>,
+    /// Requests counted by HTTP server ends, missing like the TLS handshakes map.
+    http_requests_map: Option<Box<dyn MapAccess<HttpRequestKey, u64>>>,
+    /// Count of each entry of the HTTP requests map when last polled.
+    http_counts: AHashMap<HttpRequestKey, u64>,
+    /// Responses sent by HTTP server ends, missing like the TLS handshakes map.
+    http_responses_map: Option<Box<dyn MapAccess<HttpResponseKey, HttpResponseStats>>>,
+    /// Totals of each entry of the HTTP responses map when last polled.
+    http_response_totals: AHashMap<HttpResponseKey, HttpResponseStats>,
+    /// Idle periods of client connections, missing like the TLS handshakes map.
+    idle_periods_map: Option<Box<dyn MapAccess<IdleKey, IdleStats>>>,
+    /// Totals of each entry of the idle periods map when last polled.
+    idle_totals: AHashMap<IdleKey, IdleStats>,
+    /// First datagrams sent by QUIC clients, missing like the TLS handshakes map.
+    quic_initials_map: Option<Box<dyn MapAccess<ConnectionKey, QuicInitial>>>,
+    /// Where identities are pushed down, missing like the TLS handshakes map.
+    identity_map: Option<IdentityMap>,
+    /// Set in the aggregation mode of the tracer.
+    aggregation: Option<Aggregation>,
+    past_conns: PastConnections,
+    edge_metrics: EdgeMetrics,
+    /// Set when sidecar hops are collapsed.
+    mesh: Option<MeshConfig>,
+    slos: SloSet,
+    dependencies: DependencyTracker,
+    snapshots: SnapshotRing,
+    split: TrafficSplit,
+    tls: TlsMetrics,
+    http: HttpMetrics,
+    idle: IdleMetrics,
+    quic: QuicFlows,
+    /// Connections whose workloads are not resolved yet.
+    quarantine: Quarantine,
+    mirrored: MirroredEnds,
+    /// Open connections whose handshake was already observed.
+    handshakes: AHashSet<ConnectionKey>,
+    /// Server name (SNI) each open client connection asked for in its ClientHello, over
+    /// TLS or QUIC.
+    server_names: AHashMap<ConnectionKey, Symbol>,
+    /// Connections closed since flows were last taken, oldest first.
+    closed_flows: VecDeque<Flow>,
     cache_mgr: Option<CacheManager>,
+    events_mgr: Option<EventsManager>,
 }
 
 impl Inner {
@@ -59,8 +198,35 @@ impl Inner {
             ebpf_maps: HashMap::new(),
             metadata: HashMap::new(),
             current_conns_map: None,
-            past_conns_map: HashMap::new(),
+            tls_handshakes_map: None,
+            tls_client_hellos_map: None,
+            http_requests_map: None,
+            http_counts: AHashMap::new(),
+            http_responses_map: None,
+            http_response_totals: AHashMap::new(),
+            idle_periods_map: None,
+            idle_totals: AHashMap::new(),
+            quic_initials_map: None,
+            identity_map: None,
+            aggregation: None,
+            past_conns: PastConnections::default(),
+            edge_metrics: EdgeMetrics::new(),
+            mesh: None,
+            slos: SloSet::default(),
+            dependencies: DependencyTracker::default(),
+            snapshots: SnapshotRing::default(),
+            split: TrafficSplit::default(),
+            tls: TlsMetrics::default(),
+            http: HttpMetrics::default(),
+            idle: IdleMetrics::default(),
+            quic: QuicFlows::default(),
+            quarantine: Quarantine::default(),
+            mirrored: MirroredEnds::default(),
+            handshakes: AHashSet::new(),
+            server_names: AHashMap::new(),
+            closed_flows: VecDeque::new(),
             cache_mgr: None,
+            events_mgr: None,
         }
     }
 }
@@ -68,104 +234,674 @@ impl Inner {
 #[derive(Debug)]
 pub struct ServiceMap {
     inner: Arc<RwLock<Inner>>,
+    resolutions: Resolutions,
 }
 
 impl ServiceMap {
     pub fn new() -> Self {
         Self {
             inner: Arc::new(RwLock::new(Inner::new())),
+            resolutions: Resolutions::default(),
         }
     }
 
+    /// Creates a service map polling `conns` instead of the map pinned by bpfman.
+    #[cfg(any(test, feature = "bench"))]
+    pub(crate) fn with_connections(
+        conns: Box<dyn MapAccess<ConnectionKey, ConnectionStats>>,
+        cache_mgr: CacheManager,
+        metadata: HashMap<String, String>,
+    ) -> Self {
+        let service_map = Self::new();
+        {
+            let mut inner = service_map.inner.write();
+            inner.current_conns_map = Some(conns);
+            inner.cache_mgr = Some(cache_mgr);
+            inner.mesh = MeshConfig::from_metadata(&metadata);
+            inner.split = TrafficSplit::from_metadata(&metadata);
+            inner.http = HttpMetrics::from_metadata(&metadata);
+            inner.metadata = metadata;
+        }
+        service_map
+    }
+
+    /// Makes a service map created with [`Self::with_connections`] push identities down
+    /// to `identities`, and read `aggregates` as in the aggregation mode of the tracer.
+    #[cfg(test)]
+    pub(crate) fn with_aggregation(
+        self,
+        identities: Box<dyn MapAccess<u32, u32>>,
+        aggregates: Box<dyn MapAccess<AggregateKey, AggregateStats>>,
+    ) -> Self {
+        {
+            let mut inner = self.inner.write();
+            inner.identity_map = Some(IdentityMap::new(identities));
+            inner.aggregation = Some(Aggregation::new(aggregates));
+        }
+        self
+    }
+
     async fn reset(&self) {
+        self.resolutions.clear();
         let mut inner = self.inner.write();
+        if inner.aggregation.take().is_some() {
+            if let Err(e) = set_aggregation(&inner.ebpf_maps, false) {
+                warn!("Failed to turn off kernel aggregation: {:?}", e);
+            }
+        }
         inner.current_conns_map = None;
-        inner.past_conns_map.clear();
+        inner.tls_handshakes_map = None;
+        inner.tls_client_hellos_map = None;
+        inner.http_requests_map = None;
+        inner.http_counts.clear();
+        inner.http_responses_map = None;
+        inner.http_response_totals.clear();
+        inner.idle_periods_map = None;
+        inner.idle_totals.clear();
+        inner.quic_initials_map = None;
+        inner.identity_map = None;
+        inner.past_conns.clear();
+        inner.edge_metrics.clear();
+        inner.mesh = None;
+        inner.slos = SloSet::default();
+        inner.dependencies = DependencyTracker::default();
+        inner.snapshots = SnapshotRing::default();
+        inner.split = TrafficSplit::default();
+        inner.tls.clear();
+        inner.http = HttpMetrics::default();
+        inner.idle.clear();
+        inner.quic = QuicFlows::default();
+        inner.quarantine = Quarantine::default();
+        inner.mirrored = MirroredEnds::default();
+        inner.handshakes.clear();
+        inner.server_names.clear();
+        inner.closed_flows.clear();
         inner.metadata.clear();
         inner.ebpf_maps.clear();
     }
 
-    fn poll(&self) -> Result<HashMap<Connection, u64>, Error> {
+    fn poll_connections(&self) -> Result<(), Error> {
         let inner = self.inner.read();
         let tcp_conns_map = inner
             .current_conns_map
             .as_ref()
             .ok_or(Error::msg("No current connections map"))?;
-        let past_conns_map = inner.past_conns_map.clone();
         let cache_mgr = inner
             .cache_mgr
             .as_ref()
             .ok_or(Error::msg("No cache manager"))?
             .clone();
 
+        let include_loopback = include_loopback(&inner.metadata);
+        let retries = unknown_ip_retries(&inner.metadata);
+        let quic_timeout = quic_idle_timeout(&inner.metadata);
+        let now = Instant::now();
+        let mut sidecar_hops = inner.mesh.as_ref().map(MeshConfig::hops);
         let mut keys_to_remove = Vec::new();
-        let mut current_conns: HashMap<Connection, u64> = HashMap::new();
+        let mut current_conns: HashMap<Connection, EdgeStats> = HashMap::new();
+        let misses = cache_mgr.health.misses();
+        let mut unresolved = AHashMap::new();
+
+        let entries = tcp_conns_map.entries()?;
+        let mirrored = inner.mirrored.find(&entries);
+        let mut closed_flows = Vec::new();
+        let mut handshakes = Vec::new();
+        let mut handshaken = AHashSet::new();
+        let mut named = AHashSet::new();
+        let mut quic_active = AHashMap::new();
 
-        for item in tcp_conns_map.iter() {
-            let (key, stats) = item?;
+        for (key, mut stats) in entries {
+            // Collapsed hops, and server ends already accounted from their client end,
+            // are left out.
+            let forget = sidecar_hops
+                .as_mut()
+                .is_some_and(|hops| hops.is_hop(&key, &stats))
+                || mirrored.contains(&key);
+            // The kernel sees no end to QUIC connections, so their flows end once idle.
+            if stats.is_active == 1
+                && stats.protocol as u32 == PROTOCOL_QUIC
+                && inner
+                    .quic
+                    .idle(&key, &stats, quic_timeout, now, &mut quic_active)
+            {
+                stats.is_active = 0;
+            }
             if stats.is_active != 1 {
-                keys_to_remove.push(key);
+                closed_flows.push(flow(key, &stats));
+                keys_to_remove.push((key, stats, forget));
+                continue;
+            }
+            if forget {
                 continue;
             }
-            if key.src_addr == key.dest_addr || self.is_loopback_address(key.dest_addr) {
+            let key = original_destination(with_role(key), &stats);
+            if !include_loopback && self.is_loopback(&key) {
                 continue;
             }
             if key.role == CONNECTION_ROLE_UNKNOWN {
                 continue;
             }
 
-            if let Ok(connection) = self.build_connection(key, &cache_mgr) {
+            // The totals of an open connection stay in the map, so one that does not
+            // resolve yet is only counted once it does, or once it is given up on.
+            let attempts = inner.quarantine.open_attempts(&key) + 1;
+            let server_name = inner.server_names.get(&key);
+            if server_name.is_some() {
+                named.insert(key);
+            }
+            let connection = self.build_connection(
+                key,
+                &stats,
+                stats.protocol as u32,
+                server_name,
+                &cache_mgr,
+                attempts > retries,
+            );
+            match connection {
+                Ok(connection) => {
+                    // Keep counting one given up on, so that it stays unknown.
+                    if attempts > retries {
+                        unresolved.insert(key, attempts);
+                    }
+                    // Handshakes are observed once, when their connection is first seen
+                    // established.
+                    if inner.handshakes.contains(&key) {
+                        handshaken.insert(key);
+                    } else if stats.handshake_ns > 0 {
+                        handshakes.push((connection.clone(), handshake(&stats)));
+                        handshaken.insert(key);
+                    }
+                    current_conns
+                        .entry(connection)
+                        .or_default()
+                        .merge(&EdgeStats::from(&stats));
+                }
+                Err(e) => {
+                    debug!("Retrying unresolved connection on the next poll: {}", e);
+                    unresolved.insert(key, attempts);
+                }
+            }
+        }
+
+        if let Some(aggregation) = inner.aggregation.as_ref() {
+            for (conn, edge_stats) in aggregation.edges() {
                 current_conns
-                    .entry(connection.clone())
-                    .and_modify(|e| *e += stats.bytes_sent)
-                    .or_insert(stats.bytes_sent);
+                    .entry(conn.clone())
+                    .or_default()
+                    .merge(edge_stats);
+            }
+        }
+
+        cache_mgr
+            .health
+            .set_unresolved(cache_mgr.health.misses() - misses);
+        let (tls_sessions, tls_done) = self.poll_tls_handshakes(&inner, &cache_mgr)?;
+        let (http_requests, http_counts, http_requests_closed) = self.poll_conn_map(
+            &inner,
+            inner.http_requests_map.as_deref(),
+            &inner.http_counts,
+            &cache_mgr,
+            include_loopback,
+        )?;
+        let (http_responses, http_response_totals, http_responses_closed) = self.poll_conn_map(
+            &inner,
+            inner.http_responses_map.as_deref(),
+            &inner.http_response_totals,
+            &cache_mgr,
+            include_loopback,
+        )?;
+        let (idle_periods, idle_totals, idle_closed) = self.poll_conn_map(
+            &inner,
+            inner.idle_periods_map.as_deref(),
+            &inner.idle_totals,
+            &cache_mgr,
+            include_loopback,
+        )?;
+
+        // Release the read lock before removing inactive connections
+        drop(inner);
+
+        let mut seen: AHashSet<_> = current_conns
+            .keys()
+            .map(|conn| (conn.client.clone(), conn.server.clone()))
+            .collect();
+        let mut inner = self.inner.write();
+        // Retry the connections that closed unresolved before those closing now, so
+        // that these get a whole poll interval before their first retry.
+        for pending in inner.quarantine.take_closed() {
+            let attempts = pending.attempts + 1;
+            let (key, stats) = (pending.key, &pending.stats);
+            let protocol = stats.protocol as u32;
+            match self.build_connection(key, stats, protocol, None, &cache_mgr, attempts > retries)
+            {
+                Ok(conn) => {
+                    let (key, stats) = (&pending.key, &pending.stats);
+                    self.record_closed(&conn, key, stats, &mut inner, &cache_mgr, now);
+                    seen.insert((conn.client, conn.server));
+                }
+                Err(_) if attempts <= retries => inner.quarantine.requeue(pending),
+                Err(e) => debug!("Dropping closed connection: {}", e),
+            }
+        }
+        for (key, stats, forget) in keys_to_remove {
+            if forget {
+                // Left out connections are forgotten with their socket.
+                if let Some(conns) = inner.current_conns_map.as_mut() {
+                    if let Err(e) = conns.remove(&key) {
+                        warn!("Failed to forget connection {:?}: {}", key, e);
+                    }
+                }
+                continue;
+            }
+            match self.handle_inactive_connection(key, stats, &mut inner, &cache_mgr, now, retries)
+            {
+                Ok(Some(conn)) => {
+                    seen.insert((conn.client, conn.server));
+                }
+                Ok(None) => {}
+                Err(e) => debug!("Dropping closed connection: {}", e),
+            }
+        }
+        if let Some(tls_handshakes) = inner.tls_handshakes_map.as_mut() {
+            for key in tls_done {
+                if let Err(e) = tls_handshakes.remove(&key) {
+                    warn!("Failed to remove the TLS handshake of {:?}: {}", key, e);
+                }
+            }
+        }
+        for (conn, session) in tls_sessions {
+            inner.tls.observe(&conn, &session, &cache_mgr.symbols);
+        }
+        if let Some(http_requests_map) = inner.http_requests_map.as_mut() {
+            for key in http_requests_closed {
+                if let Err(e) = http_requests_map.remove(&key) {
+                    warn!("Failed to remove the HTTP requests of {:?}: {}", key, e);
+                }
             }
         }
+        inner.http_counts = http_counts;
+        for (conn, key, count, last) in http_requests {
+            if count > last {
+                inner
+                    .http
+                    .observe(&conn, &key.line, count - last, &cache_mgr.symbols);
+            }
+        }
+        if let Some(http_responses_map) = inner.http_responses_map.as_mut() {
+            for key in http_responses_closed {
+                if let Err(e) = http_responses_map.remove(&key) {
+                    warn!("Failed to remove the HTTP responses of {:?}: {}", key, e);
+                }
+            }
+        }
+        inner.http_response_totals = http_response_totals;
+        let http_responses: Vec<_> = http_responses
+            .into_iter()
+            .filter(|(_, _, stats, last)| stats.responses > last.responses)
+            .map(|(conn, key, stats, last)| {
+                let delta = HttpResponseStats {
+                    responses: stats.responses - last.responses,
+                    body_bytes: stats.body_bytes.saturating_sub(last.body_bytes),
+                };
+                (conn, key.encoding, delta)
+            })
+            .collect();
+        inner
+            .http
+            .observe_responses(&http_responses, &cache_mgr.symbols);
+        if let Some(idle_periods_map) = inner.idle_periods_map.as_mut() {
+            for key in idle_closed {
+                if let Err(e) = idle_periods_map.remove(&key) {
+                    warn!("Failed to remove the idle periods of {:?}: {}", key, e);
+                }
+            }
+        }
+        inner.idle_totals = idle_totals;
+        let idle_periods: Vec<_> = idle_periods
+            .into_iter()
+            .filter(|(_, _, stats, last)| stats.periods > last.periods)
+            .map(|(conn, _, stats, last)| {
+                let delta = IdleStats {
+                    periods: stats.periods - last.periods,
+                    total_ns: stats.total_ns.saturating_sub(last.total_ns),
+                };
+                (conn, delta)
+            })
+            .collect();
+        inner.idle.observe(&idle_periods, &cache_mgr.symbols);
+        for (conn, handshake) in handshakes {
+            inner
+                .edge_metrics
+                .observe_handshake(&conn, &cache_mgr.symbols, handshake, now);
+        }
+        inner.quarantine.update_open(unresolved);
+        inner.quic.update(quic_active);
+        inner.handshakes = handshaken;
+        // Names of closed connections were used above, when they were accounted.
+        inner.server_names.retain(|key, _| named.contains(key));
+        inner.mirrored.update(mirrored);
+        inner.closed_flows.extend(closed_flows);
+        let overflow = inner.closed_flows.len().saturating_sub(MAX_CLOSED_FLOWS);
+        inner.closed_flows.drain(..overflow);
 
-        for (conn, bytes_sent) in past_conns_map.iter() {
+        let (past_ttl, half_life) = (
+            past_conn_ttl(&inner.metadata),
+            past_conn_half_life(&inner.metadata),
+        );
+        inner.past_conns.age(past_ttl, half_life, now);
+        // Merge past connections only after the inactive ones were moved there, so their
+        // totals don't dip for one poll.
+        for (conn, edge_stats) in inner.past_conns.iter() {
             current_conns
                 .entry(conn.clone())
-                .and_modify(|e| *e += *bytes_sent)
-                .or_insert(*bytes_sent);
+                .or_default()
+                .merge(edge_stats);
         }
 
-        // Release the read lock before removing inactive connections
+        let anomalies = inner
+            .edge_metrics
+            .update(&current_conns, &cache_mgr.symbols, now);
+        inner.split.update(&current_conns, &cache_mgr.symbols);
+        let ttl = edge_ttl(&inner.metadata);
+        let expired = inner.edge_metrics.expire(ttl, now);
+        for conn in expired.iter() {
+            inner.past_conns.remove(conn);
+        }
+        if let Some(aggregation) = inner.aggregation.as_mut() {
+            aggregation.expire(&expired);
+        }
+        inner.tls.expire(&expired);
+        inner.http.expire(&expired);
+        inner.idle.expire(&expired, &cache_mgr.symbols);
+        let timestamp_ns = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64);
+        let edges = inner.edge_metrics.graph_edges();
+        inner.snapshots.record(timestamp_ns, edges);
+        let fast_burns = inner.slos.evaluate(now);
+        let absent = absent_intervals(&inner.metadata);
+        let dependency_changes = inner.dependencies.update(seen, absent);
+        let events_mgr = inner.events_mgr.clone();
         drop(inner);
+        self.resolutions.rotate();
+        if let Some(processes) = &cache_mgr.processes {
+            processes.purge();
+        }
+        cache_mgr.symbols.purge();
 
+        if let Some(events_mgr) = events_mgr {
+            for change in dependency_changes {
+                events_mgr.publish_dependency(change);
+            }
+        }
+
+        if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            for burn in fast_burns {
+                handle.spawn(publish_fast_burn(burn));
+            }
+            for anomaly in anomalies {
+                handle.spawn(publish_anomaly(anomaly));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Pushes down the identities of workloads for the tracer to stamp connections with,
+    /// and reads the aggregates in its aggregation mode.
+    fn poll_identities(&self) -> Result<(), Error> {
         let mut inner = self.inner.write();
-        for key in keys_to_remove {
-            let _ = self.handle_inactive_connection(key, &mut inner, &cache_mgr);
+        let Some(cache_mgr) = inner.cache_mgr.clone() else {
+            return Ok(());
+        };
+        if let Some(identity_map) = inner.identity_map.as_mut() {
+            identity_map.push(&cache_mgr)?;
+        }
+        if let Some(aggregation) = inner.aggregation.as_mut() {
+            aggregation.poll(&cache_mgr)?;
         }
+        Ok(())
+    }
 
-        Ok(current_conns)
+    /// Reads the server names out of the ClientHellos sent by clients since the last
+    /// poll, over TLS or in the Initial packets of QUIC, for their connections to be
+    /// attributed to.
+    fn poll_server_names(&self) -> Result<(), Error> {
+        let mut inner = self.inner.write();
+        let Some(symbols) = inner
+            .cache_mgr
+            .as_ref()
+            .map(|cache_mgr| cache_mgr.symbols.clone())
+        else {
+            return Ok(());
+        };
+        let mut server_names = Vec::new();
+        if let Some(client_hellos) = inner.tls_client_hellos_map.as_mut() {
+            for (key, hello) in client_hellos.entries()? {
+                if let Err(e) = client_hellos.remove(&key) {
+                    warn!("Failed to remove the ClientHello of {:?}: {}", key, e);
+                }
+                if let Some(name) = TlsSession::parse(&hello).server_name {
+                    server_names.push((key, symbols.intern(&name)));
+                }
+            }
+        }
+        if let Some(quic_initials) = inner.quic_initials_map.as_mut() {
+            for (key, initial) in quic_initials.entries()? {
+                if let Err(e) = quic_initials.remove(&key) {
+                    warn!("Failed to remove the QUIC Initial of {:?}: {}", key, e);
+                }
+                if let Some(name) = quic::server_name(&initial) {
+                    server_names.push((key, symbols.intern(&name)));
+                }
+            }
+        }
+        inner.server_names.extend(server_names);
+        Ok(())
+    }
+
+    /// Parses the TLS handshakes captured by the tracer, and returns them with the
+    /// keys to remove from its map. A ClientHello waits for the client to finish the
+    /// handshake, or for the connection to close.
+    #[allow(clippy::type_complexity)]
+    fn poll_tls_handshakes(
+        &self,
+        inner: &Inner,
+        cache_mgr: &CacheManager,
+    ) -> Result<(Vec<(Connection, TlsSession)>, Vec<ConnectionKey>), Error> {
+        let (Some(tls_handshakes), Some(conns)) = (
+            inner.tls_handshakes_map.as_ref(),
+            inner.current_conns_map.as_ref(),
+        ) else {
+            return Ok((Vec::new(), Vec::new()));
+        };
+        let mut sessions = Vec::new();
+        let mut done = Vec::new();
+        for (key, handshake) in tls_handshakes.entries()? {
+            let stats = conns.get(&key).unwrap_or_default();
+            let client_hello = handshake.captured().get(5) == Some(&TLS_CLIENT_HELLO);
+            if client_hello && handshake.finished_ns == 0 && stats.is_active == 1 {
+                continue;
+            }
+            done.push(key);
+            let key = original_destination(with_role(key), &stats);
+            let server_name = inner.server_names.get(&key);
+            let protocol = stats.protocol as u32;
+            match self.build_connection(key, &stats, protocol, server_name, cache_mgr, false) {
+                Ok(conn) => {
+                    let mut session = TlsSession::parse(&handshake);
+                    // The client end sees the name it asked for in its own ClientHello.
+                    if session.server_name.is_none() {
+                        session.server_name = server_name.map(|name| name.to_string());
+                    }
+                    sessions.push((conn, session))
+                }
+                Err(e) => debug!("Dropping TLS handshake: {}", e),
+            }
+        }
+        Ok((sessions, done))
+    }
+
+    /// Reads a map the tracer keeps per connection, e.g. the requests of HTTP servers.
+    /// Returns the entries of the connections that resolve with their value when last
+    /// polled, the value of each entry to remember, and the entries of closed
+    /// connections to remove. Entries of open connections that don't resolve yet keep
+    /// their last value, so that they are accounted once they do.
+    #[allow(clippy::type_complexity)]
+    fn poll_conn_map<K, V>(
+        &self,
+        inner: &Inner,
+        map: Option<&dyn MapAccess<K, V>>,
+        last: &AHashMap<K, V>,
+        cache_mgr: &CacheManager,
+        include_loopback: bool,
+    ) -> Result<(Vec<(Connection, K, V, V)>, AHashMap<K, V>, Vec<K>), Error>
+    where
+        K: ConnectionEntry + Copy + Eq + Hash,
+        V: Copy + Default,
+    {
+        let (Some(map), Some(conns)) = (map, inner.current_conns_map.as_ref()) else {
+            return Ok((Vec::new(), AHashMap::new(), Vec::new()));
+        };
+        let mut entries = Vec::new();
+        let mut values = AHashMap::new();
+        let mut closed = Vec::new();
+        for (key, value) in map.entries()? {
+            let conn = key.connection();
+            let stats = conns.get(&conn).unwrap_or_default();
+            let open = stats.is_active == 1;
+            if !open {
+                closed.push(key);
+            }
+            let last = last.get(&key).copied().unwrap_or_default();
+            let conn = original_destination(with_role(conn), &stats);
+            if !include_loopback && self.is_loopback(&conn) {
+                continue;
+            }
+            let protocol = key.protocol(&stats);
+            match self.build_connection(conn, &stats, protocol, None, cache_mgr, !open) {
+                Ok(conn) => {
+                    entries.push((conn, key, value, last));
+                    if open {
+                        values.insert(key, value);
+                    }
+                }
+                Err(e) => {
+                    debug!("Retrying unresolved entries on the next poll: {}", e);
+                    values.insert(key, last);
+                }
+            }
+        }
+        Ok((entries, values, closed))
     }
 
-    fn resolve_ip(&self, ip: u32, cache_mgr_ref: &CacheManager) -> Option<Arc<Workload>> {
-        let ip_to_workload_lock = cache_mgr_ref.ip_to_workload.clone();
-        let ip_to_workload = ip_to_workload_lock.read();
-        let ip_addr = Ipv4Addr::from(ip);
-        let ip_string = ip_addr.to_string();
-        ip_to_workload.get(&ip_string).cloned()
+    /// Returns the workload of an IP, from the identity the tracer stamped it with when it
+    /// has one, which saves looking the IP up.
+    fn resolve_ip(
+        &self,
+        ip: u32,
+        identity: u32,
+        cache_mgr_ref: &CacheManager,
+    ) -> Option<Arc<Workload>> {
+        if let Some(workload) = cache_mgr_ref.identities.workload(identity) {
+            cache_mgr_ref.health.observe_lookup(true);
+            return Some(workload);
+        }
+        cache_mgr_ref.resolve_ip(&Ipv4Addr::from(ip).to_string())
     }
 
+    /// Resolves the workloads of a connection, or returns those it was last resolved to
+    /// while the cache manager is unchanged. Connections given up on are resolved anew.
     fn build_connection(
         &self,
         key: ConnectionKey,
+        stats: &ConnectionStats,
+        protocol: u32,
+        server_name: Option<&Symbol>,
+        cache_mgr_ref: &CacheManager,
+        give_up: bool,
+    ) -> Result<Connection, Error> {
+        if give_up {
+            return self.resolve_connection(key, stats, protocol, server_name, cache_mgr_ref, true);
+        }
+        let generation = cache_mgr_ref.generation();
+        if let Some(conn) = self
+            .resolutions
+            .get(&key, protocol, server_name, generation)
+        {
+            return Ok(conn);
+        }
+        let conn =
+            self.resolve_connection(key, stats, protocol, server_name, cache_mgr_ref, false)?;
+        self.resolutions
+            .insert(&key, protocol, server_name, generation, conn.clone());
+        Ok(conn)
+    }
+
+    /// Builds the connection of a key from the workloads of its ends. When `give_up`
+    /// is set, ends that do not resolve are attributed to the `unknown` workload. A
+    /// remote server that does not resolve is named after `server_name`, the SNI of the
+    /// client, when known.
+    fn resolve_connection(
+        &self,
+        key: ConnectionKey,
+        stats: &ConnectionStats,
+        protocol: u32,
+        server_name: Option<&Symbol>,
         cache_mgr_ref: &CacheManager,
+        give_up: bool,
     ) -> Result<Connection, Error> {
-        let client_workload = self
-            .resolve_ip(key.src_addr, cache_mgr_ref)
-            .ok_or(Error::msg(format!(
-                "Unknown IP: {}",
-                Ipv4Addr::from(key.src_addr)
-            )))?;
-        let server_workload = self
-            .resolve_ip(key.dest_addr, cache_mgr_ref)
-            .ok_or(Error::msg(format!(
-                "Unknown IP: {}",
-                Ipv4Addr::from(key.dest_addr)
-            )))?;
+        // The tracer stamped the source of a redirected server end, not the original
+        // destination it was given since.
+        let src_identity = match stats.original_dest() {
+            Some(_) if key.role == CONNECTION_ROLE_SERVER => IDENTITY_UNKNOWN,
+            _ => stats.src_identity,
+        };
+        if self.is_loopback(&key) {
+            return self.build_loopback_connection(
+                key,
+                src_identity,
+                protocol,
+                cache_mgr_ref,
+                give_up,
+            );
+        }
+        let unknown = || give_up.then(|| unknown_workload(cache_mgr_ref));
+        let external = || {
+            server_name
+                .filter(|_| key.role == CONNECTION_ROLE_CLIENT)
+                .map(|name| external_workload(name, cache_mgr_ref))
+        };
 
+        let (client_workload, server_workload) = match &cache_mgr_ref.processes {
+            // Outside Kubernetes only the local end of a connection has a process it can
+            // be attributed to. The remote end may still be a known local container.
+            Some(processes) => (
+                processes
+                    .resolve_pid(key.pid)
+                    .or_else(unknown)
+                    .ok_or(Error::msg(format!("Unknown process: {}", key.pid)))?,
+                self.resolve_ip(key.dest_addr, stats.dest_identity, cache_mgr_ref)
+                    .or_else(external)
+                    .unwrap_or_else(|| processes.resolve_peer(key.dest_ip())),
+            ),
+            None => (
+                self.resolve_ip(key.src_addr, src_identity, cache_mgr_ref)
+                    .or_else(unknown)
+                    .ok_or(Error::msg(format!("Unknown IP: {}", key.src_ip())))?,
+                self.resolve_ip(key.dest_addr, stats.dest_identity, cache_mgr_ref)
+                    .or_else(external)
+                    .or_else(unknown)
+                    .ok_or(Error::msg(format!("Unknown IP: {}", key.dest_ip())))?,
+            ),
+        };
+
+        let client_workload = cache_mgr_ref
+            .aliases
+            .apply(client_workload, &cache_mgr_ref.symbols);
+        let server_workload = cache_mgr_ref
+            .aliases
+            .apply(server_workload, &cache_mgr_ref.symbols);
         let (client, server, port) = match key.role {
             CONNECTION_ROLE_CLIENT => (client_workload, server_workload, key.dest_port),
             CONNECTION_ROLE_SERVER => (server_workload, client_workload, key.src_port),
@@ -177,33 +913,319 @@ impl ServiceMap {
             server,
             role: key.role,
             server_port: port,
+            protocol,
+            loopback: false,
         })
     }
 
+    /// Builds the self-edge of a loopback connection. Loopback addresses are the same in
+    /// every pod, so the workload is found from the process owning the socket instead.
+    fn build_loopback_connection(
+        &self,
+        key: ConnectionKey,
+        src_identity: u32,
+        protocol: u32,
+        cache_mgr_ref: &CacheManager,
+        give_up: bool,
+    ) -> Result<Connection, Error> {
+        let workload = match &cache_mgr_ref.processes {
+            Some(processes) => processes.resolve_pid(key.pid),
+            None if !key.src_ip().is_loopback() => {
+                self.resolve_ip(key.src_addr, src_identity, cache_mgr_ref)
+            }
+            None => cache_mgr_ref.resolve_pid(key.pid),
+        }
+        .or_else(|| give_up.then(|| unknown_workload(cache_mgr_ref)))
+        .ok_or(Error::msg(format!(
+            "Unknown loopback connection of process: {}",
+            key.pid
+        )))?;
+        let workload = cache_mgr_ref
+            .aliases
+            .apply(workload, &cache_mgr_ref.symbols);
+        let port = match key.role {
+            CONNECTION_ROLE_CLIENT => key.dest_port,
+            CONNECTION_ROLE_SERVER => key.src_port,
+            _ => return Err(Error::msg("Unknown connection role")),
+        };
+
+        Ok(Connection {
+            client: workload.clone(),
+            server: workload,
+            role: key.role,
+            server_port: port,
+            protocol,
+            loopback: true,
+        })
+    }
+
+    /// Accounts a connection that closed with `stats`, and removes it from the map. One
+    /// that does not resolve is quarantined until it does or the retries run out, and
+    /// `None` is returned.
     fn handle_inactive_connection(
         &self,
         key: ConnectionKey,
+        stats: ConnectionStats,
         inner: &mut Inner,
         cache_mgr_ref: &CacheManager,
-    ) -> Result<(), Error> {
-        let throughput = match inner.current_conns_map.as_mut().unwrap().get(&key, 0) {
-            Ok(stats) => stats.bytes_sent,
-            Err(_) => 0,
-        };
+        now: Instant,
+        retries: u32,
+    ) -> Result<Option<Connection>, Error> {
         inner.current_conns_map.as_mut().unwrap().remove(&key)?;
-        let connection = self.build_connection(key, cache_mgr_ref)?;
+        let key = original_destination(with_role(key), &stats);
+        let attempts = inner.quarantine.open_attempts(&key) + 1;
+        let connection = match self.build_connection(
+            key,
+            &stats,
+            stats.protocol as u32,
+            inner.server_names.get(&key),
+            cache_mgr_ref,
+            attempts > retries,
+        ) {
+            Ok(connection) => connection,
+            Err(e) if attempts <= retries => {
+                debug!("Quarantining closed connection: {}", e);
+                inner.quarantine.defer_closed(key, stats, attempts);
+                return Ok(None);
+            }
+            Err(e) => return Err(e),
+        };
+        self.record_closed(&connection, &key, &stats, inner, cache_mgr_ref, now);
+        Ok(Some(connection))
+    }
+
+    /// Moves the totals of a closed connection to the past connections, and observes
+    /// its handshake unless it was already while the connection was open.
+    fn record_closed(
+        &self,
+        connection: &Connection,
+        key: &ConnectionKey,
+        stats: &ConnectionStats,
+        inner: &mut Inner,
+        cache_mgr_ref: &CacheManager,
+        now: Instant,
+    ) {
+        inner.slos.observe(
+            connection,
+            (stats.duration_ns > 0).then(|| Duration::from_nanos(stats.duration_ns)),
+            stats.resets > 0 || stats.connect_timeouts > 0,
+        );
+        if stats.handshake_ns > 0 && !inner.handshakes.contains(key) {
+            inner.edge_metrics.observe_handshake(
+                connection,
+                &cache_mgr_ref.symbols,
+                handshake(stats),
+                now,
+            );
+        }
+        if stats.duration_ns > 0 {
+            inner.edge_metrics.observe_duration(
+                connection,
+                &cache_mgr_ref.symbols,
+                Duration::from_nanos(stats.duration_ns),
+                now,
+            );
+        }
         inner
-            .past_conns_map
-            .entry(connection)
-            .and_modify(|e| *e += throughput)
-            .or_insert(throughput);
-        Ok(())
+            .past_conns
+            .record(connection, &EdgeStats::from(stats), now);
     }
 
     fn is_loopback_address(&self, addr: u32) -> bool {
         let ip_addr = Ipv4Addr::from(addr);
         ip_addr.is_loopback()
     }
+
+    /// Whether a connection stays within its host or pod.
+    fn is_loopback(&self, key: &ConnectionKey) -> bool {
+        key.src_addr == key.dest_addr || self.is_loopback_address(key.dest_addr)
+    }
+}
+
+fn handshake(stats: &ConnectionStats) -> Duration {
+    Duration::from_nanos(stats.handshake_ns)
+}
+
+fn flow(key: ConnectionKey, stats: &ConnectionStats) -> Flow {
+    Flow {
+        key,
+        bytes_sent: stats.bytes_sent,
+        bytes_received: stats.bytes_received,
+        closed: stats.is_active != 1,
+    }
+}
+
+/// The workload of a remote server known by the name its clients asked for.
+fn external_workload(server_name: &Symbol, cache_mgr: &CacheManager) -> Arc<Workload> {
+    Arc::new(Workload {
+        name: server_name.clone(),
+        namespace: cache_mgr.symbols.intern(PEER_NAMESPACE),
+        kind: cache_mgr.symbols.intern("ExternalService"),
+        labels: Vec::new(),
+    })
+}
+
+/// The workload traffic is attributed to when its end could not be resolved.
+pub(crate) fn unknown_workload(cache_mgr: &CacheManager) -> Arc<Workload> {
+    Arc::new(Workload {
+        name: cache_mgr.symbols.intern("unknown"),
+        namespace: cache_mgr.symbols.intern("unknown"),
+        kind: cache_mgr.symbols.intern("Unknown"),
+        labels: Vec::new(),
+    })
+}
+
+/// Returns the key of a connection redirected to its socket, e.g. by the iptables rules
+/// of a sidecar, as if it had been accepted on its original destination. It is then
+/// attributed to the service the client meant to reach rather than to the proxy.
+fn original_destination(mut key: ConnectionKey, stats: &ConnectionStats) -> ConnectionKey {
+    if key.role == CONNECTION_ROLE_SERVER {
+        if let Some((addr, port)) = stats.original_dest() {
+            key.src_addr = addr;
+            key.src_port = port;
+        }
+    }
+    key
+}
+
+/// Entry of a map the tracer keeps per connection.
+trait ConnectionEntry {
+    fn connection(&self) -> ConnectionKey;
+
+    /// Protocol the entry is accounted under, that of its connection by default.
+    fn protocol(&self, stats: &ConnectionStats) -> u32 {
+        stats.protocol as u32
+    }
+}
+
+impl ConnectionEntry for HttpRequestKey {
+    fn connection(&self) -> ConnectionKey {
+        self.conn
+    }
+
+    fn protocol(&self, _stats: &ConnectionStats) -> u32 {
+        PROTOCOL_HTTP
+    }
+}
+
+impl ConnectionEntry for HttpResponseKey {
+    fn connection(&self) -> ConnectionKey {
+        self.conn
+    }
+
+    fn protocol(&self, _stats: &ConnectionStats) -> u32 {
+        PROTOCOL_HTTP
+    }
+}
+
+impl ConnectionEntry for IdleKey {
+    fn connection(&self) -> ConnectionKey {
+        self.conn
+    }
+}
+
+/// Whether loopback and same-pod connections are kept as self-edges, as set by the
+/// `loopback_traffic` metadata: `drop`, the default, or `include`.
+fn include_loopback(metadata: &HashMap<String, String>) -> bool {
+    match metadata.get("loopback_traffic").map(String::as_str) {
+        None | Some("drop") => false,
+        Some("include") => true,
+        Some(other) => {
+            warn!(
+                "Unknown loopback_traffic {:?}, dropping loopback traffic",
+                other
+            );
+            false
+        }
+    }
+}
+
+fn edge_ttl(metadata: &HashMap<String, String>) -> Duration {
+    let ttl = metadata
+        .get("edge_ttl")
+        .and_then(|t| t.parse::<u64>().ok())
+        .unwrap_or(DEFAULT_EDGE_TTL);
+    Duration::from_secs(ttl)
+}
+
+fn quic_idle_timeout(metadata: &HashMap<String, String>) -> Duration {
+    let timeout = metadata
+        .get("quic_idle_timeout")
+        .and_then(|t| t.parse::<u64>().ok())
+        .unwrap_or(DEFAULT_QUIC_IDLE_TIMEOUT);
+    Duration::from_secs(timeout)
+}
+
+pub(crate) fn protocol_name(protocol: u32) -> &'static str {
+    match protocol {
+        PROTOCOL_HTTP => "http",
+        PROTOCOL_GRPC => "grpc",
+        PROTOCOL_REDIS => "redis",
+        PROTOCOL_TLS => "tls-opaque",
+        PROTOCOL_QUIC => "quic",
+        _ => "other",
+    }
+}
+
+/// Returns where bpfman pinned the connections map of the conn-tracer program in `maps`.
+fn connections_pin(maps: &HashMap<String, u32>) -> Result<PathBuf, Error> {
+    let map_name = "CONNECTIONS";
+    let prog_id = maps
+        .get(map_name)
+        .ok_or(AgentError::MapNotFound(map_name.to_string()))?;
+    let bpfman_maps = Path::new(RTDIR_FS_MAPS);
+    if !bpfman_maps.exists() {
+        return Err(
+            AgentError::BpfmanUnavailable(format!("{} does not exist", RTDIR_FS_MAPS)).into(),
+        );
+    }
+    Ok(bpfman_maps.join(format!("{}/{}", prog_id, map_name)))
+}
+
+/// Opens a map pinned next to the connections map by tracers that have it.
+fn optional_map<K, V>(maps: &HashMap<String, u32>, name: &str) -> Option<Box<dyn MapAccess<K, V>>>
+where
+    K: Pod + Debug + Send + Sync + 'static,
+    V: Pod + Debug + Send + Sync + 'static,
+{
+    let pin = connections_pin(maps).ok()?.with_file_name(name);
+    if !pin.exists() {
+        debug!("No {} map at {}", name, pin.display());
+        return None;
+    }
+    let map: Result<AyaHashMap<MapData, K, V>, Error> = MapData::from_pin(&pin)
+        .map_err(Error::from)
+        .and_then(|map_data| Map::HashMap(map_data).try_into().map_err(Error::from));
+    match map {
+        Ok(map) => Some(Box::new(map)),
+        Err(e) => {
+            warn!("Failed to open the {} map: {:?}", name, e);
+            None
+        }
+    }
+}
+
+/// Turns on the aggregation mode of the tracer, when it has one. Identities are only
+/// known for the IPs of pods, so connections are still counted one by one outside
+/// Kubernetes.
+fn aggregation(maps: &HashMap<String, u32>, cache_mgr: &CacheManager) -> Option<Aggregation> {
+    if cache_mgr.processes.is_some() {
+        warn!("Kernel aggregation needs Kubernetes, counting connections one by one");
+        return None;
+    }
+    let aggregates = optional_map(maps, "AGGREGATES")?;
+    if let Err(e) = set_aggregation(maps, true) {
+        warn!("Failed to turn on kernel aggregation: {:?}", e);
+        return None;
+    }
+    Some(Aggregation::new(aggregates))
+}
+
+fn set_aggregation(maps: &HashMap<String, u32>, on: bool) -> Result<(), Error> {
+    let pin = connections_pin(maps)?.with_file_name("AGGREGATION");
+    let mut flag: Array<MapData, u32> = Map::Array(MapData::from_pin(pin)?).try_into()?;
+    flag.set(0, u32::from(on), 0)?;
+    Ok(())
 }
 
 #[async_trait]
@@ -212,64 +1234,127 @@ impl Program for ServiceMap {
         &self,
         metadata: HashMap<String, String>,
         cache_manager: CacheManager,
+        events_manager: EventsManager,
         maps: HashMap<String, u32>,
     ) -> Result<(), Error> {
         let mut inner = self.inner.write();
+        inner.slos = SloSet::from_metadata(&metadata)?;
+        inner
+            .edge_metrics
+            .set_anomaly_config(AnomalyConfig::from_metadata(&metadata));
+        inner.mesh = MeshConfig::from_metadata(&metadata);
+        inner.snapshots = SnapshotRing::new(SnapshotConfig::from_metadata(&metadata));
+        inner.split = TrafficSplit::from_metadata(&metadata);
+        inner.http = HttpMetrics::from_metadata(&metadata);
+        inner.aggregation = kernel_aggregation(&metadata)
+            .then(|| aggregation(&maps, &cache_manager))
+            .flatten();
         inner.ebpf_maps = maps.clone();
         inner.metadata = metadata;
         inner.cache_mgr = Some(cache_manager);
+        inner.events_mgr = Some(events_manager);
 
-        let map_name = "CONNECTIONS";
-        let prog_id = maps.get(map_name).ok_or(anyhow::anyhow!(
-            "No map named CONNECTIONS in the provided maps"
-        ))?;
-        let bpfman_maps = Path::new(RTDIR_FS_MAPS);
-        if !bpfman_maps.exists() {
-            return Err(anyhow::anyhow!("{} does not exist", RTDIR_FS_MAPS));
-        }
-
-        let map_pin_path = bpfman_maps.join(format!("{}/{}", prog_id, map_name));
-        let map_data = MapData::from_pin(map_pin_path)
-            .map_err(|_| anyhow::anyhow!("No maps named CONNECTIONS"))?;
+        let map_data = MapData::from_pin(connections_pin(&maps)?)
+            .map_err(|_| AgentError::MapNotFound("CONNECTIONS".to_string()))?;
         let tcp_conns_map: AyaHashMap<MapData, ConnectionKey, ConnectionStats> =
             Map::HashMap(map_data)
                 .try_into()
                 .map_err(|_| anyhow::anyhow!("Failed to convert map"))?;
-        inner.current_conns_map = Some(tcp_conns_map);
+        inner.current_conns_map = Some(Box::new(tcp_conns_map));
+        inner.tls_handshakes_map = optional_map(&maps, "TLS_HANDSHAKES");
+        inner.tls_client_hellos_map = optional_map(&maps, "TLS_CLIENT_HELLOS");
+        inner.http_requests_map = optional_map(&maps, "HTTP_REQUESTS");
+        inner.http_responses_map = optional_map(&maps, "HTTP_RESPONSES");
+        inner.idle_periods_map = optional_map(&maps, "IDLE_PERIODS");
+        inner.quic_initials_map = optional_map(&maps, "QUIC_INITIALS");
+        inner.identity_map = optional_map(&maps, "IDENTITIES").map(IdentityMap::new);
+
+        Ok(())
+    }
+    fn metadata_schema(&self) -> MetadataSchema {
+        MetadataSchema::common()
+            .key(MetadataKey::new("edge_ttl", MetadataType::UInt).default_value(DEFAULT_EDGE_TTL))
+            .key(
+                MetadataKey::new("loopback_traffic", MetadataType::Enum(&["drop", "include"]))
+                    .default_value("drop"),
+            )
+            .key(
+                MetadataKey::new("dependency_absent_intervals", MetadataType::UInt)
+                    .default_value(DEFAULT_DEPENDENCY_ABSENT_INTERVALS),
+            )
+            .key(
+                MetadataKey::new("unknown_ip_retries", MetadataType::UInt)
+                    .default_value(DEFAULT_UNKNOWN_IP_RETRIES),
+            )
+            .key(
+                MetadataKey::new("snapshot_window", MetadataType::UInt)
+                    .default_value(DEFAULT_SNAPSHOT_WINDOW),
+            )
+            .key(
+                MetadataKey::new("snapshot_retention", MetadataType::UInt)
+                    .default_value(DEFAULT_SNAPSHOT_RETENTION),
+            )
+            .key(
+                MetadataKey::new("snapshot_compaction", MetadataType::UInt)
+                    .default_value(DEFAULT_SNAPSHOT_COMPACTION),
+            )
+            .key(MetadataKey::new("anomaly_detection", MetadataType::Bool).default_value(false))
+            .key(MetadataKey::new("anomaly_threshold", MetadataType::Float))
+            .key(
+                MetadataKey::new("mesh_mode", MetadataType::Enum(&["raw", "collapse"]))
+                    .default_value("raw"),
+            )
+            .key(MetadataKey::new(
+                "mesh_sidecar_ports",
+                MetadataType::List(&MetadataType::UInt),
+            ))
+            .key(MetadataKey::new(
+                "mesh_sidecar_processes",
+                MetadataType::List(&MetadataType::String),
+            ))
+            .key(MetadataKey::new(
+                "traffic_split_label",
+                MetadataType::String,
+            ))
+            .key(MetadataKey::new(
+                "http_route_patterns",
+                MetadataType::List(&MetadataType::String),
+            ))
+            .key(
+                MetadataKey::new("http_max_routes", MetadataType::UInt)
+                    .default_value(DEFAULT_HTTP_MAX_ROUTES),
+            )
+            .key(
+                MetadataKey::new("quic_idle_timeout", MetadataType::UInt)
+                    .default_value(DEFAULT_QUIC_IDLE_TIMEOUT),
+            )
+            .key(MetadataKey::new("kernel_aggregation", MetadataType::Bool).default_value(false))
+            .key(MetadataKey::new("past_conn_ttl", MetadataType::UInt).default_value(0))
+            .key(MetadataKey::new("past_conn_half_life", MetadataType::UInt).default_value(0))
+            .key(MetadataKey::new(SLO_PREFIX, MetadataType::Custom(validate_slo)).prefix())
+    }
 
+    fn validate_maps(&self, maps: &HashMap<String, u32>) -> Result<(), Error> {
+        let pin = connections_pin(maps)?;
+        if !pin.exists() {
+            return Err(AgentError::MapNotFound(pin.display().to_string()).into());
+        }
         Ok(())
     }
+
     async fn start(
         &self,
         mut shutdown_rx: broadcast::Receiver<ShutdownSignal>,
     ) -> Result<(), Error> {
-        let metadata = self.get_metadata();
-        let interval = metadata
-            .get("interval")
-            .and_then(|i| i.parse::<u64>().ok())
-            .unwrap_or(DEFAULT_INTERVAL);
-
-        let mut interval = time::interval(Duration::from_secs(interval));
-        loop {
-            tokio::select! {
-                _ = interval.tick() => {
-                    if let Err(e) = self.poll() {
-                        debug!("Error polling: {:?}", e);
-                        return Err(e.into());
-                    }
+        // Polling is driven by the shared scheduler, so only wait to be stopped here.
+        while let Ok(signal) = shutdown_rx.recv().await {
+            match signal {
+                ShutdownSignal::All => break,
+                ShutdownSignal::ProgramName(name) if name == self.get_name() => {
+                    debug!("Received shutdown signal, stopping program: {}", name);
+                    break;
                 }
-                Ok(signal) = shutdown_rx.recv() => {
-                    match signal {
-                        ShutdownSignal::All => {
-                            break;
-                        },
-                        ShutdownSignal::ProgramName(name) if name == self.get_name() => {
-                            debug!("Received shutdown signal, stopping program: {}", name);
-                            break;
-                        },
-                        _ => {}
-                    }
-                },
+                _ => {}
             }
         }
 
@@ -281,50 +1366,24 @@ impl Program for ServiceMap {
         Ok(())
     }
 
-    fn collect(&self, encoder: &mut DescriptorEncoder) -> Result<(), Error> {
-        let conns = self.poll()?;
-        let conn_metric = Family::<Labels, Gauge>::default();
-        for (conn, value) in conns.iter() {
-            let labels = Labels {
-                conn_id: format!(
-                    "{:x}",
-                    fnv_hash(&format!(
-                        "{}{}{}{}",
-                        conn.client.name,
-                        conn.client.namespace,
-                        conn.server.name,
-                        conn.server.namespace
-                    ))
-                ),
-                client_id: format!(
-                    "{:x}",
-                    fnv_hash(&format!("{}{}", conn.client.name, conn.client.namespace))
-                ),
-                client_name: conn.client.name.clone(),
-                client_namespace: conn.client.namespace.clone(),
-                client_kind: conn.client.kind.clone(),
-                server_id: format!(
-                    "{:x}",
-                    fnv_hash(&format!("{}{}", conn.server.name, conn.server.namespace))
-                ),
-                server_name: conn.server.name.clone(),
-                server_namespace: conn.server.namespace.clone(),
-                server_kind: conn.server.kind.clone(),
-                server_port: conn.server_port.to_string(),
-                role: conn.role.to_string(),
-            };
-            conn_metric.get_or_create(&labels).set(*value as i64);
-        }
+    fn poll(&self) -> Result<(), Error> {
+        self.poll_server_names()?;
+        self.poll_identities()?;
+        self.poll_connections()
+    }
 
-        let metric_encoder = encoder.encode_descriptor(
-            "connection_observed",
-            "total bytes_sent value of connections observed",
-            Some(&Unit::Bytes),
-            conn_metric.metric_type(),
-        )?;
-        conn_metric.encode(metric_encoder)?;
+    fn collect(&self, encoder: &mut DescriptorEncoder) -> Result<(), Error> {
+        let inner = self.inner.read();
+        inner.edge_metrics.encode(encoder)?;
+        inner.tls.encode(encoder)?;
+        inner.http.encode(encoder)?;
+        inner.idle.encode(encoder)?;
+        inner.split.encode(encoder)?;
+        inner.slos.encode(encoder)
+    }
 
-        Ok(())
+    fn native_histograms(&self) -> Vec<NativeHistogramSeries> {
+        self.inner.read().edge_metrics.native_histograms()
     }
 
     fn get_name(&self) -> String {
@@ -367,21 +1426,1636 @@ impl Program for ServiceMap {
             bytecode: None,
             ebpf_maps: self.inner.read().ebpf_maps.clone(),
             metadata: self.get_metadata(),
+            load_diagnostics: None,
         })
     }
+
+    fn dump_maps(&self, maps: &[String]) -> Result<Vec<MapDump>, Error> {
+        let map_name = "CONNECTIONS";
+        if !maps.is_empty() && !maps.iter().any(|m| m == map_name) {
+            return Ok(vec![]);
+        }
+
+        let inner = self.inner.read();
+        let tcp_conns_map = inner
+            .current_conns_map
+            .as_ref()
+            .ok_or(anyhow::anyhow!("Map CONNECTIONS not initialized"))?;
+        let prog_id = inner.ebpf_maps.get(map_name).copied().unwrap_or_default();
+
+        let mut entries = Vec::new();
+        for (key, stats) in tcp_conns_map.entries()? {
+            entries.push(MapEntry {
+                key: pod_bytes(&key),
+                value: pod_bytes(&stats),
+                decoded: format!("{:?} => {:?}", key, stats),
+            });
+        }
+
+        Ok(vec![MapDump {
+            name: map_name.to_string(),
+            prog_id,
+            key_type: type_name::<ConnectionKey>().to_string(),
+            value_type: type_name::<ConnectionStats>().to_string(),
+            entries,
+        }])
+    }
+
+    fn graph_edges(&self) -> Vec<GraphEdge> {
+        self.inner.read().edge_metrics.graph_edges()
+    }
+
+    fn service_map_snapshots(&self, query: SnapshotQuery) -> Vec<ServiceMapSnapshot> {
+        self.inner.read().snapshots.query(query)
+    }
+
+    fn flows(&self) -> Vec<Flow> {
+        let mut inner = self.inner.write();
+        let mut flows: Vec<_> = inner.closed_flows.drain(..).collect();
+        if let Some(conns) = inner.current_conns_map.as_ref() {
+            match conns.entries() {
+                Ok(entries) => flows.extend(
+                    entries
+                        .iter()
+                        .filter(|(_, stats)| stats.is_active == 1)
+                        .map(|(key, stats)| flow(*key, stats)),
+                ),
+                Err(e) => debug!("Failed to read connections for flows: {:?}", e),
+            }
+        }
+        flows
+    }
 }
 
-#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
-struct Labels {
-    conn_id: String,
-    client_id: String,
-    client_name: String,
-    client_namespace: String,
-    client_kind: String,
-    server_id: String,
-    server_name: String,
-    server_namespace: String,
-    server_kind: String,
-    server_port: String,
-    role: String,
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::net::Ipv4Addr;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use conn_tracer_common::{
+        AggregateKey, AggregateStats, ConnectionKey, ConnectionStats, HttpRequestKey,
+        HttpResponseKey, HttpResponseStats, IdleKey, IdleStats, QuicInitial, TlsHandshake,
+        CONNECTION_ROLE_CLIENT, CONNECTION_ROLE_SERVER, CONNECTION_ROLE_UNKNOWN,
+        HTTP_ENCODING_GZIP, HTTP_ENCODING_IDENTITY, IDENTITY_UNKNOWN, PROTOCOL_HTTP, PROTOCOL_QUIC,
+        PROTOCOL_REDIS, TLS_CERTIFICATE, TLS_CLIENT_HELLO, TLS_HANDSHAKE, TLS_SERVER_HELLO,
+    };
+
+    use prometheus_client::encoding::text::encode;
+    use prometheus_client::registry::Registry;
+
+    use crate::collector::ProgramCollector;
+    use crate::common::graph::{coalesce_updates, GraphEdge, ServiceMapWatch};
+    use crate::common::maps::MemoryMap;
+    use crate::managers::alias::{AliasRule, WorkloadAliases};
+    use crate::managers::cache::{CacheManager, Workload};
+    use crate::progs::service_map::quic::InitialKeys;
+    use crate::progs::types::{Program, SnapshotQuery};
+
+    use super::{include_loopback, ServiceMap};
+
+    const FRONTEND: &str = "10.0.0.1";
+    const BACKEND: &str = "10.0.0.2";
+
+    fn service_map(
+        conns: MemoryMap<ConnectionKey, ConnectionStats>,
+        metadata: HashMap<String, String>,
+    ) -> ServiceMap {
+        let cache_mgr = CacheManager::empty();
+        cache_mgr.insert_workload(FRONTEND, "frontend", "default", "Deployment");
+        cache_mgr.insert_workload(BACKEND, "backend", "default", "Deployment");
+        ServiceMap::with_connections(Box::new(conns), cache_mgr, metadata)
+    }
+
+    fn key(id: u32, src: &str, dest: &str, role: u32) -> ConnectionKey {
+        let (src_port, dest_port) = match role {
+            CONNECTION_ROLE_SERVER => (8080, 40000 + id),
+            _ => (40000 + id, 8080),
+        };
+        ConnectionKey {
+            id,
+            pid: 1,
+            src_addr: src.parse::<Ipv4Addr>().unwrap().into(),
+            src_port,
+            dest_addr: dest.parse::<Ipv4Addr>().unwrap().into(),
+            dest_port,
+            role,
+        }
+    }
+
+    fn stats(bytes_sent: u64, is_active: bool) -> ConnectionStats {
+        ConnectionStats {
+            bytes_sent,
+            bytes_received: bytes_sent * 2,
+            is_active: u64::from(is_active),
+            protocol: PROTOCOL_HTTP as u64,
+            ..Default::default()
+        }
+    }
+
+    fn sorted_edges(service_map: &ServiceMap) -> Vec<(String, String, GraphEdge)> {
+        let mut edges: Vec<_> = service_map
+            .graph_edges()
+            .into_iter()
+            .map(|e| (e.client.name.to_string(), e.server.name.to_string(), e))
+            .collect();
+        edges.sort_by(|a, b| (&a.0, &a.1).cmp(&(&b.0, &b.1)));
+        edges
+    }
+
+    #[test]
+    fn test_poll_aggregates_connections_of_an_edge() {
+        let mut conns = MemoryMap::default();
+        conns.insert(
+            key(1, FRONTEND, BACKEND, CONNECTION_ROLE_CLIENT),
+            stats(100, true),
+        );
+        conns.insert(
+            key(2, FRONTEND, BACKEND, CONNECTION_ROLE_CLIENT),
+            stats(50, true),
+        );
+        let service_map = service_map(conns, HashMap::new());
+
+        service_map.poll().unwrap();
+        let edges = sorted_edges(&service_map);
+        assert_eq!(edges.len(), 1);
+        let (client, server, edge) = &edges[0];
+        assert_eq!((client.as_str(), server.as_str()), ("frontend", "backend"));
+        assert_eq!(edge.server_port, 8080);
+        assert_eq!(edge.protocol, "http");
+        assert_eq!(edge.bytes_sent, 150);
+        assert_eq!(edge.bytes_received, 300);
+        assert_eq!(edge.active_conns, 2);
+    }
+
+    #[test]
+    fn test_poll_splits_edges_by_protocol() {
+        let mut conns = MemoryMap::default();
+        conns.insert(
+            key(1, FRONTEND, BACKEND, CONNECTION_ROLE_CLIENT),
+            stats(100, true),
+        );
+        conns.insert(
+            key(2, FRONTEND, BACKEND, CONNECTION_ROLE_CLIENT),
+            ConnectionStats {
+                protocol: PROTOCOL_REDIS as u64,
+                ..stats(30, true)
+            },
+        );
+        // Not inferred yet.
+        conns.insert(
+            key(3, FRONTEND, BACKEND, CONNECTION_ROLE_CLIENT),
+            ConnectionStats {
+                protocol: 0,
+                ..stats(5, true)
+            },
+        );
+        let service_map = service_map(conns, HashMap::new());
+
+        service_map.poll().unwrap();
+        let mut protocols: Vec<_> = sorted_edges(&service_map)
+            .into_iter()
+            .map(|(_, _, edge)| (edge.protocol, edge.bytes_sent))
+            .collect();
+        protocols.sort();
+        assert_eq!(protocols, [("http", 100), ("other", 5), ("redis", 30)]);
+    }
+
+    #[test]
+    fn test_poll_merges_kernel_aggregates() {
+        let mut conns = MemoryMap::default();
+        conns.insert(
+            key(1, FRONTEND, BACKEND, CONNECTION_ROLE_CLIENT),
+            stats(100, true),
+        );
+        let aggregate = |client, server, role, bytes_sent| {
+            let key = AggregateKey {
+                client,
+                server,
+                server_port: 8080,
+                protocol: PROTOCOL_HTTP,
+                role,
+            };
+            let stats = AggregateStats {
+                bytes_sent,
+                bytes_received: bytes_sent * 2,
+                opened: 3,
+                closed: 1,
+                ..Default::default()
+            };
+            (key, stats)
+        };
+        // Identities are given in the order of the IPs: 1 to frontend, 2 to backend.
+        let mut aggregates = MemoryMap::default();
+        for (key, stats) in [
+            aggregate(1, 2, CONNECTION_ROLE_CLIENT, 50),
+            // Mirrored by the client end.
+            aggregate(1, 2, CONNECTION_ROLE_SERVER, 60),
+            aggregate(IDENTITY_UNKNOWN, 2, CONNECTION_ROLE_SERVER, 10),
+        ] {
+            aggregates.insert(key, stats);
+        }
+        let service_map = service_map(conns, HashMap::new())
+            .with_aggregation(Box::new(MemoryMap::default()), Box::new(aggregates));
+
+        service_map.poll().unwrap();
+        let edges = sorted_edges(&service_map);
+        assert_eq!(edges.len(), 2);
+        let (client, server, edge) = &edges[0];
+        assert_eq!((client.as_str(), server.as_str()), ("frontend", "backend"));
+        assert_eq!(edge.bytes_sent, 150);
+        assert_eq!(edge.bytes_received, 300);
+        assert_eq!(edge.active_conns, 3);
+        let (client, server, edge) = &edges[1];
+        assert_eq!((client.as_str(), server.as_str()), ("unknown", "backend"));
+        assert_eq!(edge.bytes_sent, 10);
+    }
+
+    #[test]
+    fn test_poll_resolves_connections_by_their_identities() {
+        // Addresses the cache manager doesn't know, stamped with the identities given
+        // to frontend and backend.
+        let mut conns = MemoryMap::default();
+        conns.insert(
+            key(1, "10.0.0.9", "10.0.0.8", CONNECTION_ROLE_CLIENT),
+            ConnectionStats {
+                src_identity: 1,
+                dest_identity: 2,
+                ..stats(100, true)
+            },
+        );
+        let service_map = service_map(conns, HashMap::new()).with_aggregation(
+            Box::new(MemoryMap::default()),
+            Box::new(MemoryMap::default()),
+        );
+
+        service_map.poll().unwrap();
+        let edges = sorted_edges(&service_map);
+        assert_eq!(edges.len(), 1);
+        let (client, server, edge) = &edges[0];
+        assert_eq!((client.as_str(), server.as_str()), ("frontend", "backend"));
+        assert_eq!(edge.bytes_sent, 100);
+    }
+
+    #[test]
+    fn test_snapshots_are_numbered_and_checksummed() {
+        let mut conns = MemoryMap::default();
+        conns.insert(
+            key(1, FRONTEND, BACKEND, CONNECTION_ROLE_CLIENT),
+            stats(100, true),
+        );
+        let service_map = service_map(conns, HashMap::new());
+        service_map.poll().unwrap();
+        service_map.poll().unwrap();
+
+        let snapshots = service_map.service_map_snapshots(SnapshotQuery::Range(0, u64::MAX));
+        let ids: Vec<_> = snapshots.iter().map(|s| s.snapshot_id).collect();
+        assert_eq!(ids, [1, 2]);
+        // The edges didn't change between the polls.
+        assert_ne!(snapshots[0].checksum, 0);
+        assert_eq!(snapshots[0].checksum, snapshots[1].checksum);
+    }
+
+    #[test]
+    fn test_service_map_watch_sends_deltas_between_resyncs() {
+        let mut conns = MemoryMap::default();
+        conns.insert(
+            key(1, FRONTEND, BACKEND, CONNECTION_ROLE_CLIENT),
+            stats(100, true),
+        );
+        let service_map = service_map(conns, HashMap::new());
+        service_map.poll().unwrap();
+        let first = service_map
+            .service_map_snapshots(SnapshotQuery::At(u64::MAX))
+            .pop()
+            .unwrap();
+
+        let mut watch = ServiceMapWatch::new(3);
+        let update = watch.update(first.clone()).unwrap();
+        assert!(update.full);
+        assert_eq!(update.edges, first.edges);
+        assert!(watch.update(first.clone()).is_none());
+
+        // One edge changed and another appeared.
+        let mut second = first.clone();
+        second.snapshot_id += 1;
+        second.edges[0].bytes_sent += 1;
+        let mut added = first.edges[0].clone();
+        added.server_port += 1;
+        second.edges.push(added);
+        let update = watch.update(second.clone()).unwrap();
+        assert!(!update.full);
+        assert_eq!(update.edges, second.edges);
+        assert!(update.removed.is_empty());
+
+        // The new edge went away again.
+        let mut third = second.clone();
+        third.snapshot_id += 1;
+        third.edges.pop();
+        let update = watch.update(third.clone()).unwrap();
+        assert!(!update.full);
+        assert!(update.edges.is_empty());
+        assert_eq!(update.removed.len(), 1);
+        assert_eq!(
+            update.removed[0].server_port,
+            first.edges[0].server_port + 1
+        );
+        assert_eq!(update.removed[0].bytes_sent, 0);
+
+        // Every third update is a full one.
+        let mut fourth = third.clone();
+        fourth.snapshot_id += 1;
+        let update = watch.update(fourth).unwrap();
+        assert!(update.full);
+        assert_eq!(update.edges, third.edges);
+    }
+
+    #[test]
+    fn test_coalesced_updates_bring_watchers_to_the_last_snapshot() {
+        let mut conns = MemoryMap::default();
+        conns.insert(
+            key(1, FRONTEND, BACKEND, CONNECTION_ROLE_CLIENT),
+            stats(100, true),
+        );
+        let service_map = service_map(conns, HashMap::new());
+        service_map.poll().unwrap();
+        let first = service_map
+            .service_map_snapshots(SnapshotQuery::At(u64::MAX))
+            .pop()
+            .unwrap();
+        let mut second = first.clone();
+        second.snapshot_id += 1;
+        let mut added = first.edges[0].clone();
+        added.server_port += 1;
+        second.edges.push(added);
+        let mut third = first.clone();
+        third.snapshot_id += 2;
+        third.edges[0].bytes_sent += 1;
+
+        let mut watch = ServiceMapWatch::new(0);
+        let mut full = watch.update(first.clone()).unwrap();
+        let mut delta = watch.update(second).unwrap();
+        let last = watch.update(third.clone()).unwrap();
+        coalesce_updates(&mut full, delta.clone());
+        coalesce_updates(&mut full, last.clone());
+        assert!(full.full);
+        assert_eq!(full.snapshot_id, third.snapshot_id);
+        assert_eq!(full.edges, third.edges);
+
+        // The edge added then removed is removed, and the changed one sent.
+        coalesce_updates(&mut delta, last);
+        assert!(!delta.full);
+        assert_eq!(delta.edges, third.edges);
+        assert_eq!(delta.removed.len(), 1);
+        assert_eq!(delta.removed[0].server_port, first.edges[0].server_port + 1);
+    }
+
+    #[test]
+    fn test_past_connections_decay_and_expire() {
+        let mut conns = MemoryMap::default();
+        conns.insert(
+            key(1, FRONTEND, BACKEND, CONNECTION_ROLE_CLIENT),
+            stats(100, false),
+        );
+        let service_map = service_map(conns, HashMap::new());
+        service_map.poll().unwrap();
+
+        let mut inner = service_map.inner.write();
+        let now = Instant::now();
+        let ttl = Some(Duration::from_secs(300));
+        inner.past_conns.age(
+            ttl,
+            Some(Duration::from_secs(60)),
+            now + Duration::from_secs(120),
+        );
+        let (_, past) = inner.past_conns.iter().next().unwrap();
+        assert_eq!((past.bytes_sent, past.bytes_received), (25, 50));
+        assert_eq!(past.opened_conns, 1);
+        inner
+            .past_conns
+            .age(ttl, None, now + Duration::from_secs(301));
+        assert_eq!(inner.past_conns.len(), 0);
+    }
+
+    #[test]
+    fn test_poll_reuses_resolutions_until_the_cache_changes() {
+        let mut conns = MemoryMap::default();
+        conns.insert(
+            key(1, FRONTEND, BACKEND, CONNECTION_ROLE_CLIENT),
+            stats(100, true),
+        );
+        let service_map = service_map(conns, HashMap::new());
+        let cache_mgr = service_map.inner.read().cache_mgr.clone().unwrap();
+        let database = |cache_mgr: &CacheManager| Workload {
+            name: cache_mgr.symbols.intern("database"),
+            namespace: cache_mgr.symbols.intern("default"),
+            kind: cache_mgr.symbols.intern("StatefulSet"),
+            labels: Vec::new(),
+        };
+
+        service_map.poll().unwrap();
+        assert_eq!(service_map.resolutions.len(), 1);
+        // Changed behind the back of the cache manager, so the resolution is kept.
+        cache_mgr
+            .ip_to_workload
+            .write()
+            .insert(BACKEND.to_string(), Arc::new(database(&cache_mgr)));
+        service_map.poll().unwrap();
+        let edges = sorted_edges(&service_map);
+        assert_eq!(edges.len(), 1);
+        assert_eq!(edges[0].1, "backend");
+
+        cache_mgr.insert_workload(BACKEND, "database", "default", "StatefulSet");
+        service_map.poll().unwrap();
+        let edges = sorted_edges(&service_map);
+        assert!(edges
+            .iter()
+            .any(|(client, server, _)| (client.as_str(), server.as_str())
+                == ("frontend", "database")));
+    }
+
+    #[test]
+    fn test_poll_keeps_totals_of_inactive_connections() {
+        let mut conns = MemoryMap::default();
+        conns.insert(
+            key(1, FRONTEND, BACKEND, CONNECTION_ROLE_CLIENT),
+            stats(100, true),
+        );
+        conns.insert(
+            key(2, FRONTEND, BACKEND, CONNECTION_ROLE_CLIENT),
+            stats(50, false),
+        );
+        let service_map = service_map(conns, HashMap::new());
+
+        service_map.poll().unwrap();
+        // The closed connection is moved out of the kernel map and its bytes kept.
+        let dumps = service_map.dump_maps(&[]).unwrap();
+        assert_eq!(dumps[0].entries.len(), 1);
+        assert_eq!(service_map.inner.read().past_conns.len(), 1);
+        let edges = sorted_edges(&service_map);
+        assert_eq!(edges[0].2.bytes_sent, 150);
+        assert_eq!(edges[0].2.active_conns, 1);
+
+        service_map.poll().unwrap();
+        assert_eq!(sorted_edges(&service_map)[0].2.bytes_sent, 150);
+    }
+
+    #[test]
+    fn test_dump_maps_returns_raw_and_decoded_entries() {
+        let conn = key(1, FRONTEND, BACKEND, CONNECTION_ROLE_CLIENT);
+        let mut conns = MemoryMap::default();
+        conns.insert(conn, stats(100, true));
+        let service_map = service_map(conns, HashMap::new());
+
+        let dumps = service_map.dump_maps(&["CONNECTIONS".to_string()]).unwrap();
+        assert_eq!(dumps.len(), 1);
+        assert!(dumps[0].key_type.ends_with("ConnectionKey"));
+        let entry = &dumps[0].entries[0];
+        // As the kernel stores them: seven u32 fields, the id first.
+        assert_eq!(entry.key.len(), 28);
+        assert_eq!(entry.key[..8], [1, 0, 0, 0, 1, 0, 0, 0]);
+        assert_eq!(entry.value[..8], 100u64.to_ne_bytes());
+        assert!(entry.decoded.starts_with("ConnectionKey { id: 1,"));
+
+        assert!(service_map
+            .dump_maps(&["UNKNOWN".to_string()])
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_poll_resolves_workloads() {
+        let mut conns = MemoryMap::default();
+        // The server side of a connection is reported from the backend.
+        conns.insert(
+            key(1, BACKEND, FRONTEND, CONNECTION_ROLE_SERVER),
+            stats(10, true),
+        );
+        // An unknown role is inferred from the ports, the client's being ephemeral.
+        conns.insert(
+            key(2, FRONTEND, BACKEND, CONNECTION_ROLE_UNKNOWN),
+            stats(20, true),
+        );
+        // Unknown peers make no edge.
+        conns.insert(
+            key(3, FRONTEND, "10.0.0.9", CONNECTION_ROLE_CLIENT),
+            stats(30, true),
+        );
+        // Same-pod traffic is dropped unless asked for.
+        conns.insert(
+            key(4, FRONTEND, FRONTEND, CONNECTION_ROLE_CLIENT),
+            stats(40, true),
+        );
+        let service_map = service_map(conns, HashMap::new());
+
+        service_map.poll().unwrap();
+        // One edge per observed end.
+        let edges = sorted_edges(&service_map);
+        assert_eq!(edges.len(), 2);
+        for (client, server, edge) in &edges {
+            assert_eq!((client.as_str(), server.as_str()), ("frontend", "backend"));
+            assert_eq!(edge.server_port, 8080);
+        }
+        assert_eq!(edges.iter().map(|e| e.2.bytes_sent).sum::<u64>(), 30);
+    }
+
+    #[test]
+    fn test_poll_accounts_connections_seen_from_both_ends_once() {
+        let client = key(1, FRONTEND, BACKEND, CONNECTION_ROLE_CLIENT);
+        let server = ConnectionKey {
+            src_addr: client.dest_addr,
+            src_port: client.dest_port,
+            dest_addr: client.src_addr,
+            dest_port: client.src_port,
+            role: CONNECTION_ROLE_SERVER,
+            ..client
+        };
+        let mut conns = MemoryMap::default();
+        conns.insert(client, stats(100, true));
+        conns.insert(server, stats(200, true));
+        let service_map = service_map(conns, HashMap::new());
+
+        service_map.poll().unwrap();
+        let edges = sorted_edges(&service_map);
+        assert_eq!(edges.len(), 1);
+        assert_eq!(edges[0].2.bytes_sent, 100);
+        assert_eq!(edges[0].2.active_conns, 1);
+    }
+
+    #[test]
+    fn test_poll_quarantines_unresolved_connections() {
+        let mut conns = MemoryMap::default();
+        // Closes before the informer catches up with its server.
+        conns.insert(
+            key(1, FRONTEND, "10.0.0.8", CONNECTION_ROLE_CLIENT),
+            stats(10, false),
+        );
+        // Stays open to a peer that never resolves.
+        conns.insert(
+            key(2, FRONTEND, "10.0.0.9", CONNECTION_ROLE_CLIENT),
+            stats(20, true),
+        );
+        let metadata = HashMap::from([("unknown_ip_retries".to_string(), "2".to_string())]);
+        let service_map = service_map(conns, metadata);
+
+        service_map.poll().unwrap();
+        assert!(sorted_edges(&service_map).is_empty());
+
+        let cache_mgr = service_map.inner.read().cache_mgr.clone().unwrap();
+        cache_mgr.insert_workload("10.0.0.8", "payments", "default", "Deployment");
+        service_map.poll().unwrap();
+        let edges = sorted_edges(&service_map);
+        assert_eq!(edges.len(), 1);
+        assert_eq!(edges[0].1, "payments");
+        assert_eq!(edges[0].2.bytes_sent, 10);
+
+        // Given up on after its retries, its bytes go to the unknown workload.
+        service_map.poll().unwrap();
+        let edges = sorted_edges(&service_map);
+        assert_eq!(edges.len(), 2);
+        assert_eq!(edges[1].1, "unknown");
+        assert_eq!(edges[1].2.bytes_sent, 20);
+        service_map.poll().unwrap();
+        assert_eq!(sorted_edges(&service_map)[1].2.bytes_sent, 20);
+    }
+
+    #[test]
+    fn test_poll_includes_loopback_when_asked() {
+        let mut conns = MemoryMap::default();
+        conns.insert(
+            key(1, FRONTEND, FRONTEND, CONNECTION_ROLE_CLIENT),
+            stats(40, true),
+        );
+        let metadata = HashMap::from([("loopback_traffic".to_string(), "include".to_string())]);
+        let service_map = service_map(conns, metadata);
+
+        service_map.poll().unwrap();
+        let edges = sorted_edges(&service_map);
+        assert_eq!(edges.len(), 1);
+        assert_eq!(
+            (edges[0].0.as_str(), edges[0].1.as_str()),
+            ("frontend", "frontend")
+        );
+    }
+
+    #[test]
+    fn test_loopback_edges_are_dropped_by_default_and_labeled() {
+        let conns = || {
+            let mut conns = MemoryMap::default();
+            conns.insert(
+                key(1, FRONTEND, FRONTEND, CONNECTION_ROLE_CLIENT),
+                stats(40, true),
+            );
+            conns.insert(
+                key(2, FRONTEND, BACKEND, CONNECTION_ROLE_CLIENT),
+                stats(40, true),
+            );
+            conns
+        };
+        let dropped = service_map(conns(), HashMap::new());
+        dropped.poll().unwrap();
+        let edges = sorted_edges(&dropped);
+        assert_eq!(edges.len(), 1);
+        assert_eq!(edges[0].1, "backend");
+
+        let metadata = HashMap::from([("loopback_traffic".to_string(), "include".to_string())]);
+        let included = Arc::new(service_map(conns(), metadata));
+        included.poll().unwrap();
+        let mut registry = Registry::default();
+        registry.register_collector(Box::new(ProgramCollector(included.clone())));
+        let mut metrics = String::new();
+        encode(&mut metrics, &registry).unwrap();
+        let active: Vec<_> = metrics
+            .lines()
+            .filter(|line| line.starts_with("connection_active{"))
+            .collect();
+        assert_eq!(active.len(), 2);
+        assert!(active
+            .iter()
+            .any(|line| line.contains("server_name=\"frontend\"")
+                && line.contains("loopback=\"true\"")));
+        assert!(active
+            .iter()
+            .any(|line| line.contains("server_name=\"backend\"")
+                && line.contains("loopback=\"false\"")));
+    }
+
+    #[test]
+    fn test_include_loopback_from_metadata() {
+        let metadata =
+            |value: &str| HashMap::from([("loopback_traffic".to_string(), value.to_string())]);
+        assert!(include_loopback(&metadata("include")));
+        assert!(!include_loopback(&metadata("drop")));
+        assert!(!include_loopback(&metadata("sometimes")));
+        assert!(!include_loopback(&HashMap::new()));
+    }
+
+    #[test]
+    fn test_poll_collapses_sidecar_hops() {
+        let conns = || {
+            let mut conns = MemoryMap::default();
+            conns.insert(
+                key(1, FRONTEND, BACKEND, CONNECTION_ROLE_CLIENT),
+                stats(40, true),
+            );
+            // The inbound sidecar of the backend accepting the call.
+            conns.insert(
+                ConnectionKey {
+                    dest_port: 40002,
+                    src_port: 15006,
+                    ..key(2, BACKEND, FRONTEND, CONNECTION_ROLE_SERVER)
+                },
+                stats(40, true),
+            );
+            conns
+        };
+
+        let raw = service_map(conns(), HashMap::new());
+        raw.poll().unwrap();
+        assert_eq!(sorted_edges(&raw).len(), 2);
+
+        let metadata = HashMap::from([("mesh_mode".to_string(), "collapse".to_string())]);
+        let collapsed = service_map(conns(), metadata);
+        collapsed.poll().unwrap();
+        let edges = sorted_edges(&collapsed);
+        assert_eq!(edges.len(), 1);
+        assert_eq!(edges[0].2.server_port, 8080);
+    }
+
+    #[test]
+    fn test_poll_attributes_redirected_connections_to_original_destination() {
+        for mesh_mode in ["raw", "collapse"] {
+            let mut conns = MemoryMap::default();
+            // The inbound sidecar of the backend, which looked up where the call went.
+            conns.insert(
+                ConnectionKey {
+                    dest_port: 40001,
+                    src_port: 15006,
+                    ..key(1, BACKEND, FRONTEND, CONNECTION_ROLE_SERVER)
+                },
+                ConnectionStats {
+                    original_dest_addr: BACKEND.parse::<Ipv4Addr>().unwrap().into(),
+                    original_dest_port: 8080,
+                    ..stats(40, true)
+                },
+            );
+            let metadata = HashMap::from([("mesh_mode".to_string(), mesh_mode.to_string())]);
+            let service_map = service_map(conns, metadata);
+
+            service_map.poll().unwrap();
+            let edges = sorted_edges(&service_map);
+            assert_eq!(edges.len(), 1, "{}", mesh_mode);
+            let (client, server, edge) = &edges[0];
+            assert_eq!((client.as_str(), server.as_str()), ("frontend", "backend"));
+            assert_eq!(edge.server_port, 8080);
+        }
+    }
+
+    #[test]
+    fn test_poll_exports_connection_counts() {
+        let mut conns = MemoryMap::default();
+        for id in 1..=2 {
+            conns.insert(
+                key(id, FRONTEND, BACKEND, CONNECTION_ROLE_CLIENT),
+                stats(10, true),
+            );
+        }
+        conns.insert(
+            key(3, FRONTEND, BACKEND, CONNECTION_ROLE_CLIENT),
+            stats(10, false),
+        );
+        let service_map = Arc::new(service_map(conns, HashMap::new()));
+        let mut registry = Registry::default();
+        registry.register_collector(Box::new(ProgramCollector(service_map.clone())));
+        let sample = |name: &str| {
+            let mut metrics = String::new();
+            encode(&mut metrics, &registry).unwrap();
+            metrics
+                .lines()
+                .find(|line| line.starts_with(name))
+                .and_then(|line| line.rsplit(' ').next())
+                .map(|value| value.to_string())
+        };
+
+        service_map.poll().unwrap();
+        assert_eq!(sample("connection_active{").as_deref(), Some("2"));
+        assert_eq!(sample("connection_opened_total{").as_deref(), Some("3"));
+        // The closed connection now counts from the past connections, not twice.
+        service_map.poll().unwrap();
+        assert_eq!(sample("connection_opened_total{").as_deref(), Some("3"));
+    }
+
+    #[test]
+    fn test_poll_counts_resets_and_connect_timeouts() {
+        let failed = |resets, connect_timeouts, is_active| ConnectionStats {
+            resets,
+            connect_timeouts,
+            ..stats(10, is_active)
+        };
+        let mut conns = MemoryMap::default();
+        conns.insert(
+            key(1, FRONTEND, BACKEND, CONNECTION_ROLE_CLIENT),
+            failed(1, 0, true),
+        );
+        conns.insert(
+            key(2, FRONTEND, BACKEND, CONNECTION_ROLE_CLIENT),
+            failed(0, 1, false),
+        );
+        let service_map = Arc::new(service_map(conns, HashMap::new()));
+        let mut registry = Registry::default();
+        registry.register_collector(Box::new(ProgramCollector(service_map.clone())));
+        let metric = |name: &str| {
+            let mut metrics = String::new();
+            encode(&mut metrics, &registry).unwrap();
+            metrics
+                .lines()
+                .find(|line| line.starts_with(&format!("{}{{", name)))
+                .map(|line| line.rsplit_once(' ').unwrap().1.to_string())
+        };
+
+        service_map.poll().unwrap();
+        assert_eq!(metric("connection_resets_total").as_deref(), Some("1"));
+        assert_eq!(
+            metric("connection_connect_timeouts_total").as_deref(),
+            Some("1")
+        );
+
+        // The open connection is reset again; the closed one isn't counted twice.
+        let mut conns = MemoryMap::default();
+        conns.insert(
+            key(1, FRONTEND, BACKEND, CONNECTION_ROLE_CLIENT),
+            failed(2, 0, true),
+        );
+        service_map.inner.write().current_conns_map = Some(Box::new(conns));
+        service_map.poll().unwrap();
+        assert_eq!(metric("connection_resets_total").as_deref(), Some("2"));
+        assert_eq!(
+            metric("connection_connect_timeouts_total").as_deref(),
+            Some("1")
+        );
+    }
+
+    #[test]
+    fn test_poll_observes_durations_of_closed_connections() {
+        let lasted = |duration_ns, is_active| ConnectionStats {
+            duration_ns,
+            ..stats(10, is_active)
+        };
+        let mut conns = MemoryMap::default();
+        conns.insert(
+            key(1, FRONTEND, BACKEND, CONNECTION_ROLE_CLIENT),
+            lasted(2_000_000_000, false),
+        );
+        // Still open, so its lifetime isn't known yet.
+        conns.insert(
+            key(2, FRONTEND, BACKEND, CONNECTION_ROLE_CLIENT),
+            lasted(0, true),
+        );
+        let service_map = Arc::new(service_map(conns, HashMap::new()));
+        let mut registry = Registry::default();
+        registry.register_collector(Box::new(ProgramCollector(service_map.clone())));
+        let metric = |series: &str| {
+            let mut metrics = String::new();
+            encode(&mut metrics, &registry).unwrap();
+            metrics
+                .lines()
+                .find(|line| {
+                    line.starts_with("connection_duration_seconds") && line.contains(series)
+                })
+                .map(|line| line.rsplit_once(' ').unwrap().1.to_string())
+        };
+
+        service_map.poll().unwrap();
+        assert_eq!(metric("_count{").as_deref(), Some("1"));
+        assert_eq!(metric("_sum{").as_deref(), Some("2.0"));
+        assert_eq!(metric("le=\"1.024\"").as_deref(), Some("0"));
+        assert_eq!(metric("le=\"4.096\"").as_deref(), Some("1"));
+
+        // The closed connection left the kernel map, and isn't observed again.
+        service_map.poll().unwrap();
+        assert_eq!(metric("_count{").as_deref(), Some("1"));
+    }
+
+    #[test]
+    fn test_poll_splits_edges_by_workload_labels() {
+        const CANARY: &str = "10.0.0.3";
+        let cache_mgr = CacheManager::empty();
+        cache_mgr.insert_workload(FRONTEND, "frontend", "default", "Deployment");
+        for (ip, version) in [(BACKEND, "v1"), (CANARY, "v2")] {
+            let workload = Workload {
+                name: cache_mgr.symbols.intern("backend"),
+                namespace: cache_mgr.symbols.intern("default"),
+                kind: cache_mgr.symbols.intern("Deployment"),
+                labels: vec![(
+                    cache_mgr.symbols.intern("app.kubernetes.io/version"),
+                    cache_mgr.symbols.intern(version),
+                )],
+            };
+            cache_mgr
+                .ip_to_workload
+                .write()
+                .insert(ip.to_string(), Arc::new(workload));
+        }
+        let mut conns = MemoryMap::default();
+        conns.insert(
+            key(1, FRONTEND, BACKEND, CONNECTION_ROLE_CLIENT),
+            stats(90, true),
+        );
+        conns.insert(
+            key(2, FRONTEND, CANARY, CONNECTION_ROLE_CLIENT),
+            stats(10, true),
+        );
+        let service_map = Arc::new(ServiceMap::with_connections(
+            Box::new(conns),
+            cache_mgr,
+            HashMap::new(),
+        ));
+        let mut registry = Registry::default();
+        registry.register_collector(Box::new(ProgramCollector(service_map.clone())));
+
+        service_map.poll().unwrap();
+        let mut metrics = String::new();
+        encode(&mut metrics, &registry).unwrap();
+        let mut observed: Vec<_> = metrics
+            .lines()
+            .filter(|line| line.starts_with("connection_observed_bytes{"))
+            .collect();
+        observed.sort();
+        assert_eq!(observed.len(), 2);
+        assert!(observed[0].contains("server_label_app_kubernetes_io_version=\"v1\""));
+        assert!(observed[0].ends_with(" 90"));
+        assert!(observed[1].contains("server_label_app_kubernetes_io_version=\"v2\""));
+        assert!(observed[1].ends_with(" 10"));
+    }
+
+    #[test]
+    fn test_poll_reports_traffic_split_between_versions() {
+        const CANARY: &str = "10.0.0.3";
+        let cache_mgr = CacheManager::empty();
+        cache_mgr.insert_workload(FRONTEND, "frontend", "default", "Deployment");
+        for (ip, version) in [(BACKEND, "v1"), (CANARY, "v2")] {
+            let workload = Workload {
+                name: cache_mgr.symbols.intern("backend"),
+                namespace: cache_mgr.symbols.intern("default"),
+                kind: cache_mgr.symbols.intern("Deployment"),
+                labels: vec![(
+                    cache_mgr.symbols.intern("version"),
+                    cache_mgr.symbols.intern(version),
+                )],
+            };
+            cache_mgr
+                .ip_to_workload
+                .write()
+                .insert(ip.to_string(), Arc::new(workload));
+        }
+        let mut conns = MemoryMap::default();
+        conns.insert(
+            key(1, FRONTEND, BACKEND, CONNECTION_ROLE_CLIENT),
+            stats(90, true),
+        );
+        conns.insert(
+            key(2, FRONTEND, CANARY, CONNECTION_ROLE_CLIENT),
+            stats(10, true),
+        );
+        let metadata = HashMap::from([("traffic_split_label".to_string(), "version".to_string())]);
+        let service_map = Arc::new(ServiceMap::with_connections(
+            Box::new(conns),
+            cache_mgr,
+            metadata,
+        ));
+        let mut registry = Registry::default();
+        registry.register_collector(Box::new(ProgramCollector(service_map.clone())));
+        let ratios = || {
+            let mut metrics = String::new();
+            encode(&mut metrics, &registry).unwrap();
+            let mut ratios: Vec<_> = metrics
+                .lines()
+                .filter(|line| line.starts_with("traffic_split_ratio{"))
+                .map(|line| {
+                    let (labels, ratio) = line.rsplit_once(' ').unwrap();
+                    let v2 = labels.contains("version=\"v2\"");
+                    (v2, ratio.parse::<f64>().unwrap())
+                })
+                .collect();
+            ratios.sort_by_key(|(v2, _)| *v2);
+            ratios
+        };
+
+        service_map.poll().unwrap();
+        assert_eq!(ratios(), vec![(false, 0.9), (true, 0.1)]);
+
+        // Shares follow the traffic of the last poll, not the totals.
+        let mut conns = MemoryMap::default();
+        conns.insert(
+            key(1, FRONTEND, BACKEND, CONNECTION_ROLE_CLIENT),
+            stats(90, true),
+        );
+        conns.insert(
+            key(2, FRONTEND, CANARY, CONNECTION_ROLE_CLIENT),
+            stats(100, true),
+        );
+        service_map.inner.write().current_conns_map = Some(Box::new(conns));
+        service_map.poll().unwrap();
+        assert_eq!(ratios(), vec![(false, 0.0), (true, 1.0)]);
+    }
+
+    #[test]
+    fn test_poll_observes_handshakes_once() {
+        let handshake = |is_active| ConnectionStats {
+            handshake_ns: 2_000_000,
+            ..stats(10, is_active)
+        };
+        let mut conns = MemoryMap::default();
+        conns.insert(
+            key(1, FRONTEND, BACKEND, CONNECTION_ROLE_CLIENT),
+            handshake(true),
+        );
+        let service_map = Arc::new(service_map(conns, HashMap::new()));
+        let mut registry = Registry::default();
+        registry.register_collector(Box::new(ProgramCollector(service_map.clone())));
+        let handshakes = || {
+            let mut metrics = String::new();
+            encode(&mut metrics, &registry).unwrap();
+            metrics
+                .lines()
+                .find(|line| line.starts_with("connection_handshake_seconds_count{"))
+                .map(|line| line.rsplit_once(' ').unwrap().1.to_string())
+        };
+
+        service_map.poll().unwrap();
+        service_map.poll().unwrap();
+        assert_eq!(handshakes().as_deref(), Some("1"));
+
+        // Closing doesn't observe it again, unlike a connection closed before a poll.
+        let mut conns = MemoryMap::default();
+        conns.insert(
+            key(1, FRONTEND, BACKEND, CONNECTION_ROLE_CLIENT),
+            handshake(false),
+        );
+        conns.insert(
+            key(2, FRONTEND, BACKEND, CONNECTION_ROLE_CLIENT),
+            handshake(false),
+        );
+        service_map.inner.write().current_conns_map = Some(Box::new(conns));
+        service_map.poll().unwrap();
+        assert_eq!(handshakes().as_deref(), Some("2"));
+    }
+
+    /// Captures a handshake record carrying the given messages.
+    #[test]
+    fn test_poll_exports_connection_reuse_ratio() {
+        let requests = |requests| ConnectionStats {
+            requests,
+            ..stats(10, true)
+        };
+        let mut conns = MemoryMap::default();
+        conns.insert(
+            key(1, FRONTEND, BACKEND, CONNECTION_ROLE_CLIENT),
+            requests(10),
+        );
+        let service_map = Arc::new(service_map(conns, HashMap::new()));
+        let mut registry = Registry::default();
+        registry.register_collector(Box::new(ProgramCollector(service_map.clone())));
+        let metric = |name: &str| {
+            let mut metrics = String::new();
+            encode(&mut metrics, &registry).unwrap();
+            metrics
+                .lines()
+                .find(|line| line.starts_with(&format!("{}{{", name)))
+                .map(|line| line.rsplit_once(' ').unwrap().1.to_string())
+        };
+
+        // A pooled connection made all the requests.
+        service_map.poll().unwrap();
+        assert_eq!(metric("connection_requests_total").as_deref(), Some("10"));
+        assert_eq!(metric("connection_reuse_ratio").as_deref(), Some("0.9"));
+
+        // Then a connection per request.
+        let mut conns = MemoryMap::default();
+        conns.insert(
+            key(1, FRONTEND, BACKEND, CONNECTION_ROLE_CLIENT),
+            requests(10),
+        );
+        for id in 2..6 {
+            conns.insert(
+                key(id, FRONTEND, BACKEND, CONNECTION_ROLE_CLIENT),
+                requests(1),
+            );
+        }
+        service_map.inner.write().current_conns_map = Some(Box::new(conns));
+        service_map.poll().unwrap();
+        assert_eq!(metric("connection_requests_total").as_deref(), Some("14"));
+        assert_eq!(metric("connection_reuse_ratio").as_deref(), Some("0.0"));
+    }
+
+    #[test]
+    fn test_poll_exports_connection_idle_histogram() {
+        let conn = key(1, FRONTEND, BACKEND, CONNECTION_ROLE_CLIENT);
+        let mut conns = MemoryMap::default();
+        conns.insert(conn, stats(10, true));
+        let service_map = Arc::new(service_map(conns, HashMap::new()));
+        let mut registry = Registry::default();
+        registry.register_collector(Box::new(ProgramCollector(service_map.clone())));
+        let metric = |series: &str| {
+            let mut metrics = String::new();
+            encode(&mut metrics, &registry).unwrap();
+            metrics
+                .lines()
+                .find(|line| line.starts_with("connection_idle_seconds") && line.contains(series))
+                .map(|line| line.rsplit_once(' ').unwrap().1.to_string())
+        };
+        // Periods in the bucket up to 64ms, and in the one up to 32s.
+        let periods = |short: u64, long: u64| {
+            let mut periods = MemoryMap::default();
+            let stats = |periods: u64, idle_ns: u64| IdleStats {
+                periods,
+                total_ns: periods * idle_ns,
+            };
+            periods.insert(IdleKey { conn, bucket: 6 }, stats(short, 50_000_000));
+            periods.insert(IdleKey { conn, bucket: 15 }, stats(long, 30_000_000_000));
+            Box::new(periods)
+        };
+
+        service_map.inner.write().idle_periods_map = Some(periods(4, 1));
+        service_map.poll().unwrap();
+        assert_eq!(metric("_count{").as_deref(), Some("5"));
+        assert_eq!(metric("_sum{").as_deref(), Some("30.2"));
+        assert_eq!(metric("le=\"0.064\"").as_deref(), Some("4"));
+        assert_eq!(metric("le=\"32.768\"").as_deref(), Some("5"));
+
+        // Only the periods since the last poll are observed.
+        service_map.inner.write().idle_periods_map = Some(periods(4, 3));
+        service_map.poll().unwrap();
+        assert_eq!(metric("_count{").as_deref(), Some("7"));
+        assert_eq!(metric("le=\"0.064\"").as_deref(), Some("4"));
+    }
+
+    fn tls_handshake(messages: &[(u8, Vec<u8>)], finished_ns: u64) -> TlsHandshake {
+        let body: Vec<u8> = messages
+            .iter()
+            .flat_map(|(message_type, message)| {
+                let len = (message.len() as u32).to_be_bytes();
+                [*message_type, len[1], len[2], len[3]]
+                    .into_iter()
+                    .chain(message.iter().copied())
+            })
+            .collect();
+        let mut record = vec![TLS_HANDSHAKE, 0x03, 0x03];
+        record.extend((body.len() as u16).to_be_bytes());
+        record.extend(body);
+        let mut handshake = TlsHandshake {
+            hello_ns: 1_000_000,
+            finished_ns,
+            len: record.len() as u32,
+            ..Default::default()
+        };
+        handshake.data[..record.len()].copy_from_slice(&record);
+        handshake
+    }
+
+    fn client_hello(server_name: &str) -> Vec<u8> {
+        let server_name = server_name.as_bytes();
+        let mut sni = vec![0, 0];
+        sni.extend((server_name.len() as u16 + 5).to_be_bytes());
+        sni.extend((server_name.len() as u16 + 3).to_be_bytes());
+        sni.push(0);
+        sni.extend((server_name.len() as u16).to_be_bytes());
+        sni.extend(server_name);
+        let mut hello = vec![0x03, 0x03];
+        hello.extend([0; 32]);
+        hello.extend([0, 0, 2, 0x13, 0x01, 1, 0]);
+        hello.extend((sni.len() as u16).to_be_bytes());
+        hello.extend(sni);
+        hello
+    }
+
+    fn der(tag: u8, contents: &[u8]) -> Vec<u8> {
+        [tag, contents.len() as u8]
+            .into_iter()
+            .chain(contents.iter().copied())
+            .collect()
+    }
+
+    fn tls_metrics(service_map: &Arc<ServiceMap>) -> Vec<String> {
+        let mut registry = Registry::default();
+        registry.register_collector(Box::new(ProgramCollector(service_map.clone())));
+        let mut metrics = String::new();
+        encode(&mut metrics, &registry).unwrap();
+        metrics
+            .lines()
+            .filter(|line| line.starts_with("tls_"))
+            .map(str::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn test_poll_parses_client_hello_once_the_client_finished() {
+        let key = key(1, BACKEND, FRONTEND, CONNECTION_ROLE_SERVER);
+        let mut conns = MemoryMap::default();
+        conns.insert(key, stats(10, true));
+        let service_map = Arc::new(service_map(conns, HashMap::new()));
+
+        let hello = client_hello("api.example.com");
+        let mut handshakes = MemoryMap::default();
+        handshakes.insert(key, tls_handshake(&[(TLS_CLIENT_HELLO, hello.clone())], 0));
+        service_map.inner.write().tls_handshakes_map = Some(Box::new(handshakes));
+
+        service_map.poll().unwrap();
+        assert!(tls_metrics(&service_map).is_empty());
+
+        let mut handshakes = MemoryMap::default();
+        handshakes.insert(
+            key,
+            tls_handshake(&[(TLS_CLIENT_HELLO, hello)], 1_000_000 + 3_000_000),
+        );
+        service_map.inner.write().tls_handshakes_map = Some(Box::new(handshakes));
+        service_map.poll().unwrap();
+        let metrics = tls_metrics(&service_map);
+        assert!(metrics
+            .iter()
+            .any(|line| line.starts_with("tls_handshakes_total{")
+                && line.contains("sni=\"api.example.com\"")
+                && line.ends_with(" 1")));
+        assert!(metrics.iter().any(
+            |line| line.starts_with("tls_handshake_duration_seconds_sum{")
+                && line.ends_with(" 0.003")
+        ));
+        let inner = service_map.inner.read();
+        assert!(inner
+            .tls_handshakes_map
+            .as_ref()
+            .unwrap()
+            .entries()
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_poll_parses_server_hello_and_certificate() {
+        let key = key(1, FRONTEND, BACKEND, CONNECTION_ROLE_CLIENT);
+        let mut conns = MemoryMap::default();
+        conns.insert(key, stats(10, true));
+        let service_map = Arc::new(service_map(conns, HashMap::new()));
+
+        let mut hello = vec![0x03, 0x03];
+        hello.extend([0; 32]);
+        hello.extend([0, 0xc0, 0x2f, 0]);
+        hello.extend([0, 0]);
+        let validity = [der(0x17, b"250101000000Z"), der(0x17, b"300101000000Z")].concat();
+        let tbs = [
+            der(0xa0, &der(0x02, &[2])),
+            der(0x02, &[1]),
+            der(0x30, &[]),
+            der(0x30, &[]),
+            der(0x30, &validity),
+        ]
+        .concat();
+        let certificate = der(0x30, &der(0x30, &tbs));
+        let len = (certificate.len() as u32).to_be_bytes();
+        let mut certificates = vec![0, 0, (certificate.len() + 3) as u8, len[1], len[2], len[3]];
+        certificates.extend(certificate);
+        let mut handshakes = MemoryMap::default();
+        handshakes.insert(
+            key,
+            tls_handshake(
+                &[(TLS_SERVER_HELLO, hello), (TLS_CERTIFICATE, certificates)],
+                0,
+            ),
+        );
+        service_map.inner.write().tls_handshakes_map = Some(Box::new(handshakes));
+
+        service_map.poll().unwrap();
+        let metrics = tls_metrics(&service_map);
+        assert!(metrics
+            .iter()
+            .any(|line| line.starts_with("tls_handshakes_total{")
+                && line.contains("tls_version=\"1.2\"")
+                && line.contains("cipher=\"TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256\"")));
+        // 2030-01-01T00:00:00Z
+        assert!(metrics.iter().any(|line| line
+            .starts_with("tls_certificate_expiry_timestamp_seconds{")
+            && line.ends_with(" 1893456000")));
+    }
+
+    #[test]
+    fn test_poll_names_external_servers_after_their_sni() {
+        const STRIPE: &str = "203.0.113.7";
+        const UNNAMED: &str = "203.0.113.8";
+        let named = key(1, FRONTEND, STRIPE, CONNECTION_ROLE_CLIENT);
+        let mut conns = MemoryMap::default();
+        conns.insert(named, stats(10, true));
+        conns.insert(
+            key(2, FRONTEND, UNNAMED, CONNECTION_ROLE_CLIENT),
+            stats(10, true),
+        );
+        conns.insert(
+            key(3, FRONTEND, BACKEND, CONNECTION_ROLE_CLIENT),
+            stats(10, true),
+        );
+        let mut metadata = HashMap::new();
+        metadata.insert("unknown_ip_retries".to_string(), "0".to_string());
+        let service_map = service_map(conns, metadata);
+        let mut client_hellos = MemoryMap::default();
+        client_hellos.insert(
+            named,
+            tls_handshake(&[(TLS_CLIENT_HELLO, client_hello("api.stripe.com"))], 0),
+        );
+        // A resolved server keeps its workload whatever its clients call it.
+        client_hellos.insert(
+            key(3, FRONTEND, BACKEND, CONNECTION_ROLE_CLIENT),
+            tls_handshake(
+                &[(TLS_CLIENT_HELLO, client_hello("backend.example.com"))],
+                0,
+            ),
+        );
+        service_map.inner.write().tls_client_hellos_map = Some(Box::new(client_hellos));
+
+        service_map.poll().unwrap();
+        let edges = sorted_edges(&service_map);
+        let servers: Vec<_> = edges
+            .iter()
+            .map(|(_, server, edge)| (server.as_str(), edge.server.namespace.to_string()))
+            .collect();
+        assert_eq!(
+            servers,
+            vec![
+                ("api.stripe.com", "external".to_string()),
+                ("backend", "default".to_string()),
+                ("unknown", "unknown".to_string()),
+            ]
+        );
+        let inner = service_map.inner.read();
+        assert!(inner
+            .tls_client_hellos_map
+            .as_ref()
+            .unwrap()
+            .entries()
+            .unwrap()
+            .is_empty());
+        drop(inner);
+
+        // The name is forgotten with its connection, once accounted.
+        let mut conns = MemoryMap::default();
+        conns.insert(named, stats(20, false));
+        service_map.inner.write().current_conns_map = Some(Box::new(conns));
+        service_map.poll().unwrap();
+        assert!(service_map.inner.read().server_names.is_empty());
+        let edge = sorted_edges(&service_map).remove(0);
+        assert_eq!((edge.1.as_str(), edge.2.bytes_sent), ("api.stripe.com", 20));
+    }
+
+    fn quic_initial(server_name: &str) -> QuicInitial {
+        let hello = client_hello(server_name);
+        let mut messages = vec![TLS_CLIENT_HELLO];
+        messages.extend(&(hello.len() as u32).to_be_bytes()[1..]);
+        messages.extend(hello);
+        let dcid = [0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08];
+        let datagram = InitialKeys::client_initial(&dcid, &messages);
+        let mut initial = QuicInitial {
+            len: datagram.len() as u32,
+            ..Default::default()
+        };
+        initial.data[..datagram.len()].copy_from_slice(&datagram);
+        initial
+    }
+
+    #[test]
+    fn test_poll_names_quic_flows_after_their_sni() {
+        const CDN: &str = "203.0.113.9";
+        let flow = key(1, FRONTEND, CDN, CONNECTION_ROLE_CLIENT);
+        let mut conns = MemoryMap::default();
+        conns.insert(
+            flow,
+            ConnectionStats {
+                bytes_sent: 1200,
+                bytes_received: 4800,
+                is_active: 1,
+                protocol: PROTOCOL_QUIC as u64,
+                ..Default::default()
+            },
+        );
+        let service_map = service_map(conns, HashMap::new());
+        let mut initials = MemoryMap::default();
+        initials.insert(flow, quic_initial("video.example.com"));
+        service_map.inner.write().quic_initials_map = Some(Box::new(initials));
+
+        service_map.poll().unwrap();
+        let (client, server, edge) = sorted_edges(&service_map).remove(0);
+        assert_eq!(
+            (client.as_str(), server.as_str()),
+            ("frontend", "video.example.com")
+        );
+        assert_eq!(edge.protocol, "quic");
+        assert_eq!(
+            (edge.bytes_sent, edge.bytes_received, edge.active_conns),
+            (1200, 4800, 1)
+        );
+
+        // A flow that carried nothing for quic_idle_timeout is accounted as closed.
+        service_map
+            .inner
+            .write()
+            .metadata
+            .insert("quic_idle_timeout".to_string(), "0".to_string());
+        service_map.poll().unwrap();
+        let (_, server, edge) = sorted_edges(&service_map).remove(0);
+        assert_eq!(server, "video.example.com");
+        assert_eq!((edge.bytes_sent, edge.active_conns), (1200, 0));
+        let inner = service_map.inner.read();
+        let conns = inner.current_conns_map.as_ref().unwrap();
+        assert!(conns.entries().unwrap().is_empty());
+    }
+
+    fn http_request(conn: ConnectionKey, line: &str) -> HttpRequestKey {
+        let mut key = HttpRequestKey {
+            conn,
+            ..Default::default()
+        };
+        let len = line.len().min(key.line.len());
+        key.line[..len].copy_from_slice(&line.as_bytes()[..len]);
+        key
+    }
+
+    fn http_requests(service_map: &Arc<ServiceMap>) -> Vec<(String, String, String)> {
+        let mut registry = Registry::default();
+        registry.register_collector(Box::new(ProgramCollector(service_map.clone())));
+        let mut metrics = String::new();
+        encode(&mut metrics, &registry).unwrap();
+        let label = |line: &str, name: &str| {
+            let start = line.find(&format!("{}=\"", name)).unwrap() + name.len() + 2;
+            line[start..start + line[start..].find('"').unwrap()].to_string()
+        };
+        let mut requests: Vec<_> = metrics
+            .lines()
+            .filter(|line| line.starts_with("http_requests_total{"))
+            .map(|line| {
+                (
+                    label(line, "method"),
+                    label(line, "route"),
+                    line.rsplit(' ').next().unwrap().to_string(),
+                )
+            })
+            .collect();
+        requests.sort();
+        requests
+    }
+
+    #[test]
+    fn test_poll_counts_http_requests_by_route() {
+        let conn = key(1, BACKEND, FRONTEND, CONNECTION_ROLE_SERVER);
+        let mut conns = MemoryMap::default();
+        conns.insert(conn, stats(10, true));
+        let mut metadata = HashMap::new();
+        metadata.insert(
+            "http_route_patterns".to_string(),
+            "/reports/{name}, /static/*".to_string(),
+        );
+        let service_map = Arc::new(service_map(conns, metadata));
+        let lines = [
+            "GET /users/123/orders/456?expand=items HTTP/1.1\r\nHost: backend",
+            "GET /users/77/orders/9 HTTP/1.1\r\nHost: backend",
+            "DELETE /items/3f2504e0-4f89-11d3-9a0c-0305e82c3301 HTTP/1.1\r\n",
+            "GET /reports/weekly-sales HTTP/1.1\r\n",
+            "GET /static/js/app.0123456789abcdef.js HTTP/1.1\r\n",
+            "GET /blobs/0123456789abcdef0123456789abcdef/tree/and/a/very/long/path",
+        ];
+        let mut requests = MemoryMap::default();
+        for (i, line) in lines.iter().enumerate() {
+            requests.insert(http_request(conn, line), i as u64 + 1);
+        }
+        service_map.inner.write().http_requests_map = Some(Box::new(requests));
+
+        service_map.poll().unwrap();
+        let expected = |orders: u64| {
+            vec![
+                (
+                    "DELETE".to_string(),
+                    "/items/{uuid}".to_string(),
+                    "3".to_string(),
+                ),
+                (
+                    "GET".to_string(),
+                    "/blobs/{hash}/tree/and/a/very/*".to_string(),
+                    "6".to_string(),
+                ),
+                (
+                    "GET".to_string(),
+                    "/reports/{name}".to_string(),
+                    "4".to_string(),
+                ),
+                ("GET".to_string(), "/static/*".to_string(), "5".to_string()),
+                (
+                    "GET".to_string(),
+                    "/users/{id}/orders/{id}".to_string(),
+                    orders.to_string(),
+                ),
+            ]
+        };
+        assert_eq!(http_requests(&service_map), expected(3));
+
+        // Only requests made since the last poll are added, and the entries of closed
+        // connections are removed once counted.
+        let mut requests = MemoryMap::default();
+        requests.insert(http_request(conn, lines[0]), 11);
+        requests.insert(http_request(conn, lines[1]), 2);
+        service_map.inner.write().http_requests_map = Some(Box::new(requests));
+        let mut conns = MemoryMap::default();
+        conns.insert(conn, stats(20, false));
+        service_map.inner.write().current_conns_map = Some(Box::new(conns));
+        service_map.poll().unwrap();
+        assert_eq!(http_requests(&service_map), expected(13));
+        let inner = service_map.inner.read();
+        assert!(inner
+            .http_requests_map
+            .as_ref()
+            .unwrap()
+            .entries()
+            .unwrap()
+            .is_empty());
+    }
+
+    fn http_responses(service_map: &Arc<ServiceMap>) -> Vec<(String, String, String)> {
+        let mut registry = Registry::default();
+        registry.register_collector(Box::new(ProgramCollector(service_map.clone())));
+        let mut metrics = String::new();
+        encode(&mut metrics, &registry).unwrap();
+        let mut responses: Vec<_> = metrics
+            .lines()
+            .filter(|line| line.starts_with("http_response"))
+            .map(|line| {
+                let name = &line[..line.find('{').unwrap()];
+                let encoding = line
+                    .split_once("encoding=\"")
+                    .map_or("", |(_, rest)| &rest[..rest.find('"').unwrap()]);
+                let value = line.rsplit(' ').next().unwrap();
+                (name.to_string(), encoding.to_string(), value.to_string())
+            })
+            .collect();
+        responses.sort();
+        responses
+    }
+
+    #[test]
+    fn test_poll_counts_http_responses_by_encoding() {
+        let conn = key(1, BACKEND, FRONTEND, CONNECTION_ROLE_SERVER);
+        let mut conns = MemoryMap::default();
+        conns.insert(conn, stats(10, true));
+        let service_map = Arc::new(service_map(conns, HashMap::new()));
+        let responses = |identity: (u64, u64)| {
+            let mut responses = MemoryMap::default();
+            let gzip = HttpResponseKey {
+                conn,
+                encoding: HTTP_ENCODING_GZIP,
+            };
+            responses.insert(
+                gzip,
+                HttpResponseStats {
+                    responses: 3,
+                    body_bytes: 3000,
+                },
+            );
+            let identity_key = HttpResponseKey {
+                conn,
+                encoding: HTTP_ENCODING_IDENTITY,
+            };
+            responses.insert(
+                identity_key,
+                HttpResponseStats {
+                    responses: identity.0,
+                    body_bytes: identity.1,
+                },
+            );
+            Box::new(responses)
+        };
+        let expected = |identity: (u64, u64), ratio: &str| {
+            let row = |name: &str, encoding: &str, value: String| {
+                (name.to_string(), encoding.to_string(), value)
+            };
+            vec![
+                row("http_response_body_bytes_total", "gzip", "3000".to_string()),
+                row(
+                    "http_response_body_bytes_total",
+                    "identity",
+                    identity.1.to_string(),
+                ),
+                row("http_response_compressed_ratio", "", ratio.to_string()),
+                row("http_responses_total", "gzip", "3".to_string()),
+                row("http_responses_total", "identity", identity.0.to_string()),
+            ]
+        };
+
+        service_map.inner.write().http_responses_map = Some(responses((1, 1000)));
+        service_map.poll().unwrap();
+        assert_eq!(http_responses(&service_map), expected((1, 1000), "0.75"));
+
+        // Compression turned off: only uncompressed bodies were sent since the last poll.
+        service_map.inner.write().http_responses_map = Some(responses((5, 9000)));
+        service_map.poll().unwrap();
+        assert_eq!(http_responses(&service_map), expected((5, 9000), "0.0"));
+    }
+
+    #[test]
+    fn test_poll_merges_aliased_workloads() {
+        const OTHER_JOB: &str = "10.0.0.3";
+        let mut cache_mgr = CacheManager::empty();
+        cache_mgr.insert_workload(FRONTEND, "report-28461", "batch", "Job");
+        cache_mgr.insert_workload(OTHER_JOB, "report-28462", "batch", "Job");
+        cache_mgr.insert_workload(BACKEND, "backend", "default", "Deployment");
+        let rules = vec![AliasRule::parse(r"batch/report-\d+=report").unwrap()];
+        cache_mgr.aliases = Arc::new(WorkloadAliases::new(rules, None).unwrap());
+        let mut conns = MemoryMap::default();
+        conns.insert(
+            key(1, FRONTEND, BACKEND, CONNECTION_ROLE_CLIENT),
+            stats(10, true),
+        );
+        conns.insert(
+            key(2, OTHER_JOB, BACKEND, CONNECTION_ROLE_CLIENT),
+            stats(20, true),
+        );
+        let service_map = ServiceMap::with_connections(Box::new(conns), cache_mgr, HashMap::new());
+
+        service_map.poll().unwrap();
+        let edges = sorted_edges(&service_map);
+        assert_eq!(edges.len(), 1);
+        assert_eq!(
+            (edges[0].0.as_str(), edges[0].1.as_str()),
+            ("report", "backend")
+        );
+        assert_eq!(edges[0].2.bytes_sent, 30);
+    }
+
+    #[test]
+    fn test_metadata_schema_rejects_invalid_values() {
+        let schema = ServiceMap::new().metadata_schema();
+        let metadata = schema
+            .validate(&HashMap::from([("owner".to_string(), "acme".to_string())]))
+            .unwrap();
+        assert_eq!(metadata["owner"], "acme");
+        assert_eq!(metadata["interval"], "15");
+        assert_eq!(metadata["mesh_mode"], "raw");
+
+        let errors = schema
+            .validate(&HashMap::from([
+                ("interval".to_string(), "soon".to_string()),
+                ("mesh_mode".to_string(), "sidecarless".to_string()),
+                ("mesh_sidecar_ports".to_string(), "15001,envoy".to_string()),
+                (
+                    "slo.checkout".to_string(),
+                    "default/frontend->default/checkout p99".to_string(),
+                ),
+                ("snapshot_window".to_string(), "600".to_string()),
+            ]))
+            .unwrap_err();
+        let keys: Vec<&str> = errors.0.iter().map(|e| e.key.as_str()).collect();
+        assert_eq!(
+            keys,
+            [
+                "interval",
+                "mesh_mode",
+                "mesh_sidecar_ports",
+                "slo.checkout"
+            ]
+        );
+    }
 }
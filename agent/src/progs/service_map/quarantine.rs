@@ -0,0 +1,76 @@
+use std::collections::HashMap;
+
+use ahash::AHashMap;
+
+use conn_tracer_common::{ConnectionKey, ConnectionStats};
+
+use crate::common::constants::DEFAULT_UNKNOWN_IP_RETRIES;
+
+/// Connections whose workloads could not be resolved yet, e.g. because the pod
+/// informer is behind. They are retried on the next polls and attributed to the
+/// `unknown` workload once the retries run out, so that their traffic is never lost.
+///
+/// An open connection keeps its totals in the connections map, so only the polls it
+/// went unresolved are counted. A closed one is removed from the map, so its last
+/// totals are kept here until it resolves.
+#[derive(Debug, Default)]
+pub(crate) struct Quarantine {
+    /// Polls each open connection went unresolved in a row.
+    open: AHashMap<ConnectionKey, u32>,
+    closed: Vec<Pending>,
+}
+
+/// A closed connection waiting to be resolved.
+#[derive(Debug)]
+pub(crate) struct Pending {
+    pub(crate) key: ConnectionKey,
+    pub(crate) stats: ConnectionStats,
+    /// Polls it went unresolved, open or closed.
+    pub(crate) attempts: u32,
+}
+
+impl Quarantine {
+    /// Polls an open connection went unresolved before this one.
+    pub(crate) fn open_attempts(&self, key: &ConnectionKey) -> u32 {
+        self.open.get(key).copied().unwrap_or_default()
+    }
+
+    /// Replaces the open connections still unresolved with those of the last poll, so
+    /// that connections resolved or closed since are forgotten.
+    pub(crate) fn update_open(&mut self, unresolved: AHashMap<ConnectionKey, u32>) {
+        self.open = unresolved;
+    }
+
+    /// Quarantines a connection that closed unresolved, after `attempts` polls.
+    pub(crate) fn defer_closed(
+        &mut self,
+        key: ConnectionKey,
+        stats: ConnectionStats,
+        attempts: u32,
+    ) {
+        self.closed.push(Pending {
+            key,
+            stats,
+            attempts,
+        });
+    }
+
+    /// Takes the closed connections to retry.
+    pub(crate) fn take_closed(&mut self) -> Vec<Pending> {
+        std::mem::take(&mut self.closed)
+    }
+
+    /// Puts back a closed connection that is still unresolved.
+    pub(crate) fn requeue(&mut self, mut pending: Pending) {
+        pending.attempts += 1;
+        self.closed.push(pending);
+    }
+}
+
+/// Polls a connection is retried before being attributed to the `unknown` workload.
+pub(crate) fn unknown_ip_retries(metadata: &HashMap<String, String>) -> u32 {
+    metadata
+        .get("unknown_ip_retries")
+        .and_then(|n| n.parse::<u32>().ok())
+        .unwrap_or(DEFAULT_UNKNOWN_IP_RETRIES)
+}
@@ -0,0 +1,251 @@
+use std::time::{Duration, Instant};
+
+use ahash::AHashMap;
+use conn_tracer_common::{ConnectionKey, ConnectionStats, QuicInitial};
+use ring::aead::quic::{HeaderProtectionKey, AES_128};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_128_GCM};
+use ring::hkdf::{KeyType, Prk, Salt, HKDF_SHA256};
+
+use crate::progs::service_map::tls::TlsSession;
+
+/// Salt the Initial secrets of QUIC version 1 are extracted with, from RFC 9001.
+const INITIAL_SALT: [u8; 20] = [
+    0x38, 0x76, 0x2c, 0xf7, 0xf5, 0x59, 0x34, 0xb3, 0x4d, 0x17, 0x9a, 0xe6, 0xa4, 0xc8, 0x0c, 0xad,
+    0xcc, 0xbb, 0x7f, 0x0a,
+];
+
+const FRAME_PADDING: u64 = 0x00;
+const FRAME_PING: u64 = 0x01;
+const FRAME_CRYPTO: u64 = 0x06;
+
+/// Returns the server name a QUIC client asked for, from the ClientHello in the first
+/// Initial packet of the datagram captured by the tracer. Initial packets are encrypted
+/// with keys derived from the connection ID they carry, which only keeps middleboxes
+/// from tampering with them, so anyone seeing one can read it.
+///
+/// A ClientHello too large for one packet, e.g. with post-quantum key shares, only
+/// gives its name when it comes before the cut.
+pub(crate) fn server_name(initial: &QuicInitial) -> Option<String> {
+    let handshake = crypto_stream(&decrypt_initial(initial.captured())?);
+    TlsSession::from_messages(&handshake).server_name
+}
+
+/// Decrypts the Initial packet a datagram starts with, and returns its frames.
+fn decrypt_initial(datagram: &[u8]) -> Option<Vec<u8>> {
+    // Past the first byte and the version, the destination and source connection IDs,
+    // the token and the length of the rest of the packet.
+    let mut pos = 5;
+    let dcid_len = *datagram.get(pos)? as usize;
+    let dcid = datagram.get(pos + 1..pos + 1 + dcid_len)?;
+    pos += 1 + dcid_len;
+    pos += 1 + *datagram.get(pos)? as usize;
+    let token_len = varint(datagram, &mut pos)?;
+    pos = pos.checked_add(usize::try_from(token_len).ok()?)?;
+    let len = varint(datagram, &mut pos)?;
+    let end = pos.checked_add(usize::try_from(len).ok()?)?;
+    let mut packet = datagram.get(..end)?.to_vec();
+
+    // The header protection mask is computed from a sample of the payload starting 4
+    // bytes past the start of the packet number, whatever its length.
+    let keys = InitialKeys::client(dcid)?;
+    let mask = keys.hp.new_mask(packet.get(pos + 4..pos + 20)?).ok()?;
+    packet[0] ^= mask[0] & 0x0f;
+    let pn_len = (packet[0] & 0x03) as usize + 1;
+    let mut packet_number = 0;
+    for i in 0..pn_len {
+        packet[pos + i] ^= mask[1 + i];
+        packet_number = packet_number << 8 | packet[pos + i] as u64;
+    }
+    let (header, payload) = packet.split_at_mut(pos + pn_len);
+    Some(keys.open(packet_number, header, payload)?.to_vec())
+}
+
+/// Reassembles the start of the handshake from the CRYPTO frames of a packet, which
+/// some clients send out of order, and returns the bytes following on from offset 0.
+fn crypto_stream(frames: &[u8]) -> Vec<u8> {
+    let mut chunks = Vec::new();
+    let mut pos = 0;
+    while pos < frames.len() {
+        match varint(frames, &mut pos) {
+            Some(FRAME_PADDING | FRAME_PING) => {}
+            Some(FRAME_CRYPTO) => {
+                let chunk = (|| {
+                    let offset = usize::try_from(varint(frames, &mut pos)?).ok()?;
+                    let len = usize::try_from(varint(frames, &mut pos)?).ok()?;
+                    let data = frames.get(pos..pos.checked_add(len)?)?;
+                    pos += len;
+                    Some((offset, data))
+                })();
+                match chunk {
+                    Some(chunk) => chunks.push(chunk),
+                    None => break,
+                }
+            }
+            // The first packet of a client has nothing to acknowledge, and Initial
+            // packets carry no other frames than these and CONNECTION_CLOSE.
+            _ => break,
+        }
+    }
+    chunks.sort_by_key(|(offset, _)| *offset);
+    let mut stream = Vec::new();
+    for (offset, data) in chunks {
+        if offset > stream.len() {
+            break;
+        }
+        stream.extend_from_slice(data.get(stream.len() - offset..).unwrap_or_default());
+    }
+    stream
+}
+
+/// Reads a variable-length integer, whose two high bits give its length.
+fn varint(bytes: &[u8], pos: &mut usize) -> Option<u64> {
+    let first = *bytes.get(*pos)?;
+    let len = 1 << (first >> 6);
+    let bytes = bytes.get(*pos..*pos + len)?;
+    *pos += len;
+    Some(
+        bytes[1..]
+            .iter()
+            .fold((first & 0x3f) as u64, |n, &b| n << 8 | b as u64),
+    )
+}
+
+/// The keys protecting the Initial packets sent by a client.
+pub(crate) struct InitialKeys {
+    key: LessSafeKey,
+    iv: [u8; 12],
+    hp: HeaderProtectionKey,
+}
+
+impl InitialKeys {
+    /// Derives the keys of the client that chose `dcid` as the destination connection
+    /// ID of its first Initial packet.
+    pub(crate) fn client(dcid: &[u8]) -> Option<Self> {
+        let initial_secret = Salt::new(HKDF_SHA256, &INITIAL_SALT).extract(dcid);
+        let client_secret = expand_label(&initial_secret, b"client in", 32)?;
+        let client_secret = Prk::new_less_safe(HKDF_SHA256, &client_secret);
+        let key = expand_label(&client_secret, b"quic key", 16)?;
+        let iv = expand_label(&client_secret, b"quic iv", 12)?;
+        let hp = expand_label(&client_secret, b"quic hp", 16)?;
+        Some(Self {
+            key: LessSafeKey::new(UnboundKey::new(&AES_128_GCM, &key).ok()?),
+            iv: iv.try_into().ok()?,
+            hp: HeaderProtectionKey::new(&AES_128, &hp).ok()?,
+        })
+    }
+
+    fn nonce(&self, packet_number: u64) -> Nonce {
+        let mut nonce = self.iv;
+        for (n, pn) in nonce[4..].iter_mut().zip(packet_number.to_be_bytes()) {
+            *n ^= pn;
+        }
+        Nonce::assume_unique_for_key(nonce)
+    }
+
+    fn open<'a>(
+        &self,
+        packet_number: u64,
+        header: &[u8],
+        payload: &'a mut [u8],
+    ) -> Option<&'a mut [u8]> {
+        self.key
+            .open_in_place(self.nonce(packet_number), Aad::from(header), payload)
+            .ok()
+    }
+
+    /// Builds the Initial datagram of a client carrying `handshake` in a CRYPTO frame,
+    /// padded to the 1200 bytes clients pad theirs to.
+    #[cfg(test)]
+    pub(crate) fn client_initial(dcid: &[u8], handshake: &[u8]) -> Vec<u8> {
+        let keys = Self::client(dcid).unwrap();
+        let packet_number = 0u8;
+        let mut payload = vec![FRAME_CRYPTO as u8, 0];
+        payload.extend_from_slice(&(0x4000 | handshake.len() as u16).to_be_bytes());
+        payload.extend_from_slice(handshake);
+        let header_len = 7 + dcid.len() + 1 + 2 + 1;
+        payload.resize(1200 - header_len - 16, FRAME_PADDING as u8);
+
+        let mut header = vec![0xc0, 0, 0, 0, 1, dcid.len() as u8];
+        header.extend_from_slice(dcid);
+        // No source connection ID nor token.
+        header.extend_from_slice(&[0, 0]);
+        header.extend_from_slice(&(0x4000 | (1 + payload.len() + 16) as u16).to_be_bytes());
+        header.push(packet_number);
+        let tag = keys
+            .key
+            .seal_in_place_separate_tag(
+                keys.nonce(packet_number as u64),
+                Aad::from(&header),
+                &mut payload,
+            )
+            .unwrap();
+        let pn_offset = header.len() - 1;
+        let mut packet = header;
+        packet.extend_from_slice(&payload);
+        packet.extend_from_slice(tag.as_ref());
+        let mask = keys
+            .hp
+            .new_mask(&packet[pn_offset + 4..pn_offset + 20])
+            .unwrap();
+        packet[0] ^= mask[0] & 0x0f;
+        packet[pn_offset] ^= mask[1];
+        packet
+    }
+}
+
+/// HKDF-Expand-Label of TLS 1.3, with an empty context.
+fn expand_label(secret: &Prk, label: &[u8], len: usize) -> Option<Vec<u8>> {
+    let out_len = (len as u16).to_be_bytes();
+    let label_len = [(b"tls13 ".len() + label.len()) as u8];
+    let info: [&[u8]; 5] = [&out_len, &label_len, b"tls13 ", label, &[0]];
+    let mut out = vec![0; len];
+    secret
+        .expand(&info, OutputLen(len))
+        .and_then(|okm| okm.fill(&mut out))
+        .ok()?;
+    Some(out)
+}
+
+struct OutputLen(usize);
+
+impl KeyType for OutputLen {
+    fn len(&self) -> usize {
+        self.0
+    }
+}
+
+/// When each open QUIC flow last carried traffic. The kernel sees no end to QUIC
+/// connections, so flows idle for longer than `quic_idle_timeout`, past the idle
+/// timeout most QUIC stacks negotiate, are taken as closed.
+#[derive(Debug, Default)]
+pub(crate) struct QuicFlows {
+    active: AHashMap<ConnectionKey, (u64, Instant)>,
+}
+
+impl QuicFlows {
+    /// Returns whether a flow carried nothing for `timeout`. Otherwise, records in
+    /// `next` since when its totals are what they are.
+    pub(crate) fn idle(
+        &self,
+        key: &ConnectionKey,
+        stats: &ConnectionStats,
+        timeout: Duration,
+        now: Instant,
+        next: &mut AHashMap<ConnectionKey, (u64, Instant)>,
+    ) -> bool {
+        let bytes = stats.bytes_sent + stats.bytes_received;
+        let since = match self.active.get(key) {
+            Some(&(last, since)) if last == bytes => since,
+            _ => now,
+        };
+        if now.duration_since(since) >= timeout {
+            return true;
+        }
+        next.insert(*key, (bytes, since));
+        false
+    }
+
+    pub(crate) fn update(&mut self, active: AHashMap<ConnectionKey, (u64, Instant)>) {
+        self.active = active;
+    }
+}
@@ -0,0 +1,90 @@
+use ahash::AHashMap;
+use parking_lot::Mutex;
+
+use conn_tracer_common::ConnectionKey;
+
+use crate::managers::symbol::Symbol;
+use crate::progs::service_map::program::Connection;
+
+/// What a connection is resolved from: its key, the protocol it speaks and the server
+/// name it asked for, which may all be learnt after it was first seen.
+type ResolutionKey = (ConnectionKey, u32, Option<Symbol>);
+
+/// Connections already resolved to their workloads, so that steady polls don't look
+/// the same addresses up again. The workload of an address only changes with the
+/// cache manager, so the resolutions are dropped whenever its generation moves on.
+/// Those not used for a whole poll are dropped too, their connections having closed.
+#[derive(Debug, Default)]
+pub(crate) struct Resolutions {
+    inner: Mutex<Generations>,
+}
+
+#[derive(Debug, Default)]
+struct Generations {
+    /// Generation of the cache manager the resolutions were made in.
+    cache_generation: u64,
+    /// Resolutions used in the current poll, and those of the previous one.
+    current: AHashMap<ResolutionKey, Connection>,
+    previous: AHashMap<ResolutionKey, Connection>,
+}
+
+impl Resolutions {
+    /// Returns the connection `key` was resolved to, if the cache manager is still at
+    /// `cache_generation`.
+    pub(crate) fn get(
+        &self,
+        key: &ConnectionKey,
+        protocol: u32,
+        server_name: Option<&Symbol>,
+        cache_generation: u64,
+    ) -> Option<Connection> {
+        let mut inner = self.inner.lock();
+        if inner.cache_generation != cache_generation {
+            inner.cache_generation = cache_generation;
+            inner.current.clear();
+            inner.previous.clear();
+            return None;
+        }
+        let key = (*key, protocol, server_name.cloned());
+        if let Some(conn) = inner.current.get(&key) {
+            return Some(conn.clone());
+        }
+        let conn = inner.previous.remove(&key)?;
+        inner.current.insert(key, conn.clone());
+        Some(conn)
+    }
+
+    pub(crate) fn insert(
+        &self,
+        key: &ConnectionKey,
+        protocol: u32,
+        server_name: Option<&Symbol>,
+        cache_generation: u64,
+        conn: Connection,
+    ) {
+        let mut inner = self.inner.lock();
+        if inner.cache_generation == cache_generation {
+            inner
+                .current
+                .insert((*key, protocol, server_name.cloned()), conn);
+        }
+    }
+
+    /// Ends a poll, dropping the resolutions it didn't use.
+    pub(crate) fn rotate(&self) {
+        let mut inner = self.inner.lock();
+        inner.previous = std::mem::take(&mut inner.current);
+    }
+
+    pub(crate) fn clear(&self) {
+        let mut inner = self.inner.lock();
+        inner.current.clear();
+        inner.previous.clear();
+    }
+
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        let inner = self.inner.lock();
+        inner.current.len() + inner.previous.len()
+    }
+}
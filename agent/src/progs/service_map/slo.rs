@@ -0,0 +1,503 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::AtomicU64;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Error;
+use log::warn;
+use prometheus_client::encoding::{DescriptorEncoder, EncodeLabelSet, EncodeMetric};
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
+
+use crate::managers::cache::Workload;
+use crate::progs::service_map::events::publish_warning;
+use crate::progs::service_map::program::Connection;
+
+/// Metadata keys starting with this prefix declare an SLO, named after the rest of the
+/// key, e.g. `slo.checkout-latency=default/frontend->default/checkout p99<200ms`.
+pub(crate) const SLO_PREFIX: &str = "slo.";
+const SHORT_WINDOW: Duration = Duration::from_secs(5 * 60);
+const LONG_WINDOW: Duration = Duration::from_secs(60 * 60);
+/// Burn rate at which 2% of a 30 day budget is spent within the long window.
+const FAST_BURN_RATE: f64 = 14.4;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Objective {
+    /// At most `1 - quantile` of the connections may last longer than `threshold`.
+    Latency { quantile: f64, threshold: Duration },
+    /// At most `max` of the connections may end with a reset or a connect timeout.
+    ErrorRate { max: f64 },
+}
+
+impl Objective {
+    fn budget(&self) -> f64 {
+        match self {
+            Objective::Latency { quantile, .. } => 1.0 - quantile,
+            Objective::ErrorRate { max } => *max,
+        }
+    }
+}
+
+/// Matches a workload by `namespace/name`, or any workload when `None`.
+#[derive(Debug, Clone, PartialEq)]
+struct Selector(Option<(String, String)>);
+
+impl Selector {
+    fn parse(s: &str) -> Result<Self, Error> {
+        if s == "*" {
+            return Ok(Selector(None));
+        }
+        let (namespace, name) = s.split_once('/').ok_or(anyhow::anyhow!(
+            "expected <namespace>/<name> or *, got {:?}",
+            s
+        ))?;
+        Ok(Selector(Some((namespace.to_string(), name.to_string()))))
+    }
+
+    fn matches(&self, workload: &Workload) -> bool {
+        match &self.0 {
+            None => true,
+            Some((namespace, name)) => {
+                workload.namespace.as_str() == namespace && workload.name.as_str() == name
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Slo {
+    name: String,
+    client: Selector,
+    server: Selector,
+    objective: Objective,
+}
+
+impl Slo {
+    /// Parses `<client>-><server> p<quantile><<duration>` or
+    /// `<client>-><server> error_rate<<percent>%`.
+    fn parse(name: &str, spec: &str) -> Result<Self, Error> {
+        let (edge, objective) = spec
+            .trim()
+            .split_once(char::is_whitespace)
+            .ok_or(anyhow::anyhow!("expected an edge and an objective"))?;
+        let (client, server) = edge.split_once("->").ok_or(anyhow::anyhow!(
+            "expected <client>-><server>, got {:?}",
+            edge
+        ))?;
+        let (indicator, target) = objective
+            .trim()
+            .split_once('<')
+            .ok_or(anyhow::anyhow!("expected <indicator><<target>"))?;
+        let (indicator, target) = (indicator.trim(), target.trim());
+
+        let objective = if indicator == "error_rate" {
+            let percent = target
+                .strip_suffix('%')
+                .ok_or(anyhow::anyhow!("error rate target must be a percentage"))?;
+            Objective::ErrorRate {
+                max: percent.trim().parse::<f64>()? / 100.0,
+            }
+        } else if let Some(quantile) = indicator.strip_prefix('p') {
+            let quantile = quantile.parse::<f64>()? / 100.0;
+            if !(0.0..1.0).contains(&quantile) {
+                return Err(anyhow::anyhow!("quantile {} out of range", indicator));
+            }
+            Objective::Latency {
+                quantile,
+                threshold: parse_duration(target)?,
+            }
+        } else {
+            return Err(anyhow::anyhow!("unknown indicator {:?}", indicator));
+        };
+
+        Ok(Slo {
+            name: name.to_string(),
+            client: Selector::parse(client.trim())?,
+            server: Selector::parse(server.trim())?,
+            objective,
+        })
+    }
+}
+
+/// Checks the spec of the SLO declared by metadata key `key`.
+pub(crate) fn validate_slo(key: &str, spec: &str) -> Result<(), String> {
+    let name = key.strip_prefix(SLO_PREFIX).unwrap_or(key);
+    Slo::parse(name, spec)
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+fn parse_duration(s: &str) -> Result<Duration, Error> {
+    let split = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .ok_or(anyhow::anyhow!("duration {:?} has no unit", s))?;
+    let (value, unit) = s.split_at(split);
+    let value = value.parse::<f64>()?;
+    let secs = match unit {
+        "us" => value / 1_000_000.0,
+        "ms" => value / 1_000.0,
+        "s" => value,
+        "m" => value * 60.0,
+        _ => return Err(anyhow::anyhow!("unknown duration unit {:?}", unit)),
+    };
+    Ok(Duration::from_secs_f64(secs))
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Bucket {
+    good: u64,
+    bad: u64,
+}
+
+#[derive(Debug)]
+struct SloState {
+    slo: Slo,
+    pending: Bucket,
+    buckets: VecDeque<(Instant, Bucket)>,
+    fast_burning: bool,
+    last_server: Option<Arc<Workload>>,
+}
+
+impl SloState {
+    fn burn_rate(&self, window: Duration, now: Instant) -> f64 {
+        let total = self
+            .buckets
+            .iter()
+            .filter(|(at, _)| now.duration_since(*at) <= window)
+            .fold(Bucket::default(), |acc, (_, b)| Bucket {
+                good: acc.good + b.good,
+                bad: acc.bad + b.bad,
+            });
+        let events = total.good + total.bad;
+        if events == 0 {
+            return 0.0;
+        }
+        (total.bad as f64 / events as f64) / self.slo.objective.budget()
+    }
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct BurnLabels {
+    slo: String,
+    window: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct SloLabels {
+    slo: String,
+}
+
+/// An SLO whose budget started burning faster than `FAST_BURN_RATE` in both windows.
+#[derive(Debug)]
+pub(crate) struct FastBurn {
+    name: String,
+    short_burn: f64,
+    long_burn: f64,
+    server: Option<Arc<Workload>>,
+}
+
+/// Evaluates the SLOs declared in the program metadata against the connections closed
+/// on matching edges. Burn rates are computed over a short and a long window on every
+/// poll, and an SLO is reported once each time it starts burning fast in both.
+#[derive(Debug, Default)]
+pub(crate) struct SloSet {
+    slos: Vec<SloState>,
+    burn_rates: Family<BurnLabels, Gauge<f64, AtomicU64>>,
+    fast_burns: Family<SloLabels, Counter>,
+}
+
+impl SloSet {
+    pub(crate) fn from_metadata(metadata: &HashMap<String, String>) -> Result<Self, Error> {
+        let mut slos = Vec::new();
+        for (key, spec) in metadata.iter() {
+            let Some(name) = key.strip_prefix(SLO_PREFIX) else {
+                continue;
+            };
+            let slo = Slo::parse(name, spec)
+                .map_err(|e| anyhow::anyhow!("Invalid SLO {}: {}", name, e))?;
+            slos.push(SloState {
+                slo,
+                pending: Bucket::default(),
+                buckets: VecDeque::new(),
+                fast_burning: false,
+                last_server: None,
+            });
+        }
+        Ok(Self {
+            slos,
+            ..Default::default()
+        })
+    }
+
+    /// Accounts a closed connection. `duration` is `None` when its lifetime is unknown.
+    pub(crate) fn observe(&mut self, conn: &Connection, duration: Option<Duration>, errored: bool) {
+        for state in self.slos.iter_mut() {
+            if !state.slo.client.matches(&conn.client) || !state.slo.server.matches(&conn.server) {
+                continue;
+            }
+            let bad = match state.slo.objective {
+                Objective::Latency { threshold, .. } => match duration {
+                    Some(duration) => duration > threshold,
+                    None => continue,
+                },
+                Objective::ErrorRate { .. } => errored,
+            };
+            if bad {
+                state.pending.bad += 1;
+            } else {
+                state.pending.good += 1;
+            }
+            state.last_server = Some(conn.server.clone());
+        }
+    }
+
+    /// Closes the current bucket of every SLO and returns those that just started
+    /// burning fast.
+    pub(crate) fn evaluate(&mut self, now: Instant) -> Vec<FastBurn> {
+        let mut started = Vec::new();
+        for state in self.slos.iter_mut() {
+            let bucket = std::mem::take(&mut state.pending);
+            state.buckets.push_back((now, bucket));
+            while let Some((at, _)) = state.buckets.front() {
+                if now.duration_since(*at) <= LONG_WINDOW {
+                    break;
+                }
+                state.buckets.pop_front();
+            }
+
+            let short_burn = state.burn_rate(SHORT_WINDOW, now);
+            let long_burn = state.burn_rate(LONG_WINDOW, now);
+            for (window, burn) in [("5m", short_burn), ("1h", long_burn)] {
+                self.burn_rates
+                    .get_or_create(&BurnLabels {
+                        slo: state.slo.name.clone(),
+                        window: window.to_string(),
+                    })
+                    .set(burn);
+            }
+
+            let fast = short_burn >= FAST_BURN_RATE && long_burn >= FAST_BURN_RATE;
+            if fast && !state.fast_burning {
+                self.fast_burns
+                    .get_or_create(&SloLabels {
+                        slo: state.slo.name.clone(),
+                    })
+                    .inc();
+                started.push(FastBurn {
+                    name: state.slo.name.clone(),
+                    short_burn,
+                    long_burn,
+                    server: state.last_server.clone(),
+                });
+            }
+            state.fast_burning = fast;
+        }
+        started
+    }
+
+    pub(crate) fn encode(&self, encoder: &mut DescriptorEncoder) -> Result<(), Error> {
+        let metric_encoder = encoder.encode_descriptor(
+            "slo_burn_rate",
+            "rate at which the error budget of an SLO is spent, by window",
+            None,
+            self.burn_rates.metric_type(),
+        )?;
+        self.burn_rates.encode(metric_encoder)?;
+
+        let metric_encoder = encoder.encode_descriptor(
+            "slo_fast_burn_events",
+            "times an SLO started burning its error budget fast",
+            None,
+            self.fast_burns.metric_type(),
+        )?;
+        self.fast_burns.encode(metric_encoder)?;
+
+        Ok(())
+    }
+}
+
+/// Logs a fast burn and records it as a Kubernetes event on the server workload.
+pub(crate) async fn publish_fast_burn(burn: FastBurn) {
+    let message = format!(
+        "SLO {} is burning its error budget fast: {:.1}x over 5m, {:.1}x over 1h",
+        burn.name, burn.short_burn, burn.long_burn
+    );
+    warn!("{}", message);
+
+    if let Some(server) = burn.server {
+        publish_warning(
+            server,
+            format!("slo-{}-", burn.name),
+            "SLOBudgetBurning",
+            message,
+        )
+        .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::managers::symbol::SymbolTable;
+
+    fn workload(symbols: &SymbolTable, name: &str) -> Arc<Workload> {
+        Arc::new(Workload {
+            name: symbols.intern(name),
+            namespace: symbols.intern("default"),
+            kind: symbols.intern("Deployment"),
+            labels: Vec::new(),
+        })
+    }
+
+    fn connection(symbols: &SymbolTable, client: &str, server: &str) -> Connection {
+        Connection {
+            client: workload(symbols, client),
+            server: workload(symbols, server),
+            role: 0,
+            server_port: 8080,
+            protocol: 0,
+            loopback: false,
+        }
+    }
+
+    fn slo_set(spec: &str) -> SloSet {
+        SloSet::from_metadata(&HashMap::from([(
+            "slo.checkout".to_string(),
+            spec.to_string(),
+        )]))
+        .unwrap()
+    }
+
+    /// Observes `good` and `bad` connections from the frontend to checkout.
+    fn observe_errors(set: &mut SloSet, symbols: &SymbolTable, good: u64, bad: u64) {
+        let conn = connection(symbols, "frontend", "checkout");
+        for _ in 0..good {
+            set.observe(&conn, None, false);
+        }
+        for _ in 0..bad {
+            set.observe(&conn, None, true);
+        }
+    }
+
+    #[test]
+    fn test_parse_slo() {
+        let slo = Slo::parse("latency", "default/frontend->* p99<200ms").unwrap();
+        assert_eq!(
+            slo.client,
+            Selector(Some(("default".to_string(), "frontend".to_string())))
+        );
+        assert_eq!(slo.server, Selector(None));
+        match slo.objective {
+            Objective::Latency {
+                quantile,
+                threshold,
+            } => {
+                assert!((quantile - 0.99).abs() < 1e-9);
+                assert_eq!(threshold, Duration::from_millis(200));
+            }
+            objective => panic!("unexpected objective {:?}", objective),
+        }
+        assert!((slo.objective.budget() - 0.01).abs() < 1e-9);
+
+        let slo = Slo::parse("errors", "*->default/checkout error_rate<0.5%").unwrap();
+        assert_eq!(slo.objective, Objective::ErrorRate { max: 0.005 });
+
+        assert!(Slo::parse("errors", "*->* error_rate<5").is_err());
+        assert!(Slo::parse("latency", "*->* p100<1s").is_err());
+        assert!(Slo::parse("latency", "*->* p99<1h").is_err());
+        assert!(Slo::parse("latency", "frontend->* p99<1s").is_err());
+    }
+
+    #[test]
+    fn test_burn_rate_of_error_budget() {
+        let symbols = SymbolTable::default();
+        let mut set = slo_set("*->default/checkout error_rate<1%");
+        let now = Instant::now();
+
+        // 2% of errors burn a 1% budget twice as fast as it lasts.
+        observe_errors(&mut set, &symbols, 98, 2);
+        assert!(set.evaluate(now).is_empty());
+        assert!((set.slos[0].burn_rate(SHORT_WINDOW, now) - 2.0).abs() < 1e-9);
+        assert!((set.slos[0].burn_rate(LONG_WINDOW, now) - 2.0).abs() < 1e-9);
+
+        // Other edges don't count.
+        let other = connection(&symbols, "frontend", "cart");
+        set.observe(&other, None, true);
+        assert!(set.slos[0].pending.bad == 0 && set.slos[0].pending.good == 0);
+    }
+
+    #[test]
+    fn test_fast_burn_reported_once() {
+        let symbols = SymbolTable::default();
+        let mut set = slo_set("*->default/checkout error_rate<1%");
+        let now = Instant::now();
+
+        observe_errors(&mut set, &symbols, 80, 20);
+        let burns = set.evaluate(now);
+        assert_eq!(burns.len(), 1);
+        assert_eq!(burns[0].name, "checkout");
+        assert!((burns[0].short_burn - 20.0).abs() < 1e-9);
+        assert_eq!(burns[0].server.as_ref().unwrap().name.as_str(), "checkout");
+
+        // Still burning fast, but already reported.
+        observe_errors(&mut set, &symbols, 80, 20);
+        assert!(set.evaluate(now + Duration::from_secs(15)).is_empty());
+
+        // Reported again once it stopped burning fast and started over.
+        observe_errors(&mut set, &symbols, 100_000, 0);
+        assert!(set.evaluate(now + Duration::from_secs(30)).is_empty());
+        assert!(!set.slos[0].fast_burning);
+        observe_errors(&mut set, &symbols, 0, 100_000);
+        assert_eq!(set.evaluate(now + Duration::from_secs(45)).len(), 1);
+    }
+
+    #[test]
+    fn test_burn_rate_without_traffic() {
+        let symbols = SymbolTable::default();
+        let mut set = slo_set("*->* p99<100ms");
+        let now = Instant::now();
+        assert!(set.evaluate(now).is_empty());
+        assert_eq!(set.slos[0].burn_rate(SHORT_WINDOW, now), 0.0);
+        assert_eq!(set.slos[0].burn_rate(LONG_WINDOW, now), 0.0);
+
+        // Connections of unknown lifetime say nothing about latency.
+        let conn = connection(&symbols, "frontend", "checkout");
+        set.observe(&conn, None, true);
+        assert!(set.evaluate(now + Duration::from_secs(15)).is_empty());
+        assert_eq!(set.slos[0].burn_rate(LONG_WINDOW, now), 0.0);
+
+        set.observe(&conn, Some(Duration::from_millis(150)), false);
+        set.observe(&conn, Some(Duration::from_millis(50)), false);
+        set.evaluate(now + Duration::from_secs(30));
+        let burn = set.slos[0].burn_rate(LONG_WINDOW, now + Duration::from_secs(30));
+        assert!((burn - 50.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_burn_rate_window_boundaries() {
+        let symbols = SymbolTable::default();
+        let mut set = slo_set("*->* error_rate<10%");
+        let start = Instant::now();
+
+        observe_errors(&mut set, &symbols, 0, 10);
+        set.evaluate(start);
+        observe_errors(&mut set, &symbols, 10, 0);
+        set.evaluate(start + SHORT_WINDOW);
+
+        // A bucket exactly as old as a window is still within it.
+        let at = start + SHORT_WINDOW;
+        assert!((set.slos[0].burn_rate(SHORT_WINDOW, at) - 5.0).abs() < 1e-9);
+        let at = start + SHORT_WINDOW + Duration::from_secs(1);
+        assert_eq!(set.slos[0].burn_rate(SHORT_WINDOW, at), 0.0);
+        assert!((set.slos[0].burn_rate(LONG_WINDOW, at) - 5.0).abs() < 1e-9);
+
+        // Buckets older than the long window are dropped.
+        set.evaluate(start + LONG_WINDOW);
+        assert_eq!(set.slos[0].buckets.len(), 3);
+        set.evaluate(start + LONG_WINDOW + Duration::from_secs(1));
+        assert_eq!(set.slos[0].buckets.len(), 3);
+        let at = start + LONG_WINDOW + Duration::from_secs(1);
+        assert_eq!(set.slos[0].burn_rate(LONG_WINDOW, at), 0.0);
+    }
+}
@@ -0,0 +1,279 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+
+use ahash::AHashMap;
+
+use agent_api::snapshot_checksum;
+use agent_api::v1::{ServiceMapEdge, ServiceMapSnapshot};
+
+use crate::common::constants::{
+    DEFAULT_SNAPSHOT_COMPACTION, DEFAULT_SNAPSHOT_RETENTION, DEFAULT_SNAPSHOT_WINDOW,
+};
+use crate::common::graph::GraphEdge;
+use crate::managers::cache::Workload;
+use crate::progs::types::SnapshotQuery;
+
+/// How long snapshots are kept, read from the `snapshot_window`, `snapshot_retention`
+/// and `snapshot_compaction` metadata keys, in seconds.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct SnapshotConfig {
+    /// Every poll is kept for this long.
+    window: Duration,
+    /// Older snapshots are kept for this long, at most one per `compaction`.
+    retention: Duration,
+    compaction: Duration,
+}
+
+impl SnapshotConfig {
+    pub(crate) fn from_metadata(metadata: &HashMap<String, String>) -> Self {
+        let secs = |key: &str, default: u64| {
+            Duration::from_secs(
+                metadata
+                    .get(key)
+                    .and_then(|v| v.parse::<u64>().ok())
+                    .unwrap_or(default),
+            )
+        };
+        let window = secs("snapshot_window", DEFAULT_SNAPSHOT_WINDOW);
+        Self {
+            window,
+            retention: secs("snapshot_retention", DEFAULT_SNAPSHOT_RETENTION).max(window),
+            compaction: secs("snapshot_compaction", DEFAULT_SNAPSHOT_COMPACTION),
+        }
+    }
+}
+
+impl Default for SnapshotConfig {
+    fn default() -> Self {
+        Self::from_metadata(&HashMap::new())
+    }
+}
+
+#[derive(Debug)]
+struct Snapshot {
+    /// Number of the poll, from 1.
+    id: u64,
+    timestamp_ns: u64,
+    /// Every edge with its throughput since the previous poll, in bytes per second.
+    edges: Vec<(GraphEdge, f64)>,
+}
+
+/// A time-ordered ring of service map snapshots, taken on every poll. Snapshots that
+/// leave the full-resolution window are thinned out, then dropped past the retention.
+#[derive(Debug, Default)]
+pub(crate) struct SnapshotRing {
+    config: SnapshotConfig,
+    snapshots: VecDeque<Snapshot>,
+    /// Number of snapshots at the front that are already outside the window.
+    compacted: usize,
+    /// Snapshots recorded so far, compacted ones included.
+    recorded: u64,
+}
+
+impl SnapshotRing {
+    pub(crate) fn new(config: SnapshotConfig) -> Self {
+        Self {
+            config,
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn record(&mut self, timestamp_ns: u64, edges: Vec<GraphEdge>) {
+        // Throughput is taken against the previous poll before it can be compacted
+        // away. Edges new in this poll have none yet.
+        let edges = match self.snapshots.back() {
+            Some(prev) if timestamp_ns > prev.timestamp_ns => {
+                let elapsed = (timestamp_ns - prev.timestamp_ns) as f64 / 1_000_000_000.0;
+                let sent: AHashMap<_, _> = prev
+                    .edges
+                    .iter()
+                    .map(|(edge, _)| (edge_key(edge), edge.bytes_sent))
+                    .collect();
+                edges
+                    .into_iter()
+                    .map(|edge| {
+                        let throughput = sent.get(&edge_key(&edge)).map_or(0.0, |before| {
+                            edge.bytes_sent.saturating_sub(*before) as f64 / elapsed
+                        });
+                        (edge, throughput)
+                    })
+                    .collect()
+            }
+            _ => edges.into_iter().map(|edge| (edge, 0.0)).collect(),
+        };
+        self.recorded += 1;
+        self.snapshots.push_back(Snapshot {
+            id: self.recorded,
+            timestamp_ns,
+            edges,
+        });
+        self.compact(timestamp_ns);
+    }
+
+    fn compact(&mut self, now_ns: u64) {
+        let retention_start = now_ns.saturating_sub(self.config.retention.as_nanos() as u64);
+        while self
+            .snapshots
+            .front()
+            .is_some_and(|s| s.timestamp_ns < retention_start)
+        {
+            self.snapshots.pop_front();
+            self.compacted = self.compacted.saturating_sub(1);
+        }
+
+        let window_start = now_ns.saturating_sub(self.config.window.as_nanos() as u64);
+        let compaction = self.config.compaction.as_nanos() as u64;
+        while let Some(snapshot) = self.snapshots.get(self.compacted) {
+            if snapshot.timestamp_ns >= window_start {
+                break;
+            }
+            let too_close = self
+                .compacted
+                .checked_sub(1)
+                .and_then(|i| self.snapshots.get(i))
+                .is_some_and(|prev| {
+                    snapshot.timestamp_ns.saturating_sub(prev.timestamp_ns) < compaction
+                });
+            if too_close {
+                self.snapshots.remove(self.compacted);
+            } else {
+                self.compacted += 1;
+            }
+        }
+    }
+
+    pub(crate) fn query(&self, query: SnapshotQuery) -> Vec<ServiceMapSnapshot> {
+        match query {
+            SnapshotQuery::At(at_ns) => {
+                let idx = self.snapshots.partition_point(|s| s.timestamp_ns <= at_ns);
+                idx.checked_sub(1)
+                    .and_then(|i| self.snapshots.get(i))
+                    .map(to_proto)
+                    .into_iter()
+                    .collect()
+            }
+            SnapshotQuery::Range(start_ns, end_ns) => self
+                .snapshots
+                .iter()
+                .filter(|s| (start_ns..=end_ns).contains(&s.timestamp_ns))
+                .map(to_proto)
+                .collect(),
+        }
+    }
+}
+
+fn edge_key(edge: &GraphEdge) -> (&Workload, &Workload, u32, &'static str) {
+    (&edge.client, &edge.server, edge.server_port, edge.protocol)
+}
+
+fn to_proto(snapshot: &Snapshot) -> ServiceMapSnapshot {
+    let mut edges: Vec<_> = snapshot
+        .edges
+        .iter()
+        .map(|(edge, throughput)| ServiceMapEdge {
+            throughput_bps: *throughput,
+            ..ServiceMapEdge::from(edge)
+        })
+        .collect();
+    edges.sort_by(|a, b| edge_order(a).cmp(&edge_order(b)));
+    ServiceMapSnapshot {
+        timestamp_ns: snapshot.timestamp_ns,
+        checksum: snapshot_checksum(&edges),
+        edges,
+        snapshot_id: snapshot.id,
+    }
+}
+
+fn edge_order(edge: &ServiceMapEdge) -> (&str, &str, u32, &str) {
+    (
+        &edge.client_workload,
+        &edge.server_workload,
+        edge.server_port,
+        &edge.protocol,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::managers::symbol::SymbolTable;
+
+    const SEC: u64 = 1_000_000_000;
+
+    fn edge(symbols: &SymbolTable, bytes_sent: u64) -> GraphEdge {
+        let workload = |name: &str| {
+            Arc::new(Workload {
+                name: symbols.intern(name),
+                namespace: symbols.intern("default"),
+                kind: symbols.intern("Deployment"),
+                labels: Vec::new(),
+            })
+        };
+        GraphEdge {
+            client: workload("web"),
+            server: workload("api"),
+            server_port: 8080,
+            protocol: "http",
+            bytes_sent,
+            bytes_received: 0,
+            active_conns: 1,
+            resets: 0,
+            connect_timeouts: 0,
+        }
+    }
+
+    /// Returns a ring with a snapshot every 5 seconds from 0 to 100, with 500 more
+    /// bytes sent each time.
+    fn ring(symbols: &SymbolTable) -> SnapshotRing {
+        let config = SnapshotConfig::from_metadata(&HashMap::from([
+            ("snapshot_window".to_string(), "10".to_string()),
+            ("snapshot_retention".to_string(), "60".to_string()),
+            ("snapshot_compaction".to_string(), "20".to_string()),
+        ]));
+        let mut ring = SnapshotRing::new(config);
+        for i in 0..=20 {
+            ring.record(i * 5 * SEC, vec![edge(symbols, i * 500)]);
+        }
+        ring
+    }
+
+    fn timestamps(snapshots: &[ServiceMapSnapshot]) -> Vec<u64> {
+        snapshots.iter().map(|s| s.timestamp_ns / SEC).collect()
+    }
+
+    #[test]
+    fn test_snapshots_are_compacted_then_dropped() {
+        let symbols = SymbolTable::default();
+        let ring = ring(&symbols);
+        let snapshots = ring.query(SnapshotQuery::Range(0, u64::MAX));
+        let timestamps = timestamps(&snapshots);
+        assert!(timestamps.iter().all(|t| *t >= 40));
+        assert!(timestamps.ends_with(&[90, 95, 100]));
+        let compacted: Vec<_> = timestamps.iter().filter(|t| **t < 90).collect();
+        assert!(!compacted.is_empty());
+        assert!(compacted.windows(2).all(|w| w[1] - w[0] >= 20));
+        assert_eq!(snapshots.last().unwrap().snapshot_id, 21);
+    }
+
+    #[test]
+    fn test_snapshots_carry_throughput() {
+        let symbols = SymbolTable::default();
+        let ring = ring(&symbols);
+        let latest = ring.query(SnapshotQuery::At(100 * SEC));
+        assert_eq!(latest.len(), 1);
+        let snapshot = &latest[0];
+        assert_eq!(snapshot.edges[0].client_workload, "default/web");
+        assert_eq!(snapshot.edges[0].bytes_sent, 10_000);
+        assert_eq!(snapshot.edges[0].throughput_bps, 100.0);
+        assert_eq!(snapshot.checksum, snapshot_checksum(&snapshot.edges));
+
+        // The snapshot in effect at a time is the last one taken before it.
+        let at = ring.query(SnapshotQuery::At(97 * SEC));
+        assert_eq!(timestamps(&at), vec![95]);
+        assert!(ring.query(SnapshotQuery::At(SEC)).is_empty());
+        let range = ring.query(SnapshotQuery::Range(90 * SEC, 96 * SEC));
+        assert_eq!(timestamps(&range), vec![90, 95]);
+    }
+}
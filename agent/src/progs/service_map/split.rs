@@ -0,0 +1,141 @@
+use std::collections::HashMap;
+use std::sync::atomic::AtomicU64;
+
+use ahash::AHashMap;
+use anyhow::Error;
+use prometheus_client::encoding::{DescriptorEncoder, EncodeLabelSet, EncodeMetric};
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
+
+use crate::managers::cache::Workload;
+use crate::managers::symbol::{Symbol, SymbolTable};
+use crate::progs::service_map::program::{Connection, EdgeStats};
+
+/// Version reported for the workloads of a service missing the version label.
+const UNLABELED: &str = "none";
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct SplitLabels {
+    client_name: Symbol,
+    client_namespace: Symbol,
+    server_name: Symbol,
+    server_namespace: Symbol,
+    version: Symbol,
+}
+
+impl SplitLabels {
+    fn service(&self) -> (Symbol, Symbol, Symbol, Symbol) {
+        (
+            self.client_namespace.clone(),
+            self.client_name.clone(),
+            self.server_namespace.clone(),
+            self.server_name.clone(),
+        )
+    }
+}
+
+/// Reports how the traffic of each client to a service splits between the versions of
+/// the service, told apart by the pod label set by the `traffic_split_label` metadata,
+/// e.g. to follow a canary or blue/green rollout. The label must also be copied onto
+/// workloads with `--workload-label`.
+///
+/// Shares are taken over the bytes exchanged since the last poll, so that they follow
+/// the rollout rather than the history of the service. A client idle during a poll
+/// keeps its last shares.
+#[derive(Debug, Default)]
+pub(crate) struct TrafficSplit {
+    label: Option<String>,
+    /// Bytes exchanged so far by each client with each version of a service.
+    totals: AHashMap<SplitLabels, u64>,
+    ratios: AHashMap<SplitLabels, f64>,
+}
+
+impl TrafficSplit {
+    pub(crate) fn from_metadata(metadata: &HashMap<String, String>) -> Self {
+        Self {
+            label: metadata
+                .get("traffic_split_label")
+                .filter(|label| !label.is_empty())
+                .cloned(),
+            ..Default::default()
+        }
+    }
+
+    /// Updates the shares from the totals of the edges.
+    pub(crate) fn update(&mut self, conns: &HashMap<Connection, EdgeStats>, symbols: &SymbolTable) {
+        let Some(label) = self.label.as_deref() else {
+            return;
+        };
+        let unlabeled = symbols.intern(UNLABELED);
+        let mut totals: AHashMap<SplitLabels, u64> = AHashMap::new();
+        for (conn, stats) in conns {
+            if conn.loopback {
+                continue;
+            }
+            let labels = SplitLabels {
+                client_name: conn.client.name.clone(),
+                client_namespace: conn.client.namespace.clone(),
+                server_name: conn.server.name.clone(),
+                server_namespace: conn.server.namespace.clone(),
+                version: version(&conn.server, label).unwrap_or_else(|| unlabeled.clone()),
+            };
+            *totals.entry(labels).or_default() += stats.bytes_sent + stats.bytes_received;
+        }
+
+        let mut deltas: AHashMap<_, Vec<(SplitLabels, u64)>> = AHashMap::new();
+        for (labels, total) in &totals {
+            let delta = total.saturating_sub(self.totals.get(labels).copied().unwrap_or(0));
+            deltas
+                .entry(labels.service())
+                .or_default()
+                .push((labels.clone(), delta));
+        }
+        let mut ratios = AHashMap::new();
+        for versions in deltas.into_values() {
+            // Services none of whose workloads carry the label aren't split.
+            if versions
+                .iter()
+                .all(|(labels, _)| labels.version == unlabeled)
+            {
+                continue;
+            }
+            let sum: u64 = versions.iter().map(|(_, delta)| delta).sum();
+            for (labels, delta) in versions {
+                let ratio = if sum > 0 {
+                    delta as f64 / sum as f64
+                } else {
+                    self.ratios.get(&labels).copied().unwrap_or(0.0)
+                };
+                ratios.insert(labels, ratio);
+            }
+        }
+        self.totals = totals;
+        self.ratios = ratios;
+    }
+
+    pub(crate) fn encode(&self, encoder: &mut DescriptorEncoder) -> Result<(), Error> {
+        if self.label.is_none() {
+            return Ok(());
+        }
+        let family = Family::<SplitLabels, Gauge<f64, AtomicU64>>::default();
+        for (labels, ratio) in &self.ratios {
+            family.get_or_create(labels).set(*ratio);
+        }
+        let metric_encoder = encoder.encode_descriptor(
+            "traffic_split_ratio",
+            "share of the bytes a client exchanged with a service going to each of its versions",
+            None,
+            family.metric_type(),
+        )?;
+        family.encode(metric_encoder)?;
+        Ok(())
+    }
+}
+
+fn version(workload: &Workload, label: &str) -> Option<Symbol> {
+    workload
+        .labels
+        .iter()
+        .find(|(key, _)| key.as_str() == label)
+        .map(|(_, value)| value.clone())
+}
@@ -0,0 +1,409 @@
+use std::time::Duration;
+
+use ahash::{AHashMap, AHashSet};
+use anyhow::Error;
+use prometheus_client::encoding::{DescriptorEncoder, EncodeLabelSet, EncodeMetric};
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::metrics::histogram::{exponential_buckets, Histogram};
+use prometheus_client::registry::Unit;
+
+use conn_tracer_common::{
+    TlsHandshake, TLS_CERTIFICATE, TLS_CLIENT_HELLO, TLS_HANDSHAKE, TLS_SERVER_HELLO,
+};
+
+use crate::common::utils::days_from_civil;
+use crate::managers::symbol::{Symbol, SymbolTable};
+use crate::progs::service_map::labels::Labels;
+use crate::progs::service_map::program::Connection;
+
+const EXTENSION_SERVER_NAME: u16 = 0;
+const EXTENSION_SUPPORTED_VERSIONS: u16 = 43;
+
+/// What a TLS hello tells about a connection. A ClientHello, seen on the server end,
+/// carries the server name; a ServerHello, seen on the client end, the negotiated
+/// version and cipher suite, followed by the certificate of the server up to TLS 1.2.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub(crate) struct TlsSession {
+    pub(crate) server_name: Option<String>,
+    pub(crate) version: Option<u16>,
+    pub(crate) cipher: Option<u16>,
+    /// Unix time the certificate of the server expires.
+    pub(crate) not_after: Option<i64>,
+    pub(crate) handshake: Option<Duration>,
+}
+
+impl TlsSession {
+    /// Parses the segment captured by the tracer. Messages cut short by the end of the
+    /// capture give what they can.
+    pub(crate) fn parse(handshake: &TlsHandshake) -> Self {
+        let mut session = TlsSession {
+            handshake: handshake.duration_ns().map(Duration::from_nanos),
+            ..Default::default()
+        };
+        let mut records = Reader(handshake.captured());
+        while let Some(content_type) = records.u8() {
+            let (Some(_), Some(len)) = (records.u16(), records.u16()) else {
+                break;
+            };
+            let record = records.take_up_to(len as usize);
+            if content_type != TLS_HANDSHAKE {
+                break;
+            }
+            session.parse_messages(record);
+        }
+        session
+    }
+
+    /// Parses handshake messages carried outside of TLS records, as QUIC carries them in
+    /// its CRYPTO frames.
+    pub(crate) fn from_messages(messages: &[u8]) -> Self {
+        let mut session = TlsSession::default();
+        session.parse_messages(messages);
+        session
+    }
+
+    fn parse_messages(&mut self, messages: &[u8]) {
+        let mut messages = Reader(messages);
+        while let (Some(message_type), Some(len)) = (messages.u8(), messages.u24()) {
+            let message = Reader(messages.take_up_to(len as usize));
+            match message_type {
+                TLS_CLIENT_HELLO => self.parse_client_hello(message),
+                TLS_SERVER_HELLO => self.parse_server_hello(message),
+                TLS_CERTIFICATE => self.not_after = not_after(message),
+                _ => {}
+            }
+        }
+    }
+
+    fn parse_client_hello(&mut self, mut hello: Reader) {
+        let rest = (|| {
+            hello.skip(2 + 32)?;
+            let session_id = hello.u8()?;
+            hello.skip(session_id as usize)?;
+            let ciphers = hello.u16()?;
+            hello.skip(ciphers as usize)?;
+            let compressions = hello.u8()?;
+            hello.skip(compressions as usize)?;
+            hello.u16()?;
+            Some(hello)
+        })();
+        for (extension_type, mut data) in rest.into_iter().flat_map(hello_extensions) {
+            if extension_type != EXTENSION_SERVER_NAME {
+                continue;
+            }
+            self.server_name = (|| {
+                data.u16()?;
+                // Host names are the only type of server name.
+                data.u8()?;
+                let len = data.u16()?;
+                let name = data.take(len as usize)?;
+                String::from_utf8(name.to_vec()).ok()
+            })();
+        }
+    }
+
+    fn parse_server_hello(&mut self, mut hello: Reader) {
+        let rest = (|| {
+            self.version = Some(hello.u16()?);
+            hello.skip(32)?;
+            let session_id = hello.u8()?;
+            hello.skip(session_id as usize)?;
+            self.cipher = Some(hello.u16()?);
+            hello.u8()?;
+            hello.u16()?;
+            Some(hello)
+        })();
+        // TLS 1.3 negotiates its version in an extension, the legacy one being 1.2.
+        for (extension_type, mut data) in rest.into_iter().flat_map(hello_extensions) {
+            if extension_type == EXTENSION_SUPPORTED_VERSIONS {
+                self.version = data.u16().or(self.version);
+            }
+        }
+    }
+}
+
+/// The extensions following a hello, up to the end of the capture.
+fn hello_extensions(mut hello: Reader) -> impl Iterator<Item = (u16, Reader)> {
+    std::iter::from_fn(move || {
+        let extension_type = hello.u16()?;
+        let len = hello.u16()?;
+        Some((extension_type, Reader(hello.take(len as usize)?)))
+    })
+}
+
+/// Returns the notAfter time of the first certificate of a Certificate message, the
+/// one of the server, from its DER encoding.
+fn not_after(mut message: Reader) -> Option<i64> {
+    message.u24()?;
+    let len = message.u24()?;
+    let mut certificate = Reader(message.take_up_to(len as usize));
+    let mut certificate = der(&mut certificate, 0x30)?;
+    let mut tbs = der(&mut certificate, 0x30)?;
+    // The version is optional, explicitly tagged [0].
+    if tbs.peek()? == 0xa0 {
+        der(&mut tbs, 0xa0)?;
+    }
+    // The serial number, the signature algorithm and the issuer.
+    der(&mut tbs, 0x02)?;
+    der(&mut tbs, 0x30)?;
+    der(&mut tbs, 0x30)?;
+    let mut validity = der(&mut tbs, 0x30)?;
+    let _not_before = der_time(&mut validity)?;
+    der_time(&mut validity)
+}
+
+/// Reads a DER element of the given tag, and returns its contents.
+fn der<'a>(reader: &mut Reader<'a>, tag: u8) -> Option<Reader<'a>> {
+    if reader.u8()? != tag {
+        return None;
+    }
+    let len = match reader.u8()? {
+        len if len < 0x80 => len as usize,
+        long => {
+            let mut len = 0usize;
+            for _ in 0..(long & 0x7f).min(4) {
+                len = len << 8 | reader.u8()? as usize;
+            }
+            len
+        }
+    };
+    // The element may go on past the end of the capture.
+    Some(Reader(reader.take_up_to(len)))
+}
+
+/// Reads a UTCTime or GeneralizedTime as a Unix time.
+fn der_time(reader: &mut Reader) -> Option<i64> {
+    let tag = reader.peek()?;
+    let time = der(reader, tag)?.0;
+    let time = std::str::from_utf8(time).ok()?.strip_suffix('Z')?;
+    let (year, rest) = match tag {
+        0x17 => {
+            let year: i64 = time.get(..2)?.parse().ok()?;
+            (
+                if year < 50 { 2000 + year } else { 1900 + year },
+                &time[2..],
+            )
+        }
+        0x18 => (time.get(..4)?.parse().ok()?, &time[4..]),
+        _ => return None,
+    };
+    let field = |i: usize| rest.get(i * 2..i * 2 + 2)?.parse::<i64>().ok();
+    let days = days_from_civil(year, field(0)?, field(1)?);
+    Some(days * 86400 + field(2)? * 3600 + field(3)? * 60 + field(4)?)
+}
+
+/// Reads big-endian fields off a byte slice.
+#[derive(Debug, Clone, Copy)]
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Some(head)
+    }
+
+    fn take_up_to(&mut self, n: usize) -> &'a [u8] {
+        let (head, tail) = self.0.split_at(n.min(self.0.len()));
+        self.0 = tail;
+        head
+    }
+
+    fn skip(&mut self, n: usize) -> Option<()> {
+        self.take(n).map(|_| ())
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.0.first().copied()
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        let bytes = self.take(2)?;
+        Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u24(&mut self) -> Option<u32> {
+        let bytes = self.take(3)?;
+        Some(u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]))
+    }
+}
+
+fn version_name(version: u16) -> String {
+    match version {
+        0x0300 => "ssl3".to_string(),
+        0x0301 => "1.0".to_string(),
+        0x0302 => "1.1".to_string(),
+        0x0303 => "1.2".to_string(),
+        0x0304 => "1.3".to_string(),
+        other => format!("0x{:04x}", other),
+    }
+}
+
+fn cipher_name(cipher: u16) -> String {
+    match cipher {
+        0x1301 => "TLS_AES_128_GCM_SHA256".to_string(),
+        0x1302 => "TLS_AES_256_GCM_SHA384".to_string(),
+        0x1303 => "TLS_CHACHA20_POLY1305_SHA256".to_string(),
+        0xc02b => "TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256".to_string(),
+        0xc02c => "TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384".to_string(),
+        0xc02f => "TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256".to_string(),
+        0xc030 => "TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384".to_string(),
+        0xcca8 => "TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256".to_string(),
+        0xcca9 => "TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256".to_string(),
+        other => format!("0x{:04x}", other),
+    }
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct SessionLabels {
+    sni: Symbol,
+    tls_version: Symbol,
+    cipher: Symbol,
+    #[prometheus(flatten)]
+    edge: Labels,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct CertificateLabels {
+    sni: Symbol,
+    #[prometheus(flatten)]
+    edge: Labels,
+}
+
+#[derive(Debug)]
+struct TlsEdge {
+    labels: Labels,
+    sessions: AHashSet<SessionLabels>,
+    certificates: AHashSet<CertificateLabels>,
+}
+
+/// Per-edge TLS metrics: handshakes by server name (SNI), version and cipher suite, the
+/// time handshakes took, and when the certificates of servers expire. Each end mostly
+/// sees what the other sent, so the version, cipher suite and certificate are only
+/// known when the client end is traced, and the handshake time when the server end is.
+/// Certificates are only visible up to TLS 1.2.
+#[derive(Debug)]
+pub(crate) struct TlsMetrics {
+    edges: AHashMap<Connection, TlsEdge>,
+    sessions: Family<SessionLabels, Counter>,
+    handshakes: Family<Labels, Histogram, fn() -> Histogram>,
+    certificate_expiry: Family<CertificateLabels, Gauge>,
+}
+
+impl Default for TlsMetrics {
+    fn default() -> Self {
+        Self {
+            edges: AHashMap::new(),
+            sessions: Family::default(),
+            handshakes: Family::new_with_constructor(new_handshake_histogram),
+            certificate_expiry: Family::default(),
+        }
+    }
+}
+
+impl TlsMetrics {
+    pub(crate) fn observe(
+        &mut self,
+        conn: &Connection,
+        session: &TlsSession,
+        symbols: &SymbolTable,
+    ) {
+        let edge = self.edges.entry(conn.clone()).or_insert_with(|| TlsEdge {
+            labels: Labels::new(conn, symbols),
+            sessions: AHashSet::new(),
+            certificates: AHashSet::new(),
+        });
+        let sni = symbols.intern(session.server_name.as_deref().unwrap_or(""));
+        let labels = SessionLabels {
+            edge: edge.labels.clone(),
+            sni: sni.clone(),
+            tls_version: symbols.intern(&session.version.map(version_name).unwrap_or_default()),
+            cipher: symbols.intern(&session.cipher.map(cipher_name).unwrap_or_default()),
+        };
+        self.sessions.get_or_create(&labels).inc();
+        edge.sessions.insert(labels);
+
+        if let Some(handshake) = session.handshake {
+            self.handshakes
+                .get_or_create(&edge.labels)
+                .observe(handshake.as_secs_f64());
+        }
+        if let Some(not_after) = session.not_after {
+            let labels = CertificateLabels {
+                edge: edge.labels.clone(),
+                sni,
+            };
+            self.certificate_expiry
+                .get_or_create(&labels)
+                .set(not_after);
+            edge.certificates.insert(labels);
+        }
+    }
+
+    /// Removes the series of expired edges.
+    pub(crate) fn expire(&mut self, conns: &[Connection]) {
+        for conn in conns {
+            if let Some(edge) = self.edges.remove(conn) {
+                for labels in edge.sessions {
+                    self.sessions.remove(&labels);
+                }
+                for labels in edge.certificates {
+                    self.certificate_expiry.remove(&labels);
+                }
+                self.handshakes.remove(&edge.labels);
+            }
+        }
+    }
+
+    pub(crate) fn encode(&self, encoder: &mut DescriptorEncoder) -> Result<(), Error> {
+        if self.edges.is_empty() {
+            return Ok(());
+        }
+        let metric_encoder = encoder.encode_descriptor(
+            "tls_handshakes",
+            "TLS handshakes observed, by server name, version and cipher suite",
+            None,
+            self.sessions.metric_type(),
+        )?;
+        self.sessions.encode(metric_encoder)?;
+
+        let metric_encoder = encoder.encode_descriptor(
+            "tls_handshake_duration",
+            "time from the ClientHello received by a server to the client finishing",
+            Some(&Unit::Seconds),
+            self.handshakes.metric_type(),
+        )?;
+        self.handshakes.encode(metric_encoder)?;
+
+        let metric_encoder = encoder.encode_descriptor(
+            "tls_certificate_expiry_timestamp",
+            "Unix time the certificate presented by a server expires",
+            Some(&Unit::Seconds),
+            self.certificate_expiry.metric_type(),
+        )?;
+        self.certificate_expiry.encode(metric_encoder)?;
+
+        Ok(())
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.edges.clear();
+        self.sessions.clear();
+        self.handshakes.clear();
+        self.certificate_expiry.clear();
+    }
+}
+
+fn new_handshake_histogram() -> Histogram {
+    // 1ms up to roughly 4 seconds
+    Histogram::new(exponential_buckets(0.001, 2.0, 13))
+}
@@ -5,9 +5,15 @@ use async_trait::async_trait;
 use prometheus_client::encoding::DescriptorEncoder;
 use tokio::sync::broadcast::Receiver;
 
-use agent_api::v1::ProgramInfo;
+use conn_tracer_common::ConnectionKey;
 
+use agent_api::v1::{MapDump, ProgramInfo, ServiceMapSnapshot};
+
+use crate::common::graph::GraphEdge;
+use crate::common::native_histogram::NativeHistogramSeries;
 use crate::managers::cache::CacheManager;
+use crate::managers::events::EventsManager;
+use crate::progs::schema::MetadataSchema;
 use agent_api::{ProgramState, ProgramType};
 
 #[derive(Debug, Clone)]
@@ -16,18 +22,51 @@ pub enum ShutdownSignal {
     ProgramName(String),
 }
 
+/// Selects retained service map snapshots by their wall-clock time, in nanoseconds
+/// since the Unix epoch.
+#[derive(Debug, Clone, Copy)]
+pub enum SnapshotQuery {
+    /// The latest snapshot taken at or before the given time.
+    At(u64),
+    /// Every snapshot taken within the inclusive range.
+    Range(u64, u64),
+}
+
+/// Byte totals of a connection observed by a program, for flow exporters. The source
+/// of the key is the local end.
+#[derive(Debug, Clone, Copy)]
+pub struct Flow {
+    pub key: ConnectionKey,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    /// Set once the connection closed, its totals being final.
+    pub closed: bool,
+}
+
 #[async_trait]
 pub trait Program: Debug + Send + Sync + 'static {
     fn init(
         &self,
         metadata: HashMap<String, String>,
         cache_manager: CacheManager,
+        events_manager: EventsManager,
         maps: HashMap<String, u32>,
     ) -> Result<(), anyhow::Error>;
+    /// Declares the metadata keys the program reads, which load requests are checked
+    /// against before it is initialized.
+    fn metadata_schema(&self) -> MetadataSchema;
+    /// Checks that the maps of a load request hold every map the program reads, pinned
+    /// by bpfman, without opening them.
+    fn validate_maps(&self, maps: &HashMap<String, u32>) -> Result<(), anyhow::Error>;
     async fn start(&self, shutdown_rx: Receiver<ShutdownSignal>) -> Result<(), anyhow::Error>;
 
     async fn stop(&self) -> Result<(), anyhow::Error>;
+    /// Refreshes the program's state. Called periodically by the poll scheduler on a
+    /// blocking worker, at the `interval` given in the program metadata.
+    fn poll(&self) -> Result<(), anyhow::Error>;
     fn collect(&self, encoder: &mut DescriptorEncoder) -> Result<(), anyhow::Error>;
+    /// Returns the latency data the program also keeps as native histograms.
+    fn native_histograms(&self) -> Vec<NativeHistogramSeries>;
     fn get_name(&self) -> String;
     fn get_state(&self) -> ProgramState;
     fn set_state(&self, state: ProgramState);
@@ -35,4 +74,13 @@ pub trait Program: Debug + Send + Sync + 'static {
     fn get_metadata(&self) -> HashMap<String, String>;
     fn set_metadata(&self, metadata: HashMap<String, String>);
     fn get_program_info(&self) -> Result<ProgramInfo, anyhow::Error>;
+    /// Snapshots the program's pinned maps. Only the maps named in `maps` are dumped,
+    /// or all of them when it is empty.
+    fn dump_maps(&self, maps: &[String]) -> Result<Vec<MapDump>, anyhow::Error>;
+    /// Returns the workload edges the program has observed, if it builds a service map.
+    fn graph_edges(&self) -> Vec<GraphEdge>;
+    fn service_map_snapshots(&self, query: SnapshotQuery) -> Vec<ServiceMapSnapshot>;
+    /// Returns the connections the program observes: those open, and those that closed
+    /// since the last call.
+    fn flows(&self) -> Vec<Flow>;
 }
@@ -0,0 +1,292 @@
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use futures::future::BoxFuture;
+use log::warn;
+use tonic::body::BoxBody;
+use tonic::codegen::http;
+use tonic::Status;
+use tower::{Layer, Service};
+
+/// Methods of the agent API which only read its state. They are the only ones served
+/// over TCP without a token; any other method, including those added later, needs one.
+/// Sampled requests carry paths and headers, and validating a program loads it into the
+/// kernel, so neither is on the list.
+const READ_ONLY_METHODS: &[&str] = &[
+    "List",
+    "Get",
+    "WatchDependencies",
+    "ExportGraph",
+    "GetServiceMapAt",
+    "GetServiceMapRange",
+    "DiffServiceMap",
+    "ApiVersion",
+    "GetAuditLog",
+    "GetLoadDiagnostics",
+    "WatchServiceMap",
+];
+const AGENT_SERVICE: &str = "agent.v1.agent";
+const REFLECTION_SERVICE_PREFIX: &str = "grpc.reflection.";
+
+/// Who may call which methods of the agent API on a listener.
+#[derive(Debug, Clone)]
+pub(crate) enum ApiAccess {
+    /// Every method, to every client. Used on unix sockets, whose clients the kernel
+    /// checked against the socket file mode or, in the abstract namespace, which the
+    /// listener checked against a [`PeerAllowlist`].
+    Full,
+    /// Used on TCP: every method to the clients sending the token held by
+    /// `token_file` as a bearer token, or only the read-only methods to any client
+    /// when there is no token.
+    Tcp { token_file: Option<Arc<PathBuf>> },
+}
+
+impl ApiAccess {
+    /// Returns the status `request` is denied with, if it is.
+    fn denial<B>(&self, request: &http::Request<B>) -> Option<Status> {
+        let ApiAccess::Tcp { token_file } = self else {
+            return None;
+        };
+        let path = request.uri().path();
+        let Some(token_file) = token_file else {
+            if is_read_only(path) {
+                return None;
+            }
+            return Some(Status::permission_denied(format!(
+                "{} is only served over TCP to clients with a token, see --agent-token-file",
+                path
+            )));
+        };
+        // Read on every call, so that rotated tokens are picked up.
+        let token = match fs::read_to_string(token_file.as_path()) {
+            Ok(token) => token,
+            Err(e) => {
+                warn!("Failed to read {}: {}", token_file.display(), e);
+                return Some(Status::unavailable("the agent API token can't be read"));
+            }
+        };
+        let token = token.trim();
+        let presented = request
+            .headers()
+            .get(http::header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        match presented {
+            Some(presented) if !token.is_empty() && constant_time_eq(presented, token) => None,
+            Some(_) => Some(Status::unauthenticated("invalid agent API token")),
+            None => Some(Status::unauthenticated("missing agent API token")),
+        }
+    }
+}
+
+/// Whether the gRPC method at `path`, `/<SERVICE>/<METHOD>`, only reads the agent state.
+fn is_read_only(path: &str) -> bool {
+    let Some((service, method)) = path.trim_start_matches('/').split_once('/') else {
+        return false;
+    };
+    service.starts_with(REFLECTION_SERVICE_PREFIX)
+        || (service == AGENT_SERVICE && READ_ONLY_METHODS.contains(&method))
+}
+
+/// Compares tokens in a time independent of where they differ.
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Applies an [`ApiAccess`] to the requests of a listener, answering those it denies
+/// with their status rather than passing them on.
+#[derive(Debug, Clone)]
+pub(crate) struct AccessLayer {
+    access: ApiAccess,
+}
+
+impl AccessLayer {
+    pub(crate) fn new(access: ApiAccess) -> Self {
+        Self { access }
+    }
+}
+
+impl<S> Layer<S> for AccessLayer {
+    type Service = Access<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Access {
+            inner,
+            access: self.access.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Access<S> {
+    inner: S,
+    access: ApiAccess,
+}
+
+impl<S, B> Service<http::Request<B>> for Access<S>
+where
+    S: Service<http::Request<B>, Response = http::Response<BoxBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = http::Response<BoxBody>;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: http::Request<B>) -> Self::Future {
+        match self.access.denial(&request) {
+            None => Box::pin(self.inner.call(request)),
+            Some(status) => Box::pin(async move { Ok(status.to_http()) }),
+        }
+    }
+}
+
+/// Users and groups allowed to connect to an agent socket in the abstract namespace,
+/// which has no file permissions to restrict it. They are checked against the
+/// credentials of the connecting process, its primary group only. Root always is.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct PeerAllowlist {
+    uids: Vec<u32>,
+    gids: Vec<u32>,
+}
+
+impl PeerAllowlist {
+    pub(crate) fn new(uids: Vec<u32>, gids: Vec<u32>) -> Self {
+        Self { uids, gids }
+    }
+
+    pub(crate) fn allows(&self, uid: u32, gid: u32) -> bool {
+        uid == 0 || self.uids.contains(&uid) || self.gids.contains(&gid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::future::{ready, Ready};
+
+    use super::*;
+
+    /// Answers every request it is passed.
+    struct Passed;
+
+    impl Service<http::Request<()>> for Passed {
+        type Response = http::Response<BoxBody>;
+        type Error = Infallible;
+        type Future = Ready<Result<Self::Response, Infallible>>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _: http::Request<()>) -> Self::Future {
+            ready(Ok(http::Response::new(tonic::body::empty_body())))
+        }
+    }
+
+    fn request(method: &str, token: Option<&str>) -> http::Request<()> {
+        let mut builder = http::Request::post(format!("/{}/{}", AGENT_SERVICE, method));
+        if let Some(token) = token {
+            builder = builder.header(http::header::AUTHORIZATION, format!("Bearer {}", token));
+        }
+        builder.body(()).unwrap()
+    }
+
+    /// Returns the gRPC status code of the response to `request`, 0 when it passed.
+    async fn call(access: ApiAccess, request: http::Request<()>) -> i32 {
+        let response = AccessLayer::new(access)
+            .layer(Passed)
+            .call(request)
+            .await
+            .unwrap();
+        response
+            .headers()
+            .get("grpc-status")
+            .map_or(0, |status| status.to_str().unwrap().parse().unwrap())
+    }
+
+    #[test]
+    fn test_read_only_methods() {
+        assert!(is_read_only("/agent.v1.agent/List"));
+        assert!(is_read_only("/agent.v1.agent/WatchServiceMap"));
+        assert!(is_read_only(
+            "/grpc.reflection.v1alpha.ServerReflection/ServerReflectionInfo"
+        ));
+        assert!(!is_read_only("/agent.v1.agent/Load"));
+        assert!(!is_read_only("/agent.v1.agent/TracePod"));
+        assert!(!is_read_only("/agent.v1.agent/DumpMaps"));
+        assert!(!is_read_only("/agent.v1.agent/GetRecentRequests"));
+        assert!(!is_read_only("/agent.v1.agent/ValidateProgram"));
+        assert!(!is_read_only("/agent.v1.hub/List"));
+        assert!(!is_read_only("/agent.v1.agent"));
+    }
+
+    #[tokio::test]
+    async fn test_tcp_without_token_is_read_only() {
+        let access = ApiAccess::Tcp { token_file: None };
+        assert_eq!(call(access.clone(), request("List", None)).await, 0);
+        assert_eq!(
+            call(access.clone(), request("Unload", None)).await,
+            tonic::Code::PermissionDenied as i32
+        );
+        assert_eq!(
+            call(access, request("PauseProgram", Some("anything"))).await,
+            tonic::Code::PermissionDenied as i32
+        );
+        assert_eq!(call(ApiAccess::Full, request("Unload", None)).await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_tcp_with_token() {
+        let path = std::env::temp_dir().join(format!("agent-token-{}", std::process::id()));
+        fs::write(&path, "s3cret\n").unwrap();
+        let access = ApiAccess::Tcp {
+            token_file: Some(Arc::new(path.clone())),
+        };
+        assert_eq!(
+            call(access.clone(), request("Unload", Some("s3cret"))).await,
+            0
+        );
+        // Every method needs the token once there is one.
+        assert_eq!(
+            call(access.clone(), request("List", None)).await,
+            tonic::Code::Unauthenticated as i32
+        );
+        assert_eq!(
+            call(access.clone(), request("Unload", Some("s3cre"))).await,
+            tonic::Code::Unauthenticated as i32
+        );
+        assert_eq!(
+            call(access, request("Unload", Some("s3cret "))).await,
+            tonic::Code::Unauthenticated as i32
+        );
+        fs::remove_file(&path).unwrap();
+
+        let access = ApiAccess::Tcp {
+            token_file: Some(Arc::new(PathBuf::from("/nonexistent/token"))),
+        };
+        assert_eq!(
+            call(access, request("List", Some("s3cret"))).await,
+            tonic::Code::Unavailable as i32
+        );
+    }
+
+    #[test]
+    fn test_peer_allowlist() {
+        let peers = PeerAllowlist::new(vec![1000], vec![2000]);
+        assert!(peers.allows(0, 0));
+        assert!(peers.allows(1000, 1000));
+        assert!(peers.allows(1001, 2000));
+        assert!(!peers.allows(1001, 1001));
+        assert!(!PeerAllowlist::default().allows(1000, 1000));
+    }
+}
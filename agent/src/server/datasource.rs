@@ -0,0 +1,572 @@
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Context};
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::body::{Bytes, Incoming};
+use hyper::{Method, Request, Response, StatusCode};
+use serde_json::{json, Value};
+
+use crate::collector::Collector;
+use crate::common::graph::{node_id, Graph};
+use crate::common::utils::days_from_civil;
+use agent_api::v1::ServiceMapSnapshot;
+
+use crate::progs::types::SnapshotQuery;
+
+/// Path under which the metrics server answers the Grafana datasource requests.
+pub(crate) const DATASOURCE_PATH: &str = "/datasource";
+/// Largest query body read.
+const MAX_BODY_SIZE: usize = 64 * 1024;
+/// Throughput buckets of a query when it doesn't set their width, and the most
+/// buckets a query is split into, wider ones being used beyond that.
+const DEFAULT_STEP_MS: u64 = 60_000;
+const MAX_BUCKETS: u64 = 10_000;
+/// Span of a throughput query when it doesn't set where it starts.
+const DEFAULT_RANGE_MS: u64 = 3_600_000;
+/// What the datasource serves, as listed to SimpleJSON searches.
+const TARGETS: [&str; 3] = ["nodes", "edges", "throughput"];
+
+const NODE_COLUMNS: &[(&str, &str)] = &[
+    ("id", "string"),
+    ("title", "string"),
+    ("subtitle", "string"),
+    ("detail__kind", "string"),
+];
+const EDGE_COLUMNS: &[(&str, &str)] = &[
+    ("id", "string"),
+    ("source", "string"),
+    ("target", "string"),
+    ("mainstat", "number"),
+    ("detail__port", "number"),
+    ("detail__protocol", "string"),
+    ("detail__bytes_sent", "number"),
+    ("detail__bytes_received", "number"),
+    ("detail__active_connections", "number"),
+    ("detail__resets", "number"),
+    ("detail__connect_timeouts", "number"),
+];
+
+/// Answers the requests of Grafana's JSON datasources, with the service maps of the
+/// running programs:
+///
+/// - `GET nodes` and `GET edges` return the workloads and edges of the service map, as
+///   lists of objects for the Infinity datasource. Their fields are named after those
+///   of Grafana's node graph, so that both frames can be fed to it as they are.
+/// - `GET throughput?from=&to=&step=` returns the total throughput of the edges, in
+///   bytes per second, averaged over buckets of `step` milliseconds between `from`
+///   and `to`, in milliseconds since the Unix epoch. It covers the last hour in
+///   one-minute buckets by default.
+/// - `POST search`, `POST query` and `POST annotations` implement the SimpleJSON
+///   protocol, the nodes and edges being served as tables and the throughput as a
+///   time series.
+pub(crate) async fn handle(
+    collector: &Collector,
+    path: &str,
+    request: Request<Incoming>,
+) -> Response<Full<Bytes>> {
+    match route(collector, path, request).await {
+        Ok(Some(body)) => json_response(StatusCode::OK, body),
+        Ok(None) => json_response(StatusCode::NOT_FOUND, json!({ "error": "not found" })),
+        Err(e) => json_response(
+            StatusCode::BAD_REQUEST,
+            json!({ "error": format!("{:#}", e) }),
+        ),
+    }
+}
+
+async fn route(
+    collector: &Collector,
+    path: &str,
+    request: Request<Incoming>,
+) -> anyhow::Result<Option<Value>> {
+    let method = request.method().clone();
+    let body = match (method, path.trim_matches('/')) {
+        // The connection test of the SimpleJSON datasource.
+        (Method::GET, "") => json!({}),
+        (Method::GET, "nodes") => table_objects(NODE_COLUMNS, node_rows(&graph(collector))),
+        (Method::GET, "edges") => table_objects(EDGE_COLUMNS, edge_rows(&graph(collector))),
+        (Method::GET, "throughput") => {
+            let params: BTreeMap<_, _> = request
+                .uri()
+                .query()
+                .map(|query| url::form_urlencoded::parse(query.as_bytes()).collect())
+                .unwrap_or_default();
+            let param = |name: &str| {
+                params
+                    .get(name)
+                    .map(|value| {
+                        value
+                            .parse::<u64>()
+                            .with_context(|| format!("Invalid {}: {}", name, value))
+                    })
+                    .transpose()
+            };
+            let to_ms = param("to")?.unwrap_or_else(now_ms);
+            let from_ms = param("from")?.unwrap_or(to_ms.saturating_sub(DEFAULT_RANGE_MS));
+            let step_ms = param("step")?.unwrap_or(DEFAULT_STEP_MS);
+            let points = throughput(collector, from_ms, to_ms, step_ms)?;
+            Value::from(
+                points
+                    .into_iter()
+                    .map(|(time_ms, bps)| json!({ "time": time_ms, "throughput_bps": bps }))
+                    .collect::<Vec<_>>(),
+            )
+        }
+        (Method::POST, "search") => json!(TARGETS),
+        (Method::POST, "annotations") => json!([]),
+        (Method::POST, "query") => query(collector, &read_body(request).await?)?,
+        _ => return Ok(None),
+    };
+    Ok(Some(body))
+}
+
+/// A SimpleJSON query: its time range and bucket width, in milliseconds, and the
+/// targets to serve.
+#[derive(Debug, PartialEq)]
+struct Query<'a> {
+    from_ms: u64,
+    to_ms: u64,
+    step_ms: u64,
+    targets: Vec<&'a str>,
+}
+
+impl<'a> Query<'a> {
+    /// Parses the body of a query, which gives the time range as RFC 3339 times.
+    fn parse(body: &'a Value) -> anyhow::Result<Self> {
+        let time = |field: &str| {
+            let time = body["range"][field]
+                .as_str()
+                .ok_or_else(|| anyhow!("Missing range.{}", field))?;
+            parse_time(time).ok_or_else(|| anyhow!("Invalid range.{}: {}", field, time))
+        };
+        let targets = body["targets"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(
+                |target| match target["target"].as_str().unwrap_or_default() {
+                    name @ ("nodes" | "edges" | "throughput") => Ok(name),
+                    name => Err(anyhow!("Unknown target: {}", name)),
+                },
+            )
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self {
+            from_ms: time("from")?,
+            to_ms: time("to")?,
+            step_ms: body["intervalMs"].as_u64().unwrap_or(DEFAULT_STEP_MS),
+            targets,
+        })
+    }
+}
+
+/// Answers a SimpleJSON query.
+fn query(collector: &Collector, body: &Value) -> anyhow::Result<Value> {
+    let query = Query::parse(body)?;
+    let mut graph = None;
+    let mut results = Vec::new();
+    for name in query.targets {
+        let result = match name {
+            "nodes" => table(
+                NODE_COLUMNS,
+                node_rows(graph.get_or_insert_with(|| self::graph(collector))),
+            ),
+            "edges" => table(
+                EDGE_COLUMNS,
+                edge_rows(graph.get_or_insert_with(|| self::graph(collector))),
+            ),
+            _ => time_series(
+                name,
+                throughput(collector, query.from_ms, query.to_ms, query.step_ms)?,
+            ),
+        };
+        results.push(result);
+    }
+    Ok(Value::from(results))
+}
+
+/// Shapes rows into a SimpleJSON table.
+fn table(columns: &[(&str, &str)], rows: Vec<Vec<Value>>) -> Value {
+    json!({
+        "type": "table",
+        "columns": columns
+            .iter()
+            .map(|(text, kind)| json!({ "text": text, "type": kind }))
+            .collect::<Vec<_>>(),
+        "rows": rows,
+    })
+}
+
+/// Shapes `(time, value)` points into a SimpleJSON time series, whose datapoints are
+/// `[value, time]` pairs.
+fn time_series(target: &str, points: Vec<(u64, f64)>) -> Value {
+    json!({
+        "target": target,
+        "datapoints": points
+            .into_iter()
+            .map(|(time_ms, value)| json!([value, time_ms]))
+            .collect::<Vec<_>>(),
+    })
+}
+
+fn graph(collector: &Collector) -> Graph {
+    let mut graph = Graph::default();
+    for prog in collector.running_progs() {
+        graph.extend(prog.graph_edges());
+    }
+    graph
+}
+
+fn node_rows(graph: &Graph) -> Vec<Vec<Value>> {
+    graph
+        .nodes()
+        .map(|(id, workload)| {
+            vec![
+                json!(id),
+                json!(workload.name.as_str()),
+                json!(workload.namespace.as_str()),
+                json!(workload.kind.as_str()),
+            ]
+        })
+        .collect()
+}
+
+fn edge_rows(graph: &Graph) -> Vec<Vec<Value>> {
+    graph
+        .edges()
+        .map(|edge| {
+            let (source, target) = (node_id(&edge.client), node_id(&edge.server));
+            vec![
+                json!(format!(
+                    "{}->{}:{}/{}",
+                    source, target, edge.server_port, edge.protocol
+                )),
+                json!(source),
+                json!(target),
+                json!(edge.bytes_sent),
+                json!(edge.server_port),
+                json!(edge.protocol),
+                json!(edge.bytes_sent),
+                json!(edge.bytes_received),
+                json!(edge.active_conns),
+                json!(edge.resets),
+                json!(edge.connect_timeouts),
+            ]
+        })
+        .collect()
+}
+
+/// Turns the rows of a table into objects keyed by their column names.
+fn table_objects(columns: &[(&str, &str)], rows: Vec<Vec<Value>>) -> Value {
+    Value::from(
+        rows.into_iter()
+            .map(|row| {
+                columns
+                    .iter()
+                    .map(|(name, _)| name.to_string())
+                    .zip(row)
+                    .collect::<serde_json::Map<_, _>>()
+            })
+            .collect::<Vec<_>>(),
+    )
+}
+
+/// Total throughput of the service maps retained between `from_ms` and `to_ms`, as
+/// `(bucket start, bytes per second)` points, see [`sum_throughput`].
+fn throughput(
+    collector: &Collector,
+    from_ms: u64,
+    to_ms: u64,
+    step_ms: u64,
+) -> anyhow::Result<Vec<(u64, f64)>> {
+    if from_ms > to_ms {
+        return Err(anyhow!("The range ends before it starts"));
+    }
+    let step_ms = step_ms.max(1).max((to_ms - from_ms).div_ceil(MAX_BUCKETS));
+    let query = SnapshotQuery::Range(
+        from_ms.saturating_mul(1_000_000),
+        to_ms.saturating_mul(1_000_000),
+    );
+    let programs = collector
+        .running_progs()
+        .into_iter()
+        .map(|prog| prog.service_map_snapshots(query));
+    Ok(sum_throughput(programs, from_ms, step_ms))
+}
+
+/// Sums the throughput of the snapshots of each program into buckets of `step_ms`
+/// from `from_ms`. The snapshots of each program are averaged over the buckets, and
+/// the averages of the programs summed. Buckets no program has a snapshot in are left
+/// out.
+fn sum_throughput(
+    programs: impl IntoIterator<Item = Vec<ServiceMapSnapshot>>,
+    from_ms: u64,
+    step_ms: u64,
+) -> Vec<(u64, f64)> {
+    let mut totals: BTreeMap<u64, f64> = BTreeMap::new();
+    for snapshots in programs {
+        let mut buckets: BTreeMap<u64, (f64, u32)> = BTreeMap::new();
+        for snapshot in snapshots {
+            let bucket = (snapshot.timestamp_ns / 1_000_000).saturating_sub(from_ms) / step_ms;
+            let bps: f64 = snapshot.edges.iter().map(|edge| edge.throughput_bps).sum();
+            let (sum, count) = buckets.entry(bucket).or_default();
+            *sum += bps;
+            *count += 1;
+        }
+        for (bucket, (sum, count)) in buckets {
+            *totals.entry(bucket).or_default() += sum / count as f64;
+        }
+    }
+    totals
+        .into_iter()
+        .map(|(bucket, bps)| (from_ms + bucket * step_ms, bps))
+        .collect()
+}
+
+async fn read_body(request: Request<Incoming>) -> anyhow::Result<Value> {
+    let body = Limited::new(request.into_body(), MAX_BODY_SIZE)
+        .collect()
+        .await
+        .map_err(|e| anyhow!("Failed to read the query: {}", e))?
+        .to_bytes();
+    serde_json::from_slice(&body).context("Invalid query")
+}
+
+/// Parses an RFC 3339 time, as sent by Grafana, into milliseconds since the Unix
+/// epoch. Plain millisecond counts are taken as they are.
+fn parse_time(time: &str) -> Option<u64> {
+    if let Ok(ms) = time.parse::<u64>() {
+        return Some(ms);
+    }
+    let (date, time) = time.split_once(['T', 't', ' '])?;
+    let (time, offset) = match time.strip_suffix(['Z', 'z']) {
+        Some(time) => (time, 0),
+        None => {
+            let (time, offset) = time.split_at(time.rfind(['+', '-'])?);
+            let (hours, minutes) = offset[1..].split_once(':')?;
+            let seconds = hours.parse::<i64>().ok()? * 3600 + minutes.parse::<i64>().ok()? * 60;
+            (
+                time,
+                if offset.starts_with('-') {
+                    -seconds
+                } else {
+                    seconds
+                },
+            )
+        }
+    };
+    let (time, millis) = match time.split_once('.') {
+        Some((time, fraction)) => (time, format!("{:0<3}", fraction).get(..3)?.parse().ok()?),
+        None => (time, 0),
+    };
+
+    let fields = |s: &str, sep: char| -> Option<Vec<i64>> {
+        let fields: Option<Vec<i64>> = s.split(sep).map(|f| f.parse().ok()).collect();
+        fields.filter(|fields| fields.len() == 3)
+    };
+    let (date, time) = (fields(date, '-')?, fields(time, ':')?);
+    let secs = days_from_civil(date[0], date[1], date[2]) * 86400
+        + time[0] * 3600
+        + time[1] * 60
+        + time[2]
+        - offset;
+    u64::try_from(secs * 1000 + millis).ok()
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+fn json_response(status: StatusCode, body: Value) -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(status)
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .body(Full::from(body.to_string()))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use agent_api::v1::ServiceMapEdge;
+
+    use super::*;
+    use crate::common::graph::GraphEdge;
+    use crate::managers::cache::Workload;
+    use crate::managers::symbol::SymbolTable;
+
+    #[test]
+    fn test_parse_time() {
+        assert_eq!(parse_time("1700000000000"), Some(1_700_000_000_000));
+        assert_eq!(parse_time("2023-11-14T22:13:20Z"), Some(1_700_000_000_000));
+        assert_eq!(
+            parse_time("2023-11-14T22:13:20.5Z"),
+            Some(1_700_000_000_500)
+        );
+        assert_eq!(
+            parse_time("2023-11-14T22:13:20.123456Z"),
+            Some(1_700_000_000_123)
+        );
+        assert_eq!(
+            parse_time("2023-11-15T00:13:20+02:00"),
+            Some(1_700_000_000_000)
+        );
+        assert_eq!(
+            parse_time("2023-11-14 20:13:20-02:00"),
+            Some(1_700_000_000_000)
+        );
+        assert_eq!(parse_time("1970-01-01T00:00:00Z"), Some(0));
+        assert_eq!(parse_time("1969-12-31T23:59:59Z"), None);
+        assert_eq!(parse_time("2023-11-14"), None);
+        assert_eq!(parse_time("2023-11-14T22:13Z"), None);
+        assert_eq!(parse_time("now-1h"), None);
+    }
+
+    #[test]
+    fn test_parse_query() {
+        let body = json!({
+            "range": { "from": "2023-11-14T22:13:20Z", "to": "2023-11-14T23:13:20Z" },
+            "intervalMs": 30000,
+            "targets": [{ "target": "edges" }, { "target": "throughput" }],
+        });
+        assert_eq!(
+            Query::parse(&body).unwrap(),
+            Query {
+                from_ms: 1_700_000_000_000,
+                to_ms: 1_700_003_600_000,
+                step_ms: 30_000,
+                targets: vec!["edges", "throughput"],
+            }
+        );
+
+        let body = json!({ "range": { "from": "1000", "to": "2000" } });
+        let query = Query::parse(&body).unwrap();
+        assert_eq!((query.step_ms, query.targets.len()), (DEFAULT_STEP_MS, 0));
+
+        let error = |body: Value| Query::parse(&body).unwrap_err().to_string();
+        assert_eq!(error(json!({})), "Missing range.from");
+        assert_eq!(
+            error(json!({ "range": { "from": "1000", "to": "yesterday" } })),
+            "Invalid range.to: yesterday"
+        );
+        assert_eq!(
+            error(json!({
+                "range": { "from": "1000", "to": "2000" },
+                "targets": [{ "target": "pods" }],
+            })),
+            "Unknown target: pods"
+        );
+    }
+
+    fn graph() -> Graph {
+        let symbols = SymbolTable::default();
+        let workload = |name: &str| {
+            Arc::new(Workload {
+                name: symbols.intern(name),
+                namespace: symbols.intern("shop"),
+                kind: symbols.intern("Deployment"),
+                labels: Vec::new(),
+            })
+        };
+        let mut graph = Graph::default();
+        graph.extend(vec![GraphEdge {
+            client: workload("frontend"),
+            server: workload("cart"),
+            server_port: 8080,
+            protocol: "http",
+            bytes_sent: 1000,
+            bytes_received: 4000,
+            active_conns: 2,
+            resets: 1,
+            connect_timeouts: 0,
+        }]);
+        graph
+    }
+
+    #[test]
+    fn test_node_graph_objects() {
+        let graph = graph();
+        assert_eq!(
+            table_objects(NODE_COLUMNS, node_rows(&graph)),
+            json!([
+                { "id": "shop/cart", "title": "cart", "subtitle": "shop", "detail__kind": "Deployment" },
+                { "id": "shop/frontend", "title": "frontend", "subtitle": "shop", "detail__kind": "Deployment" },
+            ])
+        );
+        assert_eq!(
+            table_objects(EDGE_COLUMNS, edge_rows(&graph)),
+            json!([{
+                "id": "shop/frontend->shop/cart:8080/http",
+                "source": "shop/frontend",
+                "target": "shop/cart",
+                "mainstat": 1000,
+                "detail__port": 8080,
+                "detail__protocol": "http",
+                "detail__bytes_sent": 1000,
+                "detail__bytes_received": 4000,
+                "detail__active_connections": 2,
+                "detail__resets": 1,
+                "detail__connect_timeouts": 0,
+            }])
+        );
+    }
+
+    #[test]
+    fn test_simple_json_shapes() {
+        let graph = graph();
+        let table = table(EDGE_COLUMNS, edge_rows(&graph));
+        assert_eq!(table["type"], "table");
+        assert_eq!(
+            table["columns"][0],
+            json!({ "text": "id", "type": "string" })
+        );
+        assert_eq!(
+            table["columns"][3],
+            json!({ "text": "mainstat", "type": "number" })
+        );
+        assert_eq!(
+            table["rows"][0].as_array().unwrap().len(),
+            EDGE_COLUMNS.len()
+        );
+        assert_eq!(
+            time_series("throughput", vec![(1000, 2.5), (2000, 0.0)]),
+            json!({ "target": "throughput", "datapoints": [[2.5, 1000], [0.0, 2000]] })
+        );
+    }
+
+    fn snapshot(timestamp_ms: u64, bps: &[f64]) -> ServiceMapSnapshot {
+        ServiceMapSnapshot {
+            timestamp_ns: timestamp_ms * 1_000_000,
+            edges: bps
+                .iter()
+                .map(|&throughput_bps| ServiceMapEdge {
+                    throughput_bps,
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_sum_throughput() {
+        let programs = vec![
+            // Two snapshots averaged in the first bucket, none in the second.
+            vec![
+                snapshot(10_000, &[100.0, 50.0]),
+                snapshot(40_000, &[50.0]),
+                snapshot(130_000, &[10.0]),
+            ],
+            vec![snapshot(59_999, &[1.0]), snapshot(60_000, &[2.0])],
+        ];
+        assert_eq!(
+            sum_throughput(programs, 0, 60_000),
+            [(0, 101.0), (60_000, 2.0), (120_000, 10.0)]
+        );
+        assert!(sum_throughput(Vec::new(), 0, 60_000).is_empty());
+    }
+}
@@ -0,0 +1,575 @@
+use std::collections::{BTreeMap, HashSet};
+
+use log::debug;
+use prost::Message;
+
+use crate::common::native_histogram::{BucketSpan, NativeHistogramSeries};
+
+/// Content type of the delimited protobuf exposition, the only scrape format able to
+/// carry native histograms.
+pub(crate) const PROTOBUF_CONTENT_TYPE: &str =
+    "application/vnd.google.protobuf; proto=io.prometheus.client.MetricFamily; encoding=delimited";
+
+/// Type of a metric family, as declared by the `# TYPE` line of a text exposition.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum MetricType {
+    Counter,
+    Gauge,
+    Histogram,
+    /// Untyped, or of a type the protobuf exposition isn't given, e.g. info.
+    #[default]
+    Unknown,
+}
+
+impl MetricType {
+    fn parse(s: &str) -> Self {
+        match s {
+            "counter" => MetricType::Counter,
+            "gauge" => MetricType::Gauge,
+            "histogram" => MetricType::Histogram,
+            _ => MetricType::Unknown,
+        }
+    }
+
+    /// Suffixes the samples of a family of this type add to the family name.
+    fn suffixes(&self) -> &'static [&'static str] {
+        match self {
+            MetricType::Counter => &["_total", "_created"],
+            MetricType::Histogram => &["_bucket", "_sum", "_count", "_created"],
+            MetricType::Gauge | MetricType::Unknown => &[""],
+        }
+    }
+}
+
+/// One sample of a text exposition.
+#[derive(Debug, Clone)]
+pub(crate) struct ParsedSample {
+    pub(crate) name: String,
+    pub(crate) labels: Vec<(String, String)>,
+    pub(crate) value: f64,
+    /// Family the sample belongs to, its own name when it has no `# TYPE` line.
+    pub(crate) family: String,
+    pub(crate) metric_type: MetricType,
+}
+
+/// Parses the samples of a text exposition, along with the type of their family.
+/// Other comment lines are skipped.
+pub(crate) fn parse_text(text: &str) -> Vec<ParsedSample> {
+    let mut samples = Vec::new();
+    let mut family: Option<(&str, MetricType)> = None;
+    for line in text.lines() {
+        if let Some(comment) = line.strip_prefix('#') {
+            let mut words = comment.split_whitespace();
+            if let (Some("TYPE"), Some(name), Some(metric_type)) =
+                (words.next(), words.next(), words.next())
+            {
+                family = Some((name, MetricType::parse(metric_type)));
+            }
+            continue;
+        }
+        if line.is_empty() {
+            continue;
+        }
+        let Some(mut sample) = parse_sample(line) else {
+            debug!("Skipping unparsable sample {:?}", line);
+            continue;
+        };
+        if let Some((name, metric_type)) = family {
+            let belongs = metric_type
+                .suffixes()
+                .iter()
+                .any(|suffix| sample.name.strip_suffix(suffix) == Some(name));
+            if belongs {
+                sample.family = name.to_string();
+                sample.metric_type = metric_type;
+            }
+        }
+        samples.push(sample);
+    }
+    samples
+}
+
+/// Parses `name{label="value",...} value`, ignoring any timestamp or exemplar after
+/// the value.
+fn parse_sample(line: &str) -> Option<ParsedSample> {
+    let name_end = line.find(['{', ' '])?;
+    let name = line[..name_end].to_string();
+    let mut labels = Vec::new();
+
+    let mut rest = &line[name_end..];
+    if let Some(mut s) = rest.strip_prefix('{') {
+        loop {
+            s = s.trim_start();
+            if let Some(after) = s.strip_prefix('}') {
+                rest = after;
+                break;
+            }
+            let (label, after) = s.split_once("=\"")?;
+            let mut value = String::new();
+            let mut chars = after.char_indices();
+            let end = loop {
+                match chars.next()? {
+                    (i, '"') => break i,
+                    (_, '\\') => match chars.next()?.1 {
+                        'n' => value.push('\n'),
+                        c => value.push(c),
+                    },
+                    (_, c) => value.push(c),
+                }
+            };
+            labels.push((label.trim().to_string(), value));
+            s = after[end + 1..].trim_start();
+            s = s.strip_prefix(',').unwrap_or(s);
+        }
+    }
+
+    let value = rest.split_whitespace().next()?.parse::<f64>().ok()?;
+    Some(ParsedSample {
+        family: name.clone(),
+        name,
+        labels,
+        value,
+        metric_type: MetricType::Unknown,
+    })
+}
+
+/// Drops the classic bucket, sum and count series of the histograms that are also
+/// exported as native ones.
+pub(crate) fn without_classic(
+    samples: Vec<ParsedSample>,
+    natives: &[NativeHistogramSeries],
+) -> Vec<ParsedSample> {
+    let names: HashSet<&str> = natives.iter().map(|n| n.name.as_str()).collect();
+    samples
+        .into_iter()
+        .filter(|sample| {
+            let base = ["_bucket", "_sum", "_count"]
+                .iter()
+                .find_map(|suffix| sample.name.strip_suffix(suffix));
+            !base.is_some_and(|base| names.contains(base))
+        })
+        .collect()
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct MetricFamily {
+    #[prost(string, tag = "1")]
+    name: String,
+    #[prost(string, tag = "2")]
+    help: String,
+    #[prost(int32, tag = "3")]
+    r#type: i32,
+    #[prost(message, repeated, tag = "4")]
+    metric: Vec<Metric>,
+}
+
+const METRIC_TYPE_COUNTER: i32 = 0;
+const METRIC_TYPE_GAUGE: i32 = 1;
+const METRIC_TYPE_UNTYPED: i32 = 3;
+const METRIC_TYPE_HISTOGRAM: i32 = 4;
+
+#[derive(Clone, PartialEq, Message)]
+struct Metric {
+    #[prost(message, repeated, tag = "1")]
+    label: Vec<LabelPair>,
+    #[prost(message, optional, tag = "2")]
+    gauge: Option<Value>,
+    #[prost(message, optional, tag = "3")]
+    counter: Option<Value>,
+    #[prost(message, optional, tag = "5")]
+    untyped: Option<Value>,
+    #[prost(message, optional, tag = "7")]
+    histogram: Option<Histogram>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct LabelPair {
+    #[prost(string, tag = "1")]
+    name: String,
+    #[prost(string, tag = "2")]
+    value: String,
+}
+
+/// The value of a counter, gauge or untyped metric, which all encode the same.
+#[derive(Clone, PartialEq, Message)]
+struct Value {
+    #[prost(double, tag = "1")]
+    value: f64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Histogram {
+    #[prost(uint64, tag = "1")]
+    sample_count: u64,
+    #[prost(double, tag = "2")]
+    sample_sum: f64,
+    #[prost(message, repeated, tag = "3")]
+    bucket: Vec<Bucket>,
+    #[prost(sint32, tag = "5")]
+    schema: i32,
+    #[prost(double, tag = "6")]
+    zero_threshold: f64,
+    #[prost(uint64, tag = "7")]
+    zero_count: u64,
+    #[prost(message, repeated, tag = "12")]
+    positive_span: Vec<Span>,
+    #[prost(sint64, repeated, tag = "13")]
+    positive_delta: Vec<i64>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Bucket {
+    #[prost(uint64, tag = "1")]
+    cumulative_count: u64,
+    #[prost(double, tag = "2")]
+    upper_bound: f64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub(crate) struct Span {
+    #[prost(sint32, tag = "1")]
+    pub(crate) offset: i32,
+    #[prost(uint32, tag = "2")]
+    pub(crate) length: u32,
+}
+
+impl From<&BucketSpan> for Span {
+    fn from(span: &BucketSpan) -> Self {
+        Self {
+            offset: span.offset,
+            length: span.length,
+        }
+    }
+}
+
+/// Encodes samples and native histograms in the delimited protobuf exposition, one
+/// family per `# TYPE` of the text exposition. The classic and native buckets of a
+/// histogram exported both ways are sent in the same metric. Samples of other types
+/// are sent as untyped families, one per series name.
+pub(crate) fn encode_protobuf(
+    samples: Vec<ParsedSample>,
+    natives: Vec<NativeHistogramSeries>,
+) -> Vec<u8> {
+    let mut families: BTreeMap<String, MetricFamily> = BTreeMap::new();
+    for sample in samples {
+        match sample.metric_type {
+            // Counters are named after their `_total` series, as in the Prometheus
+            // clients, and their creation time isn't carried.
+            MetricType::Counter if sample.name.ends_with("_total") => {
+                family(&mut families, &sample.name, METRIC_TYPE_COUNTER)
+                    .metric
+                    .push(Metric {
+                        label: label_pairs(sample.labels),
+                        counter: Some(Value {
+                            value: sample.value,
+                        }),
+                        ..Default::default()
+                    })
+            }
+            MetricType::Counter => {}
+            MetricType::Gauge => family(&mut families, &sample.name, METRIC_TYPE_GAUGE)
+                .metric
+                .push(Metric {
+                    label: label_pairs(sample.labels),
+                    gauge: Some(Value {
+                        value: sample.value,
+                    }),
+                    ..Default::default()
+                }),
+            MetricType::Histogram => {
+                let mut labels = sample.labels;
+                let le = labels
+                    .iter()
+                    .position(|(name, _)| name == "le")
+                    .map(|i| labels.remove(i).1);
+                let family = family(&mut families, &sample.family, METRIC_TYPE_HISTOGRAM);
+                let histogram = histogram(family, label_pairs(labels));
+                match &sample.name[sample.family.len()..] {
+                    "_bucket" => {
+                        // The +Inf bucket is implied by the sample count.
+                        if let Some(upper_bound) = le
+                            .and_then(|le| le.parse::<f64>().ok())
+                            .filter(|bound| bound.is_finite())
+                        {
+                            histogram.bucket.push(Bucket {
+                                cumulative_count: sample.value as u64,
+                                upper_bound,
+                            });
+                        }
+                    }
+                    "_sum" => histogram.sample_sum = sample.value,
+                    "_count" => histogram.sample_count = sample.value as u64,
+                    _ => {}
+                }
+            }
+            MetricType::Unknown => family(&mut families, &sample.name, METRIC_TYPE_UNTYPED)
+                .metric
+                .push(Metric {
+                    label: label_pairs(sample.labels),
+                    untyped: Some(Value {
+                        value: sample.value,
+                    }),
+                    ..Default::default()
+                }),
+        }
+    }
+
+    for native in natives {
+        let family = family(&mut families, &native.name, METRIC_TYPE_HISTOGRAM);
+        if family.help.is_empty() {
+            family.help = native.help.clone();
+        }
+        let native_histogram = &native.histogram;
+        let (spans, deltas) = native_histogram.positive_buckets();
+        let histogram = histogram(family, label_pairs(native.labels.clone()));
+        histogram.sample_count = native_histogram.count();
+        histogram.sample_sum = native_histogram.sum();
+        histogram.schema = native_histogram.schema();
+        histogram.zero_threshold = native_histogram.zero_threshold();
+        histogram.zero_count = native_histogram.zero_count();
+        histogram.positive_span = spans.iter().map(Span::from).collect();
+        histogram.positive_delta = deltas;
+    }
+
+    let mut buf = Vec::new();
+    for family in families.values() {
+        // Writing to a Vec can't run out of space.
+        let _ = family.encode_length_delimited(&mut buf);
+    }
+    buf
+}
+
+/// Returns the family `name`, added with type `metric_type` if it is new.
+fn family<'a>(
+    families: &'a mut BTreeMap<String, MetricFamily>,
+    name: &str,
+    metric_type: i32,
+) -> &'a mut MetricFamily {
+    families
+        .entry(name.to_string())
+        .or_insert_with(|| MetricFamily {
+            name: name.to_string(),
+            r#type: metric_type,
+            ..Default::default()
+        })
+}
+
+/// Returns the histogram of `family` with `labels`, in any order, added if it is new.
+fn histogram(family: &mut MetricFamily, labels: Vec<LabelPair>) -> &mut Histogram {
+    let position = family.metric.iter().position(|metric| {
+        metric.label.len() == labels.len() && labels.iter().all(|l| metric.label.contains(l))
+    });
+    let index = match position {
+        Some(index) => index,
+        None => {
+            family.metric.push(Metric {
+                label: labels,
+                ..Default::default()
+            });
+            family.metric.len() - 1
+        }
+    };
+    family.metric[index]
+        .histogram
+        .get_or_insert_with(Histogram::default)
+}
+fn label_pairs(labels: Vec<(String, String)>) -> Vec<LabelPair> {
+    labels
+        .into_iter()
+        .map(|(name, value)| LabelPair { name, value })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use prometheus_client::encoding::text::encode;
+    use prometheus_client::metrics::counter::Counter;
+    use prometheus_client::metrics::family::Family;
+    use prometheus_client::metrics::gauge::Gauge;
+    use prometheus_client::metrics::histogram::Histogram as ClassicHistogram;
+    use prometheus_client::registry::Registry;
+
+    use super::*;
+    use crate::common::native_histogram::NativeHistogram;
+
+    /// Exposes a counter, a gauge and a histogram the way the agent does.
+    fn exposition() -> String {
+        let mut registry = <Registry>::default();
+        let requests = Family::<Vec<(String, String)>, Counter>::default();
+        requests
+            .get_or_create(&vec![("server".to_string(), "checkout".to_string())])
+            .inc_by(7);
+        registry.register("http_requests", "requests served", requests);
+        let connections = Gauge::<i64>::default();
+        connections.set(3);
+        registry.register("tcp_connections", "open connections", connections);
+        let latency =
+            Family::<Vec<(String, String)>, ClassicHistogram>::new_with_constructor(|| {
+                ClassicHistogram::new([0.1, 1.0].into_iter())
+            });
+        for value in [0.05, 0.5, 5.0] {
+            latency
+                .get_or_create(&vec![("server".to_string(), "checkout".to_string())])
+                .observe(value);
+        }
+        registry.register("http_latency_seconds", "request latency", latency);
+
+        let mut text = String::new();
+        encode(&mut text, &registry).unwrap();
+        text
+    }
+
+    fn decode(mut buf: &[u8]) -> Vec<MetricFamily> {
+        let mut families = Vec::new();
+        while !buf.is_empty() {
+            families.push(MetricFamily::decode_length_delimited(&mut buf).unwrap());
+        }
+        families
+    }
+
+    fn labels(pairs: &[(&str, &str)]) -> Vec<LabelPair> {
+        pairs
+            .iter()
+            .map(|(name, value)| LabelPair {
+                name: name.to_string(),
+                value: value.to_string(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_text_types() {
+        let samples = parse_text(&exposition());
+        let sample = |name: &str| samples.iter().find(|s| s.name == name).unwrap();
+
+        let total = sample("http_requests_total");
+        assert_eq!(total.family, "http_requests");
+        assert_eq!(total.metric_type, MetricType::Counter);
+        assert_eq!(total.value, 7.0);
+        assert_eq!(
+            total.labels,
+            vec![("server".to_string(), "checkout".to_string())]
+        );
+        assert_eq!(sample("tcp_connections").metric_type, MetricType::Gauge);
+        for name in [
+            "http_latency_seconds_bucket",
+            "http_latency_seconds_sum",
+            "http_latency_seconds_count",
+        ] {
+            assert_eq!(sample(name).family, "http_latency_seconds");
+            assert_eq!(sample(name).metric_type, MetricType::Histogram);
+        }
+    }
+
+    #[test]
+    fn test_parse_text_without_type() {
+        let samples = parse_text("# TYPE up gauge\nup 1\nbuild_info{version=\"1.0\"} 1\n# EOF\n");
+        assert_eq!(samples.len(), 2);
+        assert_eq!(samples[0].metric_type, MetricType::Gauge);
+        // Not a sample of the family declared before it.
+        assert_eq!(samples[1].family, "build_info");
+        assert_eq!(samples[1].metric_type, MetricType::Unknown);
+        assert_eq!(
+            samples[1].labels,
+            vec![("version".to_string(), "1.0".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_encode_protobuf_families() {
+        let families = decode(&encode_protobuf(parse_text(&exposition()), vec![]));
+        let names: Vec<_> = families.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(
+            names,
+            vec![
+                "http_latency_seconds",
+                "http_requests_total",
+                "tcp_connections"
+            ]
+        );
+
+        let histogram = &families[0];
+        assert_eq!(histogram.r#type, METRIC_TYPE_HISTOGRAM);
+        assert_eq!(histogram.metric.len(), 1);
+        assert_eq!(histogram.metric[0].label, labels(&[("server", "checkout")]));
+        let classic = histogram.metric[0].histogram.as_ref().unwrap();
+        assert_eq!(classic.sample_count, 3);
+        assert!((classic.sample_sum - 5.55).abs() < 1e-9);
+        assert_eq!(
+            classic.bucket,
+            vec![
+                Bucket {
+                    cumulative_count: 1,
+                    upper_bound: 0.1,
+                },
+                Bucket {
+                    cumulative_count: 2,
+                    upper_bound: 1.0,
+                },
+            ]
+        );
+        assert!(classic.positive_span.is_empty());
+
+        let counter = &families[1];
+        assert_eq!(counter.r#type, METRIC_TYPE_COUNTER);
+        assert_eq!(counter.metric[0].counter, Some(Value { value: 7.0 }));
+        assert_eq!(counter.metric[0].untyped, None);
+
+        let gauge = &families[2];
+        assert_eq!(gauge.r#type, METRIC_TYPE_GAUGE);
+        assert_eq!(gauge.metric[0].gauge, Some(Value { value: 3.0 }));
+    }
+
+    #[test]
+    fn test_encode_protobuf_native_histograms() {
+        let mut native = NativeHistogram::default();
+        native.observe(0.05);
+        native.observe(0.5);
+        native.observe(5.0);
+        let natives = vec![NativeHistogramSeries {
+            name: "http_latency_seconds".to_string(),
+            help: "request latency".to_string(),
+            labels: vec![("server".to_string(), "checkout".to_string())],
+            histogram: native.clone(),
+        }];
+        let (spans, deltas) = native.positive_buckets();
+
+        // Exported both ways, the buckets are sent in the same metric.
+        let samples = parse_text(&exposition());
+        let families = decode(&encode_protobuf(samples.clone(), natives.clone()));
+        let histogram = &families[0];
+        assert_eq!(histogram.name, "http_latency_seconds");
+        assert_eq!(histogram.help, "request latency");
+        assert_eq!(histogram.metric.len(), 1);
+        let both = histogram.metric[0].histogram.as_ref().unwrap();
+        assert_eq!(both.bucket.len(), 2);
+        assert_eq!(both.sample_count, 3);
+        assert_eq!(both.schema, native.schema());
+        assert_eq!(both.positive_delta, deltas);
+        assert_eq!(
+            both.positive_span,
+            spans.iter().map(Span::from).collect::<Vec<_>>()
+        );
+
+        // Without the classic series, only the native buckets are left.
+        let samples = without_classic(samples, &natives);
+        assert!(!samples
+            .iter()
+            .any(|s| s.name.starts_with("http_latency_seconds")));
+        let families = decode(&encode_protobuf(samples, natives));
+        let native_only = families[0].metric[0].histogram.as_ref().unwrap();
+        assert!(native_only.bucket.is_empty());
+        assert_eq!(native_only.sample_count, 3);
+        assert_eq!(native_only.positive_delta, deltas);
+    }
+
+    #[test]
+    fn test_encode_protobuf_untyped() {
+        let samples = parse_text("build_info{version=\"1.0\"} 1\n");
+        let families = decode(&encode_protobuf(samples, vec![]));
+        assert_eq!(families.len(), 1);
+        assert_eq!(families[0].r#type, METRIC_TYPE_UNTYPED);
+        assert_eq!(families[0].metric[0].label, labels(&[("version", "1.0")]));
+        assert_eq!(families[0].metric[0].untyped, Some(Value { value: 1.0 }));
+    }
+}
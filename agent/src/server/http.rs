@@ -10,41 +10,67 @@ use hyper::service::service_fn;
 use hyper::{Request, Response, StatusCode};
 use hyper_util::rt::TokioIo;
 use log::{debug, info};
-use prometheus_client::{encoding::text::encode, registry::Registry};
+use prometheus_client::encoding::text::{encode_eof, encode_registry};
+use prometheus_client::registry::Registry;
 use tokio::net::TcpListener;
 use tokio::pin;
 use tokio::sync::broadcast::Receiver;
 use tokio::task::JoinHandle;
 
 use crate::collector::Collector;
+use crate::common::native_histogram::HistogramMode;
+use crate::managers::cache::CacheManager;
 use crate::managers::registry::RegistryManager;
+use crate::managers::scheduler::PollScheduler;
 use crate::progs::types::ShutdownSignal;
+use crate::server::datasource::{self, DATASOURCE_PATH};
+use crate::server::exposition::{
+    encode_protobuf, parse_text, without_classic, PROTOBUF_CONTENT_TYPE,
+};
+use crate::server::push::PushStats;
 
 pub async fn serve(
     address: String,
     registry_manager: RegistryManager,
+    scheduler: PollScheduler,
+    cache_manager: CacheManager,
+    push_stats: Option<PushStats>,
+    histograms: HistogramMode,
     shutdown_rx: Receiver<ShutdownSignal>,
 ) -> anyhow::Result<JoinHandle<()>> {
     let metrics_addr = address.parse::<SocketAddr>()?;
-    let collector = Box::new(Collector::new(registry_manager));
-    let mut registry = Registry::default();
-    registry.register_collector(collector);
+    let collector =
+        Collector::new(registry_manager, scheduler, cache_manager).with_push_stats(push_stats);
     let server_handle = tokio::spawn(async move {
-        start_metrics_server(metrics_addr, registry, shutdown_rx)
-            .await
-            .unwrap();
+        start_metrics_server(
+            metrics_addr,
+            Registry::default(),
+            Some(collector),
+            histograms,
+            shutdown_rx,
+        )
+        .await
+        .unwrap();
     });
     Ok(server_handle)
 }
 
-/// Start an HTTP server to report metrics.
+/// Start an HTTP server to report metrics. Scrapes get the metrics of `registry`,
+/// followed by those of the registry the collector builds for them. Scrapes accepting
+/// the protobuf exposition also get the native histograms of the collector, when
+/// `histograms` asks for them.
+/// Requests under [`DATASOURCE_PATH`] are answered by the Grafana datasource of the
+/// collector instead.
 async fn start_metrics_server(
     addr: SocketAddr,
     registry: Registry,
+    collector: Option<Collector>,
+    histograms: HistogramMode,
     mut shutdown_rx: Receiver<ShutdownSignal>,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let listener = TcpListener::bind(&addr).await?;
     let registry = Arc::new(registry);
+    let collector = Arc::new(collector);
     let connection_timeouts = vec![Duration::from_secs(5), Duration::from_secs(2)];
 
     loop {
@@ -62,10 +88,11 @@ async fn start_metrics_server(
                 let (stream, _) = accept_result?;
                 let io = TokioIo::new(stream);
                 let registry = registry.clone();
+                let collector = collector.clone();
                 let connection_timeouts_clone = connection_timeouts.clone();
 
                 tokio::task::spawn(async move {
-                    let conn = http1::Builder::new().serve_connection(io, service_fn(move |req| request_handler(registry.clone(), req)));
+                    let conn = http1::Builder::new().serve_connection(io, service_fn(move |req| request_handler(registry.clone(), collector.clone(), histograms, req)));
                     pin!(conn);
 
                     for sleep_duration in connection_timeouts_clone {
@@ -93,19 +120,52 @@ async fn start_metrics_server(
 
 async fn request_handler(
     registry: Arc<Registry>,
-    _request: Request<hyper::body::Incoming>,
+    collector: Arc<Option<Collector>>,
+    histograms: HistogramMode,
+    request: Request<hyper::body::Incoming>,
 ) -> Result<Response<Full<Bytes>>, Infallible> {
-    let reg = registry.clone();
+    if let (Some(path), Some(collector)) = (
+        request.uri().path().strip_prefix(DATASOURCE_PATH),
+        collector.as_ref(),
+    ) {
+        let path = path.to_string();
+        return Ok(datasource::handle(collector, &path, request).await);
+    }
+
     let mut buf = String::new();
-    match encode(&mut buf, &reg.clone()) {
-        Ok(_) => Ok(Response::builder()
+    let accepts_protobuf = request
+        .headers()
+        .get(hyper::header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("application/vnd.google.protobuf"));
+    let encoded = encode_registry(&mut buf, &registry)
+        .and_then(|_| match collector.as_ref() {
+            Some(collector) => encode_registry(&mut buf, &collector.registry()),
+            None => Ok(()),
+        })
+        .and_then(|_| encode_eof(&mut buf));
+    match (encoded, collector.as_ref()) {
+        // Native histograms can only be expressed in the protobuf exposition, so the
+        // text one always carries the classic histograms.
+        (Ok(_), Some(collector)) if accepts_protobuf && histograms.native() => {
+            let mut samples = parse_text(&buf);
+            let natives = collector.native_histograms();
+            if !histograms.classic() {
+                samples = without_classic(samples, &natives);
+            }
+            Ok(Response::builder()
+                .header(hyper::header::CONTENT_TYPE, PROTOBUF_CONTENT_TYPE)
+                .body(Full::from(encode_protobuf(samples, natives)))
+                .unwrap())
+        }
+        (Ok(_), _) => Ok(Response::builder()
             .header(
                 hyper::header::CONTENT_TYPE,
                 "application/openmetrics-text; version=1.0.0; charset=utf-8",
             )
             .body(Full::from(buf))
             .unwrap()),
-        Err(_) => {
+        (Err(_), _) => {
             // Handle or ignore the error here.
             // For example, you can return an empty response with a status code of 500.
             Ok(Response::builder()
@@ -183,9 +243,15 @@ mod tests {
         let (_, shutdown_rx) = tokio::sync::broadcast::channel(1);
 
         let server_handle = tokio::spawn(async move {
-            start_metrics_server(metrics_addr, registry, shutdown_rx)
-                .await
-                .unwrap();
+            start_metrics_server(
+                metrics_addr,
+                registry,
+                None,
+                HistogramMode::Classic,
+                shutdown_rx,
+            )
+            .await
+            .unwrap();
         });
 
         // Add a delay to ensure the server has time to start
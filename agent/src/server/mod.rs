@@ -1,3 +1,6 @@
+use std::sync::Arc;
+use std::time::Duration;
+
 use bpfman_api::v1::bpfman_client::BpfmanClient;
 use log::debug;
 use tokio::signal::unix::{signal, SignalKind};
@@ -7,36 +10,215 @@ use tokio::task::{JoinHandle, JoinSet};
 use agent_api::select_channel;
 use agent_api::v1::agent_server::AgentServer;
 
+use crate::common::constants::directories::{RTPATH_POD_TRACE, SOCK_MODE};
+use crate::common::constants::DEFAULT_FINDINGS_INTERVAL;
+use crate::managers::alias::WorkloadAliases;
+use crate::managers::audit::AuditLog;
+use crate::managers::cache::CacheManager;
+use crate::managers::events::EventsManager;
+use crate::managers::findings::FindingsMonitor;
+use crate::managers::pod_trace::PodTracer;
+use crate::managers::process::hostname;
 use crate::managers::prog::ProgManager;
+use crate::managers::slow_query::SlowQueryLog;
 use crate::progs::types::ShutdownSignal;
+use crate::server::access::PeerAllowlist;
+use crate::server::push::{PushConfig, PushStats};
+use crate::server::remote_write::RemoteWriteConfig;
+use crate::server::rpc::ListenAddr;
+use crate::server::sflow::SflowConfig;
+use crate::server::systemd::ListenFds;
 use crate::Args;
 
+pub(crate) mod access;
+pub(crate) mod datasource;
+pub(crate) mod exposition;
 pub(crate) mod http;
+pub(crate) mod push;
+pub(crate) mod remote_write;
 pub(crate) mod rpc;
+pub(crate) mod sflow;
+pub(crate) mod systemd;
+pub(crate) mod ui;
 
-pub(crate) async fn serve(args: Args) -> anyhow::Result<()> {
-    let (shutdown_tx, shutdown_rx1) = broadcast::channel(32);
+/// Runs the agent until it is told to stop, serving its API on `listen_fds` when
+/// systemd passed any, see [`listen_fds`](systemd::listen_fds).
+pub async fn serve(args: Args, listen_fds: ListenFds) -> anyhow::Result<()> {
+    let (shutdown_tx, _) = broadcast::channel(32);
     let shutdown_handle = tokio::spawn(shutdown_handler(shutdown_tx.clone()));
 
     let channel = select_channel(args.bpfman_socket_path).unwrap();
     let bpf_client = BpfmanClient::new(channel);
-    let prog_manager = ProgManager::new(shutdown_tx.clone()).await?;
-    let agent_service = rpc::AgentService::new(prog_manager.clone(), bpf_client);
-    let service = AgentServer::new(agent_service);
+    let slow_queries = SlowQueryLog::new(
+        Duration::from_millis(args.slow_query_threshold),
+        args.slow_query_keep_redis_keys,
+        args.slow_query_log_path.as_deref(),
+    )?;
+    let events_manager = EventsManager::new(
+        args.request_buffer_size,
+        args.request_sample_rate,
+        args.baggage_key,
+        slow_queries,
+    );
+    let aliases = WorkloadAliases::new(args.workload_alias, args.workload_aliases_file.as_deref())?;
+    let cache_manager = if args.standalone {
+        CacheManager::standalone(args.container_socket, aliases).await
+    } else {
+        let cache_manager = CacheManager::new(args.workload_label, aliases).await?;
+        cache_manager.wait_for_cache_sync().await?;
+        cache_manager
+    };
+    let pod_tracer = PodTracer::new(
+        args.socket_tracer_maps,
+        RTPATH_POD_TRACE.into(),
+        cache_manager.clone(),
+        events_manager.clone(),
+    );
+    pod_tracer.restore();
+    let prog_manager = ProgManager::new(
+        shutdown_tx.clone(),
+        args.poll_workers,
+        events_manager,
+        cache_manager,
+        AuditLog::new(args.audit_log_size, args.audit_log_path.as_deref())?,
+    )
+    .await?;
+    let agent_service = Arc::new(rpc::AgentService::new(
+        prog_manager.clone(),
+        bpf_client,
+        pod_tracer.clone(),
+    ));
+    let service = AgentServer::from_arc(agent_service.clone());
 
     let mut listeners: Vec<_> = Vec::new();
-    let rpc_handler = rpc::serve(&args.agent_socket_path, service, shutdown_rx1).await?;
-    listeners.push(rpc_handler);
+    // Sockets passed by systemd replace the configured unix socket.
+    let mut api_addrs: Vec<_> = listen_fds
+        .into_api()
+        .into_iter()
+        .map(ListenAddr::Systemd)
+        .collect();
+    if api_addrs.is_empty() {
+        api_addrs.push(ListenAddr::Unix {
+            path: args.agent_socket_path,
+            mode: args.agent_socket_mode.unwrap_or(SOCK_MODE),
+            peers: PeerAllowlist::new(args.agent_socket_allow_uid, args.agent_socket_allow_gid),
+        });
+    }
+    if let Some(addr) = args.agent_addr {
+        api_addrs.push(ListenAddr::Tcp {
+            addr,
+            token_file: args.agent_token_file.clone(),
+        });
+    }
+    for addr in api_addrs {
+        let rpc_handler = rpc::serve(
+            addr,
+            service.clone(),
+            args.agent_token_file.clone(),
+            shutdown_tx.subscribe(),
+        )
+        .await?;
+        listeners.push(rpc_handler);
+    }
+    let push_stats = (!args.push_server_url.is_empty()).then(PushStats::default);
     let shutdown_rx2 = shutdown_tx.subscribe();
     let http_server = http::serve(
         args.metrics_addr,
         prog_manager.registry_manager.clone(),
+        prog_manager.scheduler.clone(),
+        prog_manager.cache_manager.clone(),
+        push_stats.clone(),
+        args.metrics_histograms,
         shutdown_rx2,
     )
     .await?;
     listeners.push(http_server);
 
+    if let Some(url) = args.remote_write_url {
+        let config = RemoteWriteConfig {
+            url,
+            interval: Duration::from_secs(args.remote_write_interval.max(1)),
+            batch_size: args.remote_write_batch_size,
+            labels: args.remote_write_label,
+            bearer_token_file: args.remote_write_bearer_token_file,
+            ca_file: args.remote_write_ca_file,
+            cert_file: args.remote_write_cert_file,
+            key_file: args.remote_write_key_file,
+            histograms: args.remote_write_histograms,
+        };
+        let remote_write = remote_write::serve(
+            config,
+            prog_manager.registry_manager.clone(),
+            prog_manager.scheduler.clone(),
+            prog_manager.cache_manager.clone(),
+            push_stats.clone(),
+            shutdown_tx.subscribe(),
+        )
+        .await?;
+        listeners.push(remote_write);
+    }
+
+    if let Some(collector) = args.sflow_collector {
+        let config = SflowConfig {
+            collector,
+            interval: Duration::from_secs(args.sflow_interval.max(1)),
+            sampling_rate: args.sflow_sampling_rate,
+            agent_address: args.sflow_agent_address,
+        };
+        let sflow = sflow::serve(
+            config,
+            prog_manager.registry_manager.clone(),
+            shutdown_tx.subscribe(),
+        )
+        .await?;
+        listeners.push(sflow);
+    }
+
+    let node_name = args.node_name.unwrap_or_else(hostname);
+    if !args.push_server_url.is_empty() {
+        let config = PushConfig {
+            urls: args.push_server_url,
+            node_name: node_name.clone(),
+            interval: Duration::from_secs(args.push_interval.max(1)),
+            buffer_size: args.push_buffer_size,
+            batch_size: args.push_batch_size,
+            coalesce: !args.push_no_coalesce,
+            compression: args.push_compression,
+            token_file: args.push_token_file,
+        };
+        let push = push::serve(
+            config,
+            agent_service,
+            push_stats.unwrap_or_default(),
+            shutdown_tx.subscribe(),
+        )
+        .await?;
+        listeners.push(push);
+    }
+
+    if let Some(addr) = args.ui_addr {
+        let ui = ui::serve(
+            addr,
+            prog_manager.registry_manager.clone(),
+            shutdown_tx.subscribe(),
+        )
+        .await?;
+        listeners.push(ui);
+    }
+
+    if args.finding_events {
+        let monitor = FindingsMonitor::new(
+            prog_manager.clone(),
+            node_name,
+            Duration::from_secs(DEFAULT_FINDINGS_INTERVAL),
+        );
+        tokio::spawn(monitor.run(shutdown_tx.subscribe()));
+    }
+
+    systemd::notify("READY=1");
+
     let (_, res) = tokio::join!(join_listeners(listeners), shutdown_handle);
+    pod_tracer.stop();
     if let Some(e) = res.err() {
         return Err(e.into());
     }
@@ -68,5 +250,6 @@ pub(crate) async fn shutdown_handler(shutdown_tx: broadcast::Sender<ShutdownSign
     });
 
     joinset.join_next().await;
+    systemd::notify("STOPPING=1");
     shutdown_tx.send(ShutdownSignal::All).unwrap();
 }
@@ -0,0 +1,566 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use clap::ValueEnum;
+use log::{debug, info, warn};
+use prometheus_client::encoding::{DescriptorEncoder, EncodeLabelSet, EncodeMetric};
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::metrics::histogram::{exponential_buckets, Histogram};
+use prometheus_client::registry::Unit;
+use prost::Message as _;
+use tokio::sync::broadcast::Receiver;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::{Interval, MissedTickBehavior};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::codec::CompressionEncoding;
+use tonic::transport::{Channel, Endpoint};
+use tonic::{Request, Status};
+
+use agent_api::v1::agent_message::Message;
+use agent_api::v1::agent_server::Agent;
+use agent_api::v1::hub_client::HubClient;
+use agent_api::v1::hub_command::Command;
+use agent_api::v1::{
+    hub_command_result, AgentMessage, AgentRegistration, HubCommand, HubCommandResult,
+    ServiceMapBatch, ServiceMapPush,
+};
+use agent_api::{features, shard_of, API_VERSION};
+
+use crate::common::graph::{coalesce_updates, ServiceMapWatch};
+use crate::common::types::ListFilter;
+use crate::progs::types::{ShutdownSignal, SnapshotQuery};
+use crate::server::rpc::AgentService;
+
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Messages handed to the stream ahead of the transport. Kept small, since those not
+/// sent yet are lost with the stream.
+const STREAM_BUFFER: usize = 16;
+
+/// Where and how to push service maps to a server serving hub.
+#[derive(Debug, Clone)]
+pub(crate) struct PushConfig {
+    /// Replicas of the server, each owning the shard of nodes at its index.
+    pub(crate) urls: Vec<String>,
+    pub(crate) node_name: String,
+    pub(crate) interval: Duration,
+    /// Maximum number of updates kept while disconnected.
+    pub(crate) buffer_size: usize,
+    /// Maximum number of updates sent in one message.
+    pub(crate) batch_size: usize,
+    /// Whether an update queued behind one of the same program is folded into it.
+    pub(crate) coalesce: bool,
+    pub(crate) compression: PushCompression,
+    /// File holding the bearer token presented to the server.
+    pub(crate) token_file: Option<PathBuf>,
+}
+
+/// How the messages of the Connect stream are compressed.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum PushCompression {
+    None,
+    /// Zstandard, which the server must accept.
+    #[default]
+    Zstd,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct OutcomeLabels {
+    outcome: &'static str,
+}
+
+/// Metrics of the push to the server, exposed with those of the agent.
+#[derive(Debug, Clone)]
+pub(crate) struct PushStats {
+    /// Encoded size of each batch sent, before compression.
+    batch_sizes: Histogram,
+    /// Updates made, by what became of them: sent, coalesced into a later one of their
+    /// program, or dropped.
+    updates: Family<OutcomeLabels, Counter>,
+    buffered: Gauge,
+    connected: Gauge,
+}
+
+impl Default for PushStats {
+    fn default() -> Self {
+        Self {
+            batch_sizes: Histogram::new(exponential_buckets(256.0, 4.0, 10)),
+            updates: Family::default(),
+            buffered: Gauge::default(),
+            connected: Gauge::default(),
+        }
+    }
+}
+
+impl PushStats {
+    fn observe(&self, outcome: &'static str, updates: u64) {
+        self.updates
+            .get_or_create(&OutcomeLabels { outcome })
+            .inc_by(updates);
+    }
+
+    pub(crate) fn collect(&self, encoder: &mut DescriptorEncoder) -> Result<(), std::fmt::Error> {
+        let metric_encoder = encoder.encode_descriptor(
+            "push_batch_size",
+            "encoded size of each batch of service map updates pushed, before compression",
+            Some(&Unit::Bytes),
+            self.batch_sizes.metric_type(),
+        )?;
+        self.batch_sizes.encode(metric_encoder)?;
+
+        let metric_encoder = encoder.encode_descriptor(
+            "push_updates",
+            "service map updates made for the push server, by outcome",
+            None,
+            self.updates.metric_type(),
+        )?;
+        self.updates.encode(metric_encoder)?;
+
+        let metric_encoder = encoder.encode_descriptor(
+            "push_buffered_updates",
+            "service map updates waiting to be pushed",
+            None,
+            self.buffered.metric_type(),
+        )?;
+        self.buffered.encode(metric_encoder)?;
+
+        let metric_encoder = encoder.encode_descriptor(
+            "push_connected",
+            "whether the agent is connected to the push server",
+            None,
+            self.connected.metric_type(),
+        )?;
+        self.connected.encode(metric_encoder)
+    }
+}
+
+pub async fn serve(
+    config: PushConfig,
+    service: Arc<AgentService>,
+    stats: PushStats,
+    shutdown_rx: Receiver<ShutdownSignal>,
+) -> anyhow::Result<JoinHandle<()>> {
+    let shard = shard_of(&config.node_name, config.urls.len() as u32) as usize;
+    let mut replicas = Vec::with_capacity(config.urls.len());
+    for index in failover_order(shard, config.urls.len()) {
+        let url = &config.urls[index];
+        let endpoint = Endpoint::from_shared(url.clone())
+            .with_context(|| format!("invalid push server URL {}", url))?
+            .connect_timeout(CONNECT_TIMEOUT);
+        replicas.push((index, endpoint));
+    }
+    let pusher = Pusher {
+        outbox: Outbox::new(config.buffer_size, config.coalesce, stats.clone()),
+        watches: HashMap::new(),
+        shard,
+        replica: None,
+        config,
+        service,
+        stats,
+    };
+    let handle = tokio::spawn(async move {
+        push_loop(pusher, replicas, shutdown_rx).await;
+    });
+    Ok(handle)
+}
+
+/// Returns the indexes of the replicas to connect to, the one owning the node first,
+/// then the next ones, for when it is down.
+fn failover_order(shard: usize, replicas: usize) -> impl Iterator<Item = usize> {
+    (shard..replicas).chain(0..shard.min(replicas))
+}
+
+/// Connects to the first replica reachable, in order, and returns its index.
+async fn connect_first(
+    replicas: &[(usize, Endpoint)],
+    urls: &[String],
+) -> anyhow::Result<(usize, Channel)> {
+    let mut last_err = None;
+    for (index, endpoint) in replicas {
+        let url = &urls[*index];
+        match endpoint.connect().await {
+            Ok(channel) => {
+                info!("Connected to push server {}", url);
+                return Ok((*index, channel));
+            }
+            Err(e) => {
+                debug!("Failed to connect to push server {}: {:?}", url, e);
+                last_err = Some(anyhow::anyhow!("{}: {:?}", url, e));
+            }
+        }
+    }
+    Err(last_err.unwrap_or(anyhow::anyhow!("no push server")))
+}
+
+/// Keeps a Connect stream open to the server, connecting again with a growing backoff
+/// whenever it breaks. Updates keep being made in between, and are sent once
+/// connected again.
+async fn push_loop(
+    mut pusher: Pusher,
+    replicas: Vec<(usize, Endpoint)>,
+    mut shutdown_rx: Receiver<ShutdownSignal>,
+) {
+    info!(
+        "Pushing service maps to {} every {:?}",
+        pusher.config.urls[pusher.shard], pusher.config.interval
+    );
+    let mut ticker = tokio::time::interval(pusher.config.interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    let mut backoff = INITIAL_BACKOFF;
+    loop {
+        let err = match pusher
+            .session(&replicas, &mut ticker, &mut shutdown_rx, &mut backoff)
+            .await
+        {
+            Ok(()) => break,
+            Err(e) => e,
+        };
+        pusher.stats.connected.set(0);
+        warn!(
+            "Lost push server: {:?}, connecting again in {:?}",
+            err, backoff
+        );
+        let delay = tokio::time::sleep(backoff);
+        tokio::pin!(delay);
+        loop {
+            tokio::select! {
+                Ok(signal) = shutdown_rx.recv() => {
+                    if let ShutdownSignal::All = signal {
+                        info!("Received shutdown signal, stopping push.");
+                        return;
+                    }
+                },
+                _ = ticker.tick() => pusher.collect().await,
+                _ = &mut delay => break,
+            }
+        }
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+struct Pusher {
+    config: PushConfig,
+    service: Arc<AgentService>,
+    /// Service map watch of each program, across streams.
+    watches: HashMap<String, ServiceMapWatch>,
+    outbox: Outbox,
+    stats: PushStats,
+    /// Replica owning the node, and the one last connected to.
+    shard: usize,
+    replica: Option<usize>,
+}
+
+impl Pusher {
+    /// Registers with the server, then sends updates and answers commands until the
+    /// stream breaks, or the agent shuts down.
+    async fn session(
+        &mut self,
+        replicas: &[(usize, Endpoint)],
+        ticker: &mut Interval,
+        shutdown_rx: &mut Receiver<ShutdownSignal>,
+        backoff: &mut Duration,
+    ) -> anyhow::Result<()> {
+        let channel = self.connect(replicas).await?;
+        let (tx, rx) = mpsc::channel(STREAM_BUFFER);
+        // The channel is empty, so this doesn't wait.
+        tx.send(self.registration()).await?;
+        let mut request = Request::new(ReceiverStream::new(rx));
+        if let Some(token_file) = &self.config.token_file {
+            let token = fs::read_to_string(token_file)
+                .with_context(|| format!("failed to read {}", token_file.display()))?;
+            let value = format!("Bearer {}", token.trim())
+                .parse()
+                .context("invalid push token")?;
+            request.metadata_mut().insert("authorization", value);
+        }
+        let mut client = HubClient::new(channel);
+        if self.config.compression == PushCompression::Zstd {
+            client = client
+                .send_compressed(CompressionEncoding::Zstd)
+                .accept_compressed(CompressionEncoding::Zstd);
+        }
+        let mut commands = client.connect(request).await?.into_inner();
+        self.stats.connected.set(1);
+        self.outbox.dropped = 0;
+        *backoff = INITIAL_BACKOFF;
+
+        loop {
+            tokio::select! {
+                Ok(signal) = shutdown_rx.recv() => {
+                    if let ShutdownSignal::All = signal {
+                        info!("Received shutdown signal, stopping push.");
+                        return Ok(());
+                    }
+                },
+                _ = ticker.tick() => self.collect().await,
+                command = commands.message() => {
+                    let command = command?.ok_or(anyhow::anyhow!("closed by the server"))?;
+                    let result = self.execute(command).await;
+                    let message = AgentMessage {
+                        message: Some(Message::Result(result)),
+                    };
+                    tx.send(message)
+                        .await
+                        .map_err(|_| anyhow::anyhow!("stream closed"))?;
+                },
+                permit = tx.reserve(), if !self.outbox.is_empty() => {
+                    let pushes = self.outbox.pop_batch(self.config.batch_size);
+                    self.stats.observe("sent", pushes.len() as u64);
+                    let message = AgentMessage {
+                        message: Some(Message::Batch(ServiceMapBatch { pushes })),
+                    };
+                    self.stats.batch_sizes.observe(message.encoded_len() as f64);
+                    permit?.send(message);
+                },
+            }
+        }
+    }
+
+    /// Connects to the first replica reachable, the owner of the node first. Another
+    /// replica has no copy of the service maps the buffered updates change, so they are
+    /// dropped for full ones when the replica changes.
+    async fn connect(&mut self, replicas: &[(usize, Endpoint)]) -> anyhow::Result<Channel> {
+        let (index, channel) = connect_first(replicas, &self.config.urls).await?;
+        if self.replica.is_some_and(|replica| replica != index) {
+            self.outbox.clear();
+            for watch in self.watches.values_mut() {
+                watch.resync();
+            }
+        }
+        self.replica = Some(index);
+        Ok(channel)
+    }
+
+    fn registration(&self) -> AgentMessage {
+        AgentMessage {
+            message: Some(Message::Registration(AgentRegistration {
+                node_name: self.config.node_name.clone(),
+                agent_version: env!("CARGO_PKG_VERSION").to_string(),
+                api_version: API_VERSION.to_string(),
+                features: features::ALL.iter().map(|f| f.to_string()).collect(),
+                dropped_updates: self.outbox.dropped,
+                shard: self.shard as u32,
+                shards: self.config.urls.len() as u32,
+            })),
+        }
+    }
+
+    /// Queues the updates of the service maps that changed since the last call.
+    async fn collect(&mut self) {
+        let progs = self
+            .service
+            .prog_manager
+            .list(ListFilter::new(None, HashMap::new()))
+            .await;
+        let mut names = HashSet::with_capacity(progs.len());
+        for prog in progs {
+            let name = prog.get_name();
+            let Some(snapshot) = prog
+                .service_map_snapshots(SnapshotQuery::At(u64::MAX))
+                .pop()
+            else {
+                continue;
+            };
+            names.insert(name.clone());
+            let watch = self
+                .watches
+                .entry(name.clone())
+                .or_insert_with(|| ServiceMapWatch::new(0));
+            let Some(update) = watch.update(snapshot) else {
+                continue;
+            };
+            let push = ServiceMapPush {
+                name,
+                update: Some(update),
+            };
+            for name in self.outbox.push(push) {
+                debug!("Dropped updates of {}, resyncing it", name);
+                if let Some(watch) = self.watches.get_mut(&name) {
+                    watch.resync();
+                }
+            }
+        }
+        self.watches.retain(|name, _| names.contains(name));
+    }
+
+    async fn execute(&mut self, command: HubCommand) -> HubCommandResult {
+        let result = match command.command {
+            Some(Command::Resync(resync)) => {
+                for (name, watch) in self.watches.iter_mut() {
+                    if resync.name.is_empty() || *name == resync.name {
+                        watch.resync();
+                    }
+                }
+                Ok(None)
+            }
+            Some(Command::List(request)) => self
+                .service
+                .list(Request::new(request))
+                .await
+                .map(|r| Some(hub_command_result::Result::List(r.into_inner()))),
+            Some(Command::Get(request)) => self
+                .service
+                .get(Request::new(request))
+                .await
+                .map(|r| Some(hub_command_result::Result::Get(r.into_inner()))),
+            // Sent by a server newer than the agent.
+            None => Err(Status::unimplemented("Unknown command")),
+        };
+        match result {
+            Ok(result) => HubCommandResult {
+                id: command.id,
+                error: String::new(),
+                result,
+            },
+            Err(status) => HubCommandResult {
+                id: command.id,
+                error: status.message().to_string(),
+                result: None,
+            },
+        }
+    }
+}
+
+/// Updates waiting to be sent, oldest first. With `coalesce`, an update is folded
+/// into the one of its program still waiting, if any. When full, the oldest are
+/// dropped along with the later updates of their program up to its next full one,
+/// since those only carry changes the server can't apply without them.
+#[derive(Debug)]
+struct Outbox {
+    pushes: VecDeque<ServiceMapPush>,
+    capacity: usize,
+    coalesce: bool,
+    /// Updates dropped since the agent last registered.
+    dropped: u64,
+    stats: PushStats,
+}
+
+impl Outbox {
+    fn new(capacity: usize, coalesce: bool, stats: PushStats) -> Self {
+        Self {
+            pushes: VecDeque::new(),
+            capacity: capacity.max(1),
+            coalesce,
+            dropped: 0,
+            stats,
+        }
+    }
+
+    /// Queues an update, and returns the programs whose next update must be a full
+    /// one for those dropped to make room.
+    fn push(&mut self, mut push: ServiceMapPush) -> Vec<String> {
+        if self.coalesce {
+            let queued = self
+                .pushes
+                .iter_mut()
+                .rev()
+                .find(|queued| queued.name == push.name)
+                .and_then(|queued| queued.update.as_mut());
+            if let Some(update) = queued {
+                if let Some(next) = push.update.take() {
+                    coalesce_updates(update, next);
+                    self.stats.observe("coalesced", 1);
+                    return Vec::new();
+                }
+            }
+        }
+        self.pushes.push_back(push);
+        let mut resync = Vec::new();
+        while self.pushes.len() > self.capacity {
+            let Some(oldest) = self.pushes.pop_front() else {
+                break;
+            };
+            // Drop the changes made since, up to the next full update of the program.
+            let mut found_full = false;
+            let before = self.pushes.len();
+            self.pushes.retain(|push| {
+                if found_full || push.name != oldest.name {
+                    return true;
+                }
+                found_full = push.update.as_ref().is_some_and(|u| u.full);
+                found_full
+            });
+            let dropped = 1 + (before - self.pushes.len()) as u64;
+            self.dropped += dropped;
+            self.stats.observe("dropped", dropped);
+            if !found_full {
+                resync.push(oldest.name);
+            }
+        }
+        self.stats.buffered.set(self.pushes.len() as i64);
+        resync
+    }
+
+    /// Drops every update waiting.
+    fn clear(&mut self) {
+        let dropped = self.pushes.len() as u64;
+        self.pushes.clear();
+        self.dropped += dropped;
+        self.stats.observe("dropped", dropped);
+        self.stats.buffered.set(0);
+    }
+
+    /// Takes up to `max` of the oldest updates.
+    fn pop_batch(&mut self, max: usize) -> Vec<ServiceMapPush> {
+        let len = self.pushes.len().min(max.max(1));
+        let batch = self.pushes.drain(..len).collect();
+        self.stats.buffered.set(self.pushes.len() as i64);
+        batch
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pushes.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+
+    #[test]
+    fn test_failover_order() {
+        assert_eq!(failover_order(0, 1).collect::<Vec<_>>(), [0]);
+        assert_eq!(failover_order(2, 4).collect::<Vec<_>>(), [2, 3, 0, 1]);
+        assert_eq!(failover_order(0, 3).collect::<Vec<_>>(), [0, 1, 2]);
+        // A node keeps its replicas in the same order across restarts.
+        let shard = shard_of("node-1", 8) as usize;
+        let order: Vec<_> = failover_order(shard, 8).collect();
+        assert_eq!(order[0], shard);
+        assert_eq!(order, failover_order(shard, 8).collect::<Vec<_>>());
+        let mut sorted = order.clone();
+        sorted.sort();
+        assert_eq!(sorted, (0..8).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn test_connect_fails_over_to_next_replica() {
+        // The owner of the node is down: nothing listens on its port any more.
+        let down = TcpListener::bind("127.0.0.1:0").unwrap();
+        let down_url = format!("http://{}", down.local_addr().unwrap());
+        drop(down);
+        // Connections queue on a bound socket until accepted.
+        let up = TcpListener::bind("127.0.0.1:0").unwrap();
+        let up_url = format!("http://{}", up.local_addr().unwrap());
+        let urls = vec![up_url, down_url];
+        let replicas: Vec<_> = failover_order(1, 2)
+            .map(|index| (index, Endpoint::from_shared(urls[index].clone()).unwrap()))
+            .collect();
+
+        let (index, _) = connect_first(&replicas, &urls).await.unwrap();
+        assert_eq!(index, 0);
+
+        drop(up);
+        let err = connect_first(&replicas, &urls).await.unwrap_err();
+        assert!(err.to_string().starts_with(&urls[0]), "{}", err);
+    }
+}
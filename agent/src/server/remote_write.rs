@@ -0,0 +1,573 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use http_body_util::{BodyExt, Full};
+use hyper::body::Bytes;
+use hyper::client::conn::http1::SendRequest;
+use hyper::{Request, StatusCode, Uri};
+use hyper_util::rt::TokioIo;
+use log::{debug, info, warn};
+use prometheus_client::encoding::text::encode;
+use prost::Message;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::sync::broadcast::Receiver;
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+use tokio::time::MissedTickBehavior;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::rustls::{self, ClientConfig, RootCertStore};
+use tokio_rustls::TlsConnector;
+
+use crate::collector::Collector;
+use crate::common::native_histogram::{HistogramMode, NativeHistogramSeries};
+use crate::managers::cache::CacheManager;
+use crate::managers::registry::RegistryManager;
+use crate::managers::scheduler::PollScheduler;
+use crate::progs::types::ShutdownSignal;
+use crate::server::exposition::{parse_text, without_classic, ParsedSample, Span};
+use crate::server::push::PushStats;
+
+const MAX_ATTEMPTS: u32 = 5;
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(10);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Where and how to push metrics with the Prometheus remote write protocol.
+#[derive(Debug, Clone)]
+pub(crate) struct RemoteWriteConfig {
+    pub(crate) url: String,
+    pub(crate) interval: Duration,
+    /// Maximum number of series sent in one request.
+    pub(crate) batch_size: usize,
+    /// Labels added to every series that doesn't already have them.
+    pub(crate) labels: Vec<(String, String)>,
+    /// Read before every push, so that rotated tokens are picked up.
+    pub(crate) bearer_token_file: Option<PathBuf>,
+    /// CA bundle to verify the endpoint with, instead of the system roots.
+    pub(crate) ca_file: Option<PathBuf>,
+    pub(crate) cert_file: Option<PathBuf>,
+    pub(crate) key_file: Option<PathBuf>,
+    /// Whether latency histograms are pushed as classic series, native ones, or both.
+    pub(crate) histograms: HistogramMode,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct WriteRequest {
+    #[prost(message, repeated, tag = "1")]
+    timeseries: Vec<TimeSeries>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct TimeSeries {
+    #[prost(message, repeated, tag = "1")]
+    labels: Vec<Label>,
+    #[prost(message, repeated, tag = "2")]
+    samples: Vec<Sample>,
+    #[prost(message, repeated, tag = "4")]
+    histograms: Vec<Histogram>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Label {
+    #[prost(string, tag = "1")]
+    name: String,
+    #[prost(string, tag = "2")]
+    value: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Sample {
+    #[prost(double, tag = "1")]
+    value: f64,
+    #[prost(int64, tag = "2")]
+    timestamp: i64,
+}
+
+/// A native histogram with integer counts. `count_int` and `zero_count_int` are
+/// members of oneofs in the protocol, which encode the same as these plain fields.
+#[derive(Clone, PartialEq, Message)]
+struct Histogram {
+    #[prost(uint64, tag = "1")]
+    count_int: u64,
+    #[prost(double, tag = "3")]
+    sum: f64,
+    #[prost(sint32, tag = "4")]
+    schema: i32,
+    #[prost(double, tag = "5")]
+    zero_threshold: f64,
+    #[prost(uint64, tag = "6")]
+    zero_count_int: u64,
+    #[prost(message, repeated, tag = "11")]
+    positive_spans: Vec<Span>,
+    #[prost(sint64, repeated, tag = "12")]
+    positive_deltas: Vec<i64>,
+    #[prost(int64, tag = "15")]
+    timestamp: i64,
+}
+
+pub async fn serve(
+    config: RemoteWriteConfig,
+    registry_manager: RegistryManager,
+    scheduler: PollScheduler,
+    cache_manager: CacheManager,
+    push_stats: Option<PushStats>,
+    shutdown_rx: Receiver<ShutdownSignal>,
+) -> anyhow::Result<JoinHandle<()>> {
+    let client = RemoteWriteClient::new(&config)?;
+    let collector =
+        Collector::new(registry_manager, scheduler, cache_manager).with_push_stats(push_stats);
+    let handle = tokio::spawn(async move {
+        push_loop(config, client, collector, shutdown_rx).await;
+    });
+    Ok(handle)
+}
+
+/// Pushes the current metrics every interval. Nothing is buffered between pushes: a
+/// push that still fails after its retries is dropped, and the next one starts over
+/// from fresh values.
+async fn push_loop(
+    config: RemoteWriteConfig,
+    client: RemoteWriteClient,
+    collector: Collector,
+    mut shutdown_rx: Receiver<ShutdownSignal>,
+) {
+    info!(
+        "Pushing metrics to {} every {:?}",
+        config.url, config.interval
+    );
+    let mut ticker = tokio::time::interval(config.interval);
+    // A push retrying past the interval shouldn't be followed by a burst of them.
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    loop {
+        tokio::select! {
+            Ok(signal) = shutdown_rx.recv() => {
+                if let ShutdownSignal::All = signal {
+                    info!("Received shutdown signal, stopping remote write.");
+                    break;
+                }
+            },
+            _ = ticker.tick() => {
+                let mut buf = String::new();
+                if let Err(e) = encode(&mut buf, &collector.registry()) {
+                    warn!("Failed to encode metrics for remote write: {:?}", e);
+                    continue;
+                }
+                let mut samples = parse_text(&buf);
+                let natives = if config.histograms.native() {
+                    collector.native_histograms()
+                } else {
+                    vec![]
+                };
+                if !config.histograms.classic() {
+                    samples = without_classic(samples, &natives);
+                }
+                let series = to_series(samples, natives, now_ms(), &config.labels);
+                let mut pushed = 0;
+                for batch in series.chunks(config.batch_size.max(1)) {
+                    if let Err(e) = client.push(batch).await {
+                        warn!(
+                            "Dropping remote write push of {} series, a batch of {} failed: {:?}",
+                            series.len() - pushed,
+                            batch.len(),
+                            e
+                        );
+                        break;
+                    }
+                    pushed += batch.len();
+                }
+            }
+        }
+    }
+}
+
+struct RemoteWriteClient {
+    uri: Uri,
+    tls: Option<TlsConnector>,
+    bearer_token_file: Option<PathBuf>,
+    /// Connection kept open between pushes, opened again once the endpoint closed it.
+    connection: Mutex<Option<SendRequest<Full<Bytes>>>>,
+}
+
+impl RemoteWriteClient {
+    fn new(config: &RemoteWriteConfig) -> anyhow::Result<Self> {
+        let uri = config.url.parse::<Uri>()?;
+        let tls = match uri.scheme_str() {
+            Some("https") => Some(tls_connector(config)?),
+            Some("http") => None,
+            _ => return Err(anyhow::anyhow!("Unsupported remote write URL {}", uri)),
+        };
+        Ok(Self {
+            uri,
+            tls,
+            bearer_token_file: config.bearer_token_file.clone(),
+            connection: Mutex::new(None),
+        })
+    }
+
+    async fn push(&self, series: &[TimeSeries]) -> anyhow::Result<()> {
+        let request = WriteRequest {
+            timeseries: series.to_vec(),
+        };
+        let body = Bytes::from(snap::raw::Encoder::new().compress_vec(&request.encode_to_vec())?);
+        let token = match &self.bearer_token_file {
+            Some(path) => Some(
+                std::fs::read_to_string(path)
+                    .with_context(|| format!("unable to read {}", path.display()))?
+                    .trim()
+                    .to_string(),
+            ),
+            None => None,
+        };
+
+        let mut backoff = INITIAL_BACKOFF;
+        let mut attempt = 1;
+        loop {
+            let res = tokio::time::timeout(REQUEST_TIMEOUT, self.send(body.clone(), &token)).await;
+            let err = match res {
+                Ok(Ok(status)) if status.is_success() => {
+                    debug!("Pushed {} series", series.len());
+                    return Ok(());
+                }
+                // Anything else the endpoint rejects would be rejected again.
+                Ok(Ok(status))
+                    if status.is_client_error() && status != StatusCode::TOO_MANY_REQUESTS =>
+                {
+                    return Err(anyhow::anyhow!("rejected with {}", status));
+                }
+                Ok(Ok(status)) => anyhow::anyhow!("failed with {}", status),
+                Ok(Err(e)) => e,
+                Err(_) => anyhow::anyhow!("timed out after {:?}", REQUEST_TIMEOUT),
+            };
+            if attempt == MAX_ATTEMPTS {
+                return Err(err);
+            }
+            debug!(
+                "Remote write attempt {} {}, retrying in {:?}",
+                attempt, err, backoff
+            );
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+            attempt += 1;
+        }
+    }
+
+    async fn send(&self, body: Bytes, token: &Option<String>) -> anyhow::Result<StatusCode> {
+        let host = self
+            .uri
+            .host()
+            .ok_or(anyhow::anyhow!("remote write URL has no host"))?;
+        let authority = self
+            .uri
+            .authority()
+            .map_or(host.to_string(), |a| a.to_string());
+
+        let path = self.uri.path_and_query().map_or("/", |p| p.as_str());
+        let mut builder = Request::post(path)
+            .header(hyper::header::HOST, authority)
+            .header(hyper::header::CONTENT_ENCODING, "snappy")
+            .header(hyper::header::CONTENT_TYPE, "application/x-protobuf")
+            .header(hyper::header::USER_AGENT, "eBPFConductor-agent")
+            .header("X-Prometheus-Remote-Write-Version", "0.1.0");
+        if let Some(token) = token {
+            builder = builder.header(hyper::header::AUTHORIZATION, format!("Bearer {}", token));
+        }
+        let request = builder.body(Full::new(body))?;
+
+        // Pushes are sent one at a time, so the lock is never contended.
+        let mut connection = self.connection.lock().await;
+        let reusable = match connection.take() {
+            Some(mut sender) => sender.ready().await.is_ok().then_some(sender),
+            None => None,
+        };
+        let mut sender = match reusable {
+            Some(sender) => sender,
+            None => self.connect().await?,
+        };
+        let response = sender.send_request(request).await?;
+        let status = response.status();
+        // Reading the whole body lets the connection take the next request.
+        response.into_body().collect().await?;
+        *connection = Some(sender);
+        Ok(status)
+    }
+
+    async fn connect(&self) -> anyhow::Result<SendRequest<Full<Bytes>>> {
+        let host = connect_host(&self.uri)?;
+        let port = self
+            .uri
+            .port_u16()
+            .unwrap_or(if self.tls.is_some() { 443 } else { 80 });
+        debug!("Connecting to remote write endpoint {}:{}", host, port);
+        let stream = TcpStream::connect((host, port)).await?;
+        match &self.tls {
+            Some(connector) => {
+                let server_name = ServerName::try_from(host.to_string())?;
+                let stream = connector.connect(server_name, stream).await?;
+                handshake(stream).await
+            }
+            None => handshake(stream).await,
+        }
+    }
+}
+
+/// Returns the host of `uri` to connect to, without the brackets of IPv6 literals.
+fn connect_host(uri: &Uri) -> anyhow::Result<&str> {
+    let host = uri
+        .host()
+        .ok_or(anyhow::anyhow!("remote write URL has no host"))?;
+    Ok(host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host))
+}
+
+async fn handshake<S>(stream: S) -> anyhow::Result<SendRequest<Full<Bytes>>>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+    tokio::task::spawn(async move {
+        if let Err(e) = conn.await {
+            debug!("Remote write connection failed: {:?}", e);
+        }
+    });
+    Ok(sender)
+}
+
+fn tls_connector(config: &RemoteWriteConfig) -> anyhow::Result<TlsConnector> {
+    let mut roots = RootCertStore::empty();
+    match &config.ca_file {
+        Some(path) => {
+            let mut reader = BufReader::new(File::open(path)?);
+            for cert in rustls_pemfile::certs(&mut reader) {
+                roots.add(cert?)?;
+            }
+        }
+        None => {
+            for cert in rustls_native_certs::load_native_certs()? {
+                // Skip system roots rustls can't parse rather than failing on them.
+                let _ = roots.add(cert);
+            }
+        }
+    }
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots);
+    let tls_config = match (&config.cert_file, &config.key_file) {
+        (Some(cert_file), Some(key_file)) => {
+            let mut reader = BufReader::new(File::open(cert_file)?);
+            let certs = rustls_pemfile::certs(&mut reader).collect::<Result<Vec<_>, _>>()?;
+            let mut reader = BufReader::new(File::open(key_file)?);
+            let key = rustls_pemfile::private_key(&mut reader)?
+                .ok_or(anyhow::anyhow!("No private key in {}", key_file.display()))?;
+            builder.with_client_auth_cert(certs, key)?
+        }
+        (None, None) => builder.with_no_client_auth(),
+        _ => {
+            return Err(anyhow::anyhow!(
+                "Client certificate and key must be given together"
+            ))
+        }
+    };
+    Ok(TlsConnector::from(Arc::new(tls_config)))
+}
+
+/// Converts samples and native histograms into one series each, all stamped with
+/// `timestamp_ms`.
+fn to_series(
+    samples: Vec<ParsedSample>,
+    natives: Vec<NativeHistogramSeries>,
+    timestamp_ms: i64,
+    extra: &[(String, String)],
+) -> Vec<TimeSeries> {
+    let mut series: Vec<TimeSeries> = samples
+        .into_iter()
+        .map(|sample| TimeSeries {
+            labels: series_labels(&sample.name, sample.labels, extra),
+            samples: vec![Sample {
+                value: sample.value,
+                timestamp: timestamp_ms,
+            }],
+            histograms: vec![],
+        })
+        .collect();
+    series.extend(natives.into_iter().map(|native| {
+        let histogram = &native.histogram;
+        let (spans, deltas) = histogram.positive_buckets();
+        TimeSeries {
+            labels: series_labels(&native.name, native.labels.clone(), extra),
+            samples: vec![],
+            histograms: vec![Histogram {
+                count_int: histogram.count(),
+                sum: histogram.sum(),
+                schema: histogram.schema(),
+                zero_threshold: histogram.zero_threshold(),
+                zero_count_int: histogram.zero_count(),
+                positive_spans: spans.iter().map(Span::from).collect(),
+                positive_deltas: deltas,
+                timestamp: timestamp_ms,
+            }],
+        }
+    }));
+    series
+}
+
+/// Returns the labels of a series, its name as `__name__` included, sorted as the
+/// protocol requires.
+fn series_labels(
+    metric: &str,
+    labels: Vec<(String, String)>,
+    extra: &[(String, String)],
+) -> Vec<Label> {
+    let mut labels: Vec<Label> = labels
+        .into_iter()
+        .map(|(name, value)| Label { name, value })
+        .collect();
+    labels.push(Label {
+        name: "__name__".to_string(),
+        value: metric.to_string(),
+    });
+    for (name, value) in extra {
+        if !labels.iter().any(|l| &l.name == name) {
+            labels.push(Label {
+                name: name.clone(),
+                value: value.clone(),
+            });
+        }
+    }
+    labels.sort_by(|a, b| a.name.cmp(&b.name));
+    labels
+}
+
+/// Parses a `key=value` label given on the command line.
+pub(crate) fn parse_label(s: &str) -> Result<(String, String), String> {
+    s.split_once('=')
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .ok_or(format!("expected <name>=<value>, got {:?}", s))
+}
+
+fn now_ms() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::native_histogram::NativeHistogram;
+    use crate::server::exposition::MetricType;
+
+    fn label(name: &str, value: &str) -> Label {
+        Label {
+            name: name.to_string(),
+            value: value.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_series_labels() {
+        let labels = series_labels(
+            "tcp_bytes_sent",
+            vec![
+                ("server".to_string(), "checkout".to_string()),
+                ("client".to_string(), "frontend".to_string()),
+            ],
+            &[
+                ("cluster".to_string(), "prod".to_string()),
+                ("client".to_string(), "overridden".to_string()),
+            ],
+        );
+        // Sorted by name, with the labels of the sample taking precedence.
+        assert_eq!(
+            labels,
+            vec![
+                label("__name__", "tcp_bytes_sent"),
+                label("client", "frontend"),
+                label("cluster", "prod"),
+                label("server", "checkout"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_to_series() {
+        let samples = vec![ParsedSample {
+            name: "tcp_connections".to_string(),
+            labels: vec![("server".to_string(), "checkout".to_string())],
+            value: 3.0,
+            family: "tcp_connections".to_string(),
+            metric_type: MetricType::Gauge,
+        }];
+        let mut histogram = NativeHistogram::default();
+        histogram.observe(0.0);
+        histogram.observe(1.0);
+        histogram.observe(1.0);
+        let natives = vec![NativeHistogramSeries {
+            name: "http_request_duration_seconds".to_string(),
+            help: String::new(),
+            labels: vec![],
+            histogram: histogram.clone(),
+        }];
+        let extra = [("node".to_string(), "node-1".to_string())];
+        let series = to_series(samples, natives, 1_700_000_000_000, &extra);
+        assert_eq!(series.len(), 2);
+
+        assert_eq!(
+            series[0].labels,
+            vec![
+                label("__name__", "tcp_connections"),
+                label("node", "node-1"),
+                label("server", "checkout"),
+            ]
+        );
+        assert_eq!(
+            series[0].samples,
+            vec![Sample {
+                value: 3.0,
+                timestamp: 1_700_000_000_000,
+            }]
+        );
+        assert!(series[0].histograms.is_empty());
+
+        assert_eq!(
+            series[1].labels,
+            vec![
+                label("__name__", "http_request_duration_seconds"),
+                label("node", "node-1"),
+            ]
+        );
+        assert!(series[1].samples.is_empty());
+        let (spans, deltas) = histogram.positive_buckets();
+        assert_eq!(
+            series[1].histograms,
+            vec![Histogram {
+                count_int: 3,
+                sum: 2.0,
+                schema: histogram.schema(),
+                zero_threshold: histogram.zero_threshold(),
+                zero_count_int: 1,
+                positive_spans: spans.iter().map(Span::from).collect(),
+                positive_deltas: deltas,
+                timestamp: 1_700_000_000_000,
+            }]
+        );
+        assert_eq!(series[1].histograms[0].positive_deltas, vec![2]);
+    }
+
+    #[test]
+    fn test_connect_host() {
+        let host = |url: &str| connect_host(&url.parse().unwrap()).unwrap().to_string();
+        assert_eq!(host("https://prometheus:9090/api/v1/write"), "prometheus");
+        assert_eq!(host("http://10.0.0.1/api/v1/write"), "10.0.0.1");
+        assert_eq!(host("http://[fd00::1]:9090/api/v1/write"), "fd00::1");
+        assert!(ServerName::try_from(host("https://[::1]/write")).is_ok());
+    }
+}
@@ -1,55 +1,110 @@
 use std::collections::HashMap;
-use std::fs::remove_file;
-use std::path::Path;
+use std::fmt;
+use std::fs::{self, remove_file};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::os::linux::net::SocketAddrExt;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use bpfman_api::v1::bpfman_client::BpfmanClient;
+use bpfman_api::v1::list_response::ListResult as BpfmanProgram;
 use bpfman_lib::utils::set_file_permissions;
-use log::{debug, error, info};
-use tokio::net::UnixListener;
+use futures::future;
+use futures::stream::{self, Stream, StreamExt};
+use log::{debug, error, info, warn};
+use tokio::net::{TcpListener, UnixListener, UnixStream};
 use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
 use tokio::task::JoinHandle;
-use tokio_stream::wrappers::UnixListenerStream;
+use tokio::time::MissedTickBehavior;
+use tokio_stream::wrappers::{TcpListenerStream, UnixListenerStream};
+use tonic::transport::server::UdsConnectInfo;
 use tonic::transport::{Channel, Server};
 use tonic::{Request, Response, Status};
 
 use agent_api::v1::agent_server::{Agent, AgentServer};
 use agent_api::v1::list_response::ListResult;
 use agent_api::v1::{
-    GetRequest, GetResponse, ListRequest, ListResponse, LoadRequest, LoadResponse,
-    PullBytecodeRequest, PullBytecodeResponse, UnloadRequest, UnloadResponse,
+    ApiVersionRequest, ApiVersionResponse, BytecodeLocation, DependencyEvent,
+    DiffServiceMapRequest, DiffServiceMapResponse, DumpMapsRequest, DumpMapsResponse,
+    ExportGraphRequest, ExportGraphResponse, GetAuditLogRequest, GetAuditLogResponse,
+    GetLoadDiagnosticsRequest, GetLoadDiagnosticsResponse, GetRecentRequestsRequest,
+    GetRecentRequestsResponse, GetRequest, GetResponse, GetServiceMapAtRequest,
+    GetServiceMapAtResponse, GetServiceMapRangeRequest, GetServiceMapRangeResponse, GraphFormat,
+    InspectProgramsRequest, InspectProgramsResponse, ListRequest, ListResponse, LoadRequest,
+    LoadResponse, PauseProgramRequest, PauseProgramResponse, ProgramInfo, ProgramInspection,
+    PullBytecodeRequest, PullBytecodeResponse, ReportRequestsRequest, ReportRequestsResponse,
+    ResumeProgramRequest, ResumeProgramResponse, ServiceMapUpdate, TracePodRequest,
+    TracePodResponse, UnloadRequest, UnloadResponse, ValidateProgramRequest,
+    ValidateProgramResponse, ValidationCheck, ValidationStatus, WatchDependenciesRequest,
+    WatchServiceMapRequest,
 };
+use agent_api::{features, ProgramType, API_VERSION, FILE_DESCRIPTOR_SET};
 
-use crate::common::constants::directories::SOCK_MODE;
+use crate::common::bpf::enable_stats;
+use crate::common::errors::AgentError;
+use crate::common::graph::{diff_snapshots, Graph, ServiceMapWatch};
 use crate::common::types::ListFilter;
+use crate::managers::capture::CapturePolicy;
+use crate::managers::image::Verification;
+use crate::managers::inspect::{inspect, runs_since};
+use crate::managers::pod_trace::PodTracer;
 use crate::managers::prog::ProgManager;
-use crate::progs::types::ShutdownSignal;
+use crate::managers::trace_context;
+use crate::progs::types::{Program, ShutdownSignal, SnapshotQuery};
+use crate::server::access::{AccessLayer, ApiAccess, PeerAllowlist};
+
+/// The longest InspectPrograms counts runs for.
+const MAX_STATS_WINDOW: Duration = Duration::from_secs(60);
+/// How often WatchServiceMap looks for a new snapshot.
+const SERVICE_MAP_WATCH_PERIOD: Duration = Duration::from_secs(1);
 
 pub struct AgentService {
     pub prog_manager: ProgManager,
     pub bpf_client: BpfmanClient<Channel>,
+    pub(crate) pod_tracer: PodTracer,
 }
 
 impl AgentService {
-    pub(crate) fn new(prog_manager: ProgManager, bpf_client: BpfmanClient<Channel>) -> Self {
+    pub(crate) fn new(
+        prog_manager: ProgManager,
+        bpf_client: BpfmanClient<Channel>,
+        pod_tracer: PodTracer,
+    ) -> Self {
         Self {
             prog_manager,
             bpf_client,
+            pod_tracer,
         }
     }
 
-    async fn get_prog_ids_for_maps(
-        &self,
-        map_to_prog_name: HashMap<String, String>,
-    ) -> Result<HashMap<String, u32>, anyhow::Error> {
+    /// Lists the eBPF programs loaded by bpfman.
+    async fn bpfman_programs(&self) -> Result<Vec<BpfmanProgram>, AgentError> {
         let req = Request::new(bpfman_api::v1::ListRequest {
             program_type: None,
             bpfman_programs_only: None,
             match_metadata: Default::default(),
         });
         let mut bpf_client = self.bpf_client.clone();
-        let response = bpf_client.list(req).await?.into_inner();
-        let loaded_ebpf_progs = response
-            .results
+        let response = bpf_client
+            .list(req)
+            .await
+            .map_err(|e| AgentError::BpfmanUnavailable(e.message().to_string()))?
+            .into_inner();
+        Ok(response.results)
+    }
+
+    async fn get_prog_ids_for_maps(
+        &self,
+        map_to_prog_name: HashMap<String, String>,
+    ) -> Result<HashMap<String, u32>, AgentError> {
+        let loaded_ebpf_progs = self
+            .bpfman_programs()
+            .await?
             .iter()
             .filter_map(|prog| prog.kernel_info.as_ref())
             .map(|info| (info.name.clone(), info.id))
@@ -57,37 +112,26 @@ impl AgentService {
 
         let mut map_to_prog_id = HashMap::new();
         for (map_name, prog_name) in map_to_prog_name {
-            let prog_id = loaded_ebpf_progs.get(&prog_name).ok_or(anyhow::anyhow!(
-                "Required eBPF program {} not loaded",
-                prog_name
-            ))?;
+            let prog_id = loaded_ebpf_progs
+                .get(&prog_name)
+                .ok_or(AgentError::EbpfProgramNotLoaded(prog_name.clone()))?;
             map_to_prog_id.insert(map_name, *prog_id);
         }
         Ok(map_to_prog_id)
     }
-}
 
-#[tonic::async_trait]
-impl Agent for AgentService {
-    async fn load(&self, request: Request<LoadRequest>) -> Result<Response<LoadResponse>, Status> {
-        let request = request.into_inner();
+    async fn load_program(&self, request: LoadRequest) -> Result<ProgramInfo, AgentError> {
+        let program_type: ProgramType = request
+            .program_type
+            .try_into()
+            .map_err(|_| AgentError::InvalidProgramType(request.program_type))?;
 
-        let map_to_prog_id = self
-            .get_prog_ids_for_maps(request.ebpf_maps)
-            .await
-            .map_err(|e| {
-                Status::aborted(format!(
-                    "Failed to get eBPF program IDs: {:?}",
-                    e.to_string()
-                ))
-            })?;
-
-        let program_type = request.program_type.try_into().map_err(|_| {
-            Status::aborted(format!(
-                "Failed to convert program type: {:?}",
-                request.program_type
-            ))
-        })?;
+        let map_to_prog_id = self.get_prog_ids_for_maps(request.ebpf_maps).await?;
+
+        if let Some(bytecode) = request.bytecode {
+            self.verify_bytecode(&request.name, bytecode, program_type.clone())
+                .await?;
+        }
 
         let prog = self
             .prog_manager
@@ -96,24 +140,114 @@ impl Agent for AgentService {
                 program_type,
                 request.metadata,
                 self.prog_manager.cache_manager.clone(),
+                self.prog_manager.events_manager.clone(),
                 map_to_prog_id,
             )
-            .await
-            .map_err(|e| {
-                Status::aborted(format!("Failed to pre-load program: {:?}", e.to_string()))
-            })?;
+            .await?;
 
+        self.prog_manager.load(prog.clone()).await?;
+
+        self.program_info(&prog)
+    }
+
+    /// Runs the bytecode of a load request through the verifier, keeping the log of a
+    /// rejection for GetLoadDiagnostics. Only a rejection fails the load: bytecode that
+    /// can't be checked here, e.g. without a BPF filesystem, is left to bpfman.
+    async fn verify_bytecode(
+        &self,
+        name: &str,
+        bytecode: BytecodeLocation,
+        program_type: ProgramType,
+    ) -> Result<(), AgentError> {
+        let image_manager = self.prog_manager.image_manager.clone();
+        let verified =
+            tokio::task::spawn_blocking(move || image_manager.verify(&bytecode, program_type))
+                .await
+                .map_err(anyhow::Error::from)
+                .and_then(|verified| verified);
+        let image_manager = &self.prog_manager.image_manager;
+        match verified.map_err(AgentError::from) {
+            Ok(_) => {
+                image_manager.clear_diagnostics(name);
+                Ok(())
+            }
+            Err(e @ AgentError::VerifierRejected { .. }) => {
+                error!("Program {} failed verification: {}", name, e);
+                image_manager.record_rejection(name, &e);
+                Err(e)
+            }
+            Err(e) => {
+                warn!("Could not verify the bytecode of program {}: {:#}", name, e);
+                Ok(())
+            }
+        }
+    }
+
+    /// Returns the info of a program, with why the verifier rejected its last load.
+    fn program_info(&self, prog: &Arc<dyn Program>) -> Result<ProgramInfo, AgentError> {
+        let mut info = prog.get_program_info()?;
+        info.load_diagnostics = self.prog_manager.image_manager.diagnostics(&info.name);
+        Ok(info)
+    }
+
+    async fn inspect(&self, infos: &[ProgramInfo]) -> Result<Vec<ProgramInspection>, AgentError> {
+        let loaded = self.bpfman_programs().await?;
+        Ok(infos.iter().map(|info| inspect(info, &loaded)).collect())
+    }
+
+    fn audit<T>(
+        &self,
+        operation: &str,
+        caller: &str,
+        program: &str,
+        metadata: HashMap<String, String>,
+        result: &Result<T, AgentError>,
+    ) {
+        let error = result.as_ref().err().map(|e| e.to_string());
         self.prog_manager
-            .load(prog.clone())
-            .await
-            .map_err(|e| Status::aborted(format!("Failed to load program: {:?}", e.to_string())))?;
+            .audit_log
+            .record(caller, operation, program, metadata, error);
+    }
+}
 
-        let prog_info = prog.get_program_info().map_err(|e| {
-            Status::aborted(format!("Failed to get program info: {:?}", e.to_string()))
-        })?;
+/// Identifies the caller of a request: the user and process of a unix socket peer, or
+/// the address of a TCP one.
+fn caller<T>(request: &Request<T>) -> String {
+    if let Some(cred) = request
+        .extensions()
+        .get::<UdsConnectInfo>()
+        .and_then(|info| info.peer_cred)
+    {
+        return match cred.pid() {
+            Some(pid) => {
+                let comm = fs::read_to_string(format!("/proc/{}/comm", pid)).unwrap_or_default();
+                format!("uid={} pid={} comm={}", cred.uid(), pid, comm.trim_end())
+            }
+            None => format!("uid={}", cred.uid()),
+        };
+    }
+    match request.remote_addr() {
+        Some(addr) => format!("tcp={}", addr),
+        None => "unknown".to_string(),
+    }
+}
+
+#[tonic::async_trait]
+impl Agent for AgentService {
+    type WatchDependenciesStream =
+        Pin<Box<dyn Stream<Item = Result<DependencyEvent, Status>> + Send + 'static>>;
+    type WatchServiceMapStream =
+        Pin<Box<dyn Stream<Item = Result<ServiceMapUpdate, Status>> + Send + 'static>>;
+
+    async fn load(&self, request: Request<LoadRequest>) -> Result<Response<LoadResponse>, Status> {
+        let caller = caller(&request);
+        let request = request.into_inner();
+        let (name, metadata) = (request.name.clone(), request.metadata.clone());
+        let prog_info = self.load_program(request).await;
+        self.audit("load", &caller, &name, metadata, &prog_info);
 
         Ok(Response::new(LoadResponse {
-            info: Some(prog_info),
+            info: Some(prog_info?),
         }))
     }
 
@@ -121,13 +255,11 @@ impl Agent for AgentService {
         &self,
         request: Request<UnloadRequest>,
     ) -> Result<Response<UnloadResponse>, Status> {
+        let caller = caller(&request);
         let request = request.into_inner();
-        self.prog_manager
-            .unload(request.name.clone())
-            .await
-            .map_err(|e| {
-                Status::aborted(format!("Failed to unload program: {:?}", e.to_string()))
-            })?;
+        let result = self.prog_manager.unload(request.name.clone()).await;
+        self.audit("unload", &caller, &request.name, HashMap::new(), &result);
+        result?;
         Ok(Response::new(UnloadResponse {}))
     }
 
@@ -141,9 +273,7 @@ impl Agent for AgentService {
 
         for prog in progs.iter() {
             let reply_entry = ListResult {
-                info: Some(prog.get_program_info().map_err(|e| {
-                    Status::aborted(format!("Failed to get program info: {:?}", e.to_string()))
-                })?),
+                info: Some(self.program_info(prog)?),
             };
             reply.results.push(reply_entry);
         }
@@ -164,58 +294,666 @@ impl Agent for AgentService {
             .prog_manager
             .get(request.name.clone(), None)
             .await
-            .ok_or_else(|| Status::aborted(format!("Program {} not found", request.name)))?;
+            .ok_or_else(|| AgentError::ProgramNotFound(request.name.clone()))?;
 
-        let prog_info = prog.get_program_info().map_err(|e| {
-            Status::aborted(format!("Failed to get program info: {:?}", e.to_string()))
-        })?;
+        let prog_info = self.program_info(&prog)?;
 
         Ok(Response::new(GetResponse {
             info: Some(prog_info),
         }))
     }
-}
 
-pub async fn serve(
-    path: &Path,
-    service: AgentServer<AgentService>,
-    mut shutdown_rx: broadcast::Receiver<ShutdownSignal>,
-) -> anyhow::Result<JoinHandle<()>> {
-    // Listen on Unix socket
-    if path.exists() {
-        // Attempt to remove the socket, since bind fails if it exists
-        remove_file(path)?;
+    async fn dump_maps(
+        &self,
+        request: Request<DumpMapsRequest>,
+    ) -> Result<Response<DumpMapsResponse>, Status> {
+        let request = request.into_inner();
+        let prog = self
+            .prog_manager
+            .get(request.name.clone(), None)
+            .await
+            .ok_or_else(|| AgentError::ProgramNotFound(request.name.clone()))?;
+
+        let maps = prog.dump_maps(&request.maps).map_err(AgentError::from)?;
+
+        Ok(Response::new(DumpMapsResponse { maps }))
     }
 
-    let uds = UnixListener::bind(path)?;
-    let uds_stream = UnixListenerStream::new(uds);
-    // Always set the file permissions of our listening socket.
-    set_file_permissions(path, SOCK_MODE);
+    async fn report_requests(
+        &self,
+        request: Request<ReportRequestsRequest>,
+    ) -> Result<Response<ReportRequestsResponse>, Status> {
+        let mut request = request.into_inner();
+        // Requests reported without a program keep none of their headers and query.
+        let capture = if request.program.is_empty() {
+            CapturePolicy::default()
+        } else {
+            let prog = self
+                .prog_manager
+                .get(request.program.clone(), None)
+                .await
+                .ok_or_else(|| AgentError::ProgramNotFound(request.program.clone()))?;
+            CapturePolicy::from_metadata(&prog.get_metadata())
+        };
+        let events_manager = &self.prog_manager.events_manager;
+        // Headers and queries are dropped as they are parsed, so that nothing past this
+        // point sees what the capture policy doesn't allow.
+        for sample in &mut request.requests {
+            trace_context::extract(sample, events_manager.baggage_keys(), &capture);
+        }
+        let sampled = events_manager.record(request.requests);
+        Ok(Response::new(ReportRequestsResponse { sampled }))
+    }
 
-    let serve = Server::builder()
-        .add_service(service)
-        .serve_with_incoming_shutdown(uds_stream, async move {
+    async fn get_recent_requests(
+        &self,
+        request: Request<GetRecentRequestsRequest>,
+    ) -> Result<Response<GetRecentRequestsResponse>, Status> {
+        let request = request.into_inner();
+        let (requests, p99_latency_ns) = self.prog_manager.events_manager.recent(&request);
+        Ok(Response::new(GetRecentRequestsResponse {
+            requests,
+            p99_latency_ns,
+        }))
+    }
+
+    async fn watch_dependencies(
+        &self,
+        request: Request<WatchDependenciesRequest>,
+    ) -> Result<Response<Self::WatchDependenciesStream>, Status> {
+        let request = request.into_inner();
+        let (replayed, rx) = self
+            .prog_manager
+            .events_manager
+            .watch_dependencies(request.replay, request.resume_after)
+            .map_err(|e| Status::out_of_range(e.to_string()))?;
+
+        let live = stream::unfold(rx, |mut rx| async move {
             loop {
-                match shutdown_rx.recv().await {
-                    Ok(ShutdownSignal::All) => {
-                        debug!("Unix Socket: Received shutdown signal");
-                        break;
+                match rx.recv().await {
+                    Ok(event) => return Some((Ok(event), rx)),
+                    Err(RecvError::Lagged(skipped)) => {
+                        debug!("Dependency watcher lagged, {} changes skipped", skipped)
+                    }
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        });
+        let events = stream::iter(replayed.into_iter().map(Ok)).chain(live);
+        Ok(Response::new(Box::pin(events)))
+    }
+
+    async fn export_graph(
+        &self,
+        request: Request<ExportGraphRequest>,
+    ) -> Result<Response<ExportGraphResponse>, Status> {
+        let request = request.into_inner();
+        let format = GraphFormat::try_from(request.format)
+            .map_err(|_| Status::aborted(format!("Unknown graph format {}", request.format)))?;
+
+        let progs = if request.name.is_empty() {
+            self.prog_manager
+                .list(ListFilter::new(None, HashMap::new()))
+                .await
+        } else {
+            let prog = self
+                .prog_manager
+                .get(request.name.clone(), None)
+                .await
+                .ok_or_else(|| AgentError::ProgramNotFound(request.name.clone()))?;
+            vec![prog]
+        };
+
+        let mut graph = Graph::default();
+        for prog in progs {
+            graph.extend(prog.graph_edges());
+        }
+        Ok(Response::new(ExportGraphResponse {
+            document: graph.render(format),
+        }))
+    }
+
+    async fn get_service_map_at(
+        &self,
+        request: Request<GetServiceMapAtRequest>,
+    ) -> Result<Response<GetServiceMapAtResponse>, Status> {
+        let request = request.into_inner();
+        let prog = self
+            .prog_manager
+            .get(request.name.clone(), None)
+            .await
+            .ok_or_else(|| AgentError::ProgramNotFound(request.name.clone()))?;
+
+        let snapshot = prog
+            .service_map_snapshots(SnapshotQuery::At(request.timestamp_ns))
+            .pop();
+        Ok(Response::new(GetServiceMapAtResponse { snapshot }))
+    }
+
+    async fn get_service_map_range(
+        &self,
+        request: Request<GetServiceMapRangeRequest>,
+    ) -> Result<Response<GetServiceMapRangeResponse>, Status> {
+        let request = request.into_inner();
+        let prog = self
+            .prog_manager
+            .get(request.name.clone(), None)
+            .await
+            .ok_or_else(|| AgentError::ProgramNotFound(request.name.clone()))?;
+
+        let end_ns = match request.end_ns {
+            0 => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(u64::MAX, |d| d.as_nanos() as u64),
+            end_ns => end_ns,
+        };
+        if request.start_ns > end_ns {
+            return Err(Status::aborted("start_ns is after end_ns"));
+        }
+        let snapshots = prog.service_map_snapshots(SnapshotQuery::Range(request.start_ns, end_ns));
+        Ok(Response::new(GetServiceMapRangeResponse { snapshots }))
+    }
+
+    async fn diff_service_map(
+        &self,
+        request: Request<DiffServiceMapRequest>,
+    ) -> Result<Response<DiffServiceMapResponse>, Status> {
+        let request = request.into_inner();
+        let prog = self
+            .prog_manager
+            .get(request.name.clone(), None)
+            .await
+            .ok_or_else(|| AgentError::ProgramNotFound(request.name.clone()))?;
+
+        let to_ns = match request.to_ns {
+            0 => u64::MAX,
+            to_ns => to_ns,
+        };
+        let mut snapshots = Vec::with_capacity(2);
+        for at_ns in [request.from_ns, to_ns] {
+            let snapshot = prog
+                .service_map_snapshots(SnapshotQuery::At(at_ns))
+                .pop()
+                .ok_or_else(|| {
+                    Status::aborted(format!(
+                        "No snapshot of {} retained at or before {}",
+                        request.name, at_ns
+                    ))
+                })?;
+            snapshots.push(snapshot);
+        }
+
+        let (before, after) = (&snapshots[0], &snapshots[1]);
+        Ok(Response::new(DiffServiceMapResponse {
+            from_timestamp_ns: before.timestamp_ns,
+            to_timestamp_ns: after.timestamp_ns,
+            edges: diff_snapshots(before, after, request.min_change),
+        }))
+    }
+
+    async fn watch_service_map(
+        &self,
+        request: Request<WatchServiceMapRequest>,
+    ) -> Result<Response<Self::WatchServiceMapStream>, Status> {
+        let request = request.into_inner();
+        self.prog_manager
+            .get(request.name.clone(), None)
+            .await
+            .ok_or_else(|| AgentError::ProgramNotFound(request.name.clone()))?;
+
+        let mut ticker = tokio::time::interval(SERVICE_MAP_WATCH_PERIOD);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        let state = (
+            self.prog_manager.clone(),
+            request.name,
+            ServiceMapWatch::new(request.resync_interval),
+            ticker,
+        );
+        // Ends once the program is unloaded.
+        let updates = stream::unfold(state, |mut state| async move {
+            loop {
+                let (prog_manager, name, watch, ticker) = &mut state;
+                ticker.tick().await;
+                let prog = prog_manager.get(name.clone(), None).await?;
+                let snapshot = prog
+                    .service_map_snapshots(SnapshotQuery::At(u64::MAX))
+                    .pop();
+                if let Some(update) = snapshot.and_then(|s| watch.update(s)) {
+                    return Some((Ok(update), state));
+                }
+            }
+        });
+        Ok(Response::new(Box::pin(updates)))
+    }
+
+    async fn trace_pod(
+        &self,
+        request: Request<TracePodRequest>,
+    ) -> Result<Response<TracePodResponse>, Status> {
+        let caller = caller(&request);
+        let request = request.into_inner();
+        let result = self.pod_tracer.start(&request).map_err(AgentError::from);
+        let mut metadata = HashMap::new();
+        if !request.pod.is_empty() {
+            metadata.insert(
+                "pod".to_string(),
+                format!("{}/{}", request.namespace, request.pod),
+            );
+            metadata.insert(
+                "duration_secs".to_string(),
+                request.duration_secs.to_string(),
+            );
+        }
+        self.audit("trace_pod", &caller, "", metadata, &result);
+        Ok(Response::new(result?))
+    }
+
+    async fn api_version(
+        &self,
+        _request: Request<ApiVersionRequest>,
+    ) -> Result<Response<ApiVersionResponse>, Status> {
+        Ok(Response::new(ApiVersionResponse {
+            api_version: API_VERSION.to_string(),
+            agent_version: env!("CARGO_PKG_VERSION").to_string(),
+            features: features::ALL.iter().map(|f| f.to_string()).collect(),
+        }))
+    }
+
+    async fn pause_program(
+        &self,
+        request: Request<PauseProgramRequest>,
+    ) -> Result<Response<PauseProgramResponse>, Status> {
+        let caller = caller(&request);
+        let request = request.into_inner();
+        let result = self.prog_manager.pause(&request.name);
+        self.audit("pause", &caller, &request.name, HashMap::new(), &result);
+        result?;
+        Ok(Response::new(PauseProgramResponse {}))
+    }
+
+    async fn resume_program(
+        &self,
+        request: Request<ResumeProgramRequest>,
+    ) -> Result<Response<ResumeProgramResponse>, Status> {
+        let caller = caller(&request);
+        let request = request.into_inner();
+        let result = self.prog_manager.resume(&request.name);
+        self.audit("resume", &caller, &request.name, HashMap::new(), &result);
+        result?;
+        Ok(Response::new(ResumeProgramResponse {}))
+    }
+
+    async fn get_audit_log(
+        &self,
+        request: Request<GetAuditLogRequest>,
+    ) -> Result<Response<GetAuditLogResponse>, Status> {
+        let request = request.into_inner();
+        let records = self.prog_manager.audit_log.recent(&request);
+        Ok(Response::new(GetAuditLogResponse { records }))
+    }
+
+    async fn get_load_diagnostics(
+        &self,
+        request: Request<GetLoadDiagnosticsRequest>,
+    ) -> Result<Response<GetLoadDiagnosticsResponse>, Status> {
+        let request = request.into_inner();
+        self.prog_manager
+            .get(request.name.clone(), None)
+            .await
+            .ok_or_else(|| AgentError::ProgramNotFound(request.name.clone()))?;
+        Ok(Response::new(GetLoadDiagnosticsResponse {
+            diagnostics: self.prog_manager.image_manager.diagnostics(&request.name),
+        }))
+    }
+
+    async fn inspect_programs(
+        &self,
+        request: Request<InspectProgramsRequest>,
+    ) -> Result<Response<InspectProgramsResponse>, Status> {
+        let request = request.into_inner();
+        let progs = if request.name.is_empty() {
+            self.prog_manager
+                .list(ListFilter::new(None, HashMap::new()))
+                .await
+        } else {
+            vec![self
+                .prog_manager
+                .get(request.name.clone(), None)
+                .await
+                .ok_or_else(|| AgentError::ProgramNotFound(request.name.clone()))?]
+        };
+        let infos = progs
+            .iter()
+            .map(|prog| prog.get_program_info())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(AgentError::from)?;
+
+        let window = Duration::from_millis(request.stats_window_ms as u64).min(MAX_STATS_WINDOW);
+        if window.is_zero() {
+            let programs = self.inspect(&infos).await?;
+            return Ok(Response::new(InspectProgramsResponse { programs }));
+        }
+        // Run statistics are only counted while enabled, which costs every eBPF
+        // program a little, so only over the window.
+        let _stats = enable_stats().map_err(|e| match e.raw_os_error() {
+            Some(libc::EINVAL) => AgentError::KernelUnsupported("BPF_ENABLE_STATS".to_string()),
+            _ => AgentError::Internal(anyhow::Error::new(e).context("enabling run statistics")),
+        })?;
+        let before = self.inspect(&infos).await?;
+        tokio::time::sleep(window).await;
+        let mut programs = self.inspect(&infos).await?;
+        runs_since(&mut programs, &before);
+        Ok(Response::new(InspectProgramsResponse { programs }))
+    }
+
+    async fn validate_program(
+        &self,
+        request: Request<ValidateProgramRequest>,
+    ) -> Result<Response<ValidateProgramResponse>, Status> {
+        let request = request.into_inner();
+        let mut checks = Vec::new();
+
+        let program_type: Option<ProgramType> = request.program_type.try_into().ok();
+        let prog = match program_type.clone() {
+            Some(program_type) => {
+                self.prog_manager
+                    .get(request.name.clone(), Some(program_type))
+                    .await
+            }
+            None => None,
+        };
+        checks.push(match (&program_type, &prog) {
+            (None, _) => check(
+                "program",
+                ValidationStatus::Failed,
+                format!("Invalid program type {}", request.program_type),
+            ),
+            (Some(_), None) => check(
+                "program",
+                ValidationStatus::Failed,
+                format!("Program {} not found", request.name),
+            ),
+            (Some(_), Some(_)) => check(
+                "program",
+                ValidationStatus::Passed,
+                format!("Program {} is registered", request.name),
+            ),
+        });
+
+        checks.push(match program_type {
+            Some(program_type) => {
+                let image_manager = self.prog_manager.image_manager.clone();
+                let bytecode = request.bytecode.unwrap_or_default();
+                let verified = tokio::task::spawn_blocking(move || {
+                    image_manager.verify(&bytecode, program_type)
+                })
+                .await
+                .map_err(anyhow::Error::from)
+                .and_then(|verified| verified);
+                match verified {
+                    Ok(Verification::Passed(message)) => {
+                        check("bytecode", ValidationStatus::Passed, message)
+                    }
+                    Ok(Verification::Skipped(message)) => {
+                        check("bytecode", ValidationStatus::Skipped, message)
                     }
                     Err(e) => {
-                        error!("Error receiving shutdown signal {:?}", e.to_string());
-                        continue;
+                        let mut message = format!("{:#}", e);
+                        if let Some(AgentError::VerifierRejected { verifier_log, .. }) =
+                            e.downcast_ref()
+                        {
+                            message = format!("{}\n{}", message, verifier_log);
+                        }
+                        check("bytecode", ValidationStatus::Failed, message)
                     }
-                    _ => continue,
                 }
             }
+            None => check(
+                "bytecode",
+                ValidationStatus::Skipped,
+                "Unknown program type".to_string(),
+            ),
         });
 
-    let socket_path = path.to_path_buf();
+        checks.push(match self.get_prog_ids_for_maps(request.ebpf_maps).await {
+            Err(e) => check("ebpf_maps", ValidationStatus::Failed, e.to_string()),
+            Ok(maps) => match prog.as_ref().map(|prog| prog.validate_maps(&maps)) {
+                Some(Ok(())) => check(
+                    "ebpf_maps",
+                    ValidationStatus::Passed,
+                    "Every map the program reads is pinned".to_string(),
+                ),
+                Some(Err(e)) => check("ebpf_maps", ValidationStatus::Failed, e.to_string()),
+                None => check(
+                    "ebpf_maps",
+                    ValidationStatus::Skipped,
+                    "Unknown program".to_string(),
+                ),
+            },
+        });
+
+        let mut metadata_errors = Vec::new();
+        checks.push(
+            match prog
+                .as_ref()
+                .map(|prog| prog.metadata_schema().validate(&request.metadata))
+            {
+                Some(Ok(_)) => check(
+                    "metadata",
+                    ValidationStatus::Passed,
+                    "Metadata is valid".to_string(),
+                ),
+                Some(Err(errors)) => {
+                    let message = errors.to_string();
+                    metadata_errors = errors.0;
+                    check("metadata", ValidationStatus::Failed, message)
+                }
+                None => check(
+                    "metadata",
+                    ValidationStatus::Skipped,
+                    "Unknown program".to_string(),
+                ),
+            },
+        );
+
+        let valid = checks
+            .iter()
+            .all(|check| check.status() != ValidationStatus::Failed);
+        Ok(Response::new(ValidateProgramResponse {
+            valid,
+            checks,
+            metadata_errors,
+        }))
+    }
+}
+
+fn check(name: &str, status: ValidationStatus, message: String) -> ValidationCheck {
+    ValidationCheck {
+        name: name.to_string(),
+        status: status.into(),
+        message,
+    }
+}
+
+/// An address the agent API is served on.
+#[derive(Debug)]
+pub(crate) enum ListenAddr {
+    /// A unix socket, created with the given file mode. Paths starting with `@` name
+    /// a socket in the abstract namespace, which has no file and no permissions, so
+    /// its clients are checked against `peers` instead.
+    Unix {
+        path: PathBuf,
+        mode: u32,
+        peers: PeerAllowlist,
+    },
+    /// A TCP address, served every method to the clients sending the token held by
+    /// `token_file`, or only the read-only methods without one.
+    Tcp {
+        addr: SocketAddr,
+        token_file: Option<PathBuf>,
+    },
+    /// A listening unix or TCP socket passed by systemd socket activation.
+    Systemd(OwnedFd),
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddr::Unix { path, .. } => write!(f, "unix:{}", path.display()),
+            ListenAddr::Tcp { addr, .. } => write!(f, "tcp:{}", addr),
+            ListenAddr::Systemd(fd) => write!(f, "systemd:fd{}", fd.as_raw_fd()),
+        }
+    }
+}
+
+/// Serves the agent API on `addr`. Sockets passed by systemd are served like the
+/// configured socket of their kind, with `token_file` on TCP.
+pub async fn serve(
+    addr: ListenAddr,
+    service: AgentServer<AgentService>,
+    token_file: Option<PathBuf>,
+    mut shutdown_rx: broadcast::Receiver<ShutdownSignal>,
+) -> anyhow::Result<JoinHandle<()>> {
+    // Lets tools such as grpcurl discover the API without a copy of the proto.
+    let reflection = tonic_reflection::server::Builder::configure()
+        .register_encoded_file_descriptor_set(FILE_DESCRIPTOR_SET)
+        .build()?;
+
+    let tcp_access = |token_file: Option<PathBuf>| ApiAccess::Tcp {
+        token_file: token_file.map(Arc::new),
+    };
+    let access = match &addr {
+        ListenAddr::Unix { .. } => ApiAccess::Full,
+        ListenAddr::Tcp { token_file, .. } => tcp_access(token_file.clone()),
+        // Getting the address of a socket as a unix one fails if it isn't.
+        ListenAddr::Systemd(fd) => {
+            let unix = std::os::unix::net::UnixListener::from(fd.try_clone()?);
+            match unix.local_addr() {
+                Ok(_) => ApiAccess::Full,
+                Err(_) => tcp_access(token_file),
+            }
+        }
+    };
+    let router = Server::builder()
+        .layer(AccessLayer::new(access))
+        .add_service(service)
+        .add_service(reflection);
+    let shutdown = async move {
+        loop {
+            match shutdown_rx.recv().await {
+                Ok(ShutdownSignal::All) => {
+                    debug!("Agent API: Received shutdown signal");
+                    break;
+                }
+                Err(e) => {
+                    error!("Error receiving shutdown signal {:?}", e.to_string());
+                    continue;
+                }
+                _ => continue,
+            }
+        }
+    };
+
+    let serve: Pin<Box<dyn Future<Output = Result<(), tonic::transport::Error>> + Send>> =
+        match &addr {
+            ListenAddr::Systemd(fd) => {
+                // Getting the address of a socket as a unix one fails if it isn't.
+                let unix = std::os::unix::net::UnixListener::from(fd.try_clone()?);
+                if unix.local_addr().is_ok() {
+                    unix.set_nonblocking(true)?;
+                    let uds = UnixListener::from_std(unix)?;
+                    Box::pin(
+                        router.serve_with_incoming_shutdown(UnixListenerStream::new(uds), shutdown),
+                    )
+                } else {
+                    let tcp = std::net::TcpListener::from(OwnedFd::from(unix));
+                    tcp.set_nonblocking(true)?;
+                    let tcp = TcpListener::from_std(tcp)?;
+                    Box::pin(
+                        router.serve_with_incoming_shutdown(TcpListenerStream::new(tcp), shutdown),
+                    )
+                }
+            }
+            ListenAddr::Unix { path, mode, peers } => {
+                let uds = bind_unix(path, *mode)?;
+                if is_abstract(path) {
+                    let peers = peers.clone();
+                    let incoming = UnixListenerStream::new(uds)
+                        .filter(move |conn| future::ready(accept_peer(conn, &peers)));
+                    Box::pin(router.serve_with_incoming_shutdown(incoming, shutdown))
+                } else {
+                    Box::pin(
+                        router.serve_with_incoming_shutdown(UnixListenerStream::new(uds), shutdown),
+                    )
+                }
+            }
+            ListenAddr::Tcp {
+                addr: socket_addr, ..
+            } => {
+                let tcp = TcpListener::bind(socket_addr).await?;
+                Box::pin(router.serve_with_incoming_shutdown(TcpListenerStream::new(tcp), shutdown))
+            }
+        };
+
     Ok(tokio::spawn(async move {
-        info!("Listening on {}", socket_path.display());
+        info!("Listening on {}", addr);
         if let Err(e) = serve.await {
             error!("Server error: {e:?}");
         }
-        info!("Shutdown Unix Handler {}", socket_path.display());
+        info!("Shutdown Agent API Handler {}", addr);
     }))
 }
+
+/// Parses a file mode given in octal on the command line, e.g. `0660`.
+pub(crate) fn parse_mode(s: &str) -> Result<u32, String> {
+    u32::from_str_radix(s.trim_start_matches("0o"), 8)
+        .ok()
+        .filter(|mode| *mode <= 0o7777)
+        .ok_or(format!("expected an octal file mode, got {:?}", s))
+}
+
+fn is_abstract(path: &Path) -> bool {
+    path.to_str().is_some_and(|p| p.starts_with('@'))
+}
+
+/// Whether to serve a connection to an abstract socket, by the credentials of the
+/// process on the other end. Failed accepts are passed on to be reported.
+fn accept_peer(conn: &std::io::Result<UnixStream>, peers: &PeerAllowlist) -> bool {
+    let Ok(stream) = conn else {
+        return true;
+    };
+    match stream.peer_cred() {
+        Ok(cred) if peers.allows(cred.uid(), cred.gid()) => true,
+        Ok(cred) => {
+            warn!(
+                "Refusing agent API connection from uid={} gid={} pid={}",
+                cred.uid(),
+                cred.gid(),
+                cred.pid().unwrap_or_default()
+            );
+            false
+        }
+        Err(e) => {
+            warn!("Refusing agent API connection without credentials: {}", e);
+            false
+        }
+    }
+}
+
+fn bind_unix(path: &Path, mode: u32) -> anyhow::Result<UnixListener> {
+    if let Some(name) = path.to_str().and_then(|p| p.strip_prefix('@')) {
+        let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+        let listener = std::os::unix::net::UnixListener::bind_addr(&addr)?;
+        listener.set_nonblocking(true)?;
+        return Ok(UnixListener::from_std(listener)?);
+    }
+
+    if path.exists() {
+        // Attempt to remove the socket, since bind fails if it exists
+        remove_file(path)?;
+    }
+    let uds = UnixListener::bind(path)?;
+    // Always set the file permissions of our listening socket.
+    set_file_permissions(path, mode);
+    Ok(uds)
+}
@@ -0,0 +1,363 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use ahash::AHashMap;
+use bytes::{BufMut, BytesMut};
+use conn_tracer_common::ConnectionKey;
+use log::{debug, info, warn};
+use tokio::net::UdpSocket;
+use tokio::sync::broadcast::Receiver;
+use tokio::task::JoinHandle;
+use tokio::time::MissedTickBehavior;
+
+use agent_api::ProgramState;
+
+use crate::managers::registry::RegistryManager;
+use crate::progs::types::{Flow, ShutdownSignal};
+
+const SFLOW_VERSION: u32 = 5;
+const ADDRESS_IPV4: u32 = 1;
+/// Standard sFlow formats, in the enterprise 0 namespace.
+const FORMAT_FLOW_SAMPLE: u32 = 1;
+const FORMAT_SAMPLED_IPV4: u32 = 3;
+const IPPROTO_TCP: u32 = 6;
+/// Keeps datagrams within the usual Ethernet MTU.
+const MAX_DATAGRAM_SIZE: usize = 1400;
+/// Size of an encoded flow sample holding one sampled IPv4 record.
+const FLOW_SAMPLE_SIZE: usize = 8 + 32 + 8 + 32;
+/// Size of the datagram header, with an IPv4 agent address.
+const HEADER_SIZE: usize = 28;
+
+/// Where and how to export sampled flows in sFlow version 5.
+#[derive(Debug, Clone)]
+pub(crate) struct SflowConfig {
+    pub(crate) collector: SocketAddr,
+    pub(crate) interval: Duration,
+    /// One in this many connections carrying traffic in an interval is exported.
+    pub(crate) sampling_rate: u32,
+    /// Address the agent is known by to the collector. The address the datagrams are
+    /// sent from by default.
+    pub(crate) agent_address: Option<Ipv4Addr>,
+}
+
+pub async fn serve(
+    config: SflowConfig,
+    registry_manager: RegistryManager,
+    shutdown_rx: Receiver<ShutdownSignal>,
+) -> anyhow::Result<JoinHandle<()>> {
+    let bind: SocketAddr = match config.collector {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => return Err(anyhow::anyhow!("sFlow collectors must be IPv4")),
+    };
+    let socket = UdpSocket::bind(bind).await?;
+    socket.connect(config.collector).await?;
+    let agent_address = match (config.agent_address, socket.local_addr()?.ip()) {
+        (Some(address), _) => address,
+        (None, IpAddr::V4(address)) => address,
+        (None, IpAddr::V6(_)) => Ipv4Addr::UNSPECIFIED,
+    };
+    let exporter = SflowExporter::new(config, agent_address);
+    let handle = tokio::spawn(exporter.run(socket, registry_manager, shutdown_rx));
+    Ok(handle)
+}
+
+/// Samples the connections observed by the running programs into sFlow flow samples.
+/// Connection tracking doesn't see packets, so each sample stands for the bytes one
+/// connection carried in one direction over the last interval, reported as the
+/// length of a sampled IPv4 packet.
+struct SflowExporter {
+    config: SflowConfig,
+    agent_address: Ipv4Addr,
+    started: Instant,
+    datagrams: u32,
+    samples: u32,
+    /// Connections carrying traffic seen so far, sampled or not.
+    sample_pool: u32,
+    /// Totals of each open connection when last exported.
+    exported: AHashMap<(String, ConnectionKey), (u64, u64)>,
+}
+
+impl SflowExporter {
+    fn new(config: SflowConfig, agent_address: Ipv4Addr) -> Self {
+        Self {
+            config,
+            agent_address,
+            started: Instant::now(),
+            datagrams: 0,
+            samples: 0,
+            sample_pool: 0,
+            exported: AHashMap::new(),
+        }
+    }
+
+    async fn run(
+        mut self,
+        socket: UdpSocket,
+        registry_manager: RegistryManager,
+        mut shutdown_rx: Receiver<ShutdownSignal>,
+    ) {
+        info!(
+            "Exporting sFlow samples to {} every {:?}",
+            self.config.collector, self.config.interval
+        );
+        let mut ticker = tokio::time::interval(self.config.interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        loop {
+            tokio::select! {
+                Ok(signal) = shutdown_rx.recv() => {
+                    if let ShutdownSignal::All = signal {
+                        info!("Received shutdown signal, stopping sFlow export.");
+                        break;
+                    }
+                },
+                _ = ticker.tick() => {
+                    let flows = registry_manager
+                        .builtin
+                        .list()
+                        .into_iter()
+                        .filter(|prog| {
+                            matches!(
+                                prog.get_state(),
+                                ProgramState::Running | ProgramState::Degraded
+                            )
+                        })
+                        .map(|prog| (prog.get_name(), prog.flows()))
+                        .collect();
+                    for datagram in self.datagrams(flows) {
+                        if let Err(e) = socket.send(&datagram).await {
+                            warn!("Failed to send sFlow datagram: {:?}", e);
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Samples the traffic of the flows since the last export into datagrams.
+    fn datagrams(&mut self, flows: Vec<(String, Vec<Flow>)>) -> Vec<BytesMut> {
+        let mut samples = Vec::new();
+        let mut exported = AHashMap::new();
+        for (program, flows) in flows {
+            for flow in flows {
+                let id = (program.clone(), flow.key);
+                let (sent, received) = self.exported.get(&id).copied().unwrap_or_default();
+                let sent = flow.bytes_sent.saturating_sub(sent);
+                let received = flow.bytes_received.saturating_sub(received);
+                if !flow.closed {
+                    exported.insert(id, (flow.bytes_sent, flow.bytes_received));
+                }
+                if sent == 0 && received == 0 {
+                    continue;
+                }
+                self.sample_pool = self.sample_pool.wrapping_add(1);
+                let rate = self.config.sampling_rate.max(1);
+                if rate > 1 && !rand::random::<u32>().is_multiple_of(rate) {
+                    continue;
+                }
+                let key = flow.key;
+                if sent > 0 {
+                    samples.push(self.flow_sample(
+                        (key.src_addr, key.src_port),
+                        (key.dest_addr, key.dest_port),
+                        sent,
+                    ));
+                }
+                if received > 0 {
+                    samples.push(self.flow_sample(
+                        (key.dest_addr, key.dest_port),
+                        (key.src_addr, key.src_port),
+                        received,
+                    ));
+                }
+            }
+        }
+        self.exported = exported;
+
+        let per_datagram = (MAX_DATAGRAM_SIZE - HEADER_SIZE) / FLOW_SAMPLE_SIZE;
+        let datagrams: Vec<_> = samples
+            .chunks(per_datagram)
+            .map(|samples| self.datagram(samples))
+            .collect();
+        debug!(
+            "Exporting {} sFlow samples in {} datagrams",
+            samples.len(),
+            datagrams.len()
+        );
+        datagrams
+    }
+
+    fn flow_sample(&mut self, src: (u32, u32), dest: (u32, u32), bytes: u64) -> BytesMut {
+        self.samples = self.samples.wrapping_add(1);
+        let mut sample = BytesMut::with_capacity(FLOW_SAMPLE_SIZE);
+        sample.put_u32(FORMAT_FLOW_SAMPLE);
+        sample.put_u32((FLOW_SAMPLE_SIZE - 8) as u32);
+        sample.put_u32(self.samples);
+        // The agent has a single, unnamed, data source.
+        sample.put_u32(0);
+        sample.put_u32(self.config.sampling_rate.max(1));
+        sample.put_u32(self.sample_pool);
+        // Drops, and the input and output interfaces, are unknown.
+        sample.put_u32(0);
+        sample.put_u32(0);
+        sample.put_u32(0);
+        sample.put_u32(1);
+
+        sample.put_u32(FORMAT_SAMPLED_IPV4);
+        sample.put_u32(32);
+        sample.put_u32(bytes.min(u32::MAX as u64) as u32);
+        sample.put_u32(IPPROTO_TCP);
+        sample.put_u32(src.0);
+        sample.put_u32(dest.0);
+        sample.put_u32(src.1);
+        sample.put_u32(dest.1);
+        // TCP flags and type of service.
+        sample.put_u32(0);
+        sample.put_u32(0);
+        sample
+    }
+
+    fn datagram(&mut self, samples: &[BytesMut]) -> BytesMut {
+        self.datagrams = self.datagrams.wrapping_add(1);
+        let mut datagram = BytesMut::with_capacity(HEADER_SIZE + samples.len() * FLOW_SAMPLE_SIZE);
+        datagram.put_u32(SFLOW_VERSION);
+        datagram.put_u32(ADDRESS_IPV4);
+        datagram.put_slice(&self.agent_address.octets());
+        // Sub-agent id.
+        datagram.put_u32(0);
+        datagram.put_u32(self.datagrams);
+        datagram.put_u32(self.started.elapsed().as_millis() as u32);
+        datagram.put_u32(samples.len() as u32);
+        for sample in samples {
+            datagram.put_slice(sample);
+        }
+        datagram
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exporter(sampling_rate: u32) -> SflowExporter {
+        let config = SflowConfig {
+            collector: (Ipv4Addr::LOCALHOST, 6343).into(),
+            interval: Duration::from_secs(10),
+            sampling_rate,
+            agent_address: None,
+        };
+        SflowExporter::new(config, Ipv4Addr::new(192, 0, 2, 1))
+    }
+
+    fn flow(bytes_sent: u64, bytes_received: u64, closed: bool) -> Flow {
+        Flow {
+            key: ConnectionKey {
+                id: 1,
+                pid: 4242,
+                src_addr: Ipv4Addr::new(10, 244, 1, 5).into(),
+                src_port: 41234,
+                dest_addr: Ipv4Addr::new(10, 96, 0, 10).into(),
+                dest_port: 8080,
+                role: 0,
+            },
+            bytes_sent,
+            bytes_received,
+            closed,
+        }
+    }
+
+    /// A datagram holding one flow sample of 1500 bytes from 10.244.1.5:41234 to
+    /// 10.96.0.10:8080, laid out as in the sFlow version 5 specification, and as
+    /// sflowtool decodes it.
+    #[rustfmt::skip]
+    const DATAGRAM: [u8; HEADER_SIZE + FLOW_SAMPLE_SIZE] = [
+        // datagramVersion 5, agent IPv4 192.0.2.1, subAgentId 0, packetSequenceNo 1.
+        0, 0, 0, 5, 0, 0, 0, 1, 192, 0, 2, 1, 0, 0, 0, 0, 0, 0, 0, 1,
+        // sysUpTime, zeroed, and 1 sample.
+        0, 0, 0, 0, 0, 0, 0, 1,
+        // flow_sample, enterprise 0 format 1, of 72 bytes.
+        0, 0, 0, 1, 0, 0, 0, 72,
+        // sampleSequenceNo 1, sourceId 0:0, meanSkipCount 1, samplePool 1.
+        0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 1,
+        // dropEvents 0, inputPort 0, outputPort 0, 1 flow record.
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1,
+        // sampled_ipv4, enterprise 0 format 3, of 32 bytes.
+        0, 0, 0, 3, 0, 0, 0, 32,
+        // length 1500, protocol 6 (TCP).
+        0, 0, 0x05, 0xdc, 0, 0, 0, 6,
+        // src_ip 10.244.1.5, dst_ip 10.96.0.10.
+        10, 244, 1, 5, 10, 96, 0, 10,
+        // src_port 41234, dst_port 8080, tcp_flags 0, tos 0.
+        0, 0, 0xa1, 0x12, 0, 0, 0x1f, 0x90, 0, 0, 0, 0, 0, 0, 0, 0,
+    ];
+
+    #[test]
+    fn test_datagram_bytes() {
+        let mut exporter = exporter(1);
+        let datagrams = exporter.datagrams(vec![(
+            "conn-tracer".to_string(),
+            vec![flow(1500, 0, false)],
+        )]);
+        assert_eq!(datagrams.len(), 1);
+        let mut datagram = datagrams[0].to_vec();
+        let uptime = u32::from_be_bytes(datagram[20..24].try_into().unwrap());
+        assert!(uptime < 60_000, "{}", uptime);
+        datagram[20..24].fill(0);
+        assert_eq!(datagram, DATAGRAM);
+    }
+
+    #[test]
+    fn test_samples_traffic_since_last_export() {
+        let mut exporter = exporter(1);
+        let program = || "conn-tracer".to_string();
+        let datagrams = exporter.datagrams(vec![(program(), vec![flow(1000, 300, false)])]);
+        // One sample each way, the received bytes from the server to the client.
+        assert_eq!(
+            u32::from_be_bytes(datagrams[0][24..28].try_into().unwrap()),
+            2
+        );
+        let received = &datagrams[0][HEADER_SIZE + FLOW_SAMPLE_SIZE..];
+        assert_eq!(received[48..52], 300u32.to_be_bytes());
+        assert_eq!(received[56..60], [10, 96, 0, 10]);
+        assert_eq!(received[64..68], 8080u32.to_be_bytes());
+
+        // Idle connections aren't sampled.
+        assert!(exporter
+            .datagrams(vec![(program(), vec![flow(1000, 300, false)])])
+            .is_empty());
+        let datagrams = exporter.datagrams(vec![(program(), vec![flow(1500, 300, true)])]);
+        let sample = &datagrams[0][HEADER_SIZE..];
+        assert_eq!(sample[48..52], 500u32.to_be_bytes());
+        // Sequence numbers go on across datagrams, skipping the idle interval.
+        assert_eq!(datagrams[0][16..20], 2u32.to_be_bytes());
+        assert_eq!(sample[8..12], 3u32.to_be_bytes());
+        // A connection reusing the key once closed starts from zero.
+        let datagrams = exporter.datagrams(vec![(program(), vec![flow(100, 0, false)])]);
+        assert_eq!(
+            datagrams[0][HEADER_SIZE + 48..HEADER_SIZE + 52],
+            100u32.to_be_bytes()
+        );
+    }
+
+    #[test]
+    fn test_datagrams_fit_the_mtu() {
+        let mut exporter = exporter(1);
+        let flows = (0..40)
+            .map(|id| Flow {
+                key: ConnectionKey {
+                    id,
+                    ..flow(0, 0, false).key
+                },
+                ..flow(1, 0, false)
+            })
+            .collect();
+        let datagrams = exporter.datagrams(vec![("conn-tracer".to_string(), flows)]);
+        assert_eq!(datagrams.len(), 3);
+        assert!(datagrams.iter().all(|d| d.len() <= MAX_DATAGRAM_SIZE));
+        let samples: u32 = datagrams
+            .iter()
+            .map(|d| u32::from_be_bytes(d[24..28].try_into().unwrap()))
+            .sum();
+        assert_eq!(samples, 40);
+    }
+}
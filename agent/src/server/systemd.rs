@@ -0,0 +1,150 @@
+use std::env;
+use std::os::fd::{FromRawFd, OwnedFd, RawFd};
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+use log::{debug, warn};
+
+/// First file descriptor passed by socket activation, see sd_listen_fds(3).
+const SD_LISTEN_FDS_START: RawFd = 3;
+/// `FileDescriptorName=` of the sockets the agent API is served on.
+const API_FD_NAME: &str = "agent";
+/// Name of the sockets passed without one, see sd_listen_fds_with_names(3).
+const UNKNOWN_FD_NAME: &str = "unknown";
+
+/// The sockets passed by systemd socket activation, with their `FileDescriptorName=`.
+#[derive(Debug, Default)]
+pub struct ListenFds(Vec<(String, OwnedFd)>);
+
+impl ListenFds {
+    /// Takes the sockets named `agent`, to serve the agent API on. The others are
+    /// closed, the agent serving nothing else on sockets it is passed.
+    pub(crate) fn into_api(self) -> Vec<OwnedFd> {
+        debug!("Received {} sockets from systemd", self.0.len());
+        self.0
+            .into_iter()
+            .filter_map(|(name, fd)| {
+                if name == API_FD_NAME {
+                    return Some(fd);
+                }
+                warn!(
+                    "Closing socket {:?} passed by systemd, the agent API is only served on those named {:?}",
+                    name, API_FD_NAME
+                );
+                None
+            })
+            .collect()
+    }
+}
+
+/// Takes the sockets passed by systemd socket activation, if the agent was started by
+/// it. The variables describing them are removed so that they aren't taken twice, or
+/// inherited by child processes, which is only sound before other threads start: call
+/// it first thing in `main`, before the runtime is built.
+pub fn listen_fds() -> ListenFds {
+    let for_us = env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        == Some(std::process::id());
+    let count = env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|count| count.parse::<RawFd>().ok())
+        .unwrap_or(0);
+    let names = env::var("LISTEN_FDNAMES").unwrap_or_default();
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+    if !for_us {
+        return ListenFds::default();
+    }
+
+    ListenFds(
+        fd_names(count, &names)
+            .into_iter()
+            // SAFETY: systemd passes `count` open sockets starting at
+            // SD_LISTEN_FDS_START, and nothing else owns them.
+            .map(|(fd, name)| (name, unsafe { OwnedFd::from_raw_fd(fd) }))
+            .collect(),
+    )
+}
+
+/// Pairs the `count` sockets passed with their names, separated by colons in `names`.
+fn fd_names(count: RawFd, names: &str) -> Vec<(RawFd, String)> {
+    let mut names = names.split(':');
+    (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count.max(0))
+        .map(|fd| {
+            let name = names.next().filter(|name| !name.is_empty());
+            (fd, name.unwrap_or(UNKNOWN_FD_NAME).to_string())
+        })
+        .collect()
+}
+
+/// Notifies the service manager of a state change such as `READY=1`, when the agent
+/// runs as a systemd service of `Type=notify`. Does nothing otherwise.
+pub(crate) fn notify(state: &str) {
+    let Ok(path) = env::var("NOTIFY_SOCKET") else {
+        return;
+    };
+    let sent = UnixDatagram::unbound().and_then(|socket| match path.strip_prefix('@') {
+        Some(name) => socket.send_to_addr(state.as_bytes(), &SocketAddr::from_abstract_name(name)?),
+        None => socket.send_to(state.as_bytes(), &path),
+    });
+    if let Err(e) = sent {
+        warn!("Failed to notify systemd of {}: {:?}", state, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_listen_fds_for_another_process() {
+        env::set_var("LISTEN_PID", (std::process::id() + 1).to_string());
+        env::set_var("LISTEN_FDS", "2");
+        env::set_var("LISTEN_FDNAMES", "agent:metrics");
+        assert!(listen_fds().0.is_empty());
+        // Taken once, whoever they were for.
+        assert!(env::var("LISTEN_PID").is_err());
+        assert!(env::var("LISTEN_FDS").is_err());
+        assert!(env::var("LISTEN_FDNAMES").is_err());
+    }
+
+    #[test]
+    fn test_fd_names() {
+        assert_eq!(
+            fd_names(2, "agent:metrics"),
+            vec![(3, "agent".to_string()), (4, "metrics".to_string())]
+        );
+        assert_eq!(
+            fd_names(2, ""),
+            vec![(3, "unknown".to_string()), (4, "unknown".to_string())]
+        );
+        assert!(fd_names(0, "agent").is_empty());
+    }
+
+    #[test]
+    fn test_api_fds_are_selected_by_name() {
+        let socket = |name: &str| {
+            let (socket, _) = UnixDatagram::pair().unwrap();
+            (name.to_string(), OwnedFd::from(socket))
+        };
+        let fds = ListenFds(vec![socket("agent"), socket("metrics"), socket("agent")]);
+        assert_eq!(fds.into_api().len(), 2);
+        assert!(ListenFds(vec![socket("unknown")]).into_api().is_empty());
+    }
+
+    #[test]
+    fn test_notify() {
+        let path = env::temp_dir().join(format!("agent-notify-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let socket = UnixDatagram::bind(&path).unwrap();
+        env::set_var("NOTIFY_SOCKET", &path);
+        notify("READY=1");
+        env::remove_var("NOTIFY_SOCKET");
+        let mut buf = [0; 16];
+        let len = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,200 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use futures::stream;
+use http_body_util::combinators::UnsyncBoxBody;
+use http_body_util::{BodyExt, Full, StreamBody};
+use hyper::body::{Bytes, Frame, Incoming};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{header, Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use log::{debug, info};
+use serde_json::{json, Value};
+use tokio::net::TcpListener;
+use tokio::sync::broadcast::Receiver;
+use tokio::task::JoinHandle;
+use tokio::time::MissedTickBehavior;
+
+use agent_api::v1::{ServiceMapEdge, ServiceMapUpdate};
+use agent_api::ProgramState;
+
+use crate::common::graph::ServiceMapWatch;
+use crate::managers::registry::RegistryManager;
+use crate::progs::types::{ShutdownSignal, SnapshotQuery};
+
+const INDEX_HTML: &str = include_str!("ui/index.html");
+const APP_JS: &str = include_str!("ui/app.js");
+/// How often the service map streamed to a page is looked at for a new snapshot.
+const WATCH_PERIOD: Duration = Duration::from_secs(1);
+/// How long a stream is left without an event before a comment is sent on it, for
+/// proxies in between not to close it.
+const KEEPALIVE_PERIOD: Duration = Duration::from_secs(15);
+
+type Body = UnsyncBoxBody<Bytes, Infallible>;
+
+/// Serves a page drawing the live service map of a program, along with what it reads:
+///
+/// - `GET /api/programs` lists the running programs building a service map.
+/// - `GET /api/watch?program=` streams the updates of the service map of a program as
+///   server-sent events, each holding a [`ServiceMapUpdate`] in JSON. Like the watches
+///   of the agent API, the first update is full and the next ones deltas, with a full
+///   one every so often. The stream ends when the program is unloaded.
+pub async fn serve(
+    address: SocketAddr,
+    registry_manager: RegistryManager,
+    mut shutdown_rx: Receiver<ShutdownSignal>,
+) -> anyhow::Result<JoinHandle<()>> {
+    let listener = TcpListener::bind(address).await?;
+    info!("Serving the web UI on http://{}", address);
+    let handle = tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                Ok(signal) = shutdown_rx.recv() => {
+                    if let ShutdownSignal::All = signal {
+                        info!("Received shutdown signal, stopping web UI.");
+                        break;
+                    }
+                }
+                accept_result = listener.accept() => {
+                    let stream = match accept_result {
+                        Ok((stream, _)) => stream,
+                        Err(e) => {
+                            debug!("Failed to accept a web UI connection: {:?}", e);
+                            continue;
+                        }
+                    };
+                    let registry_manager = registry_manager.clone();
+                    tokio::spawn(async move {
+                        let service = service_fn(move |req| {
+                            request_handler(registry_manager.clone(), req)
+                        });
+                        if let Err(e) = http1::Builder::new()
+                            .serve_connection(TokioIo::new(stream), service)
+                            .await
+                        {
+                            debug!("Error serving web UI connection: {:?}", e);
+                        }
+                    });
+                }
+            }
+        }
+    });
+    Ok(handle)
+}
+
+async fn request_handler(
+    registry_manager: RegistryManager,
+    request: Request<Incoming>,
+) -> Result<Response<Body>, Infallible> {
+    if request.method() != Method::GET {
+        return Ok(text(StatusCode::METHOD_NOT_ALLOWED, "text/plain", ""));
+    }
+    let response = match request.uri().path() {
+        "/" | "/index.html" => text(StatusCode::OK, "text/html; charset=utf-8", INDEX_HTML),
+        "/app.js" => text(StatusCode::OK, "text/javascript; charset=utf-8", APP_JS),
+        "/api/programs" => {
+            let names: Vec<_> = registry_manager
+                .builtin
+                .list()
+                .into_iter()
+                .filter(|prog| {
+                    matches!(
+                        prog.get_state(),
+                        ProgramState::Running | ProgramState::Degraded
+                    ) && !prog
+                        .service_map_snapshots(SnapshotQuery::At(u64::MAX))
+                        .is_empty()
+                })
+                .map(|prog| prog.get_name())
+                .collect();
+            text(StatusCode::OK, "application/json", json!(names).to_string())
+        }
+        "/api/watch" => {
+            let name = request.uri().query().and_then(|query| {
+                url::form_urlencoded::parse(query.as_bytes())
+                    .find(|(key, _)| key == "program")
+                    .map(|(_, value)| value.into_owned())
+            });
+            match name.filter(|name| registry_manager.builtin.get(name).is_some()) {
+                Some(name) => Response::builder()
+                    .header(header::CONTENT_TYPE, "text/event-stream")
+                    .header(header::CACHE_CONTROL, "no-cache")
+                    .body(watch(registry_manager, name))
+                    .unwrap(),
+                None => text(StatusCode::NOT_FOUND, "text/plain", "No such program"),
+            }
+        }
+        _ => text(StatusCode::NOT_FOUND, "text/plain", "Not found"),
+    };
+    Ok(response)
+}
+
+/// Streams the updates of the service map of `name` as server-sent events.
+fn watch(registry_manager: RegistryManager, name: String) -> Body {
+    let mut ticker = tokio::time::interval(WATCH_PERIOD);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    let state = (
+        registry_manager,
+        name,
+        ServiceMapWatch::new(0),
+        ticker,
+        Duration::ZERO,
+    );
+    let events = stream::unfold(state, |mut state| async move {
+        loop {
+            let (registry_manager, name, watch, ticker, idle) = &mut state;
+            ticker.tick().await;
+            let prog = registry_manager.builtin.get(name)?;
+            let update = prog
+                .service_map_snapshots(SnapshotQuery::At(u64::MAX))
+                .pop()
+                .and_then(|snapshot| watch.update(snapshot));
+            let event = match update {
+                Some(update) => format!("data: {}\n\n", update_json(&update)),
+                None if *idle >= KEEPALIVE_PERIOD => ": keepalive\n\n".to_string(),
+                None => {
+                    *idle += WATCH_PERIOD;
+                    continue;
+                }
+            };
+            *idle = Duration::ZERO;
+            return Some((Ok(Frame::data(Bytes::from(event))), state));
+        }
+    });
+    StreamBody::new(events).boxed_unsync()
+}
+
+fn update_json(update: &ServiceMapUpdate) -> Value {
+    json!({
+        "snapshot_id": update.snapshot_id,
+        "timestamp_ns": update.timestamp_ns,
+        "full": update.full,
+        "edges": update.edges.iter().map(edge_json).collect::<Vec<_>>(),
+        "removed": update.removed.iter().map(edge_json).collect::<Vec<_>>(),
+    })
+}
+
+fn edge_json(edge: &ServiceMapEdge) -> Value {
+    json!({
+        "client": edge.client_workload,
+        "server": edge.server_workload,
+        "port": edge.server_port,
+        "protocol": edge.protocol,
+        "bytes_sent": edge.bytes_sent,
+        "bytes_received": edge.bytes_received,
+        "active_connections": edge.active_connections,
+        "resets": edge.resets,
+        "connect_timeouts": edge.connect_timeouts,
+        "throughput_bps": edge.throughput_bps,
+    })
+}
+
+fn text(status: StatusCode, content_type: &str, body: impl Into<Bytes>) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, content_type)
+        .body(Full::new(body.into()).boxed_unsync())
+        .unwrap()
+}
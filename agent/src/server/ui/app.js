@@ -0,0 +1,206 @@
+// Draws the live service map of a program from the updates streamed by /api/watch.
+// Full updates replace the edges, the others add, change or remove some of them.
+"use strict";
+
+const SVG_NS = "http://www.w3.org/2000/svg";
+const programSelect = document.getElementById("program");
+const namespaceSelect = document.getElementById("namespace");
+const status = document.getElementById("status");
+const details = document.getElementById("details");
+const svg = document.getElementById("graph");
+
+// Edges by key, and the positions of the nodes, kept across updates for the layout
+// not to jump around.
+let edges = new Map();
+const positions = new Map();
+let selected = null;
+let source = null;
+
+const edgeKey = (e) => `${e.client}|${e.server}|${e.port}|${e.protocol}`;
+const namespaceOf = (node) => node.split("/")[0];
+
+async function loadPrograms() {
+  const programs = await (await fetch("api/programs")).json();
+  programSelect.replaceChildren(...programs.map((name) => new Option(name, name)));
+  if (programs.length === 0) {
+    status.textContent = "No service map program is running";
+    return;
+  }
+  watch(programs[0]);
+}
+
+function watch(program) {
+  if (source) {
+    source.close();
+  }
+  edges = new Map();
+  selected = null;
+  render();
+  source = new EventSource(`api/watch?program=${encodeURIComponent(program)}`);
+  source.onopen = () => (status.textContent = "Live");
+  source.onerror = () => (status.textContent = "Reconnecting…");
+  source.onmessage = (event) => apply(JSON.parse(event.data));
+}
+
+function apply(update) {
+  if (update.full) {
+    edges = new Map();
+  }
+  for (const edge of update.removed) {
+    edges.delete(edgeKey(edge));
+  }
+  for (const edge of update.edges) {
+    edges.set(edgeKey(edge), edge);
+  }
+  const time = new Date(update.timestamp_ns / 1e6).toLocaleTimeString();
+  status.textContent = `Live, snapshot ${update.snapshot_id} at ${time}`;
+  updateNamespaces();
+  render();
+}
+
+function updateNamespaces() {
+  const namespaces = new Set();
+  for (const edge of edges.values()) {
+    namespaces.add(namespaceOf(edge.client));
+    namespaces.add(namespaceOf(edge.server));
+  }
+  const current = namespaceSelect.value;
+  const options = [...namespaces].sort().map((ns) => new Option(ns, ns));
+  namespaceSelect.replaceChildren(new Option("All", ""), ...options);
+  namespaceSelect.value = namespaces.has(current) ? current : "";
+}
+
+// Edges with either end in the selected namespace.
+function visibleEdges() {
+  const namespace = namespaceSelect.value;
+  return [...edges.values()].filter(
+    (e) => !namespace || namespaceOf(e.client) === namespace || namespaceOf(e.server) === namespace
+  );
+}
+
+// A few rounds of a force layout: nodes push each other away and edges pull their
+// ends together.
+function layout(nodes, links) {
+  const width = svg.clientWidth || 800;
+  const height = svg.clientHeight || 600;
+  for (const node of nodes) {
+    if (!positions.has(node)) {
+      positions.set(node, { x: width * Math.random(), y: height * Math.random() });
+    }
+  }
+  for (let round = 0; round < 100; round++) {
+    const moves = new Map(nodes.map((n) => [n, { x: 0, y: 0 }]));
+    for (const a of nodes) {
+      for (const b of nodes) {
+        if (a === b) continue;
+        const pa = positions.get(a), pb = positions.get(b);
+        const dx = pa.x - pb.x, dy = pa.y - pb.y;
+        const d2 = Math.max(dx * dx + dy * dy, 1);
+        moves.get(a).x += (dx / d2) * 2000;
+        moves.get(a).y += (dy / d2) * 2000;
+      }
+    }
+    for (const [a, b] of links) {
+      const pa = positions.get(a), pb = positions.get(b);
+      const dx = (pb.x - pa.x) * 0.02, dy = (pb.y - pa.y) * 0.02;
+      moves.get(a).x += dx;
+      moves.get(a).y += dy;
+      moves.get(b).x -= dx;
+      moves.get(b).y -= dy;
+    }
+    for (const node of nodes) {
+      const p = positions.get(node), m = moves.get(node);
+      p.x = Math.min(width - 40, Math.max(40, p.x + Math.max(-10, Math.min(10, m.x))));
+      p.y = Math.min(height - 20, Math.max(20, p.y + Math.max(-10, Math.min(10, m.y))));
+    }
+  }
+}
+
+function render() {
+  const shown = visibleEdges();
+  const nodes = [...new Set(shown.flatMap((e) => [e.client, e.server]))];
+  layout(nodes, shown.map((e) => [e.client, e.server]));
+  const maxThroughput = Math.max(1, ...shown.map((e) => e.throughput_bps));
+
+  const edgeGroup = document.getElementById("edges");
+  edgeGroup.replaceChildren(
+    ...shown.map((edge) => {
+      const a = positions.get(edge.client), b = positions.get(edge.server);
+      const line = document.createElementNS(SVG_NS, "line");
+      line.setAttribute("x1", a.x);
+      line.setAttribute("y1", a.y);
+      line.setAttribute("x2", b.x);
+      line.setAttribute("y2", b.y);
+      line.setAttribute("marker-end", "url(#arrow)");
+      line.setAttribute("stroke-width", 1 + 5 * (edge.throughput_bps / maxThroughput));
+      line.classList.add("edge");
+      if (edge.resets > 0 || edge.connect_timeouts > 0) line.classList.add("failing");
+      if (edgeKey(edge) === selected) line.classList.add("selected");
+      line.addEventListener("click", () => {
+        selected = edgeKey(edge);
+        render();
+      });
+      return line;
+    })
+  );
+
+  const nodeGroup = document.getElementById("nodes");
+  nodeGroup.replaceChildren(
+    ...nodes.map((node) => {
+      const p = positions.get(node);
+      const g = document.createElementNS(SVG_NS, "g");
+      g.classList.add("node");
+      g.setAttribute("transform", `translate(${p.x},${p.y})`);
+      const circle = document.createElementNS(SVG_NS, "circle");
+      circle.setAttribute("r", 8);
+      const label = document.createElementNS(SVG_NS, "text");
+      label.setAttribute("x", 12);
+      label.setAttribute("y", 4);
+      label.textContent = node;
+      g.append(circle, label);
+      return g;
+    })
+  );
+  showDetails();
+}
+
+function showDetails() {
+  const edge = selected && edges.get(selected);
+  if (!edge) {
+    details.innerHTML = "<p>Click an edge to see its traffic.</p>";
+    return;
+  }
+  const rows = [
+    ["Client", edge.client],
+    ["Server", edge.server],
+    ["Port", edge.port],
+    ["Protocol", edge.protocol],
+    ["Throughput", `${formatBytes(edge.throughput_bps)}/s`],
+    ["Bytes sent", formatBytes(edge.bytes_sent)],
+    ["Bytes received", formatBytes(edge.bytes_received)],
+    ["Active connections", edge.active_connections],
+    ["Resets", edge.resets],
+    ["Connect timeouts", edge.connect_timeouts],
+  ];
+  const table = document.createElement("table");
+  for (const [name, value] of rows) {
+    const row = table.insertRow();
+    row.insertCell().textContent = name;
+    row.insertCell().textContent = value;
+  }
+  details.replaceChildren(table);
+}
+
+function formatBytes(bytes) {
+  const units = ["B", "KiB", "MiB", "GiB", "TiB"];
+  let i = 0;
+  while (bytes >= 1024 && i < units.length - 1) {
+    bytes /= 1024;
+    i++;
+  }
+  return `${bytes.toFixed(i === 0 ? 0 : 1)} ${units[i]}`;
+}
+
+programSelect.addEventListener("change", () => watch(programSelect.value));
+namespaceSelect.addEventListener("change", render);
+loadPrograms().catch((e) => (status.textContent = `Failed to list programs: ${e}`));
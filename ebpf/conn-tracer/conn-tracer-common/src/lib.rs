@@ -1,4 +1,9 @@
 #![no_std]
+
+use core::net::Ipv4Addr;
+
+pub use net_endian::{self as endian, NetEndian};
+
 pub const AF_INET: u16 = 2;
 pub const AF_INET6: u16 = 10;
 pub const MAX_CONNECTIONS: u32 = 100000;
@@ -18,25 +23,117 @@ pub const TCP_NEW_SYN_RECV: i32 = 12;
 pub const TCP_MAX_STATES: i32 = 13;
 
 pub const INET_SOCK_SKADDR_OFFSET: usize = 8;
+pub const INET_SOCK_OLDSTATE_OFFSET: usize = 16;
 pub const INET_SOCK_NEWSTATE_OFFSET: usize = 20;
 
+pub const TCP_RECEIVE_RESET_SKADDR_OFFSET: usize = 8;
+pub const TCP_SEND_RESET_SKADDR_OFFSET: usize = 16;
+
+// getsockopt option returning the destination of a connection before it was redirected
+// by iptables, as sidecar proxies ask for it.
+pub const SO_ORIGINAL_DST: i32 = 80;
+
 pub const CONNECTION_ROLE_UNKNOWN: u32 = 0;
 pub const CONNECTION_ROLE_CLIENT: u32 = 1;
 pub const CONNECTION_ROLE_SERVER: u32 = 2;
 
-#[derive(Copy, Clone, Debug)]
+pub const PROTOCOL_UNKNOWN: u32 = 0;
+pub const PROTOCOL_HTTP: u32 = 1;
+pub const PROTOCOL_GRPC: u32 = 2;
+pub const PROTOCOL_REDIS: u32 = 3;
+pub const PROTOCOL_TLS: u32 = 4;
+pub const PROTOCOL_QUIC: u32 = 5;
+
+// Number of payload bytes peeked at when inferring the protocol of a connection.
+pub const PROTOCOL_PEEK_SIZE: usize = 16;
+// Number of segments inspected before giving up on inferring the protocol.
+pub const PROTOCOL_INFERENCE_LIMIT: u32 = 3;
+
+// TLS record content types, and the types of the handshake messages they carry.
+pub const TLS_CHANGE_CIPHER_SPEC: u8 = 0x14;
+pub const TLS_HANDSHAKE: u8 = 0x16;
+pub const TLS_APPLICATION_DATA: u8 = 0x17;
+pub const TLS_CLIENT_HELLO: u8 = 0x01;
+pub const TLS_SERVER_HELLO: u8 = 0x02;
+pub const TLS_CERTIFICATE: u8 = 0x0b;
+// Number of payload bytes captured from the first TLS handshake segment of a connection,
+// enough for a ClientHello, or a ServerHello and the start of the certificate after it.
+pub const TLS_CAPTURE_SIZE: usize = 1024;
+pub const MAX_TLS_HANDSHAKES: u32 = 10240;
+// Number of bytes captured from the start of HTTP requests, enough for the method and
+// the path of most.
+pub const HTTP_REQUEST_LINE_SIZE: usize = 64;
+pub const MAX_HTTP_REQUESTS: u32 = 65536;
+// Number of bytes captured from the start of HTTP responses to find their
+// Content-Length and Content-Encoding in.
+pub const HTTP_RESPONSE_HEAD_SIZE: usize = 256;
+
+// Content encodings of HTTP responses. Responses without one are identity.
+pub const HTTP_ENCODING_IDENTITY: u32 = 0;
+pub const HTTP_ENCODING_GZIP: u32 = 1;
+pub const HTTP_ENCODING_DEFLATE: u32 = 2;
+pub const HTTP_ENCODING_BR: u32 = 3;
+pub const HTTP_ENCODING_ZSTD: u32 = 4;
+pub const HTTP_ENCODING_OTHER: u32 = 5;
+
+// Idle periods of client connections are counted in buckets doubling from 1ms: bucket i
+// holds the periods up to 1ms * 2^i, and the last one those past the others.
+pub const IDLE_FIRST_BUCKET_NS: u64 = 1_000_000;
+pub const IDLE_BUCKETS: u32 = 22;
+pub const MAX_IDLE_PERIODS: u32 = 65536;
+
+// UDP flows are traced when they start with the Initial packet of a QUIC version 1
+// client, which is sent in a datagram of at least 1200 bytes.
+pub const QUIC_VERSION_1: [u8; 4] = [0, 0, 0, 1];
+pub const QUIC_MIN_INITIAL_SIZE: usize = 1200;
+// Number of bytes captured from the first datagram of QUIC clients, enough for the
+// Initial packets of most, which fit the MTU.
+pub const QUIC_CAPTURE_SIZE: usize = 1500;
+pub const MAX_QUIC_FLOWS: u32 = 65536;
+
+// Identities the agent pushes down for the addresses of workloads, which connections are
+// stamped with, and counted per pair of in aggregation mode rather than one by one.
+// Addresses it didn't push have the unknown identity.
+pub const IDENTITY_UNKNOWN: u32 = 0;
+pub const MAX_IDENTITIES: u32 = 65536;
+pub const MAX_AGGREGATES: u32 = 16384;
+
+#[derive(Copy, Clone, Debug, Default)]
 #[repr(C)]
 pub struct SockInfo {
+    pub start_ns: u64,
     pub id: u32,
     pub pid: u32,
     pub is_active: u32,
     pub role: u32,
+    pub protocol: u32,
+    pub inference_count: u32,
+    pub resets: u32,
+    /// Destination the connection was made to before being redirected to this socket,
+    /// once its owner asked for it with `SO_ORIGINAL_DST`. Zero otherwise.
+    pub original_dest_addr: u32,
+    pub original_dest_port: u32,
+    /// Time from the SYN being sent to the connection being established, for clients.
+    /// Zero until then, and for servers.
+    pub handshake_ns: u64,
+    /// Set once the TLS handshake of the connection was traced, or found not to be
+    /// visible, so that later segments are not looked at.
+    pub tls_traced: u32,
+    /// Set once the first segment sent by a client was looked at for a ClientHello.
+    pub client_hello_traced: u32,
+    /// See [`ConnectionStats::requests`].
+    pub requests: u64,
+    /// Bytes the client had received when it started its last request.
+    pub received_at_request: u64,
+    /// Totals of the connection already added to its aggregate, in aggregation mode.
+    pub aggregated: AggregateStats,
 }
 
 #[cfg(feature = "user")]
 unsafe impl aya::Pod for SockInfo {}
 
-#[derive(Copy, Clone, Debug, Default)]
+/// Addresses and ports are in host byte order, see [`endian`].
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
 #[repr(C)]
 pub struct ConnectionKey {
     pub id: u32,
@@ -48,6 +145,16 @@ pub struct ConnectionKey {
     pub role: u32,
 }
 
+impl ConnectionKey {
+    pub fn src_ip(&self) -> Ipv4Addr {
+        Ipv4Addr::from(self.src_addr)
+    }
+
+    pub fn dest_ip(&self) -> Ipv4Addr {
+        Ipv4Addr::from(self.dest_addr)
+    }
+}
+
 #[cfg(feature = "user")]
 unsafe impl aya::Pod for ConnectionKey {}
 
@@ -57,7 +164,232 @@ pub struct ConnectionStats {
     pub bytes_sent: u64,
     pub bytes_received: u64,
     pub is_active: u64,
+    pub protocol: u64,
+    pub resets: u64,
+    pub connect_timeouts: u64,
+    pub duration_ns: u64,
+    /// See [`SockInfo::handshake_ns`].
+    pub handshake_ns: u64,
+    /// See [`SockInfo::original_dest_addr`]. In host byte order like the key.
+    pub original_dest_addr: u32,
+    pub original_dest_port: u32,
+    /// Requests made by the client end, each send following received data starting a
+    /// new one. Zero on the server end.
+    pub requests: u64,
+    /// Identities the agent pushed down for the addresses of the key, when last
+    /// recorded. [`IDENTITY_UNKNOWN`] for those it didn't.
+    pub src_identity: u32,
+    pub dest_identity: u32,
+}
+
+impl ConnectionStats {
+    /// Returns the destination of a redirected connection, before its redirection.
+    pub fn original_dest(&self) -> Option<(u32, u32)> {
+        (self.original_dest_addr != 0).then_some((self.original_dest_addr, self.original_dest_port))
+    }
 }
 
 #[cfg(feature = "user")]
 unsafe impl aya::Pod for ConnectionStats {}
+
+/// The TCP connections between two identities, on one end, in aggregation mode.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+#[repr(C)]
+pub struct AggregateKey {
+    pub client: u32,
+    pub server: u32,
+    pub server_port: u32,
+    pub protocol: u32,
+    /// End of the connections the totals were counted on, as in [`ConnectionKey`].
+    pub role: u32,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for AggregateKey {}
+
+/// Totals of the connections of an [`AggregateKey`], from the socket of their end like
+/// [`ConnectionStats`].
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct AggregateStats {
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub opened: u64,
+    pub closed: u64,
+    pub resets: u64,
+    pub connect_timeouts: u64,
+    pub requests: u64,
+    /// Connections that made at least one request.
+    pub requesting: u64,
+}
+
+impl AggregateStats {
+    /// Returns by how much each total grew since `before`.
+    pub fn since(&self, before: &AggregateStats) -> AggregateStats {
+        AggregateStats {
+            bytes_sent: self.bytes_sent.saturating_sub(before.bytes_sent),
+            bytes_received: self.bytes_received.saturating_sub(before.bytes_received),
+            opened: self.opened.saturating_sub(before.opened),
+            closed: self.closed.saturating_sub(before.closed),
+            resets: self.resets.saturating_sub(before.resets),
+            connect_timeouts: self
+                .connect_timeouts
+                .saturating_sub(before.connect_timeouts),
+            requests: self.requests.saturating_sub(before.requests),
+            requesting: self.requesting.saturating_sub(before.requesting),
+        }
+    }
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for AggregateStats {}
+
+/// The first TLS handshake segment received on a connection: a ClientHello on the
+/// server end, a ServerHello on the client end. Also the ClientHello sent by the client
+/// end, in its own map.
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct TlsHandshake {
+    pub hello_ns: u64,
+    /// When the server end received the first record after the ClientHello that
+    /// isn't a handshake one, i.e. the end of the handshake of the client. Zero until
+    /// then, and on the client end.
+    pub finished_ns: u64,
+    /// Bytes captured in `data`.
+    pub len: u32,
+    pub _padding: u32,
+    pub data: [u8; TLS_CAPTURE_SIZE],
+}
+
+impl Default for TlsHandshake {
+    fn default() -> Self {
+        Self {
+            hello_ns: 0,
+            finished_ns: 0,
+            len: 0,
+            _padding: 0,
+            data: [0; TLS_CAPTURE_SIZE],
+        }
+    }
+}
+
+impl TlsHandshake {
+    /// Time the handshake took, as seen by the server end.
+    pub fn duration_ns(&self) -> Option<u64> {
+        (self.finished_ns > self.hello_ns).then(|| self.finished_ns - self.hello_ns)
+    }
+
+    pub fn captured(&self) -> &[u8] {
+        &self.data[..(self.len as usize).min(TLS_CAPTURE_SIZE)]
+    }
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for TlsHandshake {}
+
+/// Requests received by an HTTP server end, told apart by the start of their request
+/// line. Bytes past the end of the segment are zero.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[repr(C)]
+pub struct HttpRequestKey {
+    pub conn: ConnectionKey,
+    pub line: [u8; HTTP_REQUEST_LINE_SIZE],
+}
+
+impl Default for HttpRequestKey {
+    fn default() -> Self {
+        Self {
+            conn: ConnectionKey::default(),
+            line: [0; HTTP_REQUEST_LINE_SIZE],
+        }
+    }
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for HttpRequestKey {}
+
+/// Responses sent by an HTTP server end, told apart by their content encoding.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+#[repr(C)]
+pub struct HttpResponseKey {
+    pub conn: ConnectionKey,
+    pub encoding: u32,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for HttpResponseKey {}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+pub struct HttpResponseStats {
+    pub responses: u64,
+    /// Sum of the Content-Length of the responses. Responses without one, e.g. chunked
+    /// ones, add nothing.
+    pub body_bytes: u64,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for HttpResponseStats {}
+
+/// Idle periods of a client connection between two of its requests, told apart by
+/// their bucket.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+#[repr(C)]
+pub struct IdleKey {
+    pub conn: ConnectionKey,
+    pub bucket: u32,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for IdleKey {}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+pub struct IdleStats {
+    pub periods: u64,
+    pub total_ns: u64,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for IdleStats {}
+
+/// The ends of a traced UDP flow: its local port, and the remote address and port.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+#[repr(C)]
+pub struct QuicFlowKey {
+    pub src_port: u32,
+    pub dest_addr: u32,
+    pub dest_port: u32,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for QuicFlowKey {}
+
+/// The first datagram sent by a QUIC client, which starts with its Initial packet.
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct QuicInitial {
+    /// Bytes captured in `data`.
+    pub len: u32,
+    pub _padding: u32,
+    pub data: [u8; QUIC_CAPTURE_SIZE],
+}
+
+impl Default for QuicInitial {
+    fn default() -> Self {
+        Self {
+            len: 0,
+            _padding: 0,
+            data: [0; QUIC_CAPTURE_SIZE],
+        }
+    }
+}
+
+impl QuicInitial {
+    pub fn captured(&self) -> &[u8] {
+        &self.data[..(self.len as usize).min(QUIC_CAPTURE_SIZE)]
+    }
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for QuicInitial {}
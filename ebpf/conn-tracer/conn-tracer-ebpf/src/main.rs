@@ -1,17 +1,37 @@
 #![no_std]
 #![no_main]
 
+use core::ptr::{addr_of, addr_of_mut};
+use core::sync::atomic::{AtomicU64, Ordering};
+
 use aya_ebpf::{
-    helpers::{bpf_get_current_pid_tgid, bpf_ktime_get_ns, bpf_probe_read_kernel},
-    macros::{kprobe, map, tracepoint},
-    programs::{ProbeContext, TracePointContext},
+    helpers::{
+        bpf_get_current_pid_tgid, bpf_ktime_get_ns, bpf_probe_read_kernel,
+        bpf_probe_read_kernel_buf, bpf_probe_read_user, bpf_probe_read_user_buf,
+    },
+    macros::{fentry, kprobe, kretprobe, map, tracepoint},
+    programs::{FEntryContext, ProbeContext, RetProbeContext, TracePointContext},
 };
 use conn_tracer_common::{
-    ConnectionKey, ConnectionStats, SockInfo, AF_INET, AF_INET6, CONNECTION_ROLE_CLIENT,
-    CONNECTION_ROLE_SERVER, CONNECTION_ROLE_UNKNOWN, INET_SOCK_NEWSTATE_OFFSET,
-    INET_SOCK_SKADDR_OFFSET, MAX_CONNECTIONS, TCP_CLOSE, TCP_SYN_RECV, TCP_SYN_SENT,
+    AggregateKey, AggregateStats, ConnectionKey, ConnectionStats, HttpRequestKey, HttpResponseKey,
+    HttpResponseStats, IdleKey, IdleStats, NetEndian, QuicFlowKey, QuicInitial, SockInfo,
+    TlsHandshake, AF_INET, AF_INET6, CONNECTION_ROLE_CLIENT, CONNECTION_ROLE_SERVER,
+    CONNECTION_ROLE_UNKNOWN, HTTP_ENCODING_BR, HTTP_ENCODING_DEFLATE, HTTP_ENCODING_GZIP,
+    HTTP_ENCODING_IDENTITY, HTTP_ENCODING_OTHER, HTTP_ENCODING_ZSTD, HTTP_REQUEST_LINE_SIZE,
+    HTTP_RESPONSE_HEAD_SIZE, IDENTITY_UNKNOWN, IDLE_BUCKETS, IDLE_FIRST_BUCKET_NS,
+    INET_SOCK_NEWSTATE_OFFSET, INET_SOCK_OLDSTATE_OFFSET, INET_SOCK_SKADDR_OFFSET, MAX_AGGREGATES,
+    MAX_CONNECTIONS, MAX_HTTP_REQUESTS, MAX_IDENTITIES, MAX_IDLE_PERIODS, MAX_QUIC_FLOWS,
+    MAX_TLS_HANDSHAKES, PROTOCOL_GRPC, PROTOCOL_HTTP, PROTOCOL_INFERENCE_LIMIT, PROTOCOL_PEEK_SIZE,
+    PROTOCOL_QUIC, PROTOCOL_REDIS, PROTOCOL_TLS, PROTOCOL_UNKNOWN, QUIC_CAPTURE_SIZE,
+    QUIC_MIN_INITIAL_SIZE, QUIC_VERSION_1, SO_ORIGINAL_DST, TCP_CLOSE, TCP_ESTABLISHED,
+    TCP_RECEIVE_RESET_SKADDR_OFFSET, TCP_SEND_RESET_SKADDR_OFFSET, TCP_SYN_RECV, TCP_SYN_SENT,
+    TLS_APPLICATION_DATA, TLS_CAPTURE_SIZE, TLS_CHANGE_CIPHER_SPEC, TLS_CLIENT_HELLO,
+    TLS_HANDSHAKE, TLS_SERVER_HELLO,
+};
+use vmlinux::{
+    iov_iter, iovec, msghdr, sk_buff, sk_buff__bindgen_ty_5__bindgen_ty_1, sock, sock_common,
+    tcp_sock,
 };
-use vmlinux::{sock, sock_common, tcp_sock};
 
 #[allow(non_upper_case_globals)]
 #[allow(non_snake_case)]
@@ -19,6 +39,12 @@ use vmlinux::{sock, sock_common, tcp_sock};
 #[allow(dead_code)]
 pub mod vmlinux;
 
+/// Types of the iov_iter of sent data, from `enum iter_type`.
+const ITER_UBUF: u8 = 0;
+const ITER_IOVEC: u8 = 1;
+/// Flag of map updates only creating new entries.
+const BPF_NOEXIST: u64 = 1;
+
 #[map(name = "SOCKETS")]
 static mut SOCKETS: aya_ebpf::maps::LruHashMap<*const sock, SockInfo> =
     aya_ebpf::maps::LruHashMap::<*const sock, SockInfo>::pinned(MAX_CONNECTIONS, 0);
@@ -27,6 +53,94 @@ static mut SOCKETS: aya_ebpf::maps::LruHashMap<*const sock, SockInfo> =
 static mut CONNECTIONS: aya_ebpf::maps::LruHashMap<ConnectionKey, ConnectionStats> =
     aya_ebpf::maps::LruHashMap::<ConnectionKey, ConnectionStats>::pinned(MAX_CONNECTIONS, 0);
 
+/// Identity the agent gave the workload of each address, in host byte order, which
+/// connections are stamped with, and aggregated by in aggregation mode.
+#[map(name = "IDENTITIES")]
+static mut IDENTITIES: aya_ebpf::maps::HashMap<u32, u32> =
+    aya_ebpf::maps::HashMap::<u32, u32>::pinned(MAX_IDENTITIES, 0);
+
+/// TCP connections counted per pair of identities, in aggregation mode.
+#[map(name = "AGGREGATES")]
+static mut AGGREGATES: aya_ebpf::maps::LruHashMap<AggregateKey, AggregateStats> =
+    aya_ebpf::maps::LruHashMap::<AggregateKey, AggregateStats>::pinned(MAX_AGGREGATES, 0);
+
+/// Set by the agent for TCP connections to be counted in the aggregates rather than in
+/// the connections map, which then only holds QUIC flows.
+#[map(name = "AGGREGATION")]
+static mut AGGREGATION: aya_ebpf::maps::Array<u32> = aya_ebpf::maps::Array::<u32>::pinned(1, 0);
+
+/// First TLS handshake segment of each connection, until the agent took it.
+#[map(name = "TLS_HANDSHAKES")]
+static mut TLS_HANDSHAKES: aya_ebpf::maps::LruHashMap<ConnectionKey, TlsHandshake> =
+    aya_ebpf::maps::LruHashMap::<ConnectionKey, TlsHandshake>::pinned(MAX_TLS_HANDSHAKES, 0);
+
+/// ClientHello sent by each client, until the agent took it. It names the server the
+/// client meant to reach.
+#[map(name = "TLS_CLIENT_HELLOS")]
+static mut TLS_CLIENT_HELLOS: aya_ebpf::maps::LruHashMap<ConnectionKey, TlsHandshake> =
+    aya_ebpf::maps::LruHashMap::<ConnectionKey, TlsHandshake>::pinned(MAX_TLS_HANDSHAKES, 0);
+
+/// Requests received by each HTTP server end, by the start of their request line.
+#[map(name = "HTTP_REQUESTS")]
+static mut HTTP_REQUESTS: aya_ebpf::maps::LruHashMap<HttpRequestKey, u64> =
+    aya_ebpf::maps::LruHashMap::<HttpRequestKey, u64>::pinned(MAX_HTTP_REQUESTS, 0);
+
+/// Responses sent by each HTTP server end, by their content encoding.
+#[map(name = "HTTP_RESPONSES")]
+static mut HTTP_RESPONSES: aya_ebpf::maps::LruHashMap<HttpResponseKey, HttpResponseStats> =
+    aya_ebpf::maps::LruHashMap::<HttpResponseKey, HttpResponseStats>::pinned(MAX_HTTP_REQUESTS, 0);
+
+/// Idle periods of each client connection between its requests, by bucket.
+#[map(name = "IDLE_PERIODS")]
+static mut IDLE_PERIODS: aya_ebpf::maps::LruHashMap<IdleKey, IdleStats> =
+    aya_ebpf::maps::LruHashMap::<IdleKey, IdleStats>::pinned(MAX_IDLE_PERIODS, 0);
+
+/// Traced UDP flows, by their ends, with the key their traffic is counted under.
+#[map(name = "QUIC_FLOWS")]
+static mut QUIC_FLOWS: aya_ebpf::maps::LruHashMap<QuicFlowKey, ConnectionKey> =
+    aya_ebpf::maps::LruHashMap::<QuicFlowKey, ConnectionKey>::pinned(MAX_QUIC_FLOWS, 0);
+
+/// First datagram sent by each QUIC client, until the agent took it. Its Initial packet
+/// carries the ClientHello, which names the server the client meant to reach.
+#[map(name = "QUIC_INITIALS")]
+static mut QUIC_INITIALS: aya_ebpf::maps::LruHashMap<ConnectionKey, QuicInitial> =
+    aya_ebpf::maps::LruHashMap::<ConnectionKey, QuicInitial>::pinned(MAX_TLS_HANDSHAKES, 0);
+
+/// Room to read the head of an HTTP response in, as it doesn't fit on the stack.
+#[map(name = "HTTP_SCRATCH")]
+static mut HTTP_SCRATCH: aya_ebpf::maps::PerCpuArray<[u8; HTTP_RESPONSE_HEAD_SIZE]> =
+    aya_ebpf::maps::PerCpuArray::<[u8; HTTP_RESPONSE_HEAD_SIZE]>::with_max_entries(1, 0);
+
+/// Room to build a TlsHandshake in, as it doesn't fit on the stack.
+#[map(name = "TLS_SCRATCH")]
+static mut TLS_SCRATCH: aya_ebpf::maps::PerCpuArray<TlsHandshake> =
+    aya_ebpf::maps::PerCpuArray::<TlsHandshake>::with_max_entries(1, 0);
+
+/// Room to build a QuicInitial in, as it doesn't fit on the stack.
+#[map(name = "QUIC_SCRATCH")]
+static mut QUIC_SCRATCH: aya_ebpf::maps::PerCpuArray<QuicInitial> =
+    aya_ebpf::maps::PerCpuArray::<QuicInitial>::with_max_entries(1, 0);
+
+/// `SO_ORIGINAL_DST` lookups in flight, by the thread making them.
+#[map(name = "ORIGINAL_DST_LOOKUPS")]
+static mut ORIGINAL_DST_LOOKUPS: aya_ebpf::maps::HashMap<u64, OriginalDstLookup> =
+    aya_ebpf::maps::HashMap::<u64, OriginalDstLookup>::with_max_entries(1024, 0);
+
+#[derive(Copy, Clone)]
+#[repr(C)]
+struct OriginalDstLookup {
+    sk: *const sock,
+    optval: *const SockaddrIn,
+}
+
+#[derive(Copy, Clone)]
+#[repr(C)]
+struct SockaddrIn {
+    sin_family: u16,
+    sin_port: u16,
+    sin_addr: u32,
+}
+
 #[kprobe]
 pub fn sock_conn_tracer(ctx: ProbeContext) -> u32 {
     match try_sock_conn_tracer(ctx) {
@@ -38,9 +152,66 @@ pub fn sock_conn_tracer(ctx: ProbeContext) -> u32 {
     }
 }
 
+// Same as sock_conn_tracer, attached instead of it on kernels with BTF.
+#[fentry(function = "tcp_data_queue")]
+pub fn sock_conn_tracer_fentry(ctx: FEntryContext) -> u32 {
+    let sk: *const sock = unsafe { ctx.arg(0) };
+    let skb: *const sk_buff = unsafe { ctx.arg(1) };
+    match trace_data_queue(sk, skb) {
+        Ok(ret) => ret,
+        Err(ret) => match ret.try_into() {
+            Ok(rt) => rt,
+            Err(_) => 1,
+        },
+    }
+}
+
+// tcp_data_queue only runs when data is received, so the bytes sent by a connection
+// that mostly sends, such as an upload, are refreshed when it sends instead.
+#[kprobe]
+pub fn sock_send_tracer(ctx: ProbeContext) -> u32 {
+    // first argument to tcp_sendmsg is a struct sock*
+    let sk: *const sock = match ctx.arg(0) {
+        Some(sk) => sk,
+        None => return 1,
+    };
+    // second argument to tcp_sendmsg is the struct msghdr* of the data sent
+    let msg: *const msghdr = match ctx.arg(1) {
+        Some(msg) => msg,
+        None => return 1,
+    };
+    match trace_send(sk, msg) {
+        Ok(ret) => ret,
+        Err(ret) => match ret.try_into() {
+            Ok(rt) => rt,
+            Err(_) => 1,
+        },
+    }
+}
+
+// Same as sock_send_tracer, attached instead of it on kernels with BTF.
+#[fentry(function = "tcp_sendmsg")]
+pub fn sock_send_tracer_fentry(ctx: FEntryContext) -> u32 {
+    let sk: *const sock = unsafe { ctx.arg(0) };
+    let msg: *const msghdr = unsafe { ctx.arg(1) };
+    match trace_send(sk, msg) {
+        Ok(ret) => ret,
+        Err(ret) => match ret.try_into() {
+            Ok(rt) => rt,
+            Err(_) => 1,
+        },
+    }
+}
+
 fn try_sock_conn_tracer(ctx: ProbeContext) -> Result<u32, i64> {
     // first argument to tcp_data_queue is a struct sock*
     let sk: *const sock = ctx.arg(0).ok_or(1i64)?;
+    // second argument to tcp_data_queue is the struct sk_buff* being queued
+    let skb: *const sk_buff = ctx.arg(1).ok_or(1i64)?;
+    trace_data_queue(sk, skb)
+}
+
+fn trace_data_queue(sk: *const sock, skb: *const sk_buff) -> Result<u32, i64> {
     let mut conn_key = ConnectionKey::default();
     let mut conn_stats = ConnectionStats::default();
 
@@ -52,23 +223,62 @@ fn try_sock_conn_tracer(ctx: ProbeContext) -> Result<u32, i64> {
 
     match unsafe { SOCKETS.get(&sk) } {
         Some(&sock_info) => {
+            let mut sock_info = sock_info;
             conn_key.id = sock_info.id;
             conn_key.pid = sock_info.pid;
             conn_key.role = sock_info.role;
             if sock_info.is_active == 0u32 {
                 return Err(1i64);
             }
-            conn_stats.is_active = sock_info.is_active as u64;
-            unsafe {
-                CONNECTIONS.insert(&conn_key, &conn_stats, 0_u64)?;
+            if sock_info.protocol == PROTOCOL_UNKNOWN
+                && sock_info.inference_count < PROTOCOL_INFERENCE_LIMIT
+            {
+                sock_info.inference_count += 1;
+                sock_info.protocol = infer_protocol(skb);
+                unsafe {
+                    SOCKETS.insert(&sk, &sock_info, 0_u64)?;
+                }
             }
+            if sock_info.protocol == PROTOCOL_HTTP && sock_info.role == CONNECTION_ROLE_SERVER {
+                trace_http_request(&conn_key, skb)?;
+            }
+            if sock_info.protocol == PROTOCOL_TLS
+                && sock_info.tls_traced == 0
+                && trace_tls(&conn_key, skb)?
+            {
+                sock_info.tls_traced = 1;
+                unsafe {
+                    SOCKETS.insert(&sk, &sock_info, 0_u64)?;
+                }
+            }
+            conn_stats.is_active = sock_info.is_active as u64;
+            conn_stats.protocol = sock_info.protocol as u64;
+            conn_stats.resets = sock_info.resets as u64;
+            conn_stats.original_dest_addr = sock_info.original_dest_addr;
+            conn_stats.original_dest_port = sock_info.original_dest_port;
+            conn_stats.handshake_ns = sock_info.handshake_ns;
+            conn_stats.requests = sock_info.requests;
+            record_connection(sk, &conn_key, &conn_stats, &mut sock_info)?;
         }
         None => {
-            let sock_info = SockInfo {
+            let mut sock_info = SockInfo {
+                start_ns: unsafe { bpf_ktime_get_ns() },
                 id: get_unique_id(),
                 pid: 0,
                 is_active: 1,
                 role: get_sock_role(sk),
+                protocol: infer_protocol(skb),
+                inference_count: 1,
+                resets: 0,
+                original_dest_addr: 0,
+                original_dest_port: 0,
+                handshake_ns: 0,
+                // The start of a connection seen late may well be missing.
+                tls_traced: 1,
+                client_hello_traced: 1,
+                requests: 0,
+                received_at_request: 0,
+                aggregated: AggregateStats::default(),
             };
 
             unsafe {
@@ -79,13 +289,403 @@ fn try_sock_conn_tracer(ctx: ProbeContext) -> Result<u32, i64> {
             conn_key.pid = sock_info.pid;
             conn_key.role = sock_info.role;
             conn_stats.is_active = 1;
+            conn_stats.protocol = sock_info.protocol as u64;
+            record_connection(sk, &conn_key, &conn_stats, &mut sock_info)?;
+        }
+    }
+
+    Ok(0)
+}
+
+/// Refreshes the byte counters of a traced connection, counts the requests of clients
+/// and the idle periods between them, captures the ClientHello a client sends first,
+/// and counts the responses of HTTP servers. Sockets that aren't traced yet are left to
+/// tcp_data_queue and the state tracer, which know their role.
+fn trace_send(sk: *const sock, msg: *const msghdr) -> Result<u32, i64> {
+    let mut sock_info = match unsafe { SOCKETS.get(&sk) } {
+        Some(&sock_info) if sock_info.is_active != 0 => sock_info,
+        _ => return Ok(0),
+    };
+
+    let mut conn_key = ConnectionKey::default();
+    let mut conn_stats = ConnectionStats::default();
+    parse_sock_data(sk, &mut conn_key, &mut conn_stats)?;
+    if conn_key.dest_addr == 0 && conn_key.dest_port == 0 {
+        return Ok(0);
+    }
+
+    let client = sock_info.role == CONNECTION_ROLE_CLIENT;
+    // A client sending again after it received data, e.g. a response, makes a new
+    // request: many of them per connection tell a working connection pool.
+    let new_request = client
+        && (sock_info.requests == 0 || conn_stats.bytes_received > sock_info.received_at_request);
+    let after_idle = new_request && sock_info.requests > 0;
+    if new_request {
+        sock_info.requests += 1;
+        sock_info.received_at_request = conn_stats.bytes_received;
+    }
+    let trace_hello = client && sock_info.client_hello_traced == 0;
+    if trace_hello {
+        sock_info.client_hello_traced = 1;
+    }
+    if new_request || trace_hello {
+        unsafe {
+            SOCKETS.insert(&sk, &sock_info, 0_u64)?;
+        }
+    }
+
+    conn_key.id = sock_info.id;
+    conn_key.pid = sock_info.pid;
+    conn_key.role = sock_info.role;
+    conn_stats.is_active = sock_info.is_active as u64;
+    conn_stats.protocol = sock_info.protocol as u64;
+    conn_stats.resets = sock_info.resets as u64;
+    conn_stats.original_dest_addr = sock_info.original_dest_addr;
+    conn_stats.original_dest_port = sock_info.original_dest_port;
+    conn_stats.handshake_ns = sock_info.handshake_ns;
+    conn_stats.requests = sock_info.requests;
+    record_connection(sk, &conn_key, &conn_stats, &mut sock_info)?;
+
+    if after_idle {
+        trace_idle_period(&conn_key, sk)?;
+    }
+    if trace_hello {
+        trace_client_hello(&conn_key, msg)?;
+    }
+    if sock_info.role == CONNECTION_ROLE_SERVER && sock_info.protocol == PROTOCOL_HTTP {
+        trace_http_response(&conn_key, msg)?;
+    }
+
+    Ok(0)
+}
+
+// UDP has no connection for the kernel to keep state of, so QUIC flows are followed
+// through the datagrams sent by udp_sendmsg, and those consumed by skb_consume_udp.
+#[kprobe]
+pub fn udp_send_tracer(ctx: ProbeContext) -> u32 {
+    // udp_sendmsg(struct sock *sk, struct msghdr *msg, size_t len)
+    let (Some(sk), Some(msg), Some(len)) = (ctx.arg(0), ctx.arg(1), ctx.arg(2)) else {
+        return 1;
+    };
+    match trace_udp_send(sk, msg, len) {
+        Ok(ret) => ret,
+        Err(ret) => match ret.try_into() {
+            Ok(rt) => rt,
+            Err(_) => 1,
+        },
+    }
+}
+
+// Same as udp_send_tracer, attached instead of it on kernels with BTF.
+#[fentry(function = "udp_sendmsg")]
+pub fn udp_send_tracer_fentry(ctx: FEntryContext) -> u32 {
+    let sk: *const sock = unsafe { ctx.arg(0) };
+    let msg: *const msghdr = unsafe { ctx.arg(1) };
+    let len: usize = unsafe { ctx.arg(2) };
+    match trace_udp_send(sk, msg, len) {
+        Ok(ret) => ret,
+        Err(ret) => match ret.try_into() {
+            Ok(rt) => rt,
+            Err(_) => 1,
+        },
+    }
+}
+
+#[kprobe]
+pub fn udp_receive_tracer(ctx: ProbeContext) -> u32 {
+    // skb_consume_udp(struct sock *sk, struct sk_buff *skb, int len)
+    let (Some(sk), Some(skb)) = (ctx.arg(0), ctx.arg(1)) else {
+        return 1;
+    };
+    match trace_udp_receive(sk, skb) {
+        Ok(ret) => ret,
+        Err(ret) => match ret.try_into() {
+            Ok(rt) => rt,
+            Err(_) => 1,
+        },
+    }
+}
+
+// Same as udp_receive_tracer, attached instead of it on kernels with BTF.
+#[fentry(function = "skb_consume_udp")]
+pub fn udp_receive_tracer_fentry(ctx: FEntryContext) -> u32 {
+    let sk: *const sock = unsafe { ctx.arg(0) };
+    let skb: *const sk_buff = unsafe { ctx.arg(1) };
+    match trace_udp_receive(sk, skb) {
+        Ok(ret) => ret,
+        Err(ret) => match ret.try_into() {
+            Ok(rt) => rt,
+            Err(_) => 1,
+        },
+    }
+}
+
+/// Counts the bytes of a datagram sent on a traced UDP flow. A flow starts with the
+/// Initial packet of a QUIC client, captured for the agent to read the server name
+/// from; other UDP traffic, e.g. DNS, is left alone.
+fn trace_udp_send(sk: *const sock, msg: *const msghdr, len: usize) -> Result<u32, i64> {
+    let sk_common = unsafe { bpf_probe_read_kernel(&(*sk).__sk_common as *const sock_common)? };
+    if sk_common.skc_family != AF_INET {
+        return Ok(0);
+    }
+    let addrs = unsafe { sk_common.__bindgen_anon_1.__bindgen_anon_1 };
+    let ports = unsafe { sk_common.__bindgen_anon_3.__bindgen_anon_1 };
+    let mut flow = QuicFlowKey {
+        src_port: ports.skc_num as u32,
+        dest_addr: NetEndian::from_raw(addrs.skc_daddr).to_host(),
+        dest_port: NetEndian::from_raw(ports.skc_dport).to_host() as u32,
+    };
+    // Sockets that aren't connected, e.g. those of servers, are given the destination
+    // of each datagram, which the kernel copied in.
+    if flow.dest_port == 0 {
+        let name = unsafe { bpf_probe_read_kernel(&(*msg).msg_name)? } as *const SockaddrIn;
+        if name.is_null() {
+            return Ok(0);
+        }
+        let addr = unsafe { bpf_probe_read_kernel(name)? };
+        if addr.sin_family != AF_INET {
+            return Ok(0);
+        }
+        flow.dest_addr = NetEndian::from_raw(addr.sin_addr).to_host();
+        flow.dest_port = NetEndian::from_raw(addr.sin_port).to_host() as u32;
+    }
+
+    let (data, data_len) = sent_payload(msg)?;
+    let initial = data_len >= QUIC_MIN_INITIAL_SIZE
+        && is_quic_initial(&unsafe { bpf_probe_read_user(data as *const [u8; 5])? });
+    let conn_key = match quic_flow(&flow, initial) {
+        Some(conn_key) => conn_key,
+        None if initial => {
+            let conn_key = ConnectionKey {
+                id: get_unique_id(),
+                pid: bpf_get_current_pid_tgid() as u32,
+                src_addr: NetEndian::from_raw(addrs.skc_rcv_saddr).to_host(),
+                src_port: flow.src_port,
+                dest_addr: flow.dest_addr,
+                dest_port: flow.dest_port,
+                role: CONNECTION_ROLE_CLIENT,
+            };
+            unsafe {
+                QUIC_FLOWS.insert(&flow, &conn_key, 0_u64)?;
+            }
+            trace_quic_initial(&conn_key, data, data_len)?;
+            conn_key
+        }
+        None => return Ok(0),
+    };
+    count_quic_bytes(&conn_key, len as u64, 0)?;
+    Ok(0)
+}
+
+/// Counts the bytes of a datagram received on a traced UDP flow. A flow starts with
+/// the Initial packet of a QUIC client, whose receiver is the server end.
+fn trace_udp_receive(sk: *const sock, skb: *const sk_buff) -> Result<u32, i64> {
+    let sk_common = unsafe { bpf_probe_read_kernel(&(*sk).__sk_common as *const sock_common)? };
+    if sk_common.skc_family != AF_INET {
+        return Ok(0);
+    }
+    // The UDP header was pulled, so the ends of the datagram are read from the headers
+    // before skb->data: the addresses 12 bytes into the IP header, source first, and
+    // the source port at the start of the UDP header.
+    let (addrs, src_port) = unsafe {
+        let headers =
+            addr_of!((*skb).__bindgen_anon_5) as *const sk_buff__bindgen_ty_5__bindgen_ty_1;
+        let head = bpf_probe_read_kernel(&(*skb).head)?;
+        let network_header = bpf_probe_read_kernel(&(*headers).network_header)?;
+        let transport_header = bpf_probe_read_kernel(&(*headers).transport_header)?;
+        (
+            bpf_probe_read_kernel(head.add(network_header as usize + 12) as *const [u32; 2])?,
+            bpf_probe_read_kernel(head.add(transport_header as usize) as *const u16)?,
+        )
+    };
+    let flow = QuicFlowKey {
+        src_port: unsafe { sk_common.__bindgen_anon_3.__bindgen_anon_1.skc_num } as u32,
+        dest_addr: NetEndian::from_raw(addrs[0]).to_host(),
+        dest_port: NetEndian::from_raw(src_port).to_host() as u32,
+    };
+    let local_addr = NetEndian::from_raw(addrs[1]).to_host();
 
+    let (data, len) = unsafe {
+        (
+            bpf_probe_read_kernel(&(*skb).data)?,
+            bpf_probe_read_kernel(&(*skb).len)? as usize,
+        )
+    };
+    let initial = len >= QUIC_MIN_INITIAL_SIZE
+        && is_quic_initial(&unsafe { bpf_probe_read_kernel(data as *const [u8; 5])? });
+    let conn_key = match quic_flow(&flow, initial) {
+        // Clients whose socket isn't bound to an address learn theirs from the replies.
+        Some(conn_key) if conn_key.src_addr == 0 => rebind_quic_flow(&flow, conn_key, local_addr)?,
+        Some(conn_key) => conn_key,
+        None if initial => {
+            let conn_key = ConnectionKey {
+                id: get_unique_id(),
+                pid: bpf_get_current_pid_tgid() as u32,
+                src_addr: local_addr,
+                src_port: flow.src_port,
+                dest_addr: flow.dest_addr,
+                dest_port: flow.dest_port,
+                role: CONNECTION_ROLE_SERVER,
+            };
             unsafe {
-                CONNECTIONS.insert(&conn_key, &conn_stats, 0_u64)?;
+                QUIC_FLOWS.insert(&flow, &conn_key, 0_u64)?;
             }
+            conn_key
+        }
+        None => return Ok(0),
+    };
+    count_quic_bytes(&conn_key, 0, len as u64)?;
+    Ok(0)
+}
+
+/// Whether a datagram starts with the Initial packet of QUIC version 1: a long header
+/// with the fixed bit set, of the Initial type. The low bits of the first byte are
+/// protected, unlike these.
+fn is_quic_initial(header: &[u8; 5]) -> bool {
+    header[0] & 0xf0 == 0xc0 && header[1..5] == QUIC_VERSION_1
+}
+
+/// Returns the key of a traced UDP flow, unless the agent ended it after it went idle
+/// and an Initial packet starts a new QUIC connection over the same ends.
+fn quic_flow(flow: &QuicFlowKey, initial: bool) -> Option<ConnectionKey> {
+    let conn_key = *unsafe { QUIC_FLOWS.get(flow) }?;
+    if initial && unsafe { CONNECTIONS.get(&conn_key) }.is_none() {
+        return None;
+    }
+    Some(conn_key)
+}
+
+/// Moves what was traced of a client flow before its local address was known to the
+/// key with that address.
+fn rebind_quic_flow(
+    flow: &QuicFlowKey,
+    mut conn_key: ConnectionKey,
+    local_addr: u32,
+) -> Result<ConnectionKey, i64> {
+    let unbound = conn_key;
+    conn_key.src_addr = local_addr;
+    unsafe {
+        QUIC_FLOWS.insert(flow, &conn_key, 0_u64)?;
+        if let Some(&stats) = CONNECTIONS.get(&unbound) {
+            CONNECTIONS.remove(&unbound)?;
+            CONNECTIONS.insert(&conn_key, &stats, 0_u64)?;
+        }
+        if let Some(initial) = QUIC_INITIALS.get_ptr(&unbound) {
+            QUIC_INITIALS.insert(&conn_key, &*initial, 0_u64)?;
+            QUIC_INITIALS.remove(&unbound)?;
         }
     }
+    Ok(conn_key)
+}
+
+/// Adds the bytes of a datagram to the totals of its flow. Unlike TCP sockets, UDP ones
+/// don't count what they carry.
+fn count_quic_bytes(conn_key: &ConnectionKey, sent: u64, received: u64) -> Result<(), i64> {
+    unsafe {
+        match CONNECTIONS.get_ptr_mut(conn_key) {
+            Some(stats) => {
+                AtomicU64::from_ptr(addr_of_mut!((*stats).bytes_sent))
+                    .fetch_add(sent, Ordering::Relaxed);
+                AtomicU64::from_ptr(addr_of_mut!((*stats).bytes_received))
+                    .fetch_add(received, Ordering::Relaxed);
+            }
+            None => {
+                let stats = ConnectionStats {
+                    bytes_sent: sent,
+                    bytes_received: received,
+                    is_active: 1,
+                    protocol: PROTOCOL_QUIC as u64,
+                    src_identity: identity(conn_key.src_addr),
+                    dest_identity: identity(conn_key.dest_addr),
+                    ..Default::default()
+                };
+                CONNECTIONS.insert(conn_key, &stats, 0_u64)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Captures the first datagram sent by a QUIC client for the agent to decrypt its
+/// Initial packet, whose keys derive from the connection ID it carries.
+fn trace_quic_initial(conn_key: &ConnectionKey, data: *const u8, len: usize) -> Result<(), i64> {
+    let initial = unsafe { QUIC_SCRATCH.get_ptr_mut(0).ok_or(1i64)? };
+    let captured = len.min(QUIC_CAPTURE_SIZE);
+    unsafe {
+        (*initial).len = captured as u32;
+        bpf_probe_read_user_buf(data, &mut (*initial).data[..captured])?;
+        QUIC_INITIALS.insert(conn_key, &*initial, 0_u64)?;
+    }
+    Ok(())
+}
+
+// Sidecar proxies ask for the destination a connection redirected to them by iptables
+// was made to, with getsockopt(SO_ORIGINAL_DST), which ends up in nf_getsockopt.
+#[kprobe]
+pub fn original_dst_lookup(ctx: ProbeContext) -> u32 {
+    match try_original_dst_lookup(ctx) {
+        Ok(ret) => ret,
+        Err(ret) => match ret.try_into() {
+            Ok(rt) => rt,
+            Err(_) => 1,
+        },
+    }
+}
+
+#[kretprobe]
+pub fn original_dst_lookup_ret(ctx: RetProbeContext) -> u32 {
+    match try_original_dst_lookup_ret(ctx) {
+        Ok(ret) => ret,
+        Err(ret) => match ret.try_into() {
+            Ok(rt) => rt,
+            Err(_) => 1,
+        },
+    }
+}
+
+fn try_original_dst_lookup(ctx: ProbeContext) -> Result<u32, i64> {
+    // nf_getsockopt(struct sock *sk, u8 pf, int val, char __user *opt, int *len)
+    let optname: i32 = ctx.arg(2).ok_or(1i64)?;
+    if optname != SO_ORIGINAL_DST {
+        return Ok(0);
+    }
+    let lookup = OriginalDstLookup {
+        sk: ctx.arg(0).ok_or(1i64)?,
+        optval: ctx.arg(3).ok_or(1i64)?,
+    };
+    unsafe {
+        ORIGINAL_DST_LOOKUPS.insert(&bpf_get_current_pid_tgid(), &lookup, 0_u64)?;
+    }
+    Ok(0)
+}
+
+/// Records the original destination on the socket once the lookup succeeded, from the
+/// sockaddr_in the kernel copied to the caller.
+fn try_original_dst_lookup_ret(ctx: RetProbeContext) -> Result<u32, i64> {
+    let pid_tgid = bpf_get_current_pid_tgid();
+    let lookup = match unsafe { ORIGINAL_DST_LOOKUPS.get(&pid_tgid) } {
+        Some(&lookup) => lookup,
+        None => return Ok(0),
+    };
+    unsafe {
+        ORIGINAL_DST_LOOKUPS.remove(&pid_tgid)?;
+    }
+    if ctx.ret::<i32>() != Some(0) {
+        return Ok(0);
+    }
 
+    let addr = unsafe { bpf_probe_read_user(lookup.optval)? };
+    if addr.sin_family != AF_INET {
+        return Ok(0);
+    }
+    if let Some(&sock_info) = unsafe { SOCKETS.get(&lookup.sk) } {
+        let mut sock_info = sock_info;
+        sock_info.original_dest_addr = NetEndian::from_raw(addr.sin_addr).to_host();
+        sock_info.original_dest_port = NetEndian::from_raw(addr.sin_port).to_host() as u32;
+        unsafe {
+            SOCKETS.insert(&lookup.sk, &sock_info, 0_u64)?;
+        }
+    }
     Ok(0)
 }
 
@@ -108,14 +708,18 @@ fn parse_sock_data(
     // read connection data
     match sk_common.skc_family {
         AF_INET => {
-            let src_addr =
-                u32::from_be(unsafe { sk_common.__bindgen_anon_1.__bindgen_anon_1.skc_rcv_saddr });
-            let dest_addr: u32 =
-                u32::from_be(unsafe { sk_common.__bindgen_anon_1.__bindgen_anon_1.skc_daddr });
-            let src_port =
-                u16::from_be(unsafe { sk_common.__bindgen_anon_3.__bindgen_anon_1.skc_num });
+            let src_addr = NetEndian::from_raw(unsafe {
+                sk_common.__bindgen_anon_1.__bindgen_anon_1.skc_rcv_saddr
+            })
+            .to_host();
+            let dest_addr =
+                NetEndian::from_raw(unsafe { sk_common.__bindgen_anon_1.__bindgen_anon_1.skc_daddr })
+                    .to_host();
+            // The local port is kept in host byte order, unlike the remote one.
+            let src_port = unsafe { sk_common.__bindgen_anon_3.__bindgen_anon_1.skc_num };
             let dest_port =
-                u16::from_be(unsafe { sk_common.__bindgen_anon_3.__bindgen_anon_1.skc_dport });
+                NetEndian::from_raw(unsafe { sk_common.__bindgen_anon_3.__bindgen_anon_1.skc_dport })
+                    .to_host();
             conn_key.src_addr = src_addr;
             conn_key.dest_addr = dest_addr;
             conn_key.src_port = src_port as u32;
@@ -127,6 +731,347 @@ fn parse_sock_data(
     }
 }
 
+fn infer_protocol(skb: *const sk_buff) -> u32 {
+    match read_payload(skb) {
+        Ok((payload, len)) => classify_payload(&payload, len),
+        Err(_) => PROTOCOL_UNKNOWN,
+    }
+}
+
+fn read_payload(skb: *const sk_buff) -> Result<([u8; PROTOCOL_PEEK_SIZE], usize), i64> {
+    let (data, len) = payload(skb)?;
+    let payload = unsafe { bpf_probe_read_kernel(data as *const [u8; PROTOCOL_PEEK_SIZE])? };
+    Ok((payload, len.min(PROTOCOL_PEEK_SIZE)))
+}
+
+/// Returns where the payload of a segment starts, and its length.
+fn payload(skb: *const sk_buff) -> Result<(*const u8, usize), i64> {
+    // tcp_data_queue is called before the TCP header is pulled, so skb->data still
+    // points at the TCP header and skb->len includes it.
+    let data = unsafe { bpf_probe_read_kernel(&(*skb).data as *const *mut u8)? };
+    let len = unsafe { bpf_probe_read_kernel(&(*skb).len as *const u32)? };
+    // data offset is stored in the upper 4 bits of the 13th byte of the TCP header
+    let doff = unsafe { bpf_probe_read_kernel(data.add(12) as *const u8)? } >> 4;
+    let header_len = (doff as u32) * 4;
+    if len <= header_len {
+        return Err(1i64);
+    }
+    Ok((unsafe { data.add(header_len as usize) }, (len - header_len) as usize))
+}
+
+/// Captures the first TLS hello received on a connection for the agent to parse, and
+/// on the server end the time the client finished its handshake. Returns whether the
+/// handshake is traced, so that later segments are not looked at.
+fn trace_tls(conn_key: &ConnectionKey, skb: *const sk_buff) -> Result<bool, i64> {
+    let (data, len) = payload(skb)?;
+    let content_type = unsafe { bpf_probe_read_kernel(data)? };
+    let now = unsafe { bpf_ktime_get_ns() };
+
+    if let Some(handshake) = unsafe { TLS_HANDSHAKES.get_ptr_mut(conn_key) } {
+        if content_type == TLS_CHANGE_CIPHER_SPEC || content_type == TLS_APPLICATION_DATA {
+            unsafe { (*handshake).finished_ns = now };
+            return Ok(true);
+        }
+        return Ok(false);
+    }
+
+    // The record header is followed by the type of the handshake message.
+    let message_type = if len > 5 {
+        unsafe { bpf_probe_read_kernel(data.add(5))? }
+    } else {
+        0
+    };
+    if content_type != TLS_HANDSHAKE
+        || (message_type != TLS_CLIENT_HELLO && message_type != TLS_SERVER_HELLO)
+    {
+        // Nothing to capture when the handshake went by unseen.
+        return Ok(true);
+    }
+
+    let handshake = unsafe { TLS_SCRATCH.get_ptr_mut(0).ok_or(1i64)? };
+    let captured = len.min(TLS_CAPTURE_SIZE);
+    unsafe {
+        (*handshake).hello_ns = now;
+        (*handshake).finished_ns = 0;
+        (*handshake).len = captured as u32;
+        bpf_probe_read_kernel_buf(data, &mut (*handshake).data[..captured])?;
+        TLS_HANDSHAKES.insert(conn_key, &*handshake, 0_u64)?;
+    }
+    // Only the server end waits for the client to finish.
+    Ok(message_type == TLS_SERVER_HELLO)
+}
+
+/// Counts a request received by an HTTP server, by the start of its request line.
+/// Segments carrying the rest of a request, or a request body, aren't counted.
+fn trace_http_request(conn_key: &ConnectionKey, skb: *const sk_buff) -> Result<(), i64> {
+    let (peek, len) = read_payload(skb)?;
+    if classify_payload(&peek, len) != PROTOCOL_HTTP || &peek[0..4] == b"HTTP" {
+        return Ok(());
+    }
+    let (data, len) = payload(skb)?;
+    let mut key = HttpRequestKey {
+        conn: *conn_key,
+        line: [0; HTTP_REQUEST_LINE_SIZE],
+    };
+    let captured = len.min(HTTP_REQUEST_LINE_SIZE);
+    unsafe {
+        bpf_probe_read_kernel_buf(data, &mut key.line[..captured])?;
+        match HTTP_REQUESTS.get_ptr_mut(&key) {
+            Some(count) => {
+                AtomicU64::from_ptr(count).fetch_add(1, Ordering::Relaxed);
+            }
+            None => HTTP_REQUESTS.insert(&key, &1, 0_u64)?,
+        }
+    }
+    Ok(())
+}
+
+/// Counts the period a client connection was idle for before a new request, from the
+/// last segment it sent or received, which is when the kernel last stamped it: the
+/// response to the previous request, or the acknowledgement of it.
+fn trace_idle_period(conn_key: &ConnectionKey, sk: *const sock) -> Result<(), i64> {
+    let tcp_sk = sk as *const tcp_sock;
+    let last_us = unsafe { bpf_probe_read_kernel(&(*tcp_sk).tcp_mstamp as *const u64)? };
+    let idle_ns = unsafe { bpf_ktime_get_ns() }.saturating_sub(last_us * 1000);
+
+    let mut key = IdleKey {
+        conn: *conn_key,
+        bucket: IDLE_BUCKETS - 1,
+    };
+    for i in 0..IDLE_BUCKETS - 1 {
+        if idle_ns <= IDLE_FIRST_BUCKET_NS << i {
+            key.bucket = i;
+            break;
+        }
+    }
+    unsafe {
+        match IDLE_PERIODS.get_ptr_mut(&key) {
+            Some(stats) => {
+                AtomicU64::from_ptr(addr_of_mut!((*stats).periods)).fetch_add(1, Ordering::Relaxed);
+                AtomicU64::from_ptr(addr_of_mut!((*stats).total_ns))
+                    .fetch_add(idle_ns, Ordering::Relaxed);
+            }
+            None => {
+                let stats = IdleStats {
+                    periods: 1,
+                    total_ns: idle_ns,
+                };
+                IDLE_PERIODS.insert(&key, &stats, 0_u64)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns where the user buffer of the data sent starts, and its length. Sends from
+/// a single buffer are ITER_UBUF since 6.0, and ITER_IOVEC before; only the first
+/// buffer of an ITER_IOVEC is returned.
+fn sent_payload(msg: *const msghdr) -> Result<(*const u8, usize), i64> {
+    let iter = unsafe { &(*msg).msg_iter as *const iov_iter };
+    let iter_type = unsafe { bpf_probe_read_kernel(&(*iter).iter_type as *const u8)? };
+    let segments = unsafe { &(*iter).__bindgen_anon_2.__bindgen_anon_1 };
+    match iter_type {
+        ITER_UBUF => unsafe {
+            let data = bpf_probe_read_kernel(&segments.__bindgen_anon_1.ubuf)?;
+            let len = bpf_probe_read_kernel(&segments.count)?;
+            Ok((data as *const u8, len))
+        },
+        ITER_IOVEC => unsafe {
+            let iov = bpf_probe_read_kernel(&segments.__bindgen_anon_1.__iov)?;
+            let iov: iovec = bpf_probe_read_kernel(iov)?;
+            Ok((iov.iov_base as *const u8, iov.iov_len as usize))
+        },
+        _ => Err(1i64),
+    }
+}
+
+/// Captures the ClientHello sent by a client for the agent to read the server name
+/// from.
+fn trace_client_hello(conn_key: &ConnectionKey, msg: *const msghdr) -> Result<(), i64> {
+    let (data, len) = sent_payload(msg)?;
+    if len <= 5 {
+        return Ok(());
+    }
+    let header = unsafe { bpf_probe_read_user(data as *const [u8; 6])? };
+    if header[0] != TLS_HANDSHAKE || header[5] != TLS_CLIENT_HELLO {
+        return Ok(());
+    }
+
+    let handshake = unsafe { TLS_SCRATCH.get_ptr_mut(0).ok_or(1i64)? };
+    let captured = len.min(TLS_CAPTURE_SIZE);
+    unsafe {
+        (*handshake).hello_ns = bpf_ktime_get_ns();
+        (*handshake).finished_ns = 0;
+        (*handshake).len = captured as u32;
+        bpf_probe_read_user_buf(data, &mut (*handshake).data[..captured])?;
+        TLS_CLIENT_HELLOS.insert(conn_key, &*handshake, 0_u64)?;
+    }
+    Ok(())
+}
+
+/// Counts a response sent by an HTTP server, by its Content-Encoding, and adds its
+/// Content-Length to the body bytes. Only the headers within the first
+/// HTTP_RESPONSE_HEAD_SIZE bytes of the response are looked at.
+fn trace_http_response(conn_key: &ConnectionKey, msg: *const msghdr) -> Result<(), i64> {
+    let (data, len) = sent_payload(msg)?;
+    if len < 5 {
+        return Ok(());
+    }
+    let head = unsafe { &mut *HTTP_SCRATCH.get_ptr_mut(0).ok_or(1i64)? };
+    let captured = len.min(HTTP_RESPONSE_HEAD_SIZE);
+    unsafe {
+        bpf_probe_read_user_buf(data, &mut head[..captured])?;
+    }
+    if &head[0..5] != b"HTTP/" {
+        return Ok(());
+    }
+
+    let mut key = HttpResponseKey {
+        conn: *conn_key,
+        encoding: HTTP_ENCODING_IDENTITY,
+    };
+    let mut body_bytes = 0;
+    for i in 0..HTTP_RESPONSE_HEAD_SIZE {
+        let (Some(&b), Some(&next)) = (head.get(i), head.get(i + 1)) else {
+            break;
+        };
+        if i + 1 >= captured {
+            break;
+        }
+        if b != b'\n' {
+            continue;
+        }
+        // An empty line ends the headers.
+        if next == b'\r' {
+            break;
+        }
+        if let Some(value) = header_value(head, captured, i + 1, b"content-length:") {
+            body_bytes = parse_length(head, captured, value);
+        } else if let Some(value) = header_value(head, captured, i + 1, b"content-encoding:") {
+            key.encoding = parse_encoding(head, captured, value);
+        }
+    }
+
+    unsafe {
+        match HTTP_RESPONSES.get_ptr_mut(&key) {
+            Some(stats) => {
+                AtomicU64::from_ptr(addr_of_mut!((*stats).responses))
+                    .fetch_add(1, Ordering::Relaxed);
+                AtomicU64::from_ptr(addr_of_mut!((*stats).body_bytes))
+                    .fetch_add(body_bytes, Ordering::Relaxed);
+            }
+            None => {
+                let stats = HttpResponseStats {
+                    responses: 1,
+                    body_bytes,
+                };
+                HTTP_RESPONSES.insert(&key, &stats, 0_u64)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns where the value of the header line starting at `start` begins, past its
+/// leading spaces, if the header is `name`, given in lower case with its colon.
+fn header_value(
+    head: &[u8; HTTP_RESPONSE_HEAD_SIZE],
+    len: usize,
+    start: usize,
+    name: &[u8],
+) -> Option<usize> {
+    for (j, &expected) in name.iter().enumerate() {
+        if start + j >= len || head.get(start + j)?.to_ascii_lowercase() != expected {
+            return None;
+        }
+    }
+    let mut value = start + name.len();
+    for _ in 0..8 {
+        if value >= len || *head.get(value)? != b' ' {
+            break;
+        }
+        value += 1;
+    }
+    Some(value)
+}
+
+fn parse_length(head: &[u8; HTTP_RESPONSE_HEAD_SIZE], len: usize, start: usize) -> u64 {
+    let mut length: u64 = 0;
+    for j in 0..20 {
+        match head.get(start + j) {
+            Some(&b) if start + j < len && b.is_ascii_digit() => {
+                length = length.wrapping_mul(10).wrapping_add((b - b'0') as u64);
+            }
+            _ => break,
+        }
+    }
+    length
+}
+
+fn parse_encoding(head: &[u8; HTTP_RESPONSE_HEAD_SIZE], len: usize, start: usize) -> u32 {
+    let is = |token: &[u8]| {
+        token.iter().enumerate().all(|(j, &expected)| {
+            start + j < len
+                && head
+                    .get(start + j)
+                    .is_some_and(|b| b.to_ascii_lowercase() == expected)
+        })
+    };
+    if is(b"gzip") || is(b"x-gzip") {
+        HTTP_ENCODING_GZIP
+    } else if is(b"deflate") {
+        HTTP_ENCODING_DEFLATE
+    } else if is(b"br") {
+        HTTP_ENCODING_BR
+    } else if is(b"zstd") {
+        HTTP_ENCODING_ZSTD
+    } else if is(b"identity") {
+        HTTP_ENCODING_IDENTITY
+    } else {
+        HTTP_ENCODING_OTHER
+    }
+}
+
+fn classify_payload(buf: &[u8; PROTOCOL_PEEK_SIZE], len: usize) -> u32 {
+    if len < 4 {
+        return PROTOCOL_UNKNOWN;
+    }
+
+    // TLS record header: handshake (0x16) or application data (0x17), version 3.x
+    if (buf[0] == 0x16 || buf[0] == 0x17) && buf[1] == 0x03 && buf[2] <= 0x04 {
+        return PROTOCOL_TLS;
+    }
+
+    // HTTP/2 connection preface, which in practice is almost always gRPC
+    if &buf[0..4] == b"PRI " {
+        return PROTOCOL_GRPC;
+    }
+    // HTTP/2 SETTINGS frame on stream 0, the first frame a server sends
+    if len >= 9 && buf[3] == 0x04 && buf[5..9] == [0, 0, 0, 0] {
+        return PROTOCOL_GRPC;
+    }
+
+    if &buf[0..4] == b"HTTP"
+        || &buf[0..4] == b"GET "
+        || &buf[0..4] == b"POST"
+        || &buf[0..4] == b"PUT "
+        || &buf[0..4] == b"HEAD"
+        || &buf[0..4] == b"DELE"
+        || &buf[0..4] == b"PATC"
+        || &buf[0..4] == b"OPTI"
+    {
+        return PROTOCOL_HTTP;
+    }
+
+    // RESP: arrays and bulk strings carry a length, simple strings and errors are plain text
+    match buf[0] {
+        b'*' | b'$' | b':' if buf[1].is_ascii_digit() || buf[1] == b'-' => PROTOCOL_REDIS,
+        b'+' if &buf[1..3] == b"OK" || &buf[1..5] == b"PONG" => PROTOCOL_REDIS,
+        b'-' if &buf[1..4] == b"ERR" => PROTOCOL_REDIS,
+        _ => PROTOCOL_UNKNOWN,
+    }
+}
+
 fn get_sock_role(sk: *const sock) -> u32 {
     let max_ack_backlog = unsafe { bpf_probe_read_kernel(&(*sk).sk_max_ack_backlog as *const u32) };
     match max_ack_backlog {
@@ -158,24 +1103,78 @@ pub fn sock_state_tracer(ctx: TracePointContext) -> u32 {
 
 fn try_state_tracer(ctx: TracePointContext) -> Result<u32, i64> {
     let sk: *const sock = unsafe { ctx.read_at::<*const sock>(INET_SOCK_SKADDR_OFFSET)? };
+    let old_state: i32 = unsafe { ctx.read_at::<i32>(INET_SOCK_OLDSTATE_OFFSET)? };
     let new_state: i32 = unsafe { ctx.read_at::<i32>(INET_SOCK_NEWSTATE_OFFSET)? };
 
     match new_state {
         TCP_SYN_RECV => handle_tcp_syn_recv(sk),
         TCP_SYN_SENT => handle_tcp_syn_sent(sk),
-        TCP_CLOSE => handle_tcp_close(sk),
+        TCP_ESTABLISHED if old_state == TCP_SYN_SENT => handle_tcp_established(sk),
+        TCP_CLOSE => handle_tcp_close(sk, old_state),
         _ => Ok(0),
     }
 }
 
+#[tracepoint]
+pub fn sock_receive_reset_tracer(ctx: TracePointContext) -> u32 {
+    match try_reset_tracer(ctx, TCP_RECEIVE_RESET_SKADDR_OFFSET) {
+        Ok(ret) => ret,
+        Err(ret) => match ret.try_into() {
+            Ok(rt) => rt,
+            Err(_) => 1,
+        },
+    }
+}
+
+#[tracepoint]
+pub fn sock_send_reset_tracer(ctx: TracePointContext) -> u32 {
+    match try_reset_tracer(ctx, TCP_SEND_RESET_SKADDR_OFFSET) {
+        Ok(ret) => ret,
+        Err(ret) => match ret.try_into() {
+            Ok(rt) => rt,
+            Err(_) => 1,
+        },
+    }
+}
+
+fn try_reset_tracer(ctx: TracePointContext, skaddr_offset: usize) -> Result<u32, i64> {
+    let sk: *const sock = unsafe { ctx.read_at::<*const sock>(skaddr_offset)? };
+    // resets sent in reply to segments without a socket have nothing to attribute to
+    if sk.is_null() {
+        return Ok(0);
+    }
+
+    if let Some(&sock_info) = unsafe { SOCKETS.get(&sk) } {
+        let mut sock_info = sock_info;
+        sock_info.resets += 1;
+        unsafe {
+            SOCKETS.insert(&sk, &sock_info, 0_u64)?;
+        }
+    }
+
+    Ok(0)
+}
+
 fn handle_tcp_syn_sent(sk: *const sock) -> Result<u32, i64> {
     let id = get_unique_id();
     let pid = bpf_get_current_pid_tgid() as u32;
     let sock_info = SockInfo {
+        start_ns: unsafe { bpf_ktime_get_ns() },
         id,
         pid,
         is_active: 1,
         role: CONNECTION_ROLE_CLIENT,
+        protocol: PROTOCOL_UNKNOWN,
+        inference_count: 0,
+        resets: 0,
+        original_dest_addr: 0,
+        original_dest_port: 0,
+        handshake_ns: 0,
+        tls_traced: 0,
+        client_hello_traced: 0,
+        requests: 0,
+        received_at_request: 0,
+        aggregated: AggregateStats::default(),
     };
 
     unsafe {
@@ -185,17 +1184,44 @@ fn handle_tcp_syn_sent(sk: *const sock) -> Result<u32, i64> {
     Ok(0)
 }
 
+// The SYN is sent by tcp_v4_connect, and the SYN-ACK received by tcp_rcv_state_process,
+// which both move the socket to its new state. The time in between is the round trip of
+// the handshake, which leaves out the processing of requests by the server.
+fn handle_tcp_established(sk: *const sock) -> Result<u32, i64> {
+    if let Some(&sock_info) = unsafe { SOCKETS.get(&sk) } {
+        let mut sock_info = sock_info;
+        sock_info.handshake_ns = unsafe { bpf_ktime_get_ns() } - sock_info.start_ns;
+        unsafe {
+            SOCKETS.insert(&sk, &sock_info, 0_u64)?;
+        }
+    }
+
+    Ok(0)
+}
+
 fn handle_tcp_syn_recv(sk: *const sock) -> Result<u32, i64> {
     let mut conn_key = ConnectionKey::default();
     let mut conn_stats = ConnectionStats::default();
 
     parse_sock_data(sk, &mut conn_key, &mut conn_stats)?;
 
-    let sock_info = SockInfo {
+    let mut sock_info = SockInfo {
+        start_ns: unsafe { bpf_ktime_get_ns() },
         id: get_unique_id(),
         pid: 0,
         is_active: 1,
         role: CONNECTION_ROLE_SERVER,
+        protocol: PROTOCOL_UNKNOWN,
+        inference_count: 0,
+        resets: 0,
+        original_dest_addr: 0,
+        original_dest_port: 0,
+        handshake_ns: 0,
+        tls_traced: 0,
+        client_hello_traced: 0,
+        requests: 0,
+        received_at_request: 0,
+        aggregated: AggregateStats::default(),
     };
 
     unsafe {
@@ -209,24 +1235,30 @@ fn handle_tcp_syn_recv(sk: *const sock) -> Result<u32, i64> {
     conn_key.id = sock_info.id;
     conn_key.pid = sock_info.pid;
     conn_key.role = sock_info.role;
-
-    unsafe {
-        CONNECTIONS.insert(&conn_key, &conn_stats, 0_u64)?;
-    }
+    record_connection(sk, &conn_key, &conn_stats, &mut sock_info)?;
 
     Ok(0)
 }
 
-fn handle_tcp_close(sk: *const sock) -> Result<u32, i64> {
+fn handle_tcp_close(sk: *const sock, old_state: i32) -> Result<u32, i64> {
     let mut conn_key = ConnectionKey::default();
     let mut conn_stats = ConnectionStats::default();
 
     parse_sock_data(sk, &mut conn_key, &mut conn_stats)?;
 
-    if let Some(sock_info) = unsafe { SOCKETS.get(&sk) } {
+    let mut sock_info = SockInfo::default();
+    if let Some(&info) = unsafe { SOCKETS.get(&sk) } {
+        sock_info = info;
         conn_key.id = sock_info.id;
         conn_key.pid = sock_info.pid;
         conn_key.role = sock_info.role;
+        conn_stats.protocol = sock_info.protocol as u64;
+        conn_stats.resets = sock_info.resets as u64;
+        conn_stats.original_dest_addr = sock_info.original_dest_addr;
+        conn_stats.original_dest_port = sock_info.original_dest_port;
+        conn_stats.handshake_ns = sock_info.handshake_ns;
+        conn_stats.requests = sock_info.requests;
+        conn_stats.duration_ns = unsafe { bpf_ktime_get_ns() } - sock_info.start_ns;
         unsafe {
             SOCKETS.remove(&sk)?;
         }
@@ -236,14 +1268,134 @@ fn handle_tcp_close(sk: *const sock) -> Result<u32, i64> {
         conn_key.role = get_sock_role(sk);
     }
 
-    conn_stats.is_active = 0;
-    unsafe {
-        CONNECTIONS.insert(&conn_key, &conn_stats, 0_u64)?;
+    // a handshake that never completed without being reset is a connect timeout,
+    // refused connections are already accounted for as resets
+    if old_state == TCP_SYN_SENT && conn_stats.resets == 0 {
+        conn_stats.connect_timeouts = 1;
     }
 
+    conn_stats.is_active = 0;
+    record_connection(sk, &conn_key, &conn_stats, &mut sock_info)?;
+
     Ok(0)
 }
 
+/// Records the totals of a TCP connection in the connections map, stamped with the
+/// identities of its addresses. In aggregation mode,
+/// they are instead added to the aggregate of the identities of its ends, by what they
+/// grew since last added, which `sock_info` keeps track of. It is stored back for `sk`
+/// unless the connection closed.
+///
+/// Connections are only aggregated once their protocol is settled, for all their bytes
+/// to be counted under it, and connections within a host or pod are left out, like the
+/// agent does by default.
+fn record_connection(
+    sk: *const sock,
+    conn_key: &ConnectionKey,
+    conn_stats: &ConnectionStats,
+    sock_info: &mut SockInfo,
+) -> Result<(), i64> {
+    if !aggregating() {
+        let mut conn_stats = *conn_stats;
+        conn_stats.src_identity = identity(conn_key.src_addr);
+        conn_stats.dest_identity = identity(conn_key.dest_addr);
+        unsafe {
+            CONNECTIONS.insert(conn_key, &conn_stats, 0_u64)?;
+        }
+        return Ok(());
+    }
+    let closed = conn_stats.is_active == 0;
+    let settled = sock_info.protocol != PROTOCOL_UNKNOWN
+        || sock_info.inference_count >= PROTOCOL_INFERENCE_LIMIT;
+    let loopback = conn_key.src_addr == conn_key.dest_addr || conn_key.dest_addr >> 24 == 127;
+    if !(settled || closed) || loopback {
+        return Ok(());
+    }
+    // Redirected connections are counted as accepted on their original destination.
+    let (client, server, server_port) = match (conn_key.role, conn_stats.original_dest()) {
+        (CONNECTION_ROLE_CLIENT, _) => (conn_key.src_addr, conn_key.dest_addr, conn_key.dest_port),
+        (CONNECTION_ROLE_SERVER, Some((addr, port))) => (conn_key.dest_addr, addr, port),
+        (CONNECTION_ROLE_SERVER, None) => {
+            (conn_key.dest_addr, conn_key.src_addr, conn_key.src_port)
+        }
+        _ => return Ok(()),
+    };
+    let key = AggregateKey {
+        client: identity(client),
+        server: identity(server),
+        server_port,
+        protocol: conn_stats.protocol as u32,
+        role: conn_key.role,
+    };
+    let totals = AggregateStats {
+        bytes_sent: conn_stats.bytes_sent,
+        bytes_received: conn_stats.bytes_received,
+        opened: 1,
+        closed: closed as u64,
+        resets: conn_stats.resets,
+        connect_timeouts: conn_stats.connect_timeouts,
+        requests: conn_stats.requests,
+        requesting: (conn_stats.requests > 0) as u64,
+    };
+    let delta = totals.since(&sock_info.aggregated);
+    if sock_info.aggregated.opened == 0 {
+        // Whatever was recorded of the connection before aggregation was turned on goes
+        // in the aggregate now, with the rest of its totals.
+        let _ = unsafe { CONNECTIONS.remove(conn_key) };
+    }
+    sock_info.aggregated = totals;
+    add_aggregate(&key, &delta)?;
+    if !closed {
+        unsafe {
+            SOCKETS.insert(&sk, sock_info, 0_u64)?;
+        }
+    }
+    Ok(())
+}
+
+fn aggregating() -> bool {
+    unsafe { AGGREGATION.get(0) }.is_some_and(|&on| on != 0)
+}
+
+fn identity(addr: u32) -> u32 {
+    unsafe { IDENTITIES.get(&addr) }
+        .copied()
+        .unwrap_or(IDENTITY_UNKNOWN)
+}
+
+fn add_aggregate(key: &AggregateKey, delta: &AggregateStats) -> Result<(), i64> {
+    unsafe {
+        let stats = match AGGREGATES.get_ptr_mut(key) {
+            Some(stats) => stats,
+            None => {
+                if AGGREGATES.insert(key, delta, BPF_NOEXIST).is_ok() {
+                    return Ok(());
+                }
+                // Created meanwhile, on another CPU.
+                AGGREGATES.get_ptr_mut(key).ok_or(1i64)?
+            }
+        };
+        for (total, value) in [
+            (addr_of_mut!((*stats).bytes_sent), delta.bytes_sent),
+            (addr_of_mut!((*stats).bytes_received), delta.bytes_received),
+            (addr_of_mut!((*stats).opened), delta.opened),
+            (addr_of_mut!((*stats).closed), delta.closed),
+            (addr_of_mut!((*stats).resets), delta.resets),
+            (
+                addr_of_mut!((*stats).connect_timeouts),
+                delta.connect_timeouts,
+            ),
+            (addr_of_mut!((*stats).requests), delta.requests),
+            (addr_of_mut!((*stats).requesting), delta.requesting),
+        ] {
+            if value > 0 {
+                AtomicU64::from_ptr(total).fetch_add(value, Ordering::Relaxed);
+            }
+        }
+    }
+    Ok(())
+}
+
 #[panic_handler]
 fn panic(_info: &core::panic::PanicInfo) -> ! {
     unsafe { core::hint::unreachable_unchecked() }
@@ -1,8 +1,8 @@
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 
 use aya::maps::{HashMap, MapData};
-use aya::programs::{KProbe, TracePoint};
-use aya::{include_bytes_aligned, Ebpf};
+use aya::programs::{FEntry, KProbe, TracePoint};
+use aya::{include_bytes_aligned, Btf, Ebpf};
 use aya_log::EbpfLogger;
 use log::{debug, info, warn};
 use tokio::signal;
@@ -38,18 +38,70 @@ async fn main() -> Result<(), anyhow::Error> {
         // This can happen if you remove all log statements from your eBPF program.
         warn!("failed to initialize eBPF logger: {}", e);
     }
-    let sock_conn_tracer: &mut KProbe = bpf.program_mut("sock_conn_tracer").unwrap().try_into()?;
-    sock_conn_tracer.load()?;
-    sock_conn_tracer.attach("tcp_data_queue", 0)?;
+    for (program, function) in [
+        ("sock_conn_tracer", "tcp_data_queue"),
+        ("sock_send_tracer", "tcp_sendmsg"),
+        ("udp_send_tracer", "udp_sendmsg"),
+        ("udp_receive_tracer", "skb_consume_udp"),
+    ] {
+        if let Err(e) = attach_fentry(&mut bpf, program, function) {
+            info!(
+                "fentry unavailable for {}, falling back to kprobe: {}",
+                function, e
+            );
+            let kprobe: &mut KProbe = bpf.program_mut(program).unwrap().try_into()?;
+            kprobe.load()?;
+            kprobe.attach(function, 0)?;
+        }
+    }
+    // Only kernels built with netfilter have nf_getsockopt, and without it nothing is
+    // redirected whose original destination would need recovering.
+    for program in ["original_dst_lookup", "original_dst_lookup_ret"] {
+        let kprobe: &mut KProbe = bpf.program_mut(program).unwrap().try_into()?;
+        kprobe.load()?;
+        if let Err(e) = kprobe.attach("nf_getsockopt", 0) {
+            warn!(
+                "failed to attach {}, original destinations are not recovered: {}",
+                program, e
+            );
+        }
+    }
 
     let sock_state_tracer: &mut TracePoint =
         bpf.program_mut("sock_state_tracer").unwrap().try_into()?;
     sock_state_tracer.load()?;
     sock_state_tracer.attach("sock", "inet_sock_set_state")?;
 
+    let sock_receive_reset_tracer: &mut TracePoint = bpf
+        .program_mut("sock_receive_reset_tracer")
+        .unwrap()
+        .try_into()?;
+    sock_receive_reset_tracer.load()?;
+    sock_receive_reset_tracer.attach("tcp", "tcp_receive_reset")?;
+
+    let sock_send_reset_tracer: &mut TracePoint = bpf
+        .program_mut("sock_send_reset_tracer")
+        .unwrap()
+        .try_into()?;
+    sock_send_reset_tracer.load()?;
+    sock_send_reset_tracer.attach("tcp", "tcp_send_reset")?;
+
     info!("Waiting for Ctrl-C...");
     signal::ctrl_c().await?;
     info!("Exiting...");
 
     Ok(())
 }
+
+/// Attaches the fentry variant of a kprobe on `function`, which is cheaper than the
+/// kprobe but requires the kernel to expose its BTF, 5.5 or later.
+fn attach_fentry(bpf: &mut Ebpf, kprobe: &str, function: &str) -> Result<(), anyhow::Error> {
+    let btf = Btf::from_sys_fs()?;
+    let program: &mut FEntry = bpf
+        .program_mut(&format!("{}_fentry", kprobe))
+        .unwrap()
+        .try_into()?;
+    program.load(function, &btf)?;
+    program.attach()?;
+    Ok(())
+}
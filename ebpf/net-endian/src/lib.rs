@@ -0,0 +1,147 @@
+//! Byte order of the values read from the kernel.
+//!
+//! The kernel keeps IPv4 addresses and remote ports in network byte order, but local
+//! ports in host byte order (`skc_num`). Every value crossing to userspace is converted
+//! to host byte order once, in the eBPF program, so that `Ipv4Addr::from(u32)` and port
+//! comparisons work as is. Wrapping raw reads in [`NetEndian`] makes that conversion
+//! explicit and keeps host-order fields from being swapped twice.
+//!
+//! Shared by the common crates of the tracers, for their eBPF programs and userspace.
+
+#![no_std]
+
+/// A value in network (big-endian) byte order, as stored by the kernel.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct NetEndian<T>(T);
+
+impl<T: Integer> NetEndian<T> {
+    /// Wraps a value read as is from a network byte order kernel field.
+    pub const fn from_raw(raw: T) -> Self {
+        Self(raw)
+    }
+
+    /// Converts a host byte order value to network byte order.
+    pub fn from_host(value: T) -> Self {
+        Self(value.swap_to_be())
+    }
+
+    /// Returns the value as stored by the kernel.
+    pub fn raw(self) -> T {
+        self.0
+    }
+
+    /// Returns the value in host byte order.
+    pub fn to_host(self) -> T {
+        self.0.swap_from_be()
+    }
+}
+
+/// The integers the kernel stores in network byte order: addresses and ports.
+pub trait Integer: Copy + private::Sealed {
+    fn swap_to_be(self) -> Self;
+    fn swap_from_be(self) -> Self;
+}
+
+macro_rules! integer {
+    ($t:ty) => {
+        impl Integer for $t {
+            fn swap_to_be(self) -> Self {
+                self.to_be()
+            }
+
+            fn swap_from_be(self) -> Self {
+                <$t>::from_be(self)
+            }
+        }
+
+        impl private::Sealed for $t {}
+    };
+}
+
+integer!(u16);
+integer!(u32);
+
+mod private {
+    pub trait Sealed {}
+}
+
+#[cfg(test)]
+mod tests {
+    use core::net::Ipv4Addr;
+
+    use super::NetEndian;
+
+    /// The start of the `sock_common` of a connection from 10.244.1.5:41234 to
+    /// 10.96.0.10:8080 as laid out in memory on x86_64, where eBPF programs read it:
+    /// `skc_daddr`, `skc_rcv_saddr`, `skc_hash`, then `skc_dport` in network byte
+    /// order and `skc_num` in host byte order.
+    const SOCK_COMMON: [u8; 16] = [
+        10, 96, 0, 10, // skc_daddr
+        10, 244, 1, 5, // skc_rcv_saddr
+        0x5d, 0x3c, 0x8e, 0x21, // skc_hash
+        0x1f, 0x90, // skc_dport
+        0x12, 0xa1, // skc_num
+    ];
+
+    /// The ports of the TCP header of the SYN opening the same connection, as sent on
+    /// the wire.
+    const TCP_PORTS: [u8; 4] = [0xa1, 0x12, 0x1f, 0x90];
+
+    /// Reads a field the way `bpf_probe_read` does, copying its bytes as is.
+    fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_ne_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+        u16::from_ne_bytes(bytes[offset..offset + 2].try_into().unwrap())
+    }
+
+    #[test]
+    fn test_addresses_from_kernel() {
+        let dest = NetEndian::from_raw(read_u32(&SOCK_COMMON, 0)).to_host();
+        let src = NetEndian::from_raw(read_u32(&SOCK_COMMON, 4)).to_host();
+        assert_eq!(Ipv4Addr::from(src), Ipv4Addr::new(10, 244, 1, 5));
+        assert_eq!(Ipv4Addr::from(dest), Ipv4Addr::new(10, 96, 0, 10));
+    }
+
+    #[test]
+    fn test_ports_from_kernel() {
+        let dest_port = NetEndian::from_raw(read_u16(&SOCK_COMMON, 12)).to_host();
+        assert_eq!(dest_port, 8080);
+    }
+
+    // skc_num is stored in the byte order of the host the dump comes from.
+    #[cfg(target_endian = "little")]
+    #[test]
+    fn test_host_order_port_from_kernel() {
+        let src_port = read_u16(&SOCK_COMMON, 14);
+        assert_eq!(src_port, 41234);
+        // Converting it to network byte order gives the bytes sent on the wire.
+        assert_eq!(
+            NetEndian::from_host(src_port).raw().to_ne_bytes(),
+            [TCP_PORTS[0], TCP_PORTS[1]]
+        );
+    }
+
+    #[test]
+    fn test_ports_from_wire() {
+        let src_port = NetEndian::from_raw(read_u16(&TCP_PORTS, 0)).to_host();
+        let dest_port = NetEndian::from_raw(read_u16(&TCP_PORTS, 2)).to_host();
+        assert_eq!((src_port, dest_port), (41234, 8080));
+        // The kernel keeps the remote port as it came in.
+        assert_eq!(read_u16(&TCP_PORTS, 2), read_u16(&SOCK_COMMON, 12));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let addr = u32::from(Ipv4Addr::new(10, 244, 1, 5));
+        let net = NetEndian::from_host(addr);
+        assert_eq!(net.raw().to_ne_bytes(), SOCK_COMMON[4..8]);
+        assert_eq!(net.to_host(), addr);
+        assert_eq!(
+            NetEndian::from_host(8080u16).raw().to_ne_bytes(),
+            [0x1f, 0x90]
+        );
+    }
+}
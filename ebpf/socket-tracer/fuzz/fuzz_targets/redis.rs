@@ -0,0 +1,30 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use socket_tracer::protocols::redis::RedisParser;
+use socket_tracer::protocols::{parse_bounded, ParseError};
+
+fuzz_target!(|data: &[u8]| {
+    // Feed the stream one more byte at a time, as the smallest reads would deliver it,
+    // and check that resuming gives the same outcome as parsing the same bytes at once.
+    let mut buf = data;
+    while !buf.is_empty() {
+        let mut parser = RedisParser::default();
+        let mut outcome = Err(ParseError::Incomplete);
+        for end in 1..=buf.len() {
+            outcome = parse_bounded(&mut parser, &buf[..end]);
+            if outcome != Err(ParseError::Incomplete) {
+                assert_eq!(
+                    outcome,
+                    parse_bounded(&mut RedisParser::default(), &buf[..end])
+                );
+                break;
+            }
+        }
+        let Ok((_, len)) = outcome else {
+            break;
+        };
+        assert!(len > 0 && len <= buf.len());
+        buf = &buf[len..];
+    }
+});
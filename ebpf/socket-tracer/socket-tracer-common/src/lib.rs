@@ -1,9 +1,18 @@
 #![no_std]
 
+pub use net_endian::{self as endian, NetEndian};
+
 pub const AF_UNKNOWN: u32 = 0xff;
 pub const AF_INET: u32 = 2;
 pub const AF_INET6: u32 = 10;
+pub const AF_UNIX: u32 = 1;
+pub const AF_VSOCK: u32 = 40;
+// Size of the path of a unix-domain socket address, sun_path.
+pub const UNIX_PATH_SIZE: usize = 108;
 pub const MAX_MSG_SIZE: usize = 30720;
+// Bytes of each message captured unless a snap length is set, enough for the headers of
+// most requests and responses, which is what the protocol parsers need.
+pub const DEFAULT_SNAP_LEN: usize = 4096;
 pub const CHUNK_LIMIT: usize = 84;
 pub const LOOP_LIMIT: usize = 882;
 pub const PROTOCOL_VEC_LIMIT: usize = 3;
@@ -64,14 +73,56 @@ pub struct ProtocolMessage {
     pub msg_type: MessageType,
 }
 
+/// Value of [`ControlValueIndex::TargetTGIDIndex`] tracing only the processes in the
+/// `target_tgids` map, e.g. all those of a pod.
+pub const TARGET_TGID_SET: i64 = -1;
+/// Number of processes the `target_tgids` map holds at most.
+pub const MAX_TARGET_TGIDS: u32 = 1024;
+
 #[derive(Copy, Clone, Debug)]
 #[repr(u64)]
 pub enum ControlValueIndex {
+    /// Process whose sockets are traced, the processes of `target_tgids` when
+    /// [`TARGET_TGID_SET`], or every process when otherwise not positive.
     TargetTGIDIndex = 0,
     SelfTGIDIndex = 1,
+    SnapLenIndex = 2,
     NumControlValues,
 }
 
+#[derive(Copy, Clone, Debug)]
+#[repr(u32)]
+pub enum DropStage {
+    // A probe failed to record its state in a BPF map.
+    MapInsert = 0,
+    // An event could not be submitted to its perf buffer.
+    PerfOutput = 1,
+    // Userspace received an event it could not decode.
+    Parse = 2,
+    // No program was registered to parse the data of a protocol.
+    TailCall = 3,
+    NumDropStages,
+}
+
+impl DropStage {
+    pub const ALL: [DropStage; DropStage::NumDropStages as usize] = [
+        DropStage::MapInsert,
+        DropStage::PerfOutput,
+        DropStage::Parse,
+        DropStage::TailCall,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            DropStage::MapInsert => "map_insert",
+            DropStage::PerfOutput => "perf_output",
+            DropStage::Parse => "parse",
+            DropStage::TailCall => "tail_call",
+            DropStage::NumDropStages => "unknown",
+        }
+    }
+}
+
 #[derive(Copy, Clone, Debug)]
 #[repr(u64)]
 pub enum SourceFunction {
@@ -110,9 +161,17 @@ pub struct ConnId {
     pub fd: i64,
     // Unique id of the conn_id (timestamp).
     pub tsid: u64,
+    // Monotonic id given to the connection when it is first seen, unique across every
+    // probe and CPU. It stays the same for all the events of the connection, unlike the
+    // fd, which is reused once it is closed.
+    pub conn_id: u64,
 }
 
-#[derive(Copy, Clone, Debug, Default)]
+// Connections found open by userspace when the tracer starts are given ids from this
+// one on, which the probes never reach.
+pub const BOOTSTRAP_CONN_ID_BASE: u64 = 1 << 63;
+
+#[derive(Copy, Clone, Debug)]
 #[repr(C)]
 pub struct ConnInfo {
     // The unique identifier of the connection.
@@ -132,10 +191,10 @@ pub struct ConnInfo {
     // Used for determining when to send updated conn_stats values.
     pub prev_reported_bytes: i64,
 
-    // The IP address of the source.
+    // The IP address of the source, in host byte order.
     pub src_addr_in4: u32,
     pub src_addr_in6: [u8; 16usize],
-    // The IP address of the destination.
+    // The IP address of the destination, in host byte order.
     pub dst_addr_in4: u32,
     pub dst_addr_in6: [u8; 16usize],
     // The family of the socket.
@@ -146,6 +205,34 @@ pub struct ConnInfo {
     pub dst_port: u32,
     // How many times traffic inference has been applied on this connection.
     pub protocol_total_count: u32,
+    // The path of the unix-domain socket connected to, NUL padded. Abstract names start
+    // with a NUL byte.
+    pub unix_path: [u8; UNIX_PATH_SIZE],
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for ConnInfo {}
+
+impl Default for ConnInfo {
+    fn default() -> Self {
+        Self {
+            id: ConnId::default(),
+            protocol: TrafficProtocol::default(),
+            role: EndpointRole::default(),
+            write_bytes: 0,
+            read_bytes: 0,
+            prev_reported_bytes: 0,
+            src_addr_in4: 0,
+            src_addr_in6: [0; 16],
+            dst_addr_in4: 0,
+            dst_addr_in6: [0; 16],
+            sa_family: 0,
+            src_port: 0,
+            dst_port: 0,
+            protocol_total_count: 0,
+            unix_path: [0; UNIX_PATH_SIZE],
+        }
+    }
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -212,6 +299,8 @@ pub struct ConnStatsEvent {
     pub read_bytes: i64,
     // Bitmask of flags specifying whether conn open or close have been observed.
     pub event_flags: u32,
+    // The path of the unix-domain socket connected to, as in ConnInfo.
+    pub unix_path: [u8; UNIX_PATH_SIZE],
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -235,4 +324,19 @@ pub struct SocketControlEvent {
     // Fields for Close Event
     pub write_bytes: i64,
     pub read_bytes: i64,
+
+    // The path of the unix-domain socket connected to, as in ConnInfo.
+    pub unix_path: [u8; UNIX_PATH_SIZE],
+}
+
+/// Returns the path of a unix-domain socket out of its NUL padded `sun_path`, and
+/// whether it is an abstract name, which starts with a NUL byte. Unnamed sockets have
+/// an empty path.
+pub fn unix_path(sun_path: &[u8; UNIX_PATH_SIZE]) -> (&[u8], bool) {
+    let (name, abstract_name) = match sun_path.split_first() {
+        Some((&0, name)) => (name, true),
+        _ => (&sun_path[..], false),
+    };
+    let end = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+    (&name[..end], abstract_name && end > 0)
 }
@@ -0,0 +1,170 @@
+// Access to the arguments and return value of the traced kernel functions, whether
+// they are traced with kprobes or with fentry/fexit programs.
+
+use core::ptr::addr_of;
+
+use aya_ebpf::{
+    cty::c_void,
+    helpers::bpf_probe_read_kernel,
+    programs::{FEntryContext, FExitContext, ProbeContext},
+    EbpfContext,
+};
+
+use crate::vmlinux::pt_regs;
+
+/// A value held in a register or BTF argument slot.
+pub trait FromRaw {
+    fn from_raw(raw: u64) -> Self;
+}
+
+macro_rules! impl_from_raw {
+    ($($ty:ty),*) => {
+        $(impl FromRaw for $ty {
+            fn from_raw(raw: u64) -> Self {
+                raw as $ty
+            }
+        })*
+    };
+}
+
+impl_from_raw!(i32, u32, i64, u64, isize, usize);
+
+impl<T> FromRaw for *const T {
+    fn from_raw(raw: u64) -> Self {
+        raw as *const T
+    }
+}
+
+impl<T> FromRaw for *mut T {
+    fn from_raw(raw: u64) -> Self {
+        raw as *mut T
+    }
+}
+
+/// The context of a program traced on entry to or return from a kernel function.
+/// Programs are written once against it and attached as kprobes, or as fentry/fexit
+/// programs on kernels with BTF, which are cheaper and can't be missed.
+pub trait FnContext: EbpfContext {
+    /// Whether the program can tail call into the data parsers, which are kprobes.
+    const TAIL_CALLS: bool = false;
+
+    fn raw_arg(&self, n: usize) -> Option<u64>;
+
+    fn raw_ret(&self) -> Option<u64>;
+
+    /// Returns the `n`th argument of the function.
+    fn arg<T: FromRaw>(&self, n: usize) -> Option<T> {
+        self.raw_arg(n).map(T::from_raw)
+    }
+
+    /// Returns the return value of the function, on return only.
+    fn ret<T: FromRaw>(&self) -> Option<T> {
+        self.raw_ret().map(T::from_raw)
+    }
+}
+
+impl FnContext for ProbeContext {
+    const TAIL_CALLS: bool = true;
+
+    fn raw_arg(&self, n: usize) -> Option<u64> {
+        ProbeContext::arg(self, n)
+    }
+
+    fn raw_ret(&self) -> Option<u64> {
+        ProbeContext::ret(self)
+    }
+}
+
+/// The context of an fentry program.
+pub struct FEntry(pub FEntryContext);
+
+impl EbpfContext for FEntry {
+    fn as_ptr(&self) -> *mut c_void {
+        self.0.as_ptr()
+    }
+}
+
+impl FnContext for FEntry {
+    fn raw_arg(&self, n: usize) -> Option<u64> {
+        Some(unsafe { self.0.arg(n) })
+    }
+
+    fn raw_ret(&self) -> Option<u64> {
+        None
+    }
+}
+
+/// The context of an fexit program traced on a function taking `ARGS` arguments, which
+/// are followed by its return value.
+pub struct FExit<const ARGS: usize>(pub FExitContext);
+
+impl<const ARGS: usize> EbpfContext for FExit<ARGS> {
+    fn as_ptr(&self) -> *mut c_void {
+        self.0.as_ptr()
+    }
+}
+
+impl<const ARGS: usize> FnContext for FExit<ARGS> {
+    fn raw_arg(&self, n: usize) -> Option<u64> {
+        (n < ARGS).then(|| unsafe { self.0.arg(n) })
+    }
+
+    fn raw_ret(&self) -> Option<u64> {
+        Some(unsafe { self.0.arg(ARGS) })
+    }
+}
+
+/// Reads the `n`th syscall argument from the registers saved on syscall entry.
+fn syscall_arg(regs: *const pt_regs, n: usize) -> Option<u64> {
+    let reg = unsafe {
+        match n {
+            0 => addr_of!((*regs).di),
+            1 => addr_of!((*regs).si),
+            2 => addr_of!((*regs).dx),
+            3 => addr_of!((*regs).r10),
+            4 => addr_of!((*regs).r8),
+            5 => addr_of!((*regs).r9),
+            _ => return None,
+        }
+    };
+    unsafe { bpf_probe_read_kernel(reg).ok() }
+}
+
+/// The context of an fentry program traced on a syscall wrapper, `__x64_sys_*`, which
+/// takes the saved registers rather than the syscall arguments.
+pub struct SyscallEntry(pub FEntryContext);
+
+impl EbpfContext for SyscallEntry {
+    fn as_ptr(&self) -> *mut c_void {
+        self.0.as_ptr()
+    }
+}
+
+impl FnContext for SyscallEntry {
+    fn raw_arg(&self, n: usize) -> Option<u64> {
+        syscall_arg(unsafe { self.0.arg(0) }, n)
+    }
+
+    fn raw_ret(&self) -> Option<u64> {
+        None
+    }
+}
+
+/// The context of an fexit program traced on a syscall wrapper.
+pub struct SyscallExit(pub FExitContext);
+
+impl EbpfContext for SyscallExit {
+    fn as_ptr(&self) -> *mut c_void {
+        self.0.as_ptr()
+    }
+}
+
+impl FnContext for SyscallExit {
+    fn raw_arg(&self, n: usize) -> Option<u64> {
+        syscall_arg(unsafe { self.0.arg(0) }, n)
+    }
+
+    fn raw_ret(&self) -> Option<u64> {
+        Some(unsafe { self.0.arg(1) })
+    }
+}
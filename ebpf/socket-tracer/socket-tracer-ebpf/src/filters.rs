@@ -1,11 +1,16 @@
 use socket_tracer_common::{
-    AF_INET, AF_INET6, AF_UNKNOWN, ConnInfo, ControlValueIndex, TrafficProtocol,
+    AF_INET, AF_INET6, AF_UNIX, AF_UNKNOWN, AF_VSOCK, ConnInfo, ControlValueIndex,
+    TrafficProtocol,
 };
 
 use crate::maps::{CONTROL_MAP, CONTROL_VALUES};
 
 pub fn should_trace_sockaddr_family(sa_family: u32) -> bool {
-    return sa_family == AF_UNKNOWN || sa_family == AF_INET || sa_family == AF_INET6;
+    return sa_family == AF_UNKNOWN
+        || sa_family == AF_INET
+        || sa_family == AF_INET6
+        || sa_family == AF_UNIX
+        || sa_family == AF_VSOCK;
 }
 
 pub fn should_trace_conn(conn_info: &ConnInfo) -> bool {
@@ -66,24 +66,27 @@ impl<T> MyPerfEventArray<T> {
         }
     }
 
-    pub fn output<C: EbpfContext>(&self, ctx: &C, data: &T, flags: u32) {
+    pub fn output<C: EbpfContext>(&self, ctx: &C, data: &T, flags: u32) -> Result<(), c_long> {
         self.output_at_index(ctx, BPF_F_CURRENT_CPU as u32, data, flags)
     }
 
-    pub fn output_at_index<C: EbpfContext>(&self, ctx: &C, index: u32, data: &T, flags: u32) {
-        let flags = u64::from(flags) << 32 | u64::from(index);
-        unsafe {
-            bpf_perf_event_output(
-                ctx.as_ptr(),
-                self.def.get() as *mut _,
-                flags,
-                data as *const _ as *mut _,
-                size_of::<T>() as u64,
-            );
-        }
+    pub fn output_at_index<C: EbpfContext>(
+        &self,
+        ctx: &C,
+        index: u32,
+        data: &T,
+        flags: u32,
+    ) -> Result<(), c_long> {
+        self.output_at_index_with_size(ctx, index, data, size_of::<T>() as u64, flags)
     }
 
-    pub fn output_with_size<C: EbpfContext>(&self, ctx: &C, data: &T, size: u64, flags: u32) {
+    pub fn output_with_size<C: EbpfContext>(
+        &self,
+        ctx: &C,
+        data: &T,
+        size: u64,
+        flags: u32,
+    ) -> Result<(), c_long> {
         self.output_at_index_with_size(ctx, BPF_F_CURRENT_CPU as u32, data, size, flags)
     }
 
@@ -94,16 +97,21 @@ impl<T> MyPerfEventArray<T> {
         data: &T,
         size: u64,
         flags: u32,
-    ) {
+    ) -> Result<(), c_long> {
         let flags = u64::from(flags) << 32 | u64::from(index);
-        unsafe {
+        let ret = unsafe {
             bpf_perf_event_output(
                 ctx.as_ptr(),
                 self.def.get() as *mut _,
                 flags,
                 data as *const _ as *mut _,
                 size,
-            );
+            )
+        };
+        if ret == 0 {
+            Ok(())
+        } else {
+            Err(ret)
         }
     }
 }
@@ -1,16 +1,14 @@
-#![no_std]
-#![no_main]
-
 use aya_ebpf::{
     helpers::bpf_get_current_pid_tgid,
-    macros::{kprobe, kretprobe},
-    programs::ProbeContext,
+    macros::{fentry, fexit, kprobe, kretprobe},
+    programs::{FEntryContext, FExitContext, ProbeContext},
 };
 
-use socket_tracer_common::{EndpointRole, SourceFunction};
+use socket_tracer_common::{DropStage, EndpointRole, SourceFunction};
 use socket_tracer_lib::{
-    maps::*, match_trace_tgid, OpenEventArgs, submit_open_event, TargetTgidMatchResult, types,
-    vmlinux::sockaddr,
+    context::{FnContext, SyscallEntry, SyscallExit},
+    maps::*, match_trace_tgid, submit_open_event, track_drop, types, vmlinux::sockaddr,
+    OpenEventArgs, TargetTgidMatchResult,
 };
 
 #[kprobe]
@@ -18,7 +16,12 @@ pub fn entry_accept(ctx: ProbeContext) -> u32 {
     try_entry_accept(ctx).unwrap_or_else(|ret| ret.try_into().unwrap_or_else(|_| 1))
 }
 
-fn try_entry_accept(ctx: ProbeContext) -> Result<u32, i64> {
+#[fentry(function = "__x64_sys_accept")]
+pub fn fentry_accept(ctx: FEntryContext) -> u32 {
+    try_entry_accept(SyscallEntry(ctx)).unwrap_or_else(|ret| ret.try_into().unwrap_or_else(|_| 1))
+}
+
+fn try_entry_accept<C: FnContext>(ctx: C) -> Result<u32, i64> {
     let sockaddr: *const sockaddr = ctx.arg(1).ok_or(1)?;
     let pid_tgid = bpf_get_current_pid_tgid();
 
@@ -27,9 +30,9 @@ fn try_entry_accept(ctx: ProbeContext) -> Result<u32, i64> {
         sock: core::ptr::null(),
     };
 
-    unsafe {
-        ACTIVE_ACCEPT_MAP.insert(&pid_tgid, &accept_args, 0)?;
-    }
+    track_drop(DropStage::MapInsert, unsafe {
+        ACTIVE_ACCEPT_MAP.insert(&pid_tgid, &accept_args, 0)
+    })?;
 
     Ok(0)
 }
@@ -39,7 +42,12 @@ pub fn ret_accept(ctx: ProbeContext) -> u32 {
     try_ret_accept(ctx).unwrap_or_else(|ret| ret.try_into().unwrap_or_else(|_| 1))
 }
 
-fn try_ret_accept(ctx: ProbeContext) -> Result<u32, i64> {
+#[fexit(function = "__x64_sys_accept")]
+pub fn fexit_accept(ctx: FExitContext) -> u32 {
+    try_ret_accept(SyscallExit(ctx)).unwrap_or_else(|ret| ret.try_into().unwrap_or_else(|_| 1))
+}
+
+fn try_ret_accept<C: FnContext>(ctx: C) -> Result<u32, i64> {
     let pid_tgid = bpf_get_current_pid_tgid();
     let accept_args = unsafe { ACTIVE_ACCEPT_MAP.get(&pid_tgid).ok_or(1)? };
     let res = process_syscall_accept(&ctx, pid_tgid, accept_args);
@@ -51,8 +59,8 @@ fn try_ret_accept(ctx: ProbeContext) -> Result<u32, i64> {
     res
 }
 
-fn process_syscall_accept(
-    ctx: &ProbeContext,
+fn process_syscall_accept<C: FnContext>(
+    ctx: &C,
     pid_tgid: u64,
     args: &types::AcceptArgs,
 ) -> Result<u32, i64> {
@@ -80,8 +88,3 @@ fn process_syscall_accept(
 
     Ok(0)
 }
-
-#[panic_handler]
-fn panic(_info: &core::panic::PanicInfo) -> ! {
-    unsafe { core::hint::unreachable_unchecked() }
-}
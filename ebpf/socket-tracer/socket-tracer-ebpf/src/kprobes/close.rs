@@ -1,16 +1,14 @@
-#![no_std]
-#![no_main]
-
 use aya_ebpf::{
     helpers::bpf_get_current_pid_tgid,
-    macros::{kprobe, kretprobe},
-    programs::ProbeContext,
+    macros::{fentry, fexit, kprobe, kretprobe},
+    programs::{FEntryContext, FExitContext, ProbeContext},
 };
 
-use socket_tracer_common::SourceFunction;
+use socket_tracer_common::{DropStage, SourceFunction};
 use socket_tracer_lib::{
+    context::{FnContext, SyscallEntry, SyscallExit},
     filters::should_trace_sockaddr_family, gen_tgid_fd, maps::*, match_trace_tgid,
-    populate_conn_stats_event, submit_close_event, TargetTgidMatchResult, types,
+    populate_conn_stats_event, submit_close_event, track_drop, types, TargetTgidMatchResult,
 };
 
 #[kprobe]
@@ -18,13 +16,18 @@ pub fn entry_close(ctx: ProbeContext) -> u32 {
     try_entry_close(ctx).unwrap_or_else(|ret| ret.try_into().unwrap_or_else(|_| 1))
 }
 
-fn try_entry_close(ctx: ProbeContext) -> Result<u32, i64> {
+#[fentry(function = "__x64_sys_close")]
+pub fn fentry_close(ctx: FEntryContext) -> u32 {
+    try_entry_close(SyscallEntry(ctx)).unwrap_or_else(|ret| ret.try_into().unwrap_or_else(|_| 1))
+}
+
+fn try_entry_close<C: FnContext>(ctx: C) -> Result<u32, i64> {
     let fd: i32 = ctx.arg(0).ok_or(1)?;
     let close_args = types::CloseArgs { fd };
 
-    unsafe {
-        ACTIVE_CLOSE_MAP.insert(&bpf_get_current_pid_tgid(), &close_args, 0)?;
-    }
+    track_drop(DropStage::MapInsert, unsafe {
+        ACTIVE_CLOSE_MAP.insert(&bpf_get_current_pid_tgid(), &close_args, 0)
+    })?;
 
     Ok(0)
 }
@@ -34,7 +37,12 @@ pub fn ret_close(ctx: ProbeContext) -> u32 {
     try_ret_close(ctx).unwrap_or_else(|ret| ret.try_into().unwrap_or_else(|_| 1))
 }
 
-fn try_ret_close(ctx: ProbeContext) -> Result<u32, i64> {
+#[fexit(function = "__x64_sys_close")]
+pub fn fexit_close(ctx: FExitContext) -> u32 {
+    try_ret_close(SyscallExit(ctx)).unwrap_or_else(|ret| ret.try_into().unwrap_or_else(|_| 1))
+}
+
+fn try_ret_close<C: FnContext>(ctx: C) -> Result<u32, i64> {
     let pid_tgid = bpf_get_current_pid_tgid();
     let close_args = unsafe { ACTIVE_CLOSE_MAP.get(&pid_tgid).ok_or(1)? };
     let res = process_syscall_close(&ctx, pid_tgid, close_args);
@@ -46,8 +54,8 @@ fn try_ret_close(ctx: ProbeContext) -> Result<u32, i64> {
     res
 }
 
-fn process_syscall_close(
-    ctx: &ProbeContext,
+fn process_syscall_close<C: FnContext>(
+    ctx: &C,
     pid_tgid: u64,
     args: &types::CloseArgs,
 ) -> Result<u32, i64> {
@@ -85,11 +93,11 @@ fn process_syscall_close(
     {
         submit_close_event(ctx, &conn_info, SourceFunction::SyscallClose)?;
 
-        let mut event = populate_conn_stats_event(*conn_info)?;
+        let event = populate_conn_stats_event(conn_info)?;
         event.event_flags = event.event_flags | (1 << 1);
-        unsafe {
-            CONN_STATS_EVENTS.output(ctx, &event, 0);
-        }
+        let _ = track_drop(DropStage::PerfOutput, unsafe {
+            CONN_STATS_EVENTS.output(ctx, event, 0)
+        });
     }
 
     unsafe {
@@ -98,8 +106,3 @@ fn process_syscall_close(
 
     Ok(0)
 }
-
-#[panic_handler]
-fn panic(_info: &core::panic::PanicInfo) -> ! {
-    unsafe { core::hint::unreachable_unchecked() }
-}
@@ -1,16 +1,14 @@
-#![no_std]
-#![no_main]
-
 use aya_ebpf::{
     helpers::bpf_get_current_pid_tgid,
-    macros::{kprobe, kretprobe},
-    programs::ProbeContext,
+    macros::{fentry, fexit, kprobe, kretprobe},
+    programs::{FEntryContext, FExitContext, ProbeContext},
 };
 
-use socket_tracer_common::{EndpointRole, SourceFunction};
+use socket_tracer_common::{DropStage, EndpointRole, SourceFunction};
 use socket_tracer_lib::{
-    maps::*, match_trace_tgid, OpenEventArgs, submit_open_event, TargetTgidMatchResult, types,
-    vmlinux::sockaddr,
+    context::{FEntry, FExit, FnContext},
+    maps::*, match_trace_tgid, submit_open_event, track_drop, types, vmlinux::sockaddr,
+    OpenEventArgs, TargetTgidMatchResult,
 };
 
 #[kprobe]
@@ -18,15 +16,20 @@ pub fn entry_connect(ctx: ProbeContext) -> u32 {
     try_entry_connect(ctx).unwrap_or_else(|ret| ret.try_into().unwrap_or_else(|_| 1))
 }
 
-fn try_entry_connect(ctx: ProbeContext) -> Result<u32, i64> {
+#[fentry(function = "__sys_connect")]
+pub fn fentry_connect(ctx: FEntryContext) -> u32 {
+    try_entry_connect(FEntry(ctx)).unwrap_or_else(|ret| ret.try_into().unwrap_or_else(|_| 1))
+}
+
+fn try_entry_connect<C: FnContext>(ctx: C) -> Result<u32, i64> {
     let fd: i32 = ctx.arg(0).ok_or(1)?;
     let sockaddr: *const sockaddr = ctx.arg(1).ok_or(1)?;
     let pid_tgid = bpf_get_current_pid_tgid();
 
     let connect_args = types::ConnectArgs { fd, sockaddr };
-    unsafe {
-        ACTIVE_CONNECT_MAP.insert(&pid_tgid, &connect_args, 0)?;
-    }
+    track_drop(DropStage::MapInsert, unsafe {
+        ACTIVE_CONNECT_MAP.insert(&pid_tgid, &connect_args, 0)
+    })?;
 
     Ok(0)
 }
@@ -36,7 +39,12 @@ pub fn ret_connect(ctx: ProbeContext) -> u32 {
     try_ret_connect(ctx).unwrap_or_else(|ret| ret.try_into().unwrap_or_else(|_| 1))
 }
 
-fn try_ret_connect(ctx: ProbeContext) -> Result<u32, i64> {
+#[fexit(function = "__sys_connect")]
+pub fn fexit_connect(ctx: FExitContext) -> u32 {
+    try_ret_connect(FExit::<3>(ctx)).unwrap_or_else(|ret| ret.try_into().unwrap_or_else(|_| 1))
+}
+
+fn try_ret_connect<C: FnContext>(ctx: C) -> Result<u32, i64> {
     let pid_tgid = bpf_get_current_pid_tgid();
     let connect_args = unsafe { ACTIVE_CONNECT_MAP.get(&pid_tgid).ok_or(1)? };
     let res = process_syscall_connect(&ctx, pid_tgid, connect_args);
@@ -46,8 +54,8 @@ fn try_ret_connect(ctx: ProbeContext) -> Result<u32, i64> {
     res
 }
 
-fn process_syscall_connect(
-    ctx: &ProbeContext,
+fn process_syscall_connect<C: FnContext>(
+    ctx: &C,
     pid_tgid: u64,
     args: &types::ConnectArgs,
 ) -> Result<u32, i64> {
@@ -79,8 +87,3 @@ fn process_syscall_connect(
 
     Ok(0)
 }
-
-#[panic_handler]
-fn panic(_info: &core::panic::PanicInfo) -> ! {
-    unsafe { core::hint::unreachable_unchecked() }
-}
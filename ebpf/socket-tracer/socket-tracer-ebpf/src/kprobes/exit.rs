@@ -0,0 +1,26 @@
+use aya_ebpf::{
+    helpers::bpf_get_current_pid_tgid, macros::tracepoint, programs::TracePointContext,
+};
+
+use socket_tracer_lib::maps::{
+    ACTIVE_ACCEPT_MAP, ACTIVE_CLOSE_MAP, ACTIVE_CONNECT_MAP, ACTIVE_READ_MAP, ACTIVE_SENDFILE_MAP,
+    ACTIVE_WRITE_MAP,
+};
+
+/// Removes the syscall arguments stashed by the entry probes of a thread that exits
+/// before the matching ret probes ran, e.g. because it was killed while blocked in
+/// `read`. The tracepoint fires once per exiting thread, which is what they are keyed by.
+#[tracepoint]
+pub fn sched_process_exit(_ctx: TracePointContext) -> u32 {
+    let pid_tgid = bpf_get_current_pid_tgid();
+    // Most threads have no call in flight, so the removals fail harmlessly.
+    unsafe {
+        let _ = ACTIVE_ACCEPT_MAP.remove(&pid_tgid);
+        let _ = ACTIVE_CONNECT_MAP.remove(&pid_tgid);
+        let _ = ACTIVE_WRITE_MAP.remove(&pid_tgid);
+        let _ = ACTIVE_READ_MAP.remove(&pid_tgid);
+        let _ = ACTIVE_SENDFILE_MAP.remove(&pid_tgid);
+        let _ = ACTIVE_CLOSE_MAP.remove(&pid_tgid);
+    }
+    0
+}
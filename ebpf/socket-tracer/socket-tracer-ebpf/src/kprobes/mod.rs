@@ -0,0 +1,21 @@
+mod accept;
+mod accept4;
+mod close;
+mod connect;
+mod exit;
+mod parse;
+mod read;
+mod readv;
+mod recv;
+mod recvfrom;
+mod recvmmsg;
+mod recvmsg;
+mod send;
+mod sendfile;
+mod sendmmsg;
+mod sendmsg;
+mod sendto;
+mod sockalloc;
+mod ssendmsg;
+mod write;
+mod writev;
@@ -0,0 +1,11 @@
+use aya_ebpf::{macros::kprobe, programs::ProbeContext};
+
+use socket_tracer_lib::submit_pending_data;
+
+/// Submits the data event a kretprobe tail called in with, see `dispatch_pending_data`.
+/// Registered by userspace for every protocol in the `data_parsers` map, whose slots
+/// programs parsing more of a protocol in the kernel can take over. Never attached.
+#[kprobe]
+pub fn parse_data(ctx: ProbeContext) -> u32 {
+    submit_pending_data(&ctx).unwrap_or_else(|ret| ret.try_into().unwrap_or_else(|_| 1))
+}
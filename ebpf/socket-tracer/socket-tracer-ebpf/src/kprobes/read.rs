@@ -1,21 +1,26 @@
-#![no_std]
-#![no_main]
-
 use aya_ebpf::{
     helpers::bpf_get_current_pid_tgid,
-    macros::{kprobe, kretprobe},
-    programs::ProbeContext,
+    macros::{fentry, fexit, kprobe, kretprobe},
+    programs::{FEntryContext, FExitContext, ProbeContext},
 };
 
-use socket_tracer_common::{SourceFunction, TrafficDirection::Ingress};
-use socket_tracer_lib::{maps::ACTIVE_READ_MAP, process_syscall_data, types, types::AlignedBool};
+use socket_tracer_common::{DropStage, SourceFunction, TrafficDirection::Ingress};
+use socket_tracer_lib::{
+    context::{FnContext, SyscallEntry, SyscallExit}, dispatch_pending_data,
+    maps::ACTIVE_READ_MAP, process_syscall_data, track_drop, types, types::AlignedBool,
+};
 
 #[kprobe]
 pub fn entry_read(ctx: ProbeContext) -> u32 {
     try_entry_read(ctx).unwrap_or_else(|ret| ret.try_into().unwrap_or_else(|_| 1))
 }
 
-fn try_entry_read(ctx: ProbeContext) -> Result<u32, i64> {
+#[fentry(function = "__x64_sys_read")]
+pub fn fentry_read(ctx: FEntryContext) -> u32 {
+    try_entry_read(SyscallEntry(ctx)).unwrap_or_else(|ret| ret.try_into().unwrap_or_else(|_| 1))
+}
+
+fn try_entry_read<C: FnContext>(ctx: C) -> Result<u32, i64> {
     let fd: i32 = ctx.arg(0).ok_or(1)?;
     let buf: *mut u8 = ctx.arg(1).ok_or(1)?;
 
@@ -30,9 +35,9 @@ fn try_entry_read(ctx: ProbeContext) -> Result<u32, i64> {
         msg_len: 0,
     };
 
-    unsafe {
-        ACTIVE_READ_MAP.insert(&pid_tgid, &data_args, 0)?;
-    }
+    track_drop(DropStage::MapInsert, unsafe {
+        ACTIVE_READ_MAP.insert(&pid_tgid, &data_args, 0)
+    })?;
 
     Ok(0)
 }
@@ -42,7 +47,12 @@ pub fn ret_read(ctx: ProbeContext) -> u32 {
     try_ret_read(ctx).unwrap_or_else(|ret| ret.try_into().unwrap_or_else(|_| 1))
 }
 
-fn try_ret_read(ctx: ProbeContext) -> Result<u32, i64> {
+#[fexit(function = "__x64_sys_read")]
+pub fn fexit_read(ctx: FExitContext) -> u32 {
+    try_ret_read(SyscallExit(ctx)).unwrap_or_else(|ret| ret.try_into().unwrap_or_else(|_| 1))
+}
+
+fn try_ret_read<C: FnContext>(ctx: C) -> Result<u32, i64> {
     let pid_tgid = bpf_get_current_pid_tgid();
     let bytes_count = ctx.ret().ok_or(1)?;
     let data_args = unsafe { ACTIVE_READ_MAP.get(&pid_tgid).ok_or(1)? };
@@ -58,10 +68,6 @@ fn try_ret_read(ctx: ProbeContext) -> Result<u32, i64> {
         ACTIVE_READ_MAP.remove(&pid_tgid)?;
     }
 
-    res
-}
-
-#[panic_handler]
-fn panic(_info: &core::panic::PanicInfo) -> ! {
-    unsafe { core::hint::unreachable_unchecked() }
+    res?;
+    dispatch_pending_data(&ctx)
 }
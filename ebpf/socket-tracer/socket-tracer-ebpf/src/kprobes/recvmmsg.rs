@@ -1,17 +1,15 @@
-#![no_std]
-#![no_main]
-
 use aya_ebpf::{
     cty::ssize_t,
     helpers::{bpf_get_current_pid_tgid, bpf_probe_read_kernel},
-    macros::{kprobe, kretprobe},
-    programs::ProbeContext,
+    macros::{fentry, fexit, kprobe, kretprobe},
+    programs::{FEntryContext, FExitContext, ProbeContext},
 };
 
-use socket_tracer_common::{SourceFunction, TrafficDirection::Ingress};
+use socket_tracer_common::{DropStage, SourceFunction, TrafficDirection::Ingress};
 use socket_tracer_lib::{
+    context::{FnContext, SyscallEntry, SyscallExit}, dispatch_pending_data,
     maps::{ACTIVE_CONNECT_MAP, ACTIVE_READ_MAP},
-    process_syscall_data_vecs, types,
+    process_syscall_data_vecs, track_drop, types,
     types::AlignedBool,
     vmlinux::{mmsghdr, sockaddr},
 };
@@ -21,7 +19,12 @@ pub fn entry_recvmmsg(ctx: ProbeContext) -> u32 {
     try_entry_recvmmsg(ctx).unwrap_or_else(|ret| ret.try_into().unwrap_or_else(|_| 1))
 }
 
-fn try_entry_recvmmsg(ctx: ProbeContext) -> Result<u32, i64> {
+#[fentry(function = "__x64_sys_recvmmsg")]
+pub fn fentry_recvmmsg(ctx: FEntryContext) -> u32 {
+    try_entry_recvmmsg(SyscallEntry(ctx)).unwrap_or_else(|ret| ret.try_into().unwrap_or_else(|_| 1))
+}
+
+fn try_entry_recvmmsg<C: FnContext>(ctx: C) -> Result<u32, i64> {
     let pid_tgid = bpf_get_current_pid_tgid();
     let fd: i32 = ctx.arg(0).ok_or(1)?;
     let msgvec: *const mmsghdr = ctx.arg(1).ok_or(1)?;
@@ -35,9 +38,9 @@ fn try_entry_recvmmsg(ctx: ProbeContext) -> Result<u32, i64> {
                 sockaddr: msg_hdr.msg_name as *const sockaddr,
                 fd,
             };
-            unsafe {
-                _ = ACTIVE_CONNECT_MAP.insert(&pid_tgid, &connect_args, 0);
-            }
+            _ = track_drop(DropStage::MapInsert, unsafe {
+                ACTIVE_CONNECT_MAP.insert(&pid_tgid, &connect_args, 0)
+            });
         }
 
         let data_args = types::DataArgs {
@@ -50,9 +53,9 @@ fn try_entry_recvmmsg(ctx: ProbeContext) -> Result<u32, i64> {
             msg_len: mmsg_hdr.msg_len,
         };
 
-        unsafe {
-            ACTIVE_READ_MAP.insert(&pid_tgid, &data_args, 0)?;
-        }
+        track_drop(DropStage::MapInsert, unsafe {
+            ACTIVE_READ_MAP.insert(&pid_tgid, &data_args, 0)
+        })?;
     }
     Ok(0)
 }
@@ -62,7 +65,12 @@ pub fn ret_recvmmsg(ctx: ProbeContext) -> u32 {
     try_ret_recvmmsg(ctx).unwrap_or_else(|ret| ret.try_into().unwrap_or_else(|_| 1))
 }
 
-fn try_ret_recvmmsg(ctx: ProbeContext) -> Result<u32, i64> {
+#[fexit(function = "__x64_sys_recvmmsg")]
+pub fn fexit_recvmmsg(ctx: FExitContext) -> u32 {
+    try_ret_recvmmsg(SyscallExit(ctx)).unwrap_or_else(|ret| ret.try_into().unwrap_or_else(|_| 1))
+}
+
+fn try_ret_recvmmsg<C: FnContext>(ctx: C) -> Result<u32, i64> {
     let pid_tgid = bpf_get_current_pid_tgid();
     let num_msgs: u32 = ctx.ret().ok_or(1)?;
 
@@ -83,10 +91,6 @@ fn try_ret_recvmmsg(ctx: ProbeContext) -> Result<u32, i64> {
         ACTIVE_READ_MAP.remove(&pid_tgid)?;
     }
 
-    res
-}
-
-#[panic_handler]
-fn panic(_info: &core::panic::PanicInfo) -> ! {
-    unsafe { core::hint::unreachable_unchecked() }
+    res?;
+    dispatch_pending_data(&ctx)
 }
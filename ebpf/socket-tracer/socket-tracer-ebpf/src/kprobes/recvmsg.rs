@@ -1,17 +1,15 @@
-#![no_std]
-#![no_main]
-
 use aya_ebpf::{
     cty::ssize_t,
     helpers::{bpf_get_current_pid_tgid, bpf_probe_read_kernel},
-    macros::{kprobe, kretprobe},
-    programs::ProbeContext,
+    macros::{fentry, fexit, kprobe, kretprobe},
+    programs::{FEntryContext, FExitContext, ProbeContext},
 };
 
-use socket_tracer_common::{SourceFunction, TrafficDirection::Ingress};
+use socket_tracer_common::{DropStage, SourceFunction, TrafficDirection::Ingress};
 use socket_tracer_lib::{
+    context::{FnContext, SyscallEntry, SyscallExit}, dispatch_pending_data,
     maps::{ACTIVE_CONNECT_MAP, ACTIVE_READ_MAP},
-    process_syscall_data_vecs, types,
+    process_syscall_data_vecs, track_drop, types,
     types::AlignedBool,
     vmlinux::{iovec, sockaddr, user_msghdr},
 };
@@ -21,7 +19,12 @@ pub fn entry_recvmsg(ctx: ProbeContext) -> u32 {
     try_entry_recvmsg(ctx).unwrap_or_else(|ret| ret.try_into().unwrap_or_else(|_| 1))
 }
 
-fn try_entry_recvmsg(ctx: ProbeContext) -> Result<u32, i64> {
+#[fentry(function = "__x64_sys_recvmsg")]
+pub fn fentry_recvmsg(ctx: FEntryContext) -> u32 {
+    try_entry_recvmsg(SyscallEntry(ctx)).unwrap_or_else(|ret| ret.try_into().unwrap_or_else(|_| 1))
+}
+
+fn try_entry_recvmsg<C: FnContext>(ctx: C) -> Result<u32, i64> {
     let fd: i32 = ctx.arg(0).ok_or(1)?;
     let msghdr: *const user_msghdr = ctx.arg(1).ok_or(1)?;
     if msghdr.is_null() {
@@ -36,7 +39,10 @@ fn try_entry_recvmsg(ctx: ProbeContext) -> Result<u32, i64> {
 
         if !msg_name_ptr.is_null() {
             let connect_args = types::ConnectArgs { sockaddr, fd };
-            _ = ACTIVE_CONNECT_MAP.insert(&pid_tgid, &connect_args, 0);
+            _ = track_drop(
+                DropStage::MapInsert,
+                ACTIVE_CONNECT_MAP.insert(&pid_tgid, &connect_args, 0),
+            );
         }
     }
 
@@ -53,7 +59,10 @@ fn try_entry_recvmsg(ctx: ProbeContext) -> Result<u32, i64> {
             msg_len: 0,
         };
 
-        ACTIVE_READ_MAP.insert(&pid_tgid, &data_args, 0)?;
+        track_drop(
+            DropStage::MapInsert,
+            ACTIVE_READ_MAP.insert(&pid_tgid, &data_args, 0),
+        )?;
     }
 
     Ok(0)
@@ -64,7 +73,12 @@ pub fn ret_recvmsg(ctx: ProbeContext) -> u32 {
     try_ret_recvmsg(ctx).unwrap_or_else(|ret| ret.try_into().unwrap_or_else(|_| 1))
 }
 
-fn try_ret_recvmsg(ctx: ProbeContext) -> Result<u32, i64> {
+#[fexit(function = "__x64_sys_recvmsg")]
+pub fn fexit_recvmsg(ctx: FExitContext) -> u32 {
+    try_ret_recvmsg(SyscallExit(ctx)).unwrap_or_else(|ret| ret.try_into().unwrap_or_else(|_| 1))
+}
+
+fn try_ret_recvmsg<C: FnContext>(ctx: C) -> Result<u32, i64> {
     let pid_tgid = bpf_get_current_pid_tgid();
     let bytes_count: ssize_t = ctx.ret().ok_or(1)?;
 
@@ -83,10 +97,6 @@ fn try_ret_recvmsg(ctx: ProbeContext) -> Result<u32, i64> {
         ACTIVE_READ_MAP.remove(&pid_tgid)?;
     }
 
-    res
-}
-
-#[panic_handler]
-fn panic(_info: &core::panic::PanicInfo) -> ! {
-    unsafe { core::hint::unreachable_unchecked() }
+    res?;
+    dispatch_pending_data(&ctx)
 }
@@ -1,22 +1,27 @@
-#![no_std]
-#![no_main]
-
 use aya_ebpf::{
     cty::ssize_t,
     helpers::bpf_get_current_pid_tgid,
-    macros::{kprobe, kretprobe},
-    programs::ProbeContext,
+    macros::{fentry, fexit, kprobe, kretprobe},
+    programs::{FEntryContext, FExitContext, ProbeContext},
 };
 
-use socket_tracer_common::{SourceFunction, TrafficDirection::Egress};
-use socket_tracer_lib::{maps::ACTIVE_WRITE_MAP, process_syscall_data, types, types::AlignedBool};
+use socket_tracer_common::{DropStage, SourceFunction, TrafficDirection::Egress};
+use socket_tracer_lib::{
+    context::{FnContext, SyscallEntry, SyscallExit}, dispatch_pending_data,
+    maps::ACTIVE_WRITE_MAP, process_syscall_data, track_drop, types, types::AlignedBool,
+};
 
 #[kprobe]
 pub fn entry_send(ctx: ProbeContext) -> u32 {
     try_entry_send(ctx).unwrap_or_else(|ret| ret.try_into().unwrap_or_else(|_| 1))
 }
 
-fn try_entry_send(ctx: ProbeContext) -> Result<u32, i64> {
+#[fentry(function = "__x64_sys_send")]
+pub fn fentry_send(ctx: FEntryContext) -> u32 {
+    try_entry_send(SyscallEntry(ctx)).unwrap_or_else(|ret| ret.try_into().unwrap_or_else(|_| 1))
+}
+
+fn try_entry_send<C: FnContext>(ctx: C) -> Result<u32, i64> {
     let fd: i32 = ctx.arg(0).ok_or(1)?;
     let buf: *const u8 = ctx.arg(1).ok_or(1)?;
 
@@ -31,9 +36,9 @@ fn try_entry_send(ctx: ProbeContext) -> Result<u32, i64> {
         msg_len: 0,
     };
 
-    unsafe {
-        ACTIVE_WRITE_MAP.insert(&pid_tgid, &data_args, 0)?;
-    }
+    track_drop(DropStage::MapInsert, unsafe {
+        ACTIVE_WRITE_MAP.insert(&pid_tgid, &data_args, 0)
+    })?;
 
     Ok(0)
 }
@@ -43,7 +48,12 @@ pub fn ret_send(ctx: ProbeContext) -> u32 {
     try_ret_send(ctx).unwrap_or_else(|ret| ret.try_into().unwrap_or_else(|_| 1))
 }
 
-fn try_ret_send(ctx: ProbeContext) -> Result<u32, i64> {
+#[fexit(function = "__x64_sys_send")]
+pub fn fexit_send(ctx: FExitContext) -> u32 {
+    try_ret_send(SyscallExit(ctx)).unwrap_or_else(|ret| ret.try_into().unwrap_or_else(|_| 1))
+}
+
+fn try_ret_send<C: FnContext>(ctx: C) -> Result<u32, i64> {
     let pid_tgid = bpf_get_current_pid_tgid();
     let bytes_count: ssize_t = ctx.ret().ok_or(1)?;
 
@@ -54,10 +64,6 @@ fn try_ret_send(ctx: ProbeContext) -> Result<u32, i64> {
         ACTIVE_WRITE_MAP.remove(&pid_tgid)?;
     }
 
-    res
-}
-
-#[panic_handler]
-fn panic(_info: &core::panic::PanicInfo) -> ! {
-    unsafe { core::hint::unreachable_unchecked() }
+    res?;
+    dispatch_pending_data(&ctx)
 }
@@ -1,22 +1,22 @@
-#![no_std]
-#![no_main]
-
 use core::mem;
 
 use aya_ebpf::{
     cty::{size_t, ssize_t},
     helpers::bpf_get_current_pid_tgid,
-    macros::{kprobe, kretprobe},
-    programs::ProbeContext,
+    macros::{fentry, fexit, kprobe, kretprobe},
+    programs::{FEntryContext, FExitContext, ProbeContext},
 };
 
-use socket_tracer_common::{SocketDataEventInner, SourceFunction, TrafficDirection::Egress};
+use socket_tracer_common::{
+    DropStage, SocketDataEventInner, SourceFunction, TrafficDirection::Egress,
+};
 use socket_tracer_lib::{
+    context::{FnContext, SyscallEntry, SyscallExit},
     filters::should_trace_conn,
     gen_tgid_fd, get_or_create_conn_info,
     maps::{ACTIVE_SENDFILE_MAP, CONN_DISABLED_MAP, SOCKET_DATA_EVENTS},
-    match_trace_tgid, populate_socket_data_event, should_send_data, TargetTgidMatchResult, types,
-    update_conn_stats,
+    match_trace_tgid, populate_socket_data_event, should_send_data, track_drop, types,
+    update_conn_stats, TargetTgidMatchResult,
 };
 
 #[kprobe]
@@ -24,7 +24,12 @@ pub fn entry_sendfile(ctx: ProbeContext) -> u32 {
     try_entry_sendfile(ctx).unwrap_or_else(|ret| ret.try_into().unwrap_or_else(|_| 1))
 }
 
-fn try_entry_sendfile(ctx: ProbeContext) -> Result<u32, i64> {
+#[fentry(function = "__x64_sys_sendfile")]
+pub fn fentry_sendfile(ctx: FEntryContext) -> u32 {
+    try_entry_sendfile(SyscallEntry(ctx)).unwrap_or_else(|ret| ret.try_into().unwrap_or_else(|_| 1))
+}
+
+fn try_entry_sendfile<C: FnContext>(ctx: C) -> Result<u32, i64> {
     let out_fd: i32 = ctx.arg(0).ok_or(1)?;
     let in_fd: i32 = ctx.arg(1).ok_or(1)?;
     let count: size_t = ctx.arg(3).ok_or(1)?;
@@ -36,9 +41,9 @@ fn try_entry_sendfile(ctx: ProbeContext) -> Result<u32, i64> {
         count,
     };
 
-    unsafe {
-        ACTIVE_SENDFILE_MAP.insert(&pid_tgid, &sendfile_args, 0)?;
-    }
+    track_drop(DropStage::MapInsert, unsafe {
+        ACTIVE_SENDFILE_MAP.insert(&pid_tgid, &sendfile_args, 0)
+    })?;
 
     Ok(0)
 }
@@ -48,7 +53,12 @@ pub fn ret_sendfile(ctx: ProbeContext) -> u32 {
     try_ret_sendfile(ctx).unwrap_or_else(|ret| ret.try_into().unwrap_or_else(|_| 1))
 }
 
-fn try_ret_sendfile(ctx: ProbeContext) -> Result<u32, i64> {
+#[fexit(function = "__x64_sys_sendfile")]
+pub fn fexit_sendfile(ctx: FExitContext) -> u32 {
+    try_ret_sendfile(SyscallExit(ctx)).unwrap_or_else(|ret| ret.try_into().unwrap_or_else(|_| 1))
+}
+
+fn try_ret_sendfile<C: FnContext>(ctx: C) -> Result<u32, i64> {
     let pid_tgid = bpf_get_current_pid_tgid();
     let bytes_count: ssize_t = ctx.ret().ok_or(1)?;
     let sendfile_args = unsafe { ACTIVE_SENDFILE_MAP.get(&pid_tgid).ok_or(1)? };
@@ -61,8 +71,8 @@ fn try_ret_sendfile(ctx: ProbeContext) -> Result<u32, i64> {
     Ok(0)
 }
 
-fn process_syscall_sendfile(
-    ctx: &ProbeContext,
+fn process_syscall_sendfile<C: FnContext>(
+    ctx: &C,
     id: u64,
     args: &types::SendfileArgs,
     bytes_count: ssize_t,
@@ -102,18 +112,13 @@ fn process_syscall_sendfile(
         event.inner.position = conn_info.write_bytes as u64;
         event.inner.msg_size = bytes_count as u32;
         event.inner.msg_buf_size = 0;
-        unsafe {
-            let data_size = mem::size_of::<SocketDataEventInner>() as u64;
-            SOCKET_DATA_EVENTS.output_with_size(ctx, event, data_size, 0);
-        }
+        let data_size = mem::size_of::<SocketDataEventInner>() as u64;
+        let _ = track_drop(DropStage::PerfOutput, unsafe {
+            SOCKET_DATA_EVENTS.output_with_size(ctx, event, data_size, 0)
+        });
     }
 
     update_conn_stats(ctx, &mut conn_info, Egress, bytes_count)?;
 
     Ok(0)
 }
-
-#[panic_handler]
-fn panic(_info: &core::panic::PanicInfo) -> ! {
-    unsafe { core::hint::unreachable_unchecked() }
-}
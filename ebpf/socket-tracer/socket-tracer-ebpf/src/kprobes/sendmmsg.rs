@@ -1,17 +1,15 @@
-#![no_std]
-#![no_main]
-
 use aya_ebpf::{
     cty::ssize_t,
     helpers::{bpf_get_current_pid_tgid, bpf_probe_read_kernel},
-    macros::{kprobe, kretprobe},
-    programs::ProbeContext,
+    macros::{fentry, fexit, kprobe, kretprobe},
+    programs::{FEntryContext, FExitContext, ProbeContext},
 };
 
-use socket_tracer_common::{SourceFunction, TrafficDirection::Egress};
+use socket_tracer_common::{DropStage, SourceFunction, TrafficDirection::Egress};
 use socket_tracer_lib::{
+    context::{FnContext, SyscallEntry, SyscallExit}, dispatch_pending_data,
     maps::{ACTIVE_CONNECT_MAP, ACTIVE_WRITE_MAP},
-    process_syscall_data_vecs, types,
+    process_syscall_data_vecs, track_drop, types,
     types::AlignedBool,
     vmlinux::{mmsghdr, sockaddr},
 };
@@ -21,7 +19,12 @@ pub fn entry_sendmmsg(ctx: ProbeContext) -> u32 {
     try_entry_sendmmsg(ctx).unwrap_or_else(|ret| ret.try_into().unwrap_or_else(|_| 1))
 }
 
-fn try_entry_sendmmsg(ctx: ProbeContext) -> Result<u32, i64> {
+#[fentry(function = "__x64_sys_sendmmsg")]
+pub fn fentry_sendmmsg(ctx: FEntryContext) -> u32 {
+    try_entry_sendmmsg(SyscallEntry(ctx)).unwrap_or_else(|ret| ret.try_into().unwrap_or_else(|_| 1))
+}
+
+fn try_entry_sendmmsg<C: FnContext>(ctx: C) -> Result<u32, i64> {
     let pid_tgid = bpf_get_current_pid_tgid();
     let fd: i32 = ctx.arg(0).ok_or(1)?;
     let msgvec: *const mmsghdr = ctx.arg(1).ok_or(1)?;
@@ -36,9 +39,9 @@ fn try_entry_sendmmsg(ctx: ProbeContext) -> Result<u32, i64> {
                 fd,
             };
 
-            unsafe {
-                _ = ACTIVE_CONNECT_MAP.insert(&pid_tgid, &connect_args, 0);
-            }
+            _ = track_drop(DropStage::MapInsert, unsafe {
+                ACTIVE_CONNECT_MAP.insert(&pid_tgid, &connect_args, 0)
+            });
         }
 
         let data_args = types::DataArgs {
@@ -51,9 +54,9 @@ fn try_entry_sendmmsg(ctx: ProbeContext) -> Result<u32, i64> {
             msg_len: mmsg_hdr.msg_len,
         };
 
-        unsafe {
-            ACTIVE_WRITE_MAP.insert(&pid_tgid, &data_args, 0)?;
-        }
+        track_drop(DropStage::MapInsert, unsafe {
+            ACTIVE_WRITE_MAP.insert(&pid_tgid, &data_args, 0)
+        })?;
     }
     Ok(0)
 }
@@ -63,7 +66,12 @@ pub fn ret_sendmmsg(ctx: ProbeContext) -> u32 {
     try_ret_sendmmsg(ctx).unwrap_or_else(|ret| ret.try_into().unwrap_or_else(|_| 1))
 }
 
-fn try_ret_sendmmsg(ctx: ProbeContext) -> Result<u32, i64> {
+#[fexit(function = "__x64_sys_sendmmsg")]
+pub fn fexit_sendmmsg(ctx: FExitContext) -> u32 {
+    try_ret_sendmmsg(SyscallExit(ctx)).unwrap_or_else(|ret| ret.try_into().unwrap_or_else(|_| 1))
+}
+
+fn try_ret_sendmmsg<C: FnContext>(ctx: C) -> Result<u32, i64> {
     let pid_tgid = bpf_get_current_pid_tgid();
     let num_msgs: u32 = ctx.ret().ok_or(1)?;
 
@@ -84,10 +92,6 @@ fn try_ret_sendmmsg(ctx: ProbeContext) -> Result<u32, i64> {
         ACTIVE_WRITE_MAP.remove(&pid_tgid)?;
     }
 
-    res
-}
-
-#[panic_handler]
-fn panic(_info: &core::panic::PanicInfo) -> ! {
-    unsafe { core::hint::unreachable_unchecked() }
+    res?;
+    dispatch_pending_data(&ctx)
 }
@@ -1,16 +1,14 @@
-#![no_std]
-#![no_main]
-
 use aya_ebpf::{
     helpers::{bpf_get_current_pid_tgid, bpf_probe_read_kernel},
-    macros::{kprobe, kretprobe},
-    programs::ProbeContext,
+    macros::{fentry, fexit, kprobe, kretprobe},
+    programs::{FEntryContext, FExitContext, ProbeContext},
 };
 
-use socket_tracer_common::{SourceFunction, TrafficDirection::Egress};
+use socket_tracer_common::{DropStage, SourceFunction, TrafficDirection::Egress};
 use socket_tracer_lib::{
+    context::{FnContext, SyscallEntry, SyscallExit}, dispatch_pending_data,
     maps::{ACTIVE_CONNECT_MAP, ACTIVE_WRITE_MAP},
-    process_syscall_data_vecs, types,
+    process_syscall_data_vecs, track_drop, types,
     types::AlignedBool,
     vmlinux::{iovec, sockaddr, user_msghdr},
 };
@@ -20,7 +18,12 @@ pub fn entry_sendmsg(ctx: ProbeContext) -> u32 {
     try_entry_sendmsg(ctx).unwrap_or_else(|ret| ret.try_into().unwrap_or_else(|_| 1))
 }
 
-fn try_entry_sendmsg(ctx: ProbeContext) -> Result<u32, i64> {
+#[fentry(function = "__x64_sys_sendmsg")]
+pub fn fentry_sendmsg(ctx: FEntryContext) -> u32 {
+    try_entry_sendmsg(SyscallEntry(ctx)).unwrap_or_else(|ret| ret.try_into().unwrap_or_else(|_| 1))
+}
+
+fn try_entry_sendmsg<C: FnContext>(ctx: C) -> Result<u32, i64> {
     let fd: i32 = ctx.arg(0).ok_or(1)?;
     let msghdr: *const user_msghdr = ctx.arg(1).ok_or(1)?;
     let pid_tgid = bpf_get_current_pid_tgid();
@@ -37,7 +40,10 @@ fn try_entry_sendmsg(ctx: ProbeContext) -> Result<u32, i64> {
 
         if !msg_name_ptr.is_null() {
             let connect_args = types::ConnectArgs { sockaddr, fd };
-            _ = ACTIVE_CONNECT_MAP.insert(&pid_tgid, &connect_args, 0);
+            _ = track_drop(
+                DropStage::MapInsert,
+                ACTIVE_CONNECT_MAP.insert(&pid_tgid, &connect_args, 0),
+            );
         }
     }
 
@@ -54,7 +60,10 @@ fn try_entry_sendmsg(ctx: ProbeContext) -> Result<u32, i64> {
             msg_len: 0,
         };
 
-        ACTIVE_WRITE_MAP.insert(&pid_tgid, &data_args, 0)?;
+        track_drop(
+            DropStage::MapInsert,
+            ACTIVE_WRITE_MAP.insert(&pid_tgid, &data_args, 0),
+        )?;
     }
 
     Ok(0)
@@ -65,7 +74,12 @@ pub fn ret_sendmsg(ctx: ProbeContext) -> u32 {
     try_ret_sendmsg(ctx).unwrap_or_else(|ret| ret.try_into().unwrap_or_else(|_| 1))
 }
 
-fn try_ret_sendmsg(ctx: ProbeContext) -> Result<u32, i64> {
+#[fexit(function = "__x64_sys_sendmsg")]
+pub fn fexit_sendmsg(ctx: FExitContext) -> u32 {
+    try_ret_sendmsg(SyscallExit(ctx)).unwrap_or_else(|ret| ret.try_into().unwrap_or_else(|_| 1))
+}
+
+fn try_ret_sendmsg<C: FnContext>(ctx: C) -> Result<u32, i64> {
     let pid_tgid = bpf_get_current_pid_tgid();
     let bytes_count = ctx.ret().ok_or(1)?;
 
@@ -84,10 +98,6 @@ fn try_ret_sendmsg(ctx: ProbeContext) -> Result<u32, i64> {
         ACTIVE_WRITE_MAP.remove(&pid_tgid)?;
     }
 
-    res
-}
-
-#[panic_handler]
-fn panic(_info: &core::panic::PanicInfo) -> ! {
-    unsafe { core::hint::unreachable_unchecked() }
+    res?;
+    dispatch_pending_data(&ctx)
 }
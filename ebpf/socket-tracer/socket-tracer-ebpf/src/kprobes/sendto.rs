@@ -1,17 +1,15 @@
-#![no_std]
-#![no_main]
-
 use aya_ebpf::{
     cty::ssize_t,
     helpers::bpf_get_current_pid_tgid,
-    macros::{kprobe, kretprobe},
-    programs::ProbeContext,
+    macros::{fentry, fexit, kprobe, kretprobe},
+    programs::{FEntryContext, FExitContext, ProbeContext},
 };
 
-use socket_tracer_common::{SourceFunction, TrafficDirection::Egress};
+use socket_tracer_common::{DropStage, SourceFunction, TrafficDirection::Egress};
 use socket_tracer_lib::{
+    context::{FnContext, SyscallEntry, SyscallExit}, dispatch_pending_data,
     maps::{ACTIVE_CONNECT_MAP, ACTIVE_WRITE_MAP},
-    process_syscall_data, types,
+    process_syscall_data, track_drop, types,
     types::AlignedBool,
     vmlinux::sockaddr,
 };
@@ -21,7 +19,12 @@ pub fn entry_sendto(ctx: ProbeContext) -> u32 {
     try_entry_sendto(ctx).unwrap_or_else(|ret| ret.try_into().unwrap_or_else(|_| 1))
 }
 
-fn try_entry_sendto(ctx: ProbeContext) -> Result<u32, i64> {
+#[fentry(function = "__x64_sys_sendto")]
+pub fn fentry_sendto(ctx: FEntryContext) -> u32 {
+    try_entry_sendto(SyscallEntry(ctx)).unwrap_or_else(|ret| ret.try_into().unwrap_or_else(|_| 1))
+}
+
+fn try_entry_sendto<C: FnContext>(ctx: C) -> Result<u32, i64> {
     let fd: i32 = ctx.arg(0).ok_or(1)?;
     let buf: *const u8 = ctx.arg(1).ok_or(1)?;
     let dest_addr: *const sockaddr = ctx.arg(4).ok_or(1)?;
@@ -33,9 +36,9 @@ fn try_entry_sendto(ctx: ProbeContext) -> Result<u32, i64> {
             sockaddr: dest_addr,
         };
 
-        unsafe {
-            ACTIVE_CONNECT_MAP.insert(&pid_tgid, &connect_args, 0)?;
-        }
+        track_drop(DropStage::MapInsert, unsafe {
+            ACTIVE_CONNECT_MAP.insert(&pid_tgid, &connect_args, 0)
+        })?;
     }
 
     let pid_tgid = bpf_get_current_pid_tgid();
@@ -49,9 +52,9 @@ fn try_entry_sendto(ctx: ProbeContext) -> Result<u32, i64> {
         msg_len: 0,
     };
 
-    unsafe {
-        ACTIVE_WRITE_MAP.insert(&pid_tgid, &data_args, 0)?;
-    }
+    track_drop(DropStage::MapInsert, unsafe {
+        ACTIVE_WRITE_MAP.insert(&pid_tgid, &data_args, 0)
+    })?;
 
     Ok(0)
 }
@@ -61,7 +64,12 @@ pub fn ret_sendto(ctx: ProbeContext) -> u32 {
     try_ret_sendto(ctx).unwrap_or_else(|ret| ret.try_into().unwrap_or_else(|_| 1))
 }
 
-fn try_ret_sendto(ctx: ProbeContext) -> Result<u32, i64> {
+#[fexit(function = "__x64_sys_sendto")]
+pub fn fexit_sendto(ctx: FExitContext) -> u32 {
+    try_ret_sendto(SyscallExit(ctx)).unwrap_or_else(|ret| ret.try_into().unwrap_or_else(|_| 1))
+}
+
+fn try_ret_sendto<C: FnContext>(ctx: C) -> Result<u32, i64> {
     let pid_tgid = bpf_get_current_pid_tgid();
     let bytes_count: ssize_t = ctx.ret().ok_or(1)?;
 
@@ -80,10 +88,6 @@ fn try_ret_sendto(ctx: ProbeContext) -> Result<u32, i64> {
         ACTIVE_WRITE_MAP.remove(&pid_tgid)?;
     }
 
-    res
-}
-
-#[panic_handler]
-fn panic(_info: &core::panic::PanicInfo) -> ! {
-    unsafe { core::hint::unreachable_unchecked() }
+    res?;
+    dispatch_pending_data(&ctx)
 }
@@ -1,20 +1,26 @@
-#![no_std]
-#![no_main]
-
 use aya_ebpf::{
     helpers::bpf_get_current_pid_tgid,
-    macros::kretprobe,
-    programs::ProbeContext,
+    macros::{fexit, kretprobe},
+    programs::{FExitContext, ProbeContext},
 };
 
-use socket_tracer_lib::{maps::ACTIVE_ACCEPT_MAP, vmlinux::sock};
+use socket_tracer_lib::{
+    context::{FExit, FnContext},
+    maps::ACTIVE_ACCEPT_MAP,
+    vmlinux::sock,
+};
 
 #[kretprobe]
 pub fn ret_sock_alloc(ctx: ProbeContext) -> u32 {
     try_ret_sock_alloc(ctx).unwrap_or_else(|ret| ret.try_into().unwrap_or_else(|_| 1))
 }
 
-fn try_ret_sock_alloc(ctx: ProbeContext) -> Result<u32, i64> {
+#[fexit(function = "sock_alloc")]
+pub fn fexit_sock_alloc(ctx: FExitContext) -> u32 {
+    try_ret_sock_alloc(FExit::<0>(ctx)).unwrap_or_else(|ret| ret.try_into().unwrap_or_else(|_| 1))
+}
+
+fn try_ret_sock_alloc<C: FnContext>(ctx: C) -> Result<u32, i64> {
     let pid_tgid = bpf_get_current_pid_tgid();
     let sk: *const sock = ctx.ret().ok_or(1)?;
     let accept_args = unsafe { ACTIVE_ACCEPT_MAP.get_ptr_mut(&pid_tgid).ok_or(1)? };
@@ -25,8 +31,3 @@ fn try_ret_sock_alloc(ctx: ProbeContext) -> Result<u32, i64> {
     }
     Ok(0)
 }
-
-#[panic_handler]
-fn panic(_info: &core::panic::PanicInfo) -> ! {
-    unsafe { core::hint::unreachable_unchecked() }
-}
@@ -1,9 +1,11 @@
-#![no_std]
-#![no_main]
-
-use aya_ebpf::{helpers::bpf_get_current_pid_tgid, macros::kprobe, programs::ProbeContext};
+use aya_ebpf::{
+    helpers::bpf_get_current_pid_tgid,
+    macros::{fentry, kprobe},
+    programs::{FEntryContext, ProbeContext},
+};
 
 use socket_tracer_lib::{
+    context::{FEntry, FnContext},
     maps::{ACTIVE_READ_MAP, ACTIVE_WRITE_MAP},
     types::AlignedBool,
 };
@@ -14,7 +16,13 @@ pub fn entry_security_socket_sendmsg(ctx: ProbeContext) -> u32 {
         .unwrap_or_else(|ret| ret.try_into().unwrap_or_else(|_| 1))
 }
 
-fn try_entry_security_socket_sendmsg(ctx: ProbeContext) -> Result<u32, i64> {
+#[fentry(function = "security_socket_sendmsg")]
+pub fn fentry_security_socket_sendmsg(ctx: FEntryContext) -> u32 {
+    try_entry_security_socket_sendmsg(FEntry(ctx))
+        .unwrap_or_else(|ret| ret.try_into().unwrap_or_else(|_| 1))
+}
+
+fn try_entry_security_socket_sendmsg<C: FnContext>(ctx: C) -> Result<u32, i64> {
     let pid_tgid = bpf_get_current_pid_tgid();
     let data_args = unsafe { ACTIVE_WRITE_MAP.get_ptr_mut(&pid_tgid).ok_or(1)? };
 
@@ -31,7 +39,13 @@ pub fn entry_security_socket_recvmsg(ctx: ProbeContext) -> u32 {
         .unwrap_or_else(|ret| ret.try_into().unwrap_or_else(|_| 1))
 }
 
-fn try_entry_security_socket_recvmsg(ctx: ProbeContext) -> Result<u32, i64> {
+#[fentry(function = "security_socket_recvmsg")]
+pub fn fentry_security_socket_recvmsg(ctx: FEntryContext) -> u32 {
+    try_entry_security_socket_recvmsg(FEntry(ctx))
+        .unwrap_or_else(|ret| ret.try_into().unwrap_or_else(|_| 1))
+}
+
+fn try_entry_security_socket_recvmsg<C: FnContext>(ctx: C) -> Result<u32, i64> {
     let pid_tgid = bpf_get_current_pid_tgid();
     let data_args = unsafe { ACTIVE_READ_MAP.get_ptr_mut(&pid_tgid).ok_or(1)? };
 
@@ -41,8 +55,3 @@ fn try_entry_security_socket_recvmsg(ctx: ProbeContext) -> Result<u32, i64> {
 
     Ok(0)
 }
-
-#[panic_handler]
-fn panic(_info: &core::panic::PanicInfo) -> ! {
-    unsafe { core::hint::unreachable_unchecked() }
-}
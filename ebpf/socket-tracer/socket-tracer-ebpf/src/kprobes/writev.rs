@@ -1,15 +1,14 @@
-#![no_std]
-#![no_main]
-
 use aya_ebpf::{
     helpers::bpf_get_current_pid_tgid,
-    macros::{kprobe, kretprobe},
-    programs::ProbeContext,
+    macros::{fentry, fexit, kprobe, kretprobe},
+    programs::{FEntryContext, FExitContext, ProbeContext},
 };
 
-use socket_tracer_common::{SourceFunction, TrafficDirection::Egress};
+use socket_tracer_common::{DropStage, SourceFunction, TrafficDirection::Egress};
 use socket_tracer_lib::{
-    maps::ACTIVE_WRITE_MAP, process_syscall_data_vecs, types, types::AlignedBool, vmlinux::iovec,
+    context::{FnContext, SyscallEntry, SyscallExit}, dispatch_pending_data,
+    maps::ACTIVE_WRITE_MAP, process_syscall_data_vecs, track_drop, types, types::AlignedBool,
+    vmlinux::iovec,
 };
 
 #[kprobe]
@@ -17,7 +16,12 @@ pub fn entry_writev(ctx: ProbeContext) -> u32 {
     try_entry_writev(ctx).unwrap_or_else(|ret| ret.try_into().unwrap_or_else(|_| 1))
 }
 
-fn try_entry_writev(ctx: ProbeContext) -> Result<u32, i64> {
+#[fentry(function = "__x64_sys_writev")]
+pub fn fentry_writev(ctx: FEntryContext) -> u32 {
+    try_entry_writev(SyscallEntry(ctx)).unwrap_or_else(|ret| ret.try_into().unwrap_or_else(|_| 1))
+}
+
+fn try_entry_writev<C: FnContext>(ctx: C) -> Result<u32, i64> {
     let fd: i32 = ctx.arg(0).ok_or(1)?;
     let iov: *mut iovec = ctx.arg(1).ok_or(1)?;
     let iovlen: u64 = ctx.arg(2).ok_or(1)?;
@@ -33,9 +37,9 @@ fn try_entry_writev(ctx: ProbeContext) -> Result<u32, i64> {
         msg_len: 0,
     };
 
-    unsafe {
-        ACTIVE_WRITE_MAP.insert(&pid_tgid, &data_args, 0)?;
-    }
+    track_drop(DropStage::MapInsert, unsafe {
+        ACTIVE_WRITE_MAP.insert(&pid_tgid, &data_args, 0)
+    })?;
 
     Ok(0)
 }
@@ -45,7 +49,12 @@ pub fn ret_writev(ctx: ProbeContext) -> u32 {
     try_ret_writev(ctx).unwrap_or_else(|ret| ret.try_into().unwrap_or_else(|_| 1))
 }
 
-fn try_ret_writev(ctx: ProbeContext) -> Result<u32, i64> {
+#[fexit(function = "__x64_sys_writev")]
+pub fn fexit_writev(ctx: FExitContext) -> u32 {
+    try_ret_writev(SyscallExit(ctx)).unwrap_or_else(|ret| ret.try_into().unwrap_or_else(|_| 1))
+}
+
+fn try_ret_writev<C: FnContext>(ctx: C) -> Result<u32, i64> {
     let pid_tgid = bpf_get_current_pid_tgid();
     let bytes_count = ctx.ret().ok_or(1)?;
     let data_args = unsafe { ACTIVE_WRITE_MAP.get(&pid_tgid).ok_or(1)? };
@@ -58,10 +67,5 @@ fn try_ret_writev(ctx: ProbeContext) -> Result<u32, i64> {
         ACTIVE_WRITE_MAP.remove(&pid_tgid)?;
     }
 
-    Ok(0)
-}
-
-#[panic_handler]
-fn panic(_info: &core::panic::PanicInfo) -> ! {
-    unsafe { core::hint::unreachable_unchecked() }
+    dispatch_pending_data(&ctx)
 }
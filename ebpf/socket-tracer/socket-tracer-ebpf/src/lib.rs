@@ -1,38 +1,46 @@
 #![no_std]
 #![no_main]
 
-use core::{cmp::PartialEq, fmt::Debug};
+use core::{
+    cmp::PartialEq,
+    fmt::Debug,
+    sync::atomic::{AtomicU64, Ordering},
+};
 
 use aya_ebpf::{
     cty::ssize_t,
     helpers::{
         bpf_ktime_get_ns, bpf_probe_read_kernel, bpf_probe_read_user, bpf_probe_read_user_buf,
     },
-    programs::ProbeContext,
+    EbpfContext,
 };
 use aya_log_ebpf::debug;
 
 use helpers::get_tgid_start_time;
 use socket_tracer_common::{
-    AF_INET, AF_INET6, AF_UNKNOWN, CHUNK_LIMIT, CONN_STATS_DATA_THRESHOLD, ConnId,
-    ConnInfo, ConnStatsEvent, ControlEventType, ControlValueIndex, EndpointRole,
-    LOOP_LIMIT,
+    AF_INET, AF_INET6, AF_UNIX, AF_UNKNOWN, AF_VSOCK, CHUNK_LIMIT, CONN_STATS_DATA_THRESHOLD,
+    ConnId, ConnInfo, ConnStatsEvent, ControlEventType, ControlValueIndex, DEFAULT_SNAP_LEN,
+    DropStage, EndpointRole, LOOP_LIMIT,
     MAX_MSG_SIZE,
-    MessageType, PROTOCOL_VEC_LIMIT, SocketControlEvent, SocketDataEvent, SocketDataEventInner, SourceFunction, TrafficDirection,
-    TrafficDirection::{Egress, Ingress}, TrafficProtocol, Uid,
+    MessageType, NetEndian, PROTOCOL_VEC_LIMIT, SocketControlEvent, SocketDataEvent, SocketDataEventInner, SourceFunction, TARGET_TGID_SET, TrafficDirection,
+    TrafficDirection::{Egress, Ingress}, TrafficProtocol, Uid, UNIX_PATH_SIZE,
 };
 
 use crate::{
+    context::FnContext,
     filters::{
         is_self_tgid, should_trace_conn, should_trace_protocol_data, should_trace_sockaddr_family,
     },
     maps::{
-        CONN_DISABLED_MAP, CONN_INFO_MAP, CONN_STATS_EVENT_BUFFER, CONN_STATS_EVENTS,
-        CONTROL_VALUES, SOCKET_CONTROL_EVENTS, SOCKET_DATA_EVENT_BUFFER, SOCKET_DATA_EVENTS,
+        CONN_DISABLED_MAP, CONN_ID_GENERATOR, CONN_INFO_MAP, CONN_STATS_EVENT_BUFFER,
+        CONN_STATS_EVENTS, CONTROL_VALUES, DATA_PARSERS, DROP_STATS, PENDING_DATA,
+        SOCKET_CONTROL_EVENT_BUFFER, SOCKET_CONTROL_EVENTS, SOCKET_DATA_EVENT_BUFFER,
+        SOCKET_DATA_EVENTS, TARGET_TGIDS,
     },
     vmlinux::{iovec, sock, sock_common, sockaddr, sockaddr_in, sockaddr_in6},
 };
 
+pub mod context;
 pub mod filters;
 pub mod helpers;
 pub mod maps;
@@ -58,7 +66,13 @@ pub fn match_trace_tgid(tgid: u32) -> TargetTgidMatchResult {
     let target_tgid_val = unsafe { CONTROL_VALUES.get(idx) };
     match target_tgid_val {
         Some(&target_tgid) => {
-            if target_tgid <= 0 {
+            if target_tgid == TARGET_TGID_SET {
+                if unsafe { TARGET_TGIDS.get(&tgid) }.is_some() {
+                    TargetTgidMatchResult::Matched
+                } else {
+                    TargetTgidMatchResult::Unmatched
+                }
+            } else if target_tgid <= 0 {
                 TargetTgidMatchResult::All
             } else if target_tgid as u32 == tgid {
                 TargetTgidMatchResult::Matched
@@ -70,8 +84,33 @@ pub fn match_trace_tgid(tgid: u32) -> TargetTgidMatchResult {
     }
 }
 
-pub fn update_traffic_class(
-    _ctx: &ProbeContext,
+/// Returns how many bytes of each message are captured, as set by userspace. Unset, it
+/// is [`DEFAULT_SNAP_LEN`]; it never exceeds [`MAX_MSG_SIZE`].
+pub fn snap_len() -> usize {
+    let idx = ControlValueIndex::SnapLenIndex as u32;
+    match unsafe { CONTROL_VALUES.get(idx) } {
+        Some(&snap_len) if snap_len > 0 => (snap_len as usize).min(MAX_MSG_SIZE),
+        _ => DEFAULT_SNAP_LEN,
+    }
+}
+
+/// Bumps the per-CPU drop counter for `stage`.
+pub fn record_drop(stage: DropStage) {
+    if let Some(counter) = unsafe { DROP_STATS.get_ptr_mut(stage as u32) } {
+        unsafe { *counter += 1 };
+    }
+}
+
+/// Counts a failed map update or perf submission against `stage` and passes the result through.
+pub fn track_drop<T>(stage: DropStage, res: Result<T, i64>) -> Result<T, i64> {
+    if res.is_err() {
+        record_drop(stage);
+    }
+    res
+}
+
+pub fn update_traffic_class<C: EbpfContext>(
+    _ctx: &C,
     conn_info: &mut ConnInfo,
     direction: TrafficDirection,
     buf_ptr: *const u8,
@@ -109,8 +148,8 @@ pub fn update_traffic_class(
     Ok(0)
 }
 
-pub fn parse_sock_data(
-    ctx: &ProbeContext,
+pub fn parse_sock_data<C: EbpfContext>(
+    ctx: &C,
     sk: *const sock,
     conn_info: &mut ConnInfo,
 ) -> Result<u32, i64> {
@@ -120,14 +159,18 @@ pub fn parse_sock_data(
     // read connection data
     match sk_common.skc_family as u32 {
         AF_INET => {
-            let src_addr =
-                u32::from_be(unsafe { sk_common.__bindgen_anon_1.__bindgen_anon_1.skc_rcv_saddr });
-            let dst_addr: u32 =
-                u32::from_be(unsafe { sk_common.__bindgen_anon_1.__bindgen_anon_1.skc_daddr });
-            let src_port =
-                u16::from_be(unsafe { sk_common.__bindgen_anon_3.__bindgen_anon_1.skc_num });
+            let src_addr = NetEndian::from_raw(unsafe {
+                sk_common.__bindgen_anon_1.__bindgen_anon_1.skc_rcv_saddr
+            })
+            .to_host();
+            let dst_addr =
+                NetEndian::from_raw(unsafe { sk_common.__bindgen_anon_1.__bindgen_anon_1.skc_daddr })
+                    .to_host();
+            // The local port is kept in host byte order, unlike the remote one.
+            let src_port = unsafe { sk_common.__bindgen_anon_3.__bindgen_anon_1.skc_num };
             let dst_port =
-                u16::from_be(unsafe { sk_common.__bindgen_anon_3.__bindgen_anon_1.skc_dport });
+                NetEndian::from_raw(unsafe { sk_common.__bindgen_anon_3.__bindgen_anon_1.skc_dport })
+                    .to_host();
             conn_info.sa_family = AF_INET;
             conn_info.src_addr_in4 = src_addr;
             conn_info.dst_addr_in4 = dst_addr;
@@ -143,10 +186,11 @@ pub fn parse_sock_data(
         AF_INET6 => {
             let src_addr = unsafe { sk_common.skc_v6_rcv_saddr.in6_u.u6_addr8 };
             let dst_addr = unsafe { sk_common.skc_v6_daddr.in6_u.u6_addr8 };
-            let src_port =
-                u16::from_be(unsafe { sk_common.__bindgen_anon_3.__bindgen_anon_1.skc_num });
+            // The local port is kept in host byte order, unlike the remote one.
+            let src_port = unsafe { sk_common.__bindgen_anon_3.__bindgen_anon_1.skc_num };
             let dst_port =
-                u16::from_be(unsafe { sk_common.__bindgen_anon_3.__bindgen_anon_1.skc_dport });
+                NetEndian::from_raw(unsafe { sk_common.__bindgen_anon_3.__bindgen_anon_1.skc_dport })
+                    .to_host();
             conn_info.sa_family = AF_INET6;
             conn_info.src_addr_in6 = src_addr;
             conn_info.dst_addr_in6 = dst_addr;
@@ -165,8 +209,8 @@ pub fn parse_sock_data(
     Ok(0)
 }
 
-pub fn parse_sockaddr_data(
-    ctx: &ProbeContext,
+pub fn parse_sockaddr_data<C: EbpfContext>(
+    ctx: &C,
     sockaddr: *const sockaddr,
     conn_info: &mut ConnInfo,
 ) -> Result<u32, i64> {
@@ -176,8 +220,8 @@ pub fn parse_sockaddr_data(
         AF_INET => {
             let sa_ptr_in = sockaddr as *const sockaddr_in;
             let sa_in = unsafe { bpf_probe_read_user(sa_ptr_in).map_err(|e| e)? };
-            conn_info.dst_addr_in4 = u32::from_be(sa_in.sin_addr.s_addr);
-            conn_info.dst_port = u16::from_be(sa_in.sin_port) as u32;
+            conn_info.dst_addr_in4 = NetEndian::from_raw(sa_in.sin_addr.s_addr).to_host();
+            conn_info.dst_port = NetEndian::from_raw(sa_in.sin_port).to_host() as u32;
             debug!(
                 ctx,
                 "AF_INET src address: {:i}, dest address: {:i}",
@@ -189,7 +233,7 @@ pub fn parse_sockaddr_data(
             let sa_ptr_in6 = sockaddr as *const sockaddr_in6;
             let sa_in6 = unsafe { bpf_probe_read_user(sa_ptr_in6).map_err(|e| e)? };
             conn_info.dst_addr_in6 = unsafe { sa_in6.sin6_addr.in6_u.u6_addr8 };
-            conn_info.dst_port = u16::from_be(sa_in6.sin6_port) as u32;
+            conn_info.dst_port = NetEndian::from_raw(sa_in6.sin6_port).to_host() as u32;
             debug!(
                 ctx,
                 "AF_INET6 src address: {:i}, dest address: {:i}",
@@ -197,6 +241,21 @@ pub fn parse_sockaddr_data(
                 conn_info.dst_addr_in6,
             )
         }
+        AF_UNIX => {
+            // The path follows the family, and may be shorter than sun_path when the
+            // address is: the connection is then traced without it. Addresses are
+            // expected to be zeroed past their path, as most libraries leave them.
+            let sun_path = unsafe { (sockaddr as *const u8).add(size_of::<u16>()) };
+            if unsafe { bpf_probe_read_user_buf(sun_path, &mut conn_info.unix_path) }.is_err() {
+                conn_info.unix_path = [0; UNIX_PATH_SIZE];
+            }
+        }
+        AF_VSOCK => {
+            // Context ids are kept as IPv4 addresses, in host byte order like these.
+            let sa_vm = unsafe { bpf_probe_read_user(sockaddr as *const types::SockaddrVm)? };
+            conn_info.dst_addr_in4 = sa_vm.svm_cid;
+            conn_info.dst_port = sa_vm.svm_port;
+        }
         _ => return Err(1),
     }
     Ok(0)
@@ -214,7 +273,7 @@ pub struct OpenEventArgs {
     pub source_fn: SourceFunction,
 }
 
-pub fn submit_open_event(ctx: &ProbeContext, args: &OpenEventArgs) -> Result<u32, i64> {
+pub fn submit_open_event<C: EbpfContext>(ctx: &C, args: &OpenEventArgs) -> Result<u32, i64> {
     let mut conn_info = ConnInfo::default();
     init_conn_info(args.tgid, args.fd, &mut conn_info);
     conn_info.role = args.role;
@@ -226,72 +285,44 @@ pub fn submit_open_event(ctx: &ProbeContext, args: &OpenEventArgs) -> Result<u32
     }
 
     let tgid_fd = gen_tgid_fd(args.tgid, args.fd);
-    unsafe {
-        CONN_INFO_MAP.insert(&tgid_fd, &conn_info, 0)?;
-    }
+    track_drop(DropStage::MapInsert, unsafe {
+        CONN_INFO_MAP.insert(&tgid_fd, &conn_info, 0)
+    })?;
     if !should_trace_sockaddr_family(conn_info.sa_family) {
         return Ok(0);
     }
 
-    let socket_control_event = SocketControlEvent {
-        id: conn_info.id,
-        event_type: ControlEventType::Open,
-        sa_family: conn_info.sa_family as u64,
-        source_function: args.source_fn,
-        timestamp_ns: unsafe { bpf_ktime_get_ns() },
-        src_addr_in4: conn_info.src_addr_in4,
-        src_addr_in6: conn_info.src_addr_in6,
-        src_port: conn_info.src_port,
-        dst_addr_in4: conn_info.dst_addr_in4,
-        dst_addr_in6: conn_info.dst_addr_in6,
-        dst_port: conn_info.dst_port,
-        role: conn_info.role,
-        write_bytes: 0,
-        read_bytes: 0,
-    };
-
-    unsafe {
-        SOCKET_CONTROL_EVENTS.output(ctx, &socket_control_event, 0);
-    }
+    let event =
+        populate_socket_control_event(ControlEventType::Open, args.source_fn, &conn_info)?;
+    let _ = track_drop(DropStage::PerfOutput, unsafe {
+        SOCKET_CONTROL_EVENTS.output(ctx, event, 0)
+    });
     Ok(0)
 }
 
-pub fn submit_close_event(
-    ctx: &ProbeContext,
+pub fn submit_close_event<C: EbpfContext>(
+    ctx: &C,
     conn_info: &ConnInfo,
     src_fn: SourceFunction,
 ) -> Result<u32, i64> {
-    let socket_control_event = SocketControlEvent {
-        id: conn_info.id,
-        event_type: ControlEventType::Close,
-        sa_family: conn_info.sa_family as u64,
-        source_function: src_fn,
-        timestamp_ns: unsafe { bpf_ktime_get_ns() },
-        src_addr_in4: conn_info.src_addr_in4,
-        src_addr_in6: conn_info.src_addr_in6,
-        src_port: conn_info.src_port,
-        dst_addr_in4: conn_info.dst_addr_in4,
-        dst_addr_in6: conn_info.dst_addr_in6,
-        dst_port: conn_info.dst_port,
-        role: conn_info.role,
-        write_bytes: conn_info.write_bytes,
-        read_bytes: conn_info.read_bytes,
-    };
-
-    unsafe {
-        SOCKET_CONTROL_EVENTS.output(ctx, &socket_control_event, 0);
-    }
+    let event = populate_socket_control_event(ControlEventType::Close, src_fn, conn_info)?;
+    let _ = track_drop(DropStage::PerfOutput, unsafe {
+        SOCKET_CONTROL_EVENTS.output(ctx, event, 0)
+    });
     Ok(0)
 }
 
-pub fn perf_submit_buf(
-    ctx: &ProbeContext,
+pub fn perf_submit_buf<C: EbpfContext>(
+    ctx: &C,
     buf: *const u8,
-    mut buf_size: usize,
+    buf_size: usize,
+    snap_len: usize,
     event: &mut SocketDataEvent,
 ) -> Result<u32, i64> {
     event.inner.msg_size = buf_size as u32;
 
+    // Only the first `snap_len` bytes are copied, msg_size still tells the whole size.
+    let mut buf_size = buf_size.min(snap_len);
     if buf_size == 0 {
         return Ok(0);
     }
@@ -320,20 +351,21 @@ pub fn perf_submit_buf(
     // If-statement is redundant, but is required to keep the verifier happy.
     if amount_copied > 0 {
         event.inner.msg_buf_size = amount_copied as u32;
-        unsafe {
-            let data_size = size_of::<SocketDataEventInner>() + amount_copied;
-            SOCKET_DATA_EVENTS.output_with_size(ctx, event, data_size as u64, 0);
-        }
+        let data_size = size_of::<SocketDataEventInner>() + amount_copied;
+        let _ = track_drop(DropStage::PerfOutput, unsafe {
+            SOCKET_DATA_EVENTS.output_with_size(ctx, event, data_size as u64, 0)
+        });
     }
     Ok(0)
 }
 
-pub fn submit_data_event(
-    ctx: &ProbeContext,
+pub fn submit_data_event<C: EbpfContext>(
+    ctx: &C,
     buf: *const u8,
     buf_size: usize,
     event: &mut SocketDataEvent,
 ) -> Result<u32, i64> {
+    let snap_len = snap_len();
     let mut bytes_submitted: usize = 0;
     for i in 0..CHUNK_LIMIT {
         let bytes_remaining = buf_size - bytes_submitted;
@@ -343,40 +375,71 @@ pub fn submit_data_event(
             bytes_remaining
         };
         let current_buf = unsafe { buf.add(bytes_submitted) };
-        perf_submit_buf(ctx, current_buf, current_size, event)?;
+        perf_submit_buf(ctx, current_buf, current_size, snap_len, event)?;
 
         bytes_submitted += current_size;
+        event.inner.position += current_size as u64;
+        // Past the snap length, the rest of the message is left out.
+        if snap_len < MAX_MSG_SIZE {
+            break;
+        }
     }
 
     Ok(0)
 }
 
-pub fn submit_data_event_iovecs(
-    ctx: &ProbeContext,
+/// Submits the data of a vectored syscall, splitting buffers larger than an event into
+/// chunks of [`MAX_MSG_SIZE`] bytes. Each event carries its position in the stream, so
+/// that userspace reassembles the chunks in order. A single loop walks both the buffers
+/// and their chunks, which bounds a syscall to [`LOOP_LIMIT`] events for the verifier.
+pub fn submit_data_event_iovecs<C: EbpfContext>(
+    ctx: &C,
     iov: *mut iovec,
     iovlen: u64,
     total_size: usize,
     event: &mut SocketDataEvent,
 ) -> Result<u32, i64> {
+    let snap_len = snap_len();
     let mut bytes_sent = 0;
+    let mut next_iov = 0;
+    // The part of the current buffer not submitted yet.
+    let mut iov_base: *const u8 = core::ptr::null();
+    let mut iov_remaining = 0;
 
-    for i in 0..LOOP_LIMIT {
-        if i >= iovlen as usize {
-            break;
-        }
-
+    for _ in 0..LOOP_LIMIT {
         if bytes_sent >= total_size {
             break;
         }
 
-        let iov_ptr = unsafe { iov.add(i) };
-        let iov_cpy = unsafe { bpf_probe_read_kernel(iov_ptr as *const iovec)? };
-        let bytes_remaining = total_size - bytes_sent;
-        let iov_size = bytes_remaining.min(iov_cpy.iov_len as usize);
+        if iov_remaining == 0 {
+            if next_iov >= iovlen as usize {
+                break;
+            }
+            let iov_ptr = unsafe { iov.add(next_iov) };
+            let iov_cpy = unsafe { bpf_probe_read_kernel(iov_ptr as *const iovec)? };
+            iov_base = iov_cpy.iov_base as *const u8;
+            iov_remaining = iov_cpy.iov_len as usize;
+            next_iov += 1;
+            if iov_remaining == 0 {
+                continue;
+            }
+        }
 
-        perf_submit_buf(ctx, iov_cpy.iov_base as *const u8, iov_size, event)?;
-        bytes_sent += iov_size;
-        event.inner.position += iov_size as u64;
+        let chunk_size = (total_size - bytes_sent).min(iov_remaining).min(MAX_MSG_SIZE);
+        // The snap length bounds the whole message, not each of its chunks.
+        let chunk_snap_len = if snap_len < MAX_MSG_SIZE {
+            snap_len - bytes_sent
+        } else {
+            MAX_MSG_SIZE
+        };
+        perf_submit_buf(ctx, iov_base, chunk_size, chunk_snap_len, event)?;
+        bytes_sent += chunk_size;
+        event.inner.position += chunk_size as u64;
+        iov_base = unsafe { iov_base.add(chunk_size) };
+        iov_remaining -= chunk_size;
+        if snap_len < MAX_MSG_SIZE && bytes_sent >= snap_len {
+            break;
+        }
     }
 
     Ok(0)
@@ -401,8 +464,8 @@ pub fn should_send_data(
     return force_trace_tgid || should_trace_protocol_data(conn_info);
 }
 
-pub fn update_conn_stats(
-    ctx: &ProbeContext,
+pub fn update_conn_stats<C: EbpfContext>(
+    ctx: &C,
     conn_info: &mut ConnInfo,
     direction: TrafficDirection,
     bytes_count: ssize_t,
@@ -421,10 +484,10 @@ pub fn update_conn_stats(
         total_bytes >= conn_info.prev_reported_bytes + CONN_STATS_DATA_THRESHOLD;
 
     if meets_activity_threshold {
-        let event = populate_conn_stats_event(*conn_info)?;
-        unsafe {
-            CONN_STATS_EVENTS.output(ctx, &event, 0);
-        }
+        let event = populate_conn_stats_event(conn_info)?;
+        let _ = track_drop(DropStage::PerfOutput, unsafe {
+            CONN_STATS_EVENTS.output(ctx, event, 0)
+        });
         conn_info.prev_reported_bytes = total_bytes;
     }
     Ok(0)
@@ -438,8 +501,8 @@ pub struct ProcessDataArgs {
     bytes_count: ssize_t,
 }
 
-pub fn process_data(
-    ctx: &ProbeContext,
+pub fn process_data<C: FnContext>(
+    ctx: &C,
     args: &types::DataArgs,
     extra_args: &ProcessDataArgs,
 ) -> Result<u32, i64> {
@@ -518,24 +581,10 @@ pub fn process_data(
         }
     }
 
-    if should_send_data(tgid, conn_disabled_tsid, force_trace_tgid, conn_info) {
-        let event =
-            populate_socket_data_event(args.source_function, extra_args.direction, &conn_info)?;
-        match extra_args.vecs {
-            true => {
-                submit_data_event_iovecs(
-                    ctx,
-                    args.iov,
-                    args.iovlen,
-                    extra_args.bytes_count as usize,
-                    event,
-                )?;
-            }
-            false => {
-                // TODO: handle bytes_count < 0
-                submit_data_event(ctx, args.buf, extra_args.bytes_count as usize, event)?;
-            }
-        }
+    let send_data = should_send_data(tgid, conn_disabled_tsid, force_trace_tgid, conn_info);
+    if send_data {
+        // Built before the stats below count this syscall, which would move its position.
+        populate_socket_data_event(args.source_function, extra_args.direction, &conn_info)?;
     }
 
     update_conn_stats(
@@ -545,11 +594,67 @@ pub fn process_data(
         extra_args.bytes_count,
     )?;
 
+    if send_data {
+        let pending = unsafe { PENDING_DATA.get_ptr_mut(0).ok_or(1)? };
+        unsafe {
+            *pending = types::PendingData {
+                args: *args,
+                vecs: extra_args.vecs.into(),
+                bytes_count: extra_args.bytes_count as usize,
+                ready: C::TAIL_CALLS.into(),
+            };
+        }
+        // Kprobes leave the data to its parser, see dispatch_pending_data.
+        if !C::TAIL_CALLS {
+            submit_pending_data(ctx)?;
+        }
+    }
+
     Ok(0)
 }
 
-pub fn process_syscall_data(
-    ctx: &ProbeContext,
+/// Tail calls into the parser of the protocol of the data event left pending by
+/// `process_data`, if any. A tail call never returns, so the kretprobes call this last.
+pub fn dispatch_pending_data<C: FnContext>(ctx: &C) -> Result<u32, i64> {
+    if !C::TAIL_CALLS {
+        return Ok(0);
+    }
+    let pending = unsafe { PENDING_DATA.get_ptr_mut(0).ok_or(1)? };
+    unsafe {
+        if !bool::from((*pending).ready) {
+            return Ok(0);
+        }
+        (*pending).ready = types::AlignedBool::False;
+    }
+    let event = unsafe { SOCKET_DATA_EVENT_BUFFER.get(0).ok_or(1)? };
+    let _ = unsafe { DATA_PARSERS.tail_call(ctx, event.inner.protocol as u32) };
+    // Only reached when no parser is registered for the protocol.
+    record_drop(DropStage::TailCall);
+    Ok(0)
+}
+
+/// Submits the data event left pending by `process_data`, copying the data of the
+/// syscall into as many events as it takes. Run by the data parsers, or right away by
+/// the fentry and fexit programs, which can't tail call into kprobes.
+pub fn submit_pending_data<C: EbpfContext>(ctx: &C) -> Result<u32, i64> {
+    let pending = unsafe { PENDING_DATA.get(0).ok_or(1)? };
+    let event_ptr = unsafe { SOCKET_DATA_EVENT_BUFFER.get_ptr_mut(0).ok_or(1)? };
+    let event = unsafe { event_ptr.as_mut().ok_or(1)? };
+    match pending.vecs.into() {
+        true => submit_data_event_iovecs(
+            ctx,
+            pending.args.iov,
+            pending.args.iovlen,
+            pending.bytes_count,
+            event,
+        ),
+        // TODO: handle bytes_count < 0
+        false => submit_data_event(ctx, pending.args.buf, pending.bytes_count, event),
+    }
+}
+
+pub fn process_syscall_data<C: FnContext>(
+    ctx: &C,
     pid_tgid: u64,
     direction: TrafficDirection,
     args: &types::DataArgs,
@@ -564,8 +669,8 @@ pub fn process_syscall_data(
     process_data(ctx, args, &extra_args)
 }
 
-pub fn process_syscall_data_vecs(
-    ctx: &ProbeContext,
+pub fn process_syscall_data_vecs<C: FnContext>(
+    ctx: &C,
     pid_tgid: u64,
     direction: TrafficDirection,
     args: &types::DataArgs,
@@ -588,6 +693,16 @@ pub fn gen_tsid() -> u64 {
     unsafe { bpf_ktime_get_ns() as u64 }
 }
 
+/// Returns the next connection id, starting from 1 so that 0 is never a valid one.
+/// The counter is shared by every CPU, so ids are unique and increase in the order
+/// connections are seen.
+pub fn gen_conn_id() -> u64 {
+    match unsafe { CONN_ID_GENERATOR.get_ptr_mut(0) } {
+        Some(next) => unsafe { AtomicU64::from_ptr(next).fetch_add(1, Ordering::Relaxed) + 1 },
+        None => 0,
+    }
+}
+
 pub fn init_conn_id(tgid: u32, fd: i32) -> ConnId {
     ConnId {
         uid: Uid {
@@ -596,6 +711,7 @@ pub fn init_conn_id(tgid: u32, fd: i32) -> ConnId {
         },
         fd: fd as i64,
         tsid: gen_tsid(),
+        conn_id: gen_conn_id(),
     }
 }
 
@@ -607,15 +723,16 @@ pub fn init_conn_info(tgid: u32, fd: i32, conn_info: &mut ConnInfo) {
 
 pub fn get_or_create_conn_info(tgid: u32, fd: i32) -> Result<ConnInfo, i64> {
     let tgid_fd = gen_tgid_fd(tgid, fd);
-    let mut conn_info = ConnInfo::default();
-    init_conn_info(tgid, fd, &mut conn_info);
 
     match unsafe { CONN_INFO_MAP.get(&tgid_fd) } {
         Some(&info) => Ok(info),
         None => {
-            unsafe {
-                CONN_INFO_MAP.insert(&tgid_fd, &conn_info, 0)?;
-            }
+            // Only connections seen for the first time take an id.
+            let mut conn_info = ConnInfo::default();
+            init_conn_info(tgid, fd, &mut conn_info);
+            track_drop(DropStage::MapInsert, unsafe {
+                CONN_INFO_MAP.insert(&tgid_fd, &conn_info, 0)
+            })?;
             Ok(conn_info)
         }
     }
@@ -643,9 +760,39 @@ pub fn populate_socket_data_event(
     Ok(event)
 }
 
-pub fn populate_conn_stats_event(conn_info: ConnInfo) -> Result<ConnStatsEvent, i64> {
+/// Fills the per-CPU control event buffer, as control events carrying the path of unix
+/// sockets are too large to build on the stack next to their connection.
+pub fn populate_socket_control_event(
+    event_type: ControlEventType,
+    src_fn: SourceFunction,
+    conn_info: &ConnInfo,
+) -> Result<&mut SocketControlEvent, i64> {
+    let idx: u32 = 0;
+    let event_ptr = unsafe { SOCKET_CONTROL_EVENT_BUFFER.get_ptr_mut(idx).ok_or(1)? };
+    let event = unsafe { event_ptr.as_mut().ok_or(1)? };
+    event.id = conn_info.id;
+    event.event_type = event_type;
+    event.sa_family = conn_info.sa_family as u64;
+    event.source_function = src_fn;
+    event.timestamp_ns = unsafe { bpf_ktime_get_ns() };
+    event.src_addr_in4 = conn_info.src_addr_in4;
+    event.src_addr_in6 = conn_info.src_addr_in6;
+    event.src_port = conn_info.src_port;
+    event.dst_addr_in4 = conn_info.dst_addr_in4;
+    event.dst_addr_in6 = conn_info.dst_addr_in6;
+    event.dst_port = conn_info.dst_port;
+    event.role = conn_info.role;
+    event.write_bytes = conn_info.write_bytes;
+    event.read_bytes = conn_info.read_bytes;
+    event.unix_path = conn_info.unix_path;
+
+    Ok(event)
+}
+
+pub fn populate_conn_stats_event(conn_info: &ConnInfo) -> Result<&mut ConnStatsEvent, i64> {
     let idx: u32 = 0;
-    let mut event = unsafe { *CONN_STATS_EVENT_BUFFER.get_ptr_mut(idx).ok_or(1)? };
+    let event_ptr = unsafe { CONN_STATS_EVENT_BUFFER.get_ptr_mut(idx).ok_or(1)? };
+    let event = unsafe { event_ptr.as_mut().ok_or(1)? };
 
     event.id = conn_info.id;
     event.src_addr_in4 = conn_info.src_addr_in4;
@@ -658,6 +805,7 @@ pub fn populate_conn_stats_event(conn_info: ConnInfo) -> Result<ConnStatsEvent,
     event.write_bytes = conn_info.write_bytes;
     event.read_bytes = conn_info.read_bytes;
     event.event_flags = 0;
+    event.unix_path = conn_info.unix_path;
     event.timestamp_ns = unsafe { bpf_ktime_get_ns() };
 
     Ok(event)
@@ -0,0 +1,12 @@
+#![no_std]
+#![no_main]
+
+//! Every probe of the socket tracer, bundled into a single object so that they share
+//! one set of maps and are loaded at once.
+
+mod kprobes;
+
+#[panic_handler]
+fn panic(_info: &core::panic::PanicInfo) -> ! {
+    unsafe { core::hint::unreachable_unchecked() }
+}
@@ -1,11 +1,11 @@
 use aya_ebpf::{
     macros::map,
-    maps::{HashMap, PerCpuArray, PerfEventArray},
+    maps::{Array, HashMap, PerCpuArray, ProgramArray},
 };
 
 use socket_tracer_common::{
-    ConnInfo, ConnStatsEvent, ControlValueIndex, SocketControlEvent, SocketDataEvent,
-    TrafficProtocol,
+    ConnInfo, ConnStatsEvent, ControlValueIndex, DropStage, SocketControlEvent, SocketDataEvent,
+    TrafficProtocol, MAX_TARGET_TGIDS,
 };
 
 use crate::{helpers::MyPerfEventArray, types};
@@ -13,16 +13,16 @@ use crate::{helpers::MyPerfEventArray, types};
 pub const MAX_MAP_ENTRIES: u32 = 128 * 1024;
 
 #[map(name = "sk_ctrl_events")]
-pub static mut SOCKET_CONTROL_EVENTS: PerfEventArray<SocketControlEvent> =
-    PerfEventArray::<SocketControlEvent>::pinned(0, 0);
+pub static mut SOCKET_CONTROL_EVENTS: MyPerfEventArray<SocketControlEvent> =
+    MyPerfEventArray::<SocketControlEvent>::pinned(0, 0);
 
 #[map(name = "sk_data_events")]
 pub static mut SOCKET_DATA_EVENTS: MyPerfEventArray<SocketDataEvent> =
     MyPerfEventArray::<SocketDataEvent>::pinned(0, 0);
 
 #[map(name = "conn_stat_events")]
-pub static mut CONN_STATS_EVENTS: PerfEventArray<ConnStatsEvent> =
-    PerfEventArray::<ConnStatsEvent>::pinned(0, 0);
+pub static mut CONN_STATS_EVENTS: MyPerfEventArray<ConnStatsEvent> =
+    MyPerfEventArray::<ConnStatsEvent>::pinned(0, 0);
 
 #[map(name = "ctrl_map")]
 pub static mut CONTROL_MAP: PerCpuArray<u64> =
@@ -32,14 +32,43 @@ pub static mut CONTROL_MAP: PerCpuArray<u64> =
 pub static mut CONTROL_VALUES: PerCpuArray<i64> =
     PerCpuArray::<i64>::pinned(ControlValueIndex::NumControlValues as u32, 0);
 
+/// Processes traced when the target TGID is [`socket_tracer_common::TARGET_TGID_SET`].
+/// Set by userspace.
+#[map(name = "target_tgids")]
+pub static mut TARGET_TGIDS: HashMap<u32, u8> = HashMap::pinned(MAX_TARGET_TGIDS, 0);
+
+#[map(name = "conn_id_gen")]
+pub static mut CONN_ID_GENERATOR: Array<u64> = Array::<u64>::pinned(1, 0);
+
+#[map(name = "drop_stats")]
+pub static mut DROP_STATS: PerCpuArray<u64> =
+    PerCpuArray::<u64>::pinned(DropStage::NumDropStages as u32, 0);
+
 #[map(name = "sock_data_buf")]
 pub static mut SOCKET_DATA_EVENT_BUFFER: PerCpuArray<SocketDataEvent> =
     PerCpuArray::<SocketDataEvent>::pinned(1, 0);
 
+#[map(name = "sk_ctrl_buf")]
+pub static mut SOCKET_CONTROL_EVENT_BUFFER: PerCpuArray<SocketControlEvent> =
+    PerCpuArray::<SocketControlEvent>::pinned(1, 0);
+
 #[map(name = "conn_stats_buf")]
 pub static mut CONN_STATS_EVENT_BUFFER: PerCpuArray<ConnStatsEvent> =
     PerCpuArray::<ConnStatsEvent>::pinned(1, 0);
 
+/// The programs parsing the data of each protocol, which the kprobes tail call into
+/// once they have built a data event, so that each program stays within the limits of
+/// the verifier. Registered by userspace.
+#[map(name = "data_parsers")]
+pub static mut DATA_PARSERS: ProgramArray =
+    ProgramArray::pinned(TrafficProtocol::NumProtocols as u32, 0);
+
+/// Where the data of the event being built is, which the kprobes hand over to the
+/// parsers along with the event buffer.
+#[map(name = "pending_data")]
+pub static mut PENDING_DATA: PerCpuArray<types::PendingData> =
+    PerCpuArray::<types::PendingData>::pinned(1, 0);
+
 #[map(name = "conn_info")]
 pub static mut CONN_INFO_MAP: HashMap<u64, ConnInfo> =
     HashMap::<u64, ConnInfo>::pinned(MAX_MAP_ENTRIES, 0);
@@ -37,6 +37,18 @@ pub struct ConnectArgs {
 
 unsafe impl Sync for ConnectArgs {}
 
+/// The address of a vsock socket, struct sockaddr_vm, missing from the bindings.
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct SockaddrVm {
+    pub svm_family: u16,
+    pub svm_reserved1: u16,
+    pub svm_port: u32,
+    pub svm_cid: u32,
+    pub svm_flags: u8,
+    pub svm_zero: [u8; 3],
+}
+
 #[derive(Copy, Clone, Debug)]
 #[repr(C)]
 pub struct AcceptArgs {
@@ -71,6 +83,19 @@ pub struct DataArgs {
 
 unsafe impl Sync for DataArgs {}
 
+/// Where the data of the event being built is, for the program submitting it.
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct PendingData {
+    pub args: DataArgs,
+    pub vecs: AlignedBool,
+    pub bytes_count: usize,
+    // Whether a kprobe is yet to hand the event over to its parser.
+    pub ready: AlignedBool,
+}
+
+unsafe impl Sync for PendingData {}
+
 #[derive(Copy, Clone, Debug)]
 #[repr(C)]
 pub struct CloseArgs {
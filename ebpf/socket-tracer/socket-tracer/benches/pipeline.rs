@@ -0,0 +1,104 @@
+//! Cost of the userspace pipeline a data event goes through: decoding the perf sample,
+//! reassembling the stream it belongs to and parsing the messages it completes. Run
+//! with `cargo bench -p socket-tracer`.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+use socket_tracer::protocols::http::HttpParser;
+use socket_tracer::protocols::redis::RedisParser;
+use socket_tracer::protocols::{parse_bounded, Parser};
+use socket_tracer::reassembly::{Reassembler, ReassemblyConfig};
+use socket_tracer::scratch::EventScratch;
+use socket_tracer::synthetic::{data_events, http_requests, redis_commands};
+use socket_tracer_common::{SocketDataEvent, TrafficProtocol};
+
+/// Parses every message of `stream` from a single buffer.
+fn parse_all<P: Parser + Default>(stream: &[u8]) -> usize {
+    let mut parser = P::default();
+    let (mut buf, mut messages) = (stream, 0);
+    while let Ok((_, len)) = parse_bounded(&mut parser, buf) {
+        buf = &buf[len..];
+        messages += 1;
+    }
+    messages
+}
+
+fn parse(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse");
+    for (name, stream) in [
+        ("http", http_requests(100, 512)),
+        ("redis", redis_commands(100, 512)),
+    ] {
+        group.throughput(Throughput::Bytes(stream.len() as u64));
+        group.bench_with_input(
+            BenchmarkId::new(name, stream.len()),
+            &stream,
+            |b, stream| {
+                b.iter(|| match name {
+                    "http" => parse_all::<HttpParser>(stream),
+                    _ => parse_all::<RedisParser>(stream),
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+fn decode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decode");
+    let samples = data_events(TrafficProtocol::HTTP, 1, &http_requests(100, 512), 4096);
+    group.throughput(Throughput::Elements(samples.len() as u64));
+    group.bench_function("data", |b| {
+        let mut scratch = EventScratch::<SocketDataEvent>::new();
+        b.iter(|| {
+            samples
+                .iter()
+                .map(|sample| scratch.decode(sample).inner.msg_buf_size as usize)
+                .sum::<usize>()
+        })
+    });
+    group.finish();
+}
+
+fn reassembly(c: &mut Criterion) {
+    let mut group = c.benchmark_group("reassembly");
+    // Interleave the events of many connections, as the consumers see them.
+    let connections = 100;
+    let stream = http_requests(20, 512);
+    group.throughput(Throughput::Bytes(
+        (stream.len() * connections as usize) as u64,
+    ));
+    for segment_size in [64, 1024, 16384] {
+        let streams: Vec<_> = (0..connections)
+            .map(|conn| data_events(TrafficProtocol::HTTP, conn, &stream, segment_size))
+            .collect();
+        let mut samples = Vec::new();
+        for i in 0..streams.iter().map(Vec::len).max().unwrap_or(0) {
+            samples.extend(streams.iter().filter_map(|events| events.get(i)));
+        }
+        group.bench_with_input(
+            BenchmarkId::new("http", segment_size),
+            &samples,
+            |b, samples| {
+                let mut scratch = EventScratch::<SocketDataEvent>::new();
+                b.iter(|| {
+                    let mut reassembler =
+                        Reassembler::<u64, HttpParser>::new(ReassemblyConfig::default());
+                    let mut messages = 0;
+                    for sample in samples.iter() {
+                        let event = scratch.decode(sample);
+                        let data = &event.msg[..event.inner.msg_buf_size as usize];
+                        messages += reassembler
+                            .push(event.inner.id.tsid, event.inner.position, data)
+                            .len();
+                    }
+                    messages
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, parse, decode, reassembly);
+criterion_main!(benches);
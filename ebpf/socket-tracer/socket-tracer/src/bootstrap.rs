@@ -0,0 +1,220 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+
+use aya::maps::{HashMap as BpfHashMap, Map, MapData};
+use log::{debug, info, warn};
+
+use socket_tracer::clock::ClockSample;
+use socket_tracer_common::{
+    ConnId, ConnInfo, EndpointRole, Uid, AF_INET, AF_INET6, BOOTSTRAP_CONN_ID_BASE,
+};
+
+use crate::janitor::in_host_pid_namespace;
+
+/// `BPF_NOEXIST`: entries the probes created meanwhile are left as they are.
+const BPF_NOEXIST: u64 = 1;
+/// States of `/proc/net/tcp`, from `include/net/tcp_states.h`.
+const TCP_ESTABLISHED: u8 = 0x01;
+const TCP_LISTEN: u8 = 0x0a;
+
+/// A TCP socket as listed in `/proc/net/tcp{,6}`, with IPv4 addresses mapped to IPv6.
+#[derive(Debug, Clone, Copy)]
+struct TcpSocket {
+    family: u32,
+    local: (Ipv6Addr, u16),
+    remote: (Ipv6Addr, u16),
+    state: u8,
+}
+
+/// The established TCP sockets of a network namespace, by inode, and the ports it
+/// listens on.
+#[derive(Debug, Default)]
+struct NetNamespace {
+    sockets: HashMap<u64, TcpSocket>,
+    listening: HashSet<u16>,
+}
+
+/// Seeds the connection map with the TCP connections processes already had open when
+/// the tracer started, so that their events are attributed like those of connections
+/// opened later. The probes only learn of a connection when it is opened, and would
+/// otherwise know nothing but the fd of these.
+///
+/// Connections are found from the socket fds listed in `/proc/<pid>/fd` and the
+/// sockets of each network namespace in `/proc/<pid>/net/tcp{,6}`. Their role is only
+/// known for servers, whose local port is listened on. Keys are kernel PIDs, so the
+/// scan is skipped unless the tracer runs in the host PID namespace.
+pub fn run(map_path: &Path) -> anyhow::Result<()> {
+    if !in_host_pid_namespace() {
+        warn!("not in the host PID namespace, open connections won't be seeded");
+        return Ok(());
+    }
+
+    let map_data =
+        MapData::from_pin(map_path).map_err(|_| anyhow::anyhow!("No maps named {:?}", map_path))?;
+    let mut conns: BpfHashMap<_, u64, ConnInfo> = Map::HashMap(map_data).try_into()?;
+    let tsid = ClockSample::now().monotonic_ns;
+    let mut namespaces: HashMap<String, NetNamespace> = HashMap::new();
+    let mut next_id = BOOTSTRAP_CONN_ID_BASE;
+    let mut seeded = 0;
+
+    for entry in fs::read_dir("/proc")?.flatten() {
+        let Some(tgid) = entry
+            .file_name()
+            .to_str()
+            .and_then(|s| s.parse::<u32>().ok())
+        else {
+            continue;
+        };
+        let proc_dir = entry.path();
+        // The process may exit at any point, and its files vanish with it.
+        let Some(start_time_ticks) = start_time_ticks(&proc_dir) else {
+            continue;
+        };
+        let Ok(net_ns) = fs::read_link(proc_dir.join("ns/net")) else {
+            continue;
+        };
+        let namespace = namespaces
+            .entry(net_ns.to_string_lossy().into_owned())
+            .or_insert_with(|| net_namespace(&proc_dir));
+        let Ok(fds) = fs::read_dir(proc_dir.join("fd")) else {
+            continue;
+        };
+
+        for fd in fds.flatten() {
+            let Some(fd_num) = fd.file_name().to_str().and_then(|s| s.parse::<i32>().ok()) else {
+                continue;
+            };
+            let Some(socket) = fs::read_link(fd.path())
+                .ok()
+                .and_then(|link| socket_inode(&link.to_string_lossy()))
+                .and_then(|inode| namespace.sockets.get(&inode))
+            else {
+                continue;
+            };
+
+            let mut conn_info = ConnInfo {
+                id: ConnId {
+                    uid: Uid {
+                        tgid: tgid as u64,
+                        start_time_ticks,
+                    },
+                    fd: fd_num as i64,
+                    tsid,
+                    conn_id: next_id,
+                },
+                role: if namespace.listening.contains(&socket.local.1) {
+                    EndpointRole::Server
+                } else {
+                    EndpointRole::Client
+                },
+                ..Default::default()
+            };
+            set_addresses(&mut conn_info, socket);
+
+            let key = ((tgid as u64) << 32) | (fd_num as u32 as u64);
+            match conns.insert(key, conn_info, BPF_NOEXIST) {
+                Ok(()) => {
+                    next_id += 1;
+                    seeded += 1;
+                }
+                Err(e) => debug!("failed to seed connection {}/{}: {}", tgid, fd_num, e),
+            }
+        }
+    }
+
+    info!("seeded {} connections opened before starting", seeded);
+    Ok(())
+}
+
+/// Returns when a process started, in clock ticks since boot, as the probes record it.
+fn start_time_ticks(proc_dir: &Path) -> Option<u64> {
+    let stat = fs::read_to_string(proc_dir.join("stat")).ok()?;
+    // The name of the command may hold spaces and parentheses, so fields are counted
+    // from its closing parenthesis: state is the 3rd field, and starttime the 22nd.
+    let (_, fields) = stat.rsplit_once(')')?;
+    fields.split_whitespace().nth(19)?.parse().ok()
+}
+
+/// Returns the inode of a socket out of the target of its fd, as `socket:[12345]`.
+fn socket_inode(link: &str) -> Option<u64> {
+    link.strip_prefix("socket:[")?
+        .strip_suffix(']')?
+        .parse()
+        .ok()
+}
+
+fn net_namespace(proc_dir: &Path) -> NetNamespace {
+    let mut namespace = NetNamespace::default();
+    for (file, family) in [("net/tcp", AF_INET), ("net/tcp6", AF_INET6)] {
+        let Ok(table) = fs::read_to_string(proc_dir.join(file)) else {
+            continue;
+        };
+        for (inode, socket) in table
+            .lines()
+            .skip(1)
+            .filter_map(|line| tcp_socket(line, family))
+        {
+            match socket.state {
+                TCP_ESTABLISHED => {
+                    namespace.sockets.insert(inode, socket);
+                }
+                TCP_LISTEN => {
+                    namespace.listening.insert(socket.local.1);
+                }
+                _ => {}
+            }
+        }
+    }
+    namespace
+}
+
+/// Parses a line of `/proc/net/tcp{,6}`:
+/// `sl local_address rem_address st tx_queue:rx_queue tr:tm->when retrnsmt uid timeout inode`.
+fn tcp_socket(line: &str, family: u32) -> Option<(u64, TcpSocket)> {
+    let fields: Vec<_> = line.split_whitespace().collect();
+    let socket = TcpSocket {
+        family,
+        local: endpoint(fields.get(1)?)?,
+        remote: endpoint(fields.get(2)?)?,
+        state: u8::from_str_radix(fields.get(3)?, 16).ok()?,
+    };
+    Some((fields.get(9)?.parse().ok()?, socket))
+}
+
+/// Parses an address and port of `/proc/net/tcp{,6}`, as `0100007F:1F90`. The address
+/// is printed as the 32-bit words it is stored in, whose bytes are thus in host order.
+fn endpoint(field: &str) -> Option<(Ipv6Addr, u16)> {
+    let (addr, port) = field.split_once(':')?;
+    let mut octets = Vec::with_capacity(16);
+    for i in (0..addr.len()).step_by(8) {
+        let word = u32::from_str_radix(addr.get(i..i + 8)?, 16).ok()?;
+        octets.extend_from_slice(&word.to_ne_bytes());
+    }
+    let addr = match octets.len() {
+        4 => Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3]).to_ipv6_mapped(),
+        16 => Ipv6Addr::from(<[u8; 16]>::try_from(octets).ok()?),
+        _ => return None,
+    };
+    Some((addr, u16::from_str_radix(port, 16).ok()?))
+}
+
+fn set_addresses(conn_info: &mut ConnInfo, socket: &TcpSocket) {
+    conn_info.sa_family = socket.family;
+    conn_info.src_port = socket.local.1 as u32;
+    conn_info.dst_port = socket.remote.1 as u32;
+    match (
+        socket.local.0.to_ipv4_mapped(),
+        socket.remote.0.to_ipv4_mapped(),
+    ) {
+        (Some(src), Some(dst)) if socket.family == AF_INET => {
+            conn_info.src_addr_in4 = u32::from(src);
+            conn_info.dst_addr_in4 = u32::from(dst);
+        }
+        _ => {
+            conn_info.src_addr_in6 = socket.local.0.octets();
+            conn_info.dst_addr_in6 = socket.remote.0.octets();
+        }
+    }
+}
@@ -0,0 +1,315 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use log::{info, warn};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::time::{self, Instant};
+
+use socket_tracer::clock::ClockSync;
+use socket_tracer_common::{
+    ConnId, ControlEventType, SocketControlEvent, SocketDataEvent, TrafficDirection, AF_INET,
+    AF_INET6, AF_UNKNOWN, MAX_MSG_SIZE,
+};
+
+use crate::pcapng::{self, Segment};
+
+const DEFAULT_MAX_BYTES: usize = 16 << 20;
+const DEFAULT_DURATION: Duration = Duration::from_secs(60);
+const MAX_DURATION: Duration = Duration::from_secs(600);
+/// Segments buffered per session before new ones are dropped.
+const SESSION_QUEUE: usize = 1024;
+/// Large enough for the reconstructed IPv6 and TCP headers in front of a full message.
+const SNAPLEN: u32 = (MAX_MSG_SIZE + 64) as u32;
+
+/// Selects the connections of one pod endpoint: traffic where either side is
+/// `addr:port`. A zero port matches every port of the pod.
+#[derive(Debug, Clone, Copy)]
+pub struct CaptureSelector {
+    pub addr: IpAddr,
+    pub port: u16,
+}
+
+impl CaptureSelector {
+    fn matches(&self, a: SocketAddr, b: SocketAddr) -> bool {
+        [a, b]
+            .iter()
+            .any(|ep| ep.ip() == self.addr && (self.port == 0 || ep.port() == self.port))
+    }
+}
+
+/// Stops a capture once either bound is reached.
+#[derive(Debug, Clone, Copy)]
+pub struct CaptureLimits {
+    pub max_bytes: usize,
+    pub duration: Duration,
+}
+
+/// Identifies a connection across all its events by the id the probes gave it when it
+/// was first seen, which unlike its fd is never reused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ConnKey(u64);
+
+impl From<&ConnId> for ConnKey {
+    fn from(id: &ConnId) -> Self {
+        Self(id.conn_id)
+    }
+}
+
+#[derive(Debug)]
+struct ConnState {
+    local: SocketAddr,
+    remote: SocketAddr,
+    // End of the byte stream seen so far in each direction, used as the ack number
+    // of segments going the other way.
+    sent: u64,
+    received: u64,
+}
+
+#[derive(Debug)]
+struct Session {
+    selector: CaptureSelector,
+    tx: mpsc::Sender<Segment>,
+}
+
+/// Routes data events of selected connections to running captures. Data events don't
+/// carry addresses, so the endpoints of every open connection are tracked from the
+/// control events.
+#[derive(Debug)]
+pub struct CaptureHub {
+    clock: Arc<ClockSync>,
+    conns: Mutex<HashMap<ConnKey, ConnState>>,
+    sessions: RwLock<Vec<Session>>,
+    active: AtomicUsize,
+}
+
+impl CaptureHub {
+    pub fn new(clock: Arc<ClockSync>) -> Self {
+        Self {
+            clock,
+            conns: Mutex::default(),
+            sessions: RwLock::default(),
+            active: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn on_control(&self, event: &SocketControlEvent) {
+        let Ok(mut conns) = self.conns.lock() else {
+            return;
+        };
+        let key = ConnKey::from(&event.id);
+        match event.event_type {
+            // Only IP connections can be written as TCP segments. Unknown families are
+            // those of sockets accepted before tracing started, mostly IP ones.
+            ControlEventType::Open
+                if ![AF_INET, AF_INET6, AF_UNKNOWN].contains(&(event.sa_family as u32)) => {}
+            ControlEventType::Open => {
+                let (local, remote) = if event.sa_family == AF_INET6 as u64 {
+                    (
+                        Ipv6Addr::from(event.src_addr_in6).into(),
+                        Ipv6Addr::from(event.dst_addr_in6).into(),
+                    )
+                } else {
+                    (
+                        Ipv4Addr::from(event.src_addr_in4).into(),
+                        Ipv4Addr::from(event.dst_addr_in4).into(),
+                    )
+                };
+                conns.insert(
+                    key,
+                    ConnState {
+                        local: SocketAddr::new(local, event.src_port as u16),
+                        remote: SocketAddr::new(remote, event.dst_port as u16),
+                        sent: 0,
+                        received: 0,
+                    },
+                );
+            }
+            ControlEventType::Close => {
+                conns.remove(&key);
+            }
+        }
+    }
+
+    pub fn on_data(&self, event: &SocketDataEvent) {
+        if self.active.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+
+        let inner = &event.inner;
+        let segment = {
+            let Ok(mut conns) = self.conns.lock() else {
+                return;
+            };
+            // Data seen before the open event of its connection can't be attributed.
+            let Some(conn) = conns.get_mut(&ConnKey::from(&inner.id)) else {
+                return;
+            };
+            let Ok(sessions) = self.sessions.read() else {
+                return;
+            };
+            if !sessions
+                .iter()
+                .any(|s| s.selector.matches(conn.local, conn.remote))
+            {
+                return;
+            }
+
+            let end = inner.position + inner.msg_size as u64;
+            let (src, dst, ack) = match inner.direction {
+                TrafficDirection::Egress => {
+                    conn.sent = conn.sent.max(end);
+                    (conn.local, conn.remote, conn.received)
+                }
+                TrafficDirection::Ingress => {
+                    conn.received = conn.received.max(end);
+                    (conn.remote, conn.local, conn.sent)
+                }
+            };
+            let len = (inner.msg_buf_size as usize).min(MAX_MSG_SIZE);
+            Segment {
+                timestamp_ns: self.clock.to_wall(inner.timestamp_ns),
+                src,
+                dst,
+                seq: inner.position as u32,
+                ack: ack as u32,
+                msg_size: inner.msg_size,
+                payload: event.msg[..len].to_vec(),
+            }
+        };
+
+        let mut closed = false;
+        if let Ok(sessions) = self.sessions.read() {
+            for session in sessions.iter() {
+                if !session.selector.matches(segment.src, segment.dst) {
+                    continue;
+                }
+                if let Err(TrySendError::Closed(_)) = session.tx.try_send(segment.clone()) {
+                    closed = true;
+                }
+            }
+        }
+        if closed {
+            self.prune();
+        }
+    }
+
+    fn subscribe(&self, selector: CaptureSelector) -> mpsc::Receiver<Segment> {
+        let (tx, rx) = mpsc::channel(SESSION_QUEUE);
+        if let Ok(mut sessions) = self.sessions.write() {
+            sessions.retain(|s| !s.tx.is_closed());
+            sessions.push(Session { selector, tx });
+            self.active.store(sessions.len(), Ordering::Relaxed);
+        }
+        rx
+    }
+
+    fn prune(&self) {
+        if let Ok(mut sessions) = self.sessions.write() {
+            sessions.retain(|s| !s.tx.is_closed());
+            self.active.store(sessions.len(), Ordering::Relaxed);
+        }
+    }
+}
+
+/// Handles `GET /capture?addr=<pod ip>&port=<port>[&max_bytes=<n>][&seconds=<n>]` by
+/// streaming the selected traffic as a pcapng file until a limit is reached or the
+/// client goes away.
+pub async fn serve(mut stream: TcpStream, query: &str, hub: &CaptureHub) {
+    let (selector, limits) = match parse_query(query) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            let body = format!("{}\n", e);
+            let response = format!(
+                "HTTP/1.1 400 Bad Request\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            let _ = stream.write_all(response.as_bytes()).await;
+            return;
+        }
+    };
+
+    info!(
+        "Capturing {}:{} for up to {:?} or {} bytes",
+        selector.addr, selector.port, limits.duration, limits.max_bytes
+    );
+    let mut rx = hub.subscribe(selector);
+    let res = stream_capture(&mut stream, &mut rx, limits).await;
+    rx.close();
+    hub.prune();
+    match res {
+        Ok(written) => info!(
+            "Capture of {}:{} finished, {} bytes written",
+            selector.addr, selector.port, written
+        ),
+        Err(e) => warn!(
+            "Capture of {}:{} aborted: {}",
+            selector.addr, selector.port, e
+        ),
+    }
+}
+
+async fn stream_capture(
+    stream: &mut TcpStream,
+    rx: &mut mpsc::Receiver<Segment>,
+    limits: CaptureLimits,
+) -> anyhow::Result<usize> {
+    stream
+        .write_all(
+            b"HTTP/1.1 200 OK\r\nContent-Type: application/x-pcapng\r\nContent-Disposition: attachment; filename=\"capture.pcapng\"\r\nConnection: close\r\n\r\n",
+        )
+        .await?;
+    let header = pcapng::file_header(SNAPLEN);
+    stream.write_all(&header).await?;
+
+    let deadline = Instant::now() + limits.duration;
+    let mut written = header.len();
+    loop {
+        let segment = tokio::select! {
+            segment = rx.recv() => match segment {
+                Some(segment) => segment,
+                None => break,
+            },
+            _ = time::sleep_until(deadline) => break,
+        };
+        let block = pcapng::packet_block(&segment);
+        if written + block.len() > limits.max_bytes {
+            break;
+        }
+        stream.write_all(&block).await?;
+        written += block.len();
+    }
+    stream.shutdown().await?;
+    Ok(written)
+}
+
+fn parse_query(query: &str) -> anyhow::Result<(CaptureSelector, CaptureLimits)> {
+    let mut addr = None;
+    let mut port = 0;
+    let mut limits = CaptureLimits {
+        max_bytes: DEFAULT_MAX_BYTES,
+        duration: DEFAULT_DURATION,
+    };
+
+    for pair in query.split('&').filter(|p| !p.is_empty()) {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        match key {
+            "addr" => addr = Some(value.parse::<IpAddr>()?),
+            "port" => port = value.parse::<u16>()?,
+            "max_bytes" => limits.max_bytes = value.parse::<usize>()?,
+            "seconds" => {
+                limits.duration = Duration::from_secs(value.parse::<u64>()?).min(MAX_DURATION)
+            }
+            _ => return Err(anyhow::anyhow!("unknown capture parameter {:?}", key)),
+        }
+    }
+
+    let addr = addr.ok_or(anyhow::anyhow!("the addr parameter is required"))?;
+    Ok((CaptureSelector { addr, port }, limits))
+}
@@ -0,0 +1,183 @@
+//! Conversion of kprobe timestamps to wall-clock time.
+//!
+//! `bpf_ktime_get_ns` reads `CLOCK_MONOTONIC`, which starts at boot, stops while the
+//! node is suspended and is never stepped, whereas exported records need wall-clock
+//! timestamps that compare across nodes. The offset between the two clocks isn't
+//! constant: NTP slews `CLOCK_REALTIME` by up to 500 ppm and steps it when it is too
+//! far off. [`ClockSync`] is therefore calibrated periodically. Each calibration
+//! reads both clocks, measures how fast the offset moved since the previous one and
+//! extrapolates with that rate until the next, so that timestamps don't jump by the
+//! accumulated drift at every calibration. An offset moving faster than NTP ever slews
+//! means the clock was stepped, and the rate is reset.
+
+use std::mem;
+use std::sync::RwLock;
+
+/// Fastest rate at which NTP slews the clock, in parts per billion.
+const MAX_DRIFT_PPB: i128 = 500_000;
+/// Clock reads per calibration, of which the tightest is kept.
+const CALIBRATION_READS: usize = 5;
+
+/// Simultaneous readings of both clocks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClockSample {
+    pub monotonic_ns: u64,
+    pub realtime_ns: u64,
+}
+
+impl ClockSample {
+    /// Reads both clocks. The realtime read is bracketed by two monotonic ones and the
+    /// narrowest bracket of a few attempts is kept, so that a preemption between the
+    /// reads doesn't skew the sample.
+    pub fn now() -> Self {
+        let mut best = (u64::MAX, None);
+        for _ in 0..CALIBRATION_READS {
+            let before = read(libc::CLOCK_MONOTONIC);
+            let realtime_ns = read(libc::CLOCK_REALTIME);
+            let after = read(libc::CLOCK_MONOTONIC);
+            let window = after.saturating_sub(before);
+            if best.1.is_none() || window < best.0 {
+                let sample = ClockSample {
+                    monotonic_ns: before + window / 2,
+                    realtime_ns,
+                };
+                best = (window, Some(sample));
+            }
+        }
+        best.1.expect("clocks were read at least once")
+    }
+
+    fn offset(&self) -> i128 {
+        self.realtime_ns as i128 - self.monotonic_ns as i128
+    }
+}
+
+fn read(clock: libc::clockid_t) -> u64 {
+    let mut ts: libc::timespec = unsafe { mem::zeroed() };
+    unsafe { libc::clock_gettime(clock, &mut ts) };
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+/// Linear mapping from monotonic to wall-clock time, anchored at the last calibration.
+#[derive(Debug, Clone, Copy)]
+pub struct ClockModel {
+    anchor: ClockSample,
+    /// Rate at which the offset moves, in parts per billion.
+    drift_ppb: i128,
+}
+
+impl ClockModel {
+    pub fn new(anchor: ClockSample) -> Self {
+        Self {
+            anchor,
+            drift_ppb: 0,
+        }
+    }
+
+    /// Converts a `CLOCK_MONOTONIC` timestamp to nanoseconds since the Unix epoch.
+    pub fn to_wall(&self, monotonic_ns: u64) -> u64 {
+        let elapsed = monotonic_ns as i128 - self.anchor.monotonic_ns as i128;
+        let wall =
+            monotonic_ns as i128 + self.anchor.offset() + elapsed * self.drift_ppb / 1_000_000_000;
+        wall.clamp(0, u64::MAX as i128) as u64
+    }
+
+    /// Re-anchors the mapping at `sample` and updates the drift with the rate measured
+    /// since the previous anchor. Samples taken before the anchor are ignored.
+    pub fn calibrate(&mut self, sample: ClockSample) {
+        if sample.monotonic_ns <= self.anchor.monotonic_ns {
+            return;
+        }
+        let elapsed = (sample.monotonic_ns - self.anchor.monotonic_ns) as i128;
+        let measured = (sample.offset() - self.anchor.offset()) * 1_000_000_000 / elapsed;
+        self.drift_ppb = if measured.abs() > MAX_DRIFT_PPB {
+            // Stepped or resumed from suspend, the previous rate is meaningless.
+            0
+        } else if self.drift_ppb == 0 {
+            measured
+        } else {
+            // Smooth out the jitter of individual samples.
+            (self.drift_ppb + measured) / 2
+        };
+        self.anchor = sample;
+    }
+}
+
+/// A [`ClockModel`] shared between the event handlers and the task calibrating it.
+#[derive(Debug)]
+pub struct ClockSync {
+    model: RwLock<ClockModel>,
+}
+
+impl Default for ClockSync {
+    fn default() -> Self {
+        Self {
+            model: RwLock::new(ClockModel::new(ClockSample::now())),
+        }
+    }
+}
+
+impl ClockSync {
+    /// Starts from a calibration taken elsewhere, such as the first one of a recording.
+    pub fn new(anchor: ClockSample) -> Self {
+        Self {
+            model: RwLock::new(ClockModel::new(anchor)),
+        }
+    }
+
+    pub fn to_wall(&self, monotonic_ns: u64) -> u64 {
+        match self.model.read() {
+            Ok(model) => model.to_wall(monotonic_ns),
+            Err(e) => e.into_inner().to_wall(monotonic_ns),
+        }
+    }
+
+    /// Calibrates against the clocks now and returns the sample taken.
+    pub fn calibrate(&self) -> ClockSample {
+        let sample = ClockSample::now();
+        self.calibrate_with(sample);
+        sample
+    }
+
+    pub fn calibrate_with(&self, sample: ClockSample) {
+        if let Ok(mut model) = self.model.write() {
+            model.calibrate(sample);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ClockModel, ClockSample};
+
+    const SECOND: u64 = 1_000_000_000;
+    const EPOCH: u64 = 1_700_000_000 * SECOND;
+
+    fn sample(monotonic_ns: u64, realtime_ns: u64) -> ClockSample {
+        ClockSample {
+            monotonic_ns,
+            realtime_ns,
+        }
+    }
+
+    #[test]
+    fn test_drift_correction() {
+        // The wall clock runs 100 ppm faster than the monotonic one.
+        let wall = |mono: u64| EPOCH + mono + (mono - SECOND) / 10_000;
+        let mut model = ClockModel::new(sample(SECOND, wall(SECOND)));
+        model.calibrate(sample(11 * SECOND, wall(11 * SECOND)));
+        // Extrapolated with the measured rate rather than the last offset.
+        assert_eq!(model.to_wall(21 * SECOND), wall(21 * SECOND));
+        assert_eq!(model.to_wall(6 * SECOND), wall(6 * SECOND));
+    }
+
+    #[test]
+    fn test_step() {
+        let mut model = ClockModel::new(sample(SECOND, EPOCH));
+        model.calibrate(sample(2 * SECOND, EPOCH + SECOND + 1000));
+        // Set back by an hour.
+        let stepped = EPOCH + 2 * SECOND - 3600 * SECOND;
+        model.calibrate(sample(3 * SECOND, stepped));
+        assert_eq!(model.to_wall(4 * SECOND), stepped + SECOND);
+    }
+}
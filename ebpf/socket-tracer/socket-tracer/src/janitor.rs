@@ -0,0 +1,157 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::mem;
+use std::os::fd::{AsFd, AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use aya::maps::MapData;
+use log::{info, warn};
+use tokio::time;
+
+/// Maps holding the arguments stashed by entry probes, keyed by `pid_tgid`.
+const THREAD_MAPS: &[&str] = &[
+    "accept_args",
+    "conn_args",
+    "write_args",
+    "read_args",
+    "sendfile_args",
+    "close_args",
+];
+/// Maps holding per-connection state, keyed by `tgid << 32 | fd`.
+const PROCESS_MAPS: &[&str] = &["conn_info", "conn_disabled"];
+/// Keys visited per map and sweep, twice the size of the maps, since the iteration
+/// restarts whenever the ret probes remove the key it stands on.
+const MAX_VISITED_KEYS: usize = 2 * 128 * 1024;
+/// Inode of the initial PID namespace, `PROC_PID_INIT_INO` in the kernel.
+const INIT_PID_NS: &str = "pid:[4026531836]";
+
+const BPF_MAP_DELETE_ELEM: libc::c_long = 3;
+const BPF_MAP_GET_NEXT_KEY: libc::c_long = 4;
+
+/// Periodically removes the state left behind by threads and processes that exited.
+/// The `sched_process_exit` tracepoint already clears the arguments of exiting threads,
+/// but an exit can be missed while the tracer restarts, and connections closed by the
+/// process exiting never go through `close`. Keys only tell kernel PIDs apart, so the
+/// sweep is skipped unless the tracer runs in the host PID namespace.
+pub async fn run(map_dir: PathBuf, interval: Duration) {
+    if !in_host_pid_namespace() {
+        warn!("not in the host PID namespace, stale probe state won't be swept");
+        return;
+    }
+
+    let mut interval = time::interval(interval);
+    loop {
+        interval.tick().await;
+        match sweep(&map_dir) {
+            Ok(0) => {}
+            Ok(removed) => info!("removed {} stale probe map entries", removed),
+            Err(e) => warn!("failed to sweep probe maps: {}", e),
+        }
+    }
+}
+
+/// Whether the PIDs of `/proc` are those of the kernel, which the probe maps are keyed by.
+pub fn in_host_pid_namespace() -> bool {
+    fs::read_link("/proc/self/ns/pid").is_ok_and(|ns| ns.as_os_str() == INIT_PID_NS)
+}
+
+fn sweep(map_dir: &Path) -> anyhow::Result<usize> {
+    let mut removed = 0;
+    for name in THREAD_MAPS {
+        removed += sweep_map(&map_dir.join(name), |key| {
+            let (tgid, pid) = (key >> 32, key as u32);
+            Path::new(&format!("/proc/{}/task/{}", tgid, pid)).exists()
+        })?;
+    }
+    for name in PROCESS_MAPS {
+        removed += sweep_map(&map_dir.join(name), |key| {
+            Path::new(&format!("/proc/{}", key >> 32)).exists()
+        })?;
+    }
+    Ok(removed)
+}
+
+/// Removes the entries of a map with `u64` keys whose key isn't `alive`. Values are
+/// never read, so this works on maps of any value type.
+fn sweep_map(path: &Path, alive: impl Fn(u64) -> bool) -> anyhow::Result<usize> {
+    let map_data =
+        MapData::from_pin(path).map_err(|_| anyhow::anyhow!("No maps named {:?}", path))?;
+    let fd = map_data.fd().as_fd().as_raw_fd();
+
+    // Collected first, as deleting while iterating would restart the iteration.
+    let mut stale = HashSet::new();
+    let mut key = None;
+    for _ in 0..MAX_VISITED_KEYS {
+        let Some(next) = next_key(fd, key)? else {
+            break;
+        };
+        if !alive(next) {
+            stale.insert(next);
+        }
+        key = Some(next);
+    }
+
+    let mut removed = 0;
+    for key in stale {
+        match delete(fd, key) {
+            Ok(()) => removed += 1,
+            // Removed by its ret probe meanwhile.
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(removed)
+}
+
+/// `union bpf_attr` as used by the map element commands.
+#[repr(C)]
+#[derive(Default)]
+struct MapElemAttr {
+    map_fd: u32,
+    _pad: u32,
+    key: u64,
+    value_or_next_key: u64,
+    flags: u64,
+}
+
+fn bpf(cmd: libc::c_long, attr: &mut MapElemAttr) -> io::Result<()> {
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_bpf,
+            cmd,
+            attr as *mut MapElemAttr,
+            mem::size_of::<MapElemAttr>(),
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn next_key(fd: RawFd, key: Option<u64>) -> io::Result<Option<u64>> {
+    let mut next = 0u64;
+    let mut attr = MapElemAttr {
+        map_fd: fd as u32,
+        // A null key starts the iteration.
+        key: key.as_ref().map_or(0, |k| k as *const u64 as u64),
+        value_or_next_key: &mut next as *mut u64 as u64,
+        ..Default::default()
+    };
+    match bpf(BPF_MAP_GET_NEXT_KEY, &mut attr) {
+        Ok(()) => Ok(Some(next)),
+        Err(e) if e.raw_os_error() == Some(libc::ENOENT) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn delete(fd: RawFd, key: u64) -> io::Result<()> {
+    let mut attr = MapElemAttr {
+        map_fd: fd as u32,
+        key: &key as *const u64 as u64,
+        ..Default::default()
+    };
+    bpf(BPF_MAP_DELETE_ELEM, &mut attr)
+}
@@ -0,0 +1,13 @@
+//! Userspace parts of the socket tracer usable without the eBPF programs, such as the
+//! protocol parsers, the stream reassembly feeding them, the decoding of perf samples,
+//! the clock calibration, the recordings of raw samples and the resolution of the
+//! kernel symbols to probe, so that they can be fuzzed, benchmarked and tested on their
+//! own.
+
+pub mod clock;
+pub mod protocols;
+pub mod reassembly;
+pub mod recording;
+pub mod scratch;
+pub mod symbols;
+pub mod synthetic;
@@ -1,42 +1,167 @@
-use std::path::Path;
-use std::ptr;
-use std::sync::Arc;
-
-use aya::maps::{AsyncPerfEventArray, Map, MapData};
-use aya::util::online_cpus;
+use std::io;
+use std::mem;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::Context;
+use aya::maps::{AsyncPerfEventArray, Map, MapData, PerCpuArray, PerCpuValues};
+use aya::util::{nr_cpus, online_cpus};
 use bytes::BytesMut;
-use log::{debug, info};
-use tokio::signal;
-use tokio::sync::Notify;
-
-use socket_tracer_common::{ConnStatsEvent, SocketControlEvent, SocketDataEvent};
-
-mod accept;
-mod accept4;
-mod close;
-mod connect;
-mod read;
-mod readv;
-mod recv;
-mod recvfrom;
-mod recvmmsg;
-mod recvmsg;
-mod send;
-mod sendfile;
-mod sendmmsg;
-mod sendmsg;
-mod sendto;
-mod sockalloc;
-mod ssendmsg;
-mod write;
-mod writev;
+use clap::Parser;
+use log::{debug, info, warn};
+use prometheus_client::registry::Registry;
+use tokio::{runtime, signal, time};
+
+use socket_tracer::clock::{ClockSample, ClockSync};
+use socket_tracer::reassembly::ReassemblyConfig;
+use socket_tracer::recording::{Recorder, Replay, Stream};
+use socket_tracer::scratch::EventScratch;
+use socket_tracer_common::{
+    unix_path, ConnStatsEvent, ControlValueIndex, DropStage, SocketControlEvent, SocketDataEvent,
+    SocketDataEventInner, AF_INET, AF_INET6, AF_UNIX, AF_VSOCK, DEFAULT_SNAP_LEN, MAX_MSG_SIZE,
+};
+
+use crate::capture::CaptureHub;
+use crate::metrics::{DropStats, DropStatsCollector, ProbeCollector, ReassemblyCollector};
+use crate::streams::StreamHub;
+
+mod bootstrap;
+mod capture;
+mod janitor;
+mod metrics;
+mod pcapng;
+mod probes;
+mod streams;
 
 const BPF_MAP_PATH: &str = "/sys/fs/bpf";
+/// How often the state left behind by exited threads is swept from the probe maps.
+const JANITOR_INTERVAL: Duration = Duration::from_secs(60);
+/// How often the kprobes are verified to still be attached.
+const PROBE_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+/// How often the mapping of kprobe timestamps to wall-clock time is recalibrated.
+const CLOCK_CALIBRATION_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Parser, Debug)]
+#[command(name = "socket-tracer")]
+struct Args {
+    /// Optional: Use the probes loaded and attached by bpfman instead of loading
+    /// them, so that bpfman owns their lifecycle like the other programs of the
+    /// node. Their maps are found in the bpfman directory of --map-owner.
+    #[clap(long, verbatim_doc_comment)]
+    bpfman: bool,
+    /// Optional: Name of the program loaded first by bpfman, whose maps the other
+    /// probes were loaded to share.
+    #[clap(long, verbatim_doc_comment, default_value = "entry_connect")]
+    map_owner: String,
+    /// Optional: Record the raw samples of every perf event array to this file, to
+    /// replay them later with --replay.
+    #[clap(long, verbatim_doc_comment)]
+    record: Option<PathBuf>,
+    /// Optional: Replay a recording through the parsers and the reassembly instead
+    /// of tracing, as fast as it can be read, then exit.
+    #[clap(long, verbatim_doc_comment, conflicts_with_all = ["bpfman", "record"])]
+    replay: Option<PathBuf>,
+    /// Optional: Bytes of each message captured by the probes, like the snap length
+    /// of tcpdump. The default is enough for the headers of most messages, which is
+    /// what the parsers need; 0 captures as much as the probes can, up to 30720.
+    #[clap(long, verbatim_doc_comment, default_value_t = DEFAULT_SNAP_LEN)]
+    snaplen: usize,
+}
+
+/// The handlers of each perf event array, shared by the consumers and the replay.
+struct Handlers {
+    control: Arc<dyn Fn(&SocketControlEvent) + Send + Sync>,
+    stats: Arc<dyn Fn(&ConnStatsEvent) + Send + Sync>,
+    data: Arc<dyn Fn(&SocketDataEvent) + Send + Sync>,
+}
+
+impl Handlers {
+    fn new(clock: Arc<ClockSync>, captures: Arc<CaptureHub>, streams: Arc<StreamHub>) -> Self {
+        let ctrl_captures = captures.clone();
+        let ctrl_streams = streams.clone();
+        let stats_clock = clock.clone();
+        Self {
+            control: Arc::new(move |event: &SocketControlEvent| {
+                info!(
+                    "sk_ctrl_event id: {:?}, remote: {}",
+                    event.id,
+                    remote_endpoint(event)
+                );
+                ctrl_captures.on_control(event);
+                ctrl_streams.on_control(event);
+            }),
+            stats: Arc::new(move |event: &ConnStatsEvent| {
+                info!(
+                    "conn_stat_event id: {:?} at {}",
+                    event.id,
+                    stats_clock.to_wall(event.timestamp_ns)
+                );
+            }),
+            data: Arc::new(move |event: &SocketDataEvent| {
+                info!(
+                    "sk_data_event uid: {:?} at {}",
+                    event.inner.id,
+                    clock.to_wall(event.inner.timestamp_ns)
+                );
+                let msg_str = String::from_utf8_lossy(&event.msg[..48]);
+                info!(
+                    "sk_data_event source function: {:?}, msg : {:?}",
+                    event.inner.source_function, msg_str
+                );
+                captures.on_data(event);
+                streams.on_data(event);
+            }),
+        }
+    }
+}
+
+/// Describes the remote end of a connection: its address and port, the path of the
+/// unix-domain socket connected to, as `unix:/run/app.sock` or `unix:@name` for an
+/// abstract one, or the context id and port of a vsock peer, as `vsock:3:1024`.
+fn remote_endpoint(event: &SocketControlEvent) -> String {
+    match event.sa_family as u32 {
+        AF_INET => format!("{}:{}", Ipv4Addr::from(event.dst_addr_in4), event.dst_port),
+        AF_INET6 => format!(
+            "[{}]:{}",
+            Ipv6Addr::from(event.dst_addr_in6),
+            event.dst_port
+        ),
+        AF_UNIX => {
+            let (path, abstract_name) = unix_path(&event.unix_path);
+            let prefix = if abstract_name { "@" } else { "" };
+            format!("unix:{}{}", prefix, String::from_utf8_lossy(path))
+        }
+        AF_VSOCK => format!("vsock:{}:{}", event.dst_addr_in4, event.dst_port),
+        _ => "unknown".to_string(),
+    }
+}
+
+/// The smallest sample of each stream that can be decoded.
+fn min_size(stream: Stream) -> usize {
+    match stream {
+        Stream::Control => mem::size_of::<SocketControlEvent>(),
+        Stream::Stats => mem::size_of::<ConnStatsEvent>(),
+        Stream::Data => mem::size_of::<SocketDataEventInner>(),
+        Stream::Clock => 0,
+    }
+}
 
+/// Consumes a pinned perf event array with one consumer thread per online CPU. Each
+/// thread is pinned to the CPU whose buffer it drains and runs its own single-threaded
+/// runtime, so a busy CPU cannot starve the consumers of the others. Samples shorter
+/// than the minimum size of `stream` cannot be decoded as `T` and are counted as parse
+/// drops instead of being handled. Every sample, short or not, is written to `recorder`.
 async fn process_perf_events<T: 'static>(
     map_path: &Path,
+    stream: Stream,
+    drop_stats: Arc<DropStats>,
+    recorder: Option<Arc<Recorder>>,
     event_handler: Arc<dyn Fn(&T) + Send + Sync>,
 ) -> Result<(), anyhow::Error> {
+    let min_size = min_size(stream);
     let cpus = online_cpus()?;
     let num_cpus = cpus.len();
     let map_data =
@@ -44,26 +169,146 @@ async fn process_perf_events<T: 'static>(
     let map: Map = Map::PerfEventArray(map_data)
         .try_into()
         .map_err(|_| anyhow::anyhow!("Failed to convert map"))?;
-    let mut events = AsyncPerfEventArray::try_from(map)?;
+    let events = Arc::new(Mutex::new(AsyncPerfEventArray::try_from(map)?));
     for cpu in cpus {
-        let mut buf = events.open(cpu, None)?;
+        let events = events.clone();
         let event_handler = event_handler.clone();
+        let drop_stats = drop_stats.clone();
+        let recorder = recorder.clone();
+        let map_name = map_path.display().to_string();
+
+        thread::Builder::new()
+            .name(format!("perf-consumer-{}", cpu))
+            .spawn(move || {
+                if let Err(e) = pin_to_cpu(cpu) {
+                    warn!("failed to pin {} consumer to cpu {}: {}", map_name, cpu, e);
+                }
+                let rt = match runtime::Builder::new_current_thread().enable_all().build() {
+                    Ok(rt) => rt,
+                    Err(e) => {
+                        warn!("failed to build runtime for cpu {}: {}", cpu, e);
+                        return;
+                    }
+                };
+
+                let res: Result<(), anyhow::Error> = rt.block_on(async move {
+                    // The buffer has to be opened inside this runtime so that its fd is
+                    // registered with the reactor of the pinned thread.
+                    let mut buf = events
+                        .lock()
+                        .map_err(|_| anyhow::anyhow!("perf event array lock poisoned"))?
+                        .open(cpu, None)?;
+                    let mut buffers = (0..num_cpus)
+                        .map(|_| BytesMut::with_capacity(9000))
+                        .collect::<Vec<_>>();
+                    let mut scratch = EventScratch::<T>::new();
+
+                    loop {
+                        let events = buf.read_events(&mut buffers).await?;
+                        for i in 0..events.read {
+                            let buf = &mut buffers[i];
+                            if let Some(recorder) = &recorder {
+                                if let Err(e) = recorder.record(stream, cpu, buf) {
+                                    warn!("failed to record {} sample: {}", map_name, e);
+                                }
+                            }
+                            if buf.len() < min_size {
+                                drop_stats.record(DropStage::Parse, cpu);
+                                continue;
+                            }
+
+                            event_handler(scratch.decode(buf));
+                        }
+                    }
+                });
+                if let Err(e) = res {
+                    warn!("{} consumer on cpu {} exited: {}", map_name, cpu, e);
+                }
+            })?;
+    }
+    Ok(())
+}
 
-        tokio::spawn(async move {
-            let mut buffers = (0..num_cpus)
-                .map(|_| BytesMut::with_capacity(9000))
-                .collect::<Vec<_>>();
-
-            loop {
-                let events = buf.read_events(&mut buffers).await.unwrap();
-                for i in 0..events.read {
-                    let buf = &mut buffers[i];
-
-                    let event = unsafe { ptr::read_unaligned(buf.as_ptr() as *const T) };
-                    event_handler(&event);
+/// Feeds a recording through the handlers. Events are converted to wall-clock time
+/// with the calibrations taken while recording, starting with the one at its head.
+fn replay(path: &Path) -> Result<(), anyhow::Error> {
+    let mut records = Replay::open(path)
+        .with_context(|| format!("Failed to open recording {:?}", path))?
+        .peekable();
+    let clock = match records.peek() {
+        Some(Ok(record)) => record.clock_sample().map(ClockSync::new),
+        _ => None,
+    };
+    let clock = Arc::new(clock.unwrap_or_default());
+    let streams = Arc::new(StreamHub::new(ReassemblyConfig::default()));
+    let handlers = Handlers::new(
+        clock.clone(),
+        Arc::new(CaptureHub::new(clock.clone())),
+        streams.clone(),
+    );
+
+    let mut control = EventScratch::<SocketControlEvent>::new();
+    let mut stats = EventScratch::<ConnStatsEvent>::new();
+    let mut data = EventScratch::<SocketDataEvent>::new();
+    let (mut replayed, mut short) = (0, 0);
+    for record in records {
+        let record = match record {
+            Ok(record) => record,
+            Err(e) => {
+                // Most likely the last record, cut short when the tracer was killed.
+                warn!("stopped reading recording {:?}: {}", path, e);
+                break;
+            }
+        };
+        if record.data.len() < min_size(record.stream) {
+            short += 1;
+            continue;
+        }
+        match record.stream {
+            Stream::Control => (handlers.control)(control.decode(&record.data)),
+            Stream::Stats => (handlers.stats)(stats.decode(&record.data)),
+            Stream::Data => (handlers.data)(data.decode(&record.data)),
+            Stream::Clock => {
+                if let Some(sample) = record.clock_sample() {
+                    clock.calibrate_with(sample);
                 }
+                continue;
             }
-        });
+        }
+        replayed += 1;
+    }
+
+    info!(
+        "replayed {} samples, {} too short to decode",
+        replayed, short
+    );
+    for (protocol, stats) in streams.stats() {
+        info!("{} reassembly: {:?}", protocol, stats);
+    }
+    Ok(())
+}
+
+/// Sets the snap length the probes truncate messages to, 0 meaning as much as they can.
+fn set_snap_len(map_path: &Path, snap_len: usize) -> Result<(), anyhow::Error> {
+    let snap_len = match snap_len {
+        0 => MAX_MSG_SIZE,
+        snap_len => snap_len.min(MAX_MSG_SIZE),
+    };
+    let map_data =
+        MapData::from_pin(map_path).map_err(|_| anyhow::anyhow!("No maps named {:?}", map_path))?;
+    let mut array: PerCpuArray<_, i64> = Map::PerCpuArray(map_data).try_into()?;
+    let values = PerCpuValues::try_from(vec![snap_len as i64; nr_cpus()?])?;
+    array.set(ControlValueIndex::SnapLenIndex as u32, values, 0)?;
+    info!("capturing up to {} bytes of each message", snap_len);
+    Ok(())
+}
+
+fn pin_to_cpu(cpu: u32) -> io::Result<()> {
+    let mut set: libc::cpu_set_t = unsafe { mem::zeroed() };
+    unsafe { libc::CPU_SET(cpu as usize, &mut set) };
+    let ret = unsafe { libc::sched_setaffinity(0, mem::size_of::<libc::cpu_set_t>(), &set) };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
     }
     Ok(())
 }
@@ -71,7 +316,11 @@ async fn process_perf_events<T: 'static>(
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
     env_logger::init();
-    let notify = Arc::new(Notify::new());
+    let args = Args::parse();
+
+    if let Some(path) = &args.replay {
+        return replay(path);
+    }
 
     // Bump the memlock rlimit. This is needed for older kernels that don't use the
     // new memcg based accounting, see https://lwn.net/Articles/837122/
@@ -84,135 +333,113 @@ async fn main() -> Result<(), anyhow::Error> {
         debug!("remove limit on locked memory failed, ret is: {}", ret);
     }
 
-    let notify_connect = notify.clone();
-    tokio::spawn(async move {
-        connect::run(notify_connect).await.unwrap();
-    });
-
-    let notify_accept = notify.clone();
-    tokio::spawn(async move {
-        accept::run(notify_accept).await.unwrap();
-    });
-
-    let notify_accept4 = notify.clone();
-    tokio::spawn(async move {
-        accept4::run(notify_accept4).await.unwrap();
-    });
-
-    let notify_close = notify.clone();
-    tokio::spawn(async move {
-        close::run(notify_close).await.unwrap();
-    });
-
-    let notify_send = notify.clone();
-    tokio::spawn(async move {
-        send::run(notify_send).await.unwrap();
-    });
-
-    let notify_sendto = notify.clone();
-    tokio::spawn(async move {
-        sendto::run(notify_sendto).await.unwrap();
-    });
-
-    let notify_sendmsg = notify.clone();
-    tokio::spawn(async move {
-        sendmsg::run(notify_sendmsg).await.unwrap();
-    });
-
-    let notify_sendmmsg = notify.clone();
-    tokio::spawn(async move {
-        sendmmsg::run(notify_sendmmsg).await.unwrap();
-    });
-
-    let notify_sendfile = notify.clone();
-    tokio::spawn(async move {
-        sendfile::run(notify_sendfile).await.unwrap();
-    });
-
-    let notify_ssendmsg = notify.clone();
-    tokio::spawn(async move {
-        ssendmsg::run(notify_ssendmsg).await.unwrap();
-    });
-
-    let notify_recv = notify.clone();
-    tokio::spawn(async move {
-        recv::run(notify_recv).await.unwrap();
-    });
-
-    let notify_recvfrom = notify.clone();
-    tokio::spawn(async move {
-        recvfrom::run(notify_recvfrom).await.unwrap();
-    });
-
-    let notify_recvmsg = notify.clone();
-    tokio::spawn(async move {
-        recvmsg::run(notify_recvmsg).await.unwrap();
-    });
-
-    let notify_recvmmsg = notify.clone();
-    tokio::spawn(async move {
-        recvmmsg::run(notify_recvmmsg).await.unwrap();
-    });
-
-    let notify_read = notify.clone();
-    tokio::spawn(async move {
-        read::run(notify_read).await.unwrap();
-    });
-
-    let notify_readv = notify.clone();
-    tokio::spawn(async move {
-        readv::run(notify_readv).await.unwrap();
-    });
+    // Loaded before the maps are opened from their pins below.
+    let (probes, _parser, map_dir) = if args.bpfman {
+        let map_dir = probes::bpfman_map_dir(&args.map_owner)?;
+        let parser = probes::load_parser(&map_dir)?;
+        (None, Some(parser), map_dir)
+    } else {
+        (Some(probes::load()?), None, PathBuf::from(BPF_MAP_PATH))
+    };
 
-    let notify_write = notify.clone();
-    tokio::spawn(async move {
-        write::run(notify_write).await.unwrap();
+    let bpf_map_path = map_dir.as_path();
+    set_snap_len(&bpf_map_path.join("ctrl_values"), args.snaplen)?;
+    if let Err(e) = bootstrap::run(&bpf_map_path.join("conn_info")) {
+        warn!(
+            "failed to seed the connections opened before starting: {}",
+            e
+        );
+    }
+    tokio::spawn(janitor::run(bpf_map_path.to_path_buf(), JANITOR_INTERVAL));
+
+    let drop_stats = Arc::new(DropStats::new(nr_cpus()?));
+    let mut registry = Registry::default();
+    registry.register_collector(Box::new(DropStatsCollector::new(
+        bpf_map_path.join("drop_stats"),
+        drop_stats.clone(),
+    )));
+    let probes = probes.map(|probes| {
+        registry.register_collector(Box::new(ProbeCollector::new(probes.status())));
+        let probes = Arc::new(Mutex::new(probes));
+        let checked_probes = probes.clone();
+        tokio::spawn(async move {
+            let mut interval = time::interval(PROBE_CHECK_INTERVAL);
+            loop {
+                interval.tick().await;
+                if let Ok(mut probes) = checked_probes.lock() {
+                    probes.check();
+                }
+            }
+        });
+        probes
     });
-
-    let notify_writev = notify.clone();
+    let streams = Arc::new(StreamHub::new(ReassemblyConfig::default()));
+    registry.register_collector(Box::new(ReassemblyCollector::new(streams.clone())));
+    let recorder = match &args.record {
+        Some(path) => {
+            Some(Arc::new(Recorder::create(path).with_context(|| {
+                format!("Failed to create recording {:?}", path)
+            })?))
+        }
+        None => None,
+    };
+    // A recording starts with the calibration replayed events are converted with.
+    let anchor = ClockSample::now();
+    if let Some(recorder) = &recorder {
+        recorder.record_clock(anchor)?;
+    }
+    let clock = Arc::new(ClockSync::new(anchor));
+    let calibrated_clock = clock.clone();
+    let clock_recorder = recorder.clone();
     tokio::spawn(async move {
-        writev::run(notify_writev).await.unwrap();
+        let mut interval = time::interval(CLOCK_CALIBRATION_INTERVAL);
+        loop {
+            interval.tick().await;
+            let sample = calibrated_clock.calibrate();
+            if let Some(recorder) = &clock_recorder {
+                if let Err(e) = recorder.record_clock(sample) {
+                    warn!("failed to record clock calibration: {}", e);
+                }
+            }
+        }
     });
-
-    let notify_sockalloc = notify.clone();
+    let captures = Arc::new(CaptureHub::new(clock.clone()));
+    let metrics_captures = captures.clone();
     tokio::spawn(async move {
-        sockalloc::run(notify_sockalloc).await.unwrap();
+        if let Err(e) = metrics::serve(registry, metrics_captures).await {
+            warn!("metrics server exited: {}", e);
+        }
     });
 
-    let bpf_map_path = std::path::Path::new(BPF_MAP_PATH);
+    let handlers = Handlers::new(clock, captures, streams);
 
     // handle sk_ctrl_events
-    let sk_ctrl_events_map_path = bpf_map_path.join("sk_ctrl_events");
     process_perf_events(
-        &sk_ctrl_events_map_path,
-        Arc::new(|event: &SocketControlEvent| {
-            info!("sk_ctrl_event id: {:?}", event.id);
-        }),
+        &bpf_map_path.join("sk_ctrl_events"),
+        Stream::Control,
+        drop_stats.clone(),
+        recorder.clone(),
+        handlers.control,
     )
     .await?;
 
     // handle conn_stat_events
-    let conn_stat_events_map_path = bpf_map_path.join("conn_stat_events");
     process_perf_events(
-        &conn_stat_events_map_path,
-        Arc::new(|event: &ConnStatsEvent| {
-            info!("conn_stat_event id: {:?}", event.id);
-        }),
+        &bpf_map_path.join("conn_stat_events"),
+        Stream::Stats,
+        drop_stats.clone(),
+        recorder.clone(),
+        handlers.stats,
     )
     .await?;
 
     // handle sk_data_events
-    let sk_data_events_map_path = bpf_map_path.join("sk_data_events");
     process_perf_events(
-        &sk_data_events_map_path,
-        Arc::new(|event: &SocketDataEvent| {
-            info!("sk_data_event uid: {:?}", event.inner.id);
-            let msg_str = String::from_utf8_lossy(&event.msg[..48]);
-            info!(
-                "sk_data_event source function: {:?}, msg : {:?}",
-                event.inner.source_function, msg_str
-            );
-        }),
+        &bpf_map_path.join("sk_data_events"),
+        Stream::Data,
+        drop_stats.clone(),
+        recorder.clone(),
+        handlers.data,
     )
     .await?;
 
@@ -220,7 +447,11 @@ async fn main() -> Result<(), anyhow::Error> {
     signal::ctrl_c().await?;
     info!("Exiting...");
 
-    notify.notify_one();
+    // The probes stay attached until exiting, unless bpfman owns them.
+    drop(probes);
+    if let Some(recorder) = recorder {
+        recorder.flush()?;
+    }
 
     Ok(())
 }
@@ -0,0 +1,317 @@
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use aya::maps::{Map, MapData, PerCpuArray};
+use log::{info, warn};
+use prometheus_client::collector::Collector;
+use prometheus_client::encoding::{text::encode, DescriptorEncoder, EncodeMetric};
+use prometheus_client::metrics::counter::ConstCounter;
+use prometheus_client::metrics::gauge::ConstGauge;
+use prometheus_client::registry::Registry;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+use socket_tracer_common::DropStage;
+
+use crate::capture::{self, CaptureHub};
+use crate::probes::ProbeStatus;
+use crate::streams::StreamHub;
+
+const METRICS_ADDR: &str = "0.0.0.0:9464";
+
+/// Per-CPU drop counters for the stages that are only observable from userspace.
+#[derive(Debug)]
+pub struct DropStats {
+    parse: Vec<AtomicU64>,
+}
+
+impl DropStats {
+    pub fn new(nr_cpus: usize) -> Self {
+        Self {
+            parse: (0..nr_cpus).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+
+    pub fn record(&self, stage: DropStage, cpu: u32) {
+        match stage {
+            DropStage::Parse => {
+                if let Some(counter) = self.parse.get(cpu as usize) {
+                    counter.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            _ => warn!("drop stage {} is counted in kernel", stage.name()),
+        }
+    }
+
+    fn get(&self, stage: DropStage, cpu: usize) -> u64 {
+        match stage {
+            DropStage::Parse => self
+                .parse
+                .get(cpu)
+                .map_or(0, |counter| counter.load(Ordering::Relaxed)),
+            _ => 0,
+        }
+    }
+}
+
+/// Exposes `socket_tracer_events_dropped` and its per-CPU breakdown, merging the
+/// per-CPU `drop_stats` map filled by the kprobes with the userspace counters.
+#[derive(Debug)]
+pub struct DropStatsCollector {
+    map_path: PathBuf,
+    stats: Arc<DropStats>,
+}
+
+impl DropStatsCollector {
+    pub fn new(map_path: PathBuf, stats: Arc<DropStats>) -> Self {
+        Self { map_path, stats }
+    }
+
+    /// Returns the kernel drop counts indexed by `[stage][cpu]`.
+    fn kernel_drops(&self) -> anyhow::Result<Vec<Vec<u64>>> {
+        let map_data = MapData::from_pin(&self.map_path)
+            .map_err(|_| anyhow::anyhow!("No maps named {:?}", self.map_path))?;
+        let array: PerCpuArray<_, u64> = Map::PerCpuArray(map_data).try_into()?;
+
+        let mut drops = Vec::with_capacity(DropStage::NumDropStages as usize);
+        for stage in DropStage::ALL {
+            let values = array.get(&(stage as u32), 0)?;
+            drops.push(values.to_vec());
+        }
+        Ok(drops)
+    }
+
+    fn cpu_drops(&self, kernel_drops: &[Vec<u64>], stage: DropStage) -> Vec<u64> {
+        let kernel = kernel_drops.get(stage as usize);
+        let nr_cpus = kernel.map_or(0, |v| v.len()).max(self.stats.parse.len());
+        (0..nr_cpus)
+            .map(|cpu| {
+                kernel.and_then(|v| v.get(cpu)).copied().unwrap_or(0) + self.stats.get(stage, cpu)
+            })
+            .collect()
+    }
+}
+
+impl Collector for DropStatsCollector {
+    fn encode(&self, mut encoder: DescriptorEncoder) -> Result<(), std::fmt::Error> {
+        // The map only exists once the kprobes are loaded; report userspace drops until then.
+        let kernel_drops = self.kernel_drops().unwrap_or_default();
+        let per_stage: Vec<_> = DropStage::ALL
+            .iter()
+            .map(|stage| (*stage, self.cpu_drops(&kernel_drops, *stage)))
+            .collect();
+
+        let counter = ConstCounter::new(0u64);
+        let mut family_encoder = encoder.encode_descriptor(
+            "socket_tracer_events_dropped",
+            "Events dropped before reaching userspace handlers, by stage",
+            None,
+            counter.metric_type(),
+        )?;
+        for (stage, drops) in per_stage.iter() {
+            let labels = [("stage", stage.name())];
+            let counter = ConstCounter::new(drops.iter().sum::<u64>());
+            let metric_encoder = family_encoder.encode_family(&labels)?;
+            counter.encode(metric_encoder)?;
+        }
+
+        let mut family_encoder = encoder.encode_descriptor(
+            "socket_tracer_cpu_events_dropped",
+            "Events dropped before reaching userspace handlers, by stage and CPU",
+            None,
+            counter.metric_type(),
+        )?;
+        for (stage, drops) in per_stage.iter() {
+            for (cpu, dropped) in drops.iter().enumerate() {
+                let cpu = cpu.to_string();
+                let labels = [("stage", stage.name()), ("cpu", cpu.as_str())];
+                let counter = ConstCounter::new(*dropped);
+                let metric_encoder = family_encoder.encode_family(&labels)?;
+                counter.encode(metric_encoder)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Exposes `socket_tracer_reassembly_dropped`, the data that couldn't be reassembled
+/// into protocol messages.
+#[derive(Debug)]
+pub struct ReassemblyCollector {
+    streams: Arc<StreamHub>,
+}
+
+impl ReassemblyCollector {
+    pub fn new(streams: Arc<StreamHub>) -> Self {
+        Self { streams }
+    }
+}
+
+impl Collector for ReassemblyCollector {
+    fn encode(&self, mut encoder: DescriptorEncoder) -> Result<(), std::fmt::Error> {
+        let counter = ConstCounter::new(0u64);
+        let mut family_encoder = encoder.encode_descriptor(
+            "socket_tracer_reassembly_dropped",
+            "Partial messages and streams dropped while reassembling, by protocol and cause",
+            None,
+            counter.metric_type(),
+        )?;
+        for (protocol, stats) in self.streams.stats() {
+            for (cause, dropped) in [
+                ("gap", stats.gaps),
+                ("overflow", stats.overflows),
+                ("invalid", stats.invalid),
+                ("eviction", stats.evictions),
+            ] {
+                let labels = [("protocol", protocol), ("cause", cause)];
+                let counter = ConstCounter::new(dropped);
+                let metric_encoder = family_encoder.encode_family(&labels)?;
+                counter.encode(metric_encoder)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Exposes `socket_tracer_probe_attached`, whether each kprobe is attached.
+#[derive(Debug)]
+pub struct ProbeCollector {
+    status: Arc<ProbeStatus>,
+}
+
+impl ProbeCollector {
+    pub fn new(status: Arc<ProbeStatus>) -> Self {
+        Self { status }
+    }
+}
+
+impl Collector for ProbeCollector {
+    fn encode(&self, mut encoder: DescriptorEncoder) -> Result<(), std::fmt::Error> {
+        let gauge = ConstGauge::new(0i64);
+        let mut family_encoder = encoder.encode_descriptor(
+            "socket_tracer_probe_attached",
+            "Whether the kprobe is attached, 0 while it is degraded",
+            None,
+            gauge.metric_type(),
+        )?;
+        let Ok(status) = self.status.lock() else {
+            return Ok(());
+        };
+        for (program, attached) in status.iter() {
+            let labels = [("program", *program)];
+            let gauge = ConstGauge::new(*attached as i64);
+            let metric_encoder = family_encoder.encode_family(&labels)?;
+            gauge.encode(metric_encoder)?;
+        }
+        Ok(())
+    }
+}
+
+/// Serves the registry in text format on `/metrics`, and pcapng captures of selected
+/// connections on `/capture`.
+pub async fn serve(registry: Registry, captures: Arc<CaptureHub>) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(METRICS_ADDR).await?;
+    info!("Serving metrics on {}", METRICS_ADDR);
+    let registry = Arc::new(registry);
+
+    loop {
+        let (mut stream, _) = listener.accept().await?;
+        let registry = registry.clone();
+        let captures = captures.clone();
+        tokio::spawn(async move {
+            let mut req = [0u8; 1024];
+            if stream.read(&mut req).await.is_err() {
+                return;
+            }
+
+            if let Some(rest) = req.strip_prefix(b"GET /capture?") {
+                let query = String::from_utf8_lossy(rest);
+                let query = query.split([' ', '\r', '\n']).next().unwrap_or_default();
+                capture::serve(stream, query, &captures).await;
+                return;
+            }
+
+            let response = if req.starts_with(b"GET /metrics") {
+                let mut body = String::new();
+                if let Err(e) = encode(&mut body, &registry) {
+                    warn!("failed to encode metrics: {}", e);
+                }
+                format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/openmetrics-text; version=1.0.0; charset=utf-8\r\nContent-Length: {}\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+            } else {
+                "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n".to_string()
+            };
+            let _ = stream.write_all(response.as_bytes()).await;
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode_collector(collector: DropStatsCollector) -> String {
+        let mut registry = Registry::default();
+        registry.register_collector(Box::new(collector));
+        let mut metrics = String::new();
+        encode(&mut metrics, &registry).unwrap();
+        metrics
+    }
+
+    #[test]
+    fn test_userspace_drops_without_kernel_map() {
+        let stats = Arc::new(DropStats::new(2));
+        stats.record(DropStage::Parse, 0);
+        stats.record(DropStage::Parse, 1);
+        // Counted by the kprobes only.
+        stats.record(DropStage::MapInsert, 0);
+        let collector = DropStatsCollector::new(PathBuf::from("/nonexistent/drop_stats"), stats);
+
+        let metrics = encode_collector(collector);
+        for stage in DropStage::ALL {
+            let dropped = match stage {
+                DropStage::Parse => 2,
+                _ => 0,
+            };
+            let line = format!(
+                "socket_tracer_events_dropped_total{{stage=\"{}\"}} {}",
+                stage.name(),
+                dropped
+            );
+            assert!(metrics.contains(&line), "{}", line);
+        }
+    }
+
+    #[test]
+    fn test_drops_per_cpu() {
+        let stats = Arc::new(DropStats::new(2));
+        stats.record(DropStage::Parse, 1);
+        // A CPU brought online after the counters were made isn't counted.
+        stats.record(DropStage::Parse, 2);
+        let collector = DropStatsCollector::new(PathBuf::from("/nonexistent/drop_stats"), stats);
+
+        let mut kernel_drops = vec![vec![0; 3]; DropStage::NumDropStages as usize];
+        kernel_drops[DropStage::Parse as usize] = vec![4, 0, 1];
+        kernel_drops[DropStage::PerfOutput as usize] = vec![0, 7, 0];
+        assert_eq!(
+            collector.cpu_drops(&kernel_drops, DropStage::Parse),
+            [4, 1, 1]
+        );
+        assert_eq!(
+            collector.cpu_drops(&kernel_drops, DropStage::PerfOutput),
+            [0, 7, 0]
+        );
+        // Without the kernel map, the userspace counters alone.
+        assert_eq!(collector.cpu_drops(&[], DropStage::Parse), [0, 1]);
+
+        let metrics = encode_collector(collector);
+        assert!(
+            metrics.contains("socket_tracer_cpu_events_dropped_total{stage=\"parse\",cpu=\"1\"} 1")
+        );
+    }
+}
@@ -0,0 +1,235 @@
+use std::net::{IpAddr, SocketAddr};
+
+/// Packets start at the IP header, so one interface can carry both IPv4 and IPv6.
+const LINKTYPE_RAW: u16 = 101;
+const BLOCK_SECTION_HEADER: u32 = 0x0A0D_0D0A;
+const BLOCK_INTERFACE_DESCRIPTION: u32 = 0x0000_0001;
+const BLOCK_ENHANCED_PACKET: u32 = 0x0000_0006;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+const OPTION_IF_TSRESOL: u16 = 9;
+/// Timestamps are written in nanoseconds, as reported by the kprobes.
+const TSRESOL_NANOS: u8 = 9;
+
+const IPV4_HEADER_LEN: usize = 20;
+const IPV6_HEADER_LEN: usize = 40;
+const TCP_HEADER_LEN: usize = 20;
+const IPPROTO_TCP: u8 = 6;
+const TCP_FLAGS_PSH_ACK: u8 = 0x18;
+
+/// A payload slice observed on a socket, with the endpoints it travelled between.
+#[derive(Debug, Clone)]
+pub struct Segment {
+    /// Wall-clock time, in nanoseconds since the Unix epoch.
+    pub timestamp_ns: u64,
+    pub src: SocketAddr,
+    pub dst: SocketAddr,
+    /// Position of the first payload byte in the sender's byte stream.
+    pub seq: u32,
+    /// Position the sender has read up to in the peer's byte stream.
+    pub ack: u32,
+    /// Size of the original message, of which `payload` may only be a prefix.
+    pub msg_size: u32,
+    pub payload: Vec<u8>,
+}
+
+/// Writes the section header and the single raw-IP interface every capture uses.
+pub fn file_header(snaplen: u32) -> Vec<u8> {
+    let mut out = Vec::with_capacity(64);
+
+    // Section header block: magic, version 1.0, unknown section length, no options.
+    push_block(&mut out, BLOCK_SECTION_HEADER, |body| {
+        body.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+        body.extend_from_slice(&1u16.to_le_bytes());
+        body.extend_from_slice(&0u16.to_le_bytes());
+        body.extend_from_slice(&(-1i64).to_le_bytes());
+    });
+
+    push_block(&mut out, BLOCK_INTERFACE_DESCRIPTION, |body| {
+        body.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+        body.extend_from_slice(&0u16.to_le_bytes());
+        body.extend_from_slice(&snaplen.to_le_bytes());
+        body.extend_from_slice(&OPTION_IF_TSRESOL.to_le_bytes());
+        body.extend_from_slice(&1u16.to_le_bytes());
+        body.extend_from_slice(&[TSRESOL_NANOS, 0, 0, 0]);
+        // opt_endofopt
+        body.extend_from_slice(&[0, 0, 0, 0]);
+    });
+
+    out
+}
+
+/// Encodes a segment as an enhanced packet block, with IP and TCP headers
+/// reconstructed from the connection endpoints.
+pub fn packet_block(segment: &Segment) -> Vec<u8> {
+    let packet = packet(segment);
+    let header_len = packet.len() - segment.payload.len();
+    let orig_len = header_len + segment.msg_size.max(segment.payload.len() as u32) as usize;
+    let ts = segment.timestamp_ns;
+
+    let mut out = Vec::with_capacity(packet.len() + 32);
+    push_block(&mut out, BLOCK_ENHANCED_PACKET, |body| {
+        body.extend_from_slice(&0u32.to_le_bytes());
+        body.extend_from_slice(&((ts >> 32) as u32).to_le_bytes());
+        body.extend_from_slice(&(ts as u32).to_le_bytes());
+        body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        body.extend_from_slice(&(orig_len as u32).to_le_bytes());
+        body.extend_from_slice(&packet);
+        body.resize(body.len().next_multiple_of(4), 0);
+    });
+    out
+}
+
+fn push_block(out: &mut Vec<u8>, block_type: u32, fill: impl FnOnce(&mut Vec<u8>)) {
+    let mut body = Vec::new();
+    fill(&mut body);
+    let total_len = (body.len() + 12) as u32;
+    out.extend_from_slice(&block_type.to_le_bytes());
+    out.extend_from_slice(&total_len.to_le_bytes());
+    out.extend_from_slice(&body);
+    out.extend_from_slice(&total_len.to_le_bytes());
+}
+
+fn packet(segment: &Segment) -> Vec<u8> {
+    let tcp_len = TCP_HEADER_LEN + segment.msg_size.max(segment.payload.len() as u32) as usize;
+    let mut out = Vec::with_capacity(IPV6_HEADER_LEN + TCP_HEADER_LEN + segment.payload.len());
+
+    match (segment.src.ip(), segment.dst.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            let total_len = (IPV4_HEADER_LEN + tcp_len).min(u16::MAX as usize) as u16;
+            let mut header = [0u8; IPV4_HEADER_LEN];
+            header[0] = 0x45;
+            header[2..4].copy_from_slice(&total_len.to_be_bytes());
+            // Don't fragment
+            header[6] = 0x40;
+            header[8] = 64;
+            header[9] = IPPROTO_TCP;
+            header[12..16].copy_from_slice(&src.octets());
+            header[16..20].copy_from_slice(&dst.octets());
+            let checksum = ipv4_checksum(&header);
+            header[10..12].copy_from_slice(&checksum.to_be_bytes());
+            out.extend_from_slice(&header);
+        }
+        (src, dst) => {
+            let payload_len = tcp_len.min(u16::MAX as usize) as u16;
+            let mut header = [0u8; IPV6_HEADER_LEN];
+            header[0] = 0x60;
+            header[4..6].copy_from_slice(&payload_len.to_be_bytes());
+            header[6] = IPPROTO_TCP;
+            header[7] = 64;
+            header[8..24].copy_from_slice(&to_ipv6_octets(src));
+            header[24..40].copy_from_slice(&to_ipv6_octets(dst));
+            out.extend_from_slice(&header);
+        }
+    }
+
+    let mut tcp = [0u8; TCP_HEADER_LEN];
+    tcp[0..2].copy_from_slice(&segment.src.port().to_be_bytes());
+    tcp[2..4].copy_from_slice(&segment.dst.port().to_be_bytes());
+    tcp[4..8].copy_from_slice(&segment.seq.to_be_bytes());
+    tcp[8..12].copy_from_slice(&segment.ack.to_be_bytes());
+    tcp[12] = ((TCP_HEADER_LEN / 4) as u8) << 4;
+    tcp[13] = TCP_FLAGS_PSH_ACK;
+    tcp[14..16].copy_from_slice(&u16::MAX.to_be_bytes());
+    // The checksum is left at zero; analyzers don't validate it by default.
+    out.extend_from_slice(&tcp);
+
+    out.extend_from_slice(&segment.payload);
+    out
+}
+
+fn to_ipv6_octets(addr: IpAddr) -> [u8; 16] {
+    match addr {
+        IpAddr::V4(v4) => v4.to_ipv6_mapped().octets(),
+        IpAddr::V6(v6) => v6.octets(),
+    }
+}
+
+fn ipv4_checksum(header: &[u8]) -> u16 {
+    let mut sum: u32 = header
+        .chunks(2)
+        .map(|word| u32::from(u16::from_be_bytes([word[0], word[1]])))
+        .sum();
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn segment(src: &str, dst: &str, payload: &[u8], msg_size: u32) -> Segment {
+        Segment {
+            timestamp_ns: 0x0000_0001_0000_0002,
+            src: src.parse().unwrap(),
+            dst: dst.parse().unwrap(),
+            seq: 1000,
+            ack: 2000,
+            msg_size,
+            payload: payload.to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_file_header() {
+        let header = file_header(65535);
+        // Section header block, then interface description block.
+        assert_eq!(u32_at(&header, 0), BLOCK_SECTION_HEADER);
+        assert_eq!(u32_at(&header, 4), 28);
+        assert_eq!(u32_at(&header, 8), BYTE_ORDER_MAGIC);
+        assert_eq!(u32_at(&header, 24), 28);
+        let idb = &header[28..];
+        assert_eq!(u32_at(idb, 0), BLOCK_INTERFACE_DESCRIPTION);
+        assert_eq!(idb.len(), u32_at(idb, 4) as usize);
+        assert_eq!(&idb[8..10], LINKTYPE_RAW.to_le_bytes());
+        assert_eq!(u32_at(idb, 12), 65535);
+        // if_tsresol of 10^-9.
+        assert_eq!(&idb[16..21], [9, 0, 1, 0, TSRESOL_NANOS]);
+    }
+
+    #[test]
+    fn test_ipv4_packet_block() {
+        let block = packet_block(&segment("10.0.0.1:40000", "10.0.0.2:8080", b"GET /", 100));
+        assert_eq!(u32_at(&block, 0), BLOCK_ENHANCED_PACKET);
+        let total_len = u32_at(&block, 4) as usize;
+        assert_eq!(block.len(), total_len);
+        assert_eq!(total_len % 4, 0);
+        assert_eq!(u32_at(&block, total_len - 4) as usize, total_len);
+        assert_eq!((u32_at(&block, 12), u32_at(&block, 16)), (1, 2));
+        // Captured the payload given, out of the whole message.
+        assert_eq!(u32_at(&block, 20), 45);
+        assert_eq!(u32_at(&block, 24), 140);
+
+        let packet = &block[28..28 + 45];
+        assert_eq!(packet[0], 0x45);
+        assert_eq!(u16::from_be_bytes([packet[2], packet[3]]), 140);
+        assert_eq!(ipv4_checksum(&packet[..IPV4_HEADER_LEN]), 0);
+        assert_eq!(&packet[12..20], [10, 0, 0, 1, 10, 0, 0, 2]);
+        let tcp = &packet[IPV4_HEADER_LEN..];
+        assert_eq!(u16::from_be_bytes([tcp[0], tcp[1]]), 40000);
+        assert_eq!(u16::from_be_bytes([tcp[2], tcp[3]]), 8080);
+        assert_eq!(u32::from_be_bytes(tcp[4..8].try_into().unwrap()), 1000);
+        assert_eq!(u32::from_be_bytes(tcp[8..12].try_into().unwrap()), 2000);
+        assert_eq!(&tcp[TCP_HEADER_LEN..], b"GET /");
+    }
+
+    #[test]
+    fn test_mixed_families_are_written_as_ipv6() {
+        let block = packet_block(&segment("10.0.0.1:40000", "[fd00::2]:8080", b"ping", 4));
+        let packet = &block[28..];
+        assert_eq!(packet[0], 0x60);
+        assert_eq!(u16::from_be_bytes([packet[4], packet[5]]), 24);
+        assert_eq!(
+            &packet[8..24],
+            "::ffff:10.0.0.1"
+                .parse::<std::net::Ipv6Addr>()
+                .unwrap()
+                .octets()
+        );
+    }
+}
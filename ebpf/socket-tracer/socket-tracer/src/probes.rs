@@ -0,0 +1,320 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use aya::maps::ProgramArray;
+use aya::programs::kprobe::KProbeLinkId;
+use aya::programs::{loaded_programs, FEntry, FExit, KProbe, TracePoint};
+use aya::{include_bytes_aligned, Bpf, BpfLoader, Btf};
+use aya_log::BpfLogger;
+use log::{debug, info, warn};
+
+use socket_tracer::symbols::KernelSymbols;
+use socket_tracer_common::TrafficProtocol;
+
+/// Where bpfman pins the maps of the programs it loads, in a directory named after the
+/// id of the program owning them.
+const BPFMAN_MAPS_DIR: &str = "/run/bpfman/fs/maps";
+/// Longest program name kept by the kernel.
+const MAX_PROG_NAME_LEN: usize = 15;
+
+/// Kprobes and kretprobes, with the kernel function each is attached to.
+const KPROBES: &[(&str, &str)] = &[
+    ("entry_connect", "__sys_connect"),
+    ("ret_connect", "__sys_connect"),
+    ("entry_accept", "__x64_sys_accept"),
+    ("ret_accept", "__x64_sys_accept"),
+    ("entry_accept4", "__x64_sys_accept4"),
+    ("ret_accept4", "__x64_sys_accept4"),
+    ("ret_sock_alloc", "sock_alloc"),
+    ("entry_close", "__x64_sys_close"),
+    ("ret_close", "__x64_sys_close"),
+    ("entry_write", "__x64_sys_write"),
+    ("ret_write", "__x64_sys_write"),
+    ("entry_writev", "__x64_sys_writev"),
+    ("ret_writev", "__x64_sys_writev"),
+    ("entry_send", "__x64_sys_send"),
+    ("ret_send", "__x64_sys_send"),
+    ("entry_sendto", "__x64_sys_sendto"),
+    ("ret_sendto", "__x64_sys_sendto"),
+    ("entry_sendmsg", "__x64_sys_sendmsg"),
+    ("ret_sendmsg", "__x64_sys_sendmsg"),
+    ("entry_sendmmsg", "__x64_sys_sendmmsg"),
+    ("ret_sendmmsg", "__x64_sys_sendmmsg"),
+    ("entry_sendfile", "__x64_sys_sendfile"),
+    ("ret_sendfile", "__x64_sys_sendfile"),
+    ("entry_security_socket_sendmsg", "security_socket_sendmsg"),
+    ("entry_security_socket_recvmsg", "security_socket_recvmsg"),
+    ("entry_read", "__x64_sys_read"),
+    ("ret_read", "__x64_sys_read"),
+    ("entry_readv", "__x64_sys_readv"),
+    ("ret_readv", "__x64_sys_readv"),
+    ("entry_recv", "__x64_sys_recv"),
+    ("ret_recv", "__x64_sys_recv"),
+    ("entry_recvfrom", "__x64_sys_recvfrom"),
+    ("ret_recvfrom", "__x64_sys_recvfrom"),
+    ("entry_recvmsg", "__x64_sys_recvmsg"),
+    ("ret_recvmsg", "__x64_sys_recvmsg"),
+    ("entry_recvmmsg", "__x64_sys_recvmmsg"),
+    ("ret_recvmmsg", "__x64_sys_recvmmsg"),
+];
+
+/// The program the kretprobes tail call into to submit the data of each protocol, from
+/// the `data_parsers` map.
+const DATA_PARSER: &str = "parse_data";
+
+/// Registered kprobes, listed as `<addr> <k|r> <symbol>+<offset> ...`.
+const KPROBES_LIST: &str = "/sys/kernel/debug/kprobes/list";
+
+/// Whether each kprobe is currently attached, shared with the metrics collector.
+pub type ProbeStatus = Mutex<BTreeMap<&'static str, bool>>;
+
+/// The bundled eBPF object and the links of its kprobes. The programs stay attached
+/// until it is dropped.
+pub struct Probes {
+    bpf: Bpf,
+    /// How each kprobe of [`KPROBES`], or its variant, is attached.
+    links: HashMap<&'static str, Link>,
+    symbols: KernelSymbols,
+    status: Arc<ProbeStatus>,
+}
+
+enum Link {
+    /// The kprobe itself, with the symbol it was attached to.
+    KProbe(KProbeLinkId, String),
+    /// Its fentry or fexit variant, which stays attached as long as the tracer runs.
+    Trampoline,
+}
+
+/// Loads the bundled eBPF object and attaches all of its programs. They share the
+/// object's maps, which are also pinned for the event consumers. On kernels with BTF,
+/// the fentry and fexit variants of the kprobes are attached instead, as they are
+/// cheaper; each falls back to its kprobe if it can't be. Kprobes that can't be
+/// attached, e.g. because the kernel renamed their symbol, are reported degraded and
+/// retried by [`Probes::check`] rather than failing the tracer.
+pub fn load() -> anyhow::Result<Probes> {
+    let mut bpf = Bpf::load(object())?;
+    if let Err(e) = BpfLogger::init(&mut bpf) {
+        warn!("failed to initialize eBPF logger: {}", e);
+    }
+
+    for (prog_name, _) in KPROBES {
+        let program: &mut KProbe = bpf.program_mut(prog_name).unwrap().try_into()?;
+        program.load()?;
+    }
+    register_parser(&mut bpf)?;
+
+    let program: &mut TracePoint = bpf.program_mut("sched_process_exit").unwrap().try_into()?;
+    program.load()?;
+    program.attach("sched", "sched_process_exit")?;
+
+    let btf = match Btf::from_sys_fs() {
+        Ok(btf) => Some(btf),
+        Err(e) => {
+            info!("kernel BTF unavailable, using kprobes: {}", e);
+            None
+        }
+    };
+
+    let mut probes = Probes {
+        bpf,
+        links: HashMap::new(),
+        symbols: KernelSymbols::load(),
+        status: Arc::default(),
+    };
+    for (prog_name, func_name) in KPROBES {
+        if let Some(btf) = &btf {
+            match probes.attach_trampoline(btf, prog_name, func_name) {
+                Ok(()) => continue,
+                Err(e) => debug!("using the kprobe {}, variant unavailable: {}", prog_name, e),
+            }
+        }
+        if let Err(e) = probes.attach(prog_name, func_name) {
+            warn!("failed to attach {} to {}: {}", prog_name, func_name, e);
+        }
+    }
+    Ok(probes)
+}
+
+impl Probes {
+    pub fn status(&self) -> Arc<ProbeStatus> {
+        self.status.clone()
+    }
+
+    /// Re-attaches the kprobes missing from the kernel's list of registered kprobes,
+    /// whether they failed to attach or were removed by other tooling. The fentry and
+    /// fexit variants are bpf links held by the tracer and aren't checked.
+    pub fn check(&mut self) {
+        let registered = match fs::read_to_string(KPROBES_LIST) {
+            Ok(list) => parse_kprobes_list(&list),
+            Err(e) => {
+                debug!(
+                    "can't verify the probes, {} unreadable: {}",
+                    KPROBES_LIST, e
+                );
+                return;
+            }
+        };
+        for (prog_name, func_name) in KPROBES {
+            let kind = if prog_name.starts_with("ret_") {
+                'r'
+            } else {
+                'k'
+            };
+            let attached = match self.links.get(prog_name) {
+                Some(Link::KProbe(_, symbol)) => registered.contains(&(kind, symbol.clone())),
+                Some(Link::Trampoline) => true,
+                None => false,
+            };
+            if attached {
+                continue;
+            }
+            warn!("{} is not attached, re-attaching it", prog_name);
+            if let Err(e) = self.attach(prog_name, func_name) {
+                warn!("failed to re-attach {} to {}: {}", prog_name, func_name, e);
+            }
+        }
+    }
+
+    /// Attaches a kprobe to the first of the symbols resolved for `func_name` that
+    /// works, replacing any previous link.
+    fn attach(&mut self, prog_name: &'static str, func_name: &str) -> anyhow::Result<()> {
+        let program: &mut KProbe = self.bpf.program_mut(prog_name).unwrap().try_into()?;
+        if let Some(Link::KProbe(link_id, _)) = self.links.remove(prog_name) {
+            // Fails when the kprobe was removed behind our back.
+            let _ = program.detach(link_id);
+        }
+
+        let mut res = Err(anyhow::anyhow!("no candidate symbol"));
+        for symbol in self.symbols.kprobe_symbols(func_name) {
+            match program.attach(&symbol, 0) {
+                Ok(link_id) => {
+                    self.links.insert(prog_name, Link::KProbe(link_id, symbol));
+                    res = Ok(());
+                    break;
+                }
+                Err(e) => res = Err(e.into()),
+            }
+        }
+        if let Ok(mut status) = self.status.lock() {
+            status.insert(prog_name, res.is_ok());
+        }
+        res
+    }
+
+    /// Attaches the fentry or fexit variant of a kprobe or kretprobe. The variants of
+    /// the syscall probes read the registers saved by the x86-64 syscall wrappers, and
+    /// are only attached to those.
+    fn attach_trampoline(
+        &mut self,
+        btf: &Btf,
+        prog_name: &'static str,
+        func_name: &str,
+    ) -> anyhow::Result<()> {
+        if !self.symbols.has_btf_function(func_name) {
+            return Err(anyhow::anyhow!("{} not in the kernel BTF", func_name));
+        }
+        let variant = trampoline_program(prog_name);
+        let program = self
+            .bpf
+            .program_mut(&variant)
+            .ok_or(anyhow::anyhow!("no program {}", variant))?;
+        if prog_name.starts_with("ret_") {
+            let program: &mut FExit = program.try_into()?;
+            program.load(func_name, btf)?;
+            program.attach()?;
+        } else {
+            let program: &mut FEntry = program.try_into()?;
+            program.load(func_name, btf)?;
+            program.attach()?;
+        }
+        self.links.insert(prog_name, Link::Trampoline);
+        if let Ok(mut status) = self.status.lock() {
+            status.insert(prog_name, true);
+        }
+        Ok(())
+    }
+}
+
+/// Returns the name of the fentry or fexit program doing the same as a kprobe or
+/// kretprobe: `entry_read` has `fentry_read` and `ret_read` has `fexit_read`.
+fn trampoline_program(prog_name: &str) -> String {
+    match prog_name.strip_prefix("ret_") {
+        Some(name) => format!("fexit_{}", name),
+        None => format!("f{}", prog_name),
+    }
+}
+
+/// Loads the data parser for the probes loaded by bpfman, reusing the maps pinned in
+/// `map_dir`, and registers it. bpfman attaches every program it loads, which the
+/// parser must not be. It stays registered as long as the returned object lives.
+pub fn load_parser(map_dir: &Path) -> anyhow::Result<Bpf> {
+    let mut bpf = BpfLoader::new().map_pin_path(map_dir).load(object())?;
+    register_parser(&mut bpf)?;
+    Ok(bpf)
+}
+
+/// Loads the data parser, and registers it for every protocol.
+fn register_parser(bpf: &mut Bpf) -> anyhow::Result<()> {
+    let program: &mut KProbe = bpf.program_mut(DATA_PARSER).unwrap().try_into()?;
+    program.load()?;
+    let parser = program.fd()?.try_clone()?;
+    let mut parsers: ProgramArray<_> = bpf.map_mut("data_parsers").unwrap().try_into()?;
+    for protocol in 0..TrafficProtocol::NumProtocols as u32 {
+        parsers.set(protocol, &parser, 0)?;
+    }
+    Ok(())
+}
+
+fn object() -> &'static [u8] {
+    #[cfg(debug_assertions)]
+    let object = include_bytes_aligned!("../../target/bpfel-unknown-none/debug/socket-tracer");
+    #[cfg(not(debug_assertions))]
+    let object = include_bytes_aligned!("../../target/bpfel-unknown-none/release/socket-tracer");
+    object
+}
+
+/// Returns the kind, `k` or `r`, and the symbol of the registered kprobes.
+fn parse_kprobes_list(list: &str) -> HashSet<(char, String)> {
+    list.lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace().skip(1);
+            let kind = fields.next()?.chars().next()?;
+            let symbol = fields.next()?.split('+').next()?;
+            Some((kind, symbol.to_string()))
+        })
+        .collect()
+}
+
+/// Finds the maps of the probes loaded by bpfman rather than by the tracer: they are
+/// pinned under the id of `owner`, the program the others share their maps with.
+pub fn bpfman_map_dir(owner: &str) -> anyhow::Result<PathBuf> {
+    let name = &owner[..owner.len().min(MAX_PROG_NAME_LEN)];
+    let mut owner_ids = Vec::new();
+    for program in loaded_programs() {
+        let program = program?;
+        if program.name_as_str() == Some(name) {
+            owner_ids.push(program.id());
+        }
+    }
+
+    let maps_dir = Path::new(BPFMAN_MAPS_DIR);
+    if !maps_dir.exists() {
+        return Err(anyhow::anyhow!("{} does not exist", BPFMAN_MAPS_DIR));
+    }
+    // Programs of the same name loaded by others don't own a map directory of bpfman.
+    let map_dir = owner_ids
+        .into_iter()
+        .map(|id| maps_dir.join(id.to_string()))
+        .find(|dir| dir.join("sk_data_events").exists())
+        .ok_or(anyhow::anyhow!(
+            "Required eBPF program {} not loaded by bpfman",
+            owner
+        ))?;
+    info!(
+        "Using the probes loaded by bpfman, maps pinned in {:?}",
+        map_dir
+    );
+    Ok(map_dir)
+}
@@ -0,0 +1,93 @@
+use super::{ParseError, ParseResult};
+
+/// A read position over untrusted data. Reads past the end fail with
+/// [`ParseError::Incomplete`], and scans for delimiters are bounded so that a missing
+/// delimiter is rejected rather than searched for indefinitely.
+#[derive(Debug, Clone)]
+pub struct Cursor<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    /// Number of bytes consumed so far.
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    pub fn remaining(&self) -> &'a [u8] {
+        &self.buf[self.pos..]
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pos == self.buf.len()
+    }
+
+    pub fn take(&mut self, n: usize) -> ParseResult<&'a [u8]> {
+        if n > self.buf.len() - self.pos {
+            return Err(ParseError::Incomplete);
+        }
+        let taken = &self.buf[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(taken)
+    }
+
+    pub fn u8(&mut self) -> ParseResult<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    /// Consumes `expected`, failing with `error` if the data differs from it.
+    pub fn expect(&mut self, expected: &[u8], error: &'static str) -> ParseResult<()> {
+        let available = self.remaining().len().min(expected.len());
+        if self.remaining()[..available] != expected[..available] {
+            return Err(ParseError::Invalid(error));
+        }
+        self.take(expected.len()).map(|_| ())
+    }
+
+    /// Consumes the bytes up to and including `delim`, returning those before it. No
+    /// more than `max` bytes are searched: a longer token is invalid.
+    pub fn take_until(
+        &mut self,
+        delim: &[u8],
+        max: usize,
+        error: &'static str,
+    ) -> ParseResult<&'a [u8]> {
+        let window = &self.remaining()[..self.remaining().len().min(max + delim.len())];
+        match window.windows(delim.len()).position(|w| w == delim) {
+            Some(end) => {
+                let token = &window[..end];
+                self.pos += end + delim.len();
+                Ok(token)
+            }
+            None if window.len() >= max + delim.len() => Err(ParseError::Invalid(error)),
+            None => Err(ParseError::Incomplete),
+        }
+    }
+
+    /// Consumes a line ended by CRLF, of at most `max` bytes.
+    pub fn line(&mut self, max: usize, error: &'static str) -> ParseResult<&'a [u8]> {
+        self.take_until(b"\r\n", max, error)
+    }
+}
+
+/// Parses an unsigned decimal or hexadecimal number, rejecting signs, blanks and
+/// values that don't fit in a `usize`.
+pub(crate) fn parse_usize(digits: &[u8], radix: u32, error: &'static str) -> ParseResult<usize> {
+    if digits.is_empty() {
+        return Err(ParseError::Invalid(error));
+    }
+    digits.iter().try_fold(0usize, |value, &digit| {
+        let digit = (digit as char)
+            .to_digit(radix)
+            .ok_or(ParseError::Invalid(error))?;
+        value
+            .checked_mul(radix as usize)
+            .and_then(|value| value.checked_add(digit as usize))
+            .ok_or(ParseError::Invalid(error))
+    })
+}
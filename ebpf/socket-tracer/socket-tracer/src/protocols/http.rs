@@ -0,0 +1,256 @@
+//! HTTP/1.x requests and responses.
+
+use super::cursor::{parse_usize, Cursor};
+use super::{ParseError, ParseResult, Parser, MAX_MESSAGE_SIZE};
+
+const HEAD_END: &[u8] = b"\r\n\r\n";
+/// Largest start line and header section accepted.
+const MAX_HEAD_SIZE: usize = 64 << 10;
+const MAX_HEADERS: usize = 100;
+const MAX_METHOD_LEN: usize = 16;
+const MAX_CHUNK_LINE: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HttpStart {
+    Request { method: String, target: String },
+    Response { status: u16 },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpMessage {
+    pub start: HttpStart,
+    /// Minor version of HTTP/1.x.
+    pub minor_version: u8,
+    pub headers: Vec<(String, String)>,
+    /// Size of the body, after removing any chunked encoding framing.
+    pub body_size: usize,
+}
+
+impl HttpMessage {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// Parses HTTP/1.x messages. The search for the end of the headers resumes where the
+/// previous call stopped, so that headers arriving in many small reads aren't scanned
+/// again every time. Responses delimited by the connection closing, rather than by a
+/// length or chunked encoding, end with the data available.
+#[derive(Debug, Default)]
+pub struct HttpParser {
+    /// Bytes already searched for the end of the headers.
+    scanned: usize,
+}
+
+impl Parser for HttpParser {
+    type Message = HttpMessage;
+
+    fn parse(&mut self, buf: &[u8]) -> ParseResult<(HttpMessage, usize)> {
+        check_start(buf)?;
+
+        let limit = buf.len().min(MAX_HEAD_SIZE);
+        // Back off so that a terminator split across two reads is still found.
+        let from = self.scanned.min(limit).saturating_sub(HEAD_END.len() - 1);
+        let head_len = match buf[from..limit]
+            .windows(HEAD_END.len())
+            .position(|w| w == HEAD_END)
+        {
+            Some(end) => from + end + HEAD_END.len(),
+            None if limit == MAX_HEAD_SIZE => return Err(ParseError::Invalid("headers too large")),
+            None => {
+                self.scanned = limit;
+                return Err(ParseError::Incomplete);
+            }
+        };
+
+        let mut head = Cursor::new(&buf[..head_len]);
+        let (start, minor_version) = parse_start_line(&mut head)?;
+        let headers = parse_headers(&mut head)?;
+        let mut message = HttpMessage {
+            start,
+            minor_version,
+            headers,
+            body_size: 0,
+        };
+
+        let mut body = Cursor::new(&buf[head_len..]);
+        message.body_size = parse_body(&message, &mut body)?;
+        Ok((message, head_len + body.position()))
+    }
+
+    fn reset(&mut self) {
+        self.scanned = 0;
+    }
+}
+
+/// Rejects data that can't start an HTTP message as soon as its first bytes arrive,
+/// rather than once a whole header section was waited for.
+fn check_start(buf: &[u8]) -> ParseResult<()> {
+    const VERSION: &[u8] = b"HTTP/";
+    let prefix = &buf[..buf.len().min(VERSION.len())];
+    if prefix == &VERSION[..prefix.len()] {
+        return Ok(());
+    }
+    let method = buf
+        .iter()
+        .take(MAX_METHOD_LEN + 1)
+        .take_while(|b| **b != b' ');
+    let mut len = 0;
+    for b in method {
+        if !b.is_ascii_uppercase() && *b != b'-' {
+            return Err(ParseError::Invalid("not an HTTP message"));
+        }
+        len += 1;
+    }
+    if len > MAX_METHOD_LEN {
+        return Err(ParseError::Invalid("not an HTTP message"));
+    }
+    Ok(())
+}
+
+fn parse_start_line(head: &mut Cursor) -> ParseResult<(HttpStart, u8)> {
+    let line = head.line(MAX_HEAD_SIZE, "start line too long")?;
+    let mut line = Cursor::new(line);
+    if line.remaining().starts_with(b"HTTP/") {
+        let minor_version = parse_version(&mut line)?;
+        line.expect(b" ", "malformed status line")?;
+        let status = line
+            .take(3)
+            .map_err(|_| ParseError::Invalid("malformed status"))?;
+        let status = parse_usize(status, 10, "malformed status")? as u16;
+        if !(100..600).contains(&status) {
+            return Err(ParseError::Invalid("malformed status"));
+        }
+        return Ok((HttpStart::Response { status }, minor_version));
+    }
+
+    let method = line
+        .take_until(b" ", MAX_METHOD_LEN, "malformed method")
+        .map_err(|_| ParseError::Invalid("malformed method"))?;
+    let target = line
+        .take_until(b" ", MAX_HEAD_SIZE, "malformed target")
+        .map_err(|_| ParseError::Invalid("malformed target"))?;
+    if method.is_empty() || target.is_empty() {
+        return Err(ParseError::Invalid("malformed request line"));
+    }
+    let minor_version = parse_version(&mut line)?;
+    if !line.is_empty() {
+        return Err(ParseError::Invalid("malformed request line"));
+    }
+    Ok((
+        HttpStart::Request {
+            method: String::from_utf8_lossy(method).into_owned(),
+            target: String::from_utf8_lossy(target).into_owned(),
+        },
+        minor_version,
+    ))
+}
+
+fn parse_version(line: &mut Cursor) -> ParseResult<u8> {
+    line.expect(b"HTTP/1.", "unsupported version")
+        .map_err(|_| ParseError::Invalid("unsupported version"))?;
+    match line.u8() {
+        Ok(digit @ b'0'..=b'9') => Ok(digit - b'0'),
+        _ => Err(ParseError::Invalid("unsupported version")),
+    }
+}
+
+fn parse_headers(head: &mut Cursor) -> ParseResult<Vec<(String, String)>> {
+    let mut headers = Vec::new();
+    loop {
+        let line = head.line(MAX_HEAD_SIZE, "header too long")?;
+        if line.is_empty() {
+            return Ok(headers);
+        }
+        if headers.len() == MAX_HEADERS {
+            return Err(ParseError::Invalid("too many headers"));
+        }
+        let colon = line
+            .iter()
+            .position(|b| *b == b':')
+            .ok_or(ParseError::Invalid("malformed header"))?;
+        let (name, value) = (&line[..colon], &line[colon + 1..]);
+        if name.is_empty() || !name.iter().all(|b| is_token(*b)) {
+            return Err(ParseError::Invalid("malformed header name"));
+        }
+        headers.push((
+            String::from_utf8_lossy(name).into_owned(),
+            String::from_utf8_lossy(value).trim().to_string(),
+        ));
+    }
+}
+
+fn is_token(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+fn parse_body(message: &HttpMessage, body: &mut Cursor) -> ParseResult<usize> {
+    if let HttpStart::Response { status } = message.start {
+        if status < 200 || status == 204 || status == 304 {
+            return Ok(0);
+        }
+    }
+
+    let chunked = message
+        .header("Transfer-Encoding")
+        .is_some_and(|te| te.to_ascii_lowercase().contains("chunked"));
+    if chunked {
+        return parse_chunked(body);
+    }
+    if let Some(length) = message.header("Content-Length") {
+        let length = parse_usize(length.as_bytes(), 10, "malformed content length")?;
+        if length > MAX_MESSAGE_SIZE {
+            return Err(ParseError::Invalid("body too large"));
+        }
+        body.take(length)?;
+        return Ok(length);
+    }
+
+    match message.start {
+        HttpStart::Request { .. } => Ok(0),
+        // Delimited by the connection closing.
+        HttpStart::Response { .. } => {
+            let rest = body.remaining().len();
+            body.take(rest)?;
+            Ok(rest)
+        }
+    }
+}
+
+fn parse_chunked(body: &mut Cursor) -> ParseResult<usize> {
+    let mut size = 0usize;
+    loop {
+        let line = body.line(MAX_CHUNK_LINE, "chunk size too long")?;
+        // Chunk extensions follow a semicolon.
+        let digits = line.split(|b| *b == b';').next().unwrap_or_default();
+        let chunk = parse_usize(trim(digits), 16, "malformed chunk size")?;
+        if chunk == 0 {
+            break;
+        }
+        size = size
+            .checked_add(chunk)
+            .filter(|size| *size <= MAX_MESSAGE_SIZE)
+            .ok_or(ParseError::Invalid("body too large"))?;
+        body.take(chunk)?;
+        body.expect(b"\r\n", "malformed chunk")?;
+    }
+    // Trailers, up to the empty line ending the message.
+    for _ in 0..=MAX_HEADERS {
+        if body.line(MAX_HEAD_SIZE, "trailer too long")?.is_empty() {
+            return Ok(size);
+        }
+    }
+    Err(ParseError::Invalid("too many trailers"))
+}
+
+fn trim(bytes: &[u8]) -> &[u8] {
+    let start = bytes.iter().position(|b| *b != b' ').unwrap_or(bytes.len());
+    let end = bytes
+        .iter()
+        .rposition(|b| *b != b' ')
+        .map_or(start, |end| end + 1);
+    &bytes[start..end]
+}
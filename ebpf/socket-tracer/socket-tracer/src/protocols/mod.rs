@@ -0,0 +1,124 @@
+//! Parsers for the application protocols carried by traced sockets.
+//!
+//! Socket data is untrusted: it comes from every process on the node, is cut at
+//! arbitrary syscall boundaries and may be truncated by the kprobes. All parsers are
+//! therefore built on [`Cursor`], which never reads past the data it was given and
+//! bounds every scan, and on a common contract: parsing the start of a buffer yields a
+//! complete message with its length, asks for more data, or rejects the data outright.
+//! A parser asking for more data is called again with the same bytes followed by new
+//! ones, and may keep state to resume where it stopped. [`parse_bounded`] caps how long
+//! a message may stay incomplete, so that malformed traffic can't wedge a connection.
+
+mod cursor;
+pub mod http;
+pub mod redis;
+
+pub use cursor::Cursor;
+
+/// Largest message a parser may wait for before giving up on it.
+pub const MAX_MESSAGE_SIZE: usize = 1 << 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseError {
+    /// The data is the prefix of a message, parse again once more of it arrived.
+    Incomplete,
+    /// The data isn't a message of this protocol.
+    Invalid(&'static str),
+}
+
+pub type ParseResult<T> = Result<T, ParseError>;
+
+/// An incremental parser of the messages of one direction of a connection.
+pub trait Parser {
+    type Message;
+
+    /// Parses the message at the start of `buf`, returning it with its length in bytes.
+    fn parse(&mut self, buf: &[u8]) -> ParseResult<(Self::Message, usize)>;
+
+    /// Forgets any state kept to resume an incomplete message, e.g. after data was lost.
+    fn reset(&mut self);
+}
+
+/// Parses the message at the start of `buf`, rejecting messages that stay incomplete
+/// past [`MAX_MESSAGE_SIZE`] and parsers that would make no progress.
+pub fn parse_bounded<P: Parser>(parser: &mut P, buf: &[u8]) -> ParseResult<(P::Message, usize)> {
+    match parser.parse(buf) {
+        Ok((_, 0)) => {
+            parser.reset();
+            Err(ParseError::Invalid("empty message"))
+        }
+        Ok(parsed) => {
+            parser.reset();
+            Ok(parsed)
+        }
+        Err(ParseError::Incomplete) if buf.len() >= MAX_MESSAGE_SIZE => {
+            parser.reset();
+            Err(ParseError::Invalid("message too large"))
+        }
+        Err(ParseError::Incomplete) => Err(ParseError::Incomplete),
+        Err(e) => {
+            parser.reset();
+            Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::http::HttpParser;
+    use super::redis::RedisParser;
+    use super::{parse_bounded, ParseError, Parser};
+
+    /// Feeds `data` one byte at a time and checks that the parser only completes once
+    /// the whole message arrived, with the same result as parsing it at once.
+    fn assert_resumes<P: Parser + Default>(data: &[u8])
+    where
+        P::Message: PartialEq + std::fmt::Debug,
+    {
+        let expected = P::default().parse(data).expect("valid message");
+        let mut parser = P::default();
+        for end in 0..data.len() {
+            assert_eq!(
+                parse_bounded(&mut parser, &data[..end]).unwrap_err(),
+                ParseError::Incomplete
+            );
+        }
+        assert_eq!(parse_bounded(&mut parser, data).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_resumption() {
+        assert_resumes::<HttpParser>(
+            b"POST /api HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\n\r\nhello",
+        );
+        assert_resumes::<HttpParser>(
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n0\r\n\r\n",
+        );
+        assert_resumes::<RedisParser>(b"*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n");
+    }
+
+    #[test]
+    fn test_malformed() {
+        let invalid: &[&[u8]] = &[
+            b"GET / HTTP/1.1\r\nContent-Length: -1\r\n\r\n",
+            b"GET / HTTP/1.1\r\nContent-Length: 99999999999999999999\r\n\r\n",
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n",
+            b"\x16\x03\x01\x02\x00",
+        ];
+        for data in invalid {
+            assert!(matches!(
+                parse_bounded(&mut HttpParser::default(), data),
+                Err(ParseError::Invalid(_))
+            ));
+        }
+
+        let mut nested = Vec::new();
+        for _ in 0..64 {
+            nested.extend_from_slice(b"*1\r\n");
+        }
+        assert!(matches!(
+            parse_bounded(&mut RedisParser, &nested),
+            Err(ParseError::Invalid(_))
+        ));
+    }
+}
@@ -0,0 +1,111 @@
+//! Redis serialization protocol (RESP2) commands and replies.
+
+use super::cursor::{parse_usize, Cursor};
+use super::{ParseError, ParseResult, Parser, MAX_MESSAGE_SIZE};
+
+/// Deepest nesting of arrays accepted. Replies of real commands nest a few levels.
+const MAX_DEPTH: usize = 8;
+const MAX_LINE: usize = 4096;
+const MAX_COMMAND_LEN: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RedisMessage {
+    /// A command sent by a client, as an array of bulk strings.
+    Command { name: String, args: usize },
+    /// Any other value, with the message of error replies.
+    Reply { error: Option<String> },
+}
+
+/// Parses RESP2 values. Values are small and bounded, so incomplete ones are parsed
+/// again from their start rather than resumed.
+#[derive(Debug, Default)]
+pub struct RedisParser;
+
+impl Parser for RedisParser {
+    type Message = RedisMessage;
+
+    fn parse(&mut self, buf: &[u8]) -> ParseResult<(RedisMessage, usize)> {
+        let mut cursor = Cursor::new(buf);
+        let message = match cursor.u8()? {
+            b'*' => {
+                let len = parse_length(&mut cursor)?;
+                let mut name = None;
+                for i in 0..len.unwrap_or(0) {
+                    if i == 0 && cursor.remaining().first() == Some(&b'$') {
+                        cursor.u8()?;
+                        name = parse_bulk(&mut cursor)?;
+                    } else {
+                        skip_value(&mut cursor, 1)?;
+                    }
+                }
+                match name.filter(|name| is_command(name)) {
+                    Some(name) => RedisMessage::Command {
+                        name: String::from_utf8_lossy(name).to_ascii_uppercase(),
+                        args: len.unwrap_or(0) - 1,
+                    },
+                    None => RedisMessage::Reply { error: None },
+                }
+            }
+            b'-' => {
+                let error = cursor.line(MAX_LINE, "error too long")?;
+                RedisMessage::Reply {
+                    error: Some(String::from_utf8_lossy(error).into_owned()),
+                }
+            }
+            b'+' | b':' | b'$' => {
+                cursor = Cursor::new(buf);
+                skip_value(&mut cursor, 0)?;
+                RedisMessage::Reply { error: None }
+            }
+            _ => return Err(ParseError::Invalid("not a RESP value")),
+        };
+        Ok((message, cursor.position()))
+    }
+
+    fn reset(&mut self) {}
+}
+
+fn is_command(name: &[u8]) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_COMMAND_LEN
+        && name.iter().all(|b| b.is_ascii_alphanumeric() || *b == b'_')
+}
+
+/// Parses the length following `*` or `$`, `None` standing for a null value.
+fn parse_length(cursor: &mut Cursor) -> ParseResult<Option<usize>> {
+    let line = cursor.line(32, "malformed length")?;
+    if line == b"-1" {
+        return Ok(None);
+    }
+    let len = parse_usize(line, 10, "malformed length")?;
+    if len > MAX_MESSAGE_SIZE {
+        return Err(ParseError::Invalid("value too large"));
+    }
+    Ok(Some(len))
+}
+
+fn parse_bulk<'a>(cursor: &mut Cursor<'a>) -> ParseResult<Option<&'a [u8]>> {
+    let Some(len) = parse_length(cursor)? else {
+        return Ok(None);
+    };
+    let data = cursor.take(len)?;
+    cursor.expect(b"\r\n", "malformed bulk string")?;
+    Ok(Some(data))
+}
+
+fn skip_value(cursor: &mut Cursor, depth: usize) -> ParseResult<()> {
+    match cursor.u8()? {
+        b'+' | b'-' | b':' => cursor.line(MAX_LINE, "line too long").map(|_| ()),
+        b'$' => parse_bulk(cursor).map(|_| ()),
+        b'*' => {
+            if depth == MAX_DEPTH {
+                return Err(ParseError::Invalid("nested too deeply"));
+            }
+            for _ in 0..parse_length(cursor)?.unwrap_or(0) {
+                skip_value(cursor, depth + 1)?;
+            }
+            Ok(())
+        }
+        _ => Err(ParseError::Invalid("not a RESP value")),
+    }
+}
@@ -0,0 +1,234 @@
+//! Reassembly of the byte streams of traced connections into protocol messages.
+//!
+//! Data events carry whatever a single syscall read or wrote: a message may span many
+//! events and an event may hold several messages. Each direction of a connection is
+//! buffered until its parser completes a message. Events carry their position in the
+//! stream, so that lost or truncated events are detected as gaps: the partial message
+//! in front of a gap can't be completed and is dropped, and parsing resumes at the next
+//! event, which usually starts a message. Buffers are capped per stream and in total,
+//! and the least recently used streams are evicted first.
+
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+use crate::protocols::{parse_bounded, ParseError, Parser, MAX_MESSAGE_SIZE};
+
+#[derive(Debug, Clone, Copy)]
+pub struct ReassemblyConfig {
+    /// Bytes buffered for one direction of a connection.
+    pub max_stream_bytes: usize,
+    /// Bytes buffered across all streams.
+    pub max_total_bytes: usize,
+    /// Streams tracked at the same time.
+    pub max_streams: usize,
+}
+
+impl Default for ReassemblyConfig {
+    fn default() -> Self {
+        Self {
+            max_stream_bytes: MAX_MESSAGE_SIZE,
+            max_total_bytes: 64 << 20,
+            max_streams: 4096,
+        }
+    }
+}
+
+/// Counts of the data that couldn't be reassembled, by cause.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReassemblyStats {
+    /// Partial messages dropped in front of lost or truncated data.
+    pub gaps: u64,
+    /// Partial messages dropped for exceeding the per-stream cap.
+    pub overflows: u64,
+    /// Buffered data the parser rejected.
+    pub invalid: u64,
+    /// Streams evicted to make room for others.
+    pub evictions: u64,
+}
+
+#[derive(Debug)]
+struct Stream<P> {
+    parser: P,
+    /// Unparsed data, ending at `next`.
+    buf: Vec<u8>,
+    /// Stream position following the last byte received.
+    next: u64,
+    last_used: u64,
+}
+
+/// Buffers the streams identified by `K` and parses them with `P`.
+#[derive(Debug)]
+pub struct Reassembler<K, P> {
+    config: ReassemblyConfig,
+    streams: HashMap<K, Stream<P>>,
+    /// Streams by last use, oldest first.
+    lru: BTreeMap<u64, K>,
+    clock: u64,
+    total_bytes: usize,
+    stats: ReassemblyStats,
+}
+
+impl<K: Hash + Eq + Clone, P: Parser + Default> Reassembler<K, P> {
+    pub fn new(config: ReassemblyConfig) -> Self {
+        Self {
+            config,
+            streams: HashMap::new(),
+            lru: BTreeMap::new(),
+            clock: 0,
+            total_bytes: 0,
+            stats: ReassemblyStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> ReassemblyStats {
+        self.stats
+    }
+
+    /// Adds the data found at `position` of a stream and returns the messages it
+    /// completed. `data` may be shorter than what the syscall transferred when the
+    /// event was truncated; the missing bytes are then a gap.
+    pub fn push(&mut self, key: K, position: u64, data: &[u8]) -> Vec<P::Message> {
+        self.clock += 1;
+        let clock = self.clock;
+        if !self.streams.contains_key(&key) {
+            while self.streams.len() >= self.config.max_streams.max(1) {
+                self.evict_oldest();
+            }
+            self.streams.insert(
+                key.clone(),
+                Stream {
+                    parser: P::default(),
+                    buf: Vec::new(),
+                    next: position,
+                    last_used: clock,
+                },
+            );
+        }
+        let stream = self
+            .streams
+            .get_mut(&key)
+            .expect("stream was just inserted");
+        self.lru.remove(&stream.last_used);
+        self.lru.insert(clock, key.clone());
+        stream.last_used = clock;
+
+        let end = position + data.len() as u64;
+        let data = if position > stream.next {
+            if !stream.buf.is_empty() {
+                self.stats.gaps += 1;
+            }
+            self.total_bytes -= stream.buf.len();
+            stream.buf.clear();
+            stream.parser.reset();
+            data
+        } else {
+            // Skip what was already received, e.g. from a retried syscall.
+            &data[data.len().min((stream.next - position) as usize)..]
+        };
+        stream.next = stream.next.max(end);
+
+        if stream.buf.len() + data.len() > self.config.max_stream_bytes {
+            self.stats.overflows += 1;
+            self.total_bytes -= stream.buf.len();
+            stream.buf.clear();
+            stream.parser.reset();
+            return vec![];
+        }
+        stream.buf.extend_from_slice(data);
+        self.total_bytes += data.len();
+
+        let mut messages = Vec::new();
+        let mut consumed = 0;
+        loop {
+            match parse_bounded(&mut stream.parser, &stream.buf[consumed..]) {
+                Ok((message, len)) => {
+                    messages.push(message);
+                    consumed += len;
+                }
+                Err(ParseError::Incomplete) => break,
+                Err(ParseError::Invalid(_)) => {
+                    // Resynchronize at the start of the next event.
+                    self.stats.invalid += 1;
+                    consumed = stream.buf.len();
+                    break;
+                }
+            }
+        }
+        stream.buf.drain(..consumed);
+        self.total_bytes -= consumed;
+
+        while self.total_bytes > self.config.max_total_bytes {
+            self.evict_oldest();
+        }
+        messages
+    }
+
+    /// Forgets a stream, e.g. once its connection closed.
+    pub fn remove(&mut self, key: &K) {
+        if let Some(stream) = self.streams.remove(key) {
+            self.lru.remove(&stream.last_used);
+            self.total_bytes -= stream.buf.len();
+        }
+    }
+
+    fn evict_oldest(&mut self) {
+        let Some((_, key)) = self.lru.pop_first() else {
+            return;
+        };
+        if let Some(stream) = self.streams.remove(&key) {
+            self.total_bytes -= stream.buf.len();
+            self.stats.evictions += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Reassembler, ReassemblyConfig};
+    use crate::protocols::http::{HttpParser, HttpStart};
+
+    fn paths(messages: Vec<crate::protocols::http::HttpMessage>) -> Vec<String> {
+        messages
+            .into_iter()
+            .map(|m| match m.start {
+                HttpStart::Request { target, .. } => target,
+                HttpStart::Response { status } => status.to_string(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_fragments_and_gaps() {
+        let mut streams = Reassembler::<u32, HttpParser>::new(ReassemblyConfig::default());
+        let first = b"GET /a HTTP/1.1\r\n\r\nGET /b HT";
+        assert_eq!(paths(streams.push(1, 0, first)), ["/a"]);
+        // A retransmission of bytes already seen is skipped.
+        assert!(streams.push(1, 23, b"/b HT").is_empty());
+        assert_eq!(paths(streams.push(1, 28, b"TP/1.1\r\n\r\n")), ["/b"]);
+
+        // The tail of /c is lost, so it is dropped and /d parsed on its own.
+        assert!(streams.push(1, 38, b"GET /c HTTP/1.1\r\n").is_empty());
+        assert_eq!(
+            paths(streams.push(1, 100, b"GET /d HTTP/1.1\r\n\r\n")),
+            ["/d"]
+        );
+        assert_eq!(streams.stats().gaps, 1);
+    }
+
+    #[test]
+    fn test_eviction() {
+        let config = ReassemblyConfig {
+            max_streams: 2,
+            ..Default::default()
+        };
+        let mut streams = Reassembler::<u32, HttpParser>::new(config);
+        streams.push(1, 0, b"GET /a");
+        streams.push(2, 0, b"GET /b");
+        streams.push(1, 6, b" HTTP");
+        // Stream 2 is the least recently used.
+        streams.push(3, 0, b"GET /c");
+        assert_eq!(streams.stats().evictions, 1);
+        assert_eq!(paths(streams.push(1, 11, b"/1.1\r\n\r\n")), ["/a"]);
+        assert!(streams.push(2, 6, b" HTTP/1.1\r\n\r\n").is_empty());
+    }
+}
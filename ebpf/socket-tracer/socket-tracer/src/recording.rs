@@ -0,0 +1,265 @@
+//! Recording of the raw perf samples, to replay them offline through the userspace
+//! pipeline.
+//!
+//! A recording starts with a header naming the format, followed by one record per
+//! sample: the stream it was read from, the CPU whose buffer it came from, the
+//! monotonic time it was read at and its bytes, copied verbatim. Samples are kept raw
+//! rather than decoded, so that a recording taken before a parser or aggregation
+//! change can validate it. The clock calibrations are recorded as well, so that the
+//! replayed events are converted to the same wall-clock time as when they were read.
+//!
+//! All integers are little-endian. The event layouts themselves aren't described by
+//! the recording, so it is only meaningful to a tracer built with the same
+//! `socket-tracer-common`.
+
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+use crate::clock::ClockSample;
+
+const MAGIC: &[u8; 8] = b"STRACREC";
+const VERSION: u32 = 1;
+/// Larger than any sample, so that a corrupted length is reported rather than allocated.
+const MAX_RECORD_LEN: usize = 1 << 20;
+
+/// The perf event array a sample was read from, or a clock calibration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stream {
+    Control,
+    Stats,
+    Data,
+    Clock,
+}
+
+impl Stream {
+    fn tag(self) -> u8 {
+        match self {
+            Stream::Control => 1,
+            Stream::Stats => 2,
+            Stream::Data => 3,
+            Stream::Clock => 4,
+        }
+    }
+
+    fn from_tag(tag: u8) -> Option<Self> {
+        match tag {
+            1 => Some(Stream::Control),
+            2 => Some(Stream::Stats),
+            3 => Some(Stream::Data),
+            4 => Some(Stream::Clock),
+            _ => None,
+        }
+    }
+}
+
+/// One recorded sample.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub stream: Stream,
+    pub cpu: u32,
+    /// `CLOCK_MONOTONIC` time the sample was read at.
+    pub timestamp_ns: u64,
+    pub data: Vec<u8>,
+}
+
+impl Record {
+    /// Returns the calibration of a [`Stream::Clock`] record.
+    pub fn clock_sample(&self) -> Option<ClockSample> {
+        if self.stream != Stream::Clock || self.data.len() != 16 {
+            return None;
+        }
+        let (monotonic, realtime) = self.data.split_at(8);
+        Some(ClockSample {
+            monotonic_ns: u64::from_le_bytes(monotonic.try_into().ok()?),
+            realtime_ns: u64::from_le_bytes(realtime.try_into().ok()?),
+        })
+    }
+}
+
+/// Appends samples to a recording. Shared by the consumers of every CPU, which take
+/// turns writing whole records.
+pub struct Recorder<W: Write = BufWriter<File>> {
+    writer: Mutex<W>,
+}
+
+impl Recorder {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        Self::new(BufWriter::new(File::create(path)?))
+    }
+}
+
+impl<W: Write> Recorder<W> {
+    pub fn new(mut writer: W) -> io::Result<Self> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        Ok(Self {
+            writer: Mutex::new(writer),
+        })
+    }
+
+    pub fn record(&self, stream: Stream, cpu: u32, data: &[u8]) -> io::Result<()> {
+        self.record_at(stream, cpu, ClockSample::now().monotonic_ns, data)
+    }
+
+    pub fn record_at(
+        &self,
+        stream: Stream,
+        cpu: u32,
+        timestamp_ns: u64,
+        data: &[u8],
+    ) -> io::Result<()> {
+        if data.len() > MAX_RECORD_LEN {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "sample too large"));
+        }
+        let mut header = [0; 17];
+        header[0] = stream.tag();
+        header[1..5].copy_from_slice(&cpu.to_le_bytes());
+        header[5..13].copy_from_slice(&timestamp_ns.to_le_bytes());
+        header[13..17].copy_from_slice(&(data.len() as u32).to_le_bytes());
+        let mut writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        writer.write_all(&header)?;
+        writer.write_all(data)
+    }
+
+    /// Records a clock calibration.
+    pub fn record_clock(&self, sample: ClockSample) -> io::Result<()> {
+        let mut data = [0; 16];
+        data[..8].copy_from_slice(&sample.monotonic_ns.to_le_bytes());
+        data[8..].copy_from_slice(&sample.realtime_ns.to_le_bytes());
+        self.record_at(Stream::Clock, 0, sample.monotonic_ns, &data)
+    }
+
+    pub fn flush(&self) -> io::Result<()> {
+        self.writer
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .flush()
+    }
+}
+
+/// Reads the records of a recording in the order they were written.
+pub struct Replay<R: Read = BufReader<File>> {
+    reader: R,
+}
+
+impl Replay {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        Self::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> Replay<R> {
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let mut header = [0; 12];
+        reader.read_exact(&mut header)?;
+        if &header[..8] != MAGIC {
+            return Err(invalid("not a socket-tracer recording"));
+        }
+        let version = u32::from_le_bytes(header[8..].try_into().unwrap());
+        if version != VERSION {
+            return Err(invalid(&format!(
+                "unsupported recording version {}",
+                version
+            )));
+        }
+        Ok(Self { reader })
+    }
+
+    /// Returns the next record, or `None` at the end of the recording. A record cut
+    /// short, as left by a tracer that was killed, is reported as an error.
+    pub fn next_record(&mut self) -> io::Result<Option<Record>> {
+        let mut header = [0; 17];
+        match self.reader.read_exact(&mut header[..1]) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        self.reader.read_exact(&mut header[1..])?;
+        let stream = Stream::from_tag(header[0])
+            .ok_or_else(|| invalid(&format!("unknown stream {}", header[0])))?;
+        let len = u32::from_le_bytes(header[13..17].try_into().unwrap()) as usize;
+        if len > MAX_RECORD_LEN {
+            return Err(invalid(&format!("record of {} bytes", len)));
+        }
+        let mut data = vec![0; len];
+        self.reader.read_exact(&mut data)?;
+        Ok(Some(Record {
+            stream,
+            cpu: u32::from_le_bytes(header[1..5].try_into().unwrap()),
+            timestamp_ns: u64::from_le_bytes(header[5..13].try_into().unwrap()),
+            data,
+        }))
+    }
+}
+
+impl<R: Read> Iterator for Replay<R> {
+    type Item = io::Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_record().transpose()
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, msg.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Record, Recorder, Replay, Stream};
+    use crate::clock::ClockSample;
+
+    fn recording() -> Vec<u8> {
+        let recorder = Recorder::new(Vec::new()).unwrap();
+        recorder
+            .record_clock(ClockSample {
+                monotonic_ns: 10,
+                realtime_ns: 1_700_000_000,
+            })
+            .unwrap();
+        recorder
+            .record_at(Stream::Control, 3, 11, &[1, 2, 3])
+            .unwrap();
+        recorder.record_at(Stream::Data, 0, 12, &[]).unwrap();
+        recorder.writer.into_inner().unwrap()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let records = Replay::new(recording().as_slice())
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(records.len(), 3);
+        assert_eq!(
+            records[0].clock_sample(),
+            Some(ClockSample {
+                monotonic_ns: 10,
+                realtime_ns: 1_700_000_000,
+            })
+        );
+        assert_eq!(
+            records[1],
+            Record {
+                stream: Stream::Control,
+                cpu: 3,
+                timestamp_ns: 11,
+                data: vec![1, 2, 3],
+            }
+        );
+        assert_eq!(records[2].stream, Stream::Data);
+        assert!(records[2].data.is_empty());
+    }
+
+    #[test]
+    fn test_truncated() {
+        let data = recording();
+        let mut replay = Replay::new(&data[..data.len() - 2]).unwrap();
+        assert!(replay.next_record().unwrap().is_some());
+        assert!(replay.next_record().unwrap().is_some());
+        assert!(replay.next_record().is_err());
+        assert!(Replay::new(&b"STRACREC\x02\0\0\0"[..]).is_err());
+    }
+}
@@ -0,0 +1,77 @@
+use std::alloc::{self, Layout};
+use std::{mem, ptr};
+
+/// A heap slot for decoding perf samples, allocated once per consumer and reused for
+/// every event. Data events are tens of KiB, so copying each one onto the stack or into
+/// a fresh allocation dominates the cost of handling it.
+pub struct EventScratch<T> {
+    event: Box<T>,
+}
+
+impl<T> EventScratch<T> {
+    /// Allocates a zeroed slot. `T` must be a plain `#[repr(C)]` event type for which
+    /// all-zero bytes are a valid value.
+    pub fn new() -> Self {
+        let layout = Layout::new::<T>();
+        let event = unsafe {
+            let raw = alloc::alloc_zeroed(layout) as *mut T;
+            if raw.is_null() {
+                alloc::handle_alloc_error(layout);
+            }
+            Box::from_raw(raw)
+        };
+        Self { event }
+    }
+
+    /// Copies `bytes` over the front of the slot and returns the decoded event. Only the
+    /// submitted prefix is copied; bytes past it keep the contents of earlier samples,
+    /// so callers must rely on the event's own length fields for variable-size payloads.
+    pub fn decode(&mut self, bytes: &[u8]) -> &T {
+        let len = bytes.len().min(mem::size_of::<T>());
+        unsafe {
+            ptr::copy_nonoverlapping(bytes.as_ptr(), &mut *self.event as *mut T as *mut u8, len);
+        }
+        &self.event
+    }
+}
+
+impl<T> Default for EventScratch<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::EventScratch;
+
+    #[derive(Debug, PartialEq)]
+    #[repr(C)]
+    struct Event {
+        len: u32,
+        data: [u8; 4],
+    }
+
+    #[test]
+    fn test_decode_reuses_slot() {
+        let mut scratch = EventScratch::<Event>::new();
+        assert_eq!(
+            scratch.decode(&[]),
+            &Event {
+                len: 0,
+                data: [0; 4]
+            }
+        );
+
+        let mut sample = 4u32.to_ne_bytes().to_vec();
+        sample.extend_from_slice(b"abcd trailing bytes");
+        let event = scratch.decode(&sample);
+        assert_eq!((event.len, &event.data), (4, b"abcd"));
+
+        // A shorter sample only overwrites its own prefix.
+        let mut sample = 2u32.to_ne_bytes().to_vec();
+        sample.extend_from_slice(b"xy");
+        let event = scratch.decode(&sample);
+        assert_eq!((event.len, &event.data), (2, b"xycd"));
+    }
+}
@@ -0,0 +1,87 @@
+use std::sync::Mutex;
+
+use log::debug;
+
+use socket_tracer::protocols::http::HttpParser;
+use socket_tracer::protocols::redis::RedisParser;
+use socket_tracer::protocols::Parser;
+use socket_tracer::reassembly::{Reassembler, ReassemblyConfig, ReassemblyStats};
+use socket_tracer_common::{
+    ControlEventType, SocketControlEvent, SocketDataEvent, TrafficDirection, TrafficProtocol,
+    MAX_MSG_SIZE,
+};
+
+use crate::capture::ConnKey;
+
+/// One direction of a connection, egress or not.
+type StreamKey = (ConnKey, bool);
+
+/// Reassembles the data events of connections whose protocol the kprobes inferred into
+/// messages, one reassembler per protocol.
+#[derive(Debug)]
+pub struct StreamHub {
+    http: Mutex<Reassembler<StreamKey, HttpParser>>,
+    redis: Mutex<Reassembler<StreamKey, RedisParser>>,
+}
+
+impl StreamHub {
+    pub fn new(config: ReassemblyConfig) -> Self {
+        Self {
+            http: Mutex::new(Reassembler::new(config)),
+            redis: Mutex::new(Reassembler::new(config)),
+        }
+    }
+
+    pub fn on_control(&self, event: &SocketControlEvent) {
+        if let ControlEventType::Close = event.event_type {
+            let key = ConnKey::from(&event.id);
+            for egress in [true, false] {
+                if let Ok(mut http) = self.http.lock() {
+                    http.remove(&(key, egress));
+                }
+                if let Ok(mut redis) = self.redis.lock() {
+                    redis.remove(&(key, egress));
+                }
+            }
+        }
+    }
+
+    pub fn on_data(&self, event: &SocketDataEvent) {
+        let inner = &event.inner;
+        let key = (
+            ConnKey::from(&inner.id),
+            matches!(inner.direction, TrafficDirection::Egress),
+        );
+        let data = &event.msg[..(inner.msg_buf_size as usize).min(MAX_MSG_SIZE)];
+        match inner.protocol {
+            TrafficProtocol::HTTP => push(&self.http, key, inner.position, data),
+            TrafficProtocol::Redis => push(&self.redis, key, inner.position, data),
+            _ => {}
+        }
+    }
+
+    /// Returns the reassembly drops of every protocol, by protocol name.
+    pub fn stats(&self) -> Vec<(&'static str, ReassemblyStats)> {
+        let mut stats = Vec::new();
+        if let Ok(http) = self.http.lock() {
+            stats.push(("http", http.stats()));
+        }
+        if let Ok(redis) = self.redis.lock() {
+            stats.push(("redis", redis.stats()));
+        }
+        stats
+    }
+}
+
+fn push<P>(streams: &Mutex<Reassembler<StreamKey, P>>, key: StreamKey, position: u64, data: &[u8])
+where
+    P: Parser + Default,
+    P::Message: std::fmt::Debug,
+{
+    let Ok(mut streams) = streams.lock() else {
+        return;
+    };
+    for message in streams.push(key, position, data) {
+        debug!("{:?} message: {:?}", key.0, message);
+    }
+}
@@ -0,0 +1,226 @@
+//! Resolution of the kernel functions the probes are attached to.
+//!
+//! Syscalls are entered through wrappers named after the architecture, such as
+//! `__x64_sys_write` or `__arm64_sys_write`, that kernels older than 4.17 don't have,
+//! where `sys_write` is the function itself. Which of them exist, and whether they can
+//! be inlined away, depends on the kernel build, so the candidates are looked up in
+//! `/proc/kallsyms`, which lists the functions kprobes can attach to, and in the
+//! kernel's BTF, which lists those fentry and fexit programs can attach to.
+
+use std::collections::HashSet;
+use std::fs;
+
+const KALLSYMS: &str = "/proc/kallsyms";
+const VMLINUX_BTF: &str = "/sys/kernel/btf/vmlinux";
+
+/// Prefixes of the syscall wrappers of each architecture.
+const SYSCALL_WRAPPERS: &[&str] = &["__x64_sys_", "__arm64_sys_", "__s390x_sys_", "__riscv_sys_"];
+
+const BTF_MAGIC: u16 = 0xeb9f;
+const BTF_KIND_FUNC: u32 = 12;
+
+/// The kernel functions known to kprobes and to BTF. Either is unknown when its source
+/// is unreadable, in which case every candidate is assumed to exist.
+#[derive(Debug, Default)]
+pub struct KernelSymbols {
+    kallsyms: Option<HashSet<String>>,
+    btf: Option<HashSet<String>>,
+}
+
+impl KernelSymbols {
+    /// Reads the symbols of the running kernel.
+    pub fn load() -> Self {
+        Self {
+            kallsyms: fs::read_to_string(KALLSYMS)
+                .ok()
+                .map(|text| parse_kallsyms(&text)),
+            btf: fs::read(VMLINUX_BTF)
+                .ok()
+                .and_then(|data| btf_functions(&data)),
+        }
+    }
+
+    pub fn new(kallsyms: Option<HashSet<String>>, btf: Option<HashSet<String>>) -> Self {
+        Self { kallsyms, btf }
+    }
+
+    /// Returns the symbols a kprobe on `func_name` may be attached to, best first.
+    /// Candidates missing from `/proc/kallsyms` are left out, unless none is there.
+    pub fn kprobe_symbols(&self, func_name: &str) -> Vec<String> {
+        let candidates = candidates(func_name);
+        let Some(kallsyms) = &self.kallsyms else {
+            return candidates;
+        };
+        let found: Vec<String> = candidates
+            .iter()
+            .filter(|symbol| kallsyms.contains(*symbol))
+            .cloned()
+            .collect();
+        if found.is_empty() {
+            candidates
+        } else {
+            found
+        }
+    }
+
+    /// Returns whether fentry and fexit programs can be attached to `func_name`, which
+    /// is taken as is: the variants of the syscall probes only work on the wrapper
+    /// they were written for.
+    pub fn has_btf_function(&self, func_name: &str) -> bool {
+        match &self.btf {
+            Some(functions) => functions.contains(func_name),
+            None => true,
+        }
+    }
+}
+
+/// Returns the given function first, followed by the other names of a syscall wrapper.
+fn candidates(func_name: &str) -> Vec<String> {
+    let mut symbols = vec![func_name.to_string()];
+    let Some(syscall) = SYSCALL_WRAPPERS
+        .iter()
+        .find_map(|prefix| func_name.strip_prefix(prefix))
+    else {
+        return symbols;
+    };
+    for prefix in SYSCALL_WRAPPERS {
+        let symbol = format!("{}{}", prefix, syscall);
+        if symbol != func_name {
+            symbols.push(symbol);
+        }
+    }
+    symbols.push(format!("__se_sys_{}", syscall));
+    symbols.push(format!("sys_{}", syscall));
+    symbols
+}
+
+/// Returns the functions listed in `/proc/kallsyms`, whose lines read
+/// `<address> <type> <name> [<module>]`, with a type of `t` or `T` for functions.
+pub fn parse_kallsyms(text: &str) -> HashSet<String> {
+    text.lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace().skip(1);
+            let kind = fields.next()?;
+            let name = fields.next()?;
+            matches!(kind, "t" | "T").then(|| name.to_string())
+        })
+        .collect()
+}
+
+/// Returns the names of the functions described by raw BTF data, or `None` if it is
+/// malformed. Only the native byte order is supported, as for the running kernel.
+pub fn btf_functions(data: &[u8]) -> Option<HashSet<String>> {
+    let u32_at = |offset: usize| -> Option<u32> {
+        let bytes = data.get(offset..offset + 4)?;
+        Some(u32::from_ne_bytes(bytes.try_into().ok()?))
+    };
+    let magic = u16::from_ne_bytes(data.get(0..2)?.try_into().ok()?);
+    if magic != BTF_MAGIC {
+        return None;
+    }
+    let hdr_len = u32_at(4)? as usize;
+    let type_off = u32_at(8)? as usize;
+    let type_len = u32_at(12)? as usize;
+    let str_off = u32_at(16)? as usize;
+    let str_len = u32_at(20)? as usize;
+    let types = data.get(hdr_len + type_off..hdr_len + type_off + type_len)?;
+    let strings = data.get(hdr_len + str_off..hdr_len + str_off + str_len)?;
+
+    let mut functions = HashSet::new();
+    let mut offset = 0;
+    while offset < types.len() {
+        let field = |index: usize| -> Option<u32> {
+            let start = offset + 4 * index;
+            Some(u32::from_ne_bytes(
+                types.get(start..start + 4)?.try_into().ok()?,
+            ))
+        };
+        let name_off = field(0)? as usize;
+        let info = field(1)?;
+        let kind = (info >> 24) & 0x1f;
+        let vlen = (info & 0xffff) as usize;
+        if kind == BTF_KIND_FUNC {
+            let name = strings.get(name_off..)?;
+            let end = name.iter().position(|&b| b == 0)?;
+            functions.insert(String::from_utf8_lossy(&name[..end]).into_owned());
+        }
+        // Each type is followed by data whose size depends on its kind.
+        offset += 12
+            + match kind {
+                // INT, VAR, DECL_TAG
+                1 | 14 | 17 => 4,
+                // ARRAY
+                3 => 12,
+                // STRUCT, UNION, DATASEC, ENUM64
+                4 | 5 | 15 | 19 => 12 * vlen,
+                // ENUM, FUNC_PROTO
+                6 | 13 => 8 * vlen,
+                // PTR, FWD, TYPEDEF, VOLATILE, CONST, RESTRICT, FUNC, FLOAT, TYPE_TAG
+                2 | 7..=12 | 16 | 18 => 0,
+                _ => return None,
+            };
+    }
+    Some(functions)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::{btf_functions, parse_kallsyms, KernelSymbols};
+
+    #[test]
+    fn test_kprobe_symbols() {
+        let kallsyms = parse_kallsyms(
+            "0000000000000000 T __arm64_sys_write\n\
+             0000000000000000 t __se_sys_write\n\
+             0000000000000000 D sys_write\n\
+             0000000000000000 T tcp_data_queue\t[tcp]\n",
+        );
+        let symbols = KernelSymbols::new(Some(kallsyms), None);
+        assert_eq!(
+            symbols.kprobe_symbols("__x64_sys_write"),
+            ["__arm64_sys_write", "__se_sys_write"]
+        );
+        assert_eq!(symbols.kprobe_symbols("__sys_connect"), ["__sys_connect"]);
+        assert!(symbols.has_btf_function("__x64_sys_write"));
+    }
+
+    #[test]
+    fn test_btf_functions() {
+        let strings = b"\0int\0read\0";
+        let mut types = Vec::new();
+        // INT "int"
+        for word in [1u32, 1 << 24, 4, 32] {
+            types.extend_from_slice(&word.to_ne_bytes());
+        }
+        // FUNC_PROTO returning int with one int parameter
+        for word in [0u32, 13 << 24 | 1, 1, 0, 1] {
+            types.extend_from_slice(&word.to_ne_bytes());
+        }
+        // FUNC "read"
+        for word in [5u32, 12 << 24, 2] {
+            types.extend_from_slice(&word.to_ne_bytes());
+        }
+
+        let mut data = Vec::new();
+        data.extend_from_slice(&0xeb9fu16.to_ne_bytes());
+        data.extend_from_slice(&[1, 0]);
+        let header = [
+            24u32,
+            0,
+            types.len() as u32,
+            types.len() as u32,
+            strings.len() as u32,
+        ];
+        for word in header {
+            data.extend_from_slice(&word.to_ne_bytes());
+        }
+        data.extend_from_slice(&types);
+        data.extend_from_slice(strings);
+
+        let functions = btf_functions(&data).unwrap();
+        assert_eq!(functions, HashSet::from(["read".to_string()]));
+        assert!(btf_functions(&data[..30]).is_none());
+    }
+}
@@ -0,0 +1,150 @@
+//! Synthetic traffic for benchmarks and tests: HTTP and Redis streams, and the raw data
+//! event samples the kprobes would submit for them, byte for byte as read from the
+//! perf buffers.
+
+use std::mem::{self, MaybeUninit};
+use std::ptr::addr_of_mut;
+use std::slice;
+
+use socket_tracer_common::{
+    ConnId, EndpointRole, SocketDataEvent, SocketDataEventInner, SourceFunction, TrafficDirection,
+    TrafficProtocol, Uid, MAX_MSG_SIZE,
+};
+
+/// Returns `count` HTTP/1.1 requests with bodies of `body_size` bytes, as a client
+/// keeping its connection alive would write them.
+pub fn http_requests(count: usize, body_size: usize) -> Vec<u8> {
+    let mut stream = Vec::new();
+    for i in 0..count {
+        stream.extend_from_slice(
+            format!(
+                "POST /api/v1/orders/{} HTTP/1.1\r\nHost: backend\r\nUser-Agent: synthetic\r\n\
+                 Content-Type: application/json\r\nContent-Length: {}\r\n\r\n",
+                i, body_size
+            )
+            .as_bytes(),
+        );
+        stream.extend((0..body_size).map(|j| b'a' + (j % 26) as u8));
+    }
+    stream
+}
+
+/// Returns `count` Redis `SET` commands with values of `value_size` bytes.
+pub fn redis_commands(count: usize, value_size: usize) -> Vec<u8> {
+    let mut stream = Vec::new();
+    for i in 0..count {
+        let key = format!("key:{}", i);
+        stream.extend_from_slice(
+            format!(
+                "*3\r\n$3\r\nSET\r\n${}\r\n{}\r\n${}\r\n",
+                key.len(),
+                key,
+                value_size
+            )
+            .as_bytes(),
+        );
+        stream.extend((0..value_size).map(|j| b'0' + (j % 10) as u8));
+        stream.extend_from_slice(b"\r\n");
+    }
+    stream
+}
+
+/// Splits `stream` into the data events of the writes of `segment_size` bytes that sent
+/// it over connection `conn`, each at its position in the stream.
+pub fn data_events(
+    protocol: TrafficProtocol,
+    conn: u64,
+    stream: &[u8],
+    segment_size: usize,
+) -> Vec<Vec<u8>> {
+    stream
+        .chunks(segment_size.max(1))
+        .enumerate()
+        .map(|(i, segment)| {
+            let position = (i * segment_size.max(1)) as u64;
+            data_event(protocol, conn, position, segment)
+        })
+        .collect()
+}
+
+/// Returns the sample of one egress data event carrying `data`, truncated to
+/// [`MAX_MSG_SIZE`] like the kprobes do.
+pub fn data_event(protocol: TrafficProtocol, conn: u64, position: u64, data: &[u8]) -> Vec<u8> {
+    let msg_buf_size = data.len().min(MAX_MSG_SIZE);
+    // Zeroed first, so that the padding between fields is initialized too.
+    let mut inner = MaybeUninit::<SocketDataEventInner>::zeroed();
+    let p = inner.as_mut_ptr();
+    unsafe {
+        addr_of_mut!((*p).timestamp_ns).write(1_000_000 + position);
+        addr_of_mut!((*p).id).write(ConnId {
+            uid: Uid {
+                tgid: 1000 + conn,
+                start_time_ticks: 1,
+            },
+            fd: 3,
+            tsid: conn,
+            conn_id: conn,
+        });
+        addr_of_mut!((*p).protocol).write(protocol);
+        addr_of_mut!((*p).role).write(EndpointRole::Client);
+        addr_of_mut!((*p).direction).write(TrafficDirection::Egress);
+        addr_of_mut!((*p).ssl).write(false);
+        addr_of_mut!((*p).source_function).write(SourceFunction::SyscallWrite);
+        addr_of_mut!((*p).position).write(position);
+        addr_of_mut!((*p).msg_size).write(data.len() as u32);
+        addr_of_mut!((*p).msg_buf_size).write(msg_buf_size as u32);
+    }
+
+    let inner_size = mem::size_of::<SocketDataEventInner>();
+    let mut sample = vec![0; mem::size_of::<SocketDataEvent>()];
+    sample[..inner_size]
+        .copy_from_slice(unsafe { slice::from_raw_parts(p as *const u8, inner_size) });
+    let msg = mem::offset_of!(SocketDataEvent, msg);
+    sample[msg..msg + msg_buf_size].copy_from_slice(&data[..msg_buf_size]);
+    sample
+}
+
+#[cfg(test)]
+mod tests {
+    use socket_tracer_common::{SocketDataEvent, TrafficProtocol, MAX_MSG_SIZE};
+
+    use super::{data_events, http_requests, redis_commands};
+    use crate::protocols::http::HttpParser;
+    use crate::protocols::redis::RedisParser;
+    use crate::reassembly::{Reassembler, ReassemblyConfig};
+    use crate::scratch::EventScratch;
+
+    fn reassemble<P: crate::protocols::Parser + Default>(samples: &[Vec<u8>]) -> usize {
+        let mut scratch = EventScratch::<SocketDataEvent>::new();
+        let mut reassembler = Reassembler::<u64, P>::new(ReassemblyConfig::default());
+        let mut messages = 0;
+        for sample in samples {
+            let event = scratch.decode(sample);
+            let data = &event.msg[..event.inner.msg_buf_size as usize];
+            messages += reassembler
+                .push(event.inner.id.tsid, event.inner.position, data)
+                .len();
+        }
+        messages
+    }
+
+    #[test]
+    fn test_data_events_reassemble() {
+        let http = data_events(TrafficProtocol::HTTP, 1, &http_requests(10, 300), 1000);
+        assert_eq!(reassemble::<HttpParser>(&http), 10);
+        let redis = data_events(TrafficProtocol::Redis, 2, &redis_commands(10, 50), 64);
+        assert_eq!(reassemble::<RedisParser>(&redis), 10);
+    }
+
+    #[test]
+    fn test_chunked_events_reassemble() {
+        // Bodies larger than an event are split into chunks by the probes.
+        let http = data_events(
+            TrafficProtocol::HTTP,
+            3,
+            &http_requests(2, 100_000),
+            MAX_MSG_SIZE,
+        );
+        assert_eq!(reassemble::<HttpParser>(&http), 2);
+    }
+}
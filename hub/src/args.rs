@@ -0,0 +1,101 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use clap::Parser;
+
+#[derive(Parser, Debug)]
+#[command(
+    long_about = "A server the agents of one or more clusters push their service maps to, serving them merged into one topology."
+)]
+#[command(name = "hub")]
+pub(crate) struct Args {
+    /// Optional: TCP address to serve hub, to the agents, and topology on.
+    #[clap(long, verbatim_doc_comment, default_value = "0.0.0.0:9090")]
+    pub(crate) listen_addr: SocketAddr,
+    /// Optional: Cluster whose agents push to the server, as NAME=TOKEN_FILE. The
+    /// agents presenting the token held by TOKEN_FILE, see --push-token-file,
+    /// belong to the cluster NAME. The file is read every time an agent
+    /// connects, so that rotated tokens are picked up. Can be repeated, with a
+    /// different token for each cluster. Without any, every agent is accepted
+    /// without a token, as part of the cluster named by --default-cluster.
+    /// Example: --cluster prod-eu=/etc/hub/prod-eu.token
+    #[clap(long = "cluster", verbatim_doc_comment, value_parser = parse_cluster)]
+    pub(crate) clusters: Vec<(String, PathBuf)>,
+    /// Optional: Label of a cluster, as NAME:KEY=VALUE, served along with its
+    /// topology. Can be repeated.
+    /// Example: --cluster-label prod-eu:region=eu-west-1
+    #[clap(long, verbatim_doc_comment, value_parser = parse_cluster_label)]
+    pub(crate) cluster_label: Vec<(String, String, String)>,
+    /// Optional: Cluster of the agents when no --cluster is given.
+    #[clap(long, verbatim_doc_comment, default_value = "default")]
+    pub(crate) default_cluster: String,
+    /// Optional: Replica of a server sharded over several. Repeat it for each, this
+    /// one included, in the same order as --push-server-url on the agents: the
+    /// replica at --shard owns the nodes that consistent hashing of their name
+    /// gives it, and holds others while their own replica is down. Every replica
+    /// answers GetTopology with the topologies of all of them merged.
+    /// Example: --replica http://hub-0.hub:9090
+    #[clap(long = "replica", verbatim_doc_comment)]
+    pub(crate) replicas: Vec<String>,
+    /// Optional: Index of this replica in --replica, e.g. the ordinal of its pod in
+    /// a StatefulSet.
+    #[clap(long, verbatim_doc_comment, default_value = "0")]
+    pub(crate) shard: u32,
+    /// Optional: Seconds the service maps of an agent are kept once it
+    /// disconnected. They are updated again if it connects back in time.
+    #[clap(long, verbatim_doc_comment, default_value = "600")]
+    pub(crate) forget_after: u64,
+}
+
+fn parse_cluster(s: &str) -> Result<(String, PathBuf), String> {
+    match s.split_once('=') {
+        Some((name, token_file)) if !name.is_empty() && !token_file.is_empty() => {
+            Ok((name.to_string(), PathBuf::from(token_file)))
+        }
+        _ => Err(format!("expected <name>=<token file>, got {:?}", s)),
+    }
+}
+
+fn parse_cluster_label(s: &str) -> Result<(String, String, String), String> {
+    s.split_once(':')
+        .and_then(|(cluster, label)| {
+            let (key, value) = label.split_once('=')?;
+            Some((cluster.to_string(), key.to_string(), value.to_string()))
+        })
+        .ok_or(format!("expected <cluster>:<name>=<value>, got {:?}", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_cluster() {
+        assert_eq!(
+            parse_cluster("prod=/etc/hub/prod.token").unwrap(),
+            ("prod".to_string(), PathBuf::from("/etc/hub/prod.token"))
+        );
+        assert!(parse_cluster("prod").is_err());
+        assert!(parse_cluster("=/etc/hub/prod.token").is_err());
+        assert!(parse_cluster("prod=").is_err());
+    }
+
+    #[test]
+    fn test_parse_cluster_label() {
+        assert_eq!(
+            parse_cluster_label("prod:region=eu-west-1").unwrap(),
+            (
+                "prod".to_string(),
+                "region".to_string(),
+                "eu-west-1".to_string()
+            )
+        );
+        // Label values may hold colons, cluster names can't.
+        assert_eq!(
+            parse_cluster_label("prod:url=http://a:1").unwrap().2,
+            "http://a:1"
+        );
+        assert!(parse_cluster_label("prod:region").is_err());
+        assert!(parse_cluster_label("region=eu-west-1").is_err());
+    }
+}
@@ -0,0 +1,186 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::bail;
+use log::warn;
+use tonic::metadata::MetadataMap;
+
+use agent_api::v1::TopologyCluster;
+
+use crate::args::Args;
+
+/// A cluster whose agents push to the server.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Cluster {
+    pub(crate) name: String,
+    /// File holding the token the agents of the cluster present, none when every
+    /// agent is accepted.
+    token_file: Option<PathBuf>,
+    pub(crate) labels: HashMap<String, String>,
+}
+
+impl From<&Cluster> for TopologyCluster {
+    fn from(cluster: &Cluster) -> Self {
+        TopologyCluster {
+            name: cluster.name.clone(),
+            labels: cluster.labels.clone(),
+        }
+    }
+}
+
+/// The clusters the server accepts agents of, in the order they were given.
+#[derive(Debug)]
+pub(crate) struct Clusters {
+    clusters: Vec<Cluster>,
+}
+
+impl Clusters {
+    pub(crate) fn new(args: &Args) -> anyhow::Result<Self> {
+        let mut clusters: Vec<_> = args
+            .clusters
+            .iter()
+            .map(|(name, token_file)| Cluster {
+                name: name.clone(),
+                token_file: Some(token_file.clone()),
+                labels: HashMap::new(),
+            })
+            .collect();
+        if clusters.is_empty() {
+            clusters.push(Cluster {
+                name: args.default_cluster.clone(),
+                token_file: None,
+                labels: HashMap::new(),
+            });
+        }
+        for (i, cluster) in clusters.iter().enumerate() {
+            if clusters[..i].iter().any(|c| c.name == cluster.name) {
+                bail!("cluster {} is given twice", cluster.name);
+            }
+        }
+        for (name, key, value) in &args.cluster_label {
+            let Some(cluster) = clusters.iter_mut().find(|c| c.name == *name) else {
+                bail!(
+                    "label {}={} is given to unknown cluster {}",
+                    key,
+                    value,
+                    name
+                );
+            };
+            cluster.labels.insert(key.clone(), value.clone());
+        }
+        Ok(Self { clusters })
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &Cluster> {
+        self.clusters.iter()
+    }
+
+    /// Returns the cluster of the agent presenting `metadata`, the first whose token it
+    /// sends as a bearer token, if any.
+    pub(crate) fn authenticate(&self, metadata: &MetadataMap) -> Option<&Cluster> {
+        if let [cluster @ Cluster {
+            token_file: None, ..
+        }] = self.clusters.as_slice()
+        {
+            return Some(cluster);
+        }
+        let presented = metadata
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))?;
+        for cluster in &self.clusters {
+            let Some(token_file) = &cluster.token_file else {
+                continue;
+            };
+            // Read on every call, so that rotated tokens are picked up.
+            let token = match fs::read_to_string(token_file) {
+                Ok(token) => token,
+                Err(e) => {
+                    warn!("Failed to read {}: {}", token_file.display(), e);
+                    continue;
+                }
+            };
+            let token = token.trim();
+            if !token.is_empty() && constant_time_eq(presented, token) {
+                return Some(cluster);
+            }
+        }
+        None
+    }
+}
+
+/// Compares tokens in a time independent of where they differ.
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    fn metadata(token: &str) -> MetadataMap {
+        let mut metadata = MetadataMap::new();
+        metadata.insert(
+            "authorization",
+            format!("Bearer {}", token).parse().unwrap(),
+        );
+        metadata
+    }
+
+    #[test]
+    fn test_clusters_by_token() {
+        let dir = std::env::temp_dir().join(format!("hub-clusters-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("eu.token"), "eu-secret\n").unwrap();
+        fs::write(dir.join("us.token"), "us-secret").unwrap();
+        let args = Args::parse_from([
+            "hub".to_string(),
+            format!("--cluster=eu={}", dir.join("eu.token").display()),
+            format!("--cluster=us={}", dir.join("us.token").display()),
+            "--cluster-label=eu:region=eu-west-1".to_string(),
+        ]);
+        let clusters = Clusters::new(&args).unwrap();
+
+        let eu = clusters.authenticate(&metadata("eu-secret")).unwrap();
+        assert_eq!(eu.name, "eu");
+        assert_eq!(eu.labels["region"], "eu-west-1");
+        let us = clusters.authenticate(&metadata("us-secret")).unwrap();
+        assert_eq!(us.name, "us");
+        assert!(us.labels.is_empty());
+        assert!(clusters.authenticate(&metadata("eu")).is_none());
+        assert!(clusters.authenticate(&MetadataMap::new()).is_none());
+
+        // Rotated tokens are picked up.
+        fs::write(dir.join("us.token"), "us-rotated").unwrap();
+        assert!(clusters.authenticate(&metadata("us-secret")).is_none());
+        assert_eq!(
+            clusters.authenticate(&metadata("us-rotated")).unwrap().name,
+            "us"
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_default_cluster_needs_no_token() {
+        let args = Args::parse_from(["hub", "--cluster-label=default:env=dev"]);
+        let clusters = Clusters::new(&args).unwrap();
+        let cluster = clusters.authenticate(&MetadataMap::new()).unwrap();
+        assert_eq!(cluster.name, "default");
+        assert_eq!(cluster.labels["env"], "dev");
+    }
+
+    #[test]
+    fn test_invalid_clusters() {
+        let args = Args::parse_from(["hub", "--cluster=eu=/a", "--cluster=eu=/b"]);
+        assert!(Clusters::new(&args).is_err());
+        let args = Args::parse_from(["hub", "--cluster=eu=/a", "--cluster-label=us:env=prod"]);
+        assert!(Clusters::new(&args).is_err());
+    }
+}
@@ -0,0 +1,400 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use log::{debug, info, warn};
+use parking_lot::Mutex;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status, Streaming};
+
+use agent_api::v1::agent_message::Message;
+use agent_api::v1::hub_command::Command;
+use agent_api::v1::hub_server::Hub;
+use agent_api::v1::{AgentMessage, HubCommand, ResyncCommand, ServiceMapPush};
+use agent_api::{is_compatible, API_VERSION};
+
+use crate::clusters::Clusters;
+use crate::replicas::Replicas;
+use crate::store::Store;
+
+/// Commands handed to the stream of an agent ahead of the transport.
+const COMMAND_BUFFER: usize = 16;
+
+/// Serves hub: keeps a copy of the service maps pushed by the agents of each cluster.
+#[derive(Debug)]
+pub(crate) struct HubService {
+    clusters: Arc<Clusters>,
+    replicas: Arc<Replicas>,
+    store: Arc<Mutex<Store>>,
+}
+
+impl HubService {
+    pub(crate) fn new(
+        clusters: Arc<Clusters>,
+        replicas: Arc<Replicas>,
+        store: Arc<Mutex<Store>>,
+    ) -> Self {
+        Self {
+            clusters,
+            replicas,
+            store,
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl Hub for HubService {
+    type ConnectStream = ReceiverStream<Result<HubCommand, Status>>;
+
+    async fn connect(
+        &self,
+        request: Request<Streaming<AgentMessage>>,
+    ) -> Result<Response<Self::ConnectStream>, Status> {
+        let cluster = self
+            .clusters
+            .authenticate(request.metadata())
+            .ok_or(Status::unauthenticated("missing or invalid push token"))?
+            .name
+            .clone();
+        let mut messages = request.into_inner();
+        let registration = match messages.message().await? {
+            Some(AgentMessage {
+                message: Some(Message::Registration(registration)),
+            }) => registration,
+            _ => {
+                return Err(Status::invalid_argument(
+                    "the first message must be a registration",
+                ))
+            }
+        };
+        if registration.node_name.is_empty() {
+            return Err(Status::invalid_argument("missing node name"));
+        }
+        if !is_compatible(&registration.api_version) {
+            return Err(Status::failed_precondition(format!(
+                "the agent serves API {}, which is incompatible with the server's {}",
+                registration.api_version, API_VERSION
+            )));
+        }
+        if registration.shards != self.replicas.shards() {
+            warn!(
+                "Agent of node {} in cluster {} pushes to {} replicas, the server has {}",
+                registration.node_name,
+                cluster,
+                registration.shards,
+                self.replicas.shards()
+            );
+        }
+        let owned = self.replicas.owns(&registration.node_name);
+        if !owned {
+            info!(
+                "Holding node {} in cluster {} while the replica of shard {} is down",
+                registration.node_name, cluster, registration.shard
+            );
+        }
+        let session = self
+            .store
+            .lock()
+            .register(&cluster, &registration.node_name, owned);
+        info!(
+            "Agent {} of node {} in cluster {} connected, {} updates dropped since it last did",
+            registration.agent_version,
+            registration.node_name,
+            cluster,
+            registration.dropped_updates
+        );
+
+        let (tx, rx) = mpsc::channel(COMMAND_BUFFER);
+        let connection = Connection {
+            cluster,
+            node_name: registration.node_name,
+            session,
+            store: self.store.clone(),
+            commands: tx,
+            next_id: 0,
+            resyncing: HashSet::new(),
+        };
+        tokio::spawn(connection.receive(messages));
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+}
+
+/// The Connect stream of an agent.
+struct Connection {
+    cluster: String,
+    node_name: String,
+    session: u64,
+    store: Arc<Mutex<Store>>,
+    commands: mpsc::Sender<Result<HubCommand, Status>>,
+    next_id: u64,
+    /// Programs asked for a full update, which aren't asked again until it comes.
+    resyncing: HashSet<String>,
+}
+
+impl Connection {
+    /// Applies the updates the agent pushes until its stream ends.
+    async fn receive(mut self, mut messages: Streaming<AgentMessage>) {
+        loop {
+            let message = match messages.message().await {
+                Ok(Some(AgentMessage {
+                    message: Some(message),
+                })) => message,
+                // Sent by an agent newer than the server.
+                Ok(Some(_)) => continue,
+                Ok(None) => break,
+                Err(status) => {
+                    debug!(
+                        "Stream of node {} in cluster {} broke: {}",
+                        self.node_name, self.cluster, status
+                    );
+                    break;
+                }
+            };
+            let pushes = match message {
+                Message::ServiceMap(push) => vec![push],
+                Message::Batch(batch) => batch.pushes,
+                Message::Result(result) => {
+                    if !result.error.is_empty() {
+                        warn!(
+                            "Command {} failed on node {} in cluster {}: {}",
+                            result.id, self.node_name, self.cluster, result.error
+                        );
+                    }
+                    continue;
+                }
+                Message::Registration(_) => {
+                    warn!(
+                        "Node {} in cluster {} registered twice on a stream",
+                        self.node_name, self.cluster
+                    );
+                    continue;
+                }
+            };
+            if self.apply(pushes).await.is_err() {
+                break;
+            }
+        }
+        self.store
+            .lock()
+            .disconnect(&self.cluster, &self.node_name, self.session);
+        info!(
+            "Agent of node {} in cluster {} disconnected",
+            self.node_name, self.cluster
+        );
+    }
+
+    /// Applies `pushes`, and asks for a full update of the programs whose copy missed
+    /// some. Fails once the stream is closed.
+    async fn apply(&mut self, pushes: Vec<ServiceMapPush>) -> anyhow::Result<()> {
+        let mut resync = Vec::new();
+        {
+            let mut store = self.store.lock();
+            for push in pushes {
+                let Some(update) = push.update else {
+                    continue;
+                };
+                let full = update.full;
+                let matches = store.apply(
+                    &self.cluster,
+                    &self.node_name,
+                    self.session,
+                    &push.name,
+                    update,
+                );
+                if matches {
+                    if full {
+                        self.resyncing.remove(&push.name);
+                    }
+                } else if full {
+                    // Another full update wouldn't do better.
+                    warn!(
+                        "Full update of {} from node {} in cluster {} doesn't match its checksum",
+                        push.name, self.node_name, self.cluster
+                    );
+                } else if self.resyncing.insert(push.name.clone()) {
+                    resync.push(push.name);
+                }
+            }
+        }
+        for name in resync {
+            debug!(
+                "Copy of the service map of {} from node {} in cluster {} missed updates, resyncing it",
+                name, self.node_name, self.cluster
+            );
+            self.next_id += 1;
+            let command = HubCommand {
+                id: self.next_id,
+                command: Some(Command::Resync(ResyncCommand { name })),
+            };
+            self.commands
+                .send(Ok(command))
+                .await
+                .map_err(|_| anyhow::anyhow!("stream closed"))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use clap::Parser;
+    use tokio::net::TcpListener;
+    use tokio_stream::wrappers::TcpListenerStream;
+    use tonic::transport::{Endpoint, Server};
+
+    use agent_api::snapshot_checksum;
+    use agent_api::v1::hub_client::HubClient;
+    use agent_api::v1::hub_server::HubServer;
+    use agent_api::v1::topology_client::TopologyClient;
+    use agent_api::v1::topology_server::TopologyServer;
+    use agent_api::v1::{
+        AgentRegistration, GetTopologyRequest, ServiceMapBatch, ServiceMapEdge, ServiceMapUpdate,
+        TopologyAgent,
+    };
+
+    use super::*;
+    use crate::args::Args;
+    use crate::topology::TopologyService;
+
+    fn registration(node_name: &str) -> AgentMessage {
+        AgentMessage {
+            message: Some(Message::Registration(AgentRegistration {
+                node_name: node_name.to_string(),
+                api_version: API_VERSION.to_string(),
+                shards: 1,
+                ..Default::default()
+            })),
+        }
+    }
+
+    fn batch(name: &str, update: ServiceMapUpdate) -> AgentMessage {
+        AgentMessage {
+            message: Some(Message::Batch(ServiceMapBatch {
+                pushes: vec![ServiceMapPush {
+                    name: name.to_string(),
+                    update: Some(update),
+                }],
+            })),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_push_and_read_topology() {
+        let token_file =
+            std::env::temp_dir().join(format!("hub-push-{}.token", std::process::id()));
+        std::fs::write(&token_file, "eu-secret").unwrap();
+        let args = Args::parse_from([
+            "hub".to_string(),
+            format!("--cluster=eu={}", token_file.display()),
+            "--cluster-label=eu:region=eu-west-1".to_string(),
+        ]);
+        let clusters = Arc::new(Clusters::new(&args).unwrap());
+        let replicas = Arc::new(Replicas::new(&args).unwrap());
+        let store = Arc::new(Mutex::new(Store::new(Duration::from_secs(60))));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(
+            Server::builder()
+                .add_service(HubServer::new(HubService::new(
+                    clusters.clone(),
+                    replicas.clone(),
+                    store.clone(),
+                )))
+                .add_service(TopologyServer::new(TopologyService::new(
+                    clusters, replicas, store,
+                )))
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+
+        // Agents without the token of a cluster are turned away.
+        let channel = Endpoint::from_shared(url).unwrap().connect().await.unwrap();
+        let mut hub = HubClient::new(channel.clone());
+        let messages = tokio_stream::iter([registration("node-1")]);
+        let status = hub.connect(messages).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+
+        let edge = ServiceMapEdge {
+            client_workload: "shop/web".to_string(),
+            server_workload: "shop/db".to_string(),
+            server_port: 5432,
+            protocol: "TCP".to_string(),
+            ..Default::default()
+        };
+        let full = ServiceMapUpdate {
+            snapshot_id: 1,
+            timestamp_ns: 1,
+            full: true,
+            edges: vec![edge.clone()],
+            checksum: snapshot_checksum([&edge]),
+            ..Default::default()
+        };
+        // Follows an update the server didn't get.
+        let delta = ServiceMapUpdate {
+            snapshot_id: 3,
+            timestamp_ns: 3,
+            edges: vec![ServiceMapEdge {
+                server_workload: "shop/cache".to_string(),
+                ..edge.clone()
+            }],
+            checksum: 0,
+            ..Default::default()
+        };
+        let (tx, rx) = mpsc::channel(4);
+        tx.send(registration("node-1")).await.unwrap();
+        tx.send(batch("service-map", full)).await.unwrap();
+        tx.send(batch("service-map", delta)).await.unwrap();
+        let mut request = Request::new(ReceiverStream::new(rx));
+        request
+            .metadata_mut()
+            .insert("authorization", "Bearer eu-secret".parse().unwrap());
+        let mut commands = hub.connect(request).await.unwrap().into_inner();
+
+        // The copy missed an update, so the agent is asked for a full one.
+        let command = commands.message().await.unwrap().unwrap();
+        assert_eq!(
+            command.command,
+            Some(Command::Resync(ResyncCommand {
+                name: "service-map".to_string()
+            }))
+        );
+
+        let mut topology = TopologyClient::new(channel);
+        let response = topology
+            .get_topology(GetTopologyRequest::default())
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.clusters.len(), 1);
+        assert_eq!(response.clusters[0].labels["region"], "eu-west-1");
+        let nodes: Vec<_> = response
+            .nodes
+            .iter()
+            .map(|n| (n.cluster.as_str(), n.workload.as_str()))
+            .collect();
+        assert_eq!(
+            nodes,
+            [("eu", "shop/cache"), ("eu", "shop/db"), ("eu", "shop/web")]
+        );
+        assert_eq!(response.edges.len(), 2);
+        assert_eq!(
+            response.agents,
+            [TopologyAgent {
+                cluster: "eu".to_string(),
+                node_name: "node-1".to_string(),
+                connected: true,
+                owned: true,
+            }]
+        );
+        for edge in &response.edges {
+            assert_eq!(
+                (edge.cluster.as_str(), edge.node_name.as_str()),
+                ("eu", "node-1")
+            );
+            assert_eq!(edge.name, "service-map");
+        }
+        drop(tx);
+        std::fs::remove_file(&token_file).unwrap();
+    }
+}
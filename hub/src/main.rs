@@ -0,0 +1,79 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use clap::Parser;
+use log::info;
+use parking_lot::Mutex;
+use tokio::signal::unix::{signal, SignalKind};
+use tonic::codec::CompressionEncoding;
+use tonic::transport::Server;
+
+use agent_api::v1::hub_server::HubServer;
+use agent_api::v1::topology_server::TopologyServer;
+use agent_api::FILE_DESCRIPTOR_SET;
+
+use crate::args::Args;
+use crate::clusters::Clusters;
+use crate::hub::HubService;
+use crate::replicas::Replicas;
+use crate::store::Store;
+use crate::topology::TopologyService;
+
+mod args;
+mod clusters;
+mod hub;
+mod replicas;
+mod store;
+mod topology;
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    env_logger::init();
+
+    let clusters = Arc::new(Clusters::new(&args)?);
+    let replicas = Arc::new(Replicas::new(&args)?);
+    let store = Arc::new(Mutex::new(Store::new(Duration::from_secs(
+        args.forget_after,
+    ))));
+    // Agents compress their pushes with zstd by default.
+    let hub = HubServer::new(HubService::new(
+        clusters.clone(),
+        replicas.clone(),
+        store.clone(),
+    ))
+    .accept_compressed(CompressionEncoding::Zstd)
+    .send_compressed(CompressionEncoding::Zstd);
+    let topology = TopologyServer::new(TopologyService::new(
+        clusters.clone(),
+        replicas.clone(),
+        store,
+    ));
+    let reflection = tonic_reflection::server::Builder::configure()
+        .register_encoded_file_descriptor_set(FILE_DESCRIPTOR_SET)
+        .build()?;
+
+    let names: Vec<_> = clusters.iter().map(|c| c.name.as_str()).collect();
+    info!(
+        "Serving hub and topology on {} for clusters {}, as shard {} of {}",
+        args.listen_addr,
+        names.join(", "),
+        replicas.shard(),
+        replicas.shards()
+    );
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let shutdown = async move {
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {},
+            _ = sigterm.recv() => {},
+        }
+        info!("Received shutdown signal, stopping.");
+    };
+    Server::builder()
+        .add_service(hub)
+        .add_service(topology)
+        .add_service(reflection)
+        .serve_with_shutdown(args.listen_addr, shutdown)
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,357 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+use futures::future::join_all;
+use log::warn;
+use tonic::transport::{Channel, Endpoint};
+
+use agent_api::shard_of;
+use agent_api::v1::topology_client::TopologyClient;
+use agent_api::v1::{GetTopologyRequest, GetTopologyResponse, TopologyCluster};
+
+use crate::args::Args;
+use crate::store::topology;
+
+/// How long another replica has to answer.
+const FAN_OUT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The replicas of the server, this one owning the shard of nodes at its index.
+#[derive(Debug)]
+pub(crate) struct Replicas {
+    shard: u32,
+    shards: u32,
+    /// The other replicas, by shard.
+    peers: Vec<(u32, TopologyClient<Channel>)>,
+}
+
+impl Replicas {
+    pub(crate) fn new(args: &Args) -> anyhow::Result<Self> {
+        if args.replicas.is_empty() {
+            return Ok(Self {
+                shard: 0,
+                shards: 1,
+                peers: Vec::new(),
+            });
+        }
+        let shards = args.replicas.len() as u32;
+        if args.shard >= shards {
+            bail!("shard {} is out of the {} replicas", args.shard, shards);
+        }
+        let mut peers = Vec::with_capacity(args.replicas.len() - 1);
+        for (shard, url) in (0..shards).zip(&args.replicas) {
+            if shard == args.shard {
+                continue;
+            }
+            let channel = Endpoint::from_shared(url.clone())
+                .with_context(|| format!("invalid replica URL {}", url))?
+                .timeout(FAN_OUT_TIMEOUT)
+                .connect_lazy();
+            peers.push((shard, TopologyClient::new(channel)));
+        }
+        Ok(Self {
+            shard: args.shard,
+            shards,
+            peers,
+        })
+    }
+
+    pub(crate) fn shard(&self) -> u32 {
+        self.shard
+    }
+
+    pub(crate) fn shards(&self) -> u32 {
+        self.shards
+    }
+
+    /// Whether the node named `node_name` is in the shard of this replica.
+    pub(crate) fn owns(&self, node_name: &str) -> bool {
+        shard_of(node_name, self.shards) == self.shard
+    }
+
+    /// Asks every other replica for the topology it holds, and merges their answers
+    /// into `local`, that of this one. Those which can't be asked are reported missing.
+    pub(crate) async fn fan_out(
+        &self,
+        request: GetTopologyRequest,
+        local: GetTopologyResponse,
+    ) -> GetTopologyResponse {
+        if self.peers.is_empty() {
+            return local;
+        }
+        let request = GetTopologyRequest {
+            local: true,
+            ..request
+        };
+        let answers = join_all(self.peers.iter().map(|(shard, client)| {
+            let mut client = client.clone();
+            let request = request.clone();
+            async move { (*shard, client.get_topology(request).await) }
+        }))
+        .await;
+
+        let mut responses = vec![local];
+        let mut missing_shards = Vec::new();
+        for (shard, answer) in answers {
+            match answer {
+                Ok(response) => responses.push(response.into_inner()),
+                Err(status) => {
+                    warn!(
+                        "Failed to get the topology of replica {}: {}",
+                        shard, status
+                    );
+                    missing_shards.push(shard);
+                }
+            }
+        }
+        let mut merged = merge(responses);
+        merged.missing_shards = missing_shards;
+        merged
+    }
+}
+
+/// Merges the topologies held by the replicas of the server, the first one winning
+/// ties. The service maps of a node are taken from the replica its agent is connected
+/// to, else from the one owning it: another only holds copies made while the owner
+/// was down, older than those the agent pushed to it since.
+fn merge(responses: Vec<GetTopologyResponse>) -> GetTopologyResponse {
+    let mut holders: HashMap<(String, String), (usize, (bool, bool))> = HashMap::new();
+    for (replica, response) in responses.iter().enumerate() {
+        for agent in &response.agents {
+            let key = (agent.cluster.clone(), agent.node_name.clone());
+            let rank = (agent.connected, agent.owned);
+            match holders.get(&key) {
+                Some((_, best)) if *best >= rank => {}
+                _ => {
+                    holders.insert(key, (replica, rank));
+                }
+            }
+        }
+    }
+
+    let mut clusters: Vec<TopologyCluster> = Vec::new();
+    let mut agents = Vec::new();
+    let mut edges = Vec::new();
+    for (replica, response) in responses.into_iter().enumerate() {
+        for cluster in response.clusters {
+            if !clusters.iter().any(|c| c.name == cluster.name) {
+                clusters.push(cluster);
+            }
+        }
+        let held = |cluster: &str, node_name: &str| match holders
+            .get(&(cluster.to_string(), node_name.to_string()))
+        {
+            Some((holder, _)) => *holder == replica,
+            None => true,
+        };
+        agents.extend(
+            response
+                .agents
+                .into_iter()
+                .filter(|agent| held(&agent.cluster, &agent.node_name)),
+        );
+        edges.extend(
+            response
+                .edges
+                .into_iter()
+                .filter(|edge| held(&edge.cluster, &edge.node_name)),
+        );
+    }
+    topology(clusters, agents, edges)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use clap::Parser;
+    use parking_lot::Mutex;
+    use tokio::net::TcpListener;
+    use tokio_stream::wrappers::TcpListenerStream;
+    use tonic::transport::Server;
+
+    use agent_api::snapshot_checksum;
+    use agent_api::v1::topology_server::TopologyServer;
+    use agent_api::v1::{ServiceMapEdge, ServiceMapUpdate, TopologyAgent, TopologyEdge};
+
+    use super::*;
+    use crate::clusters::Clusters;
+    use crate::store::Store;
+    use crate::topology::TopologyService;
+
+    fn args(replicas: &[String], shard: u32) -> Args {
+        let mut args = vec!["hub".to_string(), format!("--shard={}", shard)];
+        args.extend(replicas.iter().map(|url| format!("--replica={}", url)));
+        Args::parse_from(args)
+    }
+
+    fn edge(client: &str) -> ServiceMapEdge {
+        ServiceMapEdge {
+            client_workload: client.to_string(),
+            server_workload: "shop/db".to_string(),
+            server_port: 5432,
+            protocol: "TCP".to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn agent(node_name: &str, connected: bool, owned: bool) -> TopologyAgent {
+        TopologyAgent {
+            cluster: "default".to_string(),
+            node_name: node_name.to_string(),
+            connected,
+            owned,
+        }
+    }
+
+    fn topology_edge(node_name: &str, client: &str) -> TopologyEdge {
+        TopologyEdge {
+            cluster: "default".to_string(),
+            node_name: node_name.to_string(),
+            name: "service-map".to_string(),
+            timestamp_ns: 1,
+            edge: Some(edge(client)),
+        }
+    }
+
+    /// Returns a node name which the replica at `shard` owns, out of `shards`.
+    fn node_of(shard: u32, shards: u32) -> String {
+        (0..)
+            .map(|i| format!("node-{}", i))
+            .find(|node| shard_of(node, shards) == shard)
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_replicas_own_their_shard() {
+        let urls: Vec<_> = (0..3)
+            .map(|i| format!("http://hub-{}.hub:9090", i))
+            .collect();
+        let replicas = Replicas::new(&args(&urls, 1)).unwrap();
+        assert_eq!((replicas.shard(), replicas.shards()), (1, 3));
+        assert_eq!(replicas.peers.len(), 2);
+        assert!(replicas.owns(&node_of(1, 3)));
+        assert!(!replicas.owns(&node_of(0, 3)));
+        assert!(!replicas.owns(&node_of(2, 3)));
+
+        // A server which isn't sharded owns every node.
+        let replicas = Replicas::new(&args(&[], 0)).unwrap();
+        assert!(replicas.owns("node-1"));
+        assert!(replicas.peers.is_empty());
+
+        assert!(Replicas::new(&args(&urls, 3)).is_err());
+    }
+
+    #[test]
+    fn test_merge_takes_nodes_from_their_holder() {
+        // node-1 failed over to replica 1 and is back on replica 0, its owner. node-2
+        // failed over to replica 0 and is disconnected since, while replica 1, its
+        // owner, was down.
+        let first = GetTopologyResponse {
+            agents: vec![agent("node-1", true, true), agent("node-2", false, false)],
+            edges: vec![
+                topology_edge("node-1", "shop/web"),
+                topology_edge("node-2", "shop/stale"),
+            ],
+            ..Default::default()
+        };
+        let second = GetTopologyResponse {
+            agents: vec![agent("node-1", false, false), agent("node-2", false, true)],
+            edges: vec![
+                topology_edge("node-1", "shop/stale"),
+                topology_edge("node-2", "shop/api"),
+            ],
+            ..Default::default()
+        };
+        let merged = merge(vec![first, second]);
+        assert_eq!(
+            merged.agents,
+            [agent("node-1", true, true), agent("node-2", false, true)]
+        );
+        let clients: Vec<_> = merged
+            .edges
+            .iter()
+            .map(|e| e.edge.as_ref().unwrap().client_workload.as_str())
+            .collect();
+        assert_eq!(clients, ["shop/web", "shop/api"]);
+        let workloads: Vec<_> = merged.nodes.iter().map(|n| n.workload.as_str()).collect();
+        assert_eq!(workloads, ["shop/api", "shop/db", "shop/web"]);
+    }
+
+    #[tokio::test]
+    async fn test_fan_out_merges_replicas() {
+        // The third replica is down: nothing listens on its port any more.
+        let mut listeners = Vec::new();
+        for _ in 0..3 {
+            listeners.push(TcpListener::bind("127.0.0.1:0").await.unwrap());
+        }
+        let urls: Vec<_> = listeners
+            .iter()
+            .map(|l| format!("http://{}", l.local_addr().unwrap()))
+            .collect();
+        listeners.pop();
+
+        let mut services = Vec::new();
+        for (shard, listener) in listeners.into_iter().enumerate() {
+            let args = args(&urls, shard as u32);
+            let clusters = Arc::new(Clusters::new(&args).unwrap());
+            let replicas = Arc::new(Replicas::new(&args).unwrap());
+            let store = Arc::new(Mutex::new(Store::new(Duration::from_secs(60))));
+            // Each replica holds a node of its own shard.
+            let node = node_of(shard as u32, 3);
+            let edge = edge(&format!("shop/web-{}", shard));
+            let update = ServiceMapUpdate {
+                timestamp_ns: 1,
+                full: true,
+                checksum: snapshot_checksum([&edge]),
+                edges: vec![edge],
+                ..Default::default()
+            };
+            {
+                let mut store = store.lock();
+                let session = store.register("default", &node, replicas.owns(&node));
+                assert!(store.apply("default", &node, session, "service-map", update));
+            }
+            let service = TopologyService::new(clusters, replicas, store);
+            tokio::spawn(
+                Server::builder()
+                    .add_service(TopologyServer::new(service))
+                    .serve_with_incoming(TcpListenerStream::new(listener)),
+            );
+            services.push(node);
+        }
+
+        let channel = Endpoint::from_shared(urls[1].clone())
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+        let mut client = TopologyClient::new(channel);
+        let response = client
+            .get_topology(GetTopologyRequest::default())
+            .await
+            .unwrap()
+            .into_inner();
+        let mut nodes: Vec<_> = response
+            .agents
+            .iter()
+            .map(|a| a.node_name.clone())
+            .collect();
+        nodes.sort();
+        let mut expected = services.clone();
+        expected.sort();
+        assert_eq!(nodes, expected);
+        assert!(response.agents.iter().all(|a| a.owned));
+        assert_eq!(response.edges.len(), 2);
+        assert_eq!(response.missing_shards, [2]);
+
+        // Asked for its own only, a replica doesn't fan out.
+        let request = GetTopologyRequest {
+            local: true,
+            ..Default::default()
+        };
+        let response = client.get_topology(request).await.unwrap().into_inner();
+        assert_eq!(response.agents, [agent(&services[1], true, true)]);
+        assert!(response.missing_shards.is_empty());
+    }
+}
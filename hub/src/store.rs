@@ -0,0 +1,396 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::time::{Duration, Instant};
+
+use agent_api::snapshot_checksum;
+use agent_api::v1::{
+    GetTopologyRequest, GetTopologyResponse, ServiceMapEdge, ServiceMapUpdate, TopologyAgent,
+    TopologyCluster, TopologyEdge, TopologyNode,
+};
+
+use crate::clusters::Clusters;
+
+/// Client, server, server port and protocol, the order of the edges of a snapshot.
+type EdgeKey = (String, String, u32, String);
+
+fn edge_key(edge: &ServiceMapEdge) -> EdgeKey {
+    (
+        edge.client_workload.clone(),
+        edge.server_workload.clone(),
+        edge.server_port,
+        edge.protocol.clone(),
+    )
+}
+
+/// Copy of the service map of a program, brought up to date by the updates pushed.
+#[derive(Debug, Default)]
+struct ServiceMapCopy {
+    timestamp_ns: u64,
+    edges: BTreeMap<EdgeKey, ServiceMapEdge>,
+}
+
+impl ServiceMapCopy {
+    /// Applies `update`, and returns whether the copy then has its checksum. One that
+    /// doesn't missed updates, and needs a full one.
+    fn apply(&mut self, update: ServiceMapUpdate) -> bool {
+        if update.full {
+            self.edges.clear();
+        }
+        for edge in &update.removed {
+            self.edges.remove(&edge_key(edge));
+        }
+        for edge in update.edges {
+            self.edges.insert(edge_key(&edge), edge);
+        }
+        self.timestamp_ns = update.timestamp_ns;
+        snapshot_checksum(self.edges.values()) == update.checksum
+    }
+}
+
+/// The agent of a node, and the service maps it pushed.
+#[derive(Debug)]
+struct Agent {
+    /// Connect stream the agent last registered on.
+    session: u64,
+    /// When the stream broke, none while it is open.
+    disconnected_at: Option<Instant>,
+    /// Whether the node is in the shard of the replica.
+    owned: bool,
+    maps: HashMap<String, ServiceMapCopy>,
+}
+
+/// Service maps pushed by the agents of every cluster, by cluster and node name.
+#[derive(Debug)]
+pub(crate) struct Store {
+    agents: HashMap<(String, String), Agent>,
+    next_session: u64,
+    /// How long the service maps of a disconnected agent are kept.
+    forget_after: Duration,
+}
+
+impl Store {
+    pub(crate) fn new(forget_after: Duration) -> Self {
+        Self {
+            agents: HashMap::new(),
+            next_session: 0,
+            forget_after,
+        }
+    }
+
+    /// Registers the agent of `node_name` in `cluster` on a new stream, and returns the
+    /// session its updates are applied with. The copies of its service maps are kept,
+    /// since the agent goes on from them.
+    pub(crate) fn register(&mut self, cluster: &str, node_name: &str, owned: bool) -> u64 {
+        self.next_session += 1;
+        let session = self.next_session;
+        let agent = self
+            .agents
+            .entry((cluster.to_string(), node_name.to_string()))
+            .or_insert_with(|| Agent {
+                session,
+                disconnected_at: None,
+                owned,
+                maps: HashMap::new(),
+            });
+        agent.session = session;
+        agent.disconnected_at = None;
+        agent.owned = owned;
+        session
+    }
+
+    /// Applies an update of the service map of program `name` the agent pushed on
+    /// `session`, and returns whether the copy then has its checksum. Updates left on
+    /// a stream the agent since replaced are ignored.
+    pub(crate) fn apply(
+        &mut self,
+        cluster: &str,
+        node_name: &str,
+        session: u64,
+        name: &str,
+        update: ServiceMapUpdate,
+    ) -> bool {
+        let agent = self
+            .agents
+            .get_mut(&(cluster.to_string(), node_name.to_string()));
+        match agent {
+            Some(agent) if agent.session == session => agent
+                .maps
+                .entry(name.to_string())
+                .or_default()
+                .apply(update),
+            _ => true,
+        }
+    }
+
+    /// Records that the stream of `session` broke, unless the agent already
+    /// registered on another one.
+    pub(crate) fn disconnect(&mut self, cluster: &str, node_name: &str, session: u64) {
+        let agent = self
+            .agents
+            .get_mut(&(cluster.to_string(), node_name.to_string()));
+        if let Some(agent) = agent.filter(|agent| agent.session == session) {
+            agent.disconnected_at = Some(Instant::now());
+        }
+    }
+
+    /// Merges the service maps matching `request` into one topology, attributing each
+    /// workload and edge to its cluster. Those of agents disconnected for longer than
+    /// allowed are forgotten first.
+    pub(crate) fn topology(
+        &mut self,
+        request: &GetTopologyRequest,
+        clusters: &Clusters,
+    ) -> GetTopologyResponse {
+        let forget_after = self.forget_after;
+        self.agents.retain(|_, agent| match agent.disconnected_at {
+            Some(at) => at.elapsed() < forget_after,
+            None => true,
+        });
+
+        let selected = |cluster: &str| request.cluster.is_empty() || request.cluster == cluster;
+        let mut agents = Vec::new();
+        let mut edges = Vec::new();
+        for ((cluster, node_name), agent) in &self.agents {
+            if !selected(cluster) {
+                continue;
+            }
+            agents.push(TopologyAgent {
+                cluster: cluster.clone(),
+                node_name: node_name.clone(),
+                connected: agent.disconnected_at.is_none(),
+                owned: agent.owned,
+            });
+            for (name, copy) in &agent.maps {
+                if !request.name.is_empty() && request.name != *name {
+                    continue;
+                }
+                for edge in copy.edges.values() {
+                    edges.push(TopologyEdge {
+                        cluster: cluster.clone(),
+                        node_name: node_name.clone(),
+                        name: name.clone(),
+                        timestamp_ns: copy.timestamp_ns,
+                        edge: Some(edge.clone()),
+                    });
+                }
+            }
+        }
+
+        let clusters = clusters
+            .iter()
+            .filter(|cluster| selected(&cluster.name))
+            .map(Into::into)
+            .collect();
+        topology(clusters, agents, edges)
+    }
+}
+
+/// Returns the topology made of `edges`, sorted, and of the workloads they connect.
+pub(crate) fn topology(
+    clusters: Vec<TopologyCluster>,
+    mut agents: Vec<TopologyAgent>,
+    mut edges: Vec<TopologyEdge>,
+) -> GetTopologyResponse {
+    agents.sort_by(|a, b| (&a.cluster, &a.node_name).cmp(&(&b.cluster, &b.node_name)));
+    edges.sort_by(|a, b| topology_edge_order(a).cmp(&topology_edge_order(b)));
+    let mut nodes = BTreeSet::new();
+    for topology_edge in &edges {
+        if let Some(edge) = &topology_edge.edge {
+            nodes.insert((&topology_edge.cluster, &edge.client_workload));
+            nodes.insert((&topology_edge.cluster, &edge.server_workload));
+        }
+    }
+    let nodes = nodes
+        .into_iter()
+        .map(|(cluster, workload)| TopologyNode {
+            cluster: cluster.clone(),
+            workload: workload.clone(),
+        })
+        .collect();
+    GetTopologyResponse {
+        clusters,
+        nodes,
+        edges,
+        agents,
+        missing_shards: Vec::new(),
+    }
+}
+
+fn topology_edge_order(edge: &TopologyEdge) -> (&str, &str, &str, Option<EdgeKey>) {
+    (
+        &edge.cluster,
+        &edge.node_name,
+        &edge.name,
+        edge.edge.as_ref().map(edge_key),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+    use crate::args::Args;
+
+    fn edge(client: &str, server: &str, bytes_sent: u64) -> ServiceMapEdge {
+        ServiceMapEdge {
+            client_workload: client.to_string(),
+            server_workload: server.to_string(),
+            server_port: 80,
+            protocol: "TCP".to_string(),
+            bytes_sent,
+            ..Default::default()
+        }
+    }
+
+    /// The update bringing a copy to `snapshot`, whose edges are sorted.
+    fn update(
+        timestamp_ns: u64,
+        full: bool,
+        edges: Vec<ServiceMapEdge>,
+        removed: Vec<ServiceMapEdge>,
+        snapshot: Vec<ServiceMapEdge>,
+    ) -> ServiceMapUpdate {
+        ServiceMapUpdate {
+            timestamp_ns,
+            full,
+            edges,
+            removed,
+            checksum: snapshot_checksum(&snapshot),
+            ..Default::default()
+        }
+    }
+
+    fn clusters(args: &[&str]) -> Clusters {
+        Clusters::new(&Args::parse_from(["hub"].iter().chain(args))).unwrap()
+    }
+
+    #[test]
+    fn test_copy_follows_updates() {
+        let mut copy = ServiceMapCopy::default();
+        let (a, b) = (
+            edge("default/a", "default/b", 1),
+            edge("default/b", "default/c", 2),
+        );
+        assert!(copy.apply(update(1, true, vec![a.clone()], vec![], vec![a.clone()])));
+
+        // A changed edge and an added one, then a removed one.
+        let a2 = edge("default/a", "default/b", 10);
+        let snapshot = [a2.clone(), b.clone()];
+        assert!(copy.apply(update(
+            2,
+            false,
+            vec![a2.clone(), b.clone()],
+            vec![],
+            snapshot.to_vec()
+        )));
+        assert_eq!(copy.edges.values().cloned().collect::<Vec<_>>(), snapshot);
+        assert!(copy.apply(update(3, false, vec![], vec![a2.clone()], vec![b.clone()])));
+        assert_eq!(copy.edges.len(), 1);
+        assert_eq!(copy.timestamp_ns, 3);
+
+        // A missed update leaves the copy wrong until a full one.
+        assert!(!copy.apply(update(5, false, vec![], vec![], vec![a.clone()])));
+        assert!(copy.apply(update(6, true, vec![a.clone()], vec![], vec![a.clone()])));
+        assert_eq!(copy.edges.values().cloned().collect::<Vec<_>>(), [a]);
+    }
+
+    #[test]
+    fn test_topology_attributes_clusters() {
+        let clusters = clusters(&["--cluster=eu=/eu.token", "--cluster=us=/us.token"]);
+        let mut store = Store::new(Duration::from_secs(60));
+        let eu = store.register("eu", "node-1", true);
+        let us = store.register("us", "node-1", true);
+        let web = edge("shop/web", "shop/db", 1);
+        assert!(store.apply(
+            "eu",
+            "node-1",
+            eu,
+            "service-map",
+            update(1, true, vec![web.clone()], vec![], vec![web.clone()])
+        ));
+        assert!(store.apply(
+            "us",
+            "node-1",
+            us,
+            "service-map",
+            update(2, true, vec![web.clone()], vec![], vec![web.clone()])
+        ));
+
+        let topology = store.topology(&GetTopologyRequest::default(), &clusters);
+        let names: Vec<_> = topology.clusters.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, ["eu", "us"]);
+        // The same workloads in two clusters are different nodes.
+        let nodes: Vec<_> = topology
+            .nodes
+            .iter()
+            .map(|n| (n.cluster.as_str(), n.workload.as_str()))
+            .collect();
+        assert_eq!(
+            nodes,
+            [
+                ("eu", "shop/db"),
+                ("eu", "shop/web"),
+                ("us", "shop/db"),
+                ("us", "shop/web")
+            ]
+        );
+        let edges: Vec<_> = topology
+            .edges
+            .iter()
+            .map(|e| (e.cluster.as_str(), e.node_name.as_str(), e.timestamp_ns))
+            .collect();
+        assert_eq!(edges, [("eu", "node-1", 1), ("us", "node-1", 2)]);
+
+        let request = GetTopologyRequest {
+            cluster: "us".to_string(),
+            ..Default::default()
+        };
+        let topology = store.topology(&request, &clusters);
+        assert_eq!(topology.clusters.len(), 1);
+        assert_eq!(topology.nodes.len(), 2);
+        assert_eq!(topology.edges.len(), 1);
+        let request = GetTopologyRequest {
+            name: "other".to_string(),
+            ..Default::default()
+        };
+        assert!(store.topology(&request, &clusters).edges.is_empty());
+    }
+
+    #[test]
+    fn test_sessions() {
+        let clusters = clusters(&[]);
+        let mut store = Store::new(Duration::ZERO);
+        let web = edge("shop/web", "shop/db", 1);
+        let first = store.register("default", "node-1", true);
+        assert!(store.apply(
+            "default",
+            "node-1",
+            first,
+            "sm",
+            update(1, true, vec![web.clone()], vec![], vec![web.clone()])
+        ));
+
+        // The agent registered again before the first stream ended: what is left on
+        // it is ignored, and its end doesn't disconnect the agent.
+        let second = store.register("default", "node-1", true);
+        assert!(store.apply(
+            "default",
+            "node-1",
+            first,
+            "sm",
+            update(2, true, vec![], vec![], vec![])
+        ));
+        store.disconnect("default", "node-1", first);
+        let topology = store.topology(&GetTopologyRequest::default(), &clusters);
+        assert_eq!(topology.edges.len(), 1);
+        assert_eq!(topology.edges[0].timestamp_ns, 1);
+        assert!(topology.agents[0].connected);
+
+        // Once disconnected for long enough, the agent is forgotten.
+        store.disconnect("default", "node-1", second);
+        assert!(store
+            .topology(&GetTopologyRequest::default(), &clusters)
+            .edges
+            .is_empty());
+    }
+}
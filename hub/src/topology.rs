@@ -0,0 +1,51 @@
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+use tonic::{Request, Response, Status};
+
+use agent_api::v1::topology_server::Topology;
+use agent_api::v1::{GetTopologyRequest, GetTopologyResponse};
+
+use crate::clusters::Clusters;
+use crate::replicas::Replicas;
+use crate::store::Store;
+
+/// Serves topology: the service maps pushed by the agents of every cluster, merged,
+/// from every replica unless only those of this one are asked for.
+#[derive(Debug)]
+pub(crate) struct TopologyService {
+    clusters: Arc<Clusters>,
+    replicas: Arc<Replicas>,
+    store: Arc<Mutex<Store>>,
+}
+
+impl TopologyService {
+    pub(crate) fn new(
+        clusters: Arc<Clusters>,
+        replicas: Arc<Replicas>,
+        store: Arc<Mutex<Store>>,
+    ) -> Self {
+        Self {
+            clusters,
+            replicas,
+            store,
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl Topology for TopologyService {
+    async fn get_topology(
+        &self,
+        request: Request<GetTopologyRequest>,
+    ) -> Result<Response<GetTopologyResponse>, Status> {
+        let request = request.into_inner();
+        let topology = self.store.lock().topology(&request, &self.clusters);
+        if request.local {
+            return Ok(Response::new(topology));
+        }
+        Ok(Response::new(
+            self.replicas.fan_out(request, topology).await,
+        ))
+    }
+}
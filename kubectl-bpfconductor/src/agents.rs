@@ -0,0 +1,317 @@
+use std::fs;
+use std::future::Future;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context};
+use k8s_openapi::api::apps::v1::{DaemonSet, Deployment, ReplicaSet, StatefulSet};
+use k8s_openapi::api::batch::v1::{CronJob, Job};
+use k8s_openapi::api::core::v1::Pod;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{ObjectMeta, OwnerReference};
+use kube::api::ListParams;
+use kube::{Api, Client};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::task::JoinSet;
+use tonic::metadata::{Ascii, MetadataValue};
+use tonic::service::interceptor::InterceptedService;
+use tonic::service::Interceptor;
+use tonic::transport::{Channel, Endpoint, Uri};
+use tonic::{Request, Status};
+use tower::service_fn;
+
+use agent_api::v1::agent_client::AgentClient;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A client of the agent API, sending the credentials of the plugin.
+pub(crate) type AgentApi = AgentClient<InterceptedService<Channel, Credentials>>;
+
+/// The token the agents are called with, as a bearer token, if any.
+#[derive(Clone, Default)]
+pub(crate) struct Credentials {
+    authorization: Option<MetadataValue<Ascii>>,
+}
+
+impl Credentials {
+    /// Reads the token held by `path`, as the agents do.
+    pub(crate) fn from_token_file(path: &Path) -> anyhow::Result<Self> {
+        let token = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read the agent token {}", path.display()))?;
+        let authorization = format!("Bearer {}", token.trim())
+            .parse()
+            .with_context(|| format!("Invalid agent token in {}", path.display()))?;
+        Ok(Self {
+            authorization: Some(authorization),
+        })
+    }
+}
+
+impl Interceptor for Credentials {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        if let Some(authorization) = &self.authorization {
+            request
+                .metadata_mut()
+                .insert("authorization", authorization.clone());
+        }
+        Ok(request)
+    }
+}
+
+/// The agent pod running on a node.
+#[derive(Clone)]
+pub(crate) struct Agent {
+    pub(crate) node: String,
+    pub(crate) pod: String,
+    pods: Api<Pod>,
+    port: u16,
+    credentials: Credentials,
+}
+
+impl Agent {
+    /// Returns a client of the agent, connecting on its first call. Connections are
+    /// forwarded to the agent pod by the Kubernetes API server, as by `kubectl
+    /// port-forward`, so that they are authenticated and authorized by the cluster
+    /// and agents can keep their API on loopback.
+    pub(crate) fn client(&self) -> anyhow::Result<AgentApi> {
+        let (pods, pod, port) = (self.pods.clone(), self.pod.clone(), self.port);
+        // Never resolved, the connector ignoring the URI.
+        let channel = Endpoint::from_static("http://agent")
+            .connect_timeout(CONNECT_TIMEOUT)
+            .connect_with_connector_lazy(service_fn(move |_: Uri| {
+                port_forward(pods.clone(), pod.clone(), port)
+            }));
+        Ok(AgentClient::with_interceptor(
+            channel,
+            self.credentials.clone(),
+        ))
+    }
+}
+
+/// Opens a connection to `port` of `pod` through the Kubernetes API server.
+async fn port_forward(
+    pods: Api<Pod>,
+    pod: String,
+    port: u16,
+) -> anyhow::Result<impl AsyncRead + AsyncWrite + Unpin + Send> {
+    let mut forwarder = pods
+        .portforward(&pod, &[port])
+        .await
+        .with_context(|| format!("Failed to forward port {} of agent pod {}", port, pod))?;
+    let stream = forwarder
+        .take_stream(port)
+        .ok_or_else(|| anyhow!("No stream forwarded to port {} of {}", port, pod))?;
+    tokio::spawn(async move {
+        if let Err(e) = forwarder.join().await {
+            eprintln!("Warning: forwarding to agent pod {} failed: {}", pod, e);
+        }
+    });
+    Ok(stream)
+}
+
+/// Finds the agents of the cluster through the Kubernetes API: the pods matching
+/// `selector` in `namespace`, one per node, serving the agent API over TCP on `port`.
+pub(crate) struct Agents {
+    client: Client,
+    namespace: String,
+    selector: String,
+    port: u16,
+    credentials: Credentials,
+}
+
+impl Agents {
+    pub(crate) async fn new(
+        namespace: String,
+        selector: String,
+        port: u16,
+        credentials: Credentials,
+    ) -> anyhow::Result<Self> {
+        let client = Client::try_default()
+            .await
+            .context("Failed to load the kubeconfig")?;
+        Ok(Self {
+            client,
+            namespace,
+            selector,
+            port,
+            credentials,
+        })
+    }
+
+    /// Lists the running agents, only that of `node` when set.
+    pub(crate) async fn list(&self, node: Option<&str>) -> anyhow::Result<Vec<Agent>> {
+        let pods: Api<Pod> = Api::namespaced(self.client.clone(), &self.namespace);
+        let mut params = ListParams::default().labels(&self.selector);
+        if let Some(node) = node {
+            params = params.fields(&format!("spec.nodeName={}", node));
+        }
+        let mut agents: Vec<_> = pods
+            .list(&params)
+            .await
+            .context("Failed to list the agent pods")?
+            .into_iter()
+            .filter_map(|pod| {
+                if pod.status?.phase.as_deref() != Some("Running") {
+                    return None;
+                }
+                Some(Agent {
+                    node: pod.spec?.node_name?,
+                    pod: pod.metadata.name?,
+                    pods: pods.clone(),
+                    port: self.port,
+                    credentials: self.credentials.clone(),
+                })
+            })
+            .collect();
+        if agents.is_empty() {
+            bail!(
+                "No running agent pod matches {} in namespace {}{}",
+                self.selector,
+                self.namespace,
+                node.map(|node| format!(" on node {}", node))
+                    .unwrap_or_default()
+            );
+        }
+        agents.sort_by(|a, b| a.node.cmp(&b.node));
+        Ok(agents)
+    }
+
+    /// Returns the node a pod runs on and its workload, named as the agents name it:
+    /// `<NAMESPACE>/<NAME>`, where the name is that of the controller of the pod's own
+    /// controller, like the deployment of its replica set, or else the pod's.
+    pub(crate) async fn pod(
+        &self,
+        namespace: &str,
+        name: &str,
+    ) -> anyhow::Result<(String, String)> {
+        let pods: Api<Pod> = Api::namespaced(self.client.clone(), namespace);
+        let pod = pods
+            .get(name)
+            .await
+            .with_context(|| format!("Failed to get pod {}/{}", namespace, name))?;
+        let node = pod
+            .spec
+            .as_ref()
+            .and_then(|spec| spec.node_name.clone())
+            .ok_or_else(|| anyhow!("Pod {}/{} is not scheduled yet", namespace, name))?;
+
+        let mut workload = name.to_string();
+        let mut owner = controller(&pod.metadata);
+        while let Some(current) = owner {
+            owner = self.controller_of(&current, namespace).await?;
+            if let Some(controller) = &owner {
+                workload = controller.name.clone();
+            }
+        }
+        Ok((node, format!("{}/{}", namespace, workload)))
+    }
+
+    async fn controller_of(
+        &self,
+        owner: &OwnerReference,
+        namespace: &str,
+    ) -> anyhow::Result<Option<OwnerReference>> {
+        let client = self.client.clone();
+        let metadata = match owner.kind.as_str() {
+            "ReplicaSet" => metadata(Api::<ReplicaSet>::namespaced(client, namespace), owner).await,
+            "Deployment" => metadata(Api::<Deployment>::namespaced(client, namespace), owner).await,
+            "DaemonSet" => metadata(Api::<DaemonSet>::namespaced(client, namespace), owner).await,
+            "StatefulSet" => {
+                metadata(Api::<StatefulSet>::namespaced(client, namespace), owner).await
+            }
+            "Job" => metadata(Api::<Job>::namespaced(client, namespace), owner).await,
+            "CronJob" => metadata(Api::<CronJob>::namespaced(client, namespace), owner).await,
+            _ => return Ok(None),
+        }?;
+        Ok(metadata.as_ref().and_then(controller))
+    }
+}
+
+/// Runs `query` on each agent at once. Agents it fails on are left out of the
+/// results, with a warning.
+pub(crate) async fn query_all<T, Fut>(
+    agents: Vec<Agent>,
+    query: impl Fn(AgentApi) -> Fut,
+) -> Vec<(Agent, T)>
+where
+    T: Send + 'static,
+    Fut: Future<Output = anyhow::Result<T>> + Send + 'static,
+{
+    let mut tasks = JoinSet::new();
+    for (index, agent) in agents.iter().enumerate() {
+        let result = agent.client().map(&query);
+        tasks.spawn(async move {
+            let result = match result {
+                Ok(query) => query.await,
+                Err(e) => Err(e),
+            };
+            (index, result)
+        });
+    }
+
+    let mut results = Vec::new();
+    while let Some(Ok((index, result))) = tasks.join_next().await {
+        let agent = &agents[index];
+        match result {
+            Ok(value) => results.push((index, value)),
+            Err(e) => eprintln!(
+                "Warning: agent {} on node {} failed: {:#}",
+                agent.pod, agent.node, e
+            ),
+        }
+    }
+    results.sort_by_key(|(index, _)| *index);
+    results
+        .into_iter()
+        .map(|(index, value)| (agents[index].clone(), value))
+        .collect()
+}
+
+async fn metadata<K>(api: Api<K>, owner: &OwnerReference) -> anyhow::Result<Option<ObjectMeta>>
+where
+    K: kube::Resource + Clone + serde::de::DeserializeOwned + std::fmt::Debug,
+{
+    let object = api
+        .get_metadata_opt(&owner.name)
+        .await
+        .with_context(|| format!("Failed to get {} {}", owner.kind, owner.name))?;
+    Ok(object.map(|object| object.metadata))
+}
+
+fn controller(metadata: &ObjectMeta) -> Option<OwnerReference> {
+    metadata
+        .owner_references
+        .as_ref()?
+        .iter()
+        .find(|r| r.controller == Some(true))
+        .cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn authorization(mut credentials: Credentials) -> Option<String> {
+        let request = credentials.call(Request::new(())).unwrap();
+        request
+            .metadata()
+            .get("authorization")
+            .map(|value| value.to_str().unwrap().to_string())
+    }
+
+    #[test]
+    fn test_credentials() {
+        assert_eq!(authorization(Credentials::default()), None);
+
+        let path = std::env::temp_dir().join(format!("agent-token-{}", std::process::id()));
+        fs::write(&path, "s3cret\n").unwrap();
+        let credentials = Credentials::from_token_file(&path).unwrap();
+        assert_eq!(
+            authorization(credentials),
+            Some("Bearer s3cret".to_string())
+        );
+        fs::write(&path, "s3cret\u{7}").unwrap();
+        assert!(Credentials::from_token_file(&path).is_err());
+        fs::remove_file(&path).unwrap();
+        assert!(Credentials::from_token_file(&path).is_err());
+    }
+}
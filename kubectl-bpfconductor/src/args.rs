@@ -0,0 +1,87 @@
+use std::path::PathBuf;
+
+use crate::agents::{Agents, Credentials};
+use crate::get::GetCommand;
+use crate::top::TopCommand;
+use crate::trace::TraceCommand;
+use clap::{Parser, Subcommand};
+
+#[derive(Parser, Debug)]
+#[command(
+    long_about = "Queries the eBPFConductor agents of a Kubernetes cluster, finding the agent of each node through the Kubernetes API."
+)]
+#[command(name = "kubectl-bpfconductor", bin_name = "kubectl bpfconductor")]
+#[command(disable_version_flag = true)]
+pub(crate) struct PluginCli {
+    /// Optional: Namespace the agents run in.
+    #[clap(
+        long,
+        global = true,
+        verbatim_doc_comment,
+        default_value = "kube-system"
+    )]
+    pub(crate) agent_namespace: String,
+
+    /// Optional: Label selector of the agent pods.
+    #[clap(
+        long,
+        global = true,
+        verbatim_doc_comment,
+        default_value = "name=bpfconductor-agent"
+    )]
+    pub(crate) agent_selector: String,
+
+    /// Optional: Port the agents serve their API on over TCP, as set by their
+    /// --agent-addr. It is reached through the Kubernetes API server, as with
+    /// `kubectl port-forward`, so the agents can listen on loopback only, e.g.
+    /// with --agent-addr 127.0.0.1:9080, and calling them needs the right to
+    /// port-forward to their pods.
+    #[clap(long, global = true, verbatim_doc_comment, default_value = "9080")]
+    pub(crate) agent_port: u16,
+
+    /// Optional: File holding the token the agents were given with their
+    /// --agent-token-file, which they then require of every call. Agents without
+    /// one only serve the commands reading their state over TCP, which trace
+    /// --focus is not.
+    #[clap(long, global = true, verbatim_doc_comment)]
+    pub(crate) agent_token_file: Option<PathBuf>,
+
+    #[command(subcommand)]
+    pub(crate) command: SubCommands,
+}
+
+#[derive(Subcommand, Debug)]
+pub(crate) enum SubCommands {
+    /// Shows the busiest parts of the cluster.
+    #[command(subcommand)]
+    Top(TopCommand),
+
+    /// Lists what the agents manage.
+    #[command(subcommand)]
+    Get(GetCommand),
+
+    /// Shows the requests recently sampled from or to the workload of a pod.
+    /// They are asked of the agent of the node the pod runs on.
+    Trace(TraceCommand),
+}
+
+impl PluginCli {
+    pub(crate) async fn execute(&self) -> anyhow::Result<()> {
+        let credentials = match &self.agent_token_file {
+            Some(path) => Credentials::from_token_file(path)?,
+            None => Credentials::default(),
+        };
+        let agents = Agents::new(
+            self.agent_namespace.clone(),
+            self.agent_selector.clone(),
+            self.agent_port,
+            credentials,
+        )
+        .await?;
+        match &self.command {
+            SubCommands::Top(t) => t.execute(&agents).await,
+            SubCommands::Get(g) => g.execute(&agents).await,
+            SubCommands::Trace(t) => t.execute(&agents).await,
+        }
+    }
+}
@@ -0,0 +1,87 @@
+use clap::{Args, Subcommand, ValueEnum};
+use comfy_table::Table;
+
+use agent_api::v1::ListRequest;
+use agent_api::{ProgramState, ProgramType};
+
+use crate::agents::{query_all, Agents};
+
+#[derive(Subcommand, Debug)]
+pub(crate) enum GetCommand {
+    /// Lists the programs loaded on every node.
+    Programs(GetProgramsArgs),
+}
+
+#[derive(Args, Debug)]
+pub(crate) struct GetProgramsArgs {
+    /// Optional: Only list the programs loaded on this node.
+    #[clap(long, verbatim_doc_comment)]
+    pub(crate) node: Option<String>,
+
+    /// Optional: Output format. wide also shows the maps and metadata of the programs.
+    /// Example: -o wide
+    #[clap(short, long, verbatim_doc_comment, value_enum)]
+    pub(crate) output: Option<OutputFormat>,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum OutputFormat {
+    Wide,
+}
+
+impl GetCommand {
+    pub(crate) async fn execute(&self, agents: &Agents) -> anyhow::Result<()> {
+        let GetCommand::Programs(args) = self;
+        let wide = args.output == Some(OutputFormat::Wide);
+        let results = query_all(
+            agents.list(args.node.as_deref()).await?,
+            |mut client| async move {
+                let response = client.list(ListRequest::default()).await?.into_inner();
+                Ok(response.results)
+            },
+        )
+        .await;
+
+        let mut table = Table::new();
+        table.load_preset(comfy_table::presets::NOTHING);
+        let mut header = vec!["Node", "Name", "Type", "State"];
+        if wide {
+            header.extend(["Maps", "Metadata"]);
+        }
+        table.set_header(header);
+        for (agent, results) in results {
+            for info in results.into_iter().filter_map(|result| result.info) {
+                let mut row = vec![
+                    agent.node.clone(),
+                    info.name,
+                    match ProgramType::try_from(info.program_type) {
+                        Ok(program_type) => format!("{:?}", program_type),
+                        Err(_) => info.program_type.to_string(),
+                    },
+                    match ProgramState::try_from(info.state) {
+                        Ok(state) => format!("{:?}", state),
+                        Err(_) => info.state.to_string(),
+                    },
+                ];
+                if wide {
+                    let mut maps: Vec<_> = info
+                        .ebpf_maps
+                        .iter()
+                        .map(|(name, id)| format!("{}={}", name, id))
+                        .collect();
+                    maps.sort();
+                    let mut metadata: Vec<_> = info
+                        .metadata
+                        .iter()
+                        .map(|(key, value)| format!("{}={}", key, value))
+                        .collect();
+                    metadata.sort();
+                    row.extend([maps.join(","), metadata.join(",")]);
+                }
+                table.add_row(row);
+            }
+        }
+        println!("{table}\n");
+        Ok(())
+    }
+}
@@ -0,0 +1,13 @@
+use crate::args::PluginCli;
+use clap::Parser;
+
+mod agents;
+mod args;
+mod get;
+mod top;
+mod trace;
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    PluginCli::parse().execute().await
+}
@@ -0,0 +1,146 @@
+use clap::{Args, Subcommand, ValueEnum};
+use comfy_table::Table;
+
+use agent_api::features;
+use agent_api::v1::{GetServiceMapAtRequest, ListRequest, ServiceMapEdge};
+use agent_api::{require_feature, ProgramState};
+
+use crate::agents::{query_all, AgentApi, Agents};
+
+#[derive(Subcommand, Debug)]
+pub(crate) enum TopCommand {
+    /// Lists the busiest edges of the service maps of every node.
+    Edges(TopEdgesArgs),
+}
+
+#[derive(Args, Debug)]
+pub(crate) struct TopEdgesArgs {
+    /// Optional: Only show the edges seen by the agent of this node.
+    #[clap(long, verbatim_doc_comment)]
+    pub(crate) node: Option<String>,
+
+    /// Optional: Only show the edges of this program, those of every running
+    /// program by default.
+    #[clap(long, verbatim_doc_comment)]
+    pub(crate) program: Option<String>,
+
+    /// Optional: What the edges are ranked by.
+    #[clap(long, verbatim_doc_comment, value_enum, default_value = "throughput")]
+    pub(crate) sort: EdgeOrder,
+
+    /// Optional: Maximum number of edges to show.
+    #[clap(short, long, verbatim_doc_comment, default_value_t = 20)]
+    pub(crate) limit: usize,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+pub(crate) enum EdgeOrder {
+    Throughput,
+    Bytes,
+    Connections,
+    Resets,
+}
+
+impl TopCommand {
+    pub(crate) async fn execute(&self, agents: &Agents) -> anyhow::Result<()> {
+        let TopCommand::Edges(args) = self;
+        let program = args.program.clone();
+        let results = query_all(agents.list(args.node.as_deref()).await?, |mut client| {
+            let program = program.clone();
+            async move {
+                require_feature(&mut client, features::SNAPSHOTS).await?;
+                let names = match program {
+                    Some(name) => vec![name],
+                    None => running_programs(&mut client).await?,
+                };
+                let mut edges = Vec::new();
+                for name in names {
+                    let request = GetServiceMapAtRequest {
+                        name: name.clone(),
+                        timestamp_ns: u64::MAX,
+                    };
+                    let response = client.get_service_map_at(request).await?.into_inner();
+                    if let Some(snapshot) = response.snapshot {
+                        edges.extend(snapshot.edges.into_iter().map(|e| (name.clone(), e)));
+                    }
+                }
+                Ok(edges)
+            }
+        })
+        .await;
+
+        let mut edges: Vec<_> = results
+            .into_iter()
+            .flat_map(|(agent, edges)| {
+                edges
+                    .into_iter()
+                    .map(move |(program, edge)| (agent.node.clone(), program, edge))
+            })
+            .collect();
+        edges.sort_by(|(_, _, a), (_, _, b)| args.sort.key(b).total_cmp(&args.sort.key(a)));
+        edges.truncate(args.limit);
+
+        let mut table = Table::new();
+        table.load_preset(comfy_table::presets::NOTHING);
+        table.set_header(vec![
+            "Node",
+            "Program",
+            "Client",
+            "Server",
+            "Port",
+            "Protocol",
+            "Throughput",
+            "Bytes Sent",
+            "Bytes Received",
+            "Active",
+            "Resets",
+            "Timeouts",
+        ]);
+        for (node, program, edge) in edges {
+            table.add_row(vec![
+                node,
+                program,
+                edge.client_workload,
+                edge.server_workload,
+                edge.server_port.to_string(),
+                edge.protocol,
+                format!("{:.0} B/s", edge.throughput_bps),
+                edge.bytes_sent.to_string(),
+                edge.bytes_received.to_string(),
+                edge.active_connections.to_string(),
+                edge.resets.to_string(),
+                edge.connect_timeouts.to_string(),
+            ]);
+        }
+        println!("{table}\n");
+        Ok(())
+    }
+}
+
+impl EdgeOrder {
+    fn key(self, edge: &ServiceMapEdge) -> f64 {
+        match self {
+            EdgeOrder::Throughput => edge.throughput_bps,
+            EdgeOrder::Bytes => (edge.bytes_sent + edge.bytes_received) as f64,
+            EdgeOrder::Connections => edge.active_connections as f64,
+            EdgeOrder::Resets => (edge.resets + edge.connect_timeouts) as f64,
+        }
+    }
+}
+
+/// Names the programs of the agent that are running, or running degraded.
+async fn running_programs(client: &mut AgentApi) -> anyhow::Result<Vec<String>> {
+    let response = client.list(ListRequest::default()).await?.into_inner();
+    Ok(response
+        .results
+        .into_iter()
+        .filter_map(|result| result.info)
+        .filter(|info| {
+            matches!(
+                ProgramState::try_from(info.state),
+                Ok(ProgramState::Running | ProgramState::Degraded)
+            )
+        })
+        .map(|info| info.name)
+        .collect())
+}
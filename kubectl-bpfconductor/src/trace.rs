@@ -0,0 +1,137 @@
+use std::time::Duration;
+
+use anyhow::bail;
+use clap::Parser;
+use comfy_table::Table;
+
+use agent_api::v1::{GetRecentRequestsRequest, RequestSample, TracePodRequest};
+use agent_api::{features, require_feature};
+
+use crate::agents::Agents;
+
+/// How often the requests are asked for again when following them.
+const FOLLOW_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Parser, Debug)]
+pub(crate) struct TraceCommand {
+    /// Required: The pod whose workload is traced.
+    /// Format: pod/<NAME> or <NAME>
+    /// Example: pod/frontend-6d8f7c9b5-x2k4q
+    pub(crate) pod: String,
+
+    /// Optional: Namespace of the pod.
+    #[clap(short, long, verbatim_doc_comment, default_value = "default")]
+    pub(crate) namespace: String,
+
+    /// Optional: Only show requests with a status code of at least this value.
+    /// Example: --min-status 500
+    #[clap(long, verbatim_doc_comment, default_value_t = 0)]
+    pub(crate) min_status: u32,
+
+    /// Optional: Maximum number of requests to show.
+    #[clap(short, long, verbatim_doc_comment, default_value_t = 100)]
+    pub(crate) limit: u32,
+
+    /// Optional: Keep showing the requests sampled from then on, until interrupted.
+    #[clap(short, long, verbatim_doc_comment)]
+    pub(crate) follow: bool,
+
+    /// Optional: Focus the agent on the pod for this many minutes first: only its
+    /// process is traced, HTTP is parsed both ways, and its requests are all sampled.
+    /// Example: --focus 10
+    #[clap(long, verbatim_doc_comment)]
+    pub(crate) focus: Option<u32>,
+}
+
+impl TraceCommand {
+    pub(crate) async fn execute(&self, agents: &Agents) -> anyhow::Result<()> {
+        let name = match self.pod.split_once('/') {
+            Some(("pod" | "pods" | "po", name)) => name,
+            Some((kind, _)) => bail!("Only pods can be traced, not {}", kind),
+            None => self.pod.as_str(),
+        };
+        let (node, workload) = agents.pod(&self.namespace, name).await?;
+        let agent = agents.list(Some(&node)).await?.remove(0);
+        let mut client = agent.client()?;
+        require_feature(&mut client, features::REQUEST_EVENTS).await?;
+        if let Some(minutes) = self.focus {
+            require_feature(&mut client, features::POD_TRACING).await?;
+            let request = TracePodRequest {
+                namespace: self.namespace.clone(),
+                pod: name.to_string(),
+                duration_secs: minutes.max(1).saturating_mul(60),
+            };
+            let response = client.trace_pod(request).await?.into_inner();
+            println!(
+                "Focused on process {} for {} minutes",
+                response.pid,
+                minutes.max(1)
+            );
+        }
+        println!("Tracing {} on node {}\n", workload, node);
+
+        let request = GetRecentRequestsRequest {
+            workload,
+            min_status: self.min_status,
+            limit: self.limit,
+            ..Default::default()
+        };
+        let mut requests = client
+            .get_recent_requests(request.clone())
+            .await?
+            .into_inner()
+            .requests;
+        // Newest first.
+        requests.reverse();
+        print_requests(&requests);
+        if !self.follow {
+            return Ok(());
+        }
+
+        let mut last_ns = requests.last().map(|r| r.timestamp_ns).unwrap_or_default();
+        loop {
+            tokio::time::sleep(FOLLOW_INTERVAL).await;
+            let mut requests: Vec<_> = client
+                .get_recent_requests(request.clone())
+                .await?
+                .into_inner()
+                .requests
+                .into_iter()
+                .filter(|r| r.timestamp_ns > last_ns)
+                .collect();
+            requests.reverse();
+            if let Some(last) = requests.last() {
+                last_ns = last.timestamp_ns;
+                print_requests(&requests);
+            }
+        }
+    }
+}
+
+fn print_requests(requests: &[RequestSample]) {
+    let mut table = Table::new();
+    table.load_preset(comfy_table::presets::NOTHING);
+    table.set_header(vec![
+        "Source",
+        "Destination",
+        "Protocol",
+        "Method",
+        "Path",
+        "Status",
+        "Latency",
+        "Trace",
+    ]);
+    for r in requests {
+        table.add_row(vec![
+            r.src_workload.clone(),
+            r.dst_workload.clone(),
+            r.protocol.clone(),
+            r.method.clone(),
+            r.path.clone(),
+            r.status.to_string(),
+            format!("{:?}", Duration::from_nanos(r.latency_ns)),
+            r.trace_id.clone(),
+        ]);
+    }
+    println!("{table}\n");
+}
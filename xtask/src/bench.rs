@@ -0,0 +1,123 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::{bail, Context as _};
+use clap::Parser;
+use serde_json::Value;
+
+use crate::protobuf::WORKSPACE_ROOT;
+
+/// The socket tracer is a workspace of its own, with its own target directory.
+const SOCKET_TRACER_DIR: &str = "ebpf/socket-tracer";
+
+#[derive(Debug, Parser)]
+pub struct Options {
+    /// Optional: Name of the criterion baseline to save or compare against
+    #[clap(long, default_value = "main")]
+    pub baseline: String,
+    /// Optional: Save the results as the baseline instead of comparing with it
+    #[clap(long)]
+    pub save: bool,
+    /// Optional: Slowdown of the mean time, in percent, failing the comparison
+    #[clap(long, default_value = "10")]
+    pub threshold: f64,
+}
+
+/// Runs the benchmarks of the agent and of the socket tracer. Unless saving a baseline,
+/// fails when a benchmark got slower than the baseline by more than the threshold.
+pub fn bench(opts: Options) -> Result<(), anyhow::Error> {
+    let root = PathBuf::from(WORKSPACE_ROOT.to_string());
+    let criterion_arg = if opts.save {
+        "--save-baseline"
+    } else {
+        "--baseline"
+    };
+    let runs: [(&[&str], PathBuf); 2] = [
+        (&["-p", "agent", "--features", "bench"], root.clone()),
+        (&["-p", "socket-tracer"], root.join(SOCKET_TRACER_DIR)),
+    ];
+
+    let mut regressions = Vec::new();
+    for (args, dir) in runs {
+        let status = Command::new("cargo")
+            .current_dir(&dir)
+            .arg("bench")
+            .args(args)
+            .args(["--", criterion_arg, &opts.baseline])
+            .status()
+            .context("failed to run cargo bench")?;
+        if !status.success() {
+            bail!("cargo bench failed in {}", dir.display());
+        }
+        if !opts.save {
+            compare(
+                &dir.join("target/criterion"),
+                &opts.baseline,
+                opts.threshold,
+                &mut regressions,
+            )?;
+        }
+    }
+
+    if !regressions.is_empty() {
+        for (bench, change) in &regressions {
+            eprintln!("{bench}: {change:+.1}%");
+        }
+        bail!(
+            "{} benchmarks are more than {}% slower than baseline {}",
+            regressions.len(),
+            opts.threshold,
+            opts.baseline
+        );
+    }
+    Ok(())
+}
+
+/// Collects the benchmarks under `dir` whose mean time grew past `threshold` percent
+/// of the baseline, with their change in percent.
+fn compare(
+    dir: &Path,
+    baseline: &str,
+    threshold: f64,
+    regressions: &mut Vec<(String, f64)>,
+) -> Result<(), anyhow::Error> {
+    for entry in fs::read_dir(dir).with_context(|| format!("failed to read {}", dir.display()))? {
+        let path = entry?.path();
+        if !path.is_dir() {
+            continue;
+        }
+        let (new, base) = (path.join("new"), path.join(baseline));
+        if !new.join("estimates.json").exists() {
+            compare(&path, baseline, threshold, regressions)?;
+            continue;
+        }
+        // Benchmarks added since the baseline was saved have nothing to compare with.
+        if !base.join("estimates.json").exists() {
+            continue;
+        }
+        let change = (mean(&new)? / mean(&base)? - 1.0) * 100.0;
+        if change > threshold {
+            let name = fs::read_to_string(new.join("benchmark.json"))
+                .ok()
+                .and_then(|text| serde_json::from_str::<Value>(&text).ok())
+                .and_then(|v| v["full_id"].as_str().map(String::from))
+                .unwrap_or_else(|| path.display().to_string());
+            regressions.push((name, change));
+        }
+    }
+    Ok(())
+}
+
+/// Reads the mean time of a criterion result, in nanoseconds.
+fn mean(dir: &Path) -> Result<f64, anyhow::Error> {
+    let path = dir.join("estimates.json");
+    let text =
+        fs::read_to_string(&path).with_context(|| format!("failed to read {}", path.display()))?;
+    let estimates: Value = serde_json::from_str(&text)?;
+    estimates["mean"]["point_estimate"]
+        .as_f64()
+        .with_context(|| format!("no mean in {}", path.display()))
+}
@@ -1,3 +1,4 @@
+mod bench;
 mod protobuf;
 mod run;
 
@@ -14,6 +15,8 @@ pub struct Options {
 
 #[derive(Debug, Parser)]
 enum Command {
+    /// Run the benchmarks, failing on regressions from a saved baseline.
+    Bench(bench::Options),
     /// Build the gRPC protobuf files.
     BuildProto(protobuf::Options),
     /// Run agent on the local host.
@@ -25,6 +28,7 @@ fn main() {
 
     use Command::*;
     let ret = match opts.command {
+        Bench(opts) => bench::bench(opts),
         BuildProto(opts) => protobuf::build(opts),
         Run(opts) => run::run(opts),
     };